@@ -113,6 +113,17 @@ async def download_file(filename: str):
         media_type='application/octet-stream'
     )
 
+@app.delete("/files/{filename}", status_code=204)
+async def delete_file(filename: str):
+    """Delete a file, e.g. what is left of an upload the client cancelled"""
+    file_path = UPLOAD_DIR / filename
+    
+    if not file_path.is_file():
+        raise HTTPException(status_code=404, detail="File not found")
+    
+    file_path.unlink()
+    print(f"🗑️ Deleted: {filename}")
+
 @app.get("/stats", response_model=StatsResponse)
 async def get_stats():
     """Get server statistics"""
@@ -0,0 +1,16 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use shrlink::compression::{parse_shr_bundle, ParallelCompressor};
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(chunks) = parse_shr_bundle(data) else {
+        return;
+    };
+
+    // Decompression trusts the sizes the bundle claims, so it's part of the attack surface too
+    let compressor = ParallelCompressor::default();
+    for chunk in &chunks {
+        let _ = compressor.decompress_chunk(chunk);
+    }
+});
@@ -0,0 +1,25 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use shrlink::compression::CompressedChunk;
+use shrlink::p2p::ChunkManifest;
+
+// Manifests will arrive from peers; until the wire format lands they are JSON
+fuzz_target!(|data: &[u8]| {
+    let Ok(manifest) = serde_json::from_slice::<ChunkManifest>(data) else {
+        return;
+    };
+
+    let signer = manifest.signer_peer_id();
+    for entry in &manifest.entries {
+        let chunk = CompressedChunk {
+            index: entry.index,
+            data: Vec::new(),
+            hash: entry.hash,
+            original_size: entry.original_size,
+            algorithm: Default::default(),
+            checksum: Default::default(),
+        };
+        let _ = manifest.verify_chunk(&chunk, signer);
+    }
+});
@@ -0,0 +1,16 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use shrlink::p2p::{create_hybrid_url, parse_hybrid_url};
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(url) = std::str::from_utf8(data) else {
+        return;
+    };
+
+    // Anything that parses must survive a round trip
+    if let Ok((peer_id, file_hash, Some(fallback))) = parse_hybrid_url(url) {
+        let again = parse_hybrid_url(&create_hybrid_url(peer_id, &file_hash, &fallback));
+        assert_eq!(again.ok(), Some((peer_id, file_hash, Some(fallback))));
+    }
+});
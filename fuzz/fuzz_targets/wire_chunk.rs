@@ -0,0 +1,14 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use shrlink::bundle::wire::{decode_chunk, encode_chunk, read_chunk, DEFAULT_MAX_FRAME};
+
+fuzz_target!(|data: &[u8]| {
+    // Whatever a peer sends, a chunk that decodes must encode back to the same bytes
+    if let Ok(Some((chunk, used))) = decode_chunk(data) {
+        assert_eq!(encode_chunk(&chunk).unwrap(), data[..used]);
+    }
+
+    let mut stream = data;
+    while let Ok(Some(_)) = read_chunk(&mut stream, DEFAULT_MAX_FRAME) {}
+});
@@ -3,16 +3,49 @@
 
 import os
 import json
+import re
 import time
 import tempfile
 import shutil
+import unicodedata
 from http.server import HTTPServer, BaseHTTPRequestHandler
-from urllib.parse import urlparse
+from urllib.parse import urlparse, quote, unquote
 
 # Directory to store uploaded files
 UPLOAD_DIR = "server_files"
 os.makedirs(UPLOAD_DIR, exist_ok=True)
 
+def normalize_filename(name):
+    """NFC-normalize and strip any directory components"""
+    name = unicodedata.normalize('NFC', name)
+    name = name.replace('\\', '/').split('/')[-1]
+    if name in ('', '.', '..'):
+        return None
+    return name
+
+def parse_disposition_filename(line):
+    """Extract the filename from a Content-Disposition line, preferring RFC 5987 filename*"""
+    plain = None
+    extended = None
+    for param in line.split(';')[1:]:
+        if '=' not in param:
+            continue
+        key, value = param.split('=', 1)
+        key = key.strip().lower()
+        value = value.strip()
+        if key == 'filename*':
+            charset, _, encoded = value.split("'", 2)
+            if charset.lower() == 'utf-8':
+                extended = unquote(encoded, encoding='utf-8', errors='strict')
+        elif key == 'filename':
+            plain = value.strip('"')
+    return extended or plain
+
+def content_disposition(name):
+    """Emit both an ASCII fallback and the RFC 5987 encoded name"""
+    fallback = ''.join(c if c.isascii() and c not in '"\\' else '_' for c in name)
+    return f"attachment; filename=\"{fallback}\"; filename*=UTF-8''{quote(name, safe='')}"
+
 class ShrLinkHandler(BaseHTTPRequestHandler):
     def do_POST(self):
         if self.path == '/upload':
@@ -30,11 +63,32 @@ class ShrLinkHandler(BaseHTTPRequestHandler):
         else:
             self.send_error(404, "Not found")
     
+    def do_DELETE(self):
+        if self.path.startswith('/files/'):
+            self.handle_delete()
+        else:
+            self.send_error(404, "Not found")
+    
+    def read_chunked_body(self):
+        """Read a Transfer-Encoding: chunked body (streamed uploads have no Content-Length)"""
+        body = bytearray()
+        while True:
+            size = int(self.rfile.readline().split(b';')[0].strip(), 16)
+            if size == 0:
+                # Skip optional trailers up to the terminating blank line
+                while self.rfile.readline() not in (b'\r\n', b'\n', b''):
+                    pass
+                return bytes(body)
+            body.extend(self.rfile.read(size))
+            self.rfile.readline()
+    
     def handle_upload(self):
         try:
+            chunked = 'chunked' in self.headers.get('Transfer-Encoding', '').lower()
+            
             # Get content length
             content_length = int(self.headers.get('Content-Length', 0))
-            if content_length == 0:
+            if content_length == 0 and not chunked:
                 self.send_error(400, "No content")
                 return
             
@@ -57,7 +111,7 @@ class ShrLinkHandler(BaseHTTPRequestHandler):
                 return
             
             # Read the entire request body
-            body = self.rfile.read(content_length)
+            body = self.read_chunked_body() if chunked else self.rfile.read(content_length)
             
             # Parse multipart data manually (simplified)
             boundary_bytes = ('--' + boundary).encode()
@@ -71,10 +125,10 @@ class ShrLinkHandler(BaseHTTPRequestHandler):
                     # Extract filename
                     lines = part.split(b'\r\n')
                     for line in lines:
-                        if b'filename=' in line:
-                            filename_part = line.decode().split('filename=')[1].strip().strip('"')
+                        if line.lower().startswith(b'content-disposition:'):
+                            filename_part = parse_disposition_filename(line.decode('utf-8'))
                             if filename_part:
-                                filename = filename_part
+                                filename = normalize_filename(filename_part)
                             break
                     
                     # Extract file data (after double CRLF)
@@ -115,22 +169,39 @@ class ShrLinkHandler(BaseHTTPRequestHandler):
     def handle_download(self):
         try:
             # Extract filename from path
-            filename = self.path[7:]  # Remove '/files/' prefix
+            filename = normalize_filename(unquote(self.path[7:], encoding='utf-8'))  # Remove '/files/' prefix
+            if not filename:
+                self.send_error(400, "Invalid filename")
+                return
             file_path = os.path.join(UPLOAD_DIR, filename)
             
             if not os.path.exists(file_path):
                 self.send_error(404, "File not found")
                 return
             
+            # Only open-ended ranges, which is what a receiver skipping cached chunks asks for
+            size = os.path.getsize(file_path)
+            match = re.fullmatch(r'bytes=(\d+)-', self.headers.get('Range', ''))
+            start = int(match.group(1)) if match else 0
+            if match and start >= size:
+                self.send_response(416)
+                self.send_header('Content-Range', f'bytes */{size}')
+                self.end_headers()
+                return
+            
             # Send file
-            self.send_response(200)
+            self.send_response(206 if match else 200)
             self.send_header('Content-Type', 'application/octet-stream')
-            self.send_header('Content-Length', str(os.path.getsize(file_path)))
-            self.send_header('Content-Disposition', f'attachment; filename="{filename}"')
+            self.send_header('Content-Length', str(size - start))
+            self.send_header('Accept-Ranges', 'bytes')
+            if match:
+                self.send_header('Content-Range', f'bytes {start}-{size - 1}/{size}')
+            self.send_header('Content-Disposition', content_disposition(filename))
             self.send_header('Access-Control-Allow-Origin', '*')
             self.end_headers()
             
             with open(file_path, 'rb') as f:
+                f.seek(start)
                 shutil.copyfileobj(f, self.wfile)
             
             print(f"Downloaded file: {filename}")
@@ -139,6 +210,23 @@ class ShrLinkHandler(BaseHTTPRequestHandler):
             print(f"Download error: {e}")
             self.send_error(500, f"Download failed: {e}")
     
+    def handle_delete(self):
+        """Remove a file, e.g. what is left of an upload the client cancelled"""
+        filename = normalize_filename(unquote(self.path[7:], encoding='utf-8'))  # Remove '/files/' prefix
+        if not filename:
+            self.send_error(400, "Invalid filename")
+            return
+        file_path = os.path.join(UPLOAD_DIR, filename)
+        
+        if not os.path.isfile(file_path):
+            self.send_error(404, "File not found")
+            return
+        
+        os.remove(file_path)
+        print(f"Deleted file: {filename}")
+        self.send_response(204)
+        self.end_headers()
+    
     def handle_cleanup(self):
         try:
             # Parse JSON request
@@ -0,0 +1,213 @@
+use crate::{Result, ShrLinkError};
+
+const HAS_MODIFIED: u8 = 0x01;
+const HAS_MODE: u8 = 0x02;
+const IS_DIR: u8 = 0x04;
+// The target follows the name as a u16 length and UTF-8 bytes
+const IS_SYMLINK: u8 = 0x08;
+// A u64 block size follows everything else, so older receivers skip it
+const HAS_BLOCK_SIZE: u8 = 0x10;
+// size + flags + modified + mode + name length
+const FIXED_SIZE: usize = 8 + 1 + 8 + 4 + 2;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum EntryKind {
+    #[default]
+    File,
+    // Carries no chunks; sent so empty directories and their modes survive
+    Dir,
+    // The target as the sender read it, recreated verbatim and never resolved
+    Symlink(String),
+}
+
+// What the receiver needs to recreate the file as it was, beyond its bytes
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileMeta {
+    // As the sender saw it; never trusted as a path on the receiving side
+    pub name: Option<String>,
+    pub size: u64,
+    // Seconds since the Unix epoch
+    pub modified: Option<i64>,
+    // Unix permission bits
+    pub mode: Option<u32>,
+    pub kind: EntryKind,
+    // What the sender cut the file into, every chunk but the last; None with CDC and from
+    // older senders
+    pub block_size: Option<u64>,
+}
+
+impl FileMeta {
+    #[cfg(feature = "fs")]
+    pub fn from_path(path: &std::path::Path) -> Result<Self> {
+        let metadata = std::fs::metadata(path)?;
+        let name = path.file_name().map(|n| n.to_string_lossy().into_owned());
+        let kind = if metadata.is_dir() { EntryKind::Dir } else { EntryKind::File };
+        Ok(Self::from_metadata(name, &metadata, kind))
+    }
+
+    // Only files have a size; a directory's length is whatever the filesystem reports for it
+    #[cfg(feature = "fs")]
+    pub fn from_metadata(name: Option<String>, metadata: &std::fs::Metadata, kind: EntryKind) -> Self {
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|m| m.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|d| d.as_secs() as i64);
+
+        #[cfg(unix)]
+        let mode = Some(std::os::unix::fs::PermissionsExt::mode(&metadata.permissions()) & 0o7777);
+        #[cfg(not(unix))]
+        let mode = None;
+
+        Self {
+            name,
+            size: if kind == EntryKind::File { metadata.len() } else { 0 },
+            modified,
+            mode,
+            kind,
+            block_size: None,
+        }
+    }
+
+    // Best effort: the bytes are what matter, so callers usually just log a failure here
+    #[cfg(feature = "fs")]
+    pub fn apply(&self, path: &std::path::Path) -> Result<()> {
+        // Either would act on the link's target
+        if let EntryKind::Symlink(_) = self.kind {
+            return Ok(());
+        }
+
+        if let Some(secs) = self.modified.filter(|s| *s >= 0) {
+            let modified = std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs as u64);
+            // Directories can't be opened for writing; owning them is enough to set the time
+            let handle = match self.kind {
+                EntryKind::Dir => std::fs::File::open(path)?,
+                _ => std::fs::File::options().write(true).open(path)?,
+            };
+            handle.set_modified(modified)?;
+        }
+
+        // Only permission bits, never setuid/setgid/sticky from someone else's machine
+        #[cfg(unix)]
+        if let Some(mode) = self.mode {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o777))?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn encode(&self) -> Result<Vec<u8>> {
+        let name = self.name.as_deref().unwrap_or_default().as_bytes();
+        let name_len = u16::try_from(name.len())
+            .map_err(|_| ShrLinkError::InvalidInput("File name is too long for a bundle".to_string()))?;
+
+        let mut flags = 0;
+        if self.modified.is_some() {
+            flags |= HAS_MODIFIED;
+        }
+        if self.mode.is_some() {
+            flags |= HAS_MODE;
+        }
+        if self.block_size.is_some() {
+            flags |= HAS_BLOCK_SIZE;
+        }
+        match self.kind {
+            EntryKind::File => {}
+            EntryKind::Dir => flags |= IS_DIR,
+            EntryKind::Symlink(_) => flags |= IS_SYMLINK,
+        }
+
+        let mut body = Vec::with_capacity(FIXED_SIZE + name.len());
+        body.extend_from_slice(&self.size.to_le_bytes());
+        body.push(flags);
+        body.extend_from_slice(&self.modified.unwrap_or_default().to_le_bytes());
+        body.extend_from_slice(&self.mode.unwrap_or_default().to_le_bytes());
+        body.extend_from_slice(&name_len.to_le_bytes());
+        body.extend_from_slice(name);
+        if let EntryKind::Symlink(target) = &self.kind {
+            let target_len = u16::try_from(target.len())
+                .map_err(|_| ShrLinkError::InvalidInput("Symlink target is too long for a bundle".to_string()))?;
+            body.extend_from_slice(&target_len.to_le_bytes());
+            body.extend_from_slice(target.as_bytes());
+        }
+        if let Some(block_size) = self.block_size {
+            body.extend_from_slice(&block_size.to_le_bytes());
+        }
+        Ok(body)
+    }
+
+    // Bytes past the known fields are ignored, so later versions can append to the body
+    pub(crate) fn decode(body: &[u8]) -> Result<Self> {
+        let too_short = || ShrLinkError::InvalidInput("Bundle file metadata is truncated".to_string());
+        let fixed = body.get(..FIXED_SIZE).ok_or_else(too_short)?;
+
+        let size = u64::from_le_bytes(fixed[0..8].try_into().unwrap());
+        let flags = fixed[8];
+        let modified = i64::from_le_bytes(fixed[9..17].try_into().unwrap());
+        let mode = u32::from_le_bytes(fixed[17..21].try_into().unwrap());
+        let name_len = u16::from_le_bytes([fixed[21], fixed[22]]) as usize;
+
+        let name = body.get(FIXED_SIZE..FIXED_SIZE + name_len).ok_or_else(too_short)?;
+        let name = std::str::from_utf8(name)
+            .map_err(|_| ShrLinkError::InvalidInput("Bundle file name is not valid UTF-8".to_string()))?;
+
+        let mut rest = &body[FIXED_SIZE + name_len..];
+        let kind = if flags & IS_SYMLINK != 0 {
+            let target_len = rest.get(..2).map(|b| u16::from_le_bytes([b[0], b[1]]) as usize).ok_or_else(too_short)?;
+            let target = rest.get(2..2 + target_len).ok_or_else(too_short)?;
+            let target = std::str::from_utf8(target)
+                .map_err(|_| ShrLinkError::InvalidInput("Bundle symlink target is not valid UTF-8".to_string()))?;
+            rest = &rest[2 + target_len..];
+            EntryKind::Symlink(target.to_string())
+        } else if flags & IS_DIR != 0 {
+            EntryKind::Dir
+        } else {
+            EntryKind::File
+        };
+
+        let block_size = match flags & HAS_BLOCK_SIZE {
+            0 => None,
+            _ => Some(u64::from_le_bytes(rest.get(..8).ok_or_else(too_short)?.try_into().unwrap())),
+        };
+
+        Ok(Self {
+            name: (!name.is_empty()).then(|| name.to_string()),
+            size,
+            modified: (flags & HAS_MODIFIED != 0).then_some(modified),
+            mode: (flags & HAS_MODE != 0).then_some(mode),
+            kind,
+            block_size,
+        })
+    }
+}
+
+#[cfg(all(test, feature = "fs", unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_from_path_and_apply() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("script.sh");
+        std::fs::write(&source, b"#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&source, std::fs::Permissions::from_mode(0o750)).unwrap();
+        let an_hour_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(3600);
+        std::fs::File::options().write(true).open(&source).unwrap().set_modified(an_hour_ago).unwrap();
+
+        let meta = FileMeta::from_path(&source).unwrap();
+        assert_eq!(meta.name.as_deref(), Some("script.sh"));
+        assert_eq!(meta.size, 10);
+        assert_eq!(meta.mode, Some(0o750));
+
+        let target = dir.path().join("copy");
+        std::fs::write(&target, b"#!/bin/sh\n").unwrap();
+        FileMeta { mode: Some(0o4755), ..meta.clone() }.apply(&target).unwrap();
+
+        let applied = FileMeta::from_path(&target).unwrap();
+        assert_eq!(applied.modified, meta.modified);
+        // setuid is dropped
+        assert_eq!(applied.mode, Some(0o755));
+    }
+}
@@ -0,0 +1,1156 @@
+use std::io::Write;
+use crate::compression::{dict, ChecksumAlgorithm, CompressedChunk, CompressionAlgorithm, CompressionResult, Dictionary};
+use crate::{Result, ShrLinkError};
+
+pub mod meta;
+pub mod parity;
+pub mod reader;
+#[cfg(feature = "fs")]
+pub mod walk;
+mod window;
+pub mod wire;
+
+pub use meta::{EntryKind, FileMeta};
+pub use parity::{Parity, ParityChunk, ParityMember};
+pub use reader::{ChunkHeader, Decoded, FrameDecoder, ShrBundleReader};
+pub use window::DEDUP_WINDOW;
+pub use wire::{decode_chunk, encode_chunk};
+
+pub const MAGIC_V1: &[u8; 4] = b"SHR\x01";
+pub const MAGIC_V2: &[u8; 4] = b"SHR\x02";
+// v2 frames plus FRAME_CHUNK_REF, so older receivers stop at the header rather than partway in
+pub const MAGIC_V3: &[u8; 4] = b"SHR\x03";
+// v3 with chunk sizes widened to u64, so no chunk size can be silently truncated
+pub const MAGIC_V4: &[u8; 4] = b"SHR\x04";
+// v4 plus FRAME_XXH3 and FRAME_CHUNK_ZERO, so a receiver that would check an XXH3 hash as
+// BLAKE3 never sees one
+pub const MAGIC_V5: &[u8; 4] = b"SHR\x05";
+// v5 plus FRAME_PARITY
+pub const MAGIC_V6: &[u8; 4] = b"SHR\x06";
+// v6 plus FRAME_DICTIONARY, so a receiver that would read dictionary-compressed chunks without
+// it stops at the header
+pub const MAGIC_V7: &[u8; 4] = b"SHR\x07";
+// v7 plus FRAME_CHUNK_GZIP, FRAME_CHUNK_SNAPPY and the codec ids kept free after them
+pub const MAGIC_V8: &[u8; 4] = b"SHR\x08";
+
+const FRAME_END: u8 = 0x00;
+const FRAME_CHUNK: u8 = 0x01;
+// Same layout as FRAME_CHUNK; only the compression of the data differs. lz4 keeps the original
+// tag so those bundles are unchanged and still readable by older receivers
+const FRAME_CHUNK_ZSTD: u8 = 0x02;
+const FRAME_CHUNK_STORED: u8 = 0x03;
+// A chunk whose data is already in the bundle under the same hash: just its index and that hash
+const FRAME_CHUNK_REF: u8 = 0x04;
+// Same layout again with no data at all: the chunk is `original_size` zeros
+const FRAME_CHUNK_ZERO: u8 = 0x05;
+// Same layout as FRAME_CHUNK again. A chunk frame's tag is its codec id, and the ids up to
+// FRAME_CHUNK_LAST are kept for codecs yet to come: a receiver that doesn't know one still
+// reads the frame, and only fails if it has to decompress the chunk
+const FRAME_CHUNK_GZIP: u8 = 0x06;
+const FRAME_CHUNK_SNAPPY: u8 = 0x07;
+const FRAME_CHUNK_LAST: u8 = 0x0f;
+// Set in a chunk frame's tag when its hash is XXH3 rather than BLAKE3. A reference takes the
+// checksum of the chunk it points at
+const FRAME_XXH3: u8 = 0x20;
+// Length-prefixed FileMeta. Each one starts a new file, whose chunks are the frames up to the
+// next one, so a bundle can carry several files
+const FRAME_META: u8 = 0x10;
+// BLAKE3 of the current file's original bytes. It follows the file's last chunk, since a
+// streamed file's hash isn't known until all of it has been read, and nothing more of the
+// file may come after it
+const FRAME_FILE_HASH: u8 = 0x11;
+// Reed-Solomon parity over a group of the current file's chunks: a u64 length, then the row,
+// a u16 count of the chunks covered, each one's chunk header plus its tag and a checksum of its
+// data, and the parity data itself. A BLAKE3 of all that follows, so damaged parity is dropped
+// rather than used to "repair" good chunks. It comes after the group's last chunk
+const FRAME_PARITY: u8 = 0x12;
+// A u32 length and a zstd dictionary that every zstd chunk in the bundle was compressed
+// against. At most one, and only as the very first frame
+const FRAME_DICTIONARY: u8 = 0x13;
+const CHUNK_META_SIZE: usize = 4 + 8 + 8 + 32; // index + original_size + compressed_size + hash
+// v1 to v3, with both sizes as u32
+const CHUNK_META_SIZE_V1: usize = 4 + 4 + 4 + 32;
+const CHUNK_REF_SIZE: usize = 4 + 32; // index + hash
+const PARITY_MEMBER_SIZE: usize = CHUNK_META_SIZE + 1 + 32; // chunk header + tag + data checksum
+
+// v2 bundles are a sequence of self-describing chunk frames closed by an end marker, so they
+// can be produced while later chunks are still being compressed
+pub struct BundleWriter<W: Write> {
+    writer: W,
+    frames: FrameEncoder,
+    chunks_written: usize,
+}
+
+impl<W: Write> BundleWriter<W> {
+    pub fn new(mut writer: W) -> Result<Self> {
+        writer.write_all(&header())?;
+        Ok(Self {
+            writer,
+            frames: FrameEncoder::default(),
+            chunks_written: 0,
+        })
+    }
+
+    // Adds parity chunks after every group of chunks, and after the last chunks of each file
+    pub fn with_parity(mut self, parity: Parity) -> Self {
+        self.frames = std::mem::take(&mut self.frames).with_parity(parity);
+        self
+    }
+
+    pub fn write_meta(&mut self, meta: &FileMeta) -> Result<()> {
+        self.writer.write_all(&self.frames.encode(&BundleItem::File(meta.clone()))?)?;
+        Ok(())
+    }
+
+    pub fn write_item(&mut self, item: &BundleItem) -> Result<()> {
+        match item {
+            BundleItem::File(meta) => self.write_meta(meta),
+            BundleItem::Chunk(chunk) => self.write_chunk(chunk),
+            BundleItem::FileHash(hash) => self.write_file_hash(hash),
+            item => {
+                self.writer.write_all(&self.frames.encode(item)?)?;
+                Ok(())
+            }
+        }
+    }
+
+    pub fn write_file_hash(&mut self, hash: &[u8; 32]) -> Result<()> {
+        self.writer.write_all(&self.frames.encode(&BundleItem::FileHash(*hash))?)?;
+        Ok(())
+    }
+
+    pub fn write_chunk(&mut self, chunk: &CompressedChunk) -> Result<()> {
+        self.writer.write_all(&self.frames.encode_chunk(chunk)?)?;
+        self.chunks_written += 1;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<W> {
+        self.writer.write_all(&self.frames.flush()?)?;
+        self.writer.write_all(&trailer())?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    pub fn chunks_written(&self) -> usize {
+        self.chunks_written
+    }
+}
+
+pub fn header() -> Vec<u8> {
+    MAGIC_V8.to_vec()
+}
+
+pub fn trailer() -> Vec<u8> {
+    vec![FRAME_END]
+}
+
+// Turns items into frames, writing each distinct chunk's data once. Later chunks with the same
+// hash, in the same file or another, become references to it while it's still in the
+// reference window; VM images and logs full of repeated blocks shrink to little more than
+// their distinct chunks
+#[derive(Default)]
+pub struct FrameEncoder {
+    window: window::Window<()>,
+    parity: Option<parity::GroupEncoder>,
+}
+
+impl FrameEncoder {
+    // Every chunk is then written in full: a reference to a frame that was lost couldn't be
+    // resolved, and the reader's window would no longer match the writer's
+    pub fn with_parity(mut self, parity: Parity) -> Self {
+        self.parity = Some(parity::GroupEncoder::new(parity));
+        self
+    }
+
+    // A chunk's frame may be followed by its group's parity; anything else ends the group first
+    pub fn encode(&mut self, item: &BundleItem) -> Result<Vec<u8>> {
+        match item {
+            BundleItem::Chunk(chunk) => self.encode_chunk(chunk),
+            item => {
+                let mut frames = self.flush()?;
+                frames.extend_from_slice(&encode_item(item)?);
+                Ok(frames)
+            }
+        }
+    }
+
+    // Parity for the chunks since the last group was closed, which must come before the end
+    // marker. Nothing without parity
+    pub fn flush(&mut self) -> Result<Vec<u8>> {
+        let mut frames = Vec::new();
+        for chunk in self.parity.iter_mut().flat_map(|group| group.flush()) {
+            frames.extend_from_slice(&encode_parity_frame(&chunk)?);
+        }
+        Ok(frames)
+    }
+
+    pub fn encode_chunk(&mut self, chunk: &CompressedChunk) -> Result<Vec<u8>> {
+        if let Some(group) = &mut self.parity {
+            let mut frames = encode_frame(chunk)?;
+            for parity in group.push(chunk) {
+                frames.extend_from_slice(&encode_parity_frame(&parity)?);
+            }
+            return Ok(frames);
+        }
+
+        if self.window.touch(&chunk.hash).is_none() {
+            self.window.insert(chunk.hash, chunk.data.len(), || ());
+            return encode_frame(chunk);
+        }
+
+        let mut frame = Vec::with_capacity(1 + CHUNK_REF_SIZE);
+        frame.push(FRAME_CHUNK_REF);
+        frame.extend_from_slice(&chunk_index(chunk)?.to_le_bytes());
+        frame.extend_from_slice(&chunk.hash);
+        Ok(frame)
+    }
+}
+
+// Every chunk in full; a `FrameEncoder` is what dedups
+pub fn encode_item(item: &BundleItem) -> Result<Vec<u8>> {
+    match item {
+        BundleItem::File(meta) => encode_meta_frame(meta),
+        BundleItem::Chunk(chunk) => encode_frame(chunk),
+        BundleItem::FileHash(hash) => Ok(encode_file_hash_frame(hash)),
+        BundleItem::Parity(parity) => encode_parity_frame(parity),
+        BundleItem::Dictionary(dictionary) => Ok(encode_dictionary_frame(dictionary)),
+    }
+}
+
+pub fn encode_dictionary_frame(dictionary: &Dictionary) -> Vec<u8> {
+    let data = dictionary.as_bytes();
+    let mut frame = Vec::with_capacity(5 + data.len());
+    frame.push(FRAME_DICTIONARY);
+    frame.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    frame.extend_from_slice(data);
+    frame
+}
+
+pub fn encode_parity_frame(parity: &ParityChunk) -> Result<Vec<u8>> {
+    let row = u8::try_from(parity.row).ok().filter(|_| parity.is_valid());
+    let (Some(row), Ok(count)) = (row, u16::try_from(parity.members.len())) else {
+        return Err(ShrLinkError::InvalidInput(format!("Parity row {} doesn't fit its group", parity.row)));
+    };
+
+    let mut body = Vec::with_capacity(3 + parity.members.len() * PARITY_MEMBER_SIZE + parity.data.len());
+    body.push(row);
+    body.extend_from_slice(&count.to_le_bytes());
+    for member in &parity.members {
+        body.extend_from_slice(&u32::try_from(member.index).map_err(|_| {
+            ShrLinkError::InvalidInput(format!("Chunk index {} is too large for a bundle", member.index))
+        })?.to_le_bytes());
+        body.extend_from_slice(&(member.original_size as u64).to_le_bytes());
+        body.extend_from_slice(&(member.compressed_size as u64).to_le_bytes());
+        body.extend_from_slice(&member.hash);
+        body.push(chunk_tag(member.algorithm, member.checksum));
+        body.extend_from_slice(&member.data_hash);
+    }
+    body.extend_from_slice(&parity.data);
+
+    let mut frame = Vec::with_capacity(1 + 8 + body.len() + 32);
+    frame.push(FRAME_PARITY);
+    frame.extend_from_slice(&(body.len() as u64).to_le_bytes());
+    frame.extend_from_slice(&body);
+    frame.extend_from_slice(blake3::hash(&body).as_bytes());
+    Ok(frame)
+}
+
+pub fn encode_file_hash_frame(hash: &[u8; 32]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(1 + hash.len());
+    frame.push(FRAME_FILE_HASH);
+    frame.extend_from_slice(hash);
+    frame
+}
+
+pub fn encode_meta_frame(meta: &FileMeta) -> Result<Vec<u8>> {
+    let body = meta.encode()?;
+    let mut frame = Vec::with_capacity(5 + body.len());
+    frame.push(FRAME_META);
+    frame.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&body);
+    Ok(frame)
+}
+
+pub fn encode_frame(chunk: &CompressedChunk) -> Result<Vec<u8>> {
+    let mut frame = Vec::with_capacity(1 + CHUNK_META_SIZE + chunk.data.len());
+    frame.push(chunk_tag(chunk.algorithm, chunk.checksum));
+    write_chunk_meta(&mut frame, chunk)?;
+    frame.extend_from_slice(&chunk.data);
+    Ok(frame)
+}
+
+// What a streamed bundle is built from: a file's metadata, its chunks and then its whole-file
+// hash, file by file, after the bundle's dictionary if it has one. Parity only comes out of
+// readers; writers add their own
+#[derive(Debug, Clone)]
+pub enum BundleItem {
+    File(FileMeta),
+    Chunk(CompressedChunk),
+    FileHash([u8; 32]),
+    Parity(ParityChunk),
+    Dictionary(Dictionary),
+}
+
+#[derive(Debug, Clone)]
+pub struct BundleEntry {
+    // None for v1 bundles and v2 bundles written without it
+    pub meta: Option<FileMeta>,
+    // Indexed from 0 within this entry
+    pub chunks: Vec<CompressedChunk>,
+    // Absent from bundles written before whole-file hashes, and for directories and symlinks
+    pub file_hash: Option<[u8; 32]>,
+}
+
+// Always at least one entry; only bundles written with metadata can hold more
+#[derive(Debug, Clone)]
+pub struct Bundle {
+    pub entries: Vec<BundleEntry>,
+    // What the zstd chunks need to decompress, when they were compressed against one
+    pub dictionary: Option<Dictionary>,
+}
+
+impl Bundle {
+    pub fn single(meta: Option<FileMeta>, chunks: Vec<CompressedChunk>) -> Self {
+        Self { entries: vec![BundleEntry { meta, chunks, file_hash: None }], dictionary: None }
+    }
+
+    // Items in the order a bundle holds them, as a reader hands them out. Chunks that parity
+    // says are damaged or missing are rebuilt, or it's an error if there's too much damage
+    pub fn from_items<I: IntoIterator<Item = BundleItem>>(items: I) -> Result<Self> {
+        let mut builder = BundleBuilder::default();
+        for item in items {
+            builder.push(item);
+        }
+        builder.finish()
+    }
+
+    // For callers that only deal in single files; a multi-file bundle is an error here, as is
+    // one whose chunks couldn't be read without its dictionary
+    pub fn into_single_file(mut self) -> Result<Vec<CompressedChunk>> {
+        if self.entries.len() > 1 {
+            return Err(ShrLinkError::InvalidInput(format!(
+                "Bundle holds {} files, not one",
+                self.entries.len()
+            )));
+        }
+        if self.dictionary.is_some() {
+            return Err(ShrLinkError::InvalidInput("Bundle's chunks need its compression dictionary".to_string()));
+        }
+        Ok(self.entries.pop().map(|e| e.chunks).unwrap_or_default())
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        self.entries.iter().map(|e| e.chunks.len()).sum()
+    }
+}
+
+pub fn create_shr_bundle(chunks: &[CompressedChunk]) -> Result<Vec<u8>> {
+    write_bundle(&[(None, chunks, None)], Vec::with_capacity(bundle_size(chunks)))
+}
+
+// The same bytes as `create_shr_bundle`, written out frame by frame
+pub fn write_shr_bundle<W: Write>(chunks: &[CompressedChunk], writer: W) -> Result<W> {
+    write_bundle(&[(None, chunks, None)], writer)
+}
+
+pub fn create_shr_bundle_with_meta(meta: &FileMeta, chunks: &[CompressedChunk]) -> Result<Vec<u8>> {
+    write_bundle(&[(Some(meta), chunks, None)], Vec::with_capacity(bundle_size(chunks)))
+}
+
+// The metadata takes the block size the result was cut to
+pub fn create_shr_bundle_from_result(meta: Option<&FileMeta>, result: &CompressionResult) -> Result<Vec<u8>> {
+    let meta = meta.map(|m| FileMeta { block_size: result.block_size.map(|size| size as u64), ..m.clone() });
+    write_bundle(&[(meta.as_ref(), &result.chunks, Some(&result.file_hash))], Vec::with_capacity(bundle_size(&result.chunks)))
+}
+
+pub fn create_shr_archive(entries: &[BundleEntry]) -> Result<Vec<u8>> {
+    if entries.iter().any(|e| e.meta.is_none()) {
+        return Err(ShrLinkError::InvalidInput("Every file in a multi-file bundle needs metadata".to_string()));
+    }
+
+    let size = entries.iter().map(|e| bundle_size(&e.chunks)).sum();
+    let entries: Vec<_> = entries.iter().map(|e| (e.meta.as_ref(), e.chunks.as_slice(), e.file_hash.as_ref())).collect();
+    write_bundle(&entries, Vec::with_capacity(size))
+}
+
+// Metadata, chunks and whole-file hash of one entry, each optional but the chunks
+type EntryParts<'a> = (Option<&'a FileMeta>, &'a [CompressedChunk], Option<&'a [u8; 32]>);
+
+// An upper bound, since repeated chunks shrink to references
+fn bundle_size(chunks: &[CompressedChunk]) -> usize {
+    chunks.iter().map(|c| 1 + CHUNK_META_SIZE + c.data.len()).sum::<usize>() + 8
+}
+
+fn write_bundle<W: Write>(entries: &[EntryParts<'_>], writer: W) -> Result<W> {
+    let mut writer = BundleWriter::new(writer)?;
+
+    for (meta, chunks, file_hash) in entries {
+        match meta {
+            Some(meta) => writer.write_meta(meta)?,
+            // Otherwise there'd be nothing to say the file is empty rather than missing its chunks
+            None if chunks.is_empty() => writer.write_meta(&FileMeta::default())?,
+            None => {}
+        }
+        for chunk in chunks.iter() {
+            writer.write_chunk(chunk)?;
+        }
+        if let Some(file_hash) = file_hash {
+            writer.write_file_hash(file_hash)?;
+        }
+    }
+
+    writer.finish()
+}
+
+// For callers that only deal in single files; a multi-file bundle is an error here
+pub fn parse_shr_bundle(bundle: &[u8]) -> Result<Vec<CompressedChunk>> {
+    parse_bundle(bundle)?.into_single_file()
+}
+
+pub fn parse_bundle(bundle: &[u8]) -> Result<Bundle> {
+    let mut decoder = FrameDecoder::default();
+    let mut builder = BundleBuilder::default();
+    let mut offset = 0;
+
+    loop {
+        let (used, decoded) = decoder.decode(&bundle[offset..])?;
+        offset += used;
+        match decoded {
+            Some(reader::Decoded::Item(item)) => builder.push(item),
+            Some(reader::Decoded::End) => break,
+            None if offset < 4 => return Err(ShrLinkError::InvalidInput("Invalid SHR bundle format".to_string())),
+            None => return Err(ShrLinkError::InvalidInput("Bundle truncated before end marker".to_string())),
+        }
+    }
+
+    builder.finish()
+}
+
+// Items in the order they were read; each file's chunks and parity are its own
+#[derive(Default)]
+struct BundleBuilder {
+    entries: Vec<BundleEntry>,
+    parity: Vec<Vec<ParityChunk>>,
+    dictionary: Option<Dictionary>,
+}
+
+impl BundleBuilder {
+    fn push(&mut self, item: BundleItem) {
+        match item {
+            BundleItem::File(meta) => {
+                self.entries.push(BundleEntry { meta: Some(meta), chunks: Vec::new(), file_hash: None });
+                self.parity.push(Vec::new());
+                return;
+            }
+            BundleItem::Dictionary(dictionary) => {
+                self.dictionary = Some(dictionary);
+                return;
+            }
+            _ => {}
+        }
+
+        if self.entries.is_empty() {
+            self.entries.push(BundleEntry { meta: None, chunks: Vec::new(), file_hash: None });
+            self.parity.push(Vec::new());
+        }
+        let current = self.entries.len() - 1;
+        match item {
+            BundleItem::Chunk(chunk) => self.entries[current].chunks.push(chunk),
+            BundleItem::FileHash(hash) => self.entries[current].file_hash = Some(hash),
+            BundleItem::Parity(parity) => self.parity[current].push(parity),
+            BundleItem::File(_) | BundleItem::Dictionary(_) => unreachable!(),
+        }
+    }
+
+    fn finish(mut self) -> Result<Bundle> {
+        if self.entries.is_empty() {
+            self.entries.push(BundleEntry { meta: None, chunks: Vec::new(), file_hash: None });
+            self.parity.push(Vec::new());
+        }
+        for (entry, parity) in self.entries.iter_mut().zip(self.parity) {
+            entry.chunks.sort_by_key(|c| c.index);
+            if !parity.is_empty() {
+                parity::repair(&mut entry.chunks, parity)?;
+            }
+            // Only a file that says it's empty may come without chunks
+            if entry.chunks.is_empty() && entry.meta.as_ref().is_none_or(|m| m.size != 0) {
+                return Err(ShrLinkError::InvalidInput(match &entry.meta {
+                    Some(meta) => format!("Bundle describes a {} byte file but holds no chunks for it", meta.size),
+                    None => "Bundle holds no chunks and doesn't say its file is empty".to_string(),
+                }));
+            }
+        }
+        Ok(Bundle { entries: self.entries, dictionary: self.dictionary })
+    }
+}
+
+fn chunk_tag(algorithm: CompressionAlgorithm, checksum: ChecksumAlgorithm) -> u8 {
+    let tag = match algorithm {
+        CompressionAlgorithm::Lz4 => FRAME_CHUNK,
+        CompressionAlgorithm::Zstd => FRAME_CHUNK_ZSTD,
+        CompressionAlgorithm::Stored => FRAME_CHUNK_STORED,
+        CompressionAlgorithm::Zero => FRAME_CHUNK_ZERO,
+        CompressionAlgorithm::Gzip => FRAME_CHUNK_GZIP,
+        CompressionAlgorithm::Snappy => FRAME_CHUNK_SNAPPY,
+        // Only ever read from a frame, so already one of the kept ids
+        CompressionAlgorithm::Unknown(id) => id,
+    };
+    match checksum {
+        ChecksumAlgorithm::Blake3 => tag,
+        ChecksumAlgorithm::Xxh3 => tag | FRAME_XXH3,
+    }
+}
+
+fn chunk_index(chunk: &CompressedChunk) -> Result<u32> {
+    u32::try_from(chunk.index)
+        .map_err(|_| ShrLinkError::InvalidInput(format!("Chunk index {} is too large for a bundle", chunk.index)))
+}
+
+fn write_chunk_meta(out: &mut Vec<u8>, chunk: &CompressedChunk) -> Result<()> {
+    out.extend_from_slice(&chunk_index(chunk)?.to_le_bytes());
+    out.extend_from_slice(&(chunk.original_size as u64).to_le_bytes());
+    out.extend_from_slice(&(chunk.data.len() as u64).to_le_bytes());
+    out.extend_from_slice(&chunk.hash);
+    Ok(())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> usize {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]]) as usize
+}
+
+// Sizes a 32-bit receiver can't address are refused rather than truncated
+fn read_u64(bytes: &[u8], offset: usize) -> Result<usize> {
+    let value = u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+    usize::try_from(value)
+        .map_err(|_| ShrLinkError::InvalidInput(format!("Bundle chunk of {} bytes is too large for this platform", value)))
+}
+
+// `wide` is the v4 layout; `bytes` must hold the whole record for its version
+fn read_chunk_meta(bytes: &[u8], offset: usize, wide: bool) -> Result<(usize, usize, usize, [u8; 32])> {
+    let index = read_u32(bytes, offset);
+    let (original_size, compressed_size, hash_at) = if wide {
+        (read_u64(bytes, offset + 4)?, read_u64(bytes, offset + 12)?, offset + 20)
+    } else {
+        (read_u32(bytes, offset + 4), read_u32(bytes, offset + 8), offset + 12)
+    };
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(&bytes[hash_at..hash_at + 32]);
+
+    Ok((index, original_size, compressed_size, hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::ParallelCompressor;
+
+    fn sample_chunks() -> Vec<CompressedChunk> {
+        let compressor = ParallelCompressor::default();
+        (0..3)
+            .map(|i| compressor.compress_chunk(i, format!("chunk {}", i).repeat(100).into_bytes()).unwrap())
+            .collect()
+    }
+
+    // The layout written before framed bundles existed
+    fn create_v1_bundle(chunks: &[CompressedChunk]) -> Vec<u8> {
+        let mut bundle = MAGIC_V1.to_vec();
+        bundle.extend_from_slice(&(chunks.len() as u32).to_le_bytes());
+        for chunk in chunks {
+            write_narrow_meta(&mut bundle, chunk);
+        }
+        for chunk in chunks {
+            bundle.extend_from_slice(&chunk.data);
+        }
+        bundle
+    }
+
+    fn write_narrow_meta(out: &mut Vec<u8>, chunk: &CompressedChunk) {
+        out.extend_from_slice(&(chunk.index as u32).to_le_bytes());
+        out.extend_from_slice(&(chunk.original_size as u32).to_le_bytes());
+        out.extend_from_slice(&(chunk.data.len() as u32).to_le_bytes());
+        out.extend_from_slice(&chunk.hash);
+    }
+
+    #[test]
+    fn test_v1_bundles_still_parse() {
+        let chunks = sample_chunks();
+        let parsed = parse_shr_bundle(&create_v1_bundle(&chunks)).unwrap();
+
+        assert_eq!(parsed.len(), chunks.len());
+        for (a, b) in parsed.iter().zip(&chunks) {
+            assert_eq!(a.data, b.data);
+            assert_eq!(a.hash, b.hash);
+        }
+    }
+
+    #[test]
+    fn test_streamed_frames_match_create_shr_bundle() {
+        let chunks = sample_chunks();
+
+        // Frames written out of order still reassemble in index order
+        let mut streamed = header();
+        for chunk in chunks.iter().rev() {
+            streamed.extend_from_slice(&encode_frame(chunk).unwrap());
+        }
+        streamed.extend_from_slice(&trailer());
+
+        let parsed = parse_shr_bundle(&streamed).unwrap();
+        assert_eq!(parsed.iter().map(|c| c.index).collect::<Vec<_>>(), vec![0, 1, 2]);
+        assert_eq!(streamed.len(), create_shr_bundle(&chunks).unwrap().len());
+    }
+
+    #[test]
+    fn test_truncated_v2_bundle_rejected() {
+        let bundle = create_shr_bundle(&sample_chunks()).unwrap();
+
+        assert!(parse_shr_bundle(&bundle[..bundle.len() - 1]).is_err());
+        assert!(parse_shr_bundle(&bundle[..bundle.len() / 2]).is_err());
+        assert!(parse_shr_bundle(b"SHR").is_err());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_algorithm_survives_bundling() {
+        let lz4 = ParallelCompressor::default();
+        let zstd = ParallelCompressor::default().with_algorithm(CompressionAlgorithm::Zstd);
+        let chunks = vec![
+            lz4.compress_chunk(0, b"lz4 chunk".repeat(50)).unwrap(),
+            zstd.compress_chunk(1, b"zstd chunk".repeat(50)).unwrap(),
+        ];
+
+        let bundle = create_shr_bundle(&chunks).unwrap();
+        assert_eq!(bundle[4], FRAME_CHUNK);
+
+        let parsed = parse_shr_bundle(&bundle).unwrap();
+        assert_eq!(parsed.iter().map(|c| c.algorithm).collect::<Vec<_>>(), vec![CompressionAlgorithm::Lz4, CompressionAlgorithm::Zstd]);
+        // Whichever compressor does the reading, each chunk comes back with its own codec
+        assert_eq!(lz4.decompress_chunk(&parsed[1]).unwrap(), b"zstd chunk".repeat(50));
+        assert_eq!(zstd.decompress_chunk(&parsed[0]).unwrap(), b"lz4 chunk".repeat(50));
+    }
+
+    #[test]
+    fn test_codec_ids_survive_bundling() {
+        let chunks: Vec<_> = [CompressionAlgorithm::Gzip, CompressionAlgorithm::Snappy, CompressionAlgorithm::Lz4]
+            .into_iter()
+            .enumerate()
+            .map(|(i, algorithm)| ParallelCompressor::default().with_algorithm(algorithm).compress_chunk(i, format!("codec {} ", i).repeat(100).into_bytes()).unwrap())
+            .collect();
+        let mut bundle = create_shr_bundle(&chunks).unwrap();
+        assert_eq!(&bundle[..4], MAGIC_V8);
+        assert_eq!(bundle[4], FRAME_CHUNK_GZIP);
+
+        let parsed = parse_shr_bundle(&bundle).unwrap();
+        assert_eq!(parsed.iter().map(|c| c.algorithm).collect::<Vec<_>>(), vec![CompressionAlgorithm::Gzip, CompressionAlgorithm::Snappy, CompressionAlgorithm::Lz4]);
+        for (i, chunk) in parsed.iter().enumerate() {
+            assert_eq!(ParallelCompressor::default().decompress_chunk(chunk).unwrap(), format!("codec {} ", i).repeat(100).into_bytes());
+        }
+
+        // An id no codec has yet is read, and kept through rebundling, but can't be decompressed
+        bundle[4] = 0x0c;
+        let parsed = parse_shr_bundle(&bundle).unwrap();
+        assert_eq!(parsed[0].algorithm, CompressionAlgorithm::Unknown(0x0c));
+        let err = ParallelCompressor::default().decompress_chunk(&parsed[0]).unwrap_err();
+        assert!(err.to_string().contains("codec id 12"), "{}", err);
+        assert_eq!(create_shr_bundle(&parsed).unwrap(), bundle);
+
+        // Older bundles never had them
+        bundle[..4].copy_from_slice(MAGIC_V7);
+        assert!(parse_shr_bundle(&bundle).is_err());
+    }
+
+    #[test]
+    fn test_stored_chunks_survive_bundling() {
+        let compressor = ParallelCompressor::default();
+        // Bytes from a hash chain don't compress, so this chunk is kept raw
+        let noise: Vec<u8> = (0..64u32).flat_map(|i| *blake3::hash(&i.to_le_bytes()).as_bytes()).collect();
+        let chunks = vec![
+            compressor.compress_chunk(0, b"compressible ".repeat(100)).unwrap(),
+            compressor.compress_chunk(1, noise.clone()).unwrap(),
+        ];
+        assert_eq!(chunks[1].algorithm, CompressionAlgorithm::Stored);
+
+        let parsed = parse_shr_bundle(&create_shr_bundle(&chunks).unwrap()).unwrap();
+        assert_eq!(parsed[0].algorithm, CompressionAlgorithm::Lz4);
+        assert_eq!(parsed[1].algorithm, CompressionAlgorithm::Stored);
+        assert_eq!(compressor.decompress_chunk(&parsed[1]).unwrap(), noise);
+    }
+
+    #[test]
+    fn test_checksum_survives_bundling() {
+        let xxh3 = ParallelCompressor::default().with_checksum(ChecksumAlgorithm::Xxh3);
+        let receiver = ParallelCompressor::default();
+        let chunks = vec![
+            xxh3.compress_chunk(0, b"xxh3 chunk".repeat(50)).unwrap(),
+            receiver.compress_chunk(1, b"blake3 chunk".repeat(50)).unwrap(),
+            xxh3.compress_chunk(2, b"xxh3 chunk".repeat(50)).unwrap(),
+        ];
+
+        let bundle = create_shr_bundle(&chunks).unwrap();
+        assert_eq!(bundle[4], FRAME_CHUNK | FRAME_XXH3);
+
+        // The repeat goes out as a reference and still comes back as XXH3
+        let parsed = parse_shr_bundle(&bundle).unwrap();
+        assert_eq!(parsed.iter().map(|c| c.checksum).collect::<Vec<_>>(), vec![ChecksumAlgorithm::Xxh3, ChecksumAlgorithm::Blake3, ChecksumAlgorithm::Xxh3]);
+        assert_eq!(receiver.decompress_chunk(&parsed[2]).unwrap(), b"xxh3 chunk".repeat(50));
+
+        // A v4 bundle has no flag, so the same tag is an unknown frame there
+        let mut v4 = bundle.clone();
+        v4[..4].copy_from_slice(MAGIC_V4);
+        assert!(parse_shr_bundle(&v4).is_err());
+        let mut v4 = create_shr_bundle(&chunks[1..2]).unwrap();
+        v4[..4].copy_from_slice(MAGIC_V4);
+        assert_eq!(parse_shr_bundle(&v4).unwrap()[0].checksum, ChecksumAlgorithm::Blake3);
+    }
+
+    #[test]
+    fn test_zero_chunks_survive_bundling() {
+        let compressor = ParallelCompressor::default().with_checksum(ChecksumAlgorithm::Xxh3);
+        let chunks = vec![
+            compressor.compress_chunk(0, vec![0; 1 << 20]).unwrap(),
+            compressor.compress_chunk(1, b"data".repeat(100)).unwrap(),
+            compressor.compress_chunk(2, vec![0; 12345]).unwrap(),
+        ];
+
+        let bundle = create_shr_bundle(&chunks).unwrap();
+        assert_eq!(bundle[4], FRAME_CHUNK_ZERO | FRAME_XXH3);
+        assert!(bundle.len() < 300, "bundle is {} bytes", bundle.len());
+
+        let parsed = parse_shr_bundle(&bundle).unwrap();
+        assert_eq!(parsed[0].algorithm, CompressionAlgorithm::Zero);
+        assert_eq!(parsed[2].original_size, 12345);
+        assert_eq!(ParallelCompressor::default().decompress_chunk(&parsed[2]).unwrap(), vec![0; 12345]);
+
+        let mut v4 = create_shr_bundle(&chunks[2..]).unwrap();
+        v4[..4].copy_from_slice(MAGIC_V4);
+        v4[4] &= !FRAME_XXH3;
+        assert!(parse_shr_bundle(&v4).is_err());
+    }
+
+    fn sample_meta(name: &str) -> FileMeta {
+        FileMeta { name: Some(name.to_string()), size: 2100, modified: Some(1_700_000_000), mode: Some(0o640), ..Default::default() }
+    }
+
+    #[test]
+    fn test_meta_roundtrip() {
+        let chunks = sample_chunks();
+        for name in ["report.pdf", "Grüße – 報告書 🚀.tar.gz"] {
+            let meta = sample_meta(name);
+            let parsed = parse_bundle(&create_shr_bundle_with_meta(&meta, &chunks).unwrap()).unwrap();
+            assert_eq!(parsed.entries.len(), 1);
+            assert_eq!(parsed.entries[0].meta, Some(meta));
+            assert_eq!(parsed.entries[0].chunks.len(), chunks.len());
+        }
+
+        let anonymous = FileMeta { size: 7, ..Default::default() };
+        let parsed = parse_bundle(&create_shr_bundle_with_meta(&anonymous, &chunks).unwrap()).unwrap();
+        assert_eq!(parsed.entries[0].meta, Some(anonymous));
+
+        assert!(parse_bundle(&create_shr_bundle(&chunks).unwrap()).unwrap().entries[0].meta.is_none());
+        assert!(parse_bundle(&create_v1_bundle(&chunks)).unwrap().entries[0].meta.is_none());
+        assert_eq!(parse_bundle(&create_shr_bundle(&[]).unwrap()).unwrap().entries.len(), 1);
+
+        for kind in [EntryKind::Dir, EntryKind::Symlink("../shared/ü.txt".to_string())] {
+            let meta = FileMeta { kind, size: 0, ..sample_meta("link") };
+            let parsed = parse_bundle(&create_shr_bundle_with_meta(&meta, &[]).unwrap()).unwrap();
+            assert_eq!(parsed.entries[0].meta, Some(meta));
+        }
+    }
+
+    #[test]
+    fn test_empty_bundles_must_say_so() {
+        // An empty file round-trips, with metadata saying it's empty written for it
+        let parsed = parse_bundle(&create_shr_bundle(&[]).unwrap()).unwrap();
+        assert_eq!(parsed.entries[0].meta.as_ref().unwrap().size, 0);
+        assert!(parse_shr_bundle(&create_shr_bundle(&[]).unwrap()).unwrap().is_empty());
+        let result = ParallelCompressor::default().compress_bytes(&[]).unwrap();
+        let parsed = parse_bundle(&create_shr_bundle_from_result(None, &result).unwrap()).unwrap();
+        assert_eq!(parsed.entries[0].file_hash, Some(*blake3::hash(b"").as_bytes()));
+
+        // Without that, no chunks could as well be chunks gone missing
+        let mut bare = header();
+        bare.extend_from_slice(&trailer());
+        assert!(parse_bundle(&bare).is_err());
+        assert!(parse_bundle(&create_v1_bundle(&[])).is_err());
+        let err = parse_bundle(&create_shr_bundle_with_meta(&sample_meta("a.txt"), &[]).unwrap()).unwrap_err();
+        assert!(err.to_string().contains("holds no chunks"), "{}", err);
+
+        // Nor does it excuse an empty file in the middle of an archive
+        let archive = create_shr_archive(&[
+            BundleEntry { meta: Some(sample_meta("a.txt")), chunks: sample_chunks(), file_hash: None },
+            BundleEntry { meta: Some(sample_meta("b.txt")), chunks: Vec::new(), file_hash: None },
+        ])
+        .unwrap();
+        assert!(parse_bundle(&archive).is_err());
+    }
+
+    #[test]
+    fn test_meta_carries_block_size() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        let result = ParallelCompressor::new(4096, 1).unwrap().compress_bytes(&data).unwrap();
+        let parsed = parse_bundle(&create_shr_bundle_from_result(Some(&sample_meta("a.bin")), &result).unwrap()).unwrap();
+        assert_eq!(parsed.entries[0].meta.as_ref().unwrap().block_size, Some(4096));
+
+        let link = FileMeta { kind: EntryKind::Symlink("b.bin".to_string()), block_size: Some(1 << 40), ..sample_meta("link") };
+        assert_eq!(FileMeta::decode(&link.encode().unwrap()).unwrap(), link);
+
+        // Receivers that predate it see the flag as unknown and the size as trailing bytes
+        let mut body = FileMeta { block_size: Some(4096), ..sample_meta("a.bin") }.encode().unwrap();
+        body[8] &= !0x10;
+        assert_eq!(FileMeta::decode(&body).unwrap(), sample_meta("a.bin"));
+    }
+
+    #[test]
+    fn test_meta_frame_layout_is_checked() {
+        let chunks = sample_chunks();
+        let meta_frame = encode_meta_frame(&sample_meta("a.txt")).unwrap();
+
+        // Metadata after chunks that had none is rejected
+        let mut late = header();
+        late.extend_from_slice(&encode_frame(&chunks[0]).unwrap());
+        late.extend_from_slice(&meta_frame);
+        late.extend_from_slice(&trailer());
+        assert!(parse_bundle(&late).is_err());
+
+        // A body longer than this version knows about is fine; a truncated one is not
+        let mut body = sample_meta("a.txt").encode().unwrap();
+        body.extend_from_slice(b"future fields");
+        let mut extended = header();
+        extended.push(FRAME_META);
+        extended.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        extended.extend_from_slice(&body);
+        extended.extend_from_slice(&encode_frame(&chunks[0]).unwrap());
+        extended.extend_from_slice(&trailer());
+        assert_eq!(parse_bundle(&extended).unwrap().entries[0].meta, Some(sample_meta("a.txt")));
+
+        let mut truncated = header();
+        truncated.extend_from_slice(&meta_frame[..meta_frame.len() - 2]);
+        assert!(parse_bundle(&truncated).is_err());
+    }
+
+    #[test]
+    fn test_dictionary_frame() {
+        let chunks = sample_chunks();
+        let dictionary = Dictionary::new(b"shared text ".repeat(100)).unwrap();
+        let write = |items: &[BundleItem]| {
+            let mut writer = BundleWriter::new(Vec::new()).unwrap();
+            for item in items {
+                writer.write_item(item).unwrap();
+            }
+            writer.finish().unwrap()
+        };
+
+        let first = BundleItem::Dictionary(dictionary.clone());
+        let file = BundleItem::File(sample_meta("a.txt"));
+        let bundle = write(&[first.clone(), file.clone(), BundleItem::Chunk(chunks[0].clone())]);
+        let parsed = parse_bundle(&bundle).unwrap();
+        assert_eq!(parsed.dictionary, Some(dictionary.clone()));
+        assert_eq!(parsed.entries.len(), 1);
+        assert_eq!(parsed.entries[0].chunks.len(), 1);
+        assert!(parsed.into_single_file().is_err());
+        assert!(parse_bundle(&create_shr_bundle(&chunks).unwrap()).unwrap().dictionary.is_none());
+
+        // Only ever first, and only once
+        assert!(parse_bundle(&write(&[file.clone(), first.clone()])).is_err());
+        assert!(parse_bundle(&write(&[BundleItem::Chunk(chunks[0].clone()), first.clone()])).is_err());
+        assert!(parse_bundle(&write(&[first.clone(), first.clone(), file])).is_err());
+
+        // Not in older bundles, and not larger than a dictionary may be
+        let mut v6 = bundle.clone();
+        v6[..4].copy_from_slice(MAGIC_V6);
+        assert!(parse_bundle(&v6).is_err());
+        let mut huge = header();
+        huge.push(FRAME_DICTIONARY);
+        huge.extend_from_slice(&(dict::MAX_SIZE as u32 + 1).to_le_bytes());
+        assert!(FrameDecoder::default().decode(&huge).is_err());
+    }
+
+    #[test]
+    fn test_archive_roundtrip() {
+        let chunks = sample_chunks();
+        let entries = vec![
+            BundleEntry { meta: Some(sample_meta("a.txt")), chunks: chunks.clone(), file_hash: Some([1; 32]) },
+            BundleEntry { meta: Some(FileMeta { name: Some("empty".to_string()), ..Default::default() }), chunks: Vec::new(), file_hash: None },
+            BundleEntry { meta: Some(sample_meta("docs/c.pdf")), chunks: chunks[..1].to_vec(), file_hash: Some([3; 32]) },
+        ];
+        let archive = create_shr_archive(&entries).unwrap();
+
+        // Written item by item, as `send` streams it, the bytes are the same
+        let mut writer = BundleWriter::new(Vec::new()).unwrap();
+        for entry in &entries {
+            writer.write_item(&BundleItem::File(entry.meta.clone().unwrap())).unwrap();
+            for chunk in &entry.chunks {
+                writer.write_item(&BundleItem::Chunk(chunk.clone())).unwrap();
+            }
+            if let Some(hash) = entry.file_hash {
+                writer.write_item(&BundleItem::FileHash(hash)).unwrap();
+            }
+        }
+        assert_eq!(writer.finish().unwrap(), archive);
+
+        let parsed = parse_bundle(&archive).unwrap();
+        assert_eq!(parsed.entries.len(), 3);
+        assert_eq!(parsed.chunk_count(), 4);
+        for (parsed, written) in parsed.entries.iter().zip(&entries) {
+            assert_eq!(parsed.meta, written.meta);
+            assert_eq!(parsed.file_hash, written.file_hash);
+            assert_eq!(parsed.chunks.iter().map(|c| c.index).collect::<Vec<_>>(), written.chunks.iter().map(|c| c.index).collect::<Vec<_>>());
+        }
+
+        assert!(parse_shr_bundle(&archive).is_err());
+        assert!(create_shr_archive(&[BundleEntry { meta: None, chunks, file_hash: None }]).is_err());
+    }
+
+    #[test]
+    fn test_file_hash_frame() {
+        let data = b"whole file ".repeat(1000);
+        let result = ParallelCompressor::new(4096, 1).unwrap().compress_bytes(&data).unwrap();
+        let bundle = create_shr_bundle_from_result(Some(&sample_meta("a.txt")), &result).unwrap();
+        let parsed = parse_bundle(&bundle).unwrap();
+        assert_eq!(parsed.entries[0].file_hash, Some(*blake3::hash(&data).as_bytes()));
+        assert_eq!(parsed.entries[0].chunks.len(), result.chunks.len());
+
+        // Without metadata the hash still closes the one unnamed file
+        let parsed = parse_bundle(&create_shr_bundle_from_result(None, &result).unwrap()).unwrap();
+        assert_eq!(parsed.entries.len(), 1);
+        assert!(parsed.entries[0].file_hash.is_some());
+
+        // Nothing of the file may follow its hash, and a hash frame can be cut short
+        let chunks = sample_chunks();
+        let mut late = header();
+        late.extend_from_slice(&encode_file_hash_frame(&[7; 32]));
+        late.extend_from_slice(&encode_frame(&chunks[0]).unwrap());
+        late.extend_from_slice(&trailer());
+        assert!(parse_bundle(&late).is_err());
+
+        let mut twice = header();
+        twice.extend_from_slice(&encode_file_hash_frame(&[7; 32]));
+        twice.extend_from_slice(&encode_file_hash_frame(&[7; 32]));
+        twice.extend_from_slice(&trailer());
+        assert!(parse_bundle(&twice).is_err());
+
+        let mut truncated = header();
+        truncated.extend_from_slice(&encode_file_hash_frame(&[7; 32])[..20]);
+        assert!(parse_bundle(&truncated).is_err());
+    }
+
+    #[test]
+    fn test_repeated_chunks_stored_once() {
+        let compressor = ParallelCompressor::default();
+        let data = vec![0u8; 40 * 1024 * 1024];
+        let result = compressor.compress_bytes(&data).unwrap();
+        assert_eq!(result.chunks.len(), 10);
+
+        // One chunk's data, then nine references and the whole-file hash
+        let bundle = create_shr_bundle_from_result(None, &result).unwrap();
+        let single = encode_frame(&result.chunks[0]).unwrap().len();
+        assert!(bundle.len() < single + 1024, "bundle is {} bytes, one chunk {}", bundle.len(), single);
+
+        let parsed = parse_shr_bundle(&bundle).unwrap();
+        assert_eq!(parsed.iter().map(|c| c.index).collect::<Vec<_>>(), (0..10).collect::<Vec<_>>());
+        let restored = compressor.decompress_chunks_parallel(&parsed).unwrap().concat();
+        assert_eq!(blake3::hash(&restored), blake3::hash(&data));
+
+        // Files share too: the second entry is all references to the first
+        let meta = (sample_meta("a.bin"), sample_meta("b.bin"));
+        let archive = write_bundle(&[(Some(&meta.0), &result.chunks, None), (Some(&meta.1), &result.chunks, None)], Vec::new()).unwrap();
+        assert!(archive.len() < bundle.len() + 1024);
+        let parsed = parse_bundle(&archive).unwrap();
+        assert_eq!(parsed.entries[1].chunks.len(), 10);
+        assert!(parsed.entries[1].chunks.iter().all(|c| c.data == result.chunks[0].data));
+    }
+
+    #[test]
+    fn test_chunk_refs_are_checked() {
+        let chunks = sample_chunks();
+        let mut encoder = FrameEncoder::default();
+        let first = encoder.encode_chunk(&chunks[0]).unwrap();
+        let repeat = encoder.encode_chunk(&CompressedChunk { index: 1, ..chunks[0].clone() }).unwrap();
+        assert_eq!(repeat.len(), 1 + CHUNK_REF_SIZE);
+
+        let mut bundle = header();
+        bundle.extend_from_slice(&first);
+        bundle.extend_from_slice(&repeat);
+        bundle.extend_from_slice(&trailer());
+        let parsed = parse_shr_bundle(&bundle).unwrap();
+        assert_eq!(parsed[1].data, chunks[0].data);
+
+        // v3 wrote the same frames with 32-bit sizes; v2 receivers never saw references, so a
+        // v2 bundle can't carry them
+        let mut v3 = MAGIC_V3.to_vec();
+        v3.push(FRAME_CHUNK);
+        write_narrow_meta(&mut v3, &chunks[0]);
+        v3.extend_from_slice(&chunks[0].data);
+        v3.extend_from_slice(&repeat);
+        v3.extend_from_slice(&trailer());
+        assert_eq!(parse_shr_bundle(&v3).unwrap()[1].data, chunks[0].data);
+        v3[..4].copy_from_slice(MAGIC_V2);
+        assert!(parse_shr_bundle(&v3).is_err());
+
+        // A reference must point back at data already in the bundle
+        let mut dangling = header();
+        dangling.extend_from_slice(&repeat);
+        dangling.extend_from_slice(&first);
+        dangling.extend_from_slice(&trailer());
+        assert!(parse_shr_bundle(&dangling).is_err());
+
+        let mut truncated = header();
+        truncated.extend_from_slice(&first);
+        truncated.extend_from_slice(&repeat[..20]);
+        assert!(parse_shr_bundle(&truncated).is_err());
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn test_sizes_past_u32_survive() {
+        // Only the sizes matter here, so the data is a stand-in rather than 4 GiB of zstd input
+        let big = CompressedChunk {
+            index: 0,
+            data: vec![1; 100],
+            hash: [9; 32],
+            original_size: u32::MAX as usize + 1,
+            algorithm: CompressionAlgorithm::Zstd,
+            checksum: ChecksumAlgorithm::Blake3,
+        };
+        let bundle = create_shr_bundle(std::slice::from_ref(&big)).unwrap();
+        assert_eq!(&bundle[..4], MAGIC_V8);
+        let parsed = parse_shr_bundle(&bundle).unwrap();
+        assert_eq!(parsed[0].original_size, u32::MAX as usize + 1);
+        assert_eq!(parsed[0].data.len(), 100);
+
+        // An index the format can't hold is refused rather than wrapped to a different chunk
+        let far = CompressedChunk { index: u32::MAX as usize + 1, ..big.clone() };
+        assert!(matches!(create_shr_bundle(std::slice::from_ref(&far)), Err(ShrLinkError::InvalidInput(_))));
+        let mut encoder = FrameEncoder::default();
+        encoder.encode_chunk(&big).unwrap();
+        assert!(encoder.encode_chunk(&far).is_err());
+    }
+
+    // Distinct, barely compressible chunks of different sizes, so parity has padding to handle
+    fn noisy_chunks(count: usize) -> (Vec<u8>, Vec<CompressedChunk>) {
+        let compressor = ParallelCompressor::default();
+        let mut state = 0x9e37_79b9u32;
+        let mut original = Vec::new();
+        let chunks = (0..count)
+            .map(|i| {
+                let data: Vec<u8> = (0..1000 + i * 37)
+                    .map(|_| {
+                        state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                        (state >> 24) as u8 & 0x3f
+                    })
+                    .collect();
+                original.extend_from_slice(&data);
+                compressor.compress_chunk(i, data).unwrap()
+            })
+            .collect();
+        (original, chunks)
+    }
+
+    // Each chunk's frame on its own, followed by whatever parity it completed, then what's left
+    fn parity_frames(chunks: &[CompressedChunk], ratio: f64) -> Vec<(Option<usize>, Vec<u8>)> {
+        let mut encoder = FrameEncoder::default().with_parity(Parity::new(ratio).unwrap());
+        let mut frames = Vec::new();
+        for chunk in chunks {
+            let mut framed = encoder.encode_chunk(chunk).unwrap();
+            let parity = framed.split_off(encode_frame(chunk).unwrap().len());
+            frames.push((Some(chunk.index), framed));
+            frames.push((None, parity));
+        }
+        frames.push((None, encoder.flush().unwrap()));
+        frames
+    }
+
+    fn assemble(frames: &[(Option<usize>, Vec<u8>)], lost: &[usize], damaged: &[usize]) -> Vec<u8> {
+        let mut bundle = header();
+        for (index, frame) in frames {
+            match index {
+                Some(index) if lost.contains(index) => {}
+                Some(index) if damaged.contains(index) => {
+                    let mut frame = frame.clone();
+                    *frame.last_mut().unwrap() ^= 0x55;
+                    bundle.extend_from_slice(&frame);
+                }
+                _ => bundle.extend_from_slice(frame),
+            }
+        }
+        bundle.extend_from_slice(&trailer());
+        bundle
+    }
+
+    #[test]
+    fn test_parity_rebuilds_lost_and_damaged_chunks() {
+        let compressor = ParallelCompressor::default();
+        let (original, chunks) = noisy_chunks(45);
+        let frames = parity_frames(&chunks, 0.1);
+        let parity = frames.iter().filter(|(index, frame)| index.is_none() && !frame.is_empty()).count();
+        assert_eq!(parity, 3);
+
+        // Groups of 20, 20 and 5 chunks, with 2, 2 and 1 parity chunks
+        let bundle = assemble(&frames, &[3, 38, 44], &[7, 21]);
+        let parsed = parse_shr_bundle(&bundle).unwrap();
+        assert_eq!(parsed.iter().map(|c| c.index).collect::<Vec<_>>(), (0..45).collect::<Vec<_>>());
+        assert_eq!(compressor.decompress_chunks_parallel(&parsed).unwrap().concat(), original);
+
+        let streamed = ShrBundleReader::new(bundle.as_slice()).read_bundle().unwrap();
+        assert_eq!(compressor.decompress_chunks_parallel(&streamed.entries[0].chunks).unwrap().concat(), original);
+
+        // Intact bundles are read as they are, and older readers' bundles still parse
+        let whole = assemble(&frames, &[], &[]);
+        assert_eq!(parse_shr_bundle(&whole).unwrap().len(), 45);
+        let plain = create_shr_bundle(&chunks).unwrap();
+        assert!(plain.len() < whole.len());
+    }
+
+    #[test]
+    fn test_parity_gives_up_past_its_limit() {
+        let (_, chunks) = noisy_chunks(45);
+        let frames = parity_frames(&chunks, 0.1);
+
+        let err = parse_shr_bundle(&assemble(&frames, &[1, 2], &[3])).unwrap_err().to_string();
+        assert!(err.contains("1, 2, 3"), "{}", err);
+        assert!(parse_shr_bundle(&assemble(&frames, &[40, 41], &[])).is_err());
+
+        // Parity that arrives damaged is dropped, so it can't rebuild anything; the gap is left
+        // for the whole-file hash to catch
+        let mut frames = frames;
+        let last = frames.last_mut().unwrap();
+        last.1[20] ^= 1;
+        let whole = parse_shr_bundle(&assemble(&frames, &[], &[])).unwrap();
+        assert_eq!(whole.len(), 45);
+        let gap = parse_shr_bundle(&assemble(&frames, &[44], &[])).unwrap();
+        assert_eq!(gap.len(), 44);
+    }
+
+    #[test]
+    fn test_parity_frames_are_checked() {
+        let (_, chunks) = noisy_chunks(3);
+        let mut encoder = FrameEncoder::default().with_parity(Parity::new(1.0).unwrap());
+        for chunk in &chunks {
+            encoder.encode_chunk(chunk).unwrap();
+        }
+        let frame = encoder.flush().unwrap();
+        let mut parity = FrameDecoder::default();
+        parity.decode(&header()).unwrap();
+        let (used, item) = parity.decode(&frame).unwrap();
+        let Some(reader::Decoded::Item(BundleItem::Parity(first))) = item else {
+            panic!("expected a parity chunk");
+        };
+        assert_eq!((first.row, first.members.len()), (0, 3));
+        assert!(used < frame.len());
+
+        // Repeated chunks are written out in full, and a truncated frame waits for the rest
+        let repeat = FrameEncoder::default().with_parity(Parity::new(1.0).unwrap()).encode_chunk(&chunks[0]).unwrap();
+        assert_eq!(repeat, encode_frame(&chunks[0]).unwrap());
+        let (used, item) = FrameDecoder::default().decode(&[header(), frame[..used - 1].to_vec()].concat()).unwrap();
+        assert!(used == 4 && item.is_none());
+
+        // v5 readers never saw parity frames
+        let mut v5 = MAGIC_V5.to_vec();
+        v5.extend_from_slice(&frame);
+        assert!(parse_shr_bundle(&v5).is_err());
+
+        let invalid = ParityChunk { data: Vec::new(), ..first };
+        assert!(encode_parity_frame(&invalid).is_err());
+        assert!(Parity::new(0.0).is_err() && Parity::new(1.5).is_err() && Parity::new(f64::NAN).is_err());
+    }
+}
@@ -0,0 +1,405 @@
+use std::collections::{BTreeMap, HashMap};
+use crate::compression::{ChecksumAlgorithm, CompressedChunk, CompressionAlgorithm};
+use crate::{Result, ShrLinkError};
+
+// Data chunks per group. Every full group gets the same number of parity chunks, and the last
+// one as many as its own size calls for
+pub const GROUP_SIZE: usize = 20;
+
+// How many parity chunks to add, as a fraction of the data chunks they cover
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Parity {
+    ratio: f64,
+}
+
+impl Parity {
+    pub fn new(ratio: f64) -> Result<Self> {
+        if !(ratio > 0.0 && ratio <= 1.0) {
+            return Err(ShrLinkError::InvalidInput(format!("Parity ratio must be above 0 and at most 1, not {}", ratio)));
+        }
+        Ok(Self { ratio })
+    }
+
+    pub fn ratio(&self) -> f64 {
+        self.ratio
+    }
+
+    // At least one, so even a short last group can lose a chunk
+    pub fn rows_for(&self, data_chunks: usize) -> usize {
+        ((data_chunks as f64 * self.ratio).ceil() as usize).max(1)
+    }
+}
+
+// What a parity chunk knows about one chunk it covers: enough to rebuild it outright, and to
+// tell whether a copy that did arrive is intact without decompressing it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParityMember {
+    pub index: usize,
+    pub original_size: usize,
+    pub compressed_size: usize,
+    pub hash: [u8; 32],
+    pub algorithm: CompressionAlgorithm,
+    pub checksum: ChecksumAlgorithm,
+    // The chunk's own checksum algorithm over its compressed data
+    pub data_hash: [u8; 32],
+}
+
+impl ParityMember {
+    pub fn of(chunk: &CompressedChunk) -> Self {
+        Self {
+            index: chunk.index,
+            original_size: chunk.original_size,
+            compressed_size: chunk.data.len(),
+            hash: chunk.hash,
+            algorithm: chunk.algorithm,
+            checksum: chunk.checksum,
+            data_hash: chunk.checksum.checksum(&chunk.data),
+        }
+    }
+
+    pub fn matches(&self, chunk: &CompressedChunk) -> bool {
+        *self == Self::of(chunk)
+    }
+
+    fn rebuild(&self, mut data: Vec<u8>) -> CompressedChunk {
+        data.truncate(self.compressed_size);
+        CompressedChunk {
+            index: self.index,
+            data,
+            hash: self.hash,
+            original_size: self.original_size,
+            algorithm: self.algorithm,
+            checksum: self.checksum,
+        }
+    }
+}
+
+// One row of a Reed-Solomon code over a group's compressed chunks, each padded with zeros to
+// the longest. Any of the group's chunks, up to as many as it has parity chunks, can be rebuilt
+// from the rest. Every parity chunk lists the whole group, so it doesn't matter which are lost
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParityChunk {
+    pub row: usize,
+    pub members: Vec<ParityMember>,
+    pub data: Vec<u8>,
+}
+
+impl ParityChunk {
+    // Rows and columns come from disjoint ranges of GF(256), which the code relies on
+    pub fn is_valid(&self) -> bool {
+        self.row + self.members.len() <= 255
+            && self.members.iter().map(|m| m.compressed_size).max().unwrap_or(0) == self.data.len()
+    }
+}
+
+// Builds each group's parity as its chunks go by, so only the parity itself is held
+pub(super) struct GroupEncoder {
+    parity: Parity,
+    members: Vec<ParityMember>,
+    rows: Vec<Vec<u8>>,
+}
+
+impl GroupEncoder {
+    pub(super) fn new(parity: Parity) -> Self {
+        Self { parity, members: Vec::new(), rows: vec![Vec::new(); parity.rows_for(GROUP_SIZE)] }
+    }
+
+    // The group's parity once this chunk completes it
+    pub(super) fn push(&mut self, chunk: &CompressedChunk) -> Vec<ParityChunk> {
+        let column = self.members.len();
+        for (row, data) in self.rows.iter_mut().enumerate() {
+            if data.len() < chunk.data.len() {
+                data.resize(chunk.data.len(), 0);
+            }
+            mul_add(data, coefficient(row, column), &chunk.data);
+        }
+        self.members.push(ParityMember::of(chunk));
+
+        if self.members.len() == GROUP_SIZE {
+            return self.flush();
+        }
+        Vec::new()
+    }
+
+    // Parity for a group cut short by the end of a file
+    pub(super) fn flush(&mut self) -> Vec<ParityChunk> {
+        if self.members.is_empty() {
+            return Vec::new();
+        }
+        let members = std::mem::take(&mut self.members);
+        let count = self.parity.rows_for(members.len());
+        let rows = std::mem::replace(&mut self.rows, vec![Vec::new(); self.parity.rows_for(GROUP_SIZE)]);
+
+        rows.into_iter()
+            .take(count)
+            .enumerate()
+            .map(|(row, data)| ParityChunk { row, members: members.clone(), data })
+            .collect()
+    }
+}
+
+// Rebuilds the chunks of `chunks` that are missing, or whose data doesn't match what the
+// parity chunks recorded, and returns their indices. A group with more damage than parity is
+// an error, naming the chunks that couldn't be restored
+pub fn repair(chunks: &mut Vec<CompressedChunk>, parity: Vec<ParityChunk>) -> Result<Vec<usize>> {
+    // Keyed on the first index each group covers; parity that disagrees with the rest of its
+    // group about the members is left out
+    let mut groups: BTreeMap<usize, Vec<ParityChunk>> = BTreeMap::new();
+    for chunk in parity {
+        let Some(first) = chunk.members.first().map(|m| m.index) else {
+            continue;
+        };
+        let group = groups.entry(first).or_default();
+        if !chunk.is_valid() || group.iter().any(|p| p.members != chunk.members) {
+            tracing::warn!("Ignoring parity row {} for chunk {}, which doesn't fit the rest of its group", chunk.row, first);
+            continue;
+        }
+        if group.iter().all(|p| p.row != chunk.row) {
+            group.push(chunk);
+        }
+    }
+
+    let mut repaired = Vec::new();
+    for group in groups.into_values() {
+        let Some(members) = group.first().map(|p| &p.members) else {
+            continue;
+        };
+        let positions: HashMap<usize, usize> = chunks.iter().enumerate().map(|(i, c)| (c.index, i)).collect();
+        let shards: Vec<Option<&[u8]>> = members
+            .iter()
+            .map(|m| positions.get(&m.index).map(|&i| &chunks[i]).filter(|c| m.matches(c)).map(|c| c.data.as_slice()))
+            .collect();
+        let erased: Vec<usize> = (0..members.len()).filter(|&j| shards[j].is_none()).collect();
+        if erased.is_empty() {
+            continue;
+        }
+        if erased.len() > group.len() {
+            let lost: Vec<_> = erased.iter().map(|&j| members[j].index.to_string()).collect();
+            return Err(ShrLinkError::InvalidInput(format!(
+                "Chunks {} are missing or damaged, and only {} parity chunks cover them",
+                lost.join(", "),
+                group.len()
+            )));
+        }
+
+        let rebuilt: Vec<CompressedChunk> = solve(&shards, &erased, &group[..erased.len()])
+            .into_iter()
+            .zip(&erased)
+            .map(|(data, &j)| members[j].rebuild(data))
+            .collect();
+        for chunk in rebuilt {
+            let member = members.iter().find(|m| m.index == chunk.index).unwrap();
+            if !member.matches(&chunk) {
+                return Err(ShrLinkError::InvalidInput(format!("Parity could not restore chunk {}", chunk.index)));
+            }
+            tracing::warn!("Chunk {} was missing or damaged and has been rebuilt from parity", chunk.index);
+            repaired.push(chunk.index);
+            match positions.get(&chunk.index) {
+                Some(&i) => chunks[i] = chunk,
+                None => chunks.push(chunk),
+            }
+        }
+    }
+
+    if !repaired.is_empty() {
+        chunks.sort_by_key(|c| c.index);
+    }
+    Ok(repaired)
+}
+
+// The erased shards, from one parity row per erasure. Subtracting the shards that survived
+// from each row leaves a small system in the erased ones, whose matrix is part of a Cauchy
+// matrix and so always invertible
+fn solve(shards: &[Option<&[u8]>], erased: &[usize], rows: &[ParityChunk]) -> Vec<Vec<u8>> {
+    let syndromes: Vec<Vec<u8>> = rows
+        .iter()
+        .map(|row| {
+            let mut syndrome = row.data.clone();
+            for (column, shard) in shards.iter().enumerate() {
+                if let Some(shard) = shard {
+                    mul_add(&mut syndrome, coefficient(row.row, column), shard);
+                }
+            }
+            syndrome
+        })
+        .collect();
+
+    let matrix: Vec<Vec<u8>> = rows.iter().map(|row| erased.iter().map(|&j| coefficient(row.row, j)).collect()).collect();
+    let inverse = invert(matrix);
+
+    let len = rows[0].data.len();
+    inverse
+        .iter()
+        .map(|weights| {
+            let mut shard = vec![0u8; len];
+            for (&weight, syndrome) in weights.iter().zip(&syndromes) {
+                mul_add(&mut shard, weight, syndrome);
+            }
+            shard
+        })
+        .collect()
+}
+
+// Gauss-Jordan elimination; the matrix must be invertible
+fn invert(mut matrix: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+    let n = matrix.len();
+    let mut inverse: Vec<Vec<u8>> = (0..n).map(|i| (0..n).map(|j| u8::from(i == j)).collect()).collect();
+
+    for col in 0..n {
+        let pivot = (col..n).find(|&r| matrix[r][col] != 0).expect("Cauchy submatrices are invertible");
+        matrix.swap(col, pivot);
+        inverse.swap(col, pivot);
+
+        let scale = inv(matrix[col][col]);
+        for j in 0..n {
+            matrix[col][j] = mul(matrix[col][j], scale);
+            inverse[col][j] = mul(inverse[col][j], scale);
+        }
+        for r in (0..n).filter(|&r| r != col) {
+            let factor = matrix[r][col];
+            if factor == 0 {
+                continue;
+            }
+            for j in 0..n {
+                matrix[r][j] ^= mul(factor, matrix[col][j]);
+                inverse[r][j] ^= mul(factor, inverse[col][j]);
+            }
+        }
+    }
+    inverse
+}
+
+// GF(256) with the polynomial x^8 + x^4 + x^3 + x^2 + 1. EXP is doubled so a product's log
+// never needs reducing
+const fn gf_tables() -> ([u8; 512], [u8; 256]) {
+    let mut exp = [0u8; 512];
+    let mut log = [0u8; 256];
+    let mut x: u16 = 1;
+    let mut i = 0;
+    while i < 255 {
+        exp[i] = x as u8;
+        log[x as usize] = i as u8;
+        x <<= 1;
+        if x & 0x100 != 0 {
+            x ^= 0x11d;
+        }
+        i += 1;
+    }
+    while i < 512 {
+        exp[i] = exp[i - 255];
+        i += 1;
+    }
+    (exp, log)
+}
+
+static GF: ([u8; 512], [u8; 256]) = gf_tables();
+
+fn mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        return 0;
+    }
+    GF.0[GF.1[a as usize] as usize + GF.1[b as usize] as usize]
+}
+
+fn inv(a: u8) -> u8 {
+    GF.0[255 - GF.1[a as usize] as usize]
+}
+
+// Row r and column c meet at 1 / (x_r + y_c), with x_r = 255 - r and y_c = c. Neither depends
+// on the size of the group, so parity can be built before the group is known to be complete
+fn coefficient(row: usize, column: usize) -> u8 {
+    inv((255 - row) as u8 ^ column as u8)
+}
+
+// `out` += `c` * `data`, with `data` padded with zeros to the length of `out`
+fn mul_add(out: &mut [u8], c: u8, data: &[u8]) {
+    if c == 0 {
+        return;
+    }
+    let table: [u8; 256] = std::array::from_fn(|d| mul(c, d as u8));
+    for (out, &d) in out.iter_mut().zip(data) {
+        *out ^= table[d as usize];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::ParallelCompressor;
+
+    fn sample_chunks(count: usize) -> Vec<CompressedChunk> {
+        let compressor = ParallelCompressor::default();
+        (0..count)
+            .map(|i| compressor.compress_chunk(i, format!("chunk {} ", i).repeat(50 + i * 7).into_bytes()).unwrap())
+            .collect()
+    }
+
+    fn contents(chunks: &[CompressedChunk]) -> Vec<(usize, &[u8], [u8; 32])> {
+        chunks.iter().map(|c| (c.index, c.data.as_slice(), c.hash)).collect()
+    }
+
+    fn encode(chunks: &[CompressedChunk], parity: Parity) -> Vec<ParityChunk> {
+        let mut encoder = GroupEncoder::new(parity);
+        let mut out: Vec<_> = chunks.iter().flat_map(|c| encoder.push(c)).collect();
+        out.extend(encoder.flush());
+        out
+    }
+
+    #[test]
+    fn test_field_arithmetic() {
+        for a in 1..=255u8 {
+            assert_eq!(mul(a, inv(a)), 1);
+            assert_eq!(mul(a, 1), a);
+        }
+        assert_eq!(mul(0x53, 0xCA), mul(0xCA, 0x53));
+        assert_eq!(mul(2, 0x80), 0x1d);
+    }
+
+    #[test]
+    fn test_groups_and_rows() {
+        let parity = Parity::new(0.1).unwrap();
+        let chunks = sample_chunks(45);
+        let rows = encode(&chunks, parity);
+
+        // Two full groups of 20 with two rows each, then 5 chunks with one
+        assert_eq!(rows.len(), 5);
+        assert_eq!(rows.iter().map(|p| p.members.len()).collect::<Vec<_>>(), [20, 20, 20, 20, 5]);
+        assert!(rows.iter().all(ParityChunk::is_valid));
+
+        assert!(Parity::new(0.0).is_err());
+        assert!(Parity::new(1.5).is_err());
+        assert!(Parity::new(f64::NAN).is_err());
+    }
+
+    #[test]
+    fn test_repairs_up_to_the_parity_count() {
+        let chunks = sample_chunks(GROUP_SIZE);
+        let parity = encode(&chunks, Parity::new(0.2).unwrap());
+        assert_eq!(parity.len(), 4);
+
+        // Every pair of lost chunks, with the parity rows that happen to be left
+        for a in 0..GROUP_SIZE {
+            for b in (a + 1)..GROUP_SIZE {
+                let mut damaged = chunks.clone();
+                damaged[b].data[0] ^= 1;
+                damaged.remove(a);
+                let rows = parity[(a + b) % 3..].to_vec();
+                assert_eq!(repair(&mut damaged, rows).unwrap(), [a, b]);
+                assert_eq!(contents(&damaged), contents(&chunks));
+            }
+        }
+
+        let mut damaged = chunks.clone();
+        damaged.drain(3..8);
+        let err = repair(&mut damaged, parity).unwrap_err();
+        assert!(err.to_string().contains("3, 4, 5, 6, 7"), "{}", err);
+    }
+
+    #[test]
+    fn test_intact_chunks_are_left_alone() {
+        let chunks = sample_chunks(7);
+        let mut received = chunks.clone();
+        assert!(repair(&mut received, encode(&chunks, Parity::new(0.5).unwrap())).unwrap().is_empty());
+        assert_eq!(contents(&received), contents(&chunks));
+    }
+}
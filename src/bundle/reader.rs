@@ -0,0 +1,688 @@
+use std::collections::VecDeque;
+use std::io::Read;
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::io::{AsyncRead, AsyncReadExt};
+use super::window::Window;
+use super::*;
+
+// How much more is asked of the underlying reader whenever a frame isn't complete yet
+const READ_SIZE: usize = 64 * 1024;
+
+// A v1 table row: index, original_size, compressed_size, hash
+type ChunkMeta = (usize, usize, usize, [u8; 32]);
+
+enum State {
+    Magic,
+    V1Table,
+    V1Chunks(VecDeque<ChunkMeta>),
+    Frames(u8),
+    Done,
+}
+
+pub enum Decoded {
+    Item(BundleItem),
+    End,
+}
+
+// The start of a chunk frame that carries its data, enough to tell whether the rest is needed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkHeader {
+    pub index: usize,
+    pub hash: [u8; 32],
+    pub original_size: usize,
+    pub compressed_size: usize,
+    pub algorithm: CompressionAlgorithm,
+    pub checksum: ChecksumAlgorithm,
+    // Tag, header and data
+    pub frame_len: usize,
+}
+
+impl ChunkHeader {
+    // Whether `chunk` can stand in for the one this frame holds
+    pub fn matches(&self, chunk: &CompressedChunk) -> bool {
+        chunk.hash == self.hash
+            && chunk.original_size == self.original_size
+            && chunk.checksum == self.checksum
+            // Parity covering this frame describes its compressed data, so it has to be the same
+            && chunk.algorithm == self.algorithm
+            && chunk.data.len() == self.compressed_size
+    }
+}
+
+// Parses a bundle from however much of it has arrived and does no I/O of its own, so bundles
+// in memory, in files and on sockets all go through the same checks. Between items it holds
+// only the reference window
+pub struct FrameDecoder {
+    state: State,
+    window: Window<CompressedChunk>,
+    // A file's metadata has arrived
+    named: bool,
+    // Content arrived ahead of any metadata: the bundle is one unnamed file, so none may follow
+    unnamed: bool,
+    // The current file's hash has arrived, so nothing more of that file may follow
+    closed: bool,
+    // Any frame has arrived, so a dictionary no longer may
+    started: bool,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self {
+            state: State::Magic,
+            window: Window::default(),
+            named: false,
+            unnamed: false,
+            closed: false,
+            started: false,
+        }
+    }
+}
+
+impl FrameDecoder {
+    // How many bytes from the front of `input` were used, and the next item if they held a
+    // whole one. Unused bytes must be passed in again, followed by more
+    pub fn decode(&mut self, input: &[u8]) -> Result<(usize, Option<Decoded>)> {
+        let mut used = 0;
+        loop {
+            let rest = &input[used..];
+            match &mut self.state {
+                State::Magic => {
+                    let Some(magic) = rest.get(..4) else {
+                        return Ok((used, None));
+                    };
+                    self.state = match magic {
+                        magic if magic == MAGIC_V1 => State::V1Table,
+                        magic if magic == MAGIC_V2 => State::Frames(2),
+                        magic if magic == MAGIC_V3 => State::Frames(3),
+                        magic if magic == MAGIC_V4 => State::Frames(4),
+                        magic if magic == MAGIC_V5 => State::Frames(5),
+                        magic if magic == MAGIC_V6 => State::Frames(6),
+                        magic if magic == MAGIC_V7 => State::Frames(7),
+                        magic if magic == MAGIC_V8 => State::Frames(8),
+                        _ => return Err(ShrLinkError::InvalidInput("Invalid SHR bundle format".to_string())),
+                    };
+                    used += 4;
+                }
+                State::V1Table => {
+                    let Some(count) = rest.get(..4).map(|_| read_u32(rest, 0)) else {
+                        return Ok((used, None));
+                    };
+                    // Checked so a huge count can't wrap on 32-bit targets and slip past the length check
+                    let table_end = count
+                        .checked_mul(CHUNK_META_SIZE_V1)
+                        .and_then(|size| size.checked_add(4))
+                        .ok_or_else(|| ShrLinkError::InvalidInput("Bundle too short for metadata".to_string()))?;
+                    if rest.len() < table_end {
+                        return Ok((used, None));
+                    }
+                    let table = (0..count)
+                        .map(|i| read_chunk_meta(rest, 4 + i * CHUNK_META_SIZE_V1, false))
+                        .collect::<Result<_>>()?;
+                    self.state = State::V1Chunks(table);
+                    used += table_end;
+                }
+                State::V1Chunks(table) => {
+                    // v1 has no end marker; the table says when it's over
+                    let Some(&(index, original_size, compressed_size, hash)) = table.front() else {
+                        self.state = State::Done;
+                        continue;
+                    };
+                    let Some(data) = rest.get(..compressed_size) else {
+                        return Ok((used, None));
+                    };
+                    table.pop_front();
+                    let chunk = CompressedChunk {
+                        index,
+                        data: data.to_vec(),
+                        hash,
+                        original_size,
+                        // v1 predates zstd support
+                        algorithm: CompressionAlgorithm::Lz4,
+                        checksum: ChecksumAlgorithm::Blake3,
+                    };
+                    return Ok((used + compressed_size, Some(Decoded::Item(BundleItem::Chunk(chunk)))));
+                }
+                State::Frames(version) => {
+                    let version = *version;
+                    let (frame_len, decoded) = self.decode_frame(version, rest)?;
+                    // A damaged parity frame, which is passed over
+                    if frame_len > 0 && decoded.is_none() {
+                        used += frame_len;
+                        continue;
+                    }
+                    return Ok((used + frame_len, decoded));
+                }
+                State::Done => return Ok((used, Some(Decoded::End))),
+            }
+        }
+    }
+
+    // The chunk frame at the front of `input`, once its header has arrived. None for anything
+    // else, including references and v1 bundles, whose sizes are all in the table up front
+    pub fn peek_chunk(&self, input: &[u8]) -> Result<Option<ChunkHeader>> {
+        let State::Frames(version) = self.state else {
+            return Ok(None);
+        };
+        let Some((&tag, body)) = input.split_first() else {
+            return Ok(None);
+        };
+        let meta_size = if version >= 4 { CHUNK_META_SIZE } else { CHUNK_META_SIZE_V1 };
+        let (tag, checksum) = split_tag(tag, version);
+        if !is_chunk_tag(tag, version) || body.len() < meta_size {
+            return Ok(None);
+        }
+
+        let (index, original_size, compressed_size, hash) = read_chunk_meta(body, 0, version >= 4)?;
+        let frame_len = compressed_size
+            .checked_add(1 + meta_size)
+            .ok_or_else(|| ShrLinkError::InvalidInput("Bundle chunk is too large".to_string()))?;
+        let algorithm = chunk_algorithm(tag);
+        Ok(Some(ChunkHeader { index, hash, original_size, compressed_size, algorithm, checksum, frame_len }))
+    }
+
+    // Takes `chunk` as the frame `header` was peeked from, for a caller that had it already
+    // and skipped the frame's bytes. It is checked and remembered as if it had been read
+    pub fn skip_chunk(&mut self, header: &ChunkHeader, chunk: CompressedChunk) -> Result<BundleItem> {
+        if !header.matches(&chunk) {
+            return Err(ShrLinkError::InvalidInput(format!("Chunk {} doesn't match the frame it replaces", header.index)));
+        }
+        self.open_chunk()?;
+
+        let chunk = CompressedChunk { index: header.index, ..chunk };
+        self.window.insert(header.hash, header.compressed_size, || chunk.clone());
+        Ok(BundleItem::Chunk(chunk))
+    }
+
+    fn decode_frame(&mut self, version: u8, input: &[u8]) -> Result<(usize, Option<Decoded>)> {
+        let Some((&tag, body)) = input.split_first() else {
+            return Ok((0, None));
+        };
+        let (tag, checksum) = split_tag(tag, version);
+        let first = !self.started;
+
+        let (body_len, item) = match tag {
+            FRAME_END => {
+                self.state = State::Done;
+                return Ok((1, Some(Decoded::End)));
+            }
+            FRAME_META => {
+                // Chunks with no metadata ahead of them are a single-file bundle; nothing can follow
+                if self.unnamed {
+                    return Err(ShrLinkError::InvalidInput("File metadata must come before any chunk".to_string()));
+                }
+                let Some(len) = body.get(..4).map(|_| read_u32(body, 0)) else {
+                    return Ok((0, None));
+                };
+                if body.len() - 4 < len {
+                    return Ok((0, None));
+                }
+                let meta = FileMeta::decode(&body[4..4 + len])?;
+                self.named = true;
+                self.closed = false;
+                (4 + len, BundleItem::File(meta))
+            }
+            FRAME_FILE_HASH => {
+                let Some(hash) = body.get(..32) else {
+                    return Ok((0, None));
+                };
+                if self.closed {
+                    return Err(ShrLinkError::InvalidInput("File has two whole-file hashes".to_string()));
+                }
+                self.unnamed |= !self.named;
+                self.closed = true;
+                (32, BundleItem::FileHash(hash.try_into().unwrap()))
+            }
+            tag if is_chunk_tag(tag, version) => {
+                let meta_size = if version >= 4 { CHUNK_META_SIZE } else { CHUNK_META_SIZE_V1 };
+                if body.len() < meta_size {
+                    return Ok((0, None));
+                }
+                let (index, original_size, compressed_size, hash) = read_chunk_meta(body, 0, version >= 4)?;
+                if body.len() - meta_size < compressed_size {
+                    return Ok((0, None));
+                }
+                self.open_chunk()?;
+
+                let chunk = CompressedChunk {
+                    index,
+                    data: body[meta_size..meta_size + compressed_size].to_vec(),
+                    hash,
+                    original_size,
+                    algorithm: chunk_algorithm(tag),
+                    checksum,
+                };
+                self.window.insert(hash, compressed_size, || chunk.clone());
+                (meta_size + compressed_size, BundleItem::Chunk(chunk))
+            }
+            FRAME_CHUNK_REF if version >= 3 => {
+                let Some(frame) = body.get(..CHUNK_REF_SIZE) else {
+                    return Ok((0, None));
+                };
+                let index = read_u32(frame, 0);
+                let hash: [u8; 32] = frame[4..].try_into().unwrap();
+                self.open_chunk()?;
+
+                let source = self.window.touch(&hash).ok_or_else(|| {
+                    ShrLinkError::InvalidInput("Chunk reference to data not recently in the bundle".to_string())
+                })?;
+                // The size and codec come from the referenced chunk, whose hash was computed over
+                // the same bytes
+                (CHUNK_REF_SIZE, BundleItem::Chunk(CompressedChunk { index, ..source.clone() }))
+            }
+            FRAME_DICTIONARY if version >= 7 => {
+                if !first {
+                    return Err(ShrLinkError::InvalidInput("Compression dictionary must be the first frame of a bundle".to_string()));
+                }
+                let Some(len) = body.get(..4).map(|_| read_u32(body, 0)) else {
+                    return Ok((0, None));
+                };
+                // Refused before any of it is waited for
+                if len > dict::MAX_SIZE {
+                    return Err(ShrLinkError::InvalidInput(format!("Bundle compression dictionary of {} bytes is too large", len)));
+                }
+                let Some(data) = body.get(4..4 + len) else {
+                    return Ok((0, None));
+                };
+                (4 + len, BundleItem::Dictionary(Dictionary::new(data.to_vec())?))
+            }
+            FRAME_PARITY if version >= 6 => {
+                let Some(len) = body.get(..8).map(|len| u64::from_le_bytes(len.try_into().unwrap())) else {
+                    return Ok((0, None));
+                };
+                let frame_len = usize::try_from(len)
+                    .ok()
+                    .and_then(|len| len.checked_add(8 + 32))
+                    .ok_or_else(|| ShrLinkError::InvalidInput("Bundle parity chunk is too large".to_string()))?;
+                let Some(frame) = body.get(8..frame_len) else {
+                    return Ok((0, None));
+                };
+                self.open_chunk()?;
+
+                let (parity, hash) = frame.split_at(frame.len() - 32);
+                // It's only a repair aid, so the transfer can go on without it
+                if blake3::hash(parity).as_bytes() != hash {
+                    tracing::warn!("Dropping a damaged parity chunk");
+                    return Ok((1 + frame_len, None));
+                }
+                (frame_len, BundleItem::Parity(read_parity(parity, version)?))
+            }
+            other => {
+                return Err(ShrLinkError::InvalidInput(format!("Unknown bundle frame type: {}", other)));
+            }
+        };
+
+        self.started = true;
+        Ok((1 + body_len, Some(Decoded::Item(item))))
+    }
+
+    fn open_chunk(&mut self) -> Result<()> {
+        if self.closed {
+            return Err(ShrLinkError::InvalidInput("Chunk after the hash that closes its file".to_string()));
+        }
+        self.unnamed |= !self.named;
+        self.started = true;
+        Ok(())
+    }
+}
+
+// The frame type with FRAME_XXH3 taken out, and the checksum that flag stands for
+pub(super) fn split_tag(tag: u8, version: u8) -> (u8, ChecksumAlgorithm) {
+    match tag & !FRAME_XXH3 {
+        base if version >= 5 && tag & FRAME_XXH3 != 0 && is_chunk_tag(base, version) => (base, ChecksumAlgorithm::Xxh3),
+        _ => (tag, ChecksumAlgorithm::Blake3),
+    }
+}
+
+pub(super) fn chunk_algorithm(tag: u8) -> CompressionAlgorithm {
+    match tag {
+        FRAME_CHUNK_ZSTD => CompressionAlgorithm::Zstd,
+        FRAME_CHUNK_STORED => CompressionAlgorithm::Stored,
+        FRAME_CHUNK_ZERO => CompressionAlgorithm::Zero,
+        FRAME_CHUNK_GZIP => CompressionAlgorithm::Gzip,
+        FRAME_CHUNK_SNAPPY => CompressionAlgorithm::Snappy,
+        0x08..=FRAME_CHUNK_LAST => CompressionAlgorithm::Unknown(tag),
+        _ => CompressionAlgorithm::Lz4,
+    }
+}
+
+// A parity frame's body, once its hash has been checked
+fn read_parity(body: &[u8], version: u8) -> Result<ParityChunk> {
+    let invalid = || ShrLinkError::InvalidInput("Invalid bundle parity chunk".to_string());
+    let (row, count) = match body {
+        [row, a, b, ..] => (*row as usize, u16::from_le_bytes([*a, *b]) as usize),
+        _ => return Err(invalid()),
+    };
+    let data_start = count
+        .checked_mul(PARITY_MEMBER_SIZE)
+        .and_then(|size| size.checked_add(3))
+        .filter(|&start| start <= body.len())
+        .ok_or_else(invalid)?;
+
+    let members = body[3..data_start]
+        .chunks_exact(PARITY_MEMBER_SIZE)
+        .map(|member| {
+            let (index, original_size, compressed_size, hash) = read_chunk_meta(member, 0, true)?;
+            let (tag, checksum) = split_tag(member[CHUNK_META_SIZE], version);
+            if !is_chunk_tag(tag, version) {
+                return Err(invalid());
+            }
+            Ok(ParityMember {
+                index,
+                original_size,
+                compressed_size,
+                hash,
+                algorithm: chunk_algorithm(tag),
+                checksum,
+                data_hash: member[CHUNK_META_SIZE + 1..].try_into().unwrap(),
+            })
+        })
+        .collect::<Result<_>>()?;
+
+    let parity = ParityChunk { row, members, data: body[data_start..].to_vec() };
+    if !parity.is_valid() {
+        return Err(invalid());
+    }
+    Ok(parity)
+}
+
+// Frames that carry a chunk's data, as opposed to a reference to it
+pub(super) fn is_chunk_tag(tag: u8, version: u8) -> bool {
+    match tag {
+        FRAME_CHUNK | FRAME_CHUNK_ZSTD | FRAME_CHUNK_STORED => true,
+        FRAME_CHUNK_ZERO => version >= 5,
+        FRAME_CHUNK_GZIP..=FRAME_CHUNK_LAST => version >= 8,
+        _ => false,
+    }
+}
+
+// Bytes read but not yet used by the decoder are `data[start..end]`
+#[derive(Default)]
+struct Buffer {
+    data: Vec<u8>,
+    start: usize,
+    end: usize,
+}
+
+impl Buffer {
+    fn pending(&self) -> &[u8] {
+        &self.data[self.start..self.end]
+    }
+
+    // Room for the next read, after moving what's pending to the front; only grows for a frame
+    // bigger than any before it
+    fn spare(&mut self) -> &mut [u8] {
+        if self.start > 0 {
+            self.data.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+        }
+        if self.data.len() < self.end + READ_SIZE {
+            self.data.resize(self.end + READ_SIZE, 0);
+        }
+        &mut self.data[self.end..]
+    }
+
+    fn filled(&mut self, read: std::io::Result<usize>) -> Result<()> {
+        match read {
+            Ok(0) => Err(ShrLinkError::InvalidInput("Bundle truncated before end marker".to_string())),
+            Ok(n) => {
+                self.end += n;
+                Ok(())
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+// Reads a bundle item by item from a stream, holding only the frame being read and the
+// reference window, so a bundle of any size can be received in bounded memory. Items come in
+// the order they were written; chunks aren't sorted by index
+pub struct ShrBundleReader<R> {
+    reader: R,
+    decoder: FrameDecoder,
+    buffer: Buffer,
+}
+
+impl<R> ShrBundleReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            decoder: FrameDecoder::default(),
+            buffer: Buffer::default(),
+        }
+    }
+
+    fn next_buffered(&mut self) -> Result<Option<Decoded>> {
+        let (used, decoded) = self.decoder.decode(self.buffer.pending())?;
+        self.buffer.start += used;
+        Ok(decoded)
+    }
+}
+
+impl<R: Read> ShrBundleReader<R> {
+    // None once the end marker has been read
+    pub fn next_item(&mut self) -> Result<Option<BundleItem>> {
+        loop {
+            match self.next_buffered()? {
+                Some(Decoded::Item(item)) => return Ok(Some(item)),
+                Some(Decoded::End) => return Ok(None),
+                None => {}
+            }
+            let read = self.reader.read(self.buffer.spare());
+            self.buffer.filled(read)?;
+        }
+    }
+
+    pub fn read_bundle(mut self) -> Result<Bundle> {
+        let mut builder = BundleBuilder::default();
+        while let Some(item) = self.next_item()? {
+            builder.push(item);
+        }
+        builder.finish()
+    }
+}
+
+impl<R: Read> Iterator for ShrBundleReader<R> {
+    type Item = Result<BundleItem>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_item().transpose()
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl<R: AsyncRead + Unpin> ShrBundleReader<R> {
+    pub async fn next_item_async(&mut self) -> Result<Option<BundleItem>> {
+        loop {
+            match self.next_buffered()? {
+                Some(Decoded::Item(item)) => return Ok(Some(item)),
+                Some(Decoded::End) => return Ok(None),
+                None => {}
+            }
+            let read = self.reader.read(self.buffer.spare()).await;
+            self.buffer.filled(read)?;
+        }
+    }
+
+    pub async fn read_bundle_async(mut self) -> Result<Bundle> {
+        let mut builder = BundleBuilder::default();
+        while let Some(item) = self.next_item_async().await? {
+            builder.push(item);
+        }
+        builder.finish()
+    }
+
+    // As `read_bundle_async`, then on past the end marker to the end of the stream, for readers
+    // that only find out whether what they gave was sound once they get there. What they find
+    // wrong there is what's returned, ahead of whatever it did to the bundle on the way
+    pub async fn read_bundle_to_end(mut self) -> Result<Bundle> {
+        let mut builder = BundleBuilder::default();
+        let read = loop {
+            match self.next_item_async().await {
+                Ok(Some(item)) => builder.push(item),
+                Ok(None) => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        tokio::io::copy(&mut self.reader, &mut tokio::io::sink()).await?;
+        read?;
+        builder.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::ParallelCompressor;
+
+    // Hands out a few bytes per read, and is interrupted now and then
+    struct Trickle<'a> {
+        data: &'a [u8],
+        reads: usize,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.reads += 1;
+            if self.reads.is_multiple_of(5) {
+                return Err(std::io::ErrorKind::Interrupted.into());
+            }
+            let n = buf.len().min(self.data.len()).min(7);
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    fn sample_archive() -> (Vec<u8>, Vec<BundleEntry>) {
+        let compressor = ParallelCompressor::new(1024, 1).unwrap();
+        let entries: Vec<_> = ["a.txt", "b.txt"]
+            .iter()
+            .map(|name| {
+                let data = format!("{} says hello ", name).repeat(300).into_bytes();
+                let result = compressor.compress_bytes(&data).unwrap();
+                let meta = FileMeta { name: Some(name.to_string()), size: data.len() as u64, ..Default::default() };
+                BundleEntry { meta: Some(meta), chunks: result.chunks, file_hash: Some(result.file_hash) }
+            })
+            .collect();
+        (create_shr_archive(&entries).unwrap(), entries)
+    }
+
+    #[test]
+    fn test_reader_matches_parse_bundle() {
+        let (bundle, entries) = sample_archive();
+        let parsed = parse_bundle(&bundle).unwrap();
+
+        let items: Vec<_> = ShrBundleReader::new(Trickle { data: &bundle, reads: 0 }).collect::<Result<_>>().unwrap();
+        assert!(matches!(&items[0], BundleItem::File(meta) if meta.name.as_deref() == Some("a.txt")));
+        assert!(matches!(items.last(), Some(BundleItem::FileHash(hash)) if *hash == entries[1].file_hash.unwrap()));
+
+        let read = ShrBundleReader::new(Trickle { data: &bundle, reads: 0 }).read_bundle().unwrap();
+        assert_eq!(read.entries.len(), 2);
+        for ((read, parsed), sent) in read.entries.iter().zip(&parsed.entries).zip(&entries) {
+            assert_eq!(read.meta, sent.meta);
+            assert_eq!(read.file_hash, parsed.file_hash);
+            assert_eq!(read.chunks.iter().map(|c| &c.data).collect::<Vec<_>>(), sent.chunks.iter().map(|c| &c.data).collect::<Vec<_>>());
+        }
+
+        // The end marker is required, not just running out of input
+        let cut = &bundle[..bundle.len() - 1];
+        assert!(ShrBundleReader::new(cut).read_bundle().is_err());
+        assert!(ShrBundleReader::new(&b"NOPE"[..]).next_item().is_err());
+    }
+
+    #[test]
+    fn test_reader_holds_one_frame_at_a_time() {
+        // Stored chunks a quarter of a MiB each, all different
+        let chunks: Vec<_> = (0..40u8)
+            .map(|i| {
+                let data = vec![i; 256 * 1024];
+                CompressedChunk { index: i as usize, hash: *blake3::hash(&data).as_bytes(), original_size: data.len(), data, algorithm: CompressionAlgorithm::Stored, checksum: ChecksumAlgorithm::Blake3 }
+            })
+            .collect();
+        let bundle = write_shr_bundle(&chunks, Vec::new()).unwrap();
+        assert_eq!(bundle, create_shr_bundle(&chunks).unwrap());
+
+        let mut reader = ShrBundleReader::new(bundle.as_slice());
+        let mut count = 0;
+        while let Some(item) = reader.next_item().unwrap() {
+            assert!(matches!(item, BundleItem::Chunk(chunk) if chunk.index == count));
+            count += 1;
+            assert!(reader.buffer.data.len() < 2 * (256 * 1024 + READ_SIZE));
+        }
+        assert_eq!(count, 40);
+    }
+
+    #[tokio::test]
+    async fn test_async_reader() {
+        let (bundle, entries) = sample_archive();
+        let read = ShrBundleReader::new(bundle.as_slice()).read_bundle_async().await.unwrap();
+        assert_eq!(read.chunk_count(), entries.iter().map(|e| e.chunks.len()).sum::<usize>());
+
+        let cut = &bundle[..bundle.len() / 2];
+        assert!(ShrBundleReader::new(cut).read_bundle_async().await.is_err());
+    }
+
+    #[test]
+    fn test_references_stay_in_the_window() {
+        // Each chunk fills more than half the window, so a second one pushes the first out
+        let big = |i: u8| {
+            let data = vec![i; DEDUP_WINDOW / 2 + 1];
+            CompressedChunk { index: i as usize, hash: [i; 32], original_size: data.len(), data, algorithm: CompressionAlgorithm::Stored, checksum: ChecksumAlgorithm::Blake3 }
+        };
+        let (a, b) = (big(0), big(1));
+        let again = CompressedChunk { index: 2, ..a.clone() };
+
+        let mut encoder = FrameEncoder::default();
+        let frames = [&a, &a, &b, &again].map(|c| encoder.encode_chunk(c).unwrap());
+        assert_eq!(frames[1].len(), 1 + CHUNK_REF_SIZE);
+        assert!(frames[3].len() > DEDUP_WINDOW / 2);
+
+        // A reader won't go looking further back than the writer would have
+        let mut late = header();
+        late.extend_from_slice(&frames[0]);
+        late.extend_from_slice(&frames[2]);
+        late.extend_from_slice(&frames[1]);
+        late.extend_from_slice(&trailer());
+        assert!(parse_bundle(&late).is_err());
+    }
+
+    #[test]
+    fn test_skipped_chunks_still_resolve_references() {
+        let chunk = |i: usize, data: &[u8]| CompressedChunk {
+            index: i,
+            hash: *blake3::hash(data).as_bytes(),
+            original_size: data.len(),
+            data: data.to_vec(),
+            algorithm: CompressionAlgorithm::Stored,
+            checksum: ChecksumAlgorithm::Blake3,
+        };
+        let (a, b) = (chunk(0, b"first chunk"), chunk(1, b"second chunk"));
+        let chunks = [a.clone(), b.clone(), CompressedChunk { index: 2, ..a.clone() }];
+        let bundle = create_shr_bundle(&chunks).unwrap();
+
+        let mut decoder = FrameDecoder::default();
+        assert!(decoder.peek_chunk(&bundle).unwrap().is_none());
+        assert!(matches!(decoder.decode(&bundle[..4]).unwrap(), (4, None)));
+        let mut input = &bundle[4..];
+
+        // Skip the first chunk's frame, handing over a copy from elsewhere under another index
+        let peeked = decoder.peek_chunk(input).unwrap().unwrap();
+        assert_eq!((peeked.index, peeked.original_size, peeked.frame_len), (0, a.original_size, 1 + CHUNK_META_SIZE + a.data.len()));
+        assert!(decoder.peek_chunk(&input[..CHUNK_META_SIZE]).unwrap().is_none());
+        assert!(decoder.skip_chunk(&peeked, b.clone()).is_err());
+        let skipped = decoder.skip_chunk(&peeked, CompressedChunk { index: 7, ..a.clone() }).unwrap();
+        assert!(matches!(skipped, BundleItem::Chunk(c) if c.index == 0));
+        input = &input[peeked.frame_len..];
+
+        let mut items = Vec::new();
+        while let (used, Some(Decoded::Item(item))) = decoder.decode(input).unwrap() {
+            items.push(item);
+            input = &input[used..];
+            // References aren't chunk frames, so there's nothing to skip
+            assert!(items.len() != 1 || decoder.peek_chunk(input).unwrap().is_none());
+        }
+        assert!(matches!(&items[1], BundleItem::Chunk(c) if c.index == 2 && c.data == a.data));
+    }
+}
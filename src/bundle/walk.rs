@@ -0,0 +1,158 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use super::meta::{EntryKind, FileMeta};
+use crate::{Result, ShrLinkError};
+
+// Everything to send for `root`, directories ahead of their contents and siblings in name order.
+// Names are `/`-separated and start with root's own name, so the receiver recreates the tree
+// under it. `exclude` sees each path relative to root and prunes whole directories. Sockets,
+// fifos and devices are skipped with a warning
+pub fn walk<F>(root: &Path, follow_symlinks: bool, exclude: F) -> Result<Vec<(PathBuf, FileMeta)>>
+where
+    F: FnMut(&Path) -> bool,
+{
+    // The argument itself is always followed, as `cp -r` and `tar` do
+    let metadata = fs::metadata(root)?;
+    let canonical = fs::canonicalize(root)?;
+    let name = canonical
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .ok_or_else(|| ShrLinkError::InvalidInput(format!("Cannot send {} as a whole", root.display())))?;
+
+    if !metadata.is_dir() {
+        return Ok(vec![(root.to_path_buf(), FileMeta::from_metadata(Some(name), &metadata, EntryKind::File))]);
+    }
+
+    let mut walker = Walker {
+        follow_symlinks,
+        exclude,
+        ancestors: vec![canonical],
+        entries: vec![(root.to_path_buf(), FileMeta::from_metadata(Some(name.clone()), &metadata, EntryKind::Dir))],
+    };
+    walker.visit(root, &name, Path::new(""))?;
+    Ok(walker.entries)
+}
+
+struct Walker<F> {
+    follow_symlinks: bool,
+    exclude: F,
+    // Canonical directories on the current path, to stop followed links from looping
+    ancestors: Vec<PathBuf>,
+    entries: Vec<(PathBuf, FileMeta)>,
+}
+
+impl<F: FnMut(&Path) -> bool> Walker<F> {
+    fn visit(&mut self, dir: &Path, name: &str, relative: &Path) -> Result<()> {
+        let mut children = fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+        children.sort_by_key(|c| c.file_name());
+
+        for child in children {
+            let path = child.path();
+            let child_relative = relative.join(child.file_name());
+            if (self.exclude)(&child_relative) {
+                continue;
+            }
+            let Some(file_name) = child.file_name().to_str().map(str::to_string) else {
+                tracing::warn!("Skipping {}: name is not valid UTF-8", path.display());
+                continue;
+            };
+            let child_name = format!("{}/{}", name, file_name);
+
+            let mut metadata = fs::symlink_metadata(&path)?;
+            if metadata.file_type().is_symlink() {
+                if !self.follow_symlinks {
+                    let Some(target) = fs::read_link(&path)?.to_str().map(str::to_string) else {
+                        tracing::warn!("Skipping {}: link target is not valid UTF-8", path.display());
+                        continue;
+                    };
+                    self.entries.push((path, FileMeta::from_metadata(Some(child_name), &metadata, EntryKind::Symlink(target))));
+                    continue;
+                }
+                metadata = match fs::metadata(&path) {
+                    Ok(metadata) => metadata,
+                    Err(e) => {
+                        tracing::warn!("Skipping {}: broken symlink ({})", path.display(), e);
+                        continue;
+                    }
+                };
+            }
+
+            let file_type = metadata.file_type();
+            if file_type.is_dir() {
+                let canonical = fs::canonicalize(&path)?;
+                if self.ancestors.contains(&canonical) {
+                    tracing::warn!("Skipping {}: symlink loops back to {}", path.display(), canonical.display());
+                    continue;
+                }
+                self.entries.push((path.clone(), FileMeta::from_metadata(Some(child_name.clone()), &metadata, EntryKind::Dir)));
+                self.ancestors.push(canonical);
+                self.visit(&path, &child_name, &child_relative)?;
+                self.ancestors.pop();
+            } else if file_type.is_file() {
+                self.entries.push((path, FileMeta::from_metadata(Some(child_name), &metadata, EntryKind::File)));
+            } else {
+                tracing::warn!("Skipping {}: not a regular file, directory or symlink", path.display());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    fn names(entries: &[(PathBuf, FileMeta)]) -> Vec<(String, EntryKind)> {
+        entries.iter().map(|(_, m)| (m.name.clone().unwrap(), m.kind.clone())).collect()
+    }
+
+    fn sample_tree() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("project");
+        fs::create_dir_all(root.join("src/empty")).unwrap();
+        fs::create_dir_all(root.join("target")).unwrap();
+        fs::write(root.join("README.md"), b"readme").unwrap();
+        fs::write(root.join("src/main.rs"), b"fn main() {}").unwrap();
+        fs::write(root.join("target/app"), b"binary").unwrap();
+        fs::write(root.join("build.log"), b"log").unwrap();
+        symlink("src/main.rs", root.join("entry.rs")).unwrap();
+        symlink("..", root.join("src/up")).unwrap();
+        std::os::unix::net::UnixListener::bind(root.join("agent.sock")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_walk_keeps_links_and_skips_specials() {
+        let dir = sample_tree();
+        let entries = walk(&dir.path().join("project"), false, |p| p == Path::new("target") || p.extension().is_some_and(|e| e == "log")).unwrap();
+
+        assert_eq!(names(&entries), vec![
+            ("project".to_string(), EntryKind::Dir),
+            ("project/README.md".to_string(), EntryKind::File),
+            ("project/entry.rs".to_string(), EntryKind::Symlink("src/main.rs".to_string())),
+            ("project/src".to_string(), EntryKind::Dir),
+            ("project/src/empty".to_string(), EntryKind::Dir),
+            ("project/src/main.rs".to_string(), EntryKind::File),
+            ("project/src/up".to_string(), EntryKind::Symlink("..".to_string())),
+        ]);
+        assert!(entries.iter().all(|(_, m)| m.kind == EntryKind::File || m.size == 0));
+    }
+
+    #[test]
+    fn test_walk_follows_links_without_looping() {
+        let dir = sample_tree();
+        let entries = walk(&dir.path().join("project/"), true, |p| p == Path::new("target")).unwrap();
+        let found = names(&entries);
+
+        assert!(found.contains(&("project/entry.rs".to_string(), EntryKind::File)));
+        // src/up points back at project, which is already being walked
+        assert!(!found.iter().any(|(n, _)| n.starts_with("project/src/up")));
+        assert!(found.contains(&("project/build.log".to_string(), EntryKind::File)));
+
+        // A plain file is a single entry named after itself
+        let single = walk(&dir.path().join("project/README.md"), false, |_| false).unwrap();
+        assert_eq!(names(&single), vec![("README.md".to_string(), EntryKind::File)]);
+    }
+}
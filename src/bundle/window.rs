@@ -0,0 +1,94 @@
+use std::collections::{BTreeMap, HashMap};
+
+// Writers only reference a chunk while it is among the most recently used distinct chunks
+// holding this much compressed data, so a reader that can't look back never needs more than
+// this to resolve a reference
+pub const DEDUP_WINDOW: usize = 64 * 1024 * 1024; // 64 MiB
+
+// Least recently used chunks leave first. Writer and reader update it frame by frame in the
+// same order, so both always agree on what a reference may point at
+pub(super) struct Window<T> {
+    budget: usize,
+    held: usize,
+    clock: u64,
+    // hash -> (last use, compressed size, value)
+    entries: HashMap<[u8; 32], (u64, usize, T)>,
+    by_use: BTreeMap<u64, [u8; 32]>,
+}
+
+impl<T> Default for Window<T> {
+    fn default() -> Self {
+        Self::new(DEDUP_WINDOW)
+    }
+}
+
+impl<T> Window<T> {
+    pub(super) fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            held: 0,
+            clock: 0,
+            entries: HashMap::new(),
+            by_use: BTreeMap::new(),
+        }
+    }
+
+    // Counts as a use, as a reference does
+    pub(super) fn touch(&mut self, hash: &[u8; 32]) -> Option<&T> {
+        let (last_use, _, value) = self.entries.get_mut(hash)?;
+        self.by_use.remove(last_use);
+        self.clock += 1;
+        *last_use = self.clock;
+        self.by_use.insert(self.clock, *hash);
+        Some(value)
+    }
+
+    // A chunk bigger than the whole budget isn't kept, and so can't be referenced
+    pub(super) fn insert(&mut self, hash: [u8; 32], size: usize, value: impl FnOnce() -> T) {
+        if let Some((last_use, old_size, _)) = self.entries.remove(&hash) {
+            self.by_use.remove(&last_use);
+            self.held -= old_size;
+        }
+        if size > self.budget {
+            return;
+        }
+
+        self.clock += 1;
+        self.entries.insert(hash, (self.clock, size, value()));
+        self.by_use.insert(self.clock, hash);
+        self.held += size;
+
+        while self.held > self.budget {
+            let Some((_, oldest)) = self.by_use.pop_first() else {
+                break;
+            };
+            if let Some((_, size, _)) = self.entries.remove(&oldest) {
+                self.held -= size;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_least_recently_used_leaves_first() {
+        let mut window = Window::new(100);
+        window.insert([1; 32], 40, || 'a');
+        window.insert([2; 32], 40, || 'b');
+        assert_eq!(window.touch(&[1; 32]), Some(&'a'));
+
+        // b is now the oldest, so it makes room for c
+        window.insert([3; 32], 40, || 'c');
+        assert_eq!(window.touch(&[2; 32]), None);
+        assert_eq!(window.touch(&[1; 32]), Some(&'a'));
+        assert_eq!(window.touch(&[3; 32]), Some(&'c'));
+
+        window.insert([4; 32], 101, || 'd');
+        assert_eq!(window.touch(&[4; 32]), None);
+        assert_eq!(window.touch(&[3; 32]), Some(&'c'));
+        assert_eq!(window.held, 80);
+    }
+}
@@ -0,0 +1,257 @@
+use std::io::Read;
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use super::reader::{chunk_algorithm, is_chunk_tag, split_tag};
+use super::*;
+
+// One chunk on its own, for streams that carry chunks rather than bundles: a u32 length, then
+// the chunk's frame exactly as the newest bundles hold it (tag, index, sizes, hash, data).
+// References and every other kind of frame are refused
+pub const PREFIX_SIZE: usize = 4;
+
+// Longest frame a decoder takes unless given its own limit. A longer length prefix is refused
+// as soon as it arrives, and a frame is only ever buffered as far as it has actually arrived
+pub const DEFAULT_MAX_FRAME: usize = 64 * 1024 * 1024 + 1 + CHUNK_META_SIZE;
+
+// The bundle version whose frame layout the wire uses
+const VERSION: u8 = MAGIC_V8[3];
+
+pub fn encode_chunk(chunk: &CompressedChunk) -> Result<Vec<u8>> {
+    let frame = encode_frame(chunk)?;
+    let len = u32::try_from(frame.len())
+        .map_err(|_| ShrLinkError::InvalidInput(format!("Chunk {} is too large to send on its own", chunk.index)))?;
+
+    let mut out = Vec::with_capacity(PREFIX_SIZE + frame.len());
+    out.extend_from_slice(&len.to_le_bytes());
+    out.extend_from_slice(&frame);
+    Ok(out)
+}
+
+// What `encode_chunk` comes to, without encoding it
+pub fn encoded_len(chunk: &CompressedChunk) -> usize {
+    PREFIX_SIZE + 1 + CHUNK_META_SIZE + chunk.data.len()
+}
+
+// The chunk at the front of `input` and how many bytes it took, or None until all of it has
+// arrived
+pub fn decode_chunk(input: &[u8]) -> Result<Option<(CompressedChunk, usize)>> {
+    decode_chunk_limited(input, DEFAULT_MAX_FRAME)
+}
+
+pub fn decode_chunk_limited(input: &[u8], max_frame: usize) -> Result<Option<(CompressedChunk, usize)>> {
+    let Some(prefix) = input.get(..PREFIX_SIZE) else {
+        return Ok(None);
+    };
+    let len = frame_len(prefix.try_into().unwrap(), max_frame)?;
+    let Some(frame) = input.get(PREFIX_SIZE..PREFIX_SIZE + len) else {
+        return Ok(None);
+    };
+    Ok(Some((parse_frame(frame)?, PREFIX_SIZE + len)))
+}
+
+// The next chunk, or None if the stream ends cleanly between chunks
+pub fn read_chunk<R: Read>(reader: &mut R, max_frame: usize) -> Result<Option<CompressedChunk>> {
+    let mut prefix = [0u8; PREFIX_SIZE];
+    let mut filled = 0;
+    while filled < PREFIX_SIZE {
+        match reader.read(&mut prefix[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(truncated()),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let len = frame_len(prefix, max_frame)?;
+
+    let mut frame = Vec::new();
+    reader.take(len as u64).read_to_end(&mut frame)?;
+    if frame.len() < len {
+        return Err(truncated());
+    }
+    parse_frame(&frame).map(Some)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn read_chunk_async<R: AsyncRead + Unpin>(reader: &mut R, max_frame: usize) -> Result<Option<CompressedChunk>> {
+    let mut prefix = [0u8; PREFIX_SIZE];
+    let mut filled = 0;
+    while filled < PREFIX_SIZE {
+        match reader.read(&mut prefix[filled..]).await? {
+            0 if filled == 0 => return Ok(None),
+            0 => return Err(truncated()),
+            n => filled += n,
+        }
+    }
+    let len = frame_len(prefix, max_frame)?;
+
+    let mut frame = Vec::new();
+    reader.take(len as u64).read_to_end(&mut frame).await?;
+    if frame.len() < len {
+        return Err(truncated());
+    }
+    parse_frame(&frame).map(Some)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn write_chunk_async<W: AsyncWrite + Unpin>(writer: &mut W, chunk: &CompressedChunk) -> Result<()> {
+    writer.write_all(&encode_chunk(chunk)?).await?;
+    Ok(())
+}
+
+fn frame_len(prefix: [u8; PREFIX_SIZE], max_frame: usize) -> Result<usize> {
+    let len = u32::from_le_bytes(prefix) as usize;
+    if len > max_frame {
+        return Err(ShrLinkError::InvalidInput(format!("Chunk of {} bytes is over the {} byte limit", len, max_frame)));
+    }
+    Ok(len)
+}
+
+fn truncated() -> ShrLinkError {
+    ShrLinkError::InvalidInput("Stream ended partway through a chunk".to_string())
+}
+
+fn parse_frame(frame: &[u8]) -> Result<CompressedChunk> {
+    let invalid = |what: &str| ShrLinkError::InvalidInput(format!("Invalid chunk frame: {}", what));
+    let Some((&tag, body)) = frame.split_first() else {
+        return Err(invalid("empty"));
+    };
+    let (tag, checksum) = split_tag(tag, VERSION);
+    if !is_chunk_tag(tag, VERSION) {
+        return Err(invalid(&format!("type {} doesn't carry a chunk", tag)));
+    }
+    if body.len() < CHUNK_META_SIZE {
+        return Err(invalid("shorter than its header"));
+    }
+
+    let (index, original_size, compressed_size, hash) = read_chunk_meta(body, 0, true)?;
+    let data = &body[CHUNK_META_SIZE..];
+    if data.len() != compressed_size {
+        return Err(invalid(&format!("holds {} bytes of data but claims {}", data.len(), compressed_size)));
+    }
+    Ok(CompressedChunk {
+        index,
+        data: data.to_vec(),
+        hash,
+        original_size,
+        algorithm: chunk_algorithm(tag),
+        checksum,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::ParallelCompressor;
+
+    fn chunks() -> Vec<CompressedChunk> {
+        let compressor = ParallelCompressor::default();
+        let mut chunks = vec![
+            compressor.compress_chunk(0, b"wire chunk ".repeat(500)).unwrap(),
+            compressor.compress_chunk(1, (0..5000u32).map(|i| (i * 7919 % 256) as u8).collect()).unwrap(),
+            compressor.compress_chunk(2, vec![0; 4096]).unwrap(),
+        ];
+        let data = b"xxh3 hashed".to_vec();
+        chunks.push(CompressedChunk {
+            index: 3,
+            hash: ChecksumAlgorithm::Xxh3.checksum(&data),
+            original_size: data.len(),
+            data,
+            algorithm: CompressionAlgorithm::Stored,
+            checksum: ChecksumAlgorithm::Xxh3,
+        });
+        chunks
+    }
+
+    fn fields(chunk: &CompressedChunk) -> (usize, &[u8], [u8; 32], usize, CompressionAlgorithm, ChecksumAlgorithm) {
+        (chunk.index, &chunk.data, chunk.hash, chunk.original_size, chunk.algorithm, chunk.checksum)
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let chunks = chunks();
+        let mut stream = Vec::new();
+        for chunk in &chunks {
+            let encoded = encode_chunk(chunk).unwrap();
+            let (decoded, used) = decode_chunk(&encoded).unwrap().unwrap();
+            assert_eq!(fields(&decoded), fields(chunk));
+            assert_eq!(used, encoded.len());
+            assert_eq!(encoded_len(chunk), encoded.len());
+            stream.extend_from_slice(&encoded);
+        }
+
+        let mut reader = stream.as_slice();
+        for chunk in &chunks {
+            assert_eq!(fields(&read_chunk(&mut reader, DEFAULT_MAX_FRAME).unwrap().unwrap()), fields(chunk));
+        }
+        assert!(read_chunk(&mut reader, DEFAULT_MAX_FRAME).unwrap().is_none());
+
+        // The same chunk through serde
+        let json = serde_json::to_string(&chunks[3]).unwrap();
+        assert_eq!(fields(&serde_json::from_str(&json).unwrap()), fields(&chunks[3]));
+    }
+
+    #[test]
+    fn test_truncated_and_oversized_input() {
+        let encoded = encode_chunk(&chunks()[1]).unwrap();
+        for len in 0..encoded.len() {
+            assert!(decode_chunk(&encoded[..len]).unwrap().is_none(), "{} bytes", len);
+            assert!(read_chunk(&mut &encoded[..len], DEFAULT_MAX_FRAME).map_or(true, |c| c.is_none() && len == 0));
+        }
+
+        // Refused from the prefix alone, before anything of the frame is read
+        let mut huge = u32::MAX.to_le_bytes().to_vec();
+        huge.extend_from_slice(&[1; 16]);
+        assert!(decode_chunk(&huge).is_err());
+        assert!(read_chunk(&mut huge.as_slice(), DEFAULT_MAX_FRAME).is_err());
+        assert!(decode_chunk_limited(&encoded, encoded.len() - PREFIX_SIZE - 1).is_err());
+
+        // A prefix under the limit that the stream never lives up to
+        let mut short = (DEFAULT_MAX_FRAME as u32).to_le_bytes().to_vec();
+        short.extend_from_slice(&encoded[PREFIX_SIZE..]);
+        assert!(read_chunk(&mut short.as_slice(), DEFAULT_MAX_FRAME).is_err());
+    }
+
+    #[test]
+    fn test_damaged_frames_are_refused() {
+        let encoded = encode_chunk(&chunks()[0]).unwrap();
+
+        // Whatever a byte is changed to, decoding fails cleanly or yields some chunk
+        for at in 0..PREFIX_SIZE + 1 + CHUNK_META_SIZE {
+            for value in [0x00, 0x01, 0x7f, 0xff] {
+                let mut damaged = encoded.clone();
+                damaged[at] = value;
+                let _ = decode_chunk(&damaged);
+                let _ = read_chunk(&mut damaged.as_slice(), DEFAULT_MAX_FRAME);
+            }
+        }
+
+        // A size that disagrees with the frame's length, and frames that aren't chunks
+        let mut lying = encoded.clone();
+        lying[PREFIX_SIZE + 1 + 12] ^= 1;
+        assert!(decode_chunk(&lying).is_err());
+        let mut reference = encoded.clone();
+        reference[PREFIX_SIZE] = FRAME_CHUNK_REF;
+        assert!(decode_chunk(&reference).is_err());
+        assert!(decode_chunk(&0u32.to_le_bytes()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_async_stream() {
+        let chunks = chunks();
+        let (mut tx, mut rx) = tokio::io::duplex(1024);
+        let writer = {
+            let chunks = chunks.clone();
+            tokio::spawn(async move {
+                for chunk in &chunks {
+                    write_chunk_async(&mut tx, chunk).await.unwrap();
+                }
+            })
+        };
+        for chunk in &chunks {
+            assert_eq!(fields(&read_chunk_async(&mut rx, DEFAULT_MAX_FRAME).await.unwrap().unwrap()), fields(chunk));
+        }
+        writer.await.unwrap();
+        assert!(read_chunk_async(&mut rx, DEFAULT_MAX_FRAME).await.unwrap().is_none());
+    }
+}
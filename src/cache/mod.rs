@@ -0,0 +1,221 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use crate::bundle::{create_shr_bundle, parse_shr_bundle};
+use crate::compression::{CompressedChunk, CompressionAlgorithm};
+use crate::config::Config;
+use crate::temp::{self, ScratchKind, TempGuard};
+use crate::Result;
+
+pub const DEFAULT_MAX_BYTES: u64 = 4 * 1024 * 1024 * 1024; // 4 GiB
+
+const ENTRY_SUFFIX: &str = ".shr";
+
+// Compressed chunks from earlier receives, kept by hash so a sender only has to send what
+// changed since. Each entry is a one-chunk bundle, and its mtime is when it was last used
+#[derive(Debug, Clone)]
+pub struct ChunkCache {
+    dir: PathBuf,
+    max_bytes: u64,
+}
+
+impl ChunkCache {
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir, max_bytes: DEFAULT_MAX_BYTES })
+    }
+
+    pub fn default_dir() -> PathBuf {
+        Config::data_dir().join("chunks")
+    }
+
+    // Only enforced by `prune`, so a single receive can go over it
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn contains(&self, hash: &[u8; 32]) -> bool {
+        self.entry_path(hash).is_file()
+    }
+
+    // Which of `hashes` are cached, for answering a manifest
+    pub fn have<'a>(&self, hashes: impl IntoIterator<Item = &'a [u8; 32]>) -> HashSet<[u8; 32]> {
+        hashes.into_iter().filter(|hash| self.contains(hash)).copied().collect()
+    }
+
+    // The chunk under index 0. Its data is checked when it's decompressed, like anything
+    // received; an entry that doesn't even parse is removed so the next receive can replace it
+    pub fn get(&self, hash: &[u8; 32]) -> Option<CompressedChunk> {
+        let path = self.entry_path(hash);
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                tracing::warn!("Failed to read cached chunk {}: {}", path.display(), e);
+                return None;
+            }
+        };
+
+        let chunk = parse_shr_bundle(&bytes)
+            .ok()
+            .and_then(|chunks| <[CompressedChunk; 1]>::try_from(chunks).ok())
+            .map(|[chunk]| chunk)
+            .filter(|chunk| chunk.hash == *hash);
+        let Some(chunk) = chunk else {
+            tracing::warn!("Dropping unreadable cached chunk {}", path.display());
+            let _ = fs::remove_file(&path);
+            return None;
+        };
+
+        // Only affects which entries `prune` removes first
+        if let Err(e) = fs::File::options().write(true).open(&path).and_then(|f| f.set_modified(SystemTime::now())) {
+            tracing::debug!("Failed to mark {} as used: {}", path.display(), e);
+        }
+        Some(chunk)
+    }
+
+    // Whether the chunk was stored. Only chunks that have been verified belong here; zero
+    // chunks aren't kept, since sending one costs no more than asking for it
+    pub fn put(&self, chunk: &CompressedChunk) -> Result<bool> {
+        let path = self.entry_path(&chunk.hash);
+        if chunk.algorithm == CompressionAlgorithm::Zero || path.is_file() {
+            return Ok(false);
+        }
+
+        let bundle = create_shr_bundle(&[CompressedChunk { index: 0, ..chunk.clone() }])?;
+        let scratch = TempGuard::beside(&path, ScratchKind::Temp)?;
+        fs::write(scratch.path(), &bundle)?;
+        scratch.commit(&path)?;
+        Ok(true)
+    }
+
+    pub fn evict(&self, hash: &[u8; 32]) -> Result<bool> {
+        match fs::remove_file(self.entry_path(hash)) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Removes the least recently used entries until the rest fit in the size limit, along
+    // with scratch files a put left behind. Returns the bytes freed
+    pub fn prune(&self) -> Result<u64> {
+        let mut freed = 0;
+        let mut entries = Vec::new();
+        for shard in fs::read_dir(&self.dir)? {
+            let shard = shard?.path();
+            if !shard.is_dir() {
+                continue;
+            }
+            freed += temp::sweep(&shard, temp::DEFAULT_SWEEP_TTL)?.bytes;
+
+            for entry in fs::read_dir(&shard)? {
+                let entry = entry?;
+                let path = entry.path();
+                let metadata = entry.metadata()?;
+                if !metadata.is_file() || !path.to_string_lossy().ends_with(ENTRY_SUFFIX) {
+                    continue;
+                }
+                let used = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                entries.push((used, metadata.len(), path));
+            }
+        }
+
+        let mut held: u64 = entries.iter().map(|(_, len, _)| len).sum();
+        entries.sort();
+        for (_, len, path) in entries {
+            if held <= self.max_bytes {
+                break;
+            }
+            fs::remove_file(&path)?;
+            held -= len;
+            freed += len;
+        }
+        Ok(freed)
+    }
+
+    // Sharded on the first byte so no one directory gets too big
+    fn entry_path(&self, hash: &[u8; 32]) -> PathBuf {
+        let name = hex::encode(hash);
+        self.dir.join(&name[..2]).join(format!("{}{}", name, ENTRY_SUFFIX))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::compression::ParallelCompressor;
+
+    fn chunk(index: usize, fill: u8) -> CompressedChunk {
+        let data: Vec<u8> = (0..4096u32).map(|i| (i % 251) as u8 ^ fill).collect();
+        ParallelCompressor::default().compress_chunk(index, data).unwrap()
+    }
+
+    #[test]
+    fn test_put_get_evict() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ChunkCache::open(dir.path().join("chunks")).unwrap();
+        let (a, b) = (chunk(3, 1), chunk(4, 2));
+
+        assert!(cache.get(&a.hash).is_none());
+        assert!(cache.put(&a).unwrap());
+        assert!(!cache.put(&a).unwrap());
+        assert_eq!(cache.have([&a.hash, &b.hash]), HashSet::from([a.hash]));
+
+        let cached = cache.get(&a.hash).unwrap();
+        assert_eq!((cached.index, &cached.data, cached.original_size), (0, &a.data, a.original_size));
+
+        assert!(cache.evict(&a.hash).unwrap());
+        assert!(!cache.evict(&a.hash).unwrap());
+        assert!(!cache.contains(&a.hash));
+
+        let zero = ParallelCompressor::default().compress_chunk(0, vec![0; 4096]).unwrap();
+        assert!(!cache.put(&zero).unwrap());
+    }
+
+    #[test]
+    fn test_unreadable_entry_is_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ChunkCache::open(dir.path()).unwrap();
+        let a = chunk(0, 1);
+        cache.put(&a).unwrap();
+
+        let path = cache.entry_path(&a.hash);
+        let mut bytes = fs::read(&path).unwrap();
+        bytes.truncate(bytes.len() / 2);
+        fs::write(&path, bytes).unwrap();
+
+        assert!(cache.get(&a.hash).is_none());
+        assert!(!path.exists());
+        assert!(cache.put(&a).unwrap());
+    }
+
+    #[test]
+    fn test_prune_removes_least_recently_used() {
+        let dir = tempfile::tempdir().unwrap();
+        let chunks: Vec<_> = (0..3).map(|i| chunk(i, i as u8)).collect();
+        let cache = ChunkCache::open(dir.path()).unwrap();
+        for (age, chunk) in chunks.iter().enumerate() {
+            cache.put(chunk).unwrap();
+            let used = SystemTime::now() - Duration::from_secs(60 * (10 - age as u64));
+            fs::File::options().write(true).open(cache.entry_path(&chunk.hash)).unwrap().set_modified(used).unwrap();
+        }
+        let len = |chunk: &CompressedChunk| fs::metadata(cache.entry_path(&chunk.hash)).unwrap().len();
+        let (kept, dropped) = (len(&chunks[0]) + len(&chunks[2]), len(&chunks[1]));
+
+        // Reading the oldest makes it the most recently used
+        cache.get(&chunks[0].hash).unwrap();
+        let cache = cache.with_max_bytes(kept);
+        assert_eq!(cache.prune().unwrap(), dropped);
+        assert_eq!(cache.have(chunks.iter().map(|c| &c.hash)), HashSet::from([chunks[0].hash, chunks[2].hash]));
+        assert_eq!(cache.prune().unwrap(), 0);
+    }
+}
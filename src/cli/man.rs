@@ -0,0 +1,66 @@
+use clap::CommandFactory;
+use clap_mangen::Man;
+use std::fs;
+use std::path::{Path, PathBuf};
+use super::Cli;
+use crate::Result;
+
+// Writes shr.1 plus one page per visible subcommand (shr-send.1, shr-config-show.1, ...).
+// Output depends only on the clap definitions, so repeated runs produce identical files
+pub fn generate(dir: &Path) -> Result<Vec<PathBuf>> {
+    fs::create_dir_all(dir)?;
+
+    let mut command = Cli::command();
+    command.build();
+
+    let mut written = Vec::new();
+    write_pages(&command, "shr", dir, &mut written)?;
+    Ok(written)
+}
+
+fn write_pages(command: &clap::Command, name: &str, dir: &Path, written: &mut Vec<PathBuf>) -> Result<()> {
+    let page = command.clone().name(name.to_string());
+    let mut out = Vec::new();
+    Man::new(page).render(&mut out)?;
+
+    let path = dir.join(format!("{}.1", name));
+    fs::write(&path, out)?;
+    written.push(path);
+
+    for sub in command.get_subcommands().filter(|s| !s.is_hide_set() && s.get_name() != "help") {
+        write_pages(sub, &format!("{}-{}", name, sub.get_name()), dir, written)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_man_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let written = generate(dir.path()).unwrap();
+
+        let page = |name: &str| fs::read_to_string(dir.path().join(name)).unwrap();
+        for name in ["shr.1", "shr-send.1", "shr-recv.1", "shr-config.1", "shr-config-set.1", "shr-peer-forgive.1", "shr-doctor.1", "shr-estimate.1", "shr-id.1"] {
+            assert!(written.contains(&dir.path().join(name)), "missing {}", name);
+        }
+        assert!(!dir.path().join("shr-generate-man.1").exists());
+        assert!(!dir.path().join("shr-help.1").exists());
+
+        assert!(page("shr-send.1").contains("shr send \\-\\-force\\-fallback \\-\\-timeout 10 backup.tar"));
+        assert!(page("shr-recv.1").contains("shr recv \\-\\-identity"));
+        assert!(page("shr-config.1").contains("shr config set compression.block_size 8388608"));
+        assert!(page("shr.1").contains("shr\\-send(1)"));
+
+        // Regenerating gives byte-identical pages
+        let again = tempfile::tempdir().unwrap();
+        generate(again.path()).unwrap();
+        for path in &written {
+            let name = path.file_name().unwrap();
+            assert_eq!(fs::read(path).unwrap(), fs::read(again.path().join(name)).unwrap());
+        }
+    }
+}
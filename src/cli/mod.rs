@@ -1,65 +1,347 @@
 use clap::{Parser, Subcommand};
 use console::style;
-use indicatif::{ProgressBar, ProgressStyle};
-use std::path::PathBuf;
-use std::time::Duration;
-use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
-use crate::{Result, ShrLinkError};
-use crate::config::Config;
-use crate::compression::ParallelCompressor;
-use crate::p2p::{P2PClient, parse_shr_url, create_shr_url};
-use crate::fallback::{HttpFallback, is_http_url};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::{Duration, Instant};
+use futures::{FutureExt, Stream, StreamExt, TryFutureExt, TryStreamExt};
+use libp2p::Multiaddr;
+use tokio_util::sync::CancellationToken;
+use crate::{Incompatibility, Result, ShrLinkError};
+use crate::config::{Config, ConfigLocation, FallbackBackend, FallbackConfig, LogFormat, FALLBACK_TOKEN_ENV};
+use crate::crypto::{self, EncryptingWriter, Encryption, SecretKey};
+use crate::temp::{self, ScratchKind, TempGuard};
+use crate::bundle::{Bundle, BundleEntry, BundleItem, BundleWriter, EntryKind, FileMeta, ShrBundleReader};
+use crate::compression::{dict, ChecksumAlgorithm, CompressedChunk, CompressionAlgorithm, CompressionProgress, Dictionary, ParallelCompressor, StreamedFile, DEFAULT_ESTIMATE_SAMPLE_BYTES};
+use crate::p2p::{Announcement, ChunkManifest, P2PClient, P2PStats, ReputationStore, StatsStore, ServeEvent, ServeStats, ServeThrottle, TransferEvent, parse_hybrid_url, parse_shr_url, create_shr_url, shr_url_addrs, add_url_addrs, PortMapping, PairingCode, PairingRecord, Rendezvous};
+use crate::p2p::receipt::{describe_receipt, short_peer_id};
+use crate::p2p::swarm;
+use crate::p2p::reputation::{self, BLOCK_THRESHOLD};
+use crate::cache::ChunkCache;
+use crate::partial::{PartialDownload, PARTIAL_SUFFIX};
+use crate::fallback::{open_store, BundleResponse, CachedDownload, FileStats, HttpFallback, is_file_url, is_http_url, is_sftp_url};
+use crate::server::FallbackServer;
+use crate::source::{fetch_with_failover, race_sources, Transport};
+use crate::verify;
+
+pub mod man;
+pub mod progress;
+
+use progress::{Progress, ProgressMode, ProgressTask, Unit};
+
+// How often a serving `send` says how it's getting on
+const SERVE_STATUS_INTERVAL: Duration = Duration::from_secs(10);
+// Fresh pairing codes tried before giving up on finding a number no other sender holds
+const PUBLISH_ATTEMPTS: usize = 5;
+// Decoded items a streamed HTTP receive lets pile up ahead of the files being written
+const STREAMED_ITEMS: usize = 16;
+
+// What `send` hands to each transport: every file's metadata followed by its compressed chunks
+// in index order and its whole-file hash, produced on demand
+trait ItemStream: Stream<Item = Result<BundleItem>> + Send + Unpin + 'static {}
+
+impl<S: Stream<Item = Result<BundleItem>> + Send + Unpin + 'static> ItemStream for S {}
 
 #[derive(Parser)]
 #[command(name = "shr")]
 #[command(about = "Fast P2P file sharing with compression")]
+#[command(long_about = "Fast P2P file sharing with compression.\n\n\
+Files are split into LZ4-compressed, BLAKE3-verified chunks and sent directly to peers when \
+one is reachable, falling back to an HTTP server otherwise.")]
+#[command(after_help = "Examples:\n  shr send report.pdf\n  shr recv shr://12D3KooW.../9f86d08...\n  shr config show")]
 #[command(version)]
 pub struct Cli {
     #[command(subcommand)]
     command: Commands,
     
-    #[arg(long, short, global = true)]
+    #[arg(long, short, global = true, help = "Config file to use instead of the default (also SHRLINK_CONFIG)")]
     config: Option<PathBuf>,
     
-    #[arg(long, short, global = true)]
-    verbose: bool,
+    #[arg(long, short, global = true, action = clap::ArgAction::Count, help = "More logging: -v info, -vv debug, -vvv trace for everything")]
+    verbose: u8,
+    
+    #[arg(long, global = true, value_name = "DIRECTIVE", help = "Extra tracing filter directives, e.g. shrlink::p2p=trace")]
+    log_filter: Option<String>,
+    
+    #[arg(long, global = true, value_enum, default_value_t = ProgressMode::Auto, help = "Progress output style")]
+    progress: ProgressMode,
+    
+    #[arg(long, global = true, value_enum, help = "Log output format (defaults to logging.format)")]
+    log_format: Option<LogFormat>,
+    
+    // Fired by Ctrl-C during a send, so compression and uploads can stop and clean up
+    #[arg(skip)]
+    cancel: CancellationToken,
 }
 
 #[derive(Subcommand)]
 enum Commands {
-    #[command(about = "Send a file")]
+    #[command(about = "Send one or more files")]
+    #[command(long_about = "Compress files and share them under one URL.\n\n\
+Peers are discovered first; if none answer within the timeout the bundle is streamed to the \
+HTTP fallback server instead. Otherwise the file is served to whoever asks for it until --copies \
+(or --max-downloads) receivers have all of it, or Ctrl-C. Receivers that check the whole file out \
+send back a signed receipt, which is printed as it arrives and counts even if some of their chunks \
+came from elsewhere. Only a single unencrypted file sent without a dictionary \
+is served directly; anything else uses the fallback. Encrypting to age recipients always uses the fallback. With \
+--encrypt the bundle is sealed with a fresh key that only appears in the share URL's #k= fragment; \
+with --password the key is derived from a password (prompted for, or read from SHR_PASSWORD). \
+--expires asks the fallback to keep the upload that long rather than for fallback.expiry_secs; \
+--once asks it to serve the upload a single time, after which the link answers 410 Gone.")]
+    #[command(after_help = "Examples:\n  \
+shr send report.pdf\n  \
+shr send --force-fallback --timeout 10 backup.tar\n  \
+shr send --force-fallback --expires 7d backup.tar\n  \
+shr send --force-fallback --once --encrypt passwords.kdbx\n  \
+shr send --copies 3 slides.pdf\n  \
+shr send --encrypt tax-return.pdf\n  \
+shr send --password contract.pdf\n  \
+shr send notes.txt diagram.png slides.pdf\n  \
+shr send --exclude target --exclude '*.log' ./my-project/\n  \
+shr send --dict ./exports/\n  \
+shr send --algorithm gzip site-assets.tar\n  \
+shr send --encrypt-to age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8p secrets.zip\n  \
+shr send --code report.pdf")]
     Send {
-        #[arg(help = "File to send")]
-        file: PathBuf,
+        #[arg(required = true, help = "Files or directories to send")]
+        files: Vec<PathBuf>,
+        
+        #[arg(long, value_name = "GLOB", help = "Skip paths inside directories matching this glob (repeatable)")]
+        exclude: Vec<String>,
+        
+        #[arg(long, help = "Send what symlinks inside directories point to instead of the links")]
+        follow_symlinks: bool,
         
-        #[arg(long, help = "Force S3 fallback")]
+        #[arg(long, value_name = "ALGORITHM", help = "Compress with lz4, zstd, gzip, snappy or stored instead of the configured algorithm")]
+        algorithm: Option<CompressionAlgorithm>,
+        
+        #[arg(long, conflicts_with = "algorithm", help = "Train a zstd dictionary on the files and compress them all with it")]
+        dict: bool,
+        
+        #[arg(long, help = "Skip P2P and upload to the fallback (fallback.backend)")]
         force_fallback: bool,
         
         #[arg(long, help = "P2P timeout in seconds")]
         timeout: Option<u64>,
+        
+        #[arg(long, visible_alias = "max-downloads", default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..), help = "Serve over P2P until this many receivers have the whole file or sent a receipt for it")]
+        copies: u64,
+        
+        #[arg(long = "encrypt-to", value_name = "RECIPIENT", help = "Encrypt the bundle to an age recipient (repeatable)")]
+        encrypt_to: Vec<String>,
+        
+        #[arg(long, conflicts_with = "encrypt_to", help = "Encrypt with a random key carried in the share URL")]
+        encrypt: bool,
+        
+        #[arg(long, conflicts_with_all = ["encrypt", "encrypt_to"], help = "Encrypt with a key derived from a password (or SHR_PASSWORD)")]
+        password: bool,
+        
+        #[arg(long, value_name = "RATE", value_parser = parse_rate, help = "Cap P2P uploads at this many bytes per second, e.g. 500K or 2M")]
+        limit_rate: Option<u64>,
+        
+        #[arg(long = "allow-peer", value_name = "PEER_ID", help = "Only serve this peer over P2P, along with p2p.allowed_peers (repeatable)")]
+        allow_peer: Vec<libp2p::PeerId>,
+        
+        #[arg(long, value_name = "DURATION", value_parser = parse_expiry, help = "Have the fallback keep the upload this long, e.g. 90m, 12h or 7d")]
+        expires: Option<Duration>,
+        
+        #[arg(long, help = "Have the fallback serve the upload to only one download (HTTP backend only)")]
+        once: bool,
+        
+        #[arg(long, conflicts_with_all = ["copies", "force_fallback", "encrypt", "encrypt_to", "password", "dict"], help = "Print a short pairing code to read out instead of a URL")]
+        code: bool,
     },
     
     #[command(about = "Receive a file")]
+    #[command(long_about = "Download and reconstruct a shared file.\n\n\
+Accepts shr:// URLs, hybrid shr:// URLs with a fallback, and plain HTTP(S) URLs. Every chunk \
+is verified against its hash before the file is written.\n\n\
+Chunks of plain (unencrypted) bundles received over HTTP are kept in a local cache, so \
+receiving a new version of the same file only downloads the chunks that changed.\n\n\
+What has arrived is kept in <output>.shrpart (or <hash>.shrpart without --output) until the \
+receive finishes. If it's interrupted, run the same command with --resume to fetch only the \
+rest.\n\n\
+Where mDNS is blocked, give the sender's address with --peer and the file's hash instead of a \
+URL. The peer is dialed directly, and has to prove it is the peer the address names before \
+anything is asked of it.")]
+    #[command(after_help = "Examples:\n  \
+shr recv http://localhost:8080/files/report.pdf\n  \
+shr recv -o backup.tar 'shr://12D3KooW.../9f86d08...?fallback=https%3A%2F%2Fexample.com%2Ffiles%2Fbackup.tar'\n  \
+shr recv --identity ~/.config/age/key.txt https://example.com/files/secrets.zip\n  \
+shr recv --resume -o backup.tar http://localhost:8080/files/backup.tar\n  \
+shr recv --peer /ip4/192.168.1.5/tcp/4001/p2p/12D3KooW... 9f86d08...\n  \
+shr recv --code 7-guitar-orbit-maple")]
     Recv {
-        #[arg(help = "SHR URL or HTTP URL to receive from")]
-        url: String,
+        #[arg(required_unless_present = "code", help = "SHR URL or HTTP URL to receive from, or a file hash with --peer")]
+        url: Option<String>,
         
-        #[arg(short, long, help = "Output file path")]
+        #[arg(long, conflicts_with = "url", help = "Pairing code the sender printed, e.g. 7-guitar-orbit-maple")]
+        code: Option<PairingCode>,
+        
+        #[arg(short, long, help = "Output file path, or directory for a multi-file bundle")]
         output: Option<PathBuf>,
+        
+        #[arg(long, help = "age identity file for decrypting age-encrypted bundles")]
+        identity: Option<PathBuf>,
+        
+        #[arg(long, help = "Directory for the chunk cache (default: chunks/ in the data directory)")]
+        cache_dir: Option<PathBuf>,
+        
+        #[arg(long, help = "Carry on from where an interrupted receive of the same URL stopped")]
+        resume: bool,
+        
+        #[arg(long, value_name = "RATE", value_parser = parse_rate, help = "Cap P2P downloads at this many bytes per second, e.g. 500K or 2M")]
+        limit_rate: Option<u64>,
+        
+        #[arg(long = "peer", value_name = "MULTIADDR", value_parser = parse_peer_addr, help = "A peer with the file to fetch chunks from, dialed directly, e.g. /ip4/10.0.0.2/tcp/4001/p2p/12D3Koo... (repeatable)")]
+        peers: Vec<Multiaddr>,
+    },
+    
+    #[command(about = "Estimate how large a file will be once compressed")]
+    #[command(long_about = "Estimate a file's compressed size before sending it.\n\n\
+Compresses blocks sampled evenly across the file with the current configuration and \
+extrapolates, or the whole file if it is no bigger than the sample size. Repeated chunks, \
+which are sent as references, aren't counted, so the real transfer can be smaller.")]
+    #[command(after_help = "Examples:\n  \
+shr estimate backup.tar\n  \
+shr estimate --bandwidth 40 --algorithm zstd disk.img")]
+    Estimate {
+        #[arg(help = "File to estimate")]
+        file: PathBuf,
+        
+        #[arg(long, value_name = "MBIT", help = "Upload bandwidth in megabits per second, to estimate the upload time")]
+        bandwidth: Option<f64>,
+        
+        #[arg(long, value_name = "ALGORITHM", help = "Estimate for lz4, zstd, gzip, snappy or stored instead of the configured algorithm")]
+        algorithm: Option<CompressionAlgorithm>,
+        
+        #[arg(long, value_name = "BYTES", default_value_t = DEFAULT_ESTIMATE_SAMPLE_BYTES, help = "How much of the file to compress")]
+        sample_size: u64,
     },
     
     #[command(about = "Show configuration")]
+    #[command(long_about = "Show or change the configuration file.\n\n\
+Without a subcommand the current configuration is printed as TOML.")]
+    #[command(after_help = "Examples:\n  \
+shr config show\n  \
+shr config set compression.block_size 8388608\n  \
+shr config reset\n  \
+SHRLINK_CONFIG=ci.toml shr config path")]
     Config {
         #[command(subcommand)]
         action: Option<ConfigAction>,
     },
     
-    #[command(about = "Clean up old S3 files")]
-    Cleanup,
+    #[command(about = "Print this machine's peer id")]
+    #[command(long_about = "Print this machine's peer id.\n\n\
+The id comes from a key made on first use and kept in the data directory, or at \
+p2p.identity_path, so shr:// URLs stay valid across restarts. The key is created if it \
+doesn't exist yet.")]
+    #[command(after_help = "Examples:\n  shr id")]
+    Id,
+    
+    #[command(about = "Inspect or reset peer reputation")]
+    #[command(after_help = "Examples:\n  shr peer list\n  shr peer forgive 12D3KooWGCYDpyGwFvjNbFWQXCCK9G4RZekkKfXXc2QnP8HWqDek")]
+    Peer {
+        #[command(subcommand)]
+        action: PeerAction,
+    },
+    
+    #[command(about = "Check whether peers can reach this machine")]
+    #[command(long_about = "Check whether peers can reach this machine.\n\n\
+Has the relays dial this machine back (AutoNAT) and give it a reservation, checks UPnP, and \
+reports whether transfers will be direct, hole-punched, relayed, or impossible. With p2p.enable_upnp set, the port is mapped on the router \
+for the check and dropped again after.")]
+    #[command(after_help = "Examples:\n  shr doctor\n  shr doctor --timeout 15")]
+    Doctor {
+        #[arg(long, default_value_t = 5, help = "Seconds to wait for each check")]
+        timeout: u64,
+    },
+    
+    #[command(about = "List what peers on the local network are sharing")]
+    #[command(long_about = "List what peers on the local network are sharing.\n\n\
+Finds peers with mDNS and asks each what it is serving, for as long as --timeout allows. Only \
+senders with p2p.announce set answer, and only while they are serving. Each file is listed with \
+a shr:// URL to hand to shr recv.")]
+    #[command(after_help = "Examples:\n  shr browse\n  shr browse --timeout 15")]
+    Browse {
+        #[arg(long, default_value_t = 5, help = "Seconds to listen for peers")]
+        timeout: u64,
+    },
+    
+    #[command(about = "Clean up old files on the fallback")]
+    #[command(after_help = "Examples:\n  shr cleanup\n  shr cleanup --dry-run\n  shr cleanup --older-than 3d\n  shr cleanup --local")]
+    Cleanup {
+        #[arg(long, help = "Remove leftover scratch files on this machine instead")]
+        local: bool,
+        
+        #[arg(long, conflicts_with = "local", help = "List what would be deleted, with sizes and ages, without deleting it")]
+        dry_run: bool,
+        
+        #[arg(long, value_name = "DURATION", value_parser = parse_expiry, conflicts_with = "local", help = "Delete uploads older than this instead of fallback.expiry_secs, e.g. 12h or 3d")]
+        older_than: Option<Duration>,
+    },
+    
+    #[command(about = "Remove an uploaded file from the fallback")]
+    #[command(long_about = "Remove an uploaded file from the fallback before it expires.\n\n\
+Takes the share URL shr send printed; any key or hash after # is ignored. HTTP servers are sent a \
+DELETE with fallback.auth_token if set, and S3 objects are deleted with DeleteObject. Fails with \
+\"not found\" if the file was already deleted, has expired or never existed.")]
+    #[command(after_help = "Examples:\n  shr delete https://shr.example.com/files/report.pdf.shr")]
+    Delete {
+        #[arg(help = "Share URL of the upload")]
+        url: String,
+    },
     
     #[command(about = "Show statistics")]
-    Stats,
+    #[command(long_about = "Show what the fallback server or S3 bucket holds, and what P2P transfers on this \
+machine have added up to: bytes and chunks each way, retries, time spent, and a line per peer. \
+The P2P counts are kept in stats.json in the data directory.")]
+    #[command(after_help = "Examples:\n  shr stats\n  shr stats --detailed\n  shr stats --json\n  shr stats --reset")]
+    Stats {
+        #[arg(long, help = "Zero the P2P statistics kept on this machine")]
+        reset: bool,
+        
+        #[arg(long, conflicts_with = "reset", help = "List each file on the fallback server, oldest first, where the server says")]
+        detailed: bool,
+        
+        #[arg(long, conflicts_with = "reset", help = "Print the statistics as JSON, with each file where the server lists them")]
+        json: bool,
+    },
+    
+    #[command(about = "Run a fallback server for senders to upload to")]
+    #[command(long_about = "Run a fallback server for senders to upload to.\n\n\
+Serves what fallback.endpoints expects: uploads to /upload, downloads from /files/<name> with \
+byte ranges for resumed downloads, and /cleanup and /stats for shr cleanup and shr stats. Uploads \
+are kept under --dir with their names sanitized, alongside an index of when each arrived, which \
+cleanup expires them by. With --token, uploads, deletes, cleanup and stats are refused with 401 unless \
+they carry it as a bearer token, which senders set as fallback.auth_token; downloads stay open unless \
+--auth-downloads is given too. Runs until Ctrl-C.")]
+    #[command(after_help = "Examples:\n  shr serve\n  shr serve --listen 127.0.0.1:9000 --dir /var/lib/shr\n  shr serve --token \"$(cat /etc/shr/token)\"")]
+    Serve {
+        #[arg(long, default_value = "0.0.0.0:8080", help = "Address to listen on")]
+        listen: SocketAddr,
+        
+        #[arg(long, default_value = "./shr-data", help = "Directory to keep uploads and their index in")]
+        dir: PathBuf,
+        
+        #[arg(long, help = "Only take uploads, deletes, cleanup and stats carrying this bearer token")]
+        token: Option<String>,
+        
+        #[arg(long, requires = "token", help = "Want the token for downloads too")]
+        auth_downloads: bool,
+        
+        #[arg(long, value_name = "URL", help = "Build share URLs on this, e.g. https://files.example.com behind a proxy")]
+        public_url: Option<url::Url>,
+    },
+    
+    #[command(name = "generate-man", hide = true, about = "Write man pages into a directory")]
+    GenerateMan {
+        #[arg(help = "Directory to write shr.1 and the per-subcommand pages into")]
+        dir: PathBuf,
+    },
 }
 
 #[derive(Subcommand)]
@@ -70,6 +352,12 @@ enum ConfigAction {
     #[command(about = "Reset to default configuration")]
     Reset,
     
+    #[command(about = "Print which config file is in use and why")]
+    Path,
+    
+    #[command(about = "Open the config file in $VISUAL or $EDITOR")]
+    Edit,
+    
     #[command(about = "Set a configuration value")]
     Set {
         #[arg(help = "Configuration key")]
@@ -80,257 +368,2631 @@ enum ConfigAction {
     },
 }
 
+#[derive(Subcommand)]
+enum PeerAction {
+    #[command(about = "List known peers and their reputation")]
+    List,
+    
+    #[command(about = "Clear a peer's strikes and history")]
+    Forgive {
+        #[arg(help = "Peer ID")]
+        peer_id: String,
+    },
+}
+
 impl Cli {
     pub fn new() -> Self {
         Self::parse()
     }
     
     pub async fn run(&self) -> Result<()> {
-        if self.verbose {
-            std::env::set_var("RUST_LOG", "debug");
+        // Packaging runs this in a build sandbox, so it must not touch config or logging
+        if let Commands::GenerateMan { dir } = &self.command {
+            for page in man::generate(dir)? {
+                println!("{}", page.display());
+            }
+            return Ok(());
         }
         
-        let config = if let Some(config_path) = &self.config {
-            let content = tokio::fs::read_to_string(config_path).await?;
-            toml::from_str(&content)?
-        } else {
-            Config::load()?
+        let location = ConfigLocation::from_env(self.config.as_deref());
+        let mut config = match &self.command {
+            // These still work when the named file doesn't exist yet, so it can be created
+            Commands::Config { action: Some(ConfigAction::Path | ConfigAction::Reset) } if !location.path.exists() => Config::default(),
+            _ => Config::load(&location)?,
         };
+        match &self.command {
+            Commands::Send { limit_rate, allow_peer, expires, once, .. } => {
+                if let Some(rate) = limit_rate {
+                    config.p2p.max_upload_bps = Some(*rate);
+                }
+                if let Some(expires) = expires {
+                    config.fallback.upload_expiry_secs = Some(expires.as_secs());
+                }
+                config.fallback.download_once |= *once;
+                config.p2p.allowed_peers.extend(allow_peer.iter().map(|p| p.to_string()));
+            }
+            Commands::Recv { limit_rate: Some(rate), .. } => config.p2p.max_download_bps = Some(*rate),
+            Commands::Cleanup { older_than: Some(age), .. } => config.fallback.expiry_secs = age.as_secs(),
+            _ => {}
+        }
+        config.fallback.apply_token_env(std::env::var(FALLBACK_TOKEN_ENV).ok());
+        
+        let rust_log = std::env::var("RUST_LOG").ok();
+        let directives = log_directives(self.verbose, rust_log.as_deref(), self.log_filter.as_deref());
+        crate::logging::init(self.log_format.unwrap_or(config.logging.format), crate::logging::filter(&directives)?);
         
+        // Scratch files from runs that were killed outright never got their guards dropped
+        match temp::sweep(&Config::cache_dir(), temp::DEFAULT_SWEEP_TTL) {
+            Ok(report) if !report.removed.is_empty() => {
+                tracing::debug!("Swept {} stale scratch files ({} bytes)", report.removed.len(), report.bytes);
+            }
+            Ok(_) => {}
+            Err(e) => tracing::debug!("Scratch sweep failed: {}", e),
+        }
+        // Said on every run, on stderr, so it isn't left set by accident
+        if config.fallback.danger_accept_invalid_certs {
+            eprintln!(
+                "{} fallback.danger_accept_invalid_certs is set: HTTPS certificates are not checked, so anyone between here and the server can read or change transfers",
+                style("⚠ WARNING").red().bold()
+            );
+        }
+
         match &self.command {
-            Commands::Send { file, force_fallback, timeout } => {
-                self.send_file(file, *force_fallback, *timeout, &config).await
+            Commands::Send { files, force_fallback, timeout, copies, encrypt_to, encrypt, password, exclude, follow_symlinks, algorithm, dict, code, .. } => {
+                let options = SendOptions { exclude: exclude_matcher(exclude)?, follow_symlinks: *follow_symlinks, algorithm: *algorithm, dictionary: *dict, copies: *copies as usize, timeout: *timeout, code: *code };
+                let encryption = if *encrypt {
+                    Some(Encryption::UrlKey(crypto::SecretKey::generate()))
+                } else if *password {
+                    let params = config.kdf.params()?;
+                    Some(Encryption::Password { password: crypto::read_password(true)?, params })
+                } else if !encrypt_to.is_empty() {
+                    Some(Encryption::Age(crypto::parse_age_recipients(encrypt_to)?))
+                } else {
+                    None
+                };
+                // Only now, so Ctrl-C at the password prompt still just quits
+                cancel_on_ctrl_c(self.cancel.clone());
+                self.send_files(files, &options, *force_fallback, encryption.as_ref(), &config).await
             }
-            Commands::Recv { url, output } => {
-                self.receive_file(url, output.as_ref(), &config).await
+            Commands::Recv { url, code, output, identity, cache_dir, resume, peers, .. } => {
+                let url = match (url, code) {
+                    (Some(url), _) => url.clone(),
+                    (None, Some(code)) => self.pair_with_sender(code, &config).await?,
+                    (None, None) => unreachable!("clap requires a URL or a code"),
+                };
+                let options = RecvOptions { output: output.as_ref(), identity: identity.as_deref(), cache_dir: cache_dir.as_deref(), resume: *resume, peers };
+                let received = self.receive_file(&url, options, &config).await;
+                if let Err(ShrLinkError::Incompatible(reason)) = &received {
+                    println!("{} Ask the sender to {}", style("💡").yellow(), incompatibility_hint(reason));
+                }
+                received
+            }
+            Commands::Estimate { file, bandwidth, algorithm, sample_size } => {
+                self.estimate_file(file, *bandwidth, *algorithm, *sample_size, &config)
             }
             Commands::Config { action } => {
-                self.handle_config(action.as_ref(), &config).await
+                self.handle_config(action.as_ref(), &config, &location).await
+            }
+            Commands::Id => {
+                self.show_id(&config)
             }
-            Commands::Cleanup => {
-                self.cleanup_http(&config).await
+            Commands::Peer { action } => {
+                self.handle_peer(action, &config)
             }
-            Commands::Stats => {
-                self.show_stats(&config).await
+            Commands::Doctor { timeout } => {
+                self.run_doctor(*timeout, &config).await
             }
+            Commands::Browse { timeout } => {
+                self.browse(*timeout, &config).await
+            }
+            Commands::Cleanup { local: true, .. } => {
+                self.cleanup_local()
+            }
+            Commands::Cleanup { local: false, dry_run, .. } => {
+                self.cleanup_http(*dry_run, &config).await
+            }
+            Commands::Delete { url } => {
+                self.delete_upload(url, &config).await
+            }
+            Commands::Stats { reset: true, .. } => {
+                self.reset_stats()
+            }
+            Commands::Stats { reset: false, detailed, json } => {
+                self.show_stats(*detailed, *json, &config).await
+            }
+            Commands::Serve { listen, dir, token, auth_downloads, public_url } => {
+                self.serve_fallback(*listen, dir, token.clone(), *auth_downloads, public_url.as_ref()).await
+            }
+            Commands::GenerateMan { .. } => unreachable!("handled before the config is loaded"),
         }
     }
     
-    async fn send_file(&self, file_path: &PathBuf, force_fallback: bool, timeout: Option<u64>, config: &Config) -> Result<()> {
-        if !file_path.exists() {
-            return Err(ShrLinkError::InvalidInput(format!("File not found: {}", file_path.display())));
+    async fn send_files(&self, paths: &[PathBuf], options: &SendOptions, force_fallback: bool, encryption: Option<&Encryption>, config: &Config) -> Result<()> {
+        // Name, size, mtime and mode travel in the bundle so the receiver can recreate each
+        // file; directories contribute every entry under them, named relative to their parent
+        let mut files = Vec::with_capacity(paths.len());
+        let mut names = std::collections::HashSet::new();
+        for path in paths {
+            if !path.exists() {
+                return Err(ShrLinkError::InvalidInput(format!("File not found: {}", path.display())));
+            }
+            let entries = crate::bundle::walk::walk(path, options.follow_symlinks, |relative| {
+                options.exclude.as_ref().is_some_and(|globs| {
+                    globs.is_match(relative) || relative.file_name().is_some_and(|n| globs.is_match(n))
+                })
+            })?;
+            for (entry_path, meta) in entries {
+                if !names.insert(meta.name.clone()) {
+                    return Err(ShrLinkError::InvalidInput(format!(
+                        "Two files are named {}; the receiver could only keep one",
+                        meta.name.as_deref().unwrap_or_default()
+                    )));
+                }
+                files.push((entry_path, meta));
+            }
         }
         
-        println!("{} Compressing file: {}", style("📦").blue(), file_path.display());
+        match paths {
+            [path] => println!("{} Compressing file: {}", style("📦").blue(), path.display()),
+            _ => println!("{} Compressing {} files", style("📦").blue(), paths.len()),
+        }
         
-        let compressor = ParallelCompressor::new(
+        let mut compressor = ParallelCompressor::with_block_size(
             config.compression.block_size,
             config.compression.acceleration,
-        ).with_workers(config.get_parallel_workers());
+        )?
+        .with_algorithm(match options.algorithm {
+            Some(algorithm) => algorithm,
+            None => config.compression.compression_algorithm()?,
+        })
+        .with_chunking(config.compression.chunking()?)
+        .with_checksum(config.compression.checksum)
+        .with_min_savings(config.compression.min_savings_percent)
+        .with_workers(config.get_parallel_workers())
+        .with_cancellation(self.cancel.clone());
         
-        let compression_result = compressor.compress_file(file_path)?;
+        // Only worth it for many small, similar files, so a dictionary that can't be trained
+        // just means sending without one
+        let dictionary = if options.dictionary {
+            let samples = dict::sample_files(files.iter().filter(|(_, meta)| meta.kind == EntryKind::File).map(|(path, _)| path))?;
+            match Dictionary::train(&samples, dict::DEFAULT_MAX_SIZE) {
+                Ok(dictionary) => {
+                    println!("{} Trained a {} byte dictionary", style("📖").blue(), dictionary.as_bytes().len());
+                    compressor = compressor.with_algorithm(CompressionAlgorithm::Zstd).with_dictionary(dictionary.clone());
+                    Some(dictionary)
+                }
+                Err(e) => {
+                    println!("{} Sending without a dictionary: {}", style("⚠").yellow(), e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
         
-        let compression_ratio = (compression_result.total_compressed_size as f64 / compression_result.total_original_size as f64) * 100.0;
+        // A single file or directory keeps its name on the server; anything else gets a generated
+        // one, as does anything sealed, since the server isn't meant to learn even that
+        let upload_name = match (paths, encryption) {
+            (_, Some(Encryption::UrlKey(_) | Encryption::Password { .. })) => None,
+            ([_], _) => files[0].1.name.clone(),
+            _ => None,
+        };
+        let total_chunks = files.iter().map(|(_, meta)| compressor.chunk_count(meta.size)).sum();
+        let input_size = files.iter().map(|(_, meta)| meta.size).sum();
+        // Peers ask for chunks by index alone, which only picks out one thing in a single plain file
+        let p2p_servable = encryption.is_none()
+            && dictionary.is_none()
+            && matches!(files.as_slice(), [(_, meta)] if meta.kind == EntryKind::File);
+        let bar = CompressionBar::new(Progress::new(self.progress), total_chunks);
+        let compressor = compressor.with_progress({
+            let bar = bar.clone();
+            move |update| bar.update(update)
+        });
         
-        println!(
-            "{} Compressed to {} chunks ({:.1}% of original size)",
-            style("✓").green(),
-            compression_result.chunks.len(),
-            compression_ratio
-        );
+        // Compression keeps running in the background while peers are discovered or the upload
+        // streams, with only a few blocks in memory however large the files are
+        let dictionary = futures::stream::iter(dictionary.map(|d| Ok(BundleItem::Dictionary(d))));
+        let items = finish_with_items(dictionary.chain(bundle_items(compressor, files)), bar.clone());
         
-        if force_fallback {
-            self.upload_to_http(&compression_result.chunks, config).await
-        } else {
-            self.try_p2p_then_fallback(&compression_result.chunks, timeout, config).await
+        let result = match encryption {
+            // age ciphertext is only meaningful to holders of the identity, so it goes via the fallback
+            Some(Encryption::Age(_)) => self.send_via_http(items, input_size, upload_name.as_deref(), encryption, &bar, config).await,
+            _ if force_fallback => self.send_via_http(items, input_size, upload_name.as_deref(), encryption, &bar, config).await,
+            _ if options.code && !p2p_servable => Err(ShrLinkError::InvalidInput(
+                "--code only works for a single file sent without encryption or a dictionary".to_string(),
+            )),
+            _ if options.code => self.pair_then_serve(items, total_chunks, config).await,
+            _ if !p2p_servable => {
+                println!("{} Only a single unencrypted file can be served to peers, sending via HTTP", style("ℹ").blue());
+                self.send_via_http(items, input_size, upload_name.as_deref(), encryption, &bar, config).await
+            }
+            _ => self.try_p2p_then_fallback(items, input_size, upload_name.as_deref(), options, &bar, config).await,
+        };
+        // In case the transport gave up before the items ran out
+        bar.finish();
+        result
+    }
+    
+    async fn send_via_http<S: ItemStream>(&self, items: S, input_size: u64, upload_name: Option<&str>, encryption: Option<&Encryption>, bar: &CompressionBar, config: &Config) -> Result<()> {
+        // A streamed upload is a single request of unknown length, so anything that could come to
+        // more than one part is spooled and sent in parts instead
+        let part_size = config.fallback.upload_part_size;
+        let fits_one_request = part_size == 0 || input_size <= part_size;
+        match encryption {
+            // PutObject needs the whole length up front, so S3 uploads are spooled first too, as
+            // are copies to the file backend's share and the sftp backend's host. age ciphertext
+            // streams as well, sealed a segment at a time on its way out
+            None | Some(Encryption::Age(_)) if fits_one_request && !config.fallback.verified_uploads && config.fallback.backend == FallbackBackend::Http => self.stream_to_http(items, bar.total_chunks, upload_name, encryption, bar, config).await,
+            _ => self.upload_encrypted(items, upload_name, encryption, config).await,
         }
     }
     
-    async fn try_p2p_then_fallback(&self, chunks: &[crate::compression::CompressedChunk], timeout: Option<u64>, config: &Config) -> Result<()> {
-        let p2p_timeout = timeout.unwrap_or(config.p2p.timeout_ms / 1000);
+    async fn try_p2p_then_fallback<S: ItemStream>(&self, items: S, input_size: u64, upload_name: Option<&str>, options: &SendOptions, bar: &CompressionBar, config: &Config) -> Result<()> {
+        let p2p_timeout = discovery_timeout(options.timeout, config);
         
         println!("{} Discovering peers...", style("🔍").yellow());
         
-        let mut p2p_client = P2PClient::new(config.p2p.clone()).await?;
+        // Ctrl-C while serving lets receivers part way through finish rather than cutting them off
+        let mut p2p_client = P2PClient::new(config.p2p.clone()).await?.with_shutdown(self.cancel.clone());
+        // Listening from the start, so the addresses mDNS announces are ones peers can reach
+        for addr in p2p_client.listen().await? {
+            tracing::debug!("Listening on {}", addr);
+        }
         
-        let progress_bar = ProgressBar::new_spinner();
-        progress_bar.set_style(ProgressStyle::default_spinner().template("{spinner:.green} {msg}").unwrap());
-        progress_bar.set_message("Searching for peers...");
-        progress_bar.enable_steady_tick(Duration::from_millis(100));
+        let progress = Progress::new(self.progress).start("discover", None, Unit::Chunks);
+        progress.status("Searching for peers...");
         
-        let peers = tokio::time::timeout(
-            Duration::from_secs(p2p_timeout),
-            p2p_client.discover_peers()
-        ).await;
+        let peers = tokio::select! {
+            peers = tokio::time::timeout(p2p_timeout, p2p_client.discover_peers()) => peers,
+            _ = self.cancel.cancelled() => {
+                progress.finish();
+                p2p_client.close().await;
+                return Err(ShrLinkError::Cancelled);
+            }
+        };
         
-        progress_bar.finish_and_clear();
+        progress.finish();
         
         match peers {
             Ok(Ok(peer_list)) if !peer_list.is_empty() => {
-                println!("{} Found {} peers, attempting P2P transfer...", style("🔗").green(), peer_list.len());
+                println!("{} Found {} peers, serving over P2P...", style("🔗").green(), peer_list.len());
                 
-                // For demo purposes, we'll just show the P2P URL
-                let peer_id = p2p_client.local_peer_id();
-                let file_hash = hex::encode(blake3::hash(&crate::compression::create_shr_bundle(chunks)?).as_bytes());
-                let shr_url = create_shr_url(peer_id, &file_hash);
+                let (files, chunks) = collect_for_serving(items, bar.total_chunks).await?;
+                let shr_url = served_url(&p2p_client, &files);
                 
                 println!("{} Share this URL:", style("📋").cyan());
                 println!("  {}", style(&shr_url).bold());
                 
-                // In a real implementation, you'd wait for incoming connections
-                // and serve the chunks to requesting peers
-                Ok(())
+                let addrs = p2p_client.advertisable_addrs();
+                if !addrs.is_empty() {
+                    println!("  reachable at:");
+                    for addr in addrs {
+                        println!("    {}", addr);
+                    }
+                }
+                
+                if config.p2p.announce {
+                    p2p_client.announce(announcement(&p2p_client, &files, &chunks));
+                    println!("{} Announcing it on the local network (p2p.announce)", style("📣").cyan());
+                }
+                
+                let manifest = p2p_client.prepare_manifest(content_hash(&files), &chunks)?;
+                let served = self.serve_until_copies(&mut p2p_client, &manifest, chunks, options.copies).await;
+                p2p_client.close().await;
+                served
             }
             _ => {
+                p2p_client.close().await;
                 println!("{} No peers found or timeout, falling back to HTTP server...", style("⚠").yellow());
-                self.upload_to_http(chunks, config).await
+                self.send_via_http(items, input_size, upload_name, None, bar, config).await
             }
         }
     }
     
-    async fn upload_to_http(&self, chunks: &[crate::compression::CompressedChunk], config: &Config) -> Result<()> {
-        let http_client = HttpFallback::new(config.fallback.clone()).await?;
+    // Serves to one receiver, which finds this client by the pairing code's number and is only
+    // handed the URL once it has shown it has the rest of the code too
+    async fn pair_then_serve<S: ItemStream>(&self, items: S, total_chunks: usize, config: &Config) -> Result<()> {
+        let rendezvous = config.p2p.rendezvous_url.as_deref().map(Rendezvous::new).transpose()?;
+        if rendezvous.is_none() && !config.p2p.enable_mdns {
+            return Err(ShrLinkError::InvalidInput(
+                "--code needs p2p.rendezvous_url or mDNS for the receiver to find this sender".to_string(),
+            ));
+        }
+        let ttl = Duration::from_millis(config.p2p.pairing_ttl_ms);
+        
+        let mut p2p_client = P2PClient::new(config.p2p.clone()).await?.with_shutdown(self.cancel.clone());
+        for addr in p2p_client.listen().await? {
+            tracing::debug!("Listening on {}", addr);
+        }
+        
+        let (files, chunks) = collect_for_serving(items, total_chunks).await?;
+        // Only the paired receiver sees it, and it may have found this client through the
+        // rendezvous server rather than mDNS, so it's told where to connect
+        let shr_url = create_shr_url(p2p_client.local_peer_id(), &hex::encode(content_hash(&files)));
+        let shr_url = add_url_addrs(&shr_url, &p2p_client.advertisable_addrs());
+        
+        // Another sender may hold the number; a fresh code very likely has one that's free
+        let mut code = PairingCode::generate();
+        if let Some(rendezvous) = &rendezvous {
+            let record = PairingRecord::new(p2p_client.local_peer_id(), &p2p_client.advertisable_addrs(), ttl);
+            let mut attempts = 0;
+            while !rendezvous.publish(code.nameplate(), &record).await? {
+                attempts += 1;
+                if attempts == PUBLISH_ATTEMPTS {
+                    p2p_client.close().await;
+                    return Err(ShrLinkError::P2P("Every pairing code tried was taken on the rendezvous server".to_string()));
+                }
+                code = PairingCode::generate();
+            }
+        }
+        
+        println!("{} On the receiving side, run:", style("📋").cyan());
+        println!("  {}", style(format!("shr recv --code {}", code)).bold());
+        println!("{} Waiting up to {}s for the receiver to pair (Ctrl-C to stop)...", style("⏳").yellow(), ttl.as_secs());
+        
+        let paired = p2p_client.await_pairing(&code, &shr_url, ttl).await;
+        if let Some(rendezvous) = &rendezvous {
+            if let Err(e) = rendezvous.withdraw(code.nameplate()).await {
+                tracing::warn!("Failed to withdraw pairing code {}: {}", code.nameplate(), e);
+            }
+        }
+        let peer_id = match paired {
+            Ok(peer_id) => peer_id,
+            Err(e) => {
+                p2p_client.close().await;
+                return Err(e);
+            }
+        };
+        println!("{} Paired with {}", style("🤝").green(), short_peer_id(&peer_id.to_string()));
+        
+        let manifest = p2p_client.prepare_manifest(content_hash(&files), &chunks)?;
+        let served = self.serve_until_copies(&mut p2p_client, &manifest, chunks, 1).await;
+        p2p_client.close().await;
+        served
+    }
+    
+    // Finds the sender holding a code with the same number, at the address the rendezvous server
+    // has for it or on the local network, and pairs with it for the URL of what it's sending
+    async fn pair_with_sender(&self, code: &PairingCode, config: &Config) -> Result<String> {
+        let timeout = Duration::from_millis(config.p2p.timeout_ms);
+        let mut p2p_client = P2PClient::new(config.p2p.clone()).await?;
+        
+        let candidates = match config.p2p.rendezvous_url.as_deref() {
+            Some(url) => {
+                let record = Rendezvous::new(url)?.lookup(code.nameplate()).await?.ok_or_else(|| {
+                    ShrLinkError::P2P(format!("No sender is holding pairing code {}-…", code.nameplate()))
+                })?;
+                let (peer_id, addrs) = record.peer()?;
+                p2p_client.reach_peer(peer_id, &addrs).await?;
+                vec![peer_id]
+            }
+            None => {
+                println!("{} Looking for the sender on the local network...", style("🔍").yellow());
+                let peers = tokio::time::timeout(timeout, p2p_client.discover_peers())
+                    .await
+                    .map_err(|_| ShrLinkError::Timeout("No sender found on the local network within p2p.timeout_ms".to_string()))??;
+                peers.into_iter().map(|p| p.peer_id).collect()
+            }
+        };
+        
+        for peer_id in candidates {
+            let paired = tokio::time::timeout(timeout, p2p_client.pair(peer_id, code))
+                .await
+                .map_err(|_| ShrLinkError::Timeout(format!("{} did not answer the pairing within p2p.timeout_ms", peer_id)))?;
+            if let Some(url) = paired? {
+                println!("{} Paired with {}", style("🤝").green(), short_peer_id(&peer_id.to_string()));
+                return Ok(url);
+            }
+        }
+        Err(ShrLinkError::P2P(format!("No sender is holding pairing code {}-…", code.nameplate())))
+    }
+    
+    // One bar for everything sent, in bytes, fed from the client's transfer events
+    async fn serve_until_copies(&self, p2p_client: &mut P2PClient, manifest: &ChunkManifest, chunks: Vec<CompressedChunk>, copies: usize) -> Result<()> {
+        match copies {
+            1 => println!("{} Waiting for a receiver (Ctrl-C to stop)...", style("⏳").yellow()),
+            _ => println!("{} Waiting for {} receivers (Ctrl-C to stop)...", style("⏳").yellow(), copies),
+        }
+        
+        let total_bytes: usize = chunks.iter().map(|c| c.data.len()).sum();
+        let progress = Progress::new(self.progress).start("send", Some((total_bytes * copies) as u64), Unit::Bytes);
+        let mut events = p2p_client.transfer_events();
+        let throttle = p2p_client.serve_throttle();
+        let grace = Duration::from_millis(p2p_client.config().shutdown_grace_ms);
+        let render = async {
+            let mut stopping = false;
+            loop {
+                tokio::select! {
+                    event = events.recv() => match event {
+                        Some(TransferEvent::ChunkSent { bytes, .. }) => progress.inc(bytes as u64),
+                        Some(event) if event.is_terminal() => break,
+                        Some(_) => {}
+                        None => break,
+                    },
+                    _ = self.cancel.cancelled(), if !stopping => {
+                        stopping = true;
+                        progress.status(format!("Stopping, letting receivers finish (up to {}s)...", grace.as_secs_f64()));
+                    }
+                }
+            }
+            progress.finish();
+        };
+        let serve = p2p_client.serve_chunks(manifest, chunks, copies, SERVE_STATUS_INTERVAL, |event| match event {
+            ServeEvent::PeerStarted(peer_id) => {
+                println!("{} {} started downloading", style("📤").blue(), short_peer_id(&peer_id.to_string()));
+            }
+            ServeEvent::PeerWaiting(peer_id) => {
+                println!("{} {} is waiting for another receiver to finish", style("⏸").yellow(), short_peer_id(&peer_id.to_string()));
+            }
+            ServeEvent::ChunkServed { .. } => {}
+            ServeEvent::PeerCompleted(peer_id) => {
+                println!("{} {} has the whole file", style("✓").green(), short_peer_id(&peer_id.to_string()));
+            }
+            ServeEvent::ReceiptReceived(receipt) => {
+                println!("{} {}", style("✓").green(), describe_receipt(&receipt));
+            }
+            ServeEvent::PeerIncompatible(peer_id, reason) => {
+                println!("{} {} can't receive this: {}", style("⚠").yellow(), short_peer_id(&peer_id.to_string()), reason);
+                println!("  to send to it, {}", incompatibility_hint(&reason));
+            }
+            ServeEvent::PeerCutOff(peer_id, reason) => {
+                println!("{} {} was cut off: {}", style("⛔").red(), short_peer_id(&peer_id.to_string()), reason);
+            }
+            ServeEvent::Status(stats) => {
+                println!("{} Served {} chunks to {} peers so far{}", style("📊").cyan(), stats.chunks_served, stats.peers, retried(&stats));
+                print_peer_rates(&throttle);
+            }
+        });
+        let (stats, ()) = tokio::join!(serve, render);
+        let stats = stats?;
+        
+        println!(
+            "{} Served {} chunks ({} bytes) to {} peers, {} of them complete{}",
+            style("✓").green(),
+            stats.chunks_served,
+            stats.bytes_served,
+            stats.peers,
+            stats.completed,
+            retried(&stats)
+        );
+        if stats.receipts > 0 {
+            println!("{} {} of them confirmed with a receipt", style("🧾").green(), stats.receipts);
+        }
+        if stats.refused > 0 {
+            println!("{} Refused {} requests from peers not allowed to receive", style("⛔").red(), stats.refused);
+        }
+        if stats.violations > 0 {
+            println!("{} Cut off {} peers for misbehaving requests", style("⛔").red(), stats.violations);
+        }
+        if self.cancel.is_cancelled() && stats.completed < copies {
+            println!("{} Stopped with {} of {} receivers complete", style("⏹").yellow(), stats.completed, copies);
+        }
+        Ok(())
+    }
+    
+    async fn stream_to_http<S: ItemStream>(&self, items: S, total_chunks: usize, upload_name: Option<&str>, encryption: Option<&Encryption>, bar: &CompressionBar, config: &Config) -> Result<()> {
+        let mut http_client = HttpFallback::new(config.fallback.clone()).await?.with_cancellation(self.cancel.clone()).with_upload_progress(Arc::new({
+            let bar = bar.clone();
+            move |sent| bar.sent(sent)
+        }));
+        if let Some(parity) = config.compression.parity()? {
+            http_client = http_client.with_parity(parity);
+        }
+        if let Some(Encryption::Age(recipients)) = encryption {
+            http_client = http_client.with_age_recipients(recipients.clone());
+        }
+        let transfer_id = new_transfer_id();
+        tracing::debug!(transfer_id = %transfer_id, total_chunks, "Starting HTTP upload");
+        
+        // The compression bar covers the upload too: chunks go on the wire as they are compressed,
+        // with only a couple waiting in between, and what's been sent shows beside the ratio
+        let tally = Arc::new(ChunkTally::default());
+        let on_item = {
+            let tally = tally.clone();
+            let transfer_id = transfer_id.clone();
+            move |item: &BundleItem| {
+                tally.record(item);
+                if let BundleItem::Chunk(chunk) = item {
+                    tracing::debug!(transfer_id = %transfer_id, chunk_index = chunk.index, bytes = chunk.data.len(), "Chunk uploaded");
+                }
+            }
+        };
+        
+        let download_url = http_client.upload_stream(items, upload_name, on_item).await?;
+        
+        tally.print_summary();
+        if let Some(Encryption::Age(recipients)) = encryption {
+            println!("{} Encrypted for {} age recipient(s)", style("🔒").green(), recipients.len());
+        }
+        print_upload_complete(http_client.last_endpoint().as_deref());
+        print_share_url(&download_url, &config.fallback);
+        
+        Ok(())
+    }
+    
+    async fn upload_encrypted<S: ItemStream>(&self, mut items: S, upload_name: Option<&str>, encryption: Option<&Encryption>, config: &Config) -> Result<()> {
+        // The bundle is encrypted and spooled to disk as chunks arrive, so neither it nor its
+        // ciphertext is ever held in memory
+        let spool = TempGuard::in_dir(&Config::cache_dir(), "upload", ScratchKind::Spool)?;
+        let mut encrypted = EncryptingWriter::new(std::io::BufWriter::new(std::fs::File::create(spool.path())?), encryption)?;
+        let mut bundle = BundleWriter::new(&mut encrypted)?;
+        let tally = ChunkTally::default();
+        while let Some(item) = items.next().await {
+            let item = item?;
+            tally.record(&item);
+            bundle.write_item(&item)?;
+        }
+        bundle.finish()?;
+        std::io::Write::flush(&mut encrypted.finish()?)?;
         
-        let progress_bar = ProgressBar::new_spinner();
-        progress_bar.set_style(ProgressStyle::default_spinner().template("{spinner:.green} {msg}").unwrap());
-        progress_bar.set_message("Uploading to HTTP server...");
-        progress_bar.enable_steady_tick(Duration::from_millis(100));
+        tally.print_summary();
+        match encryption {
+            Some(Encryption::Age(recipients)) => println!("{} Encrypted for {} age recipient(s)", style("🔒").green(), recipients.len()),
+            Some(Encryption::UrlKey(_)) => println!("{} Encrypted; the key is only in the share URL", style("🔒").green()),
+            Some(Encryption::Password { .. }) => println!("{} Encrypted; share the password separately", style("🔒").green()),
+            None => {}
+        }
+        
+        let size = std::fs::metadata(spool.path())?.len();
+        let progress = Progress::new(self.progress).start("upload", Some(size), Unit::Bytes);
+        progress.status(match config.fallback.backend {
+            FallbackBackend::Http => "Uploading to HTTP server...",
+            FallbackBackend::S3 => "Uploading to S3...",
+            FallbackBackend::File => "Copying to the shared directory...",
+            FallbackBackend::Sftp => "Uploading over SFTP...",
+        });
+        let on_sent = {
+            let progress = progress.clone();
+            move |sent: u64| progress.inc(sent)
+        };
         
-        let download_url = http_client.upload_chunks(chunks).await?;
+        let (download_url, endpoint) = match open_store(&config.fallback, self.cancel.clone(), Some(Arc::new(on_sent))).await {
+            Ok(store) => (store.upload_file(spool.path(), upload_name).await, store.upload_endpoint()),
+            Err(e) => (Err(e), None),
+        };
         
-        progress_bar.finish_and_clear();
+        progress.finish();
+        let download_url = share_url(&download_url?, encryption);
         
-        println!("{} Upload complete!", style("✓").green());
-        println!("{} Share this URL:", style("📋").cyan());
-        println!("  {}", style(&download_url).bold());
+        print_upload_complete(endpoint.as_deref());
+        print_share_url(&download_url, &config.fallback);
         
         Ok(())
     }
     
-    async fn receive_file(&self, url: &str, output_path: Option<&PathBuf>, config: &Config) -> Result<()> {
+    async fn receive_file(&self, url: &str, options: RecvOptions<'_>, config: &Config) -> Result<()> {
+        let RecvOptions { output: output_path, identity, cache_dir, resume, peers } = options;
+        let bare = bare_hash_url(url, peers)?;
+        let (url, peers) = match &bare {
+            Some((url, rest)) => (url.as_str(), *rest),
+            None => (url, peers),
+        };
+        // The key never leaves this process: it is split off before the URL is used or shown.
+        // The bundle's hash isn't secret, and stays on for the download to be checked against
+        let root = verify::split_url_hash(url)?.1;
+        let (url, key) = crypto::split_url_key(url)?;
+        let url = match root {
+            Some(root) => verify::url_with_hash(url, &root),
+            None => url.to_string(),
+        };
+        let url = url.as_str();
+        let keys = BundleKeys { identity, url_key: key.as_ref() };
         println!("{} Receiving file from: {}", style("📥").blue(), url);
         
-        let chunks = if is_http_url(url) {
-            self.download_from_http(url, config).await?
+        // Chunks that were encrypted in transit aren't written to disk in the clear
+        let cache = match &keys.url_key {
+            Some(_) => None,
+            None => open_cache(cache_dir),
+        };
+        let mut fill_cache = None;
+        let mut partial = None;
+        let mut receipt = None;
+        if !peers.is_empty() && (is_fallback_url(url) || parse_hybrid_url(url)?.2.is_some()) {
+            return Err(ShrLinkError::InvalidInput("--peer needs a shr:// URL without a fallback".to_string()));
+        }
+        let (bundle, file_name, transport) = if is_fallback_url(url) {
+            // Without a hash to go by, the body is whatever this URL serves
+            let file_hash = match root {
+                Some(root) => *root.as_bytes(),
+                None => *blake3::hash(verify::split_url_hash(url)?.0.as_bytes()).as_bytes(),
+            };
+            let kept = partial.insert(open_partial(partial_path(output_path, &file_hash), file_hash, 0, resume)?);
+            let downloaded = self.download_from_http(url, &keys, cache.as_ref(), kept, output_path, config).await;
+            match downloaded {
+                Ok(HttpDownload::Written(saved)) => {
+                    if let Some(partial) = partial {
+                        remove_partial(partial);
+                    }
+                    println!("{} {}", style("💾").green(), saved);
+                    return Ok(());
+                }
+                Ok(HttpDownload::Bundle(bundle, file_name, plain)) => {
+                    fill_cache = cache.filter(|_| plain);
+                    (bundle, file_name, http_transport(url))
+                }
+                Err(e) => {
+                    // As below, a file that came out wrong isn't worth resuming
+                    if let Some(kept) = partial.take() {
+                        match &e {
+                            ShrLinkError::HashMismatch { .. } => remove_partial(kept),
+                            _ => discard_if_empty(kept),
+                        }
+                    }
+                    return Err(e);
+                }
+            }
+        } else if let (_, _, Some(fallback_url)) = parse_hybrid_url(url)? {
+            // What was kept would depend on which source won
+            if resume {
+                return Err(ShrLinkError::InvalidInput("--resume needs a shr:// URL without a fallback, or the fallback's HTTP URL".to_string()));
+            }
+            let (bundle, file_name, transport, pending) = self.download_racing(url, &fallback_url, &keys, config).await?;
+            receipt = pending;
+            (bundle, file_name, transport)
         } else {
-            self.download_from_p2p(url, config).await?
+            let (bundle, kept, pending) = self.download_from_p2p(url, peers, output_path, cache.as_ref(), resume, config).await?;
+            partial = Some(kept);
+            receipt = Some(pending);
+            fill_cache = cache;
+            (bundle, None, Transport::P2P)
         };
         
-        println!("{} Downloaded {} chunks", style("✓").green(), chunks.len());
+        println!("{} Downloaded {} chunks via {}", style("✓").green(), bundle.chunk_count(), transport);
         
-        let output_file = output_path.cloned().unwrap_or_else(|| {
-            PathBuf::from(format!("received_file_{}", uuid::Uuid::new_v4()))
-        });
+        let (targets, saved) = match bundle.entries.as_slice() {
+            [entry] if entry.meta.as_ref().is_none_or(|m| m.kind == EntryKind::File) => {
+                let output_file = single_target(output_path, entry.meta.as_ref(), file_name.as_deref());
+                let saved = format!("File saved to: {}", output_file.display());
+                (vec![(output_file, entry)], saved)
+            }
+            entries => {
+                let output_dir = output_path.cloned().unwrap_or_else(|| PathBuf::from("."));
+                let saved = format!("Saved {} entries to: {}", entries.len(), output_dir.display());
+                (archive_targets(&output_dir, entries)?, saved)
+            }
+        };
+        
+        let transfer_id = new_transfer_id();
+        tracing::debug!(transfer_id = %transfer_id, chunks = bundle.chunk_count(), files = targets.len(), %transport, "Reconstructing files");
+        let reconstructed = self.reconstruct_files(&targets, bundle.dictionary.as_ref(), &transfer_id, config).await;
+        // Every chunk checked out but together they aren't the file, so none are worth resuming with
+        if let Err(ShrLinkError::HashMismatch { .. }) = &reconstructed {
+            if let Some(kept) = partial.take() {
+                remove_partial(kept);
+            }
+        }
+        reconstructed?;
+        
+        // Only now that every chunk has been checked against its hash
+        if let Some(cache) = fill_cache {
+            store_chunks(&cache, &bundle);
+        }
+        if let Some(partial) = partial {
+            remove_partial(partial);
+        }
         
-        self.reconstruct_file(&chunks, &output_file, config).await?;
+        println!("{} {}", style("💾").green(), saved);
         
-        println!("{} File saved to: {}", style("💾").green(), output_file.display());
+        if let Some(receipt) = receipt {
+            let bytes = bundle.entries.iter().flat_map(|e| &e.chunks).map(|c| c.original_size as u64).sum();
+            receipt.deliver(bytes).await;
+        }
         
         Ok(())
     }
     
-    async fn download_from_http(&self, url: &str, config: &Config) -> Result<Vec<crate::compression::CompressedChunk>> {
+    // Asks the peer for its manifest and the server for the bundle at the same time, and
+    // downloads from whichever answers first; the other one is kept around as a failover
+    async fn download_racing(&self, url: &str, fallback_url: &str, keys: &BundleKeys<'_>, config: &Config) -> Result<(Bundle, Option<String>, Transport, Option<PendingReceipt>)> {
+        let started = Instant::now();
+        let (peer_id, file_hash, _) = parse_hybrid_url(url)?;
+        let addrs = shr_url_addrs(url)?;
+        let p2p_config = config.p2p.clone();
+        let p2p_timeout = Duration::from_millis(config.p2p.timeout_ms);
         let http_client = HttpFallback::new(config.fallback.clone()).await?;
+        let fallback_url = fallback_url.to_string();
         
-        let progress_bar = ProgressBar::new_spinner();
-        progress_bar.set_style(ProgressStyle::default_spinner().template("{spinner:.green} {msg}").unwrap());
-        progress_bar.set_message("Downloading from HTTP server...");
-        progress_bar.enable_steady_tick(Duration::from_millis(100));
+        let p2p_preflight = async move {
+            let mut client = P2PClient::new(p2p_config).await?;
+            let manifest = tokio::time::timeout(p2p_timeout, async {
+                client.reach_peer(peer_id, &addrs).await?;
+                client.request_manifest(peer_id, &file_hash).await
+            })
+            .await
+            .map_err(|_| ShrLinkError::Timeout(format!("peer {} did not answer with the manifest within p2p.timeout_ms", peer_id)))??;
+            Ok((client, manifest))
+        };
+        let http_preflight = async move { http_client.open_bundle(&fallback_url).await };
         
-        let chunks = http_client.download_chunks(url).await?;
+        let outcome = race_sources(p2p_preflight, http_preflight, true, &CancellationToken::new()).await?;
+        println!("{} Using {} source", style("🏁").cyan(), outcome.transport());
         
-        progress_bar.finish_and_clear();
+        let (transport, (bundle, file_name, receipt)) = fetch_with_failover(
+            outcome,
+            |(mut client, manifest)| async move {
+                let chunks = client.fetch_chunks(peer_id, &manifest, |_| {}).await?;
+                let receipt = PendingReceipt { client, peer_id, file_hash: manifest.file_hash, started };
+                Ok((peer_bundle(chunks, manifest.file_hash), None, Some(receipt)))
+            },
+            |response| async move {
+                let (bundle, file_name) = response.read().await?;
+                Ok((decode_bundle(bundle, keys)?, file_name, None))
+            },
+        )
+        .await?;
         
-        Ok(chunks)
+        Ok((bundle, file_name, transport, receipt))
     }
     
-    async fn download_from_p2p(&self, url: &str, config: &Config) -> Result<Vec<crate::compression::CompressedChunk>> {
-        let (peer_id, _file_hash) = parse_shr_url(url)?;
+    // A plain bundle is written out as it arrives, holding no more of it than the chunks on
+    // their way to disk. A URL with a key is only ever for a sealed bundle, which has to be
+    // read whole to be decrypted, as does anything else that isn't plain. Parity can only
+    // rebuild a damaged chunk with the whole bundle at hand, so one fetches the rest for that
+    async fn download_from_http(&self, url: &str, keys: &BundleKeys<'_>, cache: Option<&ChunkCache>, partial: &mut PartialDownload, output_path: Option<&PathBuf>, config: &Config) -> Result<HttpDownload> {
+        let mut http_client = HttpFallback::new(config.fallback.clone()).await?;
+        if let Some(cache) = cache {
+            http_client = http_client.with_cache(cache.clone());
+        }
+        if partial.body_len() > 0 {
+            println!(
+                "{} Resuming with {:.2} MB already downloaded",
+                style("⏳").yellow(),
+                partial.body_len() as f64 / (1024.0 * 1024.0)
+            );
+        }
         
-        let _p2p_client = P2PClient::new(config.p2p.clone()).await?;
+        let response = http_client.open_bundle(url).await?;
+        let mut progress = Progress::new(self.progress).start("download", response.content_length(), Unit::Bytes);
+        progress.status(match http_transport(url) {
+            Transport::File => "Reading from the shared directory...",
+            Transport::Sftp => "Downloading over SFTP...",
+            _ => "Downloading from HTTP server...",
+        });
+        let mut response = with_download_progress(response, &progress);
         
-        println!("{} Connecting to peer: {}", style("🔗").yellow(), peer_id);
+        // An age bundle is decrypted a segment at a time as it arrives and written out like a
+        // plain one, with --identity or a passphrase. Its chunks stay out of the cache, as
+        // anything that came encrypted does
+        let age = crypto::is_age_encrypted(response.peek(crypto::AGE_MAGIC.len()).await?);
+        if age && keys.url_key.is_none() {
+            let file_name = response.original_name().map(str::to_string);
+            let (tx, rx) = tokio::sync::mpsc::channel(STREAMED_ITEMS);
+            let body = tokio_util::io::SyncIoBridge::new(response.into_body());
+            let identity = keys.identity.map(Path::to_path_buf);
+            let reading = tokio::task::spawn_blocking(move || read_age_items(body, identity.as_deref(), tx));
+            let writing = write_streamed(self.receive_compressor(config)?, rx, output_path, file_name.as_deref(), None, |_| {});
+            let (read, written) = tokio::join!(reading, writing);
+            progress.finish();
+            
+            // The reading stops short once writing has failed, so that's the error to report
+            let written = written?;
+            read.map_err(|e| ShrLinkError::Other(e.into()))??;
+            println!("{} Decrypted age payload", style("🔓").green());
+            println!("{} Downloaded {} chunks via {}", style("✓").green(), written.chunks, http_transport(url));
+            return Ok(HttpDownload::Written(written.finish()?));
+        }
         
-        // In a real implementation, you'd:
-        // 1. Connect to the peer
-        // 2. Request the file chunks
-        // 3. Receive and verify chunks
+        if keys.url_key.is_none() {
+            let file_name = response.original_name().map(str::to_string);
+            let transfer_id = new_transfer_id();
+            let (tx, rx) = tokio::sync::mpsc::channel(STREAMED_ITEMS);
+            
+            let writing = write_streamed(self.receive_compressor(config)?, rx, output_path, file_name.as_deref(), cache, |chunk| {
+                tracing::debug!(transfer_id = %transfer_id, chunk_index = chunk.index, "Chunk written");
+            });
+            let (downloaded, written) = tokio::join!(http_client.stream_bundle_download(response, Some(&mut *partial), tx), writing);
+            progress.finish();
+            
+            match (downloaded, written) {
+                (_, Err(e)) if is_damaged_chunk(&e) => {
+                    println!("{} {}; fetching the rest of the bundle to repair it", style("⚠").yellow(), e);
+                    // What arrived is kept in `partial`, so only the rest is asked for; the bar
+                    // starts at what's already there
+                    let reopened = http_client.open_bundle(url).await?;
+                    progress = Progress::new(self.progress).start("download", reopened.content_length(), Unit::Bytes);
+                    response = with_download_progress(reopened, &progress);
+                }
+                // The download stops short once writing has failed, so that's the error to report
+                (_, Err(e)) | (Err(e), _) => return Err(e),
+                (Ok(CachedDownload::Streamed { chunks_reused, bytes_skipped, .. }), Ok(written)) => {
+                    print_reused(chunks_reused, bytes_skipped);
+                    println!("{} Downloaded {} chunks via {}", style("✓").green(), written.chunks, http_transport(url));
+                    return Ok(HttpDownload::Written(written.finish()?));
+                }
+                (Ok(CachedDownload::Raw(bundle, file_name)), Ok(_)) => {
+                    return Ok(HttpDownload::Bundle(decode_bundle(bundle, keys)?, file_name, false));
+                }
+                (Ok(CachedDownload::Bundle { bundle, original_name, .. }), Ok(_)) => {
+                    return Ok(HttpDownload::Bundle(bundle, original_name, true));
+                }
+            }
+        }
         
-        // For now, return an error as this is not fully implemented
-        Err(ShrLinkError::Network("P2P download not fully implemented yet".to_string()))
+        let downloaded = http_client.resume_opened_download(response, partial).await;
+        progress.finish();
+        
+        match downloaded? {
+            // Otherwise a server could swap in a bundle of its own choosing
+            CachedDownload::Bundle { .. } if keys.url_key.is_some() => {
+                Err(ShrLinkError::Decryption("the URL has a key but the bundle served is not encrypted".to_string()))
+            }
+            CachedDownload::Bundle { bundle, original_name, chunks_reused, bytes_skipped } => {
+                print_reused(chunks_reused, bytes_skipped);
+                Ok(HttpDownload::Bundle(bundle, original_name, true))
+            }
+            CachedDownload::Raw(bundle, file_name) => Ok(HttpDownload::Bundle(decode_bundle(bundle, keys)?, file_name, false)),
+            CachedDownload::Streamed { .. } => unreachable!("nothing was given to stream to"),
+        }
     }
     
-    async fn reconstruct_file(&self, chunks: &[crate::compression::CompressedChunk], output_path: &PathBuf, config: &Config) -> Result<()> {
-        let compressor = ParallelCompressor::new(
-            config.compression.block_size,
-            config.compression.acceleration,
-        ).with_workers(config.get_parallel_workers());
-        
-        let progress_bar = ProgressBar::new(chunks.len() as u64);
-        progress_bar.set_style(
-            ProgressStyle::default_bar()
-                .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} chunks ({msg})")
-                .unwrap()
-                .progress_chars("#>-")
-        );
+    // Also hands back what it kept of the chunks, to be removed once they're written out, and
+    // the URL's peer to send a receipt to once they check out
+    // The chunks come from the peer the URL names and from each of `peers` that has the same
+    // file, whichever has them; the manifest only comes from the URL's peer
+    async fn download_from_p2p(&self, url: &str, peers: &[Multiaddr], output_path: Option<&PathBuf>, cache: Option<&ChunkCache>, resume: bool, config: &Config) -> Result<(Bundle, PartialDownload, PendingReceipt)> {
+        let started = Instant::now();
+        let (peer_id, file_hash) = parse_shr_url(url)?;
+        let addrs = shr_url_addrs(url)?;
+        let p2p_timeout = Duration::from_millis(config.p2p.timeout_ms);
+        
+        let mut p2p_client = P2PClient::new(config.p2p.clone()).await?;
+        if let Some(cache) = cache {
+            p2p_client = p2p_client.with_cache(cache.clone());
+        }
         
-        let mut output_file = File::create(output_path).await?;
+        println!("{} Connecting to peer: {}", style("🔗").yellow(), peer_id);
+        
+        let manifest = tokio::time::timeout(p2p_timeout, async {
+            p2p_client.connect_to(peer_id, &addrs).await?;
+            p2p_client.request_manifest(peer_id, &file_hash).await
+        })
+        .await
+        .map_err(|_| ShrLinkError::Timeout(format!("peer {} did not answer with the manifest within p2p.timeout_ms", peer_id)))??;
+        if let Some(path) = p2p_client.connection_path(&peer_id) {
+            println!("{} Connected {}", style("🔗").green(), path);
+        }
         
-        for chunk in chunks {
-            let decompressed = compressor.decompress_chunk(chunk)?;
-            output_file.write_all(&decompressed).await?;
-            progress_bar.inc(1);
+        // A peer that can't be reached or can't serve these chunks is left out, not fatal
+        let mut providers = vec![peer_id];
+        for addr in peers {
+            let Some(other) = swarm::peer_id_in(addr).filter(|other| !providers.contains(other)) else { continue };
+            let reached = tokio::time::timeout(p2p_timeout, async {
+                p2p_client.connect_to(other, std::slice::from_ref(addr)).await?;
+                p2p_client.handshake(other).await
+            })
+            .await
+            .unwrap_or_else(|_| Err(ShrLinkError::Timeout(format!("peer {} didn't answer within p2p.timeout_ms", other))));
+            match reached {
+                Ok(_) => providers.push(other),
+                Err(e) => println!("{} Leaving out peer {}: {}", style("⚠").yellow(), other, e),
+            }
+        }
+        if providers.len() > 1 {
+            println!("{} Fetching from {} peers", style("🔗").green(), providers.len());
         }
         
-        progress_bar.finish_with_message("Complete!");
-        output_file.flush().await?;
+        let file_hash = manifest.file_hash;
+        let mut partial = open_partial(partial_path(output_path, &file_hash), file_hash, manifest.entries.len(), resume)?;
+        if partial.received() > 0 {
+            println!("{} Resuming with {} of {} chunks already received", style("⏳").yellow(), partial.received(), manifest.entries.len());
+        }
         
-        Ok(())
+        let progress = Progress::new(self.progress).start("download", Some(manifest.entries.len() as u64), Unit::Chunks);
+        progress.inc(partial.received() as u64);
+        let mut have = partial.take_chunks();
+        // What an earlier transfer left in the cache isn't asked for, as the sender was told when
+        // it offered the manifest
+        if let Some(cache) = cache {
+            let mut cached = 0;
+            for (slot, entry) in have.iter_mut().zip(&manifest.entries).filter(|(slot, _)| slot.is_none()) {
+                *slot = cache.get(&entry.hash).map(|chunk| CompressedChunk { index: entry.index, ..chunk });
+                cached += slot.is_some() as usize;
+            }
+            if cached > 0 {
+                println!("{} {} of {} chunks are already in the cache", style("♻").green(), cached, manifest.entries.len());
+                progress.inc(cached as u64);
+            }
+        }
+        // A chunk that can't be kept only costs fetching it again if this receive is cut short
+        let chunks = p2p_client.resume_from_peers(&providers, &manifest, have, |position, chunk| {
+            if let Err(e) = partial.record(position, chunk) {
+                tracing::warn!("Failed to keep chunk {} in {}: {}", chunk.index, partial.path().display(), e);
+            }
+            progress.inc(1);
+        })
+        .await;
+        progress.finish();
+        match chunks {
+            Ok(chunks) => Ok((peer_bundle(chunks, file_hash), partial, PendingReceipt { client: p2p_client, peer_id, file_hash, started })),
+            Err(e) => {
+                discard_if_empty(partial);
+                Err(e)
+            }
+        }
+    }
+    
+    // Each file is verified and renamed into place on its own, then given back the sender's
+    // modification time and permissions
+    async fn reconstruct_files(&self, targets: &[(PathBuf, &BundleEntry)], dictionary: Option<&Dictionary>, transfer_id: &str, config: &Config) -> Result<()> {
+        // Checked up front so a bundle that lies about a size writes nothing
+        for (_, entry) in targets {
+            if let Some(meta) = &entry.meta {
+                let sizes: Vec<usize> = entry.chunks.iter().map(|c| c.original_size).collect();
+                check_chunk_sizes(meta, &sizes)?;
+            }
+        }
+        
+        let mut compressor = self.receive_compressor(config)?;
+        if let Some(dictionary) = dictionary {
+            compressor = compressor.with_dictionary(dictionary.clone());
+        }
+        
+        let total_chunks: usize = targets.iter().map(|(_, entry)| entry.chunks.len()).sum();
+        let progress = Progress::new(self.progress).start("write", Some(total_chunks as u64), Unit::Chunks);
+        
+        let result = write_entries(&compressor, targets, |chunk| {
+            tracing::debug!(transfer_id, chunk_index = chunk.index, "Chunk written");
+            progress.inc(1);
+        }).await;
+        
+        progress.finish();
+        result
+    }
+    
+    fn receive_compressor(&self, config: &Config) -> Result<ParallelCompressor> {
+        Ok(ParallelCompressor::with_block_size(
+            config.compression.block_size,
+            config.compression.acceleration,
+        )?
+        .with_workers(config.get_parallel_workers())
+        .with_memory_budget(config.compression.max_inflight_decompressed_bytes))
     }
     
-    async fn handle_config(&self, action: Option<&ConfigAction>, config: &Config) -> Result<()> {
+    // Every write goes to the resolved file, so --config and SHRLINK_CONFIG are edited in place
+    async fn handle_config(&self, action: Option<&ConfigAction>, config: &Config, location: &ConfigLocation) -> Result<()> {
         match action {
             Some(ConfigAction::Show) | None => {
-                println!("Current configuration:");
+                println!("Current configuration ({}):", location.path.display());
                 println!("{}", toml::to_string_pretty(config).unwrap());
             }
+            Some(ConfigAction::Path) => {
+                println!("{} ({})", location.path.display(), location.source);
+            }
             Some(ConfigAction::Reset) => {
                 let default_config = Config::default();
-                default_config.save()?;
+                default_config.save(&location.path)?;
                 println!("{} Configuration reset to defaults", style("✓").green());
             }
             Some(ConfigAction::Set { key, value }) => {
-                println!("Setting {} = {}", key, value);
-                // In a real implementation, you'd parse the key and update the config
-                println!("{} Configuration updated", style("✓").green());
+                config.set(key, value)?.save(&location.path)?;
+                println!("{} Set {} = {} in {}", style("✓").green(), key, value, location.path.display());
+            }
+            Some(ConfigAction::Edit) => {
+                let editor = std::env::var("VISUAL")
+                    .or_else(|_| std::env::var("EDITOR"))
+                    .unwrap_or_else(|_| "vi".to_string());
+                let mut words = editor.split_whitespace();
+                let program = words.next().unwrap_or("vi");
+                
+                let status = tokio::process::Command::new(program)
+                    .args(words)
+                    .arg(&location.path)
+                    .status()
+                    .await?;
+                if !status.success() {
+                    return Err(ShrLinkError::InvalidInput(format!("{} exited with {}", program, status)));
+                }
+                
+                // Catch mistakes now rather than on the next transfer
+                Config::load(location)?;
+                println!("{} Configuration saved to {}", style("✓").green(), location.path.display());
             }
         }
         Ok(())
     }
     
-    async fn cleanup_http(&self, config: &Config) -> Result<()> {
-        let http_client = HttpFallback::new(config.fallback.clone()).await?;
+    fn estimate_file(&self, path: &Path, bandwidth: Option<f64>, algorithm: Option<CompressionAlgorithm>, sample_size: u64, config: &Config) -> Result<()> {
+        if let Some(mbit) = bandwidth.filter(|mbit| !(*mbit > 0.0 && mbit.is_finite())) {
+            return Err(ShrLinkError::InvalidInput(format!("Bandwidth must be a positive number of megabits per second (got {})", mbit)));
+        }
+        if sample_size == 0 {
+            return Err(ShrLinkError::InvalidInput("Sample size must be at least 1 byte".to_string()));
+        }
         
-        println!("{} Cleaning up old files on HTTP server...", style("🧹").yellow());
+        let compressor = ParallelCompressor::with_block_size(
+            config.compression.block_size,
+            config.compression.acceleration,
+        )?
+        .with_algorithm(match algorithm {
+            Some(algorithm) => algorithm,
+            None => config.compression.compression_algorithm()?,
+        })
+        .with_chunking(config.compression.chunking()?)
+        .with_checksum(config.compression.checksum)
+        .with_min_savings(config.compression.min_savings_percent)
+        .with_workers(config.get_parallel_workers())
+        .with_cancellation(self.cancel.clone());
         
-        let deleted_count = http_client.cleanup_old_files().await?;
+        println!("{} Estimating {}", style("📏").blue(), path.display());
+        let estimate = compressor.estimate_ratio(path, sample_size)?;
         
-        println!("{} Deleted {} old files", style("✓").green(), deleted_count);
+        let how = if estimate.exact {
+            "compressed in full".to_string()
+        } else {
+            format!("from {} bytes sampled", estimate.sampled_bytes)
+        };
+        println!("  Original size:   {} bytes", estimate.original_size);
+        println!("  Compressed size: ~{} bytes ({:.1}% of original, {})", estimate.estimated_size, estimate.ratio * 100.0, how);
+        match compressor.sized_for(estimate.original_size).fixed_block_size() {
+            Some(block_size) => println!("  Chunks:          {} of {} bytes", estimate.chunk_count, block_size),
+            None => println!("  Chunks:          about {} (content-defined)", estimate.chunk_count),
+        }
+        if let Some(mbit) = bandwidth {
+            let secs = estimate.estimated_size as f64 * 8.0 / (mbit * 1_000_000.0);
+            println!("  Upload time:     ~{} at {} Mbit/s", format_duration(secs.ceil() as u64), mbit);
+        }
         
         Ok(())
     }
     
-    async fn show_stats(&self, config: &Config) -> Result<()> {
-        let http_client = HttpFallback::new(config.fallback.clone()).await?;
+    async fn run_doctor(&self, timeout: u64, config: &Config) -> Result<()> {
+        println!("{} Probing reachability...", style("🩺").blue());
         
-        println!("{} Fetching statistics...", style("📊").blue());
-        
-        let stats = http_client.get_upload_stats().await?;
+        let mut p2p_client = P2PClient::new(config.p2p.clone()).await?;
+        // The relays dial back what's listened on, and the router maps it
+        p2p_client.listen().await?;
+        if config.p2p.enable_upnp {
+            p2p_client.await_port_mapping(Duration::from_secs(timeout)).await;
+        }
+        let report = p2p_client.probe_reachability(Duration::from_secs(timeout)).await;
+        p2p_client.close().await;
         
-        println!("HTTP Fallback Statistics:");
-        println!("  Total files: {}", stats.total_files);
-        println!("  Total size: {:.2} MB", stats.total_bytes as f64 / (1024.0 * 1024.0));
+        for line in report.lines() {
+            println!("  {}", line);
+        }
         
         Ok(())
     }
+    
+    async fn browse(&self, timeout: u64, config: &Config) -> Result<()> {
+        println!("{} Looking for files shared on the local network...", style("🔍").yellow());
+        
+        let mut p2p_client = P2PClient::new(config.p2p.clone()).await?;
+        let found = p2p_client.browse(Duration::from_secs(timeout)).await;
+        p2p_client.close().await;
+        let found = found?;
+        if found.is_empty() {
+            println!("Nothing shared on the local network");
+            return Ok(());
+        }
+        
+        println!("{:<32} {:>10} {:<12} URL", "FILE", "SIZE", "PEER");
+        for announcement in found {
+            println!(
+                "{:<32} {:>10} {:<12} {}",
+                announcement.file_name,
+                indicatif::HumanBytes(announcement.size).to_string(),
+                short_peer_id(&announcement.peer_id),
+                announcement.url
+            );
+        }
+        
+        Ok(())
+    }
+    
+    fn show_id(&self, config: &Config) -> Result<()> {
+        let keypair = crate::p2p::identity::load_or_create(&config.p2p.identity_file())?;
+        println!("{}", keypair.public().to_peer_id());
+        Ok(())
+    }
+    
+    fn handle_peer(&self, action: &PeerAction, config: &Config) -> Result<()> {
+        let mut store = ReputationStore::load(&Config::reputation_path(), reputation::DEFAULT_CAPACITY);
+        
+        match action {
+            PeerAction::List => {
+                if store.is_empty() {
+                    println!("No peers on record");
+                    return Ok(());
+                }
+                
+                // What each was sent, and how fast its last serve went under the throttle
+                let stats = StatsStore::load(&Config::stats_path());
+                if let Some(cap) = config.p2p.per_peer_max_bps {
+                    println!("Serves to each peer are capped at {}/s (p2p.per_peer_max_bps)", indicatif::HumanBytes(cap));
+                }
+                let now = reputation::unix_now();
+                println!("{:<54} {:>8} {:>10} {:>10} {:>12} {:>12}", "PEER", "STRIKES", "SUCCESSES", "SENT", "LAST RATE", "LAST SEEN");
+                for (peer_id, record) in store.entries() {
+                    let strikes = record.effective_strikes(now);
+                    let status = if strikes >= BLOCK_THRESHOLD {
+                        style(" blocked").red().to_string()
+                    } else {
+                        String::new()
+                    };
+                    let served = stats.stats().peers.get(peer_id);
+                    println!(
+                        "{:<54} {:>8.1} {:>10} {:>10} {:>12} {:>12}{}",
+                        peer_id,
+                        strikes,
+                        record.successes,
+                        indicatif::HumanBytes(served.map_or(0, |p| p.bytes_sent)).to_string(),
+                        served.filter(|p| p.serve_bps > 0).map_or_else(|| "-".to_string(), |p| format!("{}/s", indicatif::HumanBytes(p.serve_bps))),
+                        format_age(now.saturating_sub(record.last_seen)),
+                        status
+                    );
+                }
+            }
+            PeerAction::Forgive { peer_id } => {
+                let peer_id = peer_id.parse::<libp2p::PeerId>()
+                    .map_err(|e| ShrLinkError::InvalidInput(format!("Invalid peer ID: {}", e)))?;
+                
+                if store.forgive(&peer_id) {
+                    store.save()?;
+                    println!("{} Reputation reset for {}", style("✓").green(), peer_id);
+                } else {
+                    println!("No reputation on record for {}", peer_id);
+                }
+            }
+        }
+        
+        Ok(())
+    }
+    
+    fn cleanup_local(&self) -> Result<()> {
+        println!("{} Cleaning up local scratch files...", style("🧹").yellow());
+        
+        let mut removed = 0;
+        let mut bytes = 0;
+        for dir in [Config::cache_dir(), PathBuf::from(".")] {
+            let report = temp::sweep(&dir, temp::DEFAULT_SWEEP_TTL)?;
+            removed += report.removed.len();
+            bytes += report.bytes;
+        }
+        
+        println!("{} Deleted {} scratch files ({:.2} MB)", style("✓").green(), removed, bytes as f64 / (1024.0 * 1024.0));
+        
+        Ok(())
+    }
+    
+    async fn cleanup_http(&self, dry_run: bool, config: &Config) -> Result<()> {
+        let store = open_store(&config.fallback, self.cancel.clone(), None).await?;
+        
+        let action = if dry_run { "Looking for" } else { "Cleaning up" };
+        match config.fallback.backend {
+            FallbackBackend::Http => println!("{} {} old files on HTTP server...", style("🧹").yellow(), action),
+            FallbackBackend::S3 => println!("{} {} old files in s3://{}...", style("🧹").yellow(), action, config.fallback.bucket),
+            FallbackBackend::File => println!("{} {} old files in {}...", style("🧹").yellow(), action, config.fallback.path.clone().unwrap_or_default().display()),
+            FallbackBackend::Sftp => println!("{} {} old files in {}:{}...", style("🧹").yellow(), action, config.fallback.sftp.host, config.fallback.sftp.remote_dir),
+        }
+        
+        let report = store.cleanup_old_files(dry_run).await?;
+        
+        // Servers that only count what they delete don't name it
+        let mut listed: Vec<_> = report.deleted.iter().filter(|file| !file.name.is_empty()).collect();
+        if !listed.is_empty() {
+            listed.sort_by_key(|file| std::cmp::Reverse(file.age_secs));
+            println!("{:>10} {:>10}  FILE", "SIZE", "UPLOADED");
+            for file in listed {
+                println!("{:>10} {:>10}  {}", indicatif::HumanBytes(file.size).to_string(), format_age(file.age_secs), file.name);
+            }
+        }
+        
+        let freed = indicatif::HumanBytes(report.bytes_freed);
+        if dry_run {
+            println!("{} Would delete {} old files ({}); nothing was deleted", style("✓").green(), report.deleted.len(), freed);
+        } else {
+            println!("{} Deleted {} old files ({})", style("✓").green(), report.deleted.len(), freed);
+        }
+        
+        Ok(())
+    }
+    
+    async fn delete_upload(&self, url: &str, config: &Config) -> Result<()> {
+        if !is_fallback_url(url) {
+            return Err(ShrLinkError::InvalidInput(format!("Only fallback uploads can be deleted, not {}", url)));
+        }
+        // Neither the key nor the hash in the fragment has anything to do with where it is
+        let url = url.split_once('#').map_or(url, |(url, _)| url);
+        let store = open_store(&config.fallback, self.cancel.clone(), None).await?;
+        if !store.delete_file(url).await? {
+            return Err(ShrLinkError::NotFound(url.to_string()));
+        }
+        
+        println!("{} Deleted {}", style("🗑").green(), url);
+        Ok(())
+    }
+    
+    async fn serve_fallback(&self, listen: SocketAddr, dir: &Path, token: Option<String>, auth_downloads: bool, public_url: Option<&url::Url>) -> Result<()> {
+        let guarded = token.is_some();
+        let mut server = FallbackServer::new(dir)?;
+        if let Some(token) = token {
+            server = server.with_token(token, auth_downloads);
+        }
+        if let Some(public_url) = public_url {
+            server = server.with_public_url(public_url.as_str());
+        }
+        let listener = std::net::TcpListener::bind(listen)
+            .map_err(|e| ShrLinkError::Network(format!("Failed to listen on {}: {}", listen, e)))?;
+        let (files, bytes) = server.store().totals();
+        
+        println!("{} Serving {} at http://{}", style("🌐").green(), server.store().dir().display(), listener.local_addr()?);
+        println!("  Holding {} files ({})", files, indicatif::HumanBytes(bytes));
+        if auth_downloads {
+            println!("  Every request needs the token");
+        } else if guarded {
+            println!("  Uploads, deletes, cleanup and stats need the token; downloads are open");
+        }
+        println!("Press Ctrl-C to stop");
+        
+        cancel_on_ctrl_c(self.cancel.clone());
+        server.serve(listener, self.cancel.clone()).await
+    }
+    
+    async fn show_stats(&self, detailed: bool, json: bool, config: &Config) -> Result<()> {
+        let p2p = StatsStore::load(&Config::stats_path());
+        if !json {
+            print_p2p_stats(p2p.stats());
+            println!();
+            
+            println!("{} Fetching statistics...", style("📊").blue());
+        }
+        
+        let label = match config.fallback.backend {
+            FallbackBackend::Http => "HTTP fallback",
+            FallbackBackend::S3 => "S3 fallback",
+            FallbackBackend::File => "Shared directory fallback",
+            FallbackBackend::Sftp => "SFTP fallback",
+        };
+        // The local counts are already out, so an unreachable server doesn't fail the command
+        let stats = match open_store(&config.fallback, self.cancel.clone(), None).await {
+            Ok(store) => store.get_upload_stats().await,
+            Err(e) => Err(e),
+        };
+        
+        // The same, with an unreachable server's error in place of its numbers
+        if json {
+            let mut fallback = match stats {
+                Ok(stats) => serde_json::to_value(stats).map_err(|e| ShrLinkError::Other(e.into()))?,
+                Err(e) => serde_json::json!({ "error": e.to_string() }),
+            };
+            fallback["backend"] = serde_json::json!(config.fallback.backend);
+            let output = serde_json::json!({ "p2p": p2p.stats(), "fallback": fallback });
+            println!("{}", serde_json::to_string_pretty(&output).map_err(|e| ShrLinkError::Other(e.into()))?);
+            return Ok(());
+        }
+        
+        match stats {
+            Ok(stats) => {
+                println!("{} statistics:", label);
+                println!("  Total files: {}", stats.total_files);
+                println!("  Total size: {:.2} MB", stats.total_bytes as f64 / (1024.0 * 1024.0));
+                if detailed {
+                    print_fallback_files(stats.files.as_deref());
+                }
+            }
+            Err(e) => println!("{} {} statistics unavailable: {}", style("⚠").yellow(), label, e),
+        }
+        
+        Ok(())
+    }
+    
+    fn reset_stats(&self) -> Result<()> {
+        let mut store = StatsStore::load(&Config::stats_path());
+        store.reset();
+        store.save()?;
+        println!("{} P2P statistics reset", style("✓").green());
+        Ok(())
+    }
+}
+
+// What an HTTP receive came to: its files already written, or a bundle still to write out,
+// along with whether it was a plain one, whose chunks may be cached
+enum HttpDownload {
+    Written(String),
+    Bundle(Bundle, Option<String>, bool),
+}
+
+struct RecvOptions<'a> {
+    output: Option<&'a PathBuf>,
+    identity: Option<&'a Path>,
+    cache_dir: Option<&'a Path>,
+    resume: bool,
+    // Other peers to fetch chunks from alongside the URL's
+    peers: &'a [Multiaddr],
+}
+
+// The sender of a P2P receive, told once the whole file has checked out
+struct PendingReceipt {
+    client: P2PClient,
+    peer_id: libp2p::PeerId,
+    file_hash: [u8; 32],
+    started: Instant,
+}
+
+impl PendingReceipt {
+    // Never fails the receive: the sender may have gone, or be from before receipts
+    async fn deliver(mut self, bytes: u64) {
+        let receipt = match self.client.issue_receipt(self.file_hash, bytes, self.started.elapsed(), machine_name()) {
+            Ok(receipt) => receipt,
+            Err(e) => {
+                tracing::debug!("Failed to issue a receipt: {}", e);
+                return;
+            }
+        };
+        if self.client.deliver_receipt(self.peer_id, &receipt).await {
+            println!("{} Sent {} a receipt", style("🧾").green(), short_peer_id(&self.peer_id.to_string()));
+        }
+    }
+}
+
+struct SendOptions {
+    exclude: Option<globset::GlobSet>,
+    follow_symlinks: bool,
+    algorithm: Option<CompressionAlgorithm>,
+    dictionary: bool,
+    copies: usize,
+    // How long to look for peers before going over HTTP, in seconds
+    timeout: Option<u64>,
+    // Hand the URL over to whoever has a pairing code, rather than print it
+    code: bool,
+}
+
+// Everything a served file is made of, along with the names and hashes the URL is built from.
+// The URL names the content, so it stays the same whatever the block size or codec
+async fn collect_for_serving<S: ItemStream>(mut items: S, total_chunks: usize) -> Result<(Vec<(String, [u8; 32])>, Vec<CompressedChunk>)> {
+    let tally = ChunkTally::default();
+    let mut files = Vec::new();
+    let mut name = String::new();
+    let mut chunks = Vec::with_capacity(total_chunks);
+    while let Some(item) = items.next().await {
+        let item = item?;
+        tally.record(&item);
+        match item {
+            BundleItem::File(meta) => name = meta.name.unwrap_or_default(),
+            BundleItem::FileHash(hash) => files.push((std::mem::take(&mut name), hash)),
+            BundleItem::Chunk(chunk) => chunks.push(chunk),
+            BundleItem::Parity(_) | BundleItem::Dictionary(_) => {}
+        }
+    }
+    tally.print_summary();
+    Ok((files, chunks))
+}
+
+fn served_url(p2p_client: &P2PClient, files: &[(String, [u8; 32])]) -> String {
+    let shr_url = create_shr_url(p2p_client.local_peer_id(), &hex::encode(content_hash(files)));
+    // A port the router forwards is only any use to receivers told where it is, and browsers
+    // have no mDNS to find the WebRTC addresses with
+    match p2p_client.port_mapping() {
+        PortMapping::Mapped(_) => add_url_addrs(&shr_url, &p2p_client.advertisable_addrs()),
+        _ => add_url_addrs(&shr_url, &p2p_client.browser_addrs()),
+    }
+}
+
+// What `shr browse` lists for a serve, with every address the URL can carry
+fn announcement(p2p_client: &P2PClient, files: &[(String, [u8; 32])], chunks: &[CompressedChunk]) -> Announcement {
+    let file_hash = hex::encode(content_hash(files));
+    let url = create_shr_url(p2p_client.local_peer_id(), &file_hash);
+    let file_name = match files {
+        [] => String::new(),
+        [(name, _)] => name.clone(),
+        [(name, _), rest @ ..] => format!("{} and {} more", name, rest.len()),
+    };
+    Announcement {
+        file_name,
+        size: chunks.iter().map(|c| c.original_size as u64).sum(),
+        file_hash,
+        peer_id: p2p_client.local_peer_id().to_string(),
+        url: add_url_addrs(&url, &p2p_client.advertisable_addrs()),
+    }
+}
+
+// What the sender can change for a receiver that can't take its chunks as they are
+fn incompatibility_hint(reason: &Incompatibility) -> String {
+    match reason {
+        Incompatibility::Version { .. } => "update shr so both sides speak the same major version".to_string(),
+        Incompatibility::Codecs { supported, .. } => match supported.iter().find(|c| c.parse::<CompressionAlgorithm>().is_ok()) {
+            Some(codec) => format!("re-send with --algorithm {}", codec),
+            None => "update shr on the receiving side".to_string(),
+        },
+        Incompatibility::Checksums { supported, .. } => match supported.iter().find(|c| c.parse::<ChecksumAlgorithm>().is_ok()) {
+            Some(checksum) => format!("re-send with compression.checksum = \"{}\"", checksum),
+            None => "update shr on the receiving side".to_string(),
+        },
+        Incompatibility::ChunkSize { max, .. } => format!("re-send with a compression.block_size that keeps chunks under {} bytes", max),
+    }
+}
+
+// Patterns match a path relative to the directory being sent, or just its last component, so
+// `target` and `*.log` work at any depth
+fn exclude_matcher(patterns: &[String]) -> Result<Option<globset::GlobSet>> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    
+    let mut builder = globset::GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = globset::Glob::new(pattern)
+            .map_err(|e| ShrLinkError::InvalidInput(format!("Invalid --exclude pattern '{}': {}", pattern, e)))?;
+        builder.add(glob);
+    }
+    builder.build().map(Some).map_err(|e| ShrLinkError::InvalidInput(format!("Invalid --exclude patterns: {}", e)))
+}
+
+// Bytes per second, with an optional K, M or G for multiples of 1024 as curl takes them
+// Only a full address will do: the peer id is what the receiver checks it reached the right peer by
+// A bare file hash is fetched from the first --peer, as the shr:// URL naming it at that address
+// would be, and from the rest alongside it
+fn bare_hash_url<'a>(hash: &str, peers: &'a [Multiaddr]) -> Result<Option<(String, &'a [Multiaddr])>> {
+    if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Ok(None);
+    }
+    let Some((first, rest)) = peers.split_first() else {
+        return Err(ShrLinkError::InvalidInput("A file hash on its own needs --peer to say where to fetch it from".to_string()));
+    };
+    let peer_id = swarm::peer_id_in(first)
+        .ok_or_else(|| ShrLinkError::InvalidInput(format!("{} has no /p2p/<peer id> at the end", first)))?;
+    let url = add_url_addrs(&create_shr_url(peer_id, &hash.to_ascii_lowercase()), std::slice::from_ref(first));
+    Ok(Some((url, rest)))
+}
+
+fn parse_peer_addr(s: &str) -> std::result::Result<Multiaddr, String> {
+    let addr: Multiaddr = s.trim().parse().map_err(|e| format!("'{}' isn't a multiaddr: {}", s, e))?;
+    match swarm::peer_id_in(&addr) {
+        Some(_) => Ok(addr),
+        None => Err(format!("'{}' has no /p2p/<peer id> at the end", s)),
+    }
+}
+
+// Whole seconds, minutes, hours, days or weeks: 90m, 12h, 7d. A bare number is seconds
+fn parse_expiry(s: &str) -> std::result::Result<Duration, String> {
+    let expiry = s.trim();
+    let (digits, unit) = match expiry.chars().last().map(|c| c.to_ascii_lowercase()) {
+        Some('s') => (&expiry[..expiry.len() - 1], 1),
+        Some('m') => (&expiry[..expiry.len() - 1], 60),
+        Some('h') => (&expiry[..expiry.len() - 1], 60 * 60),
+        Some('d') => (&expiry[..expiry.len() - 1], 24 * 60 * 60),
+        Some('w') => (&expiry[..expiry.len() - 1], 7 * 24 * 60 * 60),
+        _ => (expiry, 1),
+    };
+    match digits.parse::<u64>().ok().and_then(|n| n.checked_mul(unit)) {
+        Some(0) => Err("the expiry has to be above zero".to_string()),
+        Some(secs) => Ok(Duration::from_secs(secs)),
+        None => Err(format!("'{}' isn't a duration like 90m, 12h or 7d", s)),
+    }
+}
+
+fn parse_rate(s: &str) -> std::result::Result<u64, String> {
+    let rate = s.trim();
+    let (digits, multiplier) = match rate.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&rate[..rate.len() - 1], 1 << 10),
+        Some('M') => (&rate[..rate.len() - 1], 1 << 20),
+        Some('G') => (&rate[..rate.len() - 1], 1 << 30),
+        _ => (rate, 1),
+    };
+    match digits.parse::<u64>().ok().and_then(|n| n.checked_mul(multiplier)) {
+        Some(0) => Err("the rate has to be above zero".to_string()),
+        Some(rate) => Ok(rate),
+        None => Err(format!("'{}' isn't a rate like 500K or 2M", s)),
+    }
+}
+
+// Files are compressed one after another, each only once the previous one has been consumed, so
+// the in-flight bound is the same however many there are
+fn bundle_items(compressor: ParallelCompressor, files: Vec<(PathBuf, FileMeta)>) -> impl ItemStream {
+    futures::stream::iter(files).flat_map(move |(path, mut meta)| {
+        // Directories and symlinks are their metadata alone
+        if meta.kind != EntryKind::File {
+            return futures::stream::once(futures::future::ready(Ok(BundleItem::File(meta)))).boxed();
+        }
+        // Settled from the size the walk saw, so the metadata names the block size the chunks
+        // are actually cut to even if the file changes before it's opened
+        let compressor = compressor.sized_for(meta.size);
+        meta.block_size = compressor.fixed_block_size().map(|size| size as u64);
+        let file = futures::stream::once(futures::future::ready(Ok(BundleItem::File(meta))));
+        match compressor.compress_stream(path) {
+            Ok((chunks, file_hash)) => file
+                .chain(chunks.map_ok(BundleItem::Chunk))
+                .chain(file_hash.map_ok(BundleItem::FileHash).into_stream())
+                .boxed(),
+            Err(e) => futures::stream::once(futures::future::ready(Err(e))).boxed(),
+        }
+    })
+}
+
+// Replaces the default Ctrl-C handling for the rest of the process: the first press cancels and
+// lets everything unwind through its guards, a second one exits on the spot
+fn cancel_on_ctrl_c(cancel: CancellationToken) {
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_err() {
+            return;
+        }
+        eprintln!("\n{} Cancelling, press Ctrl-C again to quit immediately", style("⚠").yellow());
+        cancel.cancel();
+        
+        if tokio::signal::ctrl_c().await.is_ok() {
+            std::process::exit(130);
+        }
+    });
+}
+
+// Ends the bar with the last item or the first error, before the transport shows what it does next
+fn finish_with_items(mut items: impl ItemStream, bar: CompressionBar) -> impl ItemStream {
+    futures::stream::poll_fn(move |cx| {
+        let next = items.poll_next_unpin(cx);
+        if let Poll::Ready(None | Some(Err(_))) = &next {
+            bar.finish();
+        }
+        next
+    })
+}
+
+// Entries of a multi-file bundle land under `output_dir` by their relative names; anything
+// absolute, climbing out with `..`, unnamed, named twice or reached through an existing symlink
+// is refused before a byte is written
+fn archive_targets<'a>(output_dir: &Path, entries: &'a [BundleEntry]) -> Result<Vec<(PathBuf, &'a BundleEntry)>> {
+    let mut targets: Vec<PathBuf> = Vec::with_capacity(entries.len());
+    for entry in entries {
+        targets.push(archive_target(output_dir, entry.meta.as_ref(), &targets)?);
+    }
+    Ok(targets.into_iter().zip(entries).collect())
+}
+
+// Where one entry of a bundle of several goes under `output_dir`, given where the others before
+// it went
+fn archive_target(output_dir: &Path, meta: Option<&FileMeta>, taken: &[PathBuf]) -> Result<PathBuf> {
+    let name = meta.and_then(|m| m.name.as_deref()).unwrap_or_default();
+    let relative = crate::filename::archive_path(name)
+        .ok_or_else(|| ShrLinkError::InvalidInput(format!("Refusing bundle entry with unsafe path '{}'", name)))?;
+    
+    let mut parent = output_dir.to_path_buf();
+    for component in relative.parent().into_iter().flat_map(Path::components) {
+        parent.push(component);
+        if std::fs::symlink_metadata(&parent).is_ok_and(|m| m.file_type().is_symlink()) {
+            return Err(ShrLinkError::InvalidInput(format!("Refusing to write '{}' through the symlink {}", name, parent.display())));
+        }
+    }
+    
+    let target = output_dir.join(relative);
+    if taken.contains(&target) {
+        return Err(ShrLinkError::InvalidInput(format!("Bundle contains '{}' more than once", name)));
+    }
+    Ok(target)
+}
+
+// A bundle's only file goes to the output path if there is one. Otherwise it's named by the
+// bundle, which has the sender's own name for it, or failing that by the server. Either way only
+// the final path component is used, so `../evil` lands here as `evil`
+fn single_target(output_path: Option<&PathBuf>, meta: Option<&FileMeta>, file_name: Option<&str>) -> PathBuf {
+    output_path.cloned().unwrap_or_else(|| {
+        meta.and_then(|m| m.name.as_deref())
+            .and_then(crate::filename::sanitize)
+            .or_else(|| file_name.and_then(crate::filename::sanitize))
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(format!("received_file_{}", uuid::Uuid::new_v4())))
+    })
+}
+
+// Every chunk but the last is exactly the block size the sender named, and together they're the
+// size it said the file is
+fn check_chunk_sizes(meta: &FileMeta, sizes: &[usize]) -> Result<()> {
+    let total: u64 = sizes.iter().map(|&size| size as u64).sum();
+    if total != meta.size {
+        return Err(ShrLinkError::InvalidInput(format!(
+            "Bundle describes a {} byte file but its chunks add up to {} bytes",
+            meta.size, total
+        )));
+    }
+    if let (Some(block_size), Some((&last, rest))) = (meta.block_size, sizes.split_last()) {
+        if rest.iter().any(|&size| size as u64 != block_size) || last as u64 > block_size {
+            return Err(ShrLinkError::InvalidInput(format!(
+                "Bundle says its chunks are {} bytes but they aren't",
+                block_size
+            )));
+        }
+    }
+    Ok(())
+}
+
+// Links are made only after every file is in place, so none of this bundle's entries can be
+// written through one. Directory modes go on last and deepest first, so a read-only directory
+// doesn't lock out its own contents
+async fn write_entries<F: FnMut(&CompressedChunk)>(compressor: &ParallelCompressor, targets: &[(PathBuf, &BundleEntry)], mut on_chunk: F) -> Result<()> {
+    let mut links = Vec::new();
+    let mut dirs = Vec::new();
+    
+    for (output_file, entry) in targets {
+        match &entry.meta {
+            Some(meta) if meta.kind == EntryKind::Dir => {
+                std::fs::create_dir_all(output_file)?;
+                dirs.push((output_file, meta));
+            }
+            Some(FileMeta { kind: EntryKind::Symlink(target), .. }) => links.push((output_file, target)),
+            meta => {
+                compressor.write_chunks_to_file(&entry.chunks, output_file, entry.file_hash.as_ref(), &mut on_chunk).await?;
+                if let Some(meta) = meta {
+                    restore_meta(meta, output_file);
+                }
+            }
+        }
+    }
+    
+    for (path, target) in links {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // Replaces a link or file left by an earlier receive, as renaming a file into place does
+        if std::fs::symlink_metadata(path).is_ok_and(|m| !m.is_dir()) {
+            std::fs::remove_file(path)?;
+        }
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(target, path)?;
+        #[cfg(not(unix))]
+        tracing::warn!("Skipping symlink {} -> {}: not supported on this platform", path.display(), target);
+    }
+    
+    for (path, meta) in dirs.into_iter().rev() {
+        restore_meta(meta, path);
+    }
+    
+    Ok(())
+}
+
+// Items as a streamed receive takes them, with one looked at ahead. Parity is passed over:
+// chunks that check out don't need it, and one that doesn't is fetched again whole
+struct ItemQueue {
+    items: tokio::sync::mpsc::Receiver<BundleItem>,
+    next: Option<BundleItem>,
+}
+
+impl ItemQueue {
+    async fn peek(&mut self) -> Option<&BundleItem> {
+        while self.next.is_none() {
+            match self.items.recv().await? {
+                BundleItem::Parity(_) => {}
+                item => self.next = Some(item),
+            }
+        }
+        self.next.as_ref()
+    }
+    
+    // The next item if `pick` takes it, otherwise it's left for later
+    async fn take<T>(&mut self, pick: impl FnOnce(BundleItem) -> std::result::Result<T, BundleItem>) -> Option<T> {
+        self.peek().await?;
+        match pick(self.next.take()?) {
+            Ok(taken) => Some(taken),
+            Err(item) => {
+                self.next = Some(item);
+                None
+            }
+        }
+    }
+    
+    // The current file's chunks, up to whatever follows them
+    fn chunks(&mut self) -> impl Stream<Item = Result<CompressedChunk>> + Unpin + '_ {
+        Box::pin(futures::stream::unfold(self, |queue| async move {
+            let chunk = queue.take(|item| match item {
+                BundleItem::Chunk(chunk) => Ok(chunk),
+                item => Err(item),
+            }).await?;
+            Some((Ok(chunk), queue))
+        }))
+    }
+}
+
+// An entry written as far as it can be before it's known where it goes
+enum WrittenEntry {
+    File(StreamedFile, Option<[u8; 32]>),
+    Dir,
+    Symlink(String),
+}
+
+// What a streamed receive has written so far. Its first entry waits in scratch until it's known
+// whether it's the only one, which decides where it goes; the rest go straight into the
+// output directory
+struct StreamedEntries {
+    output_path: Option<PathBuf>,
+    file_name: Option<String>,
+    // Set once a second entry shows up
+    output_dir: Option<PathBuf>,
+    first: Option<(Option<FileMeta>, WrittenEntry)>,
+    taken: Vec<PathBuf>,
+    // Entries of an archive, with where each goes, only put there once the whole bundle is in
+    pending: Vec<(Option<FileMeta>, WrittenEntry, PathBuf)>,
+    dirs: Vec<(PathBuf, FileMeta)>,
+    links: Vec<(PathBuf, String)>,
+    entries: usize,
+    chunks: usize,
+}
+
+impl StreamedEntries {
+    // The first entry goes in under the output directory after all
+    fn start_archive(&mut self) -> Result<()> {
+        let output_dir = self.output_path.clone().unwrap_or_else(|| PathBuf::from("."));
+        self.output_dir = Some(output_dir.clone());
+        if let Some((meta, entry)) = self.first.take() {
+            let target = archive_target(&output_dir, meta.as_ref(), &self.taken)?;
+            self.taken.push(target.clone());
+            self.pending.push((meta, entry, target));
+        }
+        Ok(())
+    }
+    
+    fn place(&mut self, meta: Option<FileMeta>, entry: WrittenEntry, target: PathBuf) -> Result<()> {
+        match (entry, meta) {
+            (WrittenEntry::File(file, file_hash), meta) => {
+                if let Some(parent) = target.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                file.commit(&target, file_hash.as_ref())?;
+                if let Some(meta) = meta {
+                    restore_meta(&meta, &target);
+                }
+            }
+            (WrittenEntry::Dir, meta) => {
+                std::fs::create_dir_all(&target)?;
+                self.dirs.extend(meta.map(|meta| (target, meta)));
+            }
+            (WrittenEntry::Symlink(link), _) => self.links.push((target, link)),
+        }
+        Ok(())
+    }
+    
+    // Only once the whole bundle has arrived, since the last entry is only over when it ends.
+    // Links and directory modes go on last, as in `write_entries`
+    fn finish(mut self) -> Result<String> {
+        if self.entries == 0 {
+            return Err(ShrLinkError::InvalidInput("Bundle holds no chunks and doesn't say its file is empty".to_string()));
+        }
+        if self.output_dir.is_none() {
+            match self.first.take() {
+                Some((meta, entry)) if meta.as_ref().is_none_or(|m| m.kind == EntryKind::File) => {
+                    let target = single_target(self.output_path.as_ref(), meta.as_ref(), self.file_name.as_deref());
+                    let saved = format!("File saved to: {}", target.display());
+                    self.place(meta, entry, target)?;
+                    return Ok(saved);
+                }
+                first => {
+                    self.first = first;
+                    self.start_archive()?;
+                }
+            }
+        }
+        
+        for (meta, entry, target) in std::mem::take(&mut self.pending) {
+            self.place(meta, entry, target)?;
+        }
+        for (path, target) in &self.links {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            if std::fs::symlink_metadata(path).is_ok_and(|m| !m.is_dir()) {
+                std::fs::remove_file(path)?;
+            }
+            #[cfg(unix)]
+            std::os::unix::fs::symlink(target, path)?;
+            #[cfg(not(unix))]
+            tracing::warn!("Skipping symlink {} -> {}: not supported on this platform", path.display(), target);
+        }
+        for (path, meta) in self.dirs.iter().rev() {
+            restore_meta(meta, path);
+        }
+        
+        let output_dir = self.output_dir.unwrap_or_else(|| PathBuf::from("."));
+        Ok(format!("Saved {} entries to: {}", self.entries, output_dir.display()))
+    }
+}
+
+// Writes a bundle's files from its items as they arrive, each chunk as soon as the ones before
+// it are down; nothing is put in its final place until it has checked out. Chunks go into the
+// cache as they're written, unless they need the bundle's dictionary
+async fn write_streamed<F: FnMut(&CompressedChunk)>(
+    mut compressor: ParallelCompressor,
+    items: tokio::sync::mpsc::Receiver<BundleItem>,
+    output_path: Option<&PathBuf>,
+    file_name: Option<&str>,
+    mut cache: Option<&ChunkCache>,
+    mut on_chunk: F,
+) -> Result<StreamedEntries> {
+    let mut queue = ItemQueue { items, next: None };
+    let mut written = StreamedEntries {
+        output_path: output_path.cloned(),
+        file_name: file_name.map(str::to_string),
+        output_dir: None,
+        first: None,
+        taken: Vec::new(),
+        pending: Vec::new(),
+        dirs: Vec::new(),
+        links: Vec::new(),
+        entries: 0,
+        chunks: 0,
+    };
+    
+    let dictionary = queue.take(|item| match item {
+        BundleItem::Dictionary(dictionary) => Ok(dictionary),
+        item => Err(item),
+    }).await;
+    if let Some(dictionary) = dictionary {
+        compressor = compressor.with_dictionary(dictionary);
+        cache = None;
+    }
+    
+    while queue.peek().await.is_some() {
+        let meta = queue.take(|item| match item {
+            BundleItem::File(meta) => Ok(meta),
+            item => Err(item),
+        }).await;
+        // Only a bundle's one unnamed file starts without its metadata
+        if meta.is_none() && written.entries > 0 {
+            return Err(ShrLinkError::InvalidInput("Bundle holds items that belong to no file".to_string()));
+        }
+        written.entries += 1;
+        
+        let target = match &written.output_dir {
+            Some(output_dir) => {
+                let target = archive_target(output_dir, meta.as_ref(), &written.taken)?;
+                written.taken.push(target.clone());
+                Some(target)
+            }
+            None => None,
+        };
+        
+        let entry = match meta.as_ref().map(|m| &m.kind) {
+            Some(EntryKind::Dir) => WrittenEntry::Dir,
+            Some(EntryKind::Symlink(link)) => WrittenEntry::Symlink(link.clone()),
+            _ => {
+                // Every file is written beside where the first would go on its own, which is
+                // where the output directory is too, so nothing is in it before the end
+                let scratch = match &written.output_dir {
+                    Some(output_dir) => output_dir.clone(),
+                    None => single_target(output_path, meta.as_ref(), file_name),
+                };
+                let mut sizes = Vec::new();
+                let file = compressor.write_stream_to_file(queue.chunks(), &scratch, |chunk| {
+                    sizes.push(chunk.original_size);
+                    if let Some(cache) = cache {
+                        if let Err(e) = cache.put(chunk) {
+                            tracing::warn!("Failed to cache chunk {}: {}", chunk.index, e);
+                        }
+                    }
+                    on_chunk(chunk);
+                }).await?;
+                match &meta {
+                    Some(meta) => check_chunk_sizes(meta, &sizes)?,
+                    None if sizes.is_empty() => {
+                        return Err(ShrLinkError::InvalidInput("Bundle holds no chunks and doesn't say its file is empty".to_string()));
+                    }
+                    None => {}
+                }
+                written.chunks += sizes.len();
+                
+                let file_hash = queue.take(|item| match item {
+                    BundleItem::FileHash(hash) => Ok(hash),
+                    item => Err(item),
+                }).await;
+                WrittenEntry::File(file, file_hash)
+            }
+        };
+        
+        match target {
+            Some(target) => written.pending.push((meta, entry, target)),
+            None => {
+                written.first = Some((meta, entry));
+                if queue.peek().await.is_some() {
+                    written.start_archive()?;
+                }
+            }
+        }
+    }
+    
+    if let Some(cache) = cache.filter(|_| written.chunks > 0) {
+        if let Err(e) = cache.prune() {
+            tracing::warn!("Failed to prune the chunk cache: {}", e);
+        }
+    }
+    Ok(written)
+}
+
+// A chunk that didn't come out as the sender hashed it, as opposed to a whole file that didn't
+fn is_damaged_chunk(error: &ShrLinkError) -> bool {
+    match error {
+        ShrLinkError::HashMismatch { context, .. } => context.starts_with("chunk "),
+        ShrLinkError::Compression(_) => true,
+        _ => false,
+    }
+}
+
+fn with_download_progress(response: BundleResponse, progress: &ProgressTask) -> BundleResponse {
+    let progress = progress.clone();
+    response.with_progress(Arc::new(move |bytes| progress.inc(bytes)))
+}
+
+fn print_reused(chunks_reused: usize, bytes_skipped: u64) {
+    if chunks_reused > 0 {
+        println!(
+            "{} Reused {} cached chunks ({:.2} MB not downloaded)",
+            style("♻").green(),
+            chunks_reused,
+            bytes_skipped as f64 / (1024.0 * 1024.0)
+        );
+    }
+}
+
+fn restore_meta(meta: &FileMeta, path: &Path) {
+    if let Err(e) = meta.apply(path) {
+        tracing::warn!("Could not restore modification time and permissions on {}: {}", path.display(), e);
+    }
+}
+
+// One file is named by its own hash; several by a hash over each one's name and hash in order
+// Where a receive keeps what it has so far: beside the output, or named after the file in the
+// current directory when there's no output path to go by
+// What receipts call this machine, so a sender can tell its receivers apart
+fn machine_name() -> Option<String> {
+    ["HOSTNAME", "COMPUTERNAME"]
+        .iter()
+        .find_map(|var| std::env::var(var).ok())
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+}
+
+// `--timeout` is in seconds, the config's in milliseconds
+fn discovery_timeout(timeout: Option<u64>, config: &Config) -> Duration {
+    timeout.map(Duration::from_secs).unwrap_or_else(|| Duration::from_millis(config.p2p.timeout_ms))
+}
+
+// A peer only serves a single file, and the manifest it answered with is for the URL's hash,
+// so the chunks have to come together into a file with that hash
+fn peer_bundle(chunks: Vec<CompressedChunk>, file_hash: [u8; 32]) -> Bundle {
+    let mut bundle = Bundle::single(None, chunks);
+    bundle.entries[0].file_hash = Some(file_hash);
+    bundle
+}
+
+fn partial_path(output: Option<&PathBuf>, file_hash: &[u8; 32]) -> PathBuf {
+    match output {
+        Some(output) => PartialDownload::path_for(output),
+        None => PathBuf::from(format!("{}{}", hex::encode(&file_hash[..8]), PARTIAL_SUFFIX)),
+    }
+}
+
+// With `resume`, whatever an earlier receive of the same file left at `path`; otherwise, or if
+// it left nothing, a fresh start
+fn open_partial(path: PathBuf, file_hash: [u8; 32], chunk_count: usize, resume: bool) -> Result<PartialDownload> {
+    if resume {
+        if let Some(partial) = PartialDownload::open(&path, file_hash, chunk_count)? {
+            return Ok(partial);
+        }
+        println!("{} Nothing to resume at {}, starting from the beginning", style("ℹ").blue(), path.display());
+    }
+    PartialDownload::create(path, file_hash, chunk_count)
+}
+
+fn remove_partial(partial: PartialDownload) {
+    let path = partial.path().to_path_buf();
+    if let Err(e) = partial.remove() {
+        tracing::warn!("Failed to remove {}: {}", path.display(), e);
+    }
+}
+
+// A receive that fails keeps what it got for --resume, unless it got nothing at all
+fn discard_if_empty(partial: PartialDownload) {
+    if partial.is_empty() {
+        let _ = partial.remove();
+    }
+}
+
+fn content_hash(files: &[(String, [u8; 32])]) -> [u8; 32] {
+    if let [(_, hash)] = files {
+        return *hash;
+    }
+    
+    let mut hasher = blake3::Hasher::new();
+    for (name, hash) in files {
+        hasher.update(&(name.len() as u64).to_le_bytes());
+        hasher.update(name.as_bytes());
+        hasher.update(hash);
+    }
+    hasher.finalize().into()
+}
+
+fn share_url(url: &str, encryption: Option<&Encryption>) -> String {
+    match encryption {
+        Some(Encryption::UrlKey(key)) => crypto::url_with_key(url, key),
+        _ => url.to_string(),
+    }
+}
+
+// What any of the fallback backends hands out, all of which `HttpFallback` downloads
+fn is_fallback_url(url: &str) -> bool {
+    is_http_url(url) || is_file_url(url) || is_sftp_url(url)
+}
+
+// How a fallback URL is fetched, which for the file backend is off the receiver's own mount
+fn http_transport(url: &str) -> Transport {
+    if is_file_url(url) {
+        Transport::File
+    } else if is_sftp_url(url) {
+        Transport::Sftp
+    } else {
+        Transport::Http
+    }
+}
+
+// Naming the server that took it, out of those in fallback.endpoints
+fn print_upload_complete(endpoint: Option<&str>) {
+    match endpoint {
+        Some(endpoint) => println!("{} Upload complete via {}", style("✓").green(), endpoint),
+        None => println!("{} Upload complete!", style("✓").green()),
+    }
+}
+
+// With when it stops working, where that's known: the expiry asked for, or how long an S3
+// presigned URL lasts, and whether the first download uses it up
+fn print_share_url(url: &str, fallback: &FallbackConfig) {
+    println!("{} Share this URL:", style("📋").cyan());
+    println!("  {}", style(url).bold());
+    
+    let expiry = match fallback.backend {
+        FallbackBackend::Http | FallbackBackend::File | FallbackBackend::Sftp => fallback.upload_expiry_secs,
+        FallbackBackend::S3 => Some(fallback.upload_expiry_secs.unwrap_or(fallback.expiry_secs)),
+    };
+    if let Some(expiry) = expiry {
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs();
+        println!("  {} link expires {}", style("⏳").dim(), format_utc(now.saturating_add(expiry)));
+    }
+    if fallback.download_once {
+        println!("  {} link works for one download only", style("🔥").dim());
+    }
+}
+
+// "2024-05-01 13:00 UTC", from days since the epoch to a civil date as in Howard Hinnant's
+// `civil_from_days`
+fn format_utc(unix_secs: u64) -> String {
+    let (days, secs) = (unix_secs / 86_400, unix_secs % 86_400);
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02} {:02}:{:02} UTC", year, month, day, secs / 3600, (secs / 60) % 60)
+}
+
+// A cache that can't be opened only costs the chance to skip chunks
+fn open_cache(dir: Option<&Path>) -> Option<ChunkCache> {
+    let dir = dir.map(Path::to_path_buf).unwrap_or_else(ChunkCache::default_dir);
+    match ChunkCache::open(&dir) {
+        Ok(cache) => Some(cache),
+        Err(e) => {
+            tracing::warn!("Not using the chunk cache in {}: {}", dir.display(), e);
+            None
+        }
+    }
+}
+
+// Chunks compressed against a dictionary are only any use alongside it, so a bundle with one
+// leaves the cache as it was
+fn store_chunks(cache: &ChunkCache, bundle: &Bundle) {
+    if bundle.dictionary.is_some() {
+        return;
+    }
+    for chunk in bundle.entries.iter().flat_map(|e| &e.chunks) {
+        if let Err(e) = cache.put(chunk) {
+            tracing::warn!("Failed to cache chunk {}: {}", chunk.index, e);
+            return;
+        }
+    }
+    if let Err(e) = cache.prune() {
+        tracing::warn!("Failed to prune the chunk cache: {}", e);
+    }
+}
+
+// Whatever `recv` was given that might open an encrypted bundle
+struct BundleKeys<'a> {
+    identity: Option<&'a Path>,
+    url_key: Option<&'a SecretKey>,
+}
+
+fn decode_bundle(mut bundle: Vec<u8>, keys: &BundleKeys<'_>) -> Result<Bundle> {
+    // Sealed bundles are decrypted before anything in them is parsed. Every segment's tag is
+    // checked on the way, so the chunk hashes that follow are verified against plaintext
+    // exactly as for a bundle that was never encrypted
+    if crypto::is_sealed(&bundle) {
+        let key = keys.url_key.ok_or_else(|| {
+            ShrLinkError::Decryption("the bundle is encrypted but the URL has no #k= key".to_string())
+        })?;
+        bundle = crypto::unseal(&bundle, key)?;
+        println!("{} Decrypted with the key from the URL", style("🔓").green());
+        return crate::bundle::parse_bundle(&bundle);
+    }
+    // Otherwise a server could swap in a bundle of its own choosing
+    if keys.url_key.is_some() {
+        return Err(ShrLinkError::Decryption("the URL has a key but the bundle served is not encrypted".to_string()));
+    }
+    
+    if crypto::is_locked(&bundle) {
+        let password = crypto::read_password(false)?;
+        bundle = crypto::unlock(&bundle, &password)?;
+        println!("{} Decrypted with the password", style("🔓").green());
+        return crate::bundle::parse_bundle(&bundle);
+    }
+    
+    // Parsed as it's decrypted, so the plaintext is never held beside the bundle it makes
+    if crypto::is_age_encrypted(&bundle) {
+        let decrypted = crypto::age_reader_with(&bundle[..], keys.identity, || crypto::prompt_passphrase("Passphrase: "))?;
+        let bundle = ShrBundleReader::new(decrypted).read_bundle()?;
+        println!("{} Decrypted age payload", style("🔓").green());
+        return Ok(bundle);
+    }
+    
+    crate::bundle::parse_bundle(&bundle)
+}
+
+// Hands on the items of the age-encrypted bundle in `body` as they're decrypted and decoded.
+// Without an identity the passphrase is asked for, once the header shows it needs one
+fn read_age_items<R: std::io::Read>(body: R, identity: Option<&Path>, items: tokio::sync::mpsc::Sender<BundleItem>) -> Result<()> {
+    let decrypted = crypto::age_reader_with(std::io::BufReader::new(body), identity, || crypto::prompt_passphrase("Passphrase: "))?;
+    let mut reader = ShrBundleReader::new(decrypted);
+    while let Some(item) = reader.next_item()? {
+        // Only closed once writing has given up
+        items.blocking_send(item).map_err(|_| ShrLinkError::Cancelled)?;
+    }
+    Ok(())
+}
+
+// Compression only starts once a transport pulls the first item, which may be after peer
+// discovery, so the bar appears with the first file. Once finished it stays that way, however
+// late a worker reports
+#[derive(Clone)]
+struct CompressionBar {
+    progress: Progress,
+    total_chunks: usize,
+    state: Arc<Mutex<BarState>>,
+}
+
+enum BarState {
+    Pending,
+    // `sent` is the bytes an upload has put on the wire and when it started, once it has
+    Running { task: ProgressTask, shown: usize, ratio: Option<String>, sent: Option<(u64, Instant)> },
+    Done,
+}
+
+impl CompressionBar {
+    fn new(progress: Progress, total_chunks: usize) -> Self {
+        Self { progress, total_chunks, state: Arc::new(Mutex::new(BarState::Pending)) }
+    }
+    
+    fn update(&self, update: CompressionProgress) {
+        let mut state = self.state.lock().unwrap();
+        if let BarState::Pending = *state {
+            let task = self.progress.start("compress", Some(self.total_chunks as u64), Unit::Chunks);
+            *state = BarState::Running { task, shown: 0, ratio: None, sent: None };
+        }
+        let BarState::Running { task, shown, ratio, sent } = &mut *state else {
+            return;
+        };
+        
+        if update.bytes_in > 0 {
+            *ratio = Some(format!("{:.1}% of original size", update.bytes_out as f64 / update.bytes_in as f64 * 100.0));
+            task.detail(bar_detail(ratio.as_deref(), *sent));
+        }
+        task.inc(update.chunks_done.saturating_sub(*shown) as u64);
+        *shown = (*shown).max(update.chunks_done);
+    }
+    
+    // The bundle's size isn't known until the last chunk is compressed, so the upload shows as
+    // bytes sent and throughput beside the chunk count rather than a bar of its own
+    fn sent(&self, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        let BarState::Running { task, ratio, sent, .. } = &mut *state else {
+            return;
+        };
+        let (total, _) = sent.get_or_insert_with(|| (0, Instant::now()));
+        *total += bytes;
+        task.detail(bar_detail(ratio.as_deref(), *sent));
+    }
+    
+    fn finish(&self) {
+        if let BarState::Running { task, .. } = std::mem::replace(&mut *self.state.lock().unwrap(), BarState::Done) {
+            task.finish();
+        }
+    }
+}
+
+// "41.2% of original size, 12.0 MB sent at 3.4 MB/s"
+fn bar_detail(ratio: Option<&str>, sent: Option<(u64, Instant)>) -> String {
+    let sent = sent.map(|(bytes, started)| {
+        let mb = bytes as f64 / (1024.0 * 1024.0);
+        let secs = started.elapsed().as_secs_f64();
+        let rate = if secs > 0.0 { mb / secs } else { 0.0 };
+        format!("{:.1} MB sent at {:.1} MB/s", mb, rate)
+    });
+    [ratio.map(str::to_string), sent].into_iter().flatten().collect::<Vec<_>>().join(", ")
+}
+
+// Items handed to `send`'s consumers are counted as they pass, since none of them keeps the
+// whole file; sizes are what actually goes on the wire, stored chunks included
+#[derive(Default)]
+struct ChunkTally {
+    chunks: AtomicU64,
+    stored: AtomicU64,
+    bytes: AtomicU64,
+    // Name, original size and compressed bytes of each file, in bundle order
+    files: Mutex<Vec<(String, u64, u64)>>,
+}
+
+impl ChunkTally {
+    fn record(&self, item: &BundleItem) {
+        let chunk = match item {
+            BundleItem::File(meta) => {
+                if meta.kind == EntryKind::File {
+                    let name = meta.name.clone().unwrap_or_default();
+                    self.files.lock().unwrap().push((name, meta.size, 0));
+                }
+                return;
+            }
+            BundleItem::Chunk(chunk) => chunk,
+            BundleItem::FileHash(_) | BundleItem::Parity(_) | BundleItem::Dictionary(_) => return,
+        };
+        
+        self.chunks.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(chunk.data.len() as u64, Ordering::Relaxed);
+        if chunk.algorithm == CompressionAlgorithm::Stored {
+            self.stored.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some((_, _, compressed)) = self.files.lock().unwrap().last_mut() {
+            *compressed += chunk.data.len() as u64;
+        }
+    }
+    
+    fn print_summary(&self) {
+        let files = self.files.lock().unwrap();
+        let original_size: u64 = files.iter().map(|(_, size, _)| size).sum();
+        let chunks = self.chunks.load(Ordering::Relaxed);
+        let stored = self.stored.load(Ordering::Relaxed);
+        let compression_ratio = (self.bytes.load(Ordering::Relaxed) as f64 / original_size as f64) * 100.0;
+        
+        if stored > 0 {
+            println!(
+                "{} Compressed to {} chunks ({:.1}% of original size, {} stored uncompressed)",
+                style("✓").green(),
+                chunks,
+                compression_ratio,
+                stored
+            );
+        } else {
+            println!(
+                "{} Compressed to {} chunks ({:.1}% of original size)",
+                style("✓").green(),
+                chunks,
+                compression_ratio
+            );
+        }
+        
+        if files.len() > 1 {
+            for (name, size, compressed) in files.iter() {
+                println!("    {}: {} → {} bytes", name, size, compressed);
+            }
+        }
+    }
+}
+
+// An explicit RUST_LOG replaces the -v levels; --log-filter is layered on top of either,
+// so later directives win for the targets they name
+pub fn log_directives(verbosity: u8, rust_log: Option<&str>, log_filter: Option<&str>) -> String {
+    let base = match rust_log.map(str::trim).filter(|r| !r.is_empty()) {
+        Some(rust_log) => rust_log.to_string(),
+        None => match verbosity {
+            0 => "error".to_string(),
+            1 => "error,shrlink=info".to_string(),
+            2 => "info,shrlink=debug".to_string(),
+            _ => "trace".to_string(),
+        },
+    };
+    
+    match log_filter.map(str::trim).filter(|f| !f.is_empty()) {
+        Some(extra) => format!("{},{}", base, extra),
+        None => base,
+    }
+}
+
+// Correlates the log lines of one transfer
+fn new_transfer_id() -> String {
+    uuid::Uuid::new_v4().simple().to_string()
+}
+
+fn format_duration(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m {}s", secs / 60, secs % 60),
+        _ => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+// A hint at a poor link, when receivers had to ask for chunks again
+fn retried(stats: &ServeStats) -> String {
+    match stats.chunks_retried {
+        0 => String::new(),
+        n => format!(" ({} asked for again)", n),
+    }
+}
+
+// One line per peer under the serve tally, against its cap if there is one
+fn print_peer_rates(throttle: &ServeThrottle) {
+    let cap = throttle.per_peer_bps().map_or_else(String::new, |bps| format!(" of {}/s", indicatif::HumanBytes(bps)));
+    for (peer_id, stats) in throttle.peer_stats() {
+        println!(
+            "   {} {} sent, {}/s now{}",
+            short_peer_id(&peer_id.to_string()),
+            indicatif::HumanBytes(stats.bytes_served),
+            indicatif::HumanBytes(stats.current_bps),
+            cap
+        );
+    }
+}
+
+fn print_p2p_stats(stats: &P2PStats) {
+    println!("P2P Statistics (this machine):");
+    if stats.is_empty() {
+        println!("  No P2P transfers on record");
+        return;
+    }
+    
+    println!("  Transfers: {} ({} failed)", stats.transfers(), stats.transfers_failed);
+    println!("  Sent: {} in {} chunks", indicatif::HumanBytes(stats.bytes_sent), stats.chunks_sent);
+    println!("  Received: {} in {} chunks", indicatif::HumanBytes(stats.bytes_received), stats.chunks_received);
+    println!("  Retries: {}", stats.retries);
+    if stats.violations > 0 {
+        println!("  Peers cut off for misbehaving: {}", stats.violations);
+    }
+    if stats.receipts > 0 {
+        println!("  Downloads confirmed by a receipt: {}", stats.receipts);
+    }
+    println!("  Time transferring: {}", indicatif::HumanDuration(Duration::from_millis(stats.transfer_ms)));
+    println!("  Peers: {}", stats.peers.len());
+    if stats.peers.is_empty() {
+        return;
+    }
+    
+    let now = reputation::unix_now();
+    let mut peers: Vec<_> = stats.peers.iter().collect();
+    peers.sort_by_key(|(_, peer)| std::cmp::Reverse(peer.last_seen));
+    println!();
+    println!("  {:<54} {:>10} {:>10} {:>8} {:>10}", "PEER", "SENT", "RECEIVED", "RETRIES", "LAST SEEN");
+    for (peer_id, peer) in peers {
+        println!(
+            "  {:<54} {:>10} {:>10} {:>8} {:>10}",
+            peer_id,
+            indicatif::HumanBytes(peer.bytes_sent).to_string(),
+            indicatif::HumanBytes(peer.bytes_received).to_string(),
+            peer.retries,
+            format_age(now.saturating_sub(peer.last_seen))
+        );
+    }
+}
+
+// Oldest first, the order cleanup takes them in
+fn print_fallback_files(files: Option<&[FileStats]>) {
+    let Some(files) = files else {
+        println!("  This fallback doesn't list its files");
+        return;
+    };
+    if files.is_empty() {
+        return;
+    }
+    
+    let now = reputation::unix_now();
+    let mut files: Vec<_> = files.iter().collect();
+    files.sort_by_key(|file| file.uploaded_at);
+    println!();
+    println!("  {:>10} {:>10} {:<20} {:>9}  FILE", "SIZE", "UPLOADED", "EXPIRES", "DOWNLOADS");
+    for file in files {
+        println!(
+            "  {:>10} {:>10} {:<20} {:>9}  {}",
+            indicatif::HumanBytes(file.size).to_string(),
+            format_age(now.saturating_sub(file.uploaded_at)),
+            file.expires_at.map_or_else(|| "-".to_string(), format_utc),
+            file.downloads,
+            file.name
+        );
+    }
+}
+
+fn format_age(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s ago", secs),
+        60..=3599 => format!("{}m ago", secs / 60),
+        3600..=86399 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_discovery_timeout_keeps_milliseconds() {
+        let mut config = Config::default();
+        config.p2p.timeout_ms = 500;
+        assert_eq!(discovery_timeout(None, &config), Duration::from_millis(500));
+        assert_eq!(discovery_timeout(Some(3), &config), Duration::from_secs(3));
+    }
+    
+    #[test]
+    fn test_parse_expiry() {
+        assert_eq!(parse_expiry("90m"), Ok(Duration::from_secs(90 * 60)));
+        assert_eq!(parse_expiry("12h"), Ok(Duration::from_secs(12 * 3600)));
+        assert_eq!(parse_expiry("7D"), Ok(Duration::from_secs(7 * 86_400)));
+        assert_eq!(parse_expiry("2w"), Ok(Duration::from_secs(14 * 86_400)));
+        assert_eq!(parse_expiry("45"), Ok(Duration::from_secs(45)));
+        assert!(parse_expiry("0h").is_err());
+        assert!(parse_expiry("soon").is_err());
+        assert!(parse_expiry("1.5h").is_err());
+    }
+    
+    #[test]
+    fn test_format_utc() {
+        assert_eq!(format_utc(0), "1970-01-01 00:00 UTC");
+        assert_eq!(format_utc(1_714_568_400), "2024-05-01 13:00 UTC");
+        assert_eq!(format_utc(951_782_400 + 59), "2000-02-29 00:00 UTC");
+        assert_eq!(format_utc(4_102_444_799), "2099-12-31 23:59 UTC");
+    }
+    
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("1500"), Ok(1500));
+        assert_eq!(parse_rate("500K"), Ok(500 * 1024));
+        assert_eq!(parse_rate("2m"), Ok(2 * 1024 * 1024));
+        assert_eq!(parse_rate("1G"), Ok(1 << 30));
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("fast").is_err());
+        assert!(parse_rate("99999999999G").is_err());
+    }
+    
+    #[test]
+    fn test_parse_peer_addr() {
+        let peer_id = libp2p::PeerId::random();
+        let addr = format!("/ip4/10.0.0.2/tcp/4001/p2p/{}", peer_id);
+        assert_eq!(parse_peer_addr(&addr).map(|a| swarm::peer_id_in(&a)), Ok(Some(peer_id)));
+        assert!(parse_peer_addr("/ip4/10.0.0.2/tcp/4001").unwrap_err().contains("/p2p/"));
+        assert!(parse_peer_addr("10.0.0.2:4001").is_err());
+    }
+    
+    #[test]
+    fn test_bare_hash_url() {
+        let (first, second) = (libp2p::PeerId::random(), libp2p::PeerId::random());
+        let peers: Vec<Multiaddr> = [first, second].iter().map(|p| format!("/ip4/10.0.0.2/tcp/4001/p2p/{}", p).parse().unwrap()).collect();
+        let hash = "AB".repeat(32);
+        
+        let (url, rest) = bare_hash_url(&hash, &peers).unwrap().unwrap();
+        assert_eq!(parse_shr_url(&url).unwrap(), (first, "ab".repeat(32)));
+        assert_eq!(shr_url_addrs(&url).unwrap(), peers[..1]);
+        assert_eq!(rest, &peers[1..]);
+        
+        assert!(bare_hash_url(&hash, &[]).unwrap_err().to_string().contains("--peer"));
+        let url = create_shr_url(first, &hash);
+        assert!(bare_hash_url(&url, &peers).unwrap().is_none());
+        assert!(bare_hash_url(&"g".repeat(64), &peers).unwrap().is_none());
+    }
+    
+    #[test]
+    fn test_incompatibility_hints() {
+        let codecs = Incompatibility::Codecs { unsupported: vec!["zstd".to_string()], supported: vec!["zero".to_string(), "lz4".to_string()] };
+        assert_eq!(incompatibility_hint(&codecs), "re-send with --algorithm lz4");
+        let checksums = Incompatibility::Checksums { unsupported: vec!["xxh3".to_string()], supported: vec!["blake3".to_string()] };
+        assert_eq!(incompatibility_hint(&checksums), "re-send with compression.checksum = \"blake3\"");
+        let size = Incompatibility::ChunkSize { largest: 100, max: 10 };
+        assert!(incompatibility_hint(&size).contains("under 10 bytes"));
+    }
+    
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(42), "42s");
+        assert_eq!(format_duration(150), "2m 30s");
+        assert_eq!(format_duration(3 * 3600 + 20 * 60 + 5), "3h 20m");
+    }
+    
+    #[test]
+    fn test_log_directives_per_verbosity() {
+        assert_eq!(log_directives(0, None, None), "error");
+        assert_eq!(log_directives(1, None, None), "error,shrlink=info");
+        assert_eq!(log_directives(2, None, None), "info,shrlink=debug");
+        assert_eq!(log_directives(3, None, None), "trace");
+        assert_eq!(log_directives(7, None, None), "trace");
+    }
+    
+    #[test]
+    fn test_rust_log_takes_precedence_over_verbosity() {
+        assert_eq!(log_directives(2, Some("libp2p_swarm=debug"), None), "libp2p_swarm=debug");
+        // An empty RUST_LOG is treated as unset
+        assert_eq!(log_directives(1, Some("  "), None), "error,shrlink=info");
+    }
+    
+    #[test]
+    fn test_log_filter_is_layered_on_top() {
+        assert_eq!(log_directives(1, None, Some("shrlink::p2p=trace")), "error,shrlink=info,shrlink::p2p=trace");
+        assert_eq!(log_directives(0, Some("warn"), Some("shrlink::p2p=trace")), "warn,shrlink::p2p=trace");
+        assert!(crate::logging::filter(&log_directives(2, None, Some("shrlink::p2p=trace"))).is_ok());
+    }
+    
+    async fn run_config(args: &[&str], location: &ConfigLocation) {
+        let cli = Cli::try_parse_from(args).unwrap();
+        let Commands::Config { action } = &cli.command else {
+            panic!("not a config command");
+        };
+        let config = Config::load(location).unwrap();
+        cli.handle_config(action.as_ref(), &config, location).await.unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_config_writes_go_to_resolved_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let flag_path = dir.path().join("flag.toml");
+        let env_path = dir.path().join("env.toml");
+        Config::default().save(&flag_path).unwrap();
+        Config::default().save(&env_path).unwrap();
+        let flag_arg = flag_path.to_str().unwrap();
+        
+        // The flag wins over SHRLINK_CONFIG, so only the flag's file changes
+        let location = ConfigLocation::resolve(Some(&flag_path), Some(env_path.clone().into_os_string()));
+        run_config(&["shr", "--config", flag_arg, "config", "set", "compression.block_size", "1048576"], &location).await;
+        assert_eq!(Config::load(&location).unwrap().compression.block_size, crate::compression::BlockSize::Fixed(1048576));
+        assert_eq!(fs_config(&env_path).compression.block_size, Config::default().compression.block_size);
+        
+        run_config(&["shr", "--config", flag_arg, "config", "reset"], &location).await;
+        assert_eq!(Config::load(&location).unwrap().compression.block_size, Config::default().compression.block_size);
+        
+        let location = ConfigLocation::resolve(None, Some(env_path.clone().into_os_string()));
+        run_config(&["shr", "config", "set", "p2p.timeout_ms", "900"], &location).await;
+        assert_eq!(fs_config(&env_path).p2p.timeout_ms, 900);
+        assert_eq!(fs_config(&flag_path).p2p.timeout_ms, Config::default().p2p.timeout_ms);
+    }
+    
+    fn fs_config(path: &Path) -> Config {
+        toml::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+    }
+    
+    #[tokio::test]
+    async fn test_compression_bar_ends_on_error_and_stays_ended() {
+        let bar = CompressionBar::new(Progress::new(ProgressMode::None), 4);
+        bar.update(CompressionProgress { chunks_done: 1, chunks_total: 4, bytes_in: 100, bytes_out: 40 });
+        assert!(matches!(*bar.state.lock().unwrap(), BarState::Running { shown: 1, .. }));
+        
+        let items = futures::stream::iter(vec![
+            Ok(BundleItem::FileHash([0; 32])),
+            Err(ShrLinkError::Compression("chunk 2 failed".to_string())),
+        ]);
+        let mut items = finish_with_items(items, bar.clone());
+        assert!(items.next().await.unwrap().is_ok());
+        assert!(matches!(*bar.state.lock().unwrap(), BarState::Running { .. }));
+        assert!(items.next().await.unwrap().is_err());
+        assert!(matches!(*bar.state.lock().unwrap(), BarState::Done));
+        
+        // A worker that was still busy doesn't bring the bar back
+        bar.update(CompressionProgress { chunks_done: 2, chunks_total: 4, bytes_in: 200, bytes_out: 80 });
+        assert!(matches!(*bar.state.lock().unwrap(), BarState::Done));
+    }
+    
+    #[test]
+    fn test_compression_bar_counts_bytes_sent() {
+        let bar = CompressionBar::new(Progress::new(ProgressMode::None), 4);
+        // Nothing to show it on before the first chunk
+        bar.sent(10);
+        assert!(matches!(*bar.state.lock().unwrap(), BarState::Pending));
+        
+        bar.update(CompressionProgress { chunks_done: 1, chunks_total: 4, bytes_in: 100, bytes_out: 40 });
+        bar.sent(3 * 1024 * 1024);
+        bar.sent(1024 * 1024);
+        assert!(matches!(*bar.state.lock().unwrap(), BarState::Running { sent: Some((bytes, _)), .. } if bytes == 4 * 1024 * 1024));
+        
+        let started = Instant::now() - Duration::from_secs(2);
+        assert_eq!(bar_detail(Some("40.0% of original size"), Some((4 * 1024 * 1024, started))), "40.0% of original size, 4.0 MB sent at 2.0 MB/s");
+        assert_eq!(bar_detail(Some("40.0% of original size"), None), "40.0% of original size");
+    }
 }
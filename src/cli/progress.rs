@@ -0,0 +1,465 @@
+use clap::ValueEnum;
+use indicatif::{ProgressBar, ProgressStyle};
+use std::io::{IsTerminal, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub const PLAIN_INTERVAL: Duration = Duration::from_secs(5);
+pub const PLAIN_PERCENT_STEP: u64 = 10;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ProgressMode {
+    // Bars on a terminal, plain lines when stderr is redirected
+    #[default]
+    Auto,
+    Bar,
+    Plain,
+    None,
+}
+
+impl ProgressMode {
+    pub fn resolve(self, stderr_is_tty: bool) -> Self {
+        match self {
+            ProgressMode::Auto if stderr_is_tty => ProgressMode::Bar,
+            ProgressMode::Auto => ProgressMode::Plain,
+            other => other,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    Chunks,
+    Bytes,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProgressEvent {
+    Started { task: String, total: Option<u64>, unit: Unit },
+    Advanced(u64),
+    Status(String),
+    // Changes with every update, like a running ratio, so plain output only carries it on
+    // lines that are due anyway
+    Detail(String),
+    Finished,
+}
+
+pub trait ProgressRenderer: Send + Sync {
+    fn render(&self, event: &ProgressEvent);
+}
+
+// Picks a renderer per task; transfer code only ever sees the task handle
+#[derive(Debug, Clone, Copy)]
+pub struct Progress {
+    mode: ProgressMode,
+}
+
+impl Progress {
+    pub fn new(mode: ProgressMode) -> Self {
+        Self { mode: mode.resolve(std::io::stderr().is_terminal()) }
+    }
+
+    pub fn start(&self, task: &str, total: Option<u64>, unit: Unit) -> ProgressTask {
+        let renderer: Arc<dyn ProgressRenderer> = match self.mode {
+            ProgressMode::Bar | ProgressMode::Auto => Arc::new(BarRenderer::default()),
+            ProgressMode::Plain => Arc::new(PlainRenderer::new(Box::new(std::io::stderr()))),
+            ProgressMode::None => Arc::new(NoopRenderer),
+        };
+        ProgressTask::with_renderer(renderer, task, total, unit)
+    }
+}
+
+#[derive(Clone)]
+pub struct ProgressTask {
+    renderer: Arc<dyn ProgressRenderer>,
+}
+
+impl ProgressTask {
+    pub fn with_renderer(renderer: Arc<dyn ProgressRenderer>, task: &str, total: Option<u64>, unit: Unit) -> Self {
+        renderer.render(&ProgressEvent::Started { task: task.to_string(), total, unit });
+        Self { renderer }
+    }
+
+    pub fn inc(&self, delta: u64) {
+        self.renderer.render(&ProgressEvent::Advanced(delta));
+    }
+
+    pub fn status(&self, message: impl Into<String>) {
+        self.renderer.render(&ProgressEvent::Status(message.into()));
+    }
+
+    pub fn detail(&self, detail: impl Into<String>) {
+        self.renderer.render(&ProgressEvent::Detail(detail.into()));
+    }
+
+    pub fn finish(&self) {
+        self.renderer.render(&ProgressEvent::Finished);
+    }
+}
+
+pub struct NoopRenderer;
+
+impl ProgressRenderer for NoopRenderer {
+    fn render(&self, _: &ProgressEvent) {}
+}
+
+#[derive(Default)]
+pub struct BarRenderer {
+    bar: Mutex<Option<ProgressBar>>,
+}
+
+impl ProgressRenderer for BarRenderer {
+    fn render(&self, event: &ProgressEvent) {
+        let mut bar = self.bar.lock().unwrap();
+
+        match event {
+            ProgressEvent::Started { total: Some(total), unit, .. } => {
+                let counter = match unit {
+                    Unit::Chunks => "{pos}/{len} chunks",
+                    Unit::Bytes => "{bytes}/{total_bytes} {percent}% ({bytes_per_sec}, {eta} left)",
+                };
+                let template = format!("{{spinner:.green}} [{{elapsed_precise}}] [{{bar:40.cyan/blue}}] {} {{msg}}", counter);
+                let new_bar = ProgressBar::new(*total);
+                new_bar.set_style(ProgressStyle::default_bar().template(&template).unwrap().progress_chars("#>-"));
+                *bar = Some(new_bar);
+            }
+            ProgressEvent::Started { total: None, .. } => {
+                let spinner = ProgressBar::new_spinner();
+                spinner.set_style(ProgressStyle::default_spinner().template("{spinner:.green} {msg}").unwrap());
+                spinner.enable_steady_tick(Duration::from_millis(100));
+                *bar = Some(spinner);
+            }
+            ProgressEvent::Advanced(delta) => {
+                if let Some(bar) = bar.as_ref() {
+                    bar.inc(*delta);
+                }
+            }
+            ProgressEvent::Status(message) | ProgressEvent::Detail(message) => {
+                if let Some(bar) = bar.as_ref() {
+                    bar.set_message(message.clone());
+                }
+            }
+            ProgressEvent::Finished => {
+                if let Some(bar) = bar.take() {
+                    bar.finish_and_clear();
+                }
+            }
+        }
+    }
+}
+
+// A task dropped on an error path never sends `Finished`; abandoning the bar here stops its tick
+// and leaves it where the transfer stopped, with the error printed below it
+impl Drop for BarRenderer {
+    fn drop(&mut self) {
+        if let Some(bar) = self.bar.lock().unwrap().take() {
+            bar.abandon();
+        }
+    }
+}
+
+// Decides when a plain progress line is due: on the first update, whenever the percentage
+// crosses another step, and otherwise at least every interval
+#[derive(Debug, Clone)]
+pub struct Cadence {
+    interval: Duration,
+    percent_step: u64,
+    last_line: Option<Instant>,
+    last_step: Option<u64>,
+}
+
+impl Cadence {
+    pub fn new(interval: Duration, percent_step: u64) -> Self {
+        Self {
+            interval,
+            percent_step: percent_step.max(1),
+            last_line: None,
+            last_step: None,
+        }
+    }
+
+    pub fn due(&mut self, now: Instant, percent: Option<u64>) -> bool {
+        let step = percent.map(|p| p / self.percent_step);
+        let due = match self.last_line {
+            None => true,
+            Some(last) => now.duration_since(last) >= self.interval || step > self.last_step,
+        };
+
+        if due {
+            self.last_line = Some(now);
+            self.last_step = step;
+        }
+        due
+    }
+}
+
+struct PlainState {
+    task: String,
+    total: Option<u64>,
+    unit: Unit,
+    position: u64,
+    detail: Option<String>,
+    started: Instant,
+    cadence: Cadence,
+}
+
+impl PlainState {
+    fn line(&self, elapsed: Duration) -> String {
+        let line = format_line(&self.task, self.position, self.total, self.unit, elapsed);
+        match &self.detail {
+            Some(detail) => format!("{}, {}", line, detail),
+            None => line,
+        }
+    }
+}
+
+// One timestamped line per update that the cadence lets through, for logs and pipes
+pub struct PlainRenderer {
+    out: Mutex<Box<dyn Write + Send>>,
+    state: Mutex<Option<PlainState>>,
+    interval: Duration,
+    percent_step: u64,
+}
+
+impl PlainRenderer {
+    pub fn new(out: Box<dyn Write + Send>) -> Self {
+        Self::with_cadence(out, PLAIN_INTERVAL, PLAIN_PERCENT_STEP)
+    }
+
+    pub fn with_cadence(out: Box<dyn Write + Send>, interval: Duration, percent_step: u64) -> Self {
+        Self {
+            out: Mutex::new(out),
+            state: Mutex::new(None),
+            interval,
+            percent_step,
+        }
+    }
+
+    fn emit(&self, line: &str) {
+        let mut out = self.out.lock().unwrap();
+        // Progress is best effort; a closed pipe shouldn't fail the transfer
+        let _ = writeln!(out, "[{}] {}", clock(SystemTime::now()), line);
+        let _ = out.flush();
+    }
+}
+
+impl ProgressRenderer for PlainRenderer {
+    fn render(&self, event: &ProgressEvent) {
+        let mut state = self.state.lock().unwrap();
+
+        match event {
+            ProgressEvent::Started { task, total, unit } => {
+                *state = Some(PlainState {
+                    task: task.clone(),
+                    total: *total,
+                    unit: *unit,
+                    position: 0,
+                    detail: None,
+                    started: Instant::now(),
+                    cadence: Cadence::new(self.interval, self.percent_step),
+                });
+            }
+            ProgressEvent::Advanced(delta) => {
+                let Some(state) = state.as_mut() else {
+                    return;
+                };
+                state.position += delta;
+
+                let now = Instant::now();
+                if state.cadence.due(now, percent(state.position, state.total)) {
+                    self.emit(&state.line(now.duration_since(state.started)));
+                }
+            }
+            ProgressEvent::Status(message) => {
+                if let Some(state) = state.as_ref() {
+                    self.emit(&format!("{} {}", state.task, message));
+                }
+            }
+            ProgressEvent::Detail(detail) => {
+                if let Some(state) = state.as_mut() {
+                    state.detail = Some(detail.clone());
+                }
+            }
+            ProgressEvent::Finished => {
+                let Some(state) = state.take() else {
+                    return;
+                };
+
+                // Tasks that only ever reported status have no numbers worth repeating
+                if state.total.is_none() && state.position == 0 {
+                    self.emit(&format!("{} done", state.task));
+                } else {
+                    self.emit(&format!("{} done", state.line(state.started.elapsed())));
+                }
+            }
+        }
+    }
+}
+
+fn percent(position: u64, total: Option<u64>) -> Option<u64> {
+    match total {
+        Some(0) => Some(100),
+        Some(total) => Some((position.min(total) * 100) / total),
+        None => None,
+    }
+}
+
+// "download 45% 230/512 MB 38 MB/s"; unknown totals drop the percentage and denominator
+pub fn format_line(task: &str, position: u64, total: Option<u64>, unit: Unit, elapsed: Duration) -> String {
+    let secs = elapsed.as_secs_f64();
+    let rate = if secs > 0.0 { position as f64 / secs } else { 0.0 };
+
+    let (amount, rate) = match unit {
+        Unit::Bytes => {
+            let mb = |bytes: f64| (bytes / (1024.0 * 1024.0)).round() as u64;
+            let amount = match total {
+                Some(total) => format!("{}/{} MB", mb(position as f64), mb(total as f64)),
+                None => format!("{} MB", mb(position as f64)),
+            };
+            (amount, format!("{} MB/s", mb(rate)))
+        }
+        Unit::Chunks => {
+            let amount = match total {
+                Some(total) => format!("{}/{} chunks", position, total),
+                None => format!("{} chunks", position),
+            };
+            (amount, format!("{} chunks/s", rate.round() as u64))
+        }
+    };
+
+    match percent(position, total) {
+        Some(percent) => format!("{} {}% {} {}", task, percent, amount, rate),
+        None => format!("{} {} {}", task, amount, rate),
+    }
+}
+
+// Wall-clock time of day in UTC, which is what CI log timestamps are in anyway
+fn clock(now: SystemTime) -> String {
+    let secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() % 86_400;
+    format!("{:02}:{:02}:{:02}", secs / 3600, (secs / 60) % 60, secs % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Captured {
+        fn lines(&self) -> Vec<String> {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap().lines().map(str::to_string).collect()
+        }
+    }
+
+    #[test]
+    fn test_mode_resolution() {
+        assert_eq!(ProgressMode::Auto.resolve(true), ProgressMode::Bar);
+        assert_eq!(ProgressMode::Auto.resolve(false), ProgressMode::Plain);
+        assert_eq!(ProgressMode::Bar.resolve(false), ProgressMode::Bar);
+        assert_eq!(ProgressMode::None.resolve(true), ProgressMode::None);
+    }
+
+    #[test]
+    fn test_line_format() {
+        let mb = 1024 * 1024;
+        assert_eq!(
+            format_line("download", 230 * mb, Some(512 * mb), Unit::Bytes, Duration::from_secs(6)),
+            "download 44% 230/512 MB 38 MB/s"
+        );
+        assert_eq!(
+            format_line("compress", 3, Some(12), Unit::Chunks, Duration::from_secs(1)),
+            "compress 25% 3/12 chunks 3 chunks/s"
+        );
+        assert_eq!(format_line("upload", 7, None, Unit::Chunks, Duration::ZERO), "upload 7 chunks 0 chunks/s");
+        assert_eq!(clock(UNIX_EPOCH + Duration::from_secs(86_400 + 12 * 3600 + 63)), "12:01:03");
+    }
+
+    #[test]
+    fn test_cadence_by_percent_and_interval() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut cadence = Cadence::new(Duration::from_secs(5), 10);
+
+        assert!(cadence.due(at(0), Some(1)));
+        // Small moves inside the same 10% step stay quiet until the interval runs out
+        assert!(!cadence.due(at(100), Some(5)));
+        assert!(!cadence.due(at(200), Some(9)));
+        assert!(cadence.due(at(300), Some(10)));
+        assert!(!cadence.due(at(400), Some(19)));
+        assert!(cadence.due(at(5400), Some(19)));
+
+        // Without a total only time drives it
+        let mut cadence = Cadence::new(Duration::from_secs(5), 10);
+        assert!(cadence.due(at(0), None));
+        assert!(!cadence.due(at(4999), None));
+        assert!(cadence.due(at(5000), None));
+    }
+
+    #[test]
+    fn test_plain_renderer_output() {
+        let captured = Captured::default();
+        let renderer = Arc::new(PlainRenderer::with_cadence(Box::new(captured.clone()), Duration::from_secs(3600), 25));
+
+        let task = ProgressTask::with_renderer(renderer, "write", Some(8), Unit::Chunks);
+        for _ in 0..8 {
+            task.inc(1);
+        }
+        task.status("verifying");
+        task.finish();
+
+        let lines = captured.lines();
+        let bodies: Vec<&str> = lines
+            .iter()
+            .map(|l| {
+                // "[hh:mm:ss] " prefix
+                assert_eq!((&l[..1], &l[3..4], &l[6..7], &l[9..11]), ("[", ":", ":", "] "));
+                l[11..].split(" chunks").next().unwrap()
+            })
+            .collect();
+
+        assert_eq!(
+            bodies,
+            vec!["write 12% 1/8", "write 25% 2/8", "write 50% 4/8", "write 75% 6/8", "write 100% 8/8", "write verifying", "write 100% 8/8"]
+        );
+        assert!(lines.last().unwrap().ends_with(" done"));
+
+        let captured = Captured::default();
+        let renderer = Arc::new(PlainRenderer::new(Box::new(captured.clone())));
+        let task = ProgressTask::with_renderer(renderer, "download", None, Unit::Bytes);
+        task.status("Downloading from HTTP server...");
+        task.finish();
+        let bodies: Vec<String> = captured.lines().iter().map(|l| l[11..].to_string()).collect();
+        assert_eq!(bodies, vec!["download Downloading from HTTP server...", "download done"]);
+    }
+
+    #[test]
+    fn test_plain_detail_rides_on_due_lines() {
+        let captured = Captured::default();
+        let renderer = Arc::new(PlainRenderer::with_cadence(Box::new(captured.clone()), Duration::from_secs(3600), 50));
+
+        let task = ProgressTask::with_renderer(renderer, "compress", Some(4), Unit::Chunks);
+        for ratio in [40, 41, 42, 43] {
+            task.detail(format!("{}% of original size", ratio));
+            task.inc(1);
+        }
+        task.finish();
+
+        let lines = captured.lines();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].contains("compress 25% 1/4 chunks") && lines[0].ends_with(", 40% of original size"));
+        assert!(lines[1].contains("compress 50% 2/4") && lines[1].ends_with(", 41% of original size"));
+        assert!(lines[3].ends_with(", 43% of original size done"));
+    }
+}
@@ -0,0 +1,169 @@
+use crate::{Result, ShrLinkError};
+
+pub const DEFAULT_MIN_SIZE: usize = 1024 * 1024; // 1 MiB
+pub const DEFAULT_AVG_SIZE: usize = 4 * 1024 * 1024; // 4 MiB
+pub const DEFAULT_MAX_SIZE: usize = 16 * 1024 * 1024; // 16 MiB
+// The rolling hash only sees the last 64 bytes, so a smaller minimum would cut inside its window
+const MIN_MIN_SIZE: usize = 64;
+
+// Part of the format in all but name: changing a single entry moves every boundary, and with it
+// every chunk hash a previous send produced
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    // splitmix64 from a fixed seed
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x5368_724c_696e_6b21;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+// FastCDC with normalized chunking: below the average size a cut needs one more matching bit
+// than the average calls for and above it one fewer, which pulls sizes in towards the average.
+// Masks take the hash's high bits, which depend on the whole 64-byte window
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CdcParams {
+    min_size: usize,
+    avg_size: usize,
+    max_size: usize,
+    mask_small: u64,
+    mask_large: u64,
+}
+
+impl Default for CdcParams {
+    fn default() -> Self {
+        Self::new(DEFAULT_MIN_SIZE, DEFAULT_AVG_SIZE, DEFAULT_MAX_SIZE).expect("default CDC sizes are valid")
+    }
+}
+
+impl CdcParams {
+    pub fn new(min_size: usize, avg_size: usize, max_size: usize) -> Result<Self> {
+        if min_size < MIN_MIN_SIZE || min_size > avg_size || avg_size > max_size || max_size > u32::MAX as usize {
+            return Err(ShrLinkError::InvalidInput(format!(
+                "Content-defined chunk sizes must satisfy {} <= min <= avg <= max <= {} (got {}, {}, {})",
+                MIN_MIN_SIZE,
+                u32::MAX,
+                min_size,
+                avg_size,
+                max_size
+            )));
+        }
+
+        let bits = avg_size.ilog2();
+        let high_bits = |n: u32| u64::MAX << (64 - n);
+        Ok(Self {
+            min_size,
+            avg_size,
+            max_size,
+            mask_small: high_bits(bits + 1),
+            mask_large: high_bits(bits - 1),
+        })
+    }
+
+    pub fn min_size(&self) -> usize {
+        self.min_size
+    }
+
+    pub fn avg_size(&self) -> usize {
+        self.avg_size
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    // Length of the chunk at the start of `data`. Only the first `max_size` bytes are looked
+    // at, so the answer is the same whether `data` is a read buffer or the whole file; a
+    // shorter `data` must be the end of the input
+    pub fn cut(&self, data: &[u8]) -> usize {
+        if data.len() <= self.min_size {
+            return data.len();
+        }
+
+        let end = data.len().min(self.max_size);
+        let normal = self.avg_size.min(end);
+        let mut hash: u64 = 0;
+        let mut i = self.min_size;
+
+        while i < normal {
+            hash = (hash << 1).wrapping_add(GEAR[data[i] as usize]);
+            if hash & self.mask_small == 0 {
+                return i + 1;
+            }
+            i += 1;
+        }
+        while i < end {
+            hash = (hash << 1).wrapping_add(GEAR[data[i] as usize]);
+            if hash & self.mask_large == 0 {
+                return i + 1;
+            }
+            i += 1;
+        }
+
+        end
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn cuts(params: &CdcParams, mut data: &[u8]) -> Vec<usize> {
+        let mut sizes = Vec::new();
+        while !data.is_empty() {
+            let len = params.cut(data);
+            sizes.push(len);
+            data = &data[len..];
+        }
+        sizes
+    }
+
+    #[test]
+    fn test_sizes_stay_in_bounds() {
+        let params = CdcParams::new(2048, 8192, 32768).unwrap();
+        let data = noise(4 * 1024 * 1024, 7);
+        let sizes = cuts(&params, &data);
+
+        assert_eq!(sizes.iter().sum::<usize>(), data.len());
+        let (last, rest) = sizes.split_last().unwrap();
+        assert!(rest.iter().all(|s| (2048..=32768).contains(s)));
+        assert!(*last <= 32768);
+
+        // Normalized chunking keeps the average close to what was asked for
+        let mean = data.len() / sizes.len();
+        assert!((6 * 1024..=12 * 1024).contains(&mean), "mean chunk size {}", mean);
+
+        // Runs of identical bytes never match, so they are cut at the maximum
+        assert_eq!(params.cut(&[0u8; 100_000]), 32768);
+        assert_eq!(params.cut(&[1u8; 100]), 100);
+    }
+
+    #[test]
+    fn test_invalid_sizes_rejected() {
+        assert!(CdcParams::new(16, 8192, 32768).is_err());
+        assert!(CdcParams::new(4096, 2048, 32768).is_err());
+        assert!(CdcParams::new(4096, 8192, 4096).is_err());
+        assert!(CdcParams::new(4096, 4096, 4096).is_ok());
+        assert_eq!(CdcParams::default().avg_size(), DEFAULT_AVG_SIZE);
+    }
+}
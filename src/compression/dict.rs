@@ -0,0 +1,177 @@
+use std::fmt;
+use std::sync::Arc;
+#[cfg(feature = "zstd")]
+use std::sync::OnceLock;
+use crate::{Result, ShrLinkError};
+
+// zstd's own default, and as large as `train` makes one unless told otherwise
+pub const DEFAULT_MAX_SIZE: usize = 110 * 1024;
+// Largest a bundle may carry, checked before any of it is read
+pub const MAX_SIZE: usize = 1024 * 1024;
+// How much of each input is sampled for training, and of all of them together. zstd suggests
+// about a hundred times the dictionary's size in samples
+pub const SAMPLE_SIZE: usize = 128 * 1024;
+pub const MAX_SAMPLES_SIZE: usize = 100 * DEFAULT_MAX_SIZE;
+// A dictionary gets no larger than a tenth of its samples, and training isn't tried with less
+// than this to show for it
+#[cfg(feature = "zstd")]
+const MIN_TRAINED_SIZE: usize = 1024;
+
+// A zstd dictionary for a whole bundle. It travels once, ahead of the first file, and zstd chunks
+// compressed against it need it back to decompress; the chunks themselves only say they're zstd
+#[derive(Clone)]
+pub struct Dictionary(Arc<Inner>);
+
+struct Inner {
+    data: Vec<u8>,
+    // Digested on first use and shared by every clone, since loading one costs far more than
+    // compressing a small chunk with it
+    #[cfg(feature = "zstd")]
+    encoder: OnceLock<zstd::dict::EncoderDictionary<'static>>,
+    #[cfg(feature = "zstd")]
+    decoder: OnceLock<zstd::dict::DecoderDictionary<'static>>,
+}
+
+impl Dictionary {
+    pub fn new(data: Vec<u8>) -> Result<Self> {
+        if data.is_empty() || data.len() > MAX_SIZE {
+            return Err(ShrLinkError::InvalidInput(format!(
+                "Compression dictionary must be between 1 and {} bytes (got {})",
+                MAX_SIZE,
+                data.len()
+            )));
+        }
+        Ok(Self(Arc::new(Inner {
+            data,
+            #[cfg(feature = "zstd")]
+            encoder: OnceLock::new(),
+            #[cfg(feature = "zstd")]
+            decoder: OnceLock::new(),
+        })))
+    }
+
+    // At most `max_size` bytes, and less when the samples are too few to fill that much
+    #[cfg(feature = "zstd")]
+    pub fn train<S: AsRef<[u8]>>(samples: &[S], max_size: usize) -> Result<Self> {
+        let total: usize = samples.iter().map(|s| s.as_ref().len()).sum();
+        let size = max_size.min(MAX_SIZE).min(total / 10);
+        if size < MIN_TRAINED_SIZE {
+            return Err(ShrLinkError::InvalidInput(format!(
+                "{} bytes of samples are too few to train a dictionary on",
+                total
+            )));
+        }
+        let data = zstd::dict::from_samples(samples, size)
+            .map_err(|e| ShrLinkError::Compression(format!("dictionary training failed: {}", e)))?;
+        Self::new(data)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0.data
+    }
+
+    #[cfg(feature = "zstd")]
+    pub(super) fn encoder(&self) -> &zstd::dict::EncoderDictionary<'static> {
+        self.0.encoder.get_or_init(|| zstd::dict::EncoderDictionary::copy(&self.0.data, super::ZSTD_LEVEL))
+    }
+
+    #[cfg(feature = "zstd")]
+    pub(super) fn decoder(&self) -> &zstd::dict::DecoderDictionary<'static> {
+        self.0.decoder.get_or_init(|| zstd::dict::DecoderDictionary::copy(&self.0.data))
+    }
+}
+
+impl PartialEq for Dictionary {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+}
+
+impl Eq for Dictionary {}
+
+impl fmt::Debug for Dictionary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Dictionary({} bytes)", self.0.data.len())
+    }
+}
+
+// The start of each file, up to SAMPLE_SIZE of it, until MAX_SAMPLES_SIZE has been read
+#[cfg(feature = "fs")]
+pub fn sample_files<P: AsRef<std::path::Path>>(paths: impl IntoIterator<Item = P>) -> Result<Vec<Vec<u8>>> {
+    use std::io::Read;
+
+    let mut samples = Vec::new();
+    let mut total = 0;
+    for path in paths {
+        let limit = SAMPLE_SIZE.min(MAX_SAMPLES_SIZE - total);
+        if limit == 0 {
+            break;
+        }
+        let mut sample = Vec::new();
+        std::fs::File::open(path)?.take(limit as u64).read_to_end(&mut sample)?;
+        total += sample.len();
+        if !sample.is_empty() {
+            samples.push(sample);
+        }
+    }
+    Ok(samples)
+}
+
+#[cfg(all(test, feature = "zstd"))]
+mod tests {
+    use super::*;
+    use crate::compression::{CompressionAlgorithm, ParallelCompressor};
+
+    fn records(count: usize) -> Vec<Vec<u8>> {
+        (0..count)
+            .map(|i| {
+                format!(
+                    r#"{{"id":{},"name":"user-{}","email":"user{}@example.com","active":{},"roles":["reader","writer"],"created":"2026-0{}-1{}T08:00:00Z"}}"#,
+                    i,
+                    i * 7919 % 1000,
+                    i,
+                    i % 3 == 0,
+                    1 + i % 9,
+                    i % 10
+                )
+                .into_bytes()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_dictionary_roundtrip() {
+        let samples = records(500);
+        let dictionary = Dictionary::train(&samples, DEFAULT_MAX_SIZE).unwrap();
+        assert!(dictionary.as_bytes().len() <= DEFAULT_MAX_SIZE);
+
+        let zstd = ParallelCompressor::default().with_algorithm(CompressionAlgorithm::Zstd).with_min_savings(0.0);
+        let with_dict = zstd.clone().with_dictionary(dictionary.clone());
+        let plain: usize = samples.iter().enumerate().map(|(i, s)| zstd.compress_chunk(i, s.clone()).unwrap().data.len()).sum();
+        let chunks: Vec<_> = samples.iter().enumerate().map(|(i, s)| with_dict.compress_chunk(i, s.clone()).unwrap()).collect();
+        let trained: usize = chunks.iter().map(|c| c.data.len()).sum();
+        assert!(trained * 2 < plain, "{} bytes with the dictionary, {} without", trained, plain);
+
+        let receiver = ParallelCompressor::default().with_dictionary(Dictionary::new(dictionary.as_bytes().to_vec()).unwrap());
+        for (chunk, sample) in chunks.iter().zip(&samples) {
+            assert_eq!(receiver.decompress_chunk(chunk).unwrap(), *sample);
+        }
+
+        // Without it, or with another one, the chunks can't be read
+        let chunk = chunks.iter().find(|c| c.algorithm == CompressionAlgorithm::Zstd).unwrap();
+        assert!(ParallelCompressor::default().decompress_chunk(chunk).is_err());
+        let other = Dictionary::train(&records(400)[100..], DEFAULT_MAX_SIZE / 2).unwrap();
+        assert!(ParallelCompressor::default().with_dictionary(other).decompress_chunk(chunk).is_err());
+
+        // Chunks compressed without one still decompress with one
+        let chunk = zstd.compress_chunk(0, samples[0].repeat(20)).unwrap();
+        assert_eq!(receiver.decompress_chunk(&chunk).unwrap(), samples[0].repeat(20));
+    }
+
+    #[test]
+    fn test_dictionary_limits() {
+        assert!(Dictionary::new(Vec::new()).is_err());
+        assert!(Dictionary::new(vec![0; MAX_SIZE + 1]).is_err());
+        assert!(Dictionary::train(&records(3), DEFAULT_MAX_SIZE).is_err());
+    }
+}
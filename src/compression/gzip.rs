@@ -0,0 +1,555 @@
+use crate::{Result, ShrLinkError};
+use super::snappy::copy_back;
+
+// Single-member gzip files (RFC 1952) around a DEFLATE stream (RFC 1951), readable by gzip,
+// zcat and any HTTP client that takes `Content-Encoding: gzip`. Compression is LZ77 with the
+// fixed Huffman codes, which keeps it simple at some cost in ratio; decompression takes any
+// valid stream, including several members one after another as `gzip -d` does
+
+const WINDOW_SIZE: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const HASH_BITS: u32 = 15;
+// How many earlier positions with the same hash are tried for a longer match
+const MAX_CHAIN: usize = 32;
+// A match this long is taken without looking any further
+const GOOD_MATCH: usize = 64;
+// A 258-byte match can take as little as two bits, so nothing expands further than this
+const MAX_EXPANSION: usize = 1032;
+
+const HEADER: [u8; 10] = [0x1f, 0x8b, 0x08, 0, 0, 0, 0, 0, 0, 0xff];
+const FHCRC: u8 = 0x02;
+const FEXTRA: u8 = 0x04;
+const FNAME: u8 = 0x08;
+const FCOMMENT: u8 = 0x10;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+// The order code length code lengths are sent in
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+static CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { 0xedb8_8320 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &b| CRC_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8))
+}
+
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = BitWriter { out: Vec::with_capacity(HEADER.len() + data.len() / 2 + 64), bits: 0, count: 0 };
+    out.out.extend_from_slice(&HEADER);
+    // One final block with the fixed codes
+    out.write(1, 1);
+    out.write(1, 2);
+    deflate(data, &mut out);
+    write_fixed_literal(&mut out, 256);
+    out.flush();
+
+    let mut out = out.out;
+    out.extend_from_slice(&crc32(data).to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out
+}
+
+fn deflate(data: &[u8], out: &mut BitWriter) {
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; WINDOW_SIZE];
+    let insert = |head: &mut [usize], prev: &mut [usize], pos: usize| {
+        if pos + MIN_MATCH <= data.len() {
+            let slot = hash(&data[pos..]);
+            prev[pos % WINDOW_SIZE] = head[slot];
+            head[slot] = pos;
+        }
+    };
+
+    let mut pos = 0;
+    while pos < data.len() {
+        let (len, distance) = longest_match(data, pos, &head, &prev);
+        if len >= MIN_MATCH {
+            write_match(out, len, distance);
+            for p in pos..pos + len {
+                insert(&mut head, &mut prev, p);
+            }
+            pos += len;
+        } else {
+            write_fixed_literal(out, data[pos] as u16);
+            insert(&mut head, &mut prev, pos);
+            pos += 1;
+        }
+    }
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let word = (bytes[0] as u32) | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16;
+    (word.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+}
+
+fn longest_match(data: &[u8], pos: usize, head: &[usize], prev: &[usize]) -> (usize, usize) {
+    if pos + MIN_MATCH > data.len() {
+        return (0, 0);
+    }
+    let limit = (data.len() - pos).min(MAX_MATCH);
+    let mut best = (0, 0);
+    let mut candidate = head[hash(&data[pos..])];
+    for _ in 0..MAX_CHAIN {
+        if candidate == usize::MAX || candidate >= pos || pos - candidate > WINDOW_SIZE {
+            break;
+        }
+        let len = data[candidate..candidate + limit].iter().zip(&data[pos..pos + limit]).take_while(|(a, b)| a == b).count();
+        if len > best.0 {
+            best = (len, pos - candidate);
+            if len >= GOOD_MATCH.min(limit) {
+                break;
+            }
+        }
+        candidate = prev[candidate % WINDOW_SIZE];
+    }
+    best
+}
+
+fn write_match(out: &mut BitWriter, len: usize, distance: usize) {
+    let code = LENGTH_BASE.partition_point(|&base| base as usize <= len) - 1;
+    write_fixed_literal(out, 257 + code as u16);
+    out.write((len - LENGTH_BASE[code] as usize) as u32, LENGTH_EXTRA[code] as u32);
+
+    let code = DIST_BASE.partition_point(|&base| base as usize <= distance) - 1;
+    out.write(reverse(code as u32, 5), 5);
+    out.write((distance - DIST_BASE[code] as usize) as u32, DIST_EXTRA[code] as u32);
+}
+
+fn write_fixed_literal(out: &mut BitWriter, symbol: u16) {
+    let (code, len) = match symbol {
+        0..=143 => (0x30 + symbol as u32, 8),
+        144..=255 => (0x190 + (symbol as u32 - 144), 9),
+        256..=279 => (symbol as u32 - 256, 7),
+        _ => (0xc0 + (symbol as u32 - 280), 8),
+    };
+    out.write(reverse(code, len), len);
+}
+
+// Huffman codes go out most significant bit first, into a stream that's otherwise filled from
+// the least significant bit of each byte
+fn reverse(code: u32, len: u32) -> u32 {
+    code.reverse_bits() >> (32 - len)
+}
+
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, len: u32) {
+        self.bits |= (value as u64) << self.count;
+        self.count += len;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.count > 0 {
+            self.out.push(self.bits as u8);
+            self.bits = 0;
+            self.count = 0;
+        }
+    }
+}
+
+// Appends the decompressed input to `out`, refusing more than `max_size` bytes of it before
+// they're written and any member whose length or CRC doesn't match its trailer
+pub fn decompress(input: &[u8], max_size: usize, out: &mut Vec<u8>) -> Result<()> {
+    out.reserve(max_size.min(input.len().saturating_mul(MAX_EXPANSION)));
+    let limit = out.len() + max_size;
+    let mut rest = input;
+    loop {
+        let start = out.len();
+        let body = skip_header(rest)?;
+        let mut reader = BitReader { input: body, pos: 0, bits: 0, count: 0 };
+        inflate(&mut reader, out, limit)?;
+        let trailer = body.get(reader.byte_pos()..reader.byte_pos() + 8).ok_or_else(|| invalid("trailer is cut short"))?;
+        if u32::from_le_bytes(trailer[..4].try_into().unwrap()) != crc32(&out[start..]) {
+            return Err(invalid("CRC doesn't match"));
+        }
+        if u32::from_le_bytes(trailer[4..].try_into().unwrap()) != (out.len() - start) as u32 {
+            return Err(invalid("length doesn't match"));
+        }
+        rest = &body[reader.byte_pos() + 8..];
+        if rest.is_empty() {
+            return Ok(());
+        }
+    }
+}
+
+fn skip_header(input: &[u8]) -> Result<&[u8]> {
+    let header = input.get(..HEADER.len()).ok_or_else(|| invalid("header is cut short"))?;
+    if header[..3] != HEADER[..3] {
+        return Err(invalid("not gzip"));
+    }
+    let flags = header[3];
+    if flags & 0xe0 != 0 {
+        return Err(invalid("reserved flags are set"));
+    }
+    let mut rest = &input[HEADER.len()..];
+    if flags & FEXTRA != 0 {
+        let len = rest.get(..2).map(|b| u16::from_le_bytes([b[0], b[1]]) as usize).ok_or_else(|| invalid("header is cut short"))?;
+        rest = rest.get(2 + len..).ok_or_else(|| invalid("header is cut short"))?;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            let end = rest.iter().position(|&b| b == 0).ok_or_else(|| invalid("header is cut short"))?;
+            rest = &rest[end + 1..];
+        }
+    }
+    if flags & FHCRC != 0 {
+        rest = rest.get(2..).ok_or_else(|| invalid("header is cut short"))?;
+    }
+    Ok(rest)
+}
+
+fn inflate(reader: &mut BitReader, out: &mut Vec<u8>, limit: usize) -> Result<()> {
+    let start = out.len();
+    loop {
+        let last = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => {
+                reader.align();
+                let header = reader.take(4)?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                if len != !u16::from_le_bytes([header[2], header[3]]) {
+                    return Err(invalid("stored block length doesn't match its complement"));
+                }
+                let data = reader.take(len as usize)?;
+                if out.len() + data.len() > limit {
+                    return Err(too_long());
+                }
+                out.extend_from_slice(data);
+            }
+            1 => {
+                let (literals, distances) = fixed_codes();
+                inflate_block(reader, out, start, limit, &literals, &distances)?;
+            }
+            2 => {
+                let (literals, distances) = dynamic_codes(reader)?;
+                inflate_block(reader, out, start, limit, &literals, &distances)?;
+            }
+            _ => return Err(invalid("reserved block type")),
+        }
+        if last {
+            return Ok(());
+        }
+    }
+}
+
+fn inflate_block(reader: &mut BitReader, out: &mut Vec<u8>, start: usize, limit: usize, literals: &Huffman, distances: &Huffman) -> Result<()> {
+    loop {
+        let symbol = literals.decode(reader)? as usize;
+        if symbol < 256 {
+            if out.len() >= limit {
+                return Err(too_long());
+            }
+            out.push(symbol as u8);
+            continue;
+        }
+        if symbol == 256 {
+            return Ok(());
+        }
+
+        let code = symbol - 257;
+        if code >= LENGTH_BASE.len() {
+            return Err(invalid("bad length symbol"));
+        }
+        let len = LENGTH_BASE[code] as usize + reader.bits(LENGTH_EXTRA[code] as u32)? as usize;
+        let code = distances.decode(reader)? as usize;
+        if code >= DIST_BASE.len() {
+            return Err(invalid("bad distance symbol"));
+        }
+        let distance = DIST_BASE[code] as usize + reader.bits(DIST_EXTRA[code] as u32)? as usize;
+        if distance > out.len() - start {
+            return Err(invalid("distance reaches back before the start"));
+        }
+        if out.len() + len > limit {
+            return Err(too_long());
+        }
+        copy_back(out, distance, len);
+    }
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    // Built from lengths that are known to be good
+    (Huffman::new(&lengths).unwrap(), Huffman::new(&[5; 30]).unwrap())
+}
+
+fn dynamic_codes(reader: &mut BitReader) -> Result<(Huffman, Huffman)> {
+    let literal_count = reader.bits(5)? as usize + 257;
+    let distance_count = reader.bits(5)? as usize + 1;
+    let code_length_count = reader.bits(4)? as usize + 4;
+    if literal_count > 286 || distance_count > 30 {
+        return Err(invalid("too many codes"));
+    }
+
+    let mut code_lengths = [0u8; 19];
+    for &symbol in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_lengths[symbol] = reader.bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_lengths)?;
+
+    let mut lengths = vec![0u8; literal_count + distance_count];
+    let mut i = 0;
+    while i < lengths.len() {
+        let symbol = code_lengths.decode(reader)?;
+        let (value, repeat) = match symbol {
+            0..=15 => {
+                lengths[i] = symbol as u8;
+                i += 1;
+                continue;
+            }
+            16 => {
+                let previous = *i.checked_sub(1).map(|p| &lengths[p]).ok_or_else(|| invalid("repeat with nothing before it"))?;
+                (previous, 3 + reader.bits(2)? as usize)
+            }
+            17 => (0, 3 + reader.bits(3)? as usize),
+            _ => (0, 11 + reader.bits(7)? as usize),
+        };
+        let run = lengths.get_mut(i..i + repeat).ok_or_else(|| invalid("code lengths run past the end"))?;
+        run.fill(value);
+        i += repeat;
+    }
+    if lengths[256] == 0 {
+        return Err(invalid("no end of block code"));
+    }
+    Ok((Huffman::new(&lengths[..literal_count])?, Huffman::new(&lengths[literal_count..])?))
+}
+
+const MAX_BITS: u32 = 15;
+
+// A lookup on the next MAX_BITS bits of input, bit-reversed as they arrive, giving the symbol
+// and how many of those bits its code took. Zero entries are codes an incomplete set leaves out
+struct Huffman {
+    table: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Self> {
+        let mut counts = [0u16; MAX_BITS as usize + 1];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = left * 2 - count as i32;
+            if left < 0 {
+                return Err(invalid("over-subscribed code"));
+            }
+        }
+
+        let mut next = [0u32; MAX_BITS as usize + 2];
+        for len in 1..=MAX_BITS as usize {
+            next[len + 1] = (next[len] + counts[len] as u32) << 1;
+        }
+        let mut table = vec![0u16; 1 << MAX_BITS];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len == 0 {
+                continue;
+            }
+            let code = next[len as usize];
+            next[len as usize] += 1;
+            let entry = (symbol as u16) << 4 | len as u16;
+            let mut fill = reverse(code, len as u32) as usize;
+            while fill < table.len() {
+                table[fill] = entry;
+                fill += 1 << len;
+            }
+        }
+        Ok(Self { table })
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16> {
+        let entry = self.table[reader.peek(MAX_BITS) as usize];
+        let len = (entry & 0x0f) as u32;
+        if len == 0 {
+            return Err(invalid("bad code"));
+        }
+        reader.consume(len)?;
+        Ok(entry >> 4)
+    }
+}
+
+// Past the end of the input it reads zeros, so a code can be looked up near the end; using
+// any of them is an error
+struct BitReader<'a> {
+    input: &'a [u8],
+    pos: usize,
+    bits: u64,
+    count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn refill(&mut self) {
+        while self.count <= 56 {
+            self.bits |= (self.input.get(self.pos).copied().unwrap_or(0) as u64) << self.count;
+            self.pos += 1;
+            self.count += 8;
+        }
+    }
+
+    fn peek(&mut self, len: u32) -> u32 {
+        if self.count < len {
+            self.refill();
+        }
+        (self.bits & ((1 << len) - 1)) as u32
+    }
+
+    fn consume(&mut self, len: u32) -> Result<()> {
+        self.bits >>= len;
+        self.count -= len;
+        if self.pos * 8 - self.count as usize > self.input.len() * 8 {
+            return Err(invalid("cut short"));
+        }
+        Ok(())
+    }
+
+    fn bits(&mut self, len: u32) -> Result<u32> {
+        let value = self.peek(len);
+        self.consume(len)?;
+        Ok(value)
+    }
+
+    fn align(&mut self) {
+        let extra = self.count % 8;
+        self.bits >>= extra;
+        self.count -= extra;
+    }
+
+    // Whole bytes, once aligned
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let at = self.byte_pos();
+        let bytes = self.input.get(at..).and_then(|rest| rest.get(..len)).ok_or_else(|| invalid("stored block is cut short"))?;
+        self.pos = at + len;
+        self.bits = 0;
+        self.count = 0;
+        Ok(bytes)
+    }
+
+    // The first byte none of whose bits have been read
+    fn byte_pos(&self) -> usize {
+        self.pos - (self.count / 8) as usize
+    }
+}
+
+fn too_long() -> ShrLinkError {
+    invalid("decompresses to more than it claims")
+}
+
+fn invalid(what: &str) -> ShrLinkError {
+    ShrLinkError::Compression(format!("invalid gzip data: {}", what))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PANGRAMS: &[u8] = b"The quick brown fox jumps over the lazy dog. The quick brown fox jumps over the lazy dog. \
+The quick brown fox jumps over the lazy dog. Pack my box with five dozen liquor jugs.";
+
+    fn roundtrip(data: &[u8]) -> Vec<u8> {
+        let compressed = compress(data);
+        let mut out = Vec::new();
+        decompress(&compressed, data.len(), &mut out).unwrap();
+        assert_eq!(out, data);
+        compressed
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        roundtrip(b"");
+        roundtrip(b"a");
+        assert!(roundtrip(&b"gzip ".repeat(20_000)).len() < 1000);
+        assert!(roundtrip(&vec![0xff; 300_000]).len() < 2000);
+        let noise: Vec<u8> = (0..200_000u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+        roundtrip(&noise);
+        let text: Vec<u8> = (0..5000).flat_map(|i| format!("line {} of {}\n", i, i % 37).into_bytes()).collect();
+        assert!(roundtrip(&text).len() < text.len() / 3);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_reads_gzip_output() {
+        // `gzip -9` output, which uses a dynamic Huffman block
+        let compressed = [
+            0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xb5, 0xcb, 0xc7, 0x01, 0x80, 0x20, 0x10, 0x05,
+            0xd1, 0x56, 0x7e, 0x05, 0xd4, 0xe2, 0xc1, 0x06, 0x40, 0x49, 0x06, 0x56, 0xb2, 0x50, 0xbd, 0xdb, 0x84, 0xe7,
+            0x79, 0xb3, 0x3a, 0x8d, 0x58, 0xfd, 0x76, 0x42, 0x25, 0xea, 0x01, 0x86, 0x5e, 0x1c, 0xf5, 0x7e, 0x32, 0xa8,
+            0xe9, 0x84, 0xc2, 0xf9, 0x92, 0x73, 0x60, 0x27, 0x2b, 0xb0, 0xfe, 0x86, 0x17, 0xc9, 0xee, 0x1e, 0x50, 0x8c,
+            0xba, 0x2f, 0x0e, 0xc6, 0x37, 0xcd, 0x69, 0xea, 0x80, 0xcb, 0xc7, 0x4a, 0x89, 0x5f, 0x9b, 0xc5, 0x07, 0x12,
+            0x8f, 0x6f, 0x7a, 0xaf, 0x00, 0x00, 0x00,
+        ];
+        let mut out = Vec::new();
+        decompress(&compressed, PANGRAMS.len(), &mut out).unwrap();
+        assert_eq!(out, PANGRAMS);
+
+        // A stored block, a name in the header, and a second member after the first
+        let mut stored = vec![0x1f, 0x8b, 0x08, FNAME, 0, 0, 0, 0, 0, 0xff];
+        stored.extend_from_slice(b"a.txt\0");
+        stored.extend_from_slice(&[0x01, 0x05, 0x00, 0xfa, 0xff]);
+        stored.extend_from_slice(b"hello");
+        stored.extend_from_slice(&crc32(b"hello").to_le_bytes());
+        stored.extend_from_slice(&5u32.to_le_bytes());
+        stored.extend_from_slice(&compress(b", world"));
+        out.clear();
+        decompress(&stored, 12, &mut out).unwrap();
+        assert_eq!(out, b"hello, world");
+    }
+
+    #[test]
+    fn test_damaged_input_is_refused() {
+        let compressed = compress(&b"damaged gzip ".repeat(1000));
+        for len in 0..compressed.len() {
+            assert!(decompress(&compressed[..len], 13_000, &mut Vec::new()).is_err(), "{} bytes", len);
+        }
+        // Past the timestamp and OS in the header, a changed byte is caught or changes nothing
+        for at in HEADER.len()..compressed.len() {
+            let mut damaged = compressed.clone();
+            damaged[at] ^= 0x5a;
+            let mut out = Vec::new();
+            let result = decompress(&damaged, 13_000, &mut out);
+            assert!(result.is_err() || out == b"damaged gzip ".repeat(1000), "byte {}", at);
+        }
+
+        // Never more than it's allowed, however much the stream holds
+        assert!(decompress(&compressed, 12_999, &mut Vec::new()).is_err());
+        let bomb = compress(&vec![0; 10_000_000]);
+        assert!(decompress(&bomb, 1000, &mut Vec::new()).is_err());
+    }
+}
@@ -1,66 +1,537 @@
-use blake3::Hasher;
 use lz4_flex::compress_prepend_size;
+#[cfg(feature = "parallel")]
 use rayon::prelude::*;
+#[cfg(feature = "fs")]
+use std::collections::BTreeMap;
+#[cfg(not(target_arch = "wasm32"))]
+use std::collections::VecDeque;
+#[cfg(feature = "fs")]
 use std::io::Read;
+#[cfg(feature = "fs")]
 use std::path::Path;
+#[cfg(feature = "fs")]
 use std::fs::File;
-use tokio::io::{AsyncRead, AsyncReadExt};
+#[cfg(not(target_arch = "wasm32"))]
+use std::pin::Pin;
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::Arc;
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::sync::{oneshot, Semaphore};
+#[cfg(not(target_arch = "wasm32"))]
+use tokio_util::sync::CancellationToken;
+#[cfg(not(target_arch = "wasm32"))]
+use futures::{stream, FutureExt, Stream, StreamExt};
+#[cfg(all(feature = "fs", feature = "parallel"))]
+use futures::Future;
+#[cfg(all(feature = "fs", feature = "parallel"))]
+use tokio::sync::mpsc;
+#[cfg(feature = "fs")]
+use crate::temp::{ScratchKind, TempGuard};
+use std::fmt;
+use std::str::FromStr;
 use crate::{Result, ShrLinkError};
 
+pub use crate::bundle::{create_shr_bundle, parse_shr_bundle};
+
+pub mod cdc;
+pub mod dict;
+pub mod gzip;
+pub mod snappy;
+
+pub use cdc::CdcParams;
+pub use dict::Dictionary;
+
 pub const BLOCK_SIZE: usize = 4 * 1024 * 1024; // 4 MiB
+// Automatic block sizes aim for about this many chunks, and stay within these bounds however
+// small or large the input
+pub const AUTO_TARGET_CHUNKS: u64 = 256;
+pub const AUTO_MIN_BLOCK_SIZE: usize = 1024 * 1024; // 1 MiB
+pub const AUTO_MAX_BLOCK_SIZE: usize = 64 * 1024 * 1024; // 64 MiB
+// LZ4 output starts with the original size as a u32, so no larger chunk can be written
+pub const MAX_BLOCK_SIZE: usize = u32::MAX as usize;
 pub const LZ4_ACCELERATION: i32 = 1;
+// LZ4 can't expand data by more than this per compressed byte, so anything claiming more is
+// lying about its size
+const LZ4_MAX_EXPANSION: usize = 255;
+
+pub const ZSTD_LEVEL: i32 = 3;
+// Chunks that compress by less than this are kept as they are; already-compressed media
+// otherwise costs CPU on both ends and can even grow
+pub const DEFAULT_MIN_SAVINGS_PERCENT: f64 = 2.0;
+
+pub const DEFAULT_MAX_INFLIGHT_DECOMPRESSED_BYTES: usize = 256 * 1024 * 1024; // 256 MiB
+
+// How much of a file `estimate_ratio` compresses unless told otherwise, spread over this many
+// places so one unusual region can't decide the estimate alone
+pub const DEFAULT_ESTIMATE_SAMPLE_BYTES: u64 = 32 * 1024 * 1024; // 32 MiB
+#[cfg(feature = "fs")]
+const ESTIMATE_SAMPLES: u64 = 16;
+
+// Compared against a slice at a time, which is far quicker than looking at each byte
+static ZEROS: [u8; 64 * 1024] = [0; 64 * 1024];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionAlgorithm {
+    #[default]
+    Lz4,
+    Zstd,
+    // Raw bytes, for chunks that didn't compress well enough to be worth it
+    Stored,
+    // Nothing but zeros, as in the holes of a sparse file, so only the size is carried. Chosen
+    // per chunk, never configured
+    Zero,
+    // Standard formats, for servers and tools that should be able to read the chunks themselves
+    Gzip,
+    Snappy,
+    // A codec id from a newer sender. The chunk can be stored and passed on as it is, but not
+    // decompressed
+    #[serde(skip)]
+    Unknown(u8),
+}
+
+impl FromStr for CompressionAlgorithm {
+    type Err = ShrLinkError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "lz4" => Ok(CompressionAlgorithm::Lz4),
+            "zstd" => Ok(CompressionAlgorithm::Zstd),
+            "stored" => Ok(CompressionAlgorithm::Stored),
+            "gzip" => Ok(CompressionAlgorithm::Gzip),
+            "snappy" => Ok(CompressionAlgorithm::Snappy),
+            other => Err(ShrLinkError::InvalidInput(format!(
+                "Unknown compression algorithm '{}' (expected lz4, zstd, gzip, snappy or stored)",
+                other
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for CompressionAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompressionAlgorithm::Lz4 => f.write_str("lz4"),
+            CompressionAlgorithm::Zstd => f.write_str("zstd"),
+            CompressionAlgorithm::Stored => f.write_str("stored"),
+            CompressionAlgorithm::Zero => f.write_str("zero"),
+            CompressionAlgorithm::Gzip => f.write_str("gzip"),
+            CompressionAlgorithm::Snappy => f.write_str("snappy"),
+            CompressionAlgorithm::Unknown(id) => write!(f, "codec {}", id),
+        }
+    }
+}
+
+// What each chunk's `hash` is. XXH3 is several times cheaper but only catches accidents, not
+// tampering, so it's for links where both ends are trusted; its 128 bits fill the first half
+// of the hash and the rest is zero
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChecksumAlgorithm {
+    #[default]
+    Blake3,
+    Xxh3,
+}
+
+impl ChecksumAlgorithm {
+    pub fn checksum(&self, data: &[u8]) -> [u8; 32] {
+        match self {
+            ChecksumAlgorithm::Blake3 => blake3::hash(data).into(),
+            ChecksumAlgorithm::Xxh3 => {
+                let mut hash = [0u8; 32];
+                hash[..16].copy_from_slice(&twox_hash::XxHash3_128::oneshot(data).to_le_bytes());
+                hash
+            }
+        }
+    }
+}
+
+impl FromStr for ChecksumAlgorithm {
+    type Err = ShrLinkError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "blake3" => Ok(ChecksumAlgorithm::Blake3),
+            "xxh3" => Ok(ChecksumAlgorithm::Xxh3),
+            other => Err(ShrLinkError::InvalidInput(format!(
+                "Unknown checksum algorithm '{}' (expected blake3 or xxh3)",
+                other
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for ChecksumAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChecksumAlgorithm::Blake3 => f.write_str("blake3"),
+            ChecksumAlgorithm::Xxh3 => f.write_str("xxh3"),
+        }
+    }
+}
+
+// "auto" picks a size from each input's length: a power of two that gives 128 to 256 chunks,
+// within AUTO_MIN_BLOCK_SIZE and AUTO_MAX_BLOCK_SIZE. Inputs whose length isn't known up front
+// use BLOCK_SIZE
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockSize {
+    Fixed(usize),
+    Auto,
+}
+
+impl Default for BlockSize {
+    fn default() -> Self {
+        BlockSize::Fixed(BLOCK_SIZE)
+    }
+}
+
+impl serde::Serialize for BlockSize {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match self {
+            BlockSize::Fixed(size) => serializer.serialize_u64(*size as u64),
+            BlockSize::Auto => serializer.serialize_str("auto"),
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for BlockSize {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        use serde::de::Error;
+
+        #[derive(serde::Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Bytes(u64),
+            Name(String),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Bytes(size) => usize::try_from(size).map(BlockSize::Fixed).map_err(D::Error::custom),
+            Raw::Name(name) if name.trim().eq_ignore_ascii_case("auto") => Ok(BlockSize::Auto),
+            Raw::Name(name) => Err(D::Error::custom(format!("expected a size in bytes or \"auto\", not '{}'", name))),
+        }
+    }
+}
+
+pub fn auto_block_size(len: u64) -> usize {
+    let size = len.div_ceil(AUTO_TARGET_CHUNKS).next_power_of_two();
+    size.clamp(AUTO_MIN_BLOCK_SIZE as u64, AUTO_MAX_BLOCK_SIZE as u64) as usize
+}
+
+// How input is cut into chunks. Fixed blocks are cheapest; content-defined boundaries move with
+// the data, so an insertion only changes the chunks around it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Chunking {
+    #[default]
+    Fixed,
+    Cdc(CdcParams),
+}
 
-#[derive(Debug, Clone)]
+// `bundle::wire` is its compact binary form; serde is for everything else
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct CompressedChunk {
     pub index: usize,
     pub data: Vec<u8>,
     pub hash: [u8; 32],
     pub original_size: usize,
+    // Travels with the chunk, so the receiver decompresses it whatever its own config says
+    pub algorithm: CompressionAlgorithm,
+    // Likewise: the receiver verifies `hash` with whatever the chunk was hashed with
+    pub checksum: ChecksumAlgorithm,
 }
 
+#[cfg(all(feature = "fs", feature = "parallel"))]
+pub type ChunkReceiver = mpsc::Receiver<Result<CompressedChunk>>;
+
 #[derive(Debug)]
 pub struct CompressionResult {
     pub chunks: Vec<CompressedChunk>,
     pub total_original_size: usize,
     pub total_compressed_size: usize,
+    // BLAKE3 of the original bytes, whatever the block size
+    pub file_hash: [u8; 32],
+    // What the input was cut into, after "auto" has picked; None with CDC
+    pub block_size: Option<usize>,
+}
+
+// What compressing a whole file would probably come to, from compressing parts of it. Chunks
+// repeated within the file would be sent as references, which sampling can't see, so for files
+// with much repetition the real bundle can be smaller still
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompressionEstimate {
+    pub original_size: u64,
+    pub sampled_bytes: u64,
+    pub sampled_compressed_bytes: u64,
+    // Compressed over original, for the samples and so for the whole file
+    pub ratio: f64,
+    pub estimated_size: u64,
+    pub chunk_count: usize,
+    // The file fitted in the sample budget, so all of it was compressed
+    pub exact: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReconstructStats {
+    pub bytes_written: u64,
+    // Of those, the zeros left as holes rather than written out, when the writer can seek
+    pub bytes_skipped: u64,
+    // Most bytes of chunks being decompressed or waiting to be written at once; over the memory
+    // budget only for a chunk bigger than the whole of it, which goes through on its own
+    pub peak_buffered_bytes: usize,
+    // Most chunks queued or being decompressed at once; never more than the worker count
+    pub peak_inflight_chunks: usize,
+    // BLAKE3 of everything written, to check against the sender's whole-file hash
+    pub file_hash: [u8; 32],
+}
+
+// Running totals over everything a compressor and its clones have compressed since
+// `with_progress`. The total grows by the expected chunk count as each input starts, which with
+// CDC is only an estimate, so it never reads less than what is already done
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompressionProgress {
+    pub chunks_done: usize,
+    pub chunks_total: usize,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+// Chunks finish on whichever worker got them, so the observer runs under the lock: calls never
+// overlap and `chunks_done` only ever goes up from one call to the next
+#[derive(Clone)]
+struct ProgressHook {
+    state: std::sync::Arc<std::sync::Mutex<CompressionProgress>>,
+    observer: std::sync::Arc<dyn Fn(CompressionProgress) + Send + Sync>,
 }
 
+impl ProgressHook {
+    fn update(&self, change: impl FnOnce(&mut CompressionProgress)) {
+        let mut state = self.state.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+        change(&mut state);
+        state.chunks_total = state.chunks_total.max(state.chunks_done);
+        (self.observer)(*state);
+    }
+}
+
+#[cfg(feature = "parallel")]
+type SharedPool = Arc<std::sync::OnceLock<Arc<rayon::ThreadPool>>>;
+
+#[derive(Clone)]
 pub struct ParallelCompressor {
+    // With `auto_block_size`, only for inputs whose length isn't known up front
     block_size: usize,
+    auto_block_size: bool,
+    chunking: Chunking,
     acceleration: i32,
+    algorithm: CompressionAlgorithm,
+    checksum: ChecksumAlgorithm,
+    min_savings_percent: f64,
     num_workers: usize,
+    max_inflight_bytes: usize,
+    dictionary: Option<Dictionary>,
+    progress: Option<ProgressHook>,
+    // Checked before every block is read and every chunk compressed, so a cancelled compression
+    // stops within about a block per worker
+    #[cfg(not(target_arch = "wasm32"))]
+    cancel: CancellationToken,
+    // Built on first use and shared with every clone, so compressing many small files doesn't
+    // start and join a set of threads each time
+    #[cfg(feature = "parallel")]
+    pool: SharedPool,
+}
+
+#[cfg(feature = "parallel")]
+pub fn default_workers() -> usize {
+    num_cpus::get()
+}
+
+#[cfg(not(feature = "parallel"))]
+pub fn default_workers() -> usize {
+    1
 }
 
 impl Default for ParallelCompressor {
     fn default() -> Self {
         Self {
             block_size: BLOCK_SIZE,
+            auto_block_size: false,
+            chunking: Chunking::Fixed,
             acceleration: LZ4_ACCELERATION,
-            num_workers: num_cpus::get(),
+            algorithm: CompressionAlgorithm::Lz4,
+            checksum: ChecksumAlgorithm::Blake3,
+            min_savings_percent: DEFAULT_MIN_SAVINGS_PERCENT,
+            num_workers: default_workers(),
+            max_inflight_bytes: DEFAULT_MAX_INFLIGHT_DECOMPRESSED_BYTES,
+            dictionary: None,
+            progress: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            cancel: CancellationToken::new(),
+            #[cfg(feature = "parallel")]
+            pool: SharedPool::default(),
         }
     }
 }
 
 impl ParallelCompressor {
-    pub fn new(block_size: usize, acceleration: i32) -> Self {
-        Self {
+    pub fn new(block_size: usize, acceleration: i32) -> Result<Self> {
+        if block_size == 0 || block_size > MAX_BLOCK_SIZE {
+            return Err(ShrLinkError::InvalidInput(format!(
+                "Block size must be between 1 and {} bytes (got {})",
+                MAX_BLOCK_SIZE, block_size
+            )));
+        }
+
+        Ok(Self {
             block_size,
+            auto_block_size: false,
+            chunking: Chunking::Fixed,
             acceleration,
-            num_workers: num_cpus::get(),
+            algorithm: CompressionAlgorithm::Lz4,
+            checksum: ChecksumAlgorithm::Blake3,
+            min_savings_percent: DEFAULT_MIN_SAVINGS_PERCENT,
+            num_workers: default_workers(),
+            max_inflight_bytes: DEFAULT_MAX_INFLIGHT_DECOMPRESSED_BYTES,
+            dictionary: None,
+            progress: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            cancel: CancellationToken::new(),
+            #[cfg(feature = "parallel")]
+            pool: SharedPool::default(),
+        })
+    }
+
+    pub fn with_block_size(block_size: BlockSize, acceleration: i32) -> Result<Self> {
+        match block_size {
+            BlockSize::Fixed(size) => Self::new(size, acceleration),
+            BlockSize::Auto => Ok(Self { auto_block_size: true, ..Self::new(BLOCK_SIZE, acceleration)? }),
         }
     }
 
+    // With CDC the block size no longer applies; chunks fall between the CDC minimum and maximum
+    pub fn with_chunking(mut self, chunking: Chunking) -> Self {
+        self.chunking = chunking;
+        self
+    }
+
+    pub fn with_algorithm(mut self, algorithm: CompressionAlgorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    pub fn with_checksum(mut self, checksum: ChecksumAlgorithm) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    // 0 stores only chunks that didn't shrink at all; 100 or more stores everything
+    pub fn with_min_savings(mut self, percent: f64) -> Self {
+        self.min_savings_percent = percent.max(0.0);
+        self
+    }
+
+    // zstd chunks are compressed against it. Receiving, it must be the dictionary of the bundle
+    // the chunks came from
+    pub fn with_dictionary(mut self, dictionary: Dictionary) -> Self {
+        self.dictionary = Some(dictionary);
+        self
+    }
+
+    pub fn with_memory_budget(mut self, max_inflight_bytes: usize) -> Self {
+        self.max_inflight_bytes = max_inflight_bytes.max(1);
+        self
+    }
+
+    // A different count means a new pool, built on first use; the same count keeps the old one
     pub fn with_workers(mut self, num_workers: usize) -> Self {
-        self.num_workers = num_workers.max(1);
+        let num_workers = num_workers.max(1);
+        if num_workers != self.num_workers {
+            self.num_workers = num_workers;
+            #[cfg(feature = "parallel")]
+            {
+                self.pool = SharedPool::default();
+            }
+        }
+        self
+    }
+
+    // Runs on a pool shared with the rest of the application; the worker count follows its size
+    #[cfg(feature = "parallel")]
+    pub fn with_thread_pool(mut self, pool: Arc<rayon::ThreadPool>) -> Self {
+        self.num_workers = pool.current_num_threads();
+        self.pool = Arc::new(std::sync::OnceLock::from(pool));
+        self
+    }
+
+    // Called once per compressed chunk, from whichever thread compressed it; a chunk that
+    // fails isn't reported
+    pub fn with_progress<F: Fn(CompressionProgress) + Send + Sync + 'static>(mut self, observer: F) -> Self {
+        self.progress = Some(ProgressHook {
+            state: Default::default(),
+            observer: std::sync::Arc::new(observer),
+        });
+        self
+    }
+
+    // Compression then fails with `ShrLinkError::Cancelled` once `cancel` fires
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
         self
     }
 
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    // What fixed chunking cuts an input of `len` bytes into
+    pub fn block_size_for(&self, len: u64) -> usize {
+        if self.auto_block_size {
+            auto_block_size(len)
+        } else {
+            self.block_size
+        }
+    }
+
+    // This compressor with the block size for an input of `len` bytes settled, for when the
+    // length is known before the input is read. Progress and the pool are still shared
+    pub fn sized_for(&self, len: u64) -> Self {
+        let mut sized = self.clone();
+        sized.block_size = self.block_size_for(len);
+        sized.auto_block_size = false;
+        sized
+    }
+
+    // The block size every chunk but the last is cut to, once `sized_for` has settled it; None
+    // with CDC
+    pub fn fixed_block_size(&self) -> Option<usize> {
+        match self.chunking {
+            Chunking::Fixed => Some(self.block_size),
+            Chunking::Cdc(_) => None,
+        }
+    }
+
+    pub fn chunking(&self) -> Chunking {
+        self.chunking
+    }
+
+    pub fn algorithm(&self) -> CompressionAlgorithm {
+        self.algorithm
+    }
+
+    pub fn checksum(&self) -> ChecksumAlgorithm {
+        self.checksum
+    }
+
+    #[cfg(feature = "fs")]
     pub fn compress_file<P: AsRef<Path>>(&self, path: P) -> Result<CompressionResult> {
         let file = File::open(path)?;
         let file_size = file.metadata()?.len() as usize;
+        let compressor = self.sized_for(file_size as u64);
+        compressor.expect(compressor.chunk_count(file_size as u64));
         
-        let chunks = self.read_file_chunks(file)?;
-        let compressed_chunks = self.compress_chunks_parallel(chunks)?;
+        let (chunks, file_hash) = compressor.read_file_chunks(file)?;
+        let compressed_chunks = compressor.compress_chunks_parallel(chunks)?;
         
         let total_compressed_size = compressed_chunks.iter()
             .map(|c| c.data.len())
@@ -70,12 +541,92 @@ impl ParallelCompressor {
             chunks: compressed_chunks,
             total_original_size: file_size,
             total_compressed_size,
+            file_hash,
+            block_size: compressor.fixed_block_size(),
+        })
+    }
+
+    // Compresses about `sample_bytes` of the file, in blocks spread evenly from its start to its
+    // end, or all of it if it's no bigger than that
+    #[cfg(feature = "fs")]
+    pub fn estimate_ratio<P: AsRef<Path>>(&self, path: P, sample_bytes: u64) -> Result<CompressionEstimate> {
+        use std::io::{Seek, SeekFrom};
+
+        let mut file = File::open(path.as_ref())?;
+        let original_size = file.metadata()?.len();
+        let compressor = self.sized_for(original_size);
+        let chunk_count = compressor.chunk_count(original_size);
+
+        let (sampled_bytes, sampled_compressed_bytes, exact) = if original_size <= sample_bytes {
+            let result = compressor.compress_file(path)?;
+            (original_size, result.total_compressed_size as u64, true)
+        } else {
+            let block = match &compressor.chunking {
+                Chunking::Fixed => compressor.block_size,
+                Chunking::Cdc(params) => params.avg_size(),
+            } as u64;
+            let sample_len = (sample_bytes / ESTIMATE_SAMPLES).clamp(1, block);
+            let count = (sample_bytes / sample_len).clamp(1, ESTIMATE_SAMPLES);
+
+            let mut samples = Vec::new();
+            for i in 0..count {
+                let offset = (original_size - sample_len) * i / (count - 1).max(1);
+                file.seek(SeekFrom::Start(offset))?;
+                let mut sample = vec![0; sample_len as usize];
+                file.read_exact(&mut sample)?;
+                samples.push(sample);
+            }
+            let chunks = compressor.compress_chunks_parallel(samples)?;
+            let compressed = chunks.iter().map(|c| c.data.len() as u64).sum();
+            (sample_len * count, compressed, false)
+        };
+
+        let ratio = if sampled_bytes == 0 { 1.0 } else { sampled_compressed_bytes as f64 / sampled_bytes as f64 };
+        Ok(CompressionEstimate {
+            original_size,
+            sampled_bytes,
+            sampled_compressed_bytes,
+            ratio,
+            estimated_size: if exact { sampled_compressed_bytes } else { (original_size as f64 * ratio).round() as u64 },
+            chunk_count,
+            exact,
+        })
+    }
+
+    // Single-threaded and free of I/O, so it works the same in a browser; the output is
+    // identical to the parallel paths for the same input and block size
+    pub fn compress_bytes(&self, data: &[u8]) -> Result<CompressionResult> {
+        let compressor = self.sized_for(data.len() as u64);
+        let mut blocks = Vec::new();
+        let mut rest = data;
+        while !rest.is_empty() {
+            let (block, tail) = rest.split_at(compressor.block_len(rest));
+            blocks.push(block);
+            rest = tail;
+        }
+        compressor.expect(blocks.len());
+        
+        let chunks = blocks
+            .into_iter()
+            .enumerate()
+            .map(|(index, block)| compressor.compress_chunk(index, block.to_vec()))
+            .collect::<Result<Vec<_>>>()?;
+        let total_compressed_size = chunks.iter().map(|c| c.data.len()).sum();
+        
+        Ok(CompressionResult {
+            chunks,
+            total_original_size: data.len(),
+            total_compressed_size,
+            file_hash: blake3::hash(data).into(),
+            block_size: compressor.fixed_block_size(),
         })
     }
 
+    #[cfg(not(target_arch = "wasm32"))]
     pub async fn compress_async_reader<R: AsyncRead + Unpin>(&self, reader: &mut R) -> Result<CompressionResult> {
-        let chunks = self.read_async_chunks(reader).await?;
+        let (chunks, file_hash) = self.read_async_chunks(reader).await?;
         let total_original_size = chunks.iter().map(|c| c.len()).sum();
+        self.expect(chunks.len());
         
         let compressed_chunks = self.compress_chunks_parallel(chunks)?;
         let total_compressed_size = compressed_chunks.iter()
@@ -86,54 +637,202 @@ impl ParallelCompressor {
             chunks: compressed_chunks,
             total_original_size,
             total_compressed_size,
+            file_hash,
+            block_size: self.fixed_block_size(),
         })
     }
 
-    fn read_file_chunks(&self, mut file: File) -> Result<Vec<Vec<u8>>> {
+    // The hash resolves once every chunk has been handed over, and fails if reading stopped early
+    #[cfg(all(feature = "fs", feature = "parallel"))]
+    pub fn compress_stream<P: AsRef<Path>>(&self, path: P) -> Result<(impl Stream<Item = Result<CompressedChunk>> + Send + Unpin + 'static, impl Future<Output = Result<[u8; 32]>> + Send + Unpin + 'static)> {
+        let (mut rx, file_hash) = self.compress_file_stream(path)?;
+        let file_hash = file_hash.map(|hash| {
+            hash.map_err(|_| ShrLinkError::Compression("stopped before the whole file was read".to_string()))
+        });
+        Ok((futures::stream::poll_fn(move |cx| rx.poll_recv(cx)), file_hash))
+    }
+
+    #[cfg(all(feature = "fs", feature = "parallel"))]
+    pub fn compress_file_stream<P: AsRef<Path>>(&self, path: P) -> Result<(ChunkReceiver, oneshot::Receiver<[u8; 32]>)> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        let compressor = self.sized_for(len);
+        compressor.expect(compressor.chunk_count(len));
+        Ok(compressor.compress_reader_stream(file))
+    }
+
+    #[cfg(all(feature = "fs", feature = "parallel"))]
+    // Chunks are read, compressed `num_workers` at a time and handed over in index order through a
+    // channel of the same size, so at most `num_workers * 2` blocks are in memory at once: one
+    // window being compressed and one waiting for the consumer. Blocks are hashed as they are
+    // read, and the whole-file hash is sent after the last chunk
+    pub fn compress_reader_stream<R: Read + Send + 'static>(&self, mut file: R) -> (ChunkReceiver, oneshot::Receiver<[u8; 32]>) {
+        let (tx, rx) = mpsc::channel(self.num_workers);
+        let (hash_tx, hash_rx) = oneshot::channel();
+        let compressor = self.clone();
+        
+        std::thread::spawn(move || {
+            let mut hasher = blake3::Hasher::new();
+            let mut blocks = BlockReader::default();
+            let pool = match compressor.thread_pool() {
+                Ok(pool) => pool,
+                Err(e) => {
+                    let _ = tx.blocking_send(Err(e));
+                    return;
+                }
+            };
+            
+            let mut next_index = 0;
+            loop {
+                let mut window = Vec::with_capacity(compressor.num_workers);
+                let mut eof = false;
+                while window.len() < compressor.num_workers {
+                    if let Err(e) = compressor.check_cancelled() {
+                        let _ = tx.blocking_send(Err(e));
+                        return;
+                    }
+                    match blocks.next_block(&compressor, &mut file) {
+                        Ok(block) if block.is_empty() => {
+                            eof = true;
+                            break;
+                        }
+                        Ok(block) => {
+                            hasher.update(&block);
+                            window.push((next_index, block));
+                            next_index += 1;
+                        }
+                        Err(e) => {
+                            let _ = tx.blocking_send(Err(e.into()));
+                            return;
+                        }
+                    }
+                }
+                
+                let compressed: Vec<Result<CompressedChunk>> = pool.install(|| {
+                    window
+                        .into_par_iter()
+                        .map(|(index, block)| compressor.compress_chunk(index, block))
+                        .collect()
+                });
+                
+                for chunk in compressed {
+                    // The receiver hung up, nobody wants the rest
+                    if tx.blocking_send(chunk).is_err() {
+                        return;
+                    }
+                }
+                
+                if eof {
+                    let _ = hash_tx.send(hasher.finalize().into());
+                    return;
+                }
+            }
+        });
+        
+        (rx, hash_rx)
+    }
+
+    fn check_cancelled(&self) -> Result<()> {
+        #[cfg(not(target_arch = "wasm32"))]
+        if self.cancel.is_cancelled() {
+            return Err(ShrLinkError::Cancelled);
+        }
+        Ok(())
+    }
+
+    fn expect(&self, chunks: usize) {
+        if let Some(progress) = &self.progress {
+            progress.update(|state| state.chunks_total += chunks);
+        }
+    }
+
+    // Exact for fixed blocks; with CDC only the data decides, so this is the expected count
+    pub fn chunk_count(&self, total_size: u64) -> usize {
+        let size = match &self.chunking {
+            Chunking::Fixed => self.block_size_for(total_size),
+            Chunking::Cdc(params) => params.avg_size(),
+        };
+        total_size.div_ceil(size as u64) as usize
+    }
+
+    // Where the block at the start of `data` ends. `data` must hold `read_ahead()` bytes unless
+    // the input ends within it
+    fn block_len(&self, data: &[u8]) -> usize {
+        match &self.chunking {
+            Chunking::Fixed => data.len().min(self.block_size),
+            Chunking::Cdc(params) => params.cut(data),
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn read_ahead(&self) -> usize {
+        match &self.chunking {
+            Chunking::Fixed => self.block_size,
+            Chunking::Cdc(params) => params.max_size(),
+        }
+    }
+
+    // Only an empty read ends the input; a short one just means the reader had less to hand
+    // over right then (pipes, network filesystems, signals)
+    #[cfg(feature = "fs")]
+    fn read_file_chunks<R: Read>(&self, mut reader: R) -> Result<(Vec<Vec<u8>>, [u8; 32])> {
         let mut chunks = Vec::new();
-        let mut buffer = vec![0u8; self.block_size];
+        let mut hasher = blake3::Hasher::new();
+        let mut blocks = BlockReader::default();
         
         loop {
-            let bytes_read = file.read(&mut buffer)?;
-            if bytes_read == 0 {
+            self.check_cancelled()?;
+            let block = blocks.next_block(self, &mut reader)?;
+            if block.is_empty() {
                 break;
             }
             
-            chunks.push(buffer[..bytes_read].to_vec());
-            
-            if bytes_read < self.block_size {
-                break;
-            }
+            hasher.update(&block);
+            chunks.push(block);
         }
         
-        Ok(chunks)
+        Ok((chunks, hasher.finalize().into()))
     }
 
-    async fn read_async_chunks<R: AsyncRead + Unpin>(&self, reader: &mut R) -> Result<Vec<Vec<u8>>> {
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn read_async_chunks<R: AsyncRead + Unpin>(&self, reader: &mut R) -> Result<(Vec<Vec<u8>>, [u8; 32])> {
         let mut chunks = Vec::new();
-        let mut buffer = vec![0u8; self.block_size];
+        let mut hasher = blake3::Hasher::new();
+        let mut blocks = BlockReader::default();
         
         loop {
-            let bytes_read = reader.read(&mut buffer).await?;
-            if bytes_read == 0 {
+            // The reader may be waiting on a pipe or socket that never delivers
+            let block = tokio::select! {
+                block = blocks.next_block_async(self, reader) => block?,
+                _ = self.cancel.cancelled() => return Err(ShrLinkError::Cancelled),
+            };
+            if block.is_empty() {
                 break;
             }
             
-            chunks.push(buffer[..bytes_read].to_vec());
-            
-            if bytes_read < self.block_size {
-                break;
-            }
+            hasher.update(&block);
+            chunks.push(block);
         }
         
-        Ok(chunks)
+        Ok((chunks, hasher.finalize().into()))
     }
 
-    fn compress_chunks_parallel(&self, chunks: Vec<Vec<u8>>) -> Result<Vec<CompressedChunk>> {
+    #[cfg(feature = "parallel")]
+    fn thread_pool(&self) -> Result<Arc<rayon::ThreadPool>> {
+        if let Some(pool) = self.pool.get() {
+            return Ok(pool.clone());
+        }
         let pool = rayon::ThreadPoolBuilder::new()
             .num_threads(self.num_workers)
             .build()
             .map_err(|e| ShrLinkError::Compression(e.to_string()))?;
+        // Two callers racing here both build one, but only the first is kept
+        Ok(self.pool.get_or_init(|| Arc::new(pool)).clone())
+    }
+
+    #[cfg(feature = "parallel")]
+    fn compress_chunks_parallel(&self, chunks: Vec<Vec<u8>>) -> Result<Vec<CompressedChunk>> {
+        let pool = self.thread_pool()?;
 
         let results: Result<Vec<_>> = pool.install(|| {
             chunks
@@ -146,138 +845,675 @@ impl ParallelCompressor {
         results
     }
 
+    #[cfg(all(not(feature = "parallel"), not(target_arch = "wasm32")))]
+    fn compress_chunks_parallel(&self, chunks: Vec<Vec<u8>>) -> Result<Vec<CompressedChunk>> {
+        chunks
+            .into_iter()
+            .enumerate()
+            .map(|(index, chunk)| self.compress_chunk(index, chunk))
+            .collect()
+    }
+
+    // Output is in the same order as `chunks`, and every chunk's hash is checked
+    #[cfg(feature = "parallel")]
+    pub fn decompress_chunks_parallel(&self, chunks: &[CompressedChunk]) -> Result<Vec<Vec<u8>>> {
+        let pool = self.thread_pool()?;
+
+        pool.install(|| {
+            chunks
+                .par_iter()
+                .map(|chunk| self.decompress_chunk(chunk))
+                .collect()
+        })
+    }
+
+    #[cfg(not(feature = "parallel"))]
+    pub fn decompress_chunks_parallel(&self, chunks: &[CompressedChunk]) -> Result<Vec<Vec<u8>>> {
+        chunks.iter().map(|chunk| self.decompress_chunk(chunk)).collect()
+    }
+
     pub fn compress_chunk(&self, index: usize, chunk: Vec<u8>) -> Result<CompressedChunk> {
+        self.check_cancelled()?;
         let original_size = chunk.len();
+        if matches!(self.algorithm, CompressionAlgorithm::Lz4 | CompressionAlgorithm::Snappy) && original_size > MAX_BLOCK_SIZE {
+            return Err(ShrLinkError::InvalidInput(format!(
+                "Chunk {} is {} bytes, more than {} can record ({})",
+                index, original_size, self.algorithm, MAX_BLOCK_SIZE
+            )));
+        }
+        if let CompressionAlgorithm::Unknown(id) = self.algorithm {
+            return Err(ShrLinkError::InvalidInput(format!("Can't compress with unknown codec id {}", id)));
+        }
         
-        // Hash the original data
-        let mut hasher = Hasher::new();
-        hasher.update(&chunk);
-        let hash = hasher.finalize();
+        let hash = self.checksum.checksum(&chunk);
 
-        // Compress with LZ4
-        let compressed = compress_prepend_size(&chunk);
+        let (data, algorithm) = if original_size > 0 && is_zero(&chunk) {
+            (Vec::new(), CompressionAlgorithm::Zero)
+        } else {
+            let compressed = match self.algorithm {
+                CompressionAlgorithm::Lz4 => compress_prepend_size(&chunk),
+                CompressionAlgorithm::Zstd => compress_zstd(&chunk, self.dictionary.as_ref())?,
+                CompressionAlgorithm::Gzip => gzip::compress(&chunk),
+                CompressionAlgorithm::Snappy => snappy::compress(&chunk)?,
+                CompressionAlgorithm::Stored | CompressionAlgorithm::Zero | CompressionAlgorithm::Unknown(_) => Vec::new(),
+            };
+            
+            let worth_it = original_size as f64 * (1.0 - self.min_savings_percent / 100.0);
+            if matches!(self.algorithm, CompressionAlgorithm::Stored | CompressionAlgorithm::Zero) || compressed.len() as f64 > worth_it {
+                (chunk, CompressionAlgorithm::Stored)
+            } else {
+                (compressed, self.algorithm)
+            }
+        };
+        
+        if let Some(progress) = &self.progress {
+            progress.update(|state| {
+                state.chunks_done += 1;
+                state.bytes_in += original_size as u64;
+                state.bytes_out += data.len() as u64;
+            });
+        }
         
         Ok(CompressedChunk {
             index,
-            data: compressed,
-            hash: hash.into(),
+            data,
+            hash,
             original_size,
+            algorithm,
+            checksum: self.checksum,
         })
     }
 
+    // Uses the chunk's own algorithm and checksum, not the ones this compressor was configured with
     pub fn decompress_chunk(&self, chunk: &CompressedChunk) -> Result<Vec<u8>> {
-        use lz4_flex::decompress_size_prepended;
-        
-        let decompressed = decompress_size_prepended(&chunk.data)
-            .map_err(|e| ShrLinkError::Compression(e.to_string()))?;
-        
-        // Verify hash
-        let mut hasher = Hasher::new();
-        hasher.update(&decompressed);
-        let hash = hasher.finalize();
+        let mut decompressed = Vec::new();
+        self.decompress_chunk_into(chunk, &mut decompressed)?;
+        Ok(decompressed)
+    }
+    
+    // Replaces what `out` holds with the chunk's bytes, reusing its allocation and only growing it
+    // when the chunk is bigger than anything it held before. Returns the decompressed length;
+    // after an error `out` holds nothing worth reading
+    pub fn decompress_chunk_into(&self, chunk: &CompressedChunk, out: &mut Vec<u8>) -> Result<usize> {
+        out.clear();
+        match chunk.algorithm {
+            CompressionAlgorithm::Lz4 => decompress_lz4(chunk, out)?,
+            CompressionAlgorithm::Zstd => decompress_zstd(chunk, self.dictionary.as_ref(), out)?,
+            CompressionAlgorithm::Stored => decompress_stored(chunk, out)?,
+            CompressionAlgorithm::Zero => decompress_zero(chunk, out)?,
+            CompressionAlgorithm::Gzip => decompress_gzip(chunk, out)?,
+            CompressionAlgorithm::Snappy => decompress_snappy(chunk, out)?,
+            CompressionAlgorithm::Unknown(id) => {
+                return Err(ShrLinkError::Compression(format!(
+                    "chunk {} uses compression codec id {}, which this version doesn't know",
+                    chunk.index, id
+                )))
+            }
+        }
         
-        if hash.as_bytes() != &chunk.hash {
+        let hash = chunk.checksum.checksum(out);
+        if hash != chunk.hash {
             return Err(ShrLinkError::HashMismatch {
+                context: format!("chunk {} ({})", chunk.index, chunk.checksum),
                 expected: hex::encode(chunk.hash),
-                actual: hex::encode(hash.as_bytes()),
+                actual: hex::encode(hash),
             });
         }
         
-        Ok(decompressed)
+        Ok(out.len())
     }
-}
-
-pub fn create_shr_bundle(chunks: &[CompressedChunk]) -> Result<Vec<u8>> {
-    let mut bundle = Vec::new();
     
-    // Write header: magic + version + chunk count
-    bundle.extend_from_slice(b"SHR\x01");
-    bundle.extend_from_slice(&(chunks.len() as u32).to_le_bytes());
-    
-    // Write chunk metadata
-    for chunk in chunks {
-        bundle.extend_from_slice(&(chunk.index as u32).to_le_bytes());
-        bundle.extend_from_slice(&(chunk.original_size as u32).to_le_bytes());
-        bundle.extend_from_slice(&(chunk.data.len() as u32).to_le_bytes());
-        bundle.extend_from_slice(&chunk.hash);
+    #[cfg(feature = "fs")]
+    // Writes to a scratch file beside `output_path` and only renames it into place once every
+    // chunk, and the whole file if `file_hash` is given, has verified, so a failed receive never
+    // leaves a truncated or wrong file behind
+    pub async fn write_chunks_to_file<F: FnMut(&CompressedChunk)>(&self, chunks: &[CompressedChunk], output_path: &Path, file_hash: Option<&[u8; 32]>, on_chunk: F) -> Result<()> {
+        let guard = TempGuard::beside(output_path, ScratchKind::Temp)?;
+        let mut output_file = tokio::fs::File::create(guard.path()).await?;
+        
+        let stats = self.reconstruct_sparse(chunks, &mut output_file, on_chunk).await?;
+        drop(output_file);
+        StreamedFile { guard, stats }.commit(output_path, file_hash)
     }
     
-    // Write chunk data
-    for chunk in chunks {
-        bundle.extend_from_slice(&chunk.data);
+    #[cfg(feature = "fs")]
+    // As `write_chunks_to_file`, for chunks that are still arriving and in whatever order the
+    // bundle has them; ones that turn up ahead of their turn are held until it comes, up to the
+    // memory budget. A bundle's whole-file hash comes after its chunks, so the file stays in
+    // scratch beside `output_path` until `StreamedFile::commit` has checked it
+    pub async fn write_stream_to_file<S, F>(&self, chunks: S, output_path: &Path, on_chunk: F) -> Result<StreamedFile>
+    where
+        S: Stream<Item = Result<CompressedChunk>> + Unpin,
+        F: FnMut(&CompressedChunk),
+    {
+        let guard = TempGuard::beside(output_path, ScratchKind::Temp)?;
+        let mut output_file = tokio::fs::File::create(guard.path()).await?;
+        
+        let ordered = Box::pin(in_index_order(chunks, self.max_inflight_bytes));
+        let stats = self.reconstruct_into(ordered, SparseSink { writer: &mut output_file, hole: 0 }, on_chunk).await?;
+        drop(output_file);
+        Ok(StreamedFile { guard, stats })
     }
     
-    Ok(bundle)
-}
-
-pub fn parse_shr_bundle(bundle: &[u8]) -> Result<Vec<CompressedChunk>> {
-    if bundle.len() < 8 || &bundle[0..4] != b"SHR\x01" {
-        return Err(ShrLinkError::InvalidInput("Invalid SHR bundle format".to_string()));
+    #[cfg(not(target_arch = "wasm32"))]
+    // Decompresses chunks on the blocking pool and writes them in order. Each chunk reserves its
+    // `original_size` from a byte-weighted semaphore before it is decompressed and gives it back
+    // once written, so a lagging writer holds back decompression instead of memory piling up
+    pub async fn reconstruct<W, F>(&self, chunks: &[CompressedChunk], writer: &mut W, on_chunk: F) -> Result<ReconstructStats>
+    where
+        W: AsyncWrite + Unpin,
+        F: FnMut(&CompressedChunk),
+    {
+        self.reconstruct_into(stream::iter(chunks.iter().cloned().map(Ok)), DenseSink(writer), on_chunk).await
     }
     
-    let chunk_count = u32::from_le_bytes([bundle[4], bundle[5], bundle[6], bundle[7]]) as usize;
-    let mut chunks = Vec::with_capacity(chunk_count);
-    
-    let mut offset = 8;
-    let metadata_size = chunk_count * (4 + 4 + 4 + 32); // index + original_size + compressed_size + hash
-    
-    if bundle.len() < offset + metadata_size {
-        return Err(ShrLinkError::InvalidInput("Bundle too short for metadata".to_string()));
+    #[cfg(not(target_arch = "wasm32"))]
+    // As `reconstruct`, but zero chunks are seeked over rather than written, so a file written
+    // from the start keeps the sender's holes on filesystems that have them
+    pub async fn reconstruct_sparse<W, F>(&self, chunks: &[CompressedChunk], writer: &mut W, on_chunk: F) -> Result<ReconstructStats>
+    where
+        W: AsyncWrite + AsyncSeek + Unpin,
+        F: FnMut(&CompressedChunk),
+    {
+        self.reconstruct_into(stream::iter(chunks.iter().cloned().map(Ok)), SparseSink { writer, hole: 0 }, on_chunk).await
     }
     
-    // Parse metadata
-    let mut chunk_infos = Vec::with_capacity(chunk_count);
-    for _ in 0..chunk_count {
-        let index = u32::from_le_bytes([bundle[offset], bundle[offset + 1], bundle[offset + 2], bundle[offset + 3]]) as usize;
-        let original_size = u32::from_le_bytes([bundle[offset + 4], bundle[offset + 5], bundle[offset + 6], bundle[offset + 7]]) as usize;
-        let compressed_size = u32::from_le_bytes([bundle[offset + 8], bundle[offset + 9], bundle[offset + 10], bundle[offset + 11]]) as usize;
-        let mut hash = [0u8; 32];
-        hash.copy_from_slice(&bundle[offset + 12..offset + 44]);
+    #[cfg(not(target_arch = "wasm32"))]
+    // Only waits on `chunks` when nothing is left to decompress or write, so chunks still
+    // arriving over the network don't hold up the ones already here
+    async fn reconstruct_into<C, S, F>(&self, chunks: C, mut sink: S, mut on_chunk: F) -> Result<ReconstructStats>
+    where
+        C: Stream<Item = Result<CompressedChunk>> + Unpin,
+        S: ChunkSink,
+        F: FnMut(&CompressedChunk),
+    {
+        #[cfg(feature = "parallel")]
+        let pool = self.thread_pool()?;
+        #[cfg(not(feature = "parallel"))]
+        let pool = ();
+        let budget = self.max_inflight_bytes.min(u32::MAX as usize);
+        let semaphore = Arc::new(Semaphore::new(budget));
+        let mut stats = ReconstructStats::default();
+        let mut hasher = blake3::Hasher::new();
+        let mut pending = chunks.peekable();
+        let mut inflight = VecDeque::new();
+        // What the chunks in `inflight` come to decompressed, whatever the permits were clamped to
+        let mut buffered = 0;
+        // Written buffers come back here for the next chunk, so there are never more of them
+        // than chunks that were in flight at once
+        let mut spare: Vec<Vec<u8>> = Vec::new();
         
-        chunk_infos.push((index, original_size, compressed_size, hash));
-        offset += 44;
-    }
-    
-    // Parse chunk data
-    for (index, original_size, compressed_size, hash) in chunk_infos {
-        if offset + compressed_size > bundle.len() {
-            return Err(ShrLinkError::InvalidInput("Bundle too short for chunk data".to_string()));
+        loop {
+            while inflight.len() < self.num_workers {
+                let next = if inflight.is_empty() {
+                    Pin::new(&mut pending).peek().await
+                } else {
+                    match Pin::new(&mut pending).peek().now_or_never() {
+                        Some(next) => next,
+                        None => break,
+                    }
+                };
+                // A chunk bigger than the whole budget still goes through, just on its own
+                let weight = match next {
+                    None => break,
+                    Some(Ok(chunk)) => chunk.original_size.clamp(1, budget) as u32,
+                    Some(Err(_)) => match pending.next().await {
+                        Some(Err(e)) => return Err(e),
+                        _ => unreachable!("peeked an error"),
+                    },
+                };
+                
+                let permit = if inflight.is_empty() {
+                    semaphore.clone().acquire_many_owned(weight).await
+                        .map_err(|e| ShrLinkError::Other(e.into()))?
+                } else {
+                    match semaphore.clone().try_acquire_many_owned(weight) {
+                        Ok(permit) => permit,
+                        Err(_) => break,
+                    }
+                };
+                let chunk = pending.next().await.expect("peeked chunk")?;
+                buffered += chunk.original_size;
+                stats.peak_buffered_bytes = stats.peak_buffered_bytes.max(buffered);
+                let buffer = spare.pop().unwrap_or_default();
+                let task = spawn_decompress(&pool, self.clone(), chunk.clone(), buffer);
+                inflight.push_back((chunk, task, permit));
+                stats.peak_inflight_chunks = stats.peak_inflight_chunks.max(inflight.len());
+            }
+            
+            let Some((chunk, task, permit)) = inflight.pop_front() else {
+                break;
+            };
+            
+            // Later chunks keep decompressing on the pool while this one is written
+            let decompressed = task.await.map_err(|e| ShrLinkError::Other(e.into()))??;
+            if chunk.algorithm == CompressionAlgorithm::Zero {
+                stats.bytes_skipped += sink.skip(decompressed.len() as u64).await?;
+            } else {
+                sink.write(&decompressed).await?;
+            }
+            hasher.update(&decompressed);
+            stats.bytes_written += decompressed.len() as u64;
+            buffered -= chunk.original_size;
+            spare.push(decompressed);
+            drop(permit);
+            on_chunk(&chunk);
         }
         
-        let data = bundle[offset..offset + compressed_size].to_vec();
-        
-        chunks.push(CompressedChunk {
-            index,
-            data,
-            hash,
-            original_size,
-        });
-        
-        offset += compressed_size;
+        sink.finish().await?;
+        stats.file_hash = hasher.finalize().into();
+        Ok(stats)
     }
-    
-    // Sort chunks by index
-    chunks.sort_by_key(|c| c.index);
-    
-    Ok(chunks)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::io::Cursor;
-    
-    #[test]
-    fn test_compression_roundtrip() {
-        let compressor = ParallelCompressor::default();
-        let test_data = b"Hello, world! This is a test compression string.".repeat(1000);
-        
-        let chunk = compressor.compress_chunk(0, test_data.clone()).unwrap();
-        let decompressed = compressor.decompress_chunk(&chunk).unwrap();
-        
-        assert_eq!(test_data, decompressed);
+// A file written from chunks as they arrived, still under its scratch name; dropped, it's removed
+#[cfg(feature = "fs")]
+pub struct StreamedFile {
+    guard: TempGuard,
+    pub stats: ReconstructStats,
+}
+
+#[cfg(feature = "fs")]
+impl StreamedFile {
+    pub fn commit(self, target: &Path, file_hash: Option<&[u8; 32]>) -> Result<()> {
+        if let Some(expected) = file_hash.filter(|h| **h != self.stats.file_hash) {
+            return Err(ShrLinkError::HashMismatch {
+                context: format!("{} ({} bytes reassembled)", target.display(), self.stats.bytes_written),
+                expected: hex::encode(expected),
+                actual: hex::encode(self.stats.file_hash),
+            });
+        }
+        tracing::debug!("Reconstructed {} bytes, peak {} bytes buffered", self.stats.bytes_written, self.stats.peak_buffered_bytes);
+        self.guard.commit(target)
     }
-    
+}
+
+// `chunks` by index from 0. One that arrives ahead of its turn is held until the chunks before
+// it have gone, as long as what's held stays within `max_held_bytes`; a gap in the indices is
+// only passed over once the stream has ended, the way sorting them would
+#[cfg(feature = "fs")]
+fn in_index_order<S>(chunks: S, max_held_bytes: usize) -> impl Stream<Item = Result<CompressedChunk>>
+where
+    S: Stream<Item = Result<CompressedChunk>> + Unpin,
+{
+    struct Reorder<S> {
+        chunks: Option<S>,
+        held: BTreeMap<usize, CompressedChunk>,
+        held_bytes: usize,
+        next: usize,
+    }
+    
+    let state = Reorder { chunks: Some(chunks), held: BTreeMap::new(), held_bytes: 0, next: 0 };
+    stream::unfold(state, move |mut state| async move {
+        loop {
+            if let Some(chunk) = state.held.remove(&state.next) {
+                state.held_bytes -= chunk.data.len();
+                state.next += 1;
+                return Some((Ok(chunk), state));
+            }
+            let Some(chunks) = state.chunks.as_mut() else {
+                let (_, chunk) = state.held.pop_first()?;
+                state.held_bytes -= chunk.data.len();
+                state.next = chunk.index + 1;
+                return Some((Ok(chunk), state));
+            };
+            
+            let failed = match chunks.next().await {
+                None => {
+                    state.chunks = None;
+                    continue;
+                }
+                Some(Ok(chunk)) if chunk.index == state.next => {
+                    state.next += 1;
+                    return Some((Ok(chunk), state));
+                }
+                Some(Ok(chunk)) if chunk.index < state.next || state.held.contains_key(&chunk.index) => {
+                    ShrLinkError::InvalidInput(format!("Bundle holds chunk {} more than once", chunk.index))
+                }
+                Some(Ok(chunk)) if state.held_bytes + chunk.data.len() > max_held_bytes => {
+                    ShrLinkError::InvalidInput(format!(
+                        "Chunk {} arrived too far ahead of chunk {} to be held until its turn",
+                        chunk.index, state.next
+                    ))
+                }
+                Some(Ok(chunk)) => {
+                    state.held_bytes += chunk.data.len();
+                    state.held.insert(chunk.index, chunk);
+                    continue;
+                }
+                Some(Err(e)) => e,
+            };
+            // Nothing more comes after an error
+            state.chunks = None;
+            state.held.clear();
+            return Some((Err(failed), state));
+        }
+    })
+}
+
+// Where `reconstruct_into` puts each chunk's bytes. `skip` is handed a run of zeros and says how
+// much of it was left as a hole
+#[cfg(not(target_arch = "wasm32"))]
+trait ChunkSink {
+    async fn write(&mut self, data: &[u8]) -> std::io::Result<()>;
+    async fn skip(&mut self, len: u64) -> std::io::Result<u64>;
+    async fn finish(&mut self) -> std::io::Result<()>;
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+struct DenseSink<'a, W>(&'a mut W);
+
+#[cfg(not(target_arch = "wasm32"))]
+impl<W: AsyncWrite + Unpin> ChunkSink for DenseSink<'_, W> {
+    async fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.0.write_all(data).await
+    }
+
+    async fn skip(&mut self, len: u64) -> std::io::Result<u64> {
+        let mut left = len;
+        while left > 0 {
+            let step = left.min(ZEROS.len() as u64) as usize;
+            self.0.write_all(&ZEROS[..step]).await?;
+            left -= step as u64;
+        }
+        Ok(0)
+    }
+
+    async fn finish(&mut self) -> std::io::Result<()> {
+        self.0.flush().await
+    }
+}
+
+// Holes are only seeked over once something follows them. One at the very end gets its last
+// byte written instead, since seeking alone doesn't make a file any longer
+#[cfg(not(target_arch = "wasm32"))]
+struct SparseSink<'a, W> {
+    writer: &'a mut W,
+    hole: u64,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl<W: AsyncWrite + AsyncSeek + Unpin> SparseSink<'_, W> {
+    async fn seek_past(&mut self, len: u64) -> std::io::Result<()> {
+        let offset = i64::try_from(len).map_err(|_| std::io::Error::other("hole is too long to seek over"))?;
+        self.writer.seek(std::io::SeekFrom::Current(offset)).await?;
+        Ok(())
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl<W: AsyncWrite + AsyncSeek + Unpin> ChunkSink for SparseSink<'_, W> {
+    async fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        let hole = std::mem::take(&mut self.hole);
+        if hole > 0 {
+            self.seek_past(hole).await?;
+        }
+        self.writer.write_all(data).await
+    }
+
+    async fn skip(&mut self, len: u64) -> std::io::Result<u64> {
+        self.hole += len;
+        Ok(len)
+    }
+
+    async fn finish(&mut self) -> std::io::Result<()> {
+        let hole = std::mem::take(&mut self.hole);
+        if hole > 0 {
+            self.seek_past(hole - 1).await?;
+            self.writer.write_all(&[0]).await?;
+        }
+        self.writer.flush().await
+    }
+}
+
+#[cfg(all(feature = "parallel", not(target_arch = "wasm32")))]
+fn spawn_decompress(pool: &rayon::ThreadPool, compressor: ParallelCompressor, chunk: CompressedChunk, mut buffer: Vec<u8>) -> oneshot::Receiver<Result<Vec<u8>>> {
+    let (tx, rx) = oneshot::channel();
+    pool.spawn(move || {
+        let _ = tx.send(compressor.decompress_chunk_into(&chunk, &mut buffer).map(|_| buffer));
+    });
+    rx
+}
+
+#[cfg(all(not(feature = "parallel"), not(target_arch = "wasm32")))]
+fn spawn_decompress(_: &(), compressor: ParallelCompressor, chunk: CompressedChunk, mut buffer: Vec<u8>) -> oneshot::Receiver<Result<Vec<u8>>> {
+    let (tx, rx) = oneshot::channel();
+    tokio::task::spawn_blocking(move || {
+        let _ = tx.send(compressor.decompress_chunk_into(&chunk, &mut buffer).map(|_| buffer));
+    });
+    rx
+}
+
+fn decompress_stored(chunk: &CompressedChunk, out: &mut Vec<u8>) -> Result<()> {
+    if chunk.data.len() != chunk.original_size {
+        return Err(ShrLinkError::Compression(format!(
+            "stored chunk {} claims {} bytes but holds {}",
+            chunk.index,
+            chunk.original_size,
+            chunk.data.len()
+        )));
+    }
+    out.extend_from_slice(&chunk.data);
+    Ok(())
+}
+
+fn decompress_zero(chunk: &CompressedChunk, out: &mut Vec<u8>) -> Result<()> {
+    if !chunk.data.is_empty() {
+        return Err(ShrLinkError::Compression(format!(
+            "zero chunk {} carries {} bytes of data",
+            chunk.index,
+            chunk.data.len()
+        )));
+    }
+    out.resize(chunk.original_size, 0);
+    Ok(())
+}
+
+fn is_zero(data: &[u8]) -> bool {
+    data.chunks(ZEROS.len()).all(|block| block == &ZEROS[..block.len()])
+}
+
+fn decompress_lz4(chunk: &CompressedChunk, out: &mut Vec<u8>) -> Result<()> {
+    // The size prefix decides the allocation, so it must agree with what the chunk claims
+    // before anything is decompressed
+    let prefix = chunk.data.get(..4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+        .ok_or_else(|| ShrLinkError::Compression("chunk is too short".to_string()))?;
+    if prefix != chunk.original_size {
+        return Err(ShrLinkError::Compression(format!(
+            "chunk {} claims {} bytes but decompresses to {}",
+            chunk.index, chunk.original_size, prefix
+        )));
+    }
+    let payload = chunk.data.len() - 4;
+    if prefix > payload.saturating_mul(LZ4_MAX_EXPANSION).saturating_add(16) {
+        return Err(ShrLinkError::Compression(format!(
+            "chunk {} claims {} bytes from only {} compressed",
+            chunk.index, prefix, payload
+        )));
+    }
+    
+    out.resize(prefix, 0);
+    let written = lz4_flex::decompress_into(&chunk.data[4..], out)
+        .map_err(|e| ShrLinkError::Compression(e.to_string()))?;
+    if written != prefix {
+        return Err(ShrLinkError::Compression(format!(
+            "chunk {} claims {} bytes but decompresses to {}",
+            chunk.index, prefix, written
+        )));
+    }
+    Ok(())
+}
+
+fn decompress_gzip(chunk: &CompressedChunk, out: &mut Vec<u8>) -> Result<()> {
+    gzip::decompress(&chunk.data, chunk.original_size, out)?;
+    if out.len() != chunk.original_size {
+        return Err(ShrLinkError::Compression(format!(
+            "chunk {} claims {} bytes but decompresses to {}",
+            chunk.index,
+            chunk.original_size,
+            out.len()
+        )));
+    }
+    Ok(())
+}
+
+fn decompress_snappy(chunk: &CompressedChunk, out: &mut Vec<u8>) -> Result<()> {
+    // Like lz4, the length up front must agree with the chunk before anything is allocated
+    let len = snappy::decompressed_len(&chunk.data)?;
+    if len != chunk.original_size {
+        return Err(ShrLinkError::Compression(format!(
+            "chunk {} claims {} bytes but decompresses to {}",
+            chunk.index, chunk.original_size, len
+        )));
+    }
+    snappy::decompress(&chunk.data, out)
+}
+
+#[cfg(feature = "zstd")]
+fn compress_zstd(data: &[u8], dictionary: Option<&Dictionary>) -> Result<Vec<u8>> {
+    match dictionary {
+        Some(dictionary) => zstd::bulk::Compressor::with_prepared_dictionary(dictionary.encoder())
+            .and_then(|mut compressor| compressor.compress(data)),
+        None => zstd::bulk::compress(data, ZSTD_LEVEL),
+    }
+    .map_err(|e| ShrLinkError::Compression(e.to_string()))
+}
+
+#[cfg(feature = "zstd")]
+fn decompress_zstd(chunk: &CompressedChunk, dictionary: Option<&Dictionary>, out: &mut Vec<u8>) -> Result<()> {
+    // Streamed rather than sized up front: the output only grows as far as the frame really
+    // expands, and never past what the chunk claims. A frame made without a dictionary reads
+    // the same with one
+    let mut decoder = match dictionary {
+        Some(dictionary) => zstd::stream::read::Decoder::with_prepared_dictionary(chunk.data.as_slice(), dictionary.decoder()),
+        None => zstd::stream::read::Decoder::with_buffer(chunk.data.as_slice()),
+    }
+    .map_err(|e| ShrLinkError::Compression(e.to_string()))?;
+    std::io::Read::read_to_end(&mut std::io::Read::take(&mut decoder, chunk.original_size as u64 + 1), out)
+        .map_err(|e| ShrLinkError::Compression(e.to_string()))?;
+    
+    if out.len() != chunk.original_size {
+        return Err(ShrLinkError::Compression(format!(
+            "chunk {} claims {} bytes but decompresses to {}",
+            chunk.index,
+            chunk.original_size,
+            out.len()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "zstd"))]
+fn compress_zstd(_: &[u8], _: Option<&Dictionary>) -> Result<Vec<u8>> {
+    Err(ShrLinkError::Compression("zstd support is not compiled in".to_string()))
+}
+
+#[cfg(not(feature = "zstd"))]
+fn decompress_zstd(chunk: &CompressedChunk, _: Option<&Dictionary>, _: &mut Vec<u8>) -> Result<()> {
+    Err(ShrLinkError::Compression(format!("chunk {} is zstd, which this build can't read", chunk.index)))
+}
+
+// Hands out one block at a time. A content-defined cut can only be made once `read_ahead()`
+// bytes are in view, so whatever follows it is kept for the next block
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Default)]
+struct BlockReader {
+    pending: Vec<u8>,
+    eof: bool,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl BlockReader {
+    #[cfg(feature = "fs")]
+    fn next_block<R: Read>(&mut self, compressor: &ParallelCompressor, reader: &mut R) -> std::io::Result<Vec<u8>> {
+        let wanted = compressor.read_ahead().saturating_sub(self.pending.len());
+        if !self.eof && wanted > 0 {
+            let more = read_block(reader, wanted)?;
+            self.append(more, wanted);
+        }
+        Ok(self.take(compressor))
+    }
+
+    async fn next_block_async<R: AsyncRead + Unpin>(&mut self, compressor: &ParallelCompressor, reader: &mut R) -> std::io::Result<Vec<u8>> {
+        let wanted = compressor.read_ahead().saturating_sub(self.pending.len());
+        if !self.eof && wanted > 0 {
+            let more = read_block_async(reader, wanted).await?;
+            self.append(more, wanted);
+        }
+        Ok(self.take(compressor))
+    }
+
+    fn append(&mut self, more: Vec<u8>, wanted: usize) {
+        // A short fill means the reader is exhausted, so it isn't asked again
+        self.eof = more.len() < wanted;
+        if self.pending.is_empty() {
+            self.pending = more;
+        } else {
+            self.pending.extend_from_slice(&more);
+        }
+    }
+
+    fn take(&mut self, compressor: &ParallelCompressor) -> Vec<u8> {
+        let len = compressor.block_len(&self.pending);
+        let rest = self.pending.split_off(len);
+        std::mem::replace(&mut self.pending, rest)
+    }
+}
+
+#[cfg(feature = "fs")]
+// Fills a whole block unless the reader is exhausted, since a single read may return less
+fn read_block<R: Read>(reader: &mut R, block_size: usize) -> std::io::Result<Vec<u8>> {
+    let mut block = vec![0u8; block_size];
+    let mut filled = 0;
+    
+    while filled < block_size {
+        match reader.read(&mut block[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    
+    block.truncate(filled);
+    Ok(block)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+async fn read_block_async<R: AsyncRead + Unpin>(reader: &mut R, block_size: usize) -> std::io::Result<Vec<u8>> {
+    let mut block = vec![0u8; block_size];
+    let mut filled = 0;
+    
+    while filled < block_size {
+        match reader.read(&mut block[filled..]).await {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    
+    block.truncate(filled);
+    Ok(block)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    
+    #[test]
+    fn test_compression_roundtrip() {
+        let compressor = ParallelCompressor::default();
+        let test_data = b"Hello, world! This is a test compression string.".repeat(1000);
+        
+        let chunk = compressor.compress_chunk(0, test_data.clone()).unwrap();
+        let decompressed = compressor.decompress_chunk(&chunk).unwrap();
+        
+        assert_eq!(test_data, decompressed);
+    }
+    
     #[test]
     fn test_shr_bundle_roundtrip() {
         let compressor = ParallelCompressor::default();
@@ -292,6 +1528,909 @@ mod tests {
         assert_eq!(test_data, decompressed);
     }
     
+    #[cfg(feature = "fs")]
+    #[tokio::test]
+    async fn test_write_chunks_to_file_leaves_nothing_on_failure() {
+        let compressor = ParallelCompressor::default();
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out.bin");
+        
+        let mut chunks: Vec<_> = (0..3)
+            .map(|i| compressor.compress_chunk(i, vec![i as u8; 4096]).unwrap())
+            .collect();
+        chunks[2].hash = [0u8; 32];
+        
+        // The first two chunks hit the disk before the third fails verification
+        let mut written = 0;
+        assert!(compressor.write_chunks_to_file(&chunks, &output, None, |_| written += 1).await.is_err());
+        assert_eq!(written, 2);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+        
+        chunks.pop();
+        // Every chunk checks out, but together they aren't the file the sender hashed
+        let other = blake3::hash(&[0u8; 2 * 4096]).into();
+        let mismatch = compressor.write_chunks_to_file(&chunks, &output, Some(&other), |_| {}).await;
+        assert!(matches!(mismatch, Err(ShrLinkError::HashMismatch { context, .. }) if context.contains("out.bin")));
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+        
+        let expected: [u8; 32] = blake3::hash(&[vec![0u8; 4096], vec![1u8; 4096]].concat()).into();
+        compressor.write_chunks_to_file(&chunks, &output, Some(&expected), |_| {}).await.unwrap();
+        assert_eq!(std::fs::read(&output).unwrap().len(), 2 * 4096);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+    
+    #[cfg(feature = "fs")]
+    #[tokio::test]
+    async fn test_write_stream_to_file_reorders_within_budget() {
+        let compressor = ParallelCompressor::default().with_memory_budget(3 * 4096);
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out.bin");
+        
+        // Incompressible, so each chunk holds its full 4096 bytes against the budget
+        let data = |i: usize| {
+            let mut block = vec![0u8; 4096];
+            blake3::Hasher::new().update(&i.to_le_bytes()).finalize_xof().fill(&mut block);
+            block
+        };
+        let chunks: Vec<_> = (0..6).map(|i| compressor.compress_chunk(i, data(i)).unwrap()).collect();
+        let expected: [u8; 32] = blake3::hash(&(0..6).flat_map(data).collect::<Vec<_>>()).into();
+        let arriving = |order: &[usize]| futures::stream::iter(order.iter().map(|&i| Ok(chunks[i].clone())).collect::<Vec<_>>());
+        
+        // Chunk 0 comes last, so 1, 2 and 4 wait for it; 3 and 5 still come after their turn
+        let mut written = Vec::new();
+        let streamed = compressor.write_stream_to_file(arriving(&[1, 2, 4, 0, 3, 5]), &output, |c| written.push(c.index)).await.unwrap();
+        assert_eq!(written, vec![0, 1, 2, 3, 4, 5]);
+        assert!(!output.exists());
+        streamed.commit(&output, Some(&expected)).unwrap();
+        assert_eq!(std::fs::read(&output).unwrap().len(), 6 * 4096);
+        
+        // Nothing of the file is left behind when there's more to hold than the budget allows
+        let ahead = compressor.write_stream_to_file(arriving(&[5, 4, 3, 2, 1, 0]), &dir.path().join("ahead.bin"), |_| {}).await;
+        assert!(matches!(ahead, Err(ShrLinkError::InvalidInput(message)) if message.contains("too far ahead")));
+        let twice = compressor.write_stream_to_file(arriving(&[0, 1, 1]), &dir.path().join("twice.bin"), |_| {}).await;
+        assert!(matches!(twice, Err(ShrLinkError::InvalidInput(message)) if message.contains("more than once")));
+        
+        // Indices that skip one are written in order once the stream ends
+        let gapped = compressor.write_stream_to_file(arriving(&[0, 3, 2]), &dir.path().join("gapped.bin"), |_| {}).await.unwrap();
+        assert_eq!(gapped.stats.bytes_written, 3 * 4096);
+        let mismatch = gapped.commit(&dir.path().join("gapped.bin"), Some(&expected));
+        assert!(matches!(mismatch, Err(ShrLinkError::HashMismatch { .. })));
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+    
+    #[cfg(all(feature = "fs", feature = "parallel"))]
+    #[tokio::test]
+    async fn test_empty_tiny_and_block_sized_files() {
+        let compressor = ParallelCompressor::new(64 * 1024, 1).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        
+        for (size, count) in [(0, 0), (1, 1), (64 * 1024, 1), (64 * 1024 + 1, 2)] {
+            let data: Vec<u8> = (0..size).map(|i| (i * 31 % 251) as u8).collect();
+            let input = dir.path().join(format!("in-{}", size));
+            std::fs::write(&input, &data).unwrap();
+            let hash = *blake3::hash(&data).as_bytes();
+            
+            let result = compressor.compress_file(&input).unwrap();
+            assert_eq!((result.chunks.len(), result.file_hash), (count, hash), "{} bytes", size);
+            assert_eq!(compressor.chunk_count(size as u64), count);
+            assert_eq!(compressor.compress_bytes(&data).unwrap().chunks.len(), count);
+            
+            let (mut rx, file_hash) = compressor.compress_file_stream(&input).unwrap();
+            let mut streamed = 0;
+            while let Some(chunk) = rx.recv().await {
+                chunk.unwrap();
+                streamed += 1;
+            }
+            assert_eq!((streamed, file_hash.await.unwrap()), (count, hash));
+            
+            // An empty file is written out and verifies like any other
+            let output = dir.path().join(format!("out-{}", size));
+            compressor.write_chunks_to_file(&result.chunks, &output, Some(&hash), |_| {}).await.unwrap();
+            assert_eq!(std::fs::read(&output).unwrap(), data);
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_single_threaded_path_matches_parallel_bundles() {
+        let data: Vec<u8> = (0..(5 * 64 * 1024 + 123)).map(|i| (i * 31 % 251) as u8).collect();
+        let compressor = ParallelCompressor::new(64 * 1024, 1).unwrap().with_workers(4);
+        
+        let single = compressor.compress_bytes(&data).unwrap();
+        let parallel = compressor.compress_async_reader(&mut Cursor::new(data.clone())).await.unwrap();
+        assert_eq!(single.chunks.len(), 6);
+        assert_eq!(single.total_compressed_size, parallel.total_compressed_size);
+        assert_eq!(single.file_hash, *blake3::hash(&data).as_bytes());
+        assert_eq!(parallel.file_hash, single.file_hash);
+        // The block size changes the chunks but not the file's hash
+        assert_eq!(ParallelCompressor::new(4096, 1).unwrap().compress_bytes(&data).unwrap().file_hash, single.file_hash);
+        
+        let bundle = create_shr_bundle(&single.chunks).unwrap();
+        assert_eq!(bundle, create_shr_bundle(&parallel.chunks).unwrap());
+        
+        // Decoding needs nothing beyond parse and decompress either
+        let decoded: Vec<u8> = parse_shr_bundle(&bundle)
+            .unwrap()
+            .iter()
+            .flat_map(|chunk| compressor.decompress_chunk(chunk).unwrap())
+            .collect();
+        assert_eq!(decoded, data);
+        
+        assert!(compressor.compress_bytes(&[]).unwrap().chunks.is_empty());
+    }
+    
+    #[test]
+    fn test_decompress_rejects_size_prefix_mismatch() {
+        let compressor = ParallelCompressor::default();
+        let mut chunk = compressor.compress_chunk(0, vec![7u8; 1024]).unwrap();
+        
+        // A tiny chunk that claims to be small but whose prefix asks for 4 GiB
+        chunk.data[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(compressor.decompress_chunk(&chunk), Err(ShrLinkError::Compression(_))));
+    }
+    
+    #[test]
+    fn test_algorithm_parsing() {
+        assert_eq!("lz4".parse::<CompressionAlgorithm>().unwrap(), CompressionAlgorithm::Lz4);
+        assert_eq!(" ZSTD ".parse::<CompressionAlgorithm>().unwrap(), CompressionAlgorithm::Zstd);
+        assert!(matches!("brotli".parse::<CompressionAlgorithm>(), Err(ShrLinkError::InvalidInput(_))));
+        assert_eq!(CompressionAlgorithm::Zstd.to_string().parse::<CompressionAlgorithm>().unwrap(), CompressionAlgorithm::Zstd);
+        assert_eq!("gzip".parse::<CompressionAlgorithm>().unwrap(), CompressionAlgorithm::Gzip);
+        assert_eq!("Snappy".parse::<CompressionAlgorithm>().unwrap(), CompressionAlgorithm::Snappy);
+    }
+    
+    #[test]
+    fn test_checksum_algorithms_roundtrip_and_catch_corruption() {
+        let test_data = b"Hello, world! This is a test compression string.".repeat(20_000);
+        let receiver = ParallelCompressor::default();
+
+        for checksum in [ChecksumAlgorithm::Blake3, ChecksumAlgorithm::Xxh3] {
+            let started = std::time::Instant::now();
+            for _ in 0..10 {
+                std::hint::black_box(checksum.checksum(&test_data));
+            }
+            eprintln!("{}: {:?} for 10 x {} bytes", checksum, started.elapsed(), test_data.len());
+
+            let compressor = ParallelCompressor::new(64 * 1024, 1).unwrap().with_checksum(checksum);
+            let chunks = compressor.compress_bytes(&test_data).unwrap().chunks;
+            assert!(chunks.len() > 1);
+            assert!(chunks.iter().all(|c| c.checksum == checksum));
+            // The receiver keeps its default and verifies with whatever each chunk declares
+            let restored: Vec<u8> = chunks.iter().flat_map(|c| receiver.decompress_chunk(c).unwrap()).collect();
+            assert_eq!(restored, test_data);
+
+            let mut corrupt = compressor.compress_chunk(0, b"checksum me".repeat(100)).unwrap();
+            corrupt.hash[0] ^= 1;
+            assert!(matches!(receiver.decompress_chunk(&corrupt), Err(ShrLinkError::HashMismatch { .. })));
+        }
+
+        let blake3 = ChecksumAlgorithm::Blake3.checksum(b"data");
+        let xxh3 = ChecksumAlgorithm::Xxh3.checksum(b"data");
+        assert_ne!(blake3, xxh3);
+        assert_eq!(xxh3[16..], [0; 16]);
+        assert_eq!("XXH3".parse::<ChecksumAlgorithm>().unwrap(), ChecksumAlgorithm::Xxh3);
+        assert!(matches!("crc32".parse::<ChecksumAlgorithm>(), Err(ShrLinkError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn test_xxh3_catches_flipped_data() {
+        // Stored chunks hand the data straight to the checksum, so a flipped byte can't hide
+        // behind a decompression error
+        let noise: Vec<u8> = (0..256u32).flat_map(|i| *blake3::hash(&i.to_le_bytes()).as_bytes()).collect();
+        let compressor = ParallelCompressor::default().with_checksum(ChecksumAlgorithm::Xxh3);
+        let mut chunk = compressor.compress_chunk(3, noise).unwrap();
+        assert_eq!(chunk.algorithm, CompressionAlgorithm::Stored);
+
+        let last = chunk.data.len() - 1;
+        chunk.data[last] ^= 0x80;
+        match compressor.decompress_chunk(&chunk) {
+            Err(ShrLinkError::HashMismatch { context, .. }) => assert_eq!(context, "chunk 3 (xxh3)"),
+            other => panic!("expected a hash mismatch, got {:?}", other.map(|d| d.len())),
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_zstd_roundtrip() {
+        let compressor = ParallelCompressor::default().with_algorithm(CompressionAlgorithm::Zstd);
+        let test_data = b"Hello, world! This is a test compression string.".repeat(1000);
+        
+        let chunk = compressor.compress_chunk(0, test_data.clone()).unwrap();
+        assert_eq!(chunk.algorithm, CompressionAlgorithm::Zstd);
+        assert!(chunk.data.len() < test_data.len() / 10);
+        assert_eq!(ParallelCompressor::default().decompress_chunk(&chunk).unwrap(), test_data);
+        
+        // The claimed size caps the output, so a frame that expands further is rejected
+        let mut lying = chunk.clone();
+        lying.original_size = 100;
+        assert!(matches!(compressor.decompress_chunk(&lying), Err(ShrLinkError::Compression(_))));
+        lying.original_size = test_data.len() + 1;
+        assert!(matches!(compressor.decompress_chunk(&lying), Err(ShrLinkError::Compression(_))));
+    }
+
+    #[test]
+    fn test_gzip_and_snappy_roundtrip() {
+        let test_data = b"Hello, world! This is a test compression string.".repeat(1000);
+        for algorithm in [CompressionAlgorithm::Gzip, CompressionAlgorithm::Snappy] {
+            let compressor = ParallelCompressor::default().with_algorithm(algorithm);
+            let chunk = compressor.compress_chunk(0, test_data.clone()).unwrap();
+            assert_eq!(chunk.algorithm, algorithm);
+            assert!(chunk.data.len() < test_data.len() / 10, "{}", algorithm);
+            assert_eq!(ParallelCompressor::default().decompress_chunk(&chunk).unwrap(), test_data);
+
+            let mut lying = chunk.clone();
+            lying.original_size = 100;
+            assert!(matches!(compressor.decompress_chunk(&lying), Err(ShrLinkError::Compression(_))));
+            lying.original_size = test_data.len() + 1;
+            assert!(matches!(compressor.decompress_chunk(&lying), Err(ShrLinkError::Compression(_))));
+        }
+
+        // The gzip data is a file gzip itself can read
+        let chunk = ParallelCompressor::default().with_algorithm(CompressionAlgorithm::Gzip).compress_chunk(0, test_data).unwrap();
+        assert_eq!(&chunk.data[..2], &[0x1f, 0x8b]);
+    }
+
+    #[test]
+    fn test_unknown_codec_is_refused() {
+        let mut chunk = ParallelCompressor::default().compress_chunk(3, b"codec ".repeat(100)).unwrap();
+        chunk.algorithm = CompressionAlgorithm::Unknown(12);
+        let err = ParallelCompressor::default().decompress_chunk(&chunk).unwrap_err();
+        assert!(err.to_string().contains("codec id 12"), "{}", err);
+        assert!(ParallelCompressor::default().with_algorithm(CompressionAlgorithm::Unknown(12)).compress_chunk(0, vec![1; 10]).is_err());
+    }
+    
+    #[test]
+    fn test_incompressible_chunks_are_stored() {
+        let noise: Vec<u8> = (0..1024u32).flat_map(|i| *blake3::hash(&i.to_le_bytes()).as_bytes()).collect();
+        let compressor = ParallelCompressor::default();
+        
+        let chunk = compressor.compress_chunk(0, noise.clone()).unwrap();
+        assert_eq!(chunk.algorithm, CompressionAlgorithm::Stored);
+        assert_eq!(chunk.data, noise);
+        assert_eq!(compressor.decompress_chunk(&chunk).unwrap(), noise);
+        
+        let text = b"plenty of redundancy here ".repeat(1000);
+        assert_eq!(compressor.compress_chunk(1, text.clone()).unwrap().algorithm, CompressionAlgorithm::Lz4);
+        // A threshold above what lz4 achieves on this text stores it too
+        let strict = ParallelCompressor::default().with_min_savings(99.9);
+        assert_eq!(strict.compress_chunk(1, text).unwrap().algorithm, CompressionAlgorithm::Stored);
+        
+        let mut truncated = chunk;
+        truncated.data.pop();
+        assert!(matches!(compressor.decompress_chunk(&truncated), Err(ShrLinkError::Compression(_))));
+    }
+    
+    #[test]
+    fn test_zero_chunks_carry_only_their_size() {
+        let compressor = ParallelCompressor::default().with_algorithm(CompressionAlgorithm::Stored);
+        let zeros = vec![0u8; 3 * ZEROS.len() + 5];
+        
+        // Caught whatever the configured algorithm, and hashed like any other chunk
+        let chunk = compressor.compress_chunk(0, zeros.clone()).unwrap();
+        assert_eq!(chunk.algorithm, CompressionAlgorithm::Zero);
+        assert!(chunk.data.is_empty());
+        assert_eq!(chunk.original_size, zeros.len());
+        assert_eq!(chunk.hash, *blake3::hash(&zeros).as_bytes());
+        assert_eq!(compressor.decompress_chunk(&chunk).unwrap(), zeros);
+        
+        // A single set byte, even in the last partial block, is real data
+        let mut almost = zeros.clone();
+        *almost.last_mut().unwrap() = 1;
+        assert_eq!(compressor.compress_chunk(1, almost).unwrap().algorithm, CompressionAlgorithm::Stored);
+        assert_eq!(compressor.compress_chunk(2, Vec::new()).unwrap().algorithm, CompressionAlgorithm::Stored);
+        
+        let mut shorter = chunk.clone();
+        shorter.original_size -= 1;
+        assert!(matches!(compressor.decompress_chunk(&shorter), Err(ShrLinkError::HashMismatch { .. })));
+        let mut padded = chunk;
+        padded.data.push(0);
+        assert!(matches!(compressor.decompress_chunk(&padded), Err(ShrLinkError::Compression(_))));
+    }
+    
+    #[cfg(feature = "fs")]
+    #[tokio::test]
+    async fn test_zero_chunks_become_holes() {
+        let compressor = ParallelCompressor::new(64 * 1024, 1).unwrap();
+        let mut data = vec![0u8; 64 * 1024 * 8];
+        data[..5].copy_from_slice(b"start");
+        data[3 * 64 * 1024 + 7] = 0xff;
+        let chunks = compressor.compress_bytes(&data).unwrap().chunks;
+        let zero = chunks.iter().filter(|c| c.algorithm == CompressionAlgorithm::Zero).count();
+        assert_eq!(zero, 6);
+        
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sparse.bin");
+        let mut file = tokio::fs::File::create(&path).await.unwrap();
+        let stats = compressor.reconstruct_sparse(&chunks, &mut file, |_| {}).await.unwrap();
+        drop(file);
+        assert_eq!(stats.bytes_written, data.len() as u64);
+        assert_eq!(stats.bytes_skipped, zero as u64 * 64 * 1024);
+        assert_eq!(stats.file_hash, *blake3::hash(&data).as_bytes());
+        // The trailing hole still counts towards the length
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+        
+        // A writer that can't seek gets the zeros written out
+        let mut dense = Vec::new();
+        let stats = compressor.reconstruct(&chunks, &mut dense, |_| {}).await.unwrap();
+        assert_eq!(stats.bytes_skipped, 0);
+        assert_eq!(dense, data);
+    }
+    
+    // Hands out one byte per read, with an EINTR every so often
+    struct TrickleReader {
+        data: Vec<u8>,
+        pos: usize,
+        reads: usize,
+    }
+    
+    impl TrickleReader {
+        fn new(data: Vec<u8>) -> Self {
+            Self { data, pos: 0, reads: 0 }
+        }
+        
+        fn next(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.reads += 1;
+            if self.reads.is_multiple_of(7) {
+                return Err(std::io::ErrorKind::Interrupted.into());
+            }
+            if self.pos == self.data.len() || buf.is_empty() {
+                return Ok(0);
+            }
+            buf[0] = self.data[self.pos];
+            self.pos += 1;
+            Ok(1)
+        }
+    }
+    
+    impl std::io::Read for TrickleReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.next(buf)
+        }
+    }
+    
+    impl AsyncRead for TrickleReader {
+        fn poll_read(mut self: std::pin::Pin<&mut Self>, _: &mut std::task::Context<'_>, buf: &mut tokio::io::ReadBuf<'_>) -> std::task::Poll<std::io::Result<()>> {
+            let n = self.next(buf.initialize_unfilled())?;
+            buf.advance(n);
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+    
+    #[cfg(feature = "fs")]
+    #[test]
+    fn test_short_reads_do_not_truncate() {
+        let compressor = ParallelCompressor::new(64, LZ4_ACCELERATION).unwrap();
+        let data: Vec<u8> = (0..200u8).collect();
+        
+        let (chunks, file_hash) = compressor.read_file_chunks(TrickleReader::new(data.clone())).unwrap();
+        assert_eq!(chunks.iter().map(|c| c.len()).collect::<Vec<_>>(), vec![64, 64, 64, 8]);
+        assert_eq!(chunks.concat(), data);
+        assert_eq!(file_hash, *blake3::hash(&data).as_bytes());
+    }
+    
+    #[test]
+    fn test_auto_block_size_is_clamped() {
+        const MIB: u64 = 1024 * 1024;
+        assert_eq!(auto_block_size(0), AUTO_MIN_BLOCK_SIZE);
+        assert_eq!(auto_block_size(10 * 1024), AUTO_MIN_BLOCK_SIZE);
+        assert_eq!(auto_block_size(256 * MIB), AUTO_MIN_BLOCK_SIZE);
+        assert_eq!(auto_block_size(256 * MIB + 1), 2 * AUTO_MIN_BLOCK_SIZE);
+        assert_eq!(auto_block_size(1024 * MIB), 4 * 1024 * 1024);
+        assert_eq!(auto_block_size(16 * 1024 * MIB), AUTO_MAX_BLOCK_SIZE);
+        assert_eq!(auto_block_size(50 * 1024 * MIB), AUTO_MAX_BLOCK_SIZE);
+        assert_eq!(auto_block_size(u64::MAX), AUTO_MAX_BLOCK_SIZE);
+
+        // Within the bounds, 128 to 256 chunks
+        for len in [300 * MIB, 1000 * MIB, 5000 * MIB, 16 * 1024 * MIB] {
+            let chunks = len.div_ceil(auto_block_size(len) as u64);
+            assert!((128..=256).contains(&chunks), "{} bytes make {} chunks", len, chunks);
+        }
+    }
+
+    #[test]
+    fn test_block_size_modes() {
+        let data: Vec<u8> = (0..3 * AUTO_MIN_BLOCK_SIZE + 17).map(|i| (i * 13 % 241) as u8).collect();
+
+        let auto = ParallelCompressor::with_block_size(BlockSize::Auto, 1).unwrap();
+        let result = auto.compress_bytes(&data).unwrap();
+        assert_eq!(result.block_size, Some(AUTO_MIN_BLOCK_SIZE));
+        assert_eq!(result.chunks.len(), 4);
+        assert_eq!(auto.chunk_count(data.len() as u64), 4);
+        assert_eq!(auto.sized_for(data.len() as u64).fixed_block_size(), Some(AUTO_MIN_BLOCK_SIZE));
+        // Still BLOCK_SIZE for inputs of unknown length
+        assert_eq!(auto.block_size(), BLOCK_SIZE);
+
+        // An explicit size is used whatever the input's length
+        for size in [4096, BLOCK_SIZE] {
+            let fixed = ParallelCompressor::with_block_size(BlockSize::Fixed(size), 1).unwrap();
+            let result = fixed.compress_bytes(&data).unwrap();
+            assert_eq!(result.block_size, Some(size));
+            assert_eq!(result.chunks.len(), data.len().div_ceil(size));
+            assert_eq!(fixed.block_size_for(1 << 40), size);
+            let plain = ParallelCompressor::new(size, 1).unwrap().compress_bytes(&data).unwrap();
+            assert_eq!(create_shr_bundle(&result.chunks).unwrap(), create_shr_bundle(&plain.chunks).unwrap());
+        }
+        assert!(matches!(ParallelCompressor::with_block_size(BlockSize::Fixed(0), 1), Err(ShrLinkError::InvalidInput(_))));
+
+        let cdc = auto.with_chunking(Chunking::Cdc(CdcParams::default()));
+        assert_eq!(cdc.compress_bytes(&data).unwrap().block_size, None);
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn test_block_size_must_fit_lz4() {
+        assert!(matches!(ParallelCompressor::new(0, 1), Err(ShrLinkError::InvalidInput(_))));
+        assert!(matches!(ParallelCompressor::new(MAX_BLOCK_SIZE + 1, 1), Err(ShrLinkError::InvalidInput(_))));
+        assert_eq!(ParallelCompressor::new(MAX_BLOCK_SIZE, 1).unwrap().block_size(), MAX_BLOCK_SIZE);
+
+        // Zeroed allocations are only mapped when touched, and the size is checked before the
+        // chunk is read
+        let past_u32 = vec![0u8; MAX_BLOCK_SIZE + 1];
+        let compressor = ParallelCompressor::default();
+        assert!(matches!(compressor.compress_chunk(0, past_u32), Err(ShrLinkError::InvalidInput(_))));
+    }
+
+    #[tokio::test]
+    async fn test_short_async_reads_do_not_truncate() {
+        let compressor = ParallelCompressor::new(64, LZ4_ACCELERATION).unwrap();
+        let data: Vec<u8> = (0..128u8).collect();
+        
+        let result = compressor.compress_async_reader(&mut TrickleReader::new(data.clone())).await.unwrap();
+        assert_eq!(result.total_original_size, data.len());
+        assert_eq!(result.chunks.len(), 2);
+        let roundtrip: Vec<u8> = result.chunks.iter().flat_map(|c| compressor.decompress_chunk(c).unwrap()).collect();
+        assert_eq!(roundtrip, data);
+    }
+
+    // Endless input that cancels the compression reading it once `trip_after` bytes are out
+    struct EndlessReader {
+        produced: usize,
+        trip_after: usize,
+        cancel: CancellationToken,
+    }
+
+    impl AsyncRead for EndlessReader {
+        fn poll_read(mut self: std::pin::Pin<&mut Self>, _: &mut std::task::Context<'_>, buf: &mut tokio::io::ReadBuf<'_>) -> std::task::Poll<std::io::Result<()>> {
+            let n = buf.remaining();
+            buf.put_slice(&vec![0x5a; n]);
+            self.produced += n;
+            if self.produced >= self.trip_after {
+                self.cancel.cancel();
+            }
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_compression_stops_promptly() {
+        let cancel = CancellationToken::new();
+        let compressor = ParallelCompressor::new(1024 * 1024, LZ4_ACCELERATION).unwrap().with_cancellation(cancel.clone());
+        let mut reader = EndlessReader { produced: 0, trip_after: 16 * 1024 * 1024, cancel };
+
+        let result = tokio::time::timeout(std::time::Duration::from_secs(10), compressor.compress_async_reader(&mut reader)).await;
+        assert!(matches!(result, Ok(Err(ShrLinkError::Cancelled))));
+        assert!(reader.produced < 32 * 1024 * 1024);
+
+        // A reader that never delivers doesn't hold it up either
+        let cancel = CancellationToken::new();
+        let compressor = compressor.with_cancellation(cancel.clone());
+        let (_writer, mut stalled) = tokio::io::duplex(64);
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            cancel.cancel();
+        });
+        let result = tokio::time::timeout(std::time::Duration::from_secs(10), compressor.compress_async_reader(&mut stalled)).await;
+        assert!(matches!(result, Ok(Err(ShrLinkError::Cancelled))));
+
+        // Nor does anything already in memory get compressed
+        assert!(matches!(compressor.compress_bytes(b"too late"), Err(ShrLinkError::Cancelled)));
+    }
+
+    struct SlowWriter {
+        written: Vec<u8>,
+        delay: std::pin::Pin<Box<tokio::time::Sleep>>,
+        waiting: bool,
+    }
+    
+    impl SlowWriter {
+        fn new() -> Self {
+            Self {
+                written: Vec::new(),
+                delay: Box::pin(tokio::time::sleep(std::time::Duration::ZERO)),
+                waiting: false,
+            }
+        }
+    }
+    
+    impl AsyncWrite for SlowWriter {
+        fn poll_write(mut self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>, buf: &[u8]) -> std::task::Poll<std::io::Result<usize>> {
+            use std::future::Future;
+            
+            if !self.waiting {
+                let deadline = tokio::time::Instant::now() + std::time::Duration::from_millis(5);
+                self.delay.as_mut().reset(deadline);
+                self.waiting = true;
+            }
+            std::task::ready!(self.delay.as_mut().poll(cx));
+            self.waiting = false;
+            self.written.extend_from_slice(buf);
+            std::task::Poll::Ready(Ok(buf.len()))
+        }
+        
+        fn poll_flush(self: std::pin::Pin<&mut Self>, _: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+        
+        fn poll_shutdown(self: std::pin::Pin<&mut Self>, _: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_reconstruct_keeps_buffered_bytes_under_budget() {
+        let chunk_size = 64 * 1024;
+        let base = ParallelCompressor::new(chunk_size, 1).unwrap().with_workers(8);
+        let chunks: Vec<_> = (0..24)
+            .map(|i| base.compress_chunk(i, vec![i as u8; chunk_size]).unwrap())
+            .collect();
+        let expected: Vec<u8> = (0..24).flat_map(|i| vec![i as u8; chunk_size]).collect();
+        
+        let budget = 3 * chunk_size;
+        let mut writer = SlowWriter::new();
+        let stats = base.clone().with_memory_budget(budget)
+            .reconstruct(&chunks, &mut writer, |_| {})
+            .await
+            .unwrap();
+        // The slow writer keeps the budget full, and no more than full
+        assert_eq!(stats.peak_buffered_bytes, budget);
+        assert_eq!(stats.bytes_written, expected.len() as u64);
+        assert_eq!(writer.written, expected);
+        assert_eq!(stats.file_hash, *blake3::hash(&expected).as_bytes());
+        
+        // Without the cap the same slow writer lets every worker run ahead
+        let mut writer = SlowWriter::new();
+        let stats = base.reconstruct(&chunks, &mut writer, |_| {}).await.unwrap();
+        assert!(stats.peak_buffered_bytes > budget);
+        
+        // A chunk larger than the whole budget is still let through, one at a time
+        let mut writer = SlowWriter::new();
+        let stats = base.with_memory_budget(chunk_size / 2)
+            .reconstruct(&chunks[..4], &mut writer, |_| {})
+            .await
+            .unwrap();
+        assert_eq!((stats.peak_buffered_bytes, stats.peak_inflight_chunks), (chunk_size, 1));
+        assert_eq!(writer.written, expected[..4 * chunk_size]);
+    }
+    
+    #[test]
+    fn test_decompress_into_reuses_one_buffer() {
+        let compressor = ParallelCompressor::new(64 * 1024, 1).unwrap();
+        let noise: Vec<u8> = (0..512u32).flat_map(|i| *blake3::hash(&i.to_le_bytes()).as_bytes()).collect();
+        let mut chunks = vec![
+            compressor.compress_chunk(0, b"small ".repeat(10)).unwrap(),
+            compressor.compress_chunk(1, vec![7u8; 64 * 1024]).unwrap(),
+            compressor.compress_chunk(2, noise.clone()).unwrap(),
+            compressor.compress_chunk(3, Vec::new()).unwrap(),
+            compressor.compress_chunk(4, b"tail".repeat(3)).unwrap(),
+        ];
+        #[cfg(feature = "zstd")]
+        chunks.push(compressor.clone().with_algorithm(CompressionAlgorithm::Zstd).compress_chunk(5, b"zstd ".repeat(2000)).unwrap());
+        assert_eq!(chunks[2].algorithm, CompressionAlgorithm::Stored);
+        
+        let mut out = Vec::new();
+        let mut capacity = 0;
+        for chunk in &chunks {
+            let len = compressor.decompress_chunk_into(chunk, &mut out).unwrap();
+            assert_eq!(len, chunk.original_size);
+            assert_eq!(out, compressor.decompress_chunk(chunk).unwrap());
+            // Shorter chunks fit in what the longest one left behind
+            if chunk.original_size <= capacity {
+                assert_eq!(out.capacity(), capacity);
+            }
+            capacity = capacity.max(out.capacity());
+        }
+        assert!(capacity >= 64 * 1024);
+        
+        // Verification still runs on the reused bytes
+        chunks[0].hash[0] ^= 1;
+        assert!(matches!(compressor.decompress_chunk_into(&chunks[0], &mut out), Err(ShrLinkError::HashMismatch { .. })));
+        let len = compressor.decompress_chunk_into(&chunks[4], &mut out).unwrap();
+        assert_eq!(&out[..len], b"tail".repeat(3).as_slice());
+    }
+    
+    #[test]
+    fn test_decompress_chunks_parallel_keeps_order() {
+        let compressor = ParallelCompressor::new(4096, 1).unwrap().with_workers(4);
+        let blocks: Vec<Vec<u8>> = (0..16u8).map(|i| format!("block {} ", i).repeat(400).into_bytes()).collect();
+        let mut chunks: Vec<_> = blocks.iter().enumerate()
+            .map(|(i, b)| compressor.compress_chunk(i, b.clone()).unwrap())
+            .collect();
+        
+        assert_eq!(compressor.decompress_chunks_parallel(&chunks).unwrap(), blocks);
+        
+        chunks[11].hash[0] ^= 1;
+        assert!(matches!(compressor.decompress_chunks_parallel(&chunks), Err(ShrLinkError::HashMismatch { .. })));
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn test_estimate_ratio() {
+        let dir = tempfile::tempdir().unwrap();
+        let text = dir.path().join("text");
+        std::fs::write(&text, (0..100_000).flat_map(|i| format!("row {},{},ok\n", i, i % 17).into_bytes()).collect::<Vec<_>>()).unwrap();
+        let noise = dir.path().join("noise");
+        std::fs::write(&noise, (0..40_000u32).flat_map(|i| *blake3::hash(&i.to_le_bytes()).as_bytes()).collect::<Vec<_>>()).unwrap();
+        let compressor = ParallelCompressor::new(64 * 1024, 1).unwrap();
+
+        // Sampled, and everything when the file fits the budget
+        for budget in [256 * 1024, DEFAULT_ESTIMATE_SAMPLE_BYTES] {
+            let estimate = compressor.estimate_ratio(&text, budget).unwrap();
+            assert!(estimate.ratio < 0.5, "{:?}", estimate);
+            assert_eq!(estimate.exact, budget == DEFAULT_ESTIMATE_SAMPLE_BYTES);
+            assert_eq!(estimate.chunk_count, compressor.chunk_count(estimate.original_size));
+            assert!(estimate.sampled_bytes <= budget.min(estimate.original_size));
+
+            let estimate = compressor.estimate_ratio(&noise, budget).unwrap();
+            assert!(estimate.ratio > 0.5, "{:?}", estimate);
+            assert_eq!(estimate.estimated_size, (estimate.original_size as f64 * estimate.ratio).round() as u64);
+        }
+
+        // The exact figure is what compressing the file really gives
+        let estimate = compressor.estimate_ratio(&text, u64::MAX).unwrap();
+        assert_eq!(estimate.estimated_size as usize, compressor.compress_file(&text).unwrap().total_compressed_size);
+        let sampled = compressor.estimate_ratio(&text, 256 * 1024).unwrap();
+        assert!((sampled.ratio - estimate.ratio).abs() < 0.05, "{} against {}", sampled.ratio, estimate.ratio);
+
+        let empty = dir.path().join("empty");
+        std::fs::write(&empty, b"").unwrap();
+        let estimate = compressor.estimate_ratio(&empty, 1).unwrap();
+        assert_eq!((estimate.estimated_size, estimate.chunk_count), (0, 0));
+    }
+
+    #[cfg(all(feature = "fs", feature = "parallel"))]
+    #[test]
+    fn test_pool_is_built_once_and_shared() {
+        let mut temp_file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut temp_file, &b"small input ".repeat(100)).unwrap();
+
+        let threads = Arc::new(std::sync::Mutex::new(std::collections::HashSet::new()));
+        let compressor = ParallelCompressor::new(256, 1).unwrap().with_workers(2).with_progress({
+            let threads = threads.clone();
+            move |_| {
+                threads.lock().unwrap().insert(std::thread::current().id());
+            }
+        });
+        let pool = compressor.thread_pool().unwrap();
+
+        for _ in 0..1000 {
+            let result = compressor.compress_file(temp_file.path()).unwrap();
+            compressor.decompress_chunks_parallel(&result.chunks).unwrap();
+        }
+        assert!(Arc::ptr_eq(&pool, &compressor.thread_pool().unwrap()));
+        // The calling thread reports the expected counts; every chunk ran on one of the two workers
+        assert!(threads.lock().unwrap().len() <= 3);
+
+        // Clones share it, as does setting the same worker count again
+        assert!(Arc::ptr_eq(&pool, &compressor.clone().with_workers(2).thread_pool().unwrap()));
+        let resized = compressor.clone().with_workers(3);
+        assert_eq!(resized.thread_pool().unwrap().current_num_threads(), 3);
+        assert!(Arc::ptr_eq(&pool, &compressor.thread_pool().unwrap()));
+
+        let shared = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(5).build().unwrap());
+        let injected = compressor.with_thread_pool(shared.clone());
+        assert!(Arc::ptr_eq(&shared, &injected.thread_pool().unwrap()));
+        assert_eq!(injected.num_workers, 5);
+    }
+
+    #[tokio::test]
+    async fn test_reconstruct_decompresses_on_all_workers() {
+        let chunk_size = 256 * 1024;
+        let data: Vec<u8> = (0..chunk_size * 12).map(|i| (i % 241) as u8).collect();
+        
+        for workers in [1, 3] {
+            let compressor = ParallelCompressor::new(chunk_size, 1).unwrap().with_workers(workers);
+            let chunks = compressor.compress_bytes(&data).unwrap().chunks;
+            
+            let mut writer = SlowWriter::new();
+            let stats = compressor.reconstruct(&chunks, &mut writer, |_| {}).await.unwrap();
+            assert_eq!(writer.written, data);
+            // The slow writer keeps the queue full, so every worker gets a chunk but no more
+            assert_eq!(stats.peak_inflight_chunks, workers);
+        }
+    }
+    
+    #[cfg(all(feature = "fs", feature = "parallel"))]
+    #[tokio::test]
+    async fn test_compress_file_stream_yields_ordered_chunks() {
+        let test_data: Vec<u8> = (0..(3 * 1024 * 1024 + 17)).map(|i| (i % 251) as u8).collect();
+        let mut temp_file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut temp_file, &test_data).unwrap();
+        
+        let compressor = ParallelCompressor::new(1024 * 1024, 1).unwrap().with_workers(2);
+        let (mut rx, file_hash) = compressor.compress_file_stream(temp_file.path()).unwrap();
+        
+        let mut reconstructed = Vec::new();
+        let mut indices = Vec::new();
+        while let Some(chunk) = rx.recv().await {
+            let chunk = chunk.unwrap();
+            indices.push(chunk.index);
+            reconstructed.extend_from_slice(&compressor.decompress_chunk(&chunk).unwrap());
+        }
+        
+        assert_eq!(indices, vec![0, 1, 2, 3]);
+        assert_eq!(compressor.chunk_count(test_data.len() as u64), 4);
+        assert_eq!(reconstructed, test_data);
+        assert_eq!(file_hash.await.unwrap(), *blake3::hash(&test_data).as_bytes());
+    }
+
+    #[cfg(all(feature = "fs", feature = "parallel"))]
+    #[tokio::test]
+    async fn test_progress_reported_from_every_worker() {
+        let test_data: Vec<u8> = (0..(8 * 256 * 1024 + 5)).map(|i| (i % 13) as u8).collect();
+        let mut temp_file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut temp_file, &test_data).unwrap();
+
+        let reports = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let compressor = ParallelCompressor::new(256 * 1024, 1).unwrap().with_workers(4).with_progress({
+            let reports = reports.clone();
+            move |progress| reports.lock().unwrap().push(progress)
+        });
+        let (mut chunks, file_hash) = compressor.compress_stream(temp_file.path()).unwrap();
+
+        let mut compressed = 0;
+        while let Some(chunk) = futures::StreamExt::next(&mut chunks).await {
+            compressed += chunk.unwrap().data.len() as u64;
+        }
+        file_hash.await.unwrap();
+
+        let reports = reports.lock().unwrap();
+        // One report for the expected count, then one per chunk in the order they finished
+        assert_eq!(reports.len(), 1 + 9);
+        assert!(reports.windows(2).all(|w| w[1].chunks_done == w[0].chunks_done + 1));
+        assert!(reports.iter().all(|p| p.chunks_total == 9));
+        assert_eq!(*reports.last().unwrap(), CompressionProgress {
+            chunks_done: 9,
+            chunks_total: 9,
+            bytes_in: test_data.len() as u64,
+            bytes_out: compressed,
+        });
+    }
+
+    // Produces `blocks` blocks of generated data without ever holding them, and records the most
+    // blocks that have been read but not yet taken by the consumer
+    #[cfg(all(feature = "fs", feature = "parallel"))]
+    struct SyntheticReader {
+        block_size: usize,
+        remaining: usize,
+        read: usize,
+        consumed: Arc<std::sync::atomic::AtomicUsize>,
+        peak: Arc<std::sync::atomic::AtomicUsize>,
+    }
+    
+    #[cfg(all(feature = "fs", feature = "parallel"))]
+    impl std::io::Read for SyntheticReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            use std::sync::atomic::Ordering;
+            
+            let n = buf.len().min(self.remaining);
+            for (i, byte) in buf[..n].iter_mut().enumerate() {
+                *byte = ((self.read + i) % 251) as u8;
+            }
+            self.read += n;
+            self.remaining -= n;
+            
+            let blocks_read = self.read.div_ceil(self.block_size);
+            let in_flight = blocks_read - self.consumed.load(Ordering::SeqCst);
+            self.peak.fetch_max(in_flight, Ordering::SeqCst);
+            Ok(n)
+        }
+    }
+    
+    #[cfg(all(feature = "fs", feature = "parallel"))]
+    #[tokio::test]
+    async fn test_compress_stream_bounds_chunks_in_flight() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        
+        const BLOCK: usize = 64 * 1024;
+        const BLOCKS: usize = 256;
+        let workers = 3;
+        let consumed = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let reader = SyntheticReader {
+            block_size: BLOCK,
+            remaining: BLOCK * BLOCKS,
+            read: 0,
+            consumed: consumed.clone(),
+            peak: peak.clone(),
+        };
+        
+        let compressor = ParallelCompressor::new(BLOCK, 1).unwrap().with_workers(workers);
+        let (mut rx, _) = compressor.compress_reader_stream(reader);
+        
+        let mut next = 0;
+        while let Some(chunk) = rx.recv().await {
+            let chunk = chunk.unwrap();
+            assert_eq!(chunk.index, next);
+            next += 1;
+            consumed.fetch_add(1, Ordering::SeqCst);
+            // A consumer slower than compression is what lets the producer run ahead
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        }
+        
+        assert_eq!(next, BLOCKS);
+        let peak = peak.load(Ordering::SeqCst);
+        // Plus the one just received here, which is counted as consumed a moment after the
+        // channel frees its slot
+        assert!(peak <= workers * 2 + 1, "{} blocks were buffered at once", peak);
+        assert!(peak >= workers);
+    }
+    
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+    
+    fn chunk_hashes(compressor: &ParallelCompressor, data: &[u8]) -> Vec<[u8; 32]> {
+        compressor.compress_bytes(data).unwrap().chunks.iter().map(|c| c.hash).collect()
+    }
+    
+    #[test]
+    fn test_cdc_survives_an_insertion() {
+        let data = noise(50 * 1024 * 1024);
+        let mut shifted = vec![0xAA; 100];
+        shifted.extend_from_slice(&data);
+        
+        // Stored keeps this about chunk boundaries rather than compression speed
+        let fixed = ParallelCompressor::default().with_algorithm(CompressionAlgorithm::Stored);
+        let cdc = fixed.clone().with_chunking(Chunking::Cdc(CdcParams::default()));
+        
+        let unchanged = |compressor: &ParallelCompressor| {
+            let before = chunk_hashes(compressor, &data);
+            let after = chunk_hashes(compressor, &shifted);
+            let kept = after.iter().filter(|h| before.contains(h)).count();
+            (kept, before.len())
+        };
+        
+        let (kept, total) = unchanged(&cdc);
+        assert!(kept * 2 > total, "CDC kept {} of {} chunks", kept, total);
+        let (kept, total) = unchanged(&fixed);
+        assert_eq!(kept, 0, "fixed blocks kept {} of {} chunks", kept, total);
+        
+        // Deterministic: the same bytes always give the same chunks
+        assert_eq!(chunk_hashes(&cdc, &data), chunk_hashes(&cdc, &data));
+    }
+    
+    #[tokio::test]
+    async fn test_cdc_streamed_matches_in_memory() {
+        let data = noise(3 * 1024 * 1024 + 77);
+        let params = CdcParams::new(16 * 1024, 64 * 1024, 256 * 1024).unwrap();
+        let compressor = ParallelCompressor::default().with_workers(3).with_chunking(Chunking::Cdc(params));
+        
+        let in_memory = compressor.compress_bytes(&data).unwrap();
+        let streamed = compressor.compress_async_reader(&mut Cursor::new(data.clone())).await.unwrap();
+        let sizes = |r: &CompressionResult| r.chunks.iter().map(|c| c.original_size).collect::<Vec<_>>();
+        assert_eq!(sizes(&in_memory), sizes(&streamed));
+        assert!(in_memory.chunks.len() > 10);
+        assert!(sizes(&in_memory).iter().all(|s| *s <= params.max_size()));
+        assert_eq!(streamed.file_hash, *blake3::hash(&data).as_bytes());
+        
+        #[cfg(all(feature = "fs", feature = "parallel"))]
+        {
+            let (mut rx, _) = compressor.compress_reader_stream(Cursor::new(data.clone()));
+            let mut threaded = Vec::new();
+            while let Some(chunk) = rx.recv().await {
+                threaded.push(chunk.unwrap().original_size);
+            }
+            assert_eq!(threaded, sizes(&in_memory));
+        }
+    }
+    
     #[tokio::test]
     async fn test_parallel_compression() {
         let compressor = ParallelCompressor::default();
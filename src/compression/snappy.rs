@@ -0,0 +1,275 @@
+use crate::{Result, ShrLinkError};
+
+// Snappy's raw block format, as snappy's own `Compress`/`Uncompress` and most of its ports read
+// and write it: a varint of the uncompressed length, then literals and back-references. No
+// framing and no checksum, since each chunk carries its own hash
+
+// Largest length the preamble can hold
+pub const MAX_INPUT_SIZE: usize = u32::MAX as usize;
+
+// Matches are only looked for within a block of this much, as the reference compressor does,
+// so every offset fits two bytes
+const BLOCK_SIZE: usize = 1 << 16;
+const HASH_BITS: u32 = 14;
+const MIN_MATCH: usize = 4;
+// A three-byte copy of 64 bytes is as far as any input can expand
+const MAX_EXPANSION: usize = 22;
+
+pub fn compress(data: &[u8]) -> Result<Vec<u8>> {
+    if data.len() > MAX_INPUT_SIZE {
+        return Err(ShrLinkError::InvalidInput(format!(
+            "{} bytes is more than snappy can record ({})",
+            data.len(),
+            MAX_INPUT_SIZE
+        )));
+    }
+    let mut out = Vec::with_capacity(32 + data.len() + data.len() / 6);
+    write_varint(&mut out, data.len() as u32);
+    let mut table = vec![0u16; 1 << HASH_BITS];
+    for block in data.chunks(BLOCK_SIZE) {
+        compress_block(block, &mut table, &mut out);
+    }
+    Ok(out)
+}
+
+fn compress_block(block: &[u8], table: &mut [u16], out: &mut Vec<u8>) {
+    table.fill(0);
+    let mut literal_start = 0;
+    let mut pos = 1;
+    while pos + MIN_MATCH <= block.len() {
+        let slot = hash(&block[pos..]);
+        let candidate = table[slot] as usize;
+        table[slot] = pos as u16;
+        if candidate >= pos || block[candidate..candidate + MIN_MATCH] != block[pos..pos + MIN_MATCH] {
+            pos += 1;
+            continue;
+        }
+
+        let len = MIN_MATCH + common_prefix(&block[candidate + MIN_MATCH..], &block[pos + MIN_MATCH..]);
+        emit_literal(&block[literal_start..pos], out);
+        emit_copy(pos - candidate, len, out);
+        pos += len;
+        literal_start = pos;
+        if pos + MIN_MATCH <= block.len() {
+            table[hash(&block[pos - 1..])] = (pos - 1) as u16;
+        }
+    }
+    emit_literal(&block[literal_start..], out);
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    (word.wrapping_mul(0x1e35_a7bd) >> (32 - HASH_BITS)) as usize
+}
+
+fn common_prefix(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+fn emit_literal(literal: &[u8], out: &mut Vec<u8>) {
+    if literal.is_empty() {
+        return;
+    }
+    let n = literal.len() - 1;
+    if n < 60 {
+        out.push((n as u8) << 2);
+    } else {
+        let width = match n {
+            0..=0xff => 1,
+            0x100..=0xffff => 2,
+            0x1_0000..=0xff_ffff => 3,
+            _ => 4,
+        };
+        out.push(((59 + width) as u8) << 2);
+        out.extend_from_slice(&(n as u32).to_le_bytes()[..width]);
+    }
+    out.extend_from_slice(literal);
+}
+
+fn emit_copy(offset: usize, mut len: usize, out: &mut Vec<u8>) {
+    // At most 64 bytes a copy, without ever leaving one of fewer than four for the last
+    while len >= 68 {
+        emit_copy_upto64(offset, 64, out);
+        len -= 64;
+    }
+    if len > 64 {
+        emit_copy_upto64(offset, 60, out);
+        len -= 60;
+    }
+    emit_copy_upto64(offset, len, out);
+}
+
+fn emit_copy_upto64(offset: usize, len: usize, out: &mut Vec<u8>) {
+    if len < 12 && offset < 2048 {
+        out.push(0x01 | (((len - 4) as u8) << 2) | (((offset >> 8) as u8) << 5));
+        out.push(offset as u8);
+    } else {
+        out.push(0x02 | (((len - 1) as u8) << 2));
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+// The length the input claims to decompress to, before anything is decompressed
+pub fn decompressed_len(input: &[u8]) -> Result<usize> {
+    read_varint(input).map(|(len, _)| len)
+}
+
+// Appends the decompressed input to `out`, refusing anything that doesn't come to exactly the
+// length its preamble claims
+pub fn decompress(input: &[u8], out: &mut Vec<u8>) -> Result<()> {
+    let (len, mut pos) = read_varint(input)?;
+    if len > input.len().saturating_mul(MAX_EXPANSION) {
+        return Err(invalid(&format!("claims {} bytes from only {}", len, input.len())));
+    }
+    let start = out.len();
+    let end = start + len;
+    out.reserve(len);
+
+    while pos < input.len() {
+        let tag = input[pos];
+        pos += 1;
+        let (offset, copy) = match tag & 0x03 {
+            0x00 => {
+                let n = (tag >> 2) as usize;
+                let n = if n < 60 {
+                    n + 1
+                } else {
+                    let width = n - 59;
+                    let bytes = input.get(pos..pos + width).ok_or_else(|| invalid("literal length is cut short"))?;
+                    pos += width;
+                    bytes.iter().rev().fold(0usize, |acc, &b| acc << 8 | b as usize) + 1
+                };
+                let literal = input.get(pos..).and_then(|rest| rest.get(..n)).ok_or_else(|| invalid("literal is cut short"))?;
+                if out.len() + n > end {
+                    return Err(invalid("decompresses to more than it claims"));
+                }
+                out.extend_from_slice(literal);
+                pos += n;
+                continue;
+            }
+            0x01 => {
+                let low = *input.get(pos).ok_or_else(|| invalid("copy is cut short"))?;
+                pos += 1;
+                (((tag as usize >> 5) << 8) | low as usize, 4 + ((tag >> 2) & 0x07) as usize)
+            }
+            0x02 => {
+                let bytes = input.get(pos..pos + 2).ok_or_else(|| invalid("copy is cut short"))?;
+                pos += 2;
+                (u16::from_le_bytes([bytes[0], bytes[1]]) as usize, 1 + (tag >> 2) as usize)
+            }
+            _ => {
+                let bytes = input.get(pos..pos + 4).ok_or_else(|| invalid("copy is cut short"))?;
+                pos += 4;
+                (u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize, 1 + (tag >> 2) as usize)
+            }
+        };
+
+        if offset == 0 || offset > out.len() - start {
+            return Err(invalid("copy reaches back before the start"));
+        }
+        if out.len() + copy > end {
+            return Err(invalid("decompresses to more than it claims"));
+        }
+        copy_back(out, offset, copy);
+    }
+
+    if out.len() != end {
+        return Err(invalid(&format!("decompresses to {} bytes but claims {}", out.len() - start, len)));
+    }
+    Ok(())
+}
+
+// Overlapping copies repeat the bytes they've just written, so they go a distance at a time
+pub(super) fn copy_back(out: &mut Vec<u8>, distance: usize, len: usize) {
+    let mut remaining = len;
+    while remaining > 0 {
+        let from = out.len() - distance;
+        let n = remaining.min(distance);
+        out.extend_from_within(from..from + n);
+        remaining -= n;
+    }
+}
+
+fn read_varint(input: &[u8]) -> Result<(usize, usize)> {
+    let mut value = 0u64;
+    for (i, &byte) in input.iter().take(5).enumerate() {
+        value |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return u32::try_from(value)
+                .map(|len| (len as usize, i + 1))
+                .map_err(|_| invalid("length is too large"));
+        }
+    }
+    Err(invalid("length is cut short"))
+}
+
+fn invalid(what: &str) -> ShrLinkError {
+    ShrLinkError::Compression(format!("invalid snappy data: {}", what))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(data: &[u8]) -> Vec<u8> {
+        let compressed = compress(data).unwrap();
+        assert_eq!(decompressed_len(&compressed).unwrap(), data.len());
+        let mut out = Vec::new();
+        decompress(&compressed, &mut out).unwrap();
+        assert_eq!(out, data);
+        compressed
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        roundtrip(b"");
+        roundtrip(b"a");
+        roundtrip(b"abcd");
+        assert!(roundtrip(&b"snappy ".repeat(10_000)).len() < 4000);
+        assert!(roundtrip(&vec![7; 300_000]).len() < 20_000);
+        let noise: Vec<u8> = (0..200_000u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+        roundtrip(&noise);
+        // Literals longer than every length encoding but the widest
+        let literal: Vec<u8> = (0..70_000u32).map(|i| (i.wrapping_mul(40503) >> 7) as u8).collect();
+        roundtrip(&literal);
+    }
+
+    #[test]
+    fn test_reads_reference_output() {
+        // From the reference implementation: "Wikipedia" then a 4-byte copy back 9 bytes,
+        // and a 4-byte offset copy
+        let mut out = Vec::new();
+        decompress(&[0x0d, 0x20, b'W', b'i', b'k', b'i', b'p', b'e', b'd', b'i', b'a', 0x01, 0x09], &mut out).unwrap();
+        assert_eq!(out, b"WikipediaWiki");
+        out.clear();
+        decompress(&[0x06, 0x04, b'a', b'b', 0x0f, 0x02, 0, 0, 0], &mut out).unwrap();
+        assert_eq!(out, b"ababab");
+    }
+
+    #[test]
+    fn test_damaged_input_is_refused() {
+        let compressed = compress(&b"damaged snappy ".repeat(1000)).unwrap();
+        for len in 0..compressed.len() {
+            assert!(decompress(&compressed[..len], &mut Vec::new()).is_err(), "{} bytes", len);
+        }
+        for at in 0..compressed.len() {
+            let mut damaged = compressed.clone();
+            damaged[at] ^= 0x5a;
+            let _ = decompress(&damaged, &mut Vec::new());
+        }
+
+        // A claim far beyond what follows, and copies from before the start
+        assert!(decompress(&[0xff, 0xff, 0xff, 0xff, 0x0f, 0x00, b'x'], &mut Vec::new()).is_err());
+        assert!(decompress(&[0x04, 0x01, 0x01], &mut Vec::new()).is_err());
+        assert!(decompress(&[0x05, 0x00, b'x', 0x01, 0x00], &mut Vec::new()).is_err());
+        assert!(decompress(&[0xff, 0xff, 0xff, 0xff, 0x1f], &mut Vec::new()).is_err());
+    }
+}
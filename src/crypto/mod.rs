@@ -0,0 +1,746 @@
+use age::secrecy::SecretString;
+use argon2::Argon2;
+use base64::Engine;
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit};
+use rand::RngCore;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::iter;
+use std::path::Path;
+use zeroize::{Zeroize, Zeroizing};
+use crate::{Result, ShrLinkError};
+
+pub const KEY_SIZE: usize = 32;
+pub const AGE_MAGIC: &[u8] = b"age-encryption.org/v1";
+pub const SEALED_MAGIC: &[u8] = b"shr-sealed/v1\n";
+// A sealed bundle whose key comes from a password; the KDF header sits between magic and nonce
+pub const LOCKED_MAGIC: &[u8] = b"shr-locked/v1\n";
+// Read instead of prompting, for scripts and CI
+pub const PASSWORD_ENV: &str = "SHR_PASSWORD";
+
+// STREAM construction (BE32): a 7-byte random nonce prefix, then segments that each carry a
+// 16-byte tag, the last one flagged so cutting whole segments off the end is detected
+const SEAL_NONCE_PREFIX_SIZE: usize = 7;
+const SEAL_SEGMENT_SIZE: usize = 64 * 1024;
+const SEAL_TAG_SIZE: usize = 16;
+// The key travels in the share URL fragment, which browsers and HTTP clients never send
+const URL_KEY_PARAM: &str = "k=";
+// memory, iterations and parallelism as u32 LE, then the salt and a password check value
+const KDF_SALT_SIZE: usize = 16;
+const KDF_CHECK_SIZE: usize = 32;
+const LOCKED_HEADER_SIZE: usize = 3 * 4 + KDF_SALT_SIZE + KDF_CHECK_SIZE;
+// The header is chosen by whoever made the bundle, so a receiver won't spend more than this
+const MAX_KDF_MEMORY_KIB: u32 = 1024 * 1024;
+const MAX_KDF_ITERATIONS: u32 = 64;
+const MAX_KDF_PARALLELISM: u32 = 16;
+
+// Passphrases are wiped from the heap as soon as they go out of scope
+pub type Passphrase = Zeroizing<String>;
+
+pub struct SecretKey([u8; KEY_SIZE]);
+
+impl SecretKey {
+    pub fn from_bytes(bytes: [u8; KEY_SIZE]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn generate() -> Self {
+        let mut key = Self([0u8; KEY_SIZE]);
+        rand::rngs::OsRng.fill_bytes(&mut key.0);
+        key
+    }
+
+    pub fn expose_secret(&self) -> &[u8; KEY_SIZE] {
+        &self.0
+    }
+}
+
+impl Zeroize for SecretKey {
+    fn zeroize(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretKey(<redacted>)")
+    }
+}
+
+// Argon2id cost; the defaults are the OWASP minimum, which phones and small boards can manage
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            memory_kib: argon2::Params::DEFAULT_M_COST,
+            iterations: argon2::Params::DEFAULT_T_COST,
+            parallelism: argon2::Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl KdfParams {
+    pub fn validate(&self) -> Result<()> {
+        self.argon2().map(|_| ())
+    }
+
+    fn argon2(&self) -> Result<Argon2<'static>> {
+        let params = argon2::Params::new(self.memory_kib, self.iterations, self.parallelism, Some(KEY_SIZE))
+            .map_err(|e| ShrLinkError::InvalidInput(format!("Invalid KDF parameters: {}", e)))?;
+        Ok(Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params))
+    }
+
+    fn encode(&self) -> [u8; 12] {
+        let mut bytes = [0u8; 12];
+        bytes[0..4].copy_from_slice(&self.memory_kib.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.iterations.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.parallelism.to_le_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Self {
+        let word = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        Self { memory_kib: word(0), iterations: word(4), parallelism: word(8) }
+    }
+}
+
+// One Argon2id run yields both the bundle key and a value stored in the header, so a wrong
+// password is told apart from a damaged bundle before any segment is opened
+fn derive_password_keys(password: &str, salt: &[u8], params: &KdfParams) -> Result<(SecretKey, [u8; KDF_CHECK_SIZE])> {
+    let mut master = Zeroizing::new([0u8; KEY_SIZE]);
+    params
+        .argon2()?
+        .hash_password_into(password.as_bytes(), salt, &mut master[..])
+        .map_err(|e| ShrLinkError::Encryption(format!("Key derivation failed: {}", e)))?;
+
+    let key = SecretKey::from_bytes(blake3::derive_key("shrlink locked bundle v1 key", &master[..]));
+    let check = blake3::derive_key("shrlink locked bundle v1 check", &master[..]);
+    Ok((key, check))
+}
+
+#[cfg(feature = "cli")]
+pub fn prompt_passphrase(prompt: &str) -> Result<Passphrase> {
+    // rpassword hands back the buffer it read into, which is moved straight into the wrapper
+    let passphrase = Zeroizing::new(rpassword::prompt_password(prompt)?);
+
+    if passphrase.is_empty() {
+        return Err(ShrLinkError::InvalidInput("Passphrase must not be empty".to_string()));
+    }
+
+    Ok(passphrase)
+}
+
+// SHR_PASSWORD wins so scripts never block on a prompt; senders type it twice to catch typos
+#[cfg(feature = "cli")]
+pub fn read_password(confirm: bool) -> Result<Passphrase> {
+    if let Some(password) = std::env::var_os(PASSWORD_ENV).filter(|v| !v.is_empty()) {
+        return password
+            .into_string()
+            .map(Zeroizing::new)
+            .map_err(|_| ShrLinkError::InvalidInput(format!("{} is not valid UTF-8", PASSWORD_ENV)));
+    }
+
+    let password = prompt_passphrase("Password: ")?;
+    if confirm && *prompt_passphrase("Confirm password: ")? != *password {
+        return Err(ShrLinkError::InvalidInput("Passwords do not match".to_string()));
+    }
+    Ok(password)
+}
+
+pub enum AgeKey<'a> {
+    IdentityFile(&'a Path),
+    Passphrase(&'a Passphrase),
+}
+
+pub fn is_age_encrypted(data: &[u8]) -> bool {
+    data.starts_with(AGE_MAGIC)
+}
+
+pub fn parse_age_recipients(recipients: &[String]) -> Result<Vec<age::x25519::Recipient>> {
+    recipients
+        .iter()
+        .map(|r| {
+            r.trim()
+                .parse::<age::x25519::Recipient>()
+                .map_err(|e| ShrLinkError::InvalidInput(format!("Invalid age recipient {}: {}", r, e)))
+        })
+        .collect()
+}
+
+// Everything written is encrypted with the age STREAM construction, one 64 KiB segment at a
+// time; the caller must `finish()` it to write the final segment
+pub fn age_writer<W: Write>(writer: W, recipients: &[age::x25519::Recipient]) -> Result<age::stream::StreamWriter<W>> {
+    let encryptor = age::Encryptor::with_recipients(recipients.iter().map(|r| r as &dyn age::Recipient))
+        .map_err(|e| ShrLinkError::Encryption(e.to_string()))?;
+
+    Ok(encryptor.wrap_output(writer)?)
+}
+
+// Streams `reader` through the age STREAM construction, so only one 64 KiB segment is held at a time
+pub fn age_encrypt<R: Read, W: Write>(reader: &mut R, writer: W, recipients: &[age::x25519::Recipient]) -> Result<u64> {
+    let mut output = age_writer(writer, recipients)?;
+    let written = io::copy(reader, &mut output)?;
+    output.finish()?;
+
+    Ok(written)
+}
+
+// The plaintext of the age payload `reader` holds, decrypted one 64 KiB segment at a time as
+// it's read, so a download can be decrypted as it arrives
+pub fn age_reader<R: BufRead>(reader: R, key: AgeKey<'_>) -> Result<age::stream::StreamReader<R>> {
+    decrypt_age(age_decryptor(reader)?, key)
+}
+
+// As `age_reader`, with the key picked once the header says how the payload was encrypted: the
+// identity file if there is one, otherwise a passphrase from `passphrase`, which is only asked
+// for when the payload was encrypted with one
+pub fn age_reader_with<R: BufRead>(reader: R, identity: Option<&Path>, passphrase: impl FnOnce() -> Result<Passphrase>) -> Result<age::stream::StreamReader<R>> {
+    let decryptor = age_decryptor(reader)?;
+    match identity {
+        Some(path) => decrypt_age(decryptor, AgeKey::IdentityFile(path)),
+        None if decryptor.is_scrypt() => decrypt_age(decryptor, AgeKey::Passphrase(&passphrase()?)),
+        None => Err(ShrLinkError::Encryption("Bundle is age-encrypted to a recipient, pass --identity <keyfile>".to_string())),
+    }
+}
+
+fn age_decryptor<R: BufRead>(reader: R) -> Result<age::Decryptor<R>> {
+    age::Decryptor::new_buffered(reader).map_err(|e| ShrLinkError::Encryption(format!("Not a valid age file: {}", e)))
+}
+
+fn decrypt_age<R: BufRead>(decryptor: age::Decryptor<R>, key: AgeKey<'_>) -> Result<age::stream::StreamReader<R>> {
+    let plaintext = match key {
+        AgeKey::IdentityFile(path) => {
+            if decryptor.is_scrypt() {
+                return Err(ShrLinkError::Encryption("Payload is passphrase-encrypted, an identity file cannot decrypt it".to_string()));
+            }
+
+            let file = std::fs::File::open(path)?;
+            let identities = age::IdentityFile::from_buffer(BufReader::new(file))?
+                .into_identities()
+                .map_err(|e| ShrLinkError::Encryption(format!("Invalid identity file {}: {}", path.display(), e)))?;
+
+            decryptor.decrypt(identities.iter().map(|i| i.as_ref() as &dyn age::Identity))
+        }
+        AgeKey::Passphrase(passphrase) => {
+            if !decryptor.is_scrypt() {
+                return Err(ShrLinkError::Encryption("Payload is encrypted to recipients, pass --identity to decrypt it".to_string()));
+            }
+
+            let identity = age::scrypt::Identity::new(SecretString::from(passphrase.to_string()));
+            decryptor.decrypt(iter::once(&identity as &dyn age::Identity))
+        }
+    }
+    .map_err(|e| ShrLinkError::Encryption(format!("Failed to decrypt age payload: {}", e)))?;
+
+    Ok(plaintext)
+}
+
+pub fn age_decrypt<R: BufRead, W: Write>(reader: R, writer: &mut W, key: AgeKey<'_>) -> Result<u64> {
+    let mut plaintext = age_reader(reader, key)?;
+    Ok(io::copy(&mut plaintext, writer)?)
+}
+
+// Encrypts a body that's handed over a piece at a time, such as an upload's frames. Each piece
+// comes back as whatever ciphertext has been sealed so far, which trails the plaintext by less
+// than a segment
+pub struct AgeStream {
+    writer: age::stream::StreamWriter<Sealed>,
+    sealed: Sealed,
+}
+
+// Where the writer leaves its ciphertext until it's taken
+#[derive(Clone, Default)]
+struct Sealed(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl Sealed {
+    fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+impl Write for Sealed {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AgeStream {
+    pub fn new(recipients: &[age::x25519::Recipient]) -> Result<Self> {
+        let sealed = Sealed::default();
+        Ok(Self { writer: age_writer(sealed.clone(), recipients)?, sealed })
+    }
+
+    pub fn seal(&mut self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        self.writer.write_all(plaintext)?;
+        Ok(self.sealed.take())
+    }
+
+    // The final segment, which has to follow everything else
+    pub fn finish(self) -> Result<Vec<u8>> {
+        self.writer.finish()?;
+        Ok(self.sealed.take())
+    }
+}
+
+pub fn age_requires_passphrase(data: &[u8]) -> bool {
+    age::Decryptor::new_buffered(data)
+        .map(|d| d.is_scrypt())
+        .unwrap_or(false)
+}
+
+pub fn is_sealed(data: &[u8]) -> bool {
+    data.starts_with(SEALED_MAGIC)
+}
+
+pub fn is_locked(data: &[u8]) -> bool {
+    data.starts_with(LOCKED_MAGIC)
+}
+
+// Encrypts everything written with ChaCha20-Poly1305 under `key`, one segment at a time; the
+// caller must `finish()` it to write the final segment
+pub struct SealWriter<W: Write> {
+    writer: W,
+    encryptor: EncryptorBE32<ChaCha20Poly1305>,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> SealWriter<W> {
+    pub fn new(mut writer: W, key: &SecretKey) -> Result<Self> {
+        writer.write_all(SEALED_MAGIC)?;
+        Self::begin(writer, key)
+    }
+
+    // Derives the key from `password` under a fresh salt, which goes in the header with `params`
+    pub fn with_password(mut writer: W, password: &str, params: &KdfParams) -> Result<Self> {
+        let mut salt = [0u8; KDF_SALT_SIZE];
+        rand::rngs::OsRng.fill_bytes(&mut salt);
+        let (key, check) = derive_password_keys(password, &salt, params)?;
+
+        writer.write_all(LOCKED_MAGIC)?;
+        writer.write_all(&params.encode())?;
+        writer.write_all(&salt)?;
+        writer.write_all(&check)?;
+        Self::begin(writer, &key)
+    }
+
+    fn begin(mut writer: W, key: &SecretKey) -> Result<Self> {
+        let mut nonce_prefix = [0u8; SEAL_NONCE_PREFIX_SIZE];
+        rand::rngs::OsRng.fill_bytes(&mut nonce_prefix);
+        writer.write_all(&nonce_prefix)?;
+
+        let cipher = ChaCha20Poly1305::new(key.expose_secret().into());
+        Ok(Self {
+            writer,
+            encryptor: EncryptorBE32::from_aead(cipher, (&nonce_prefix).into()),
+            buffer: Vec::with_capacity(SEAL_SEGMENT_SIZE),
+        })
+    }
+
+    pub fn finish(self) -> Result<W> {
+        let Self { mut writer, encryptor, buffer } = self;
+        let last = encryptor
+            .encrypt_last(&buffer[..])
+            .map_err(|_| ShrLinkError::Encryption("Failed to seal final segment".to_string()))?;
+        writer.write_all(&last)?;
+        writer.flush()?;
+        Ok(writer)
+    }
+}
+
+impl<W: Write> Write for SealWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(data);
+
+        // A full buffer is only sealed once more arrives, so the last segment is never empty
+        // unless everything is
+        let mut start = 0;
+        while self.buffer.len() - start > SEAL_SEGMENT_SIZE {
+            let segment = self.encryptor
+                .encrypt_next(&self.buffer[start..start + SEAL_SEGMENT_SIZE])
+                .map_err(|_| io::Error::other("Failed to seal segment"))?;
+            self.writer.write_all(&segment)?;
+            start += SEAL_SEGMENT_SIZE;
+        }
+        self.buffer.drain(..start);
+
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// How a bundle is protected beyond the transport
+pub enum Encryption {
+    // Only holders of a matching identity can read it
+    Age(Vec<age::x25519::Recipient>),
+    // A fresh key per send, handed over in the share URL's fragment
+    UrlKey(SecretKey),
+    // A key derived from a password the recipient is told some other way
+    Password { password: Passphrase, params: KdfParams },
+}
+
+// What a bundle is written through on its way out; `finish()` writes any final segment
+pub enum EncryptingWriter<W: Write> {
+    Plain(W),
+    Age(age::stream::StreamWriter<W>),
+    Sealed(SealWriter<W>),
+}
+
+impl<W: Write> EncryptingWriter<W> {
+    pub fn new(writer: W, encryption: Option<&Encryption>) -> Result<Self> {
+        Ok(match encryption {
+            None => Self::Plain(writer),
+            Some(Encryption::Age(recipients)) => Self::Age(age_writer(writer, recipients)?),
+            Some(Encryption::UrlKey(key)) => Self::Sealed(SealWriter::new(writer, key)?),
+            Some(Encryption::Password { password, params }) => Self::Sealed(SealWriter::with_password(writer, password, params)?),
+        })
+    }
+
+    pub fn finish(self) -> Result<W> {
+        match self {
+            Self::Plain(writer) => Ok(writer),
+            Self::Age(writer) => Ok(writer.finish()?),
+            Self::Sealed(writer) => writer.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for EncryptingWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(writer) => writer.write(data),
+            Self::Age(writer) => writer.write(data),
+            Self::Sealed(writer) => writer.write(data),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(writer) => writer.flush(),
+            Self::Age(writer) => writer.flush(),
+            Self::Sealed(writer) => writer.flush(),
+        }
+    }
+}
+
+pub fn seal(plaintext: &[u8], key: &SecretKey) -> Result<Vec<u8>> {
+    let mut writer = SealWriter::new(Vec::with_capacity(plaintext.len() + plaintext.len() / 1024 + 64), key)?;
+    writer.write_all(plaintext)?;
+    writer.finish()
+}
+
+// A wrong key, a flipped bit and a missing tail all fail the same tag check, so they share an error
+pub fn unseal(data: &[u8], key: &SecretKey) -> Result<Vec<u8>> {
+    let body = data
+        .strip_prefix(SEALED_MAGIC)
+        .ok_or_else(|| ShrLinkError::InvalidInput("Not a sealed bundle".to_string()))?;
+    open_segments(body, key, "wrong key, or the bundle was altered or truncated")
+}
+
+// Only the password is checked up front; after that every failure means the bundle itself is bad
+pub fn unlock(data: &[u8], password: &str) -> Result<Vec<u8>> {
+    let body = data
+        .strip_prefix(LOCKED_MAGIC)
+        .ok_or_else(|| ShrLinkError::InvalidInput("Not a password-protected bundle".to_string()))?;
+    if body.len() < LOCKED_HEADER_SIZE {
+        return Err(ShrLinkError::Decryption("sealed bundle is truncated".to_string()));
+    }
+    let (header, body) = body.split_at(LOCKED_HEADER_SIZE);
+
+    let params = KdfParams::decode(&header[..12]);
+    if params.memory_kib > MAX_KDF_MEMORY_KIB || params.iterations > MAX_KDF_ITERATIONS || params.parallelism > MAX_KDF_PARALLELISM {
+        return Err(ShrLinkError::Decryption(format!(
+            "the bundle asks for an unreasonably expensive key derivation ({} KiB, {} iterations, {} lanes)",
+            params.memory_kib, params.iterations, params.parallelism
+        )));
+    }
+    let salt = &header[12..12 + KDF_SALT_SIZE];
+    let stored: [u8; KDF_CHECK_SIZE] = header[12 + KDF_SALT_SIZE..].try_into().unwrap();
+
+    let (key, check) = derive_password_keys(password, salt, &params)?;
+    // blake3::Hash compares in constant time
+    if blake3::Hash::from(check) != blake3::Hash::from(stored) {
+        return Err(ShrLinkError::WrongPassword);
+    }
+
+    open_segments(body, &key, "the bundle was altered or truncated")
+}
+
+// `body` is the nonce prefix followed by the segments
+fn open_segments(body: &[u8], key: &SecretKey, failure: &str) -> Result<Vec<u8>> {
+    if body.len() < SEAL_NONCE_PREFIX_SIZE + SEAL_TAG_SIZE {
+        return Err(ShrLinkError::Decryption("sealed bundle is truncated".to_string()));
+    }
+    let (nonce_prefix, ciphertext) = body.split_at(SEAL_NONCE_PREFIX_SIZE);
+
+    let cipher = ChaCha20Poly1305::new(key.expose_secret().into());
+    let mut decryptor = DecryptorBE32::from_aead(cipher, nonce_prefix.into());
+    let failed = || ShrLinkError::Decryption(failure.to_string());
+
+    let mut plaintext = Vec::with_capacity(ciphertext.len());
+    let mut segments = ciphertext.chunks(SEAL_SEGMENT_SIZE + SEAL_TAG_SIZE).peekable();
+    while let Some(segment) = segments.next() {
+        if segments.peek().is_some() {
+            plaintext.extend_from_slice(&decryptor.decrypt_next(segment).map_err(|_| failed())?);
+        } else {
+            plaintext.extend_from_slice(&decryptor.decrypt_last(segment).map_err(|_| failed())?);
+            break;
+        }
+    }
+
+    Ok(plaintext)
+}
+
+pub fn url_with_key(url: &str, key: &SecretKey) -> String {
+    let encoded = Zeroizing::new(base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(key.expose_secret()));
+    let separator = if url.contains('#') { '&' } else { '#' };
+    format!("{}{}{}{}", url, separator, URL_KEY_PARAM, encoded.as_str())
+}
+
+// Splits `#k=<key>` off a share URL; anything else in the fragment is ignored and dropped
+pub fn split_url_key(url: &str) -> Result<(&str, Option<SecretKey>)> {
+    let Some((base, fragment)) = url.split_once('#') else {
+        return Ok((url, None));
+    };
+    let Some(encoded) = fragment.split('&').find_map(|p| p.strip_prefix(URL_KEY_PARAM)) else {
+        return Ok((base, None));
+    };
+
+    let invalid = || ShrLinkError::InvalidInput("The key in the share URL is malformed".to_string());
+    let decoded = Zeroizing::new(base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(encoded).map_err(|_| invalid())?);
+    let bytes: [u8; KEY_SIZE] = decoded.as_slice().try_into().map_err(|_| invalid())?;
+    Ok((base, Some(SecretKey::from_bytes(bytes))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::ManuallyDrop;
+
+    #[test]
+    fn test_secret_key_zeroed_on_drop() {
+        let mut key = ManuallyDrop::new(SecretKey::from_bytes([0xAB; KEY_SIZE]));
+        let storage: *const [u8; KEY_SIZE] = key.expose_secret();
+
+        // The wrapper keeps the storage alive after Drop runs so it can be inspected
+        unsafe { ManuallyDrop::drop(&mut key) };
+        assert_eq!(unsafe { std::ptr::read_volatile(storage) }, [0u8; KEY_SIZE]);
+    }
+
+    #[test]
+    fn test_secret_key_debug_is_redacted() {
+        let key = SecretKey::from_bytes([0x42; KEY_SIZE]);
+        let debug = format!("{:?}", key);
+
+        assert_eq!(debug, "SecretKey(<redacted>)");
+        assert!(!debug.contains("42"));
+    }
+
+    #[test]
+    fn test_generated_keys_differ() {
+        let a = SecretKey::generate();
+        let b = SecretKey::generate();
+        assert_ne!(a.expose_secret(), b.expose_secret());
+    }
+
+    #[test]
+    fn test_seal_roundtrip() {
+        let key = SecretKey::generate();
+        // Empty, under one segment, exactly one, and several with a partial tail
+        for len in [0, 100, SEAL_SEGMENT_SIZE, 3 * SEAL_SEGMENT_SIZE + 17] {
+            let plaintext: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let sealed = seal(&plaintext, &key).unwrap();
+            assert!(is_sealed(&sealed));
+            assert_eq!(unseal(&sealed, &key).unwrap(), plaintext, "len {}", len);
+        }
+
+        // Same plaintext and key, fresh nonce every time
+        assert_ne!(seal(b"same", &key).unwrap(), seal(b"same", &key).unwrap());
+    }
+
+    #[test]
+    fn test_unseal_rejects_wrong_key_and_tampering() {
+        let key = SecretKey::generate();
+        let plaintext = b"confidential ".repeat(20_000);
+        let sealed = seal(&plaintext, &key).unwrap();
+
+        let wrong = unseal(&sealed, &SecretKey::generate());
+        assert!(matches!(wrong, Err(ShrLinkError::Decryption(_))));
+
+        let mut flipped = sealed.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        assert!(matches!(unseal(&flipped, &key), Err(ShrLinkError::Decryption(_))));
+
+        // Cut mid-segment, and cleanly at a segment boundary so the earlier segments still verify
+        let header = SEALED_MAGIC.len() + SEAL_NONCE_PREFIX_SIZE;
+        for cut in [sealed.len() - 1, header + SEAL_SEGMENT_SIZE + SEAL_TAG_SIZE, header + 3] {
+            assert!(matches!(unseal(&sealed[..cut], &key), Err(ShrLinkError::Decryption(_))), "cut at {}", cut);
+        }
+    }
+
+    fn lock(plaintext: &[u8], password: &str, params: &KdfParams) -> Vec<u8> {
+        let mut writer = SealWriter::with_password(Vec::new(), password, params).unwrap();
+        writer.write_all(plaintext).unwrap();
+        writer.finish().unwrap()
+    }
+
+    // Cheap enough that the tests don't spend their time in Argon2
+    const FAST_KDF: KdfParams = KdfParams { memory_kib: 64, iterations: 1, parallelism: 1 };
+
+    #[test]
+    fn test_password_roundtrip() {
+        let plaintext = b"locked ".repeat(20_000);
+        let locked = lock(&plaintext, "correct horse", &FAST_KDF);
+        assert!(is_locked(&locked));
+        assert!(!is_sealed(&locked));
+        assert_eq!(unlock(&locked, "correct horse").unwrap(), plaintext);
+
+        // Fresh salt every time, so the same password never gives the same key twice
+        let header = LOCKED_MAGIC.len() + LOCKED_HEADER_SIZE;
+        assert_ne!(lock(b"same", "pw", &FAST_KDF)[..header], lock(b"same", "pw", &FAST_KDF)[..header]);
+    }
+
+    #[test]
+    fn test_wrong_password_is_distinguished_from_tampering() {
+        let locked = lock(b"confidential", "correct horse", &FAST_KDF);
+        assert!(matches!(unlock(&locked, "battery staple"), Err(ShrLinkError::WrongPassword)));
+
+        let mut flipped = locked.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        assert!(matches!(unlock(&flipped, "correct horse"), Err(ShrLinkError::Decryption(_))));
+        assert!(matches!(unlock(&locked[..LOCKED_MAGIC.len() + 10], "correct horse"), Err(ShrLinkError::Decryption(_))));
+    }
+
+    #[test]
+    fn test_unlock_uses_params_from_header() {
+        // Whatever the receiver has configured, the sender's parameters are the ones that open it
+        let params = KdfParams { memory_kib: 256, iterations: 3, parallelism: 2 };
+        let locked = lock(b"tuned for a small board", "pw", &params);
+        assert_eq!(KdfParams::decode(&locked[LOCKED_MAGIC.len()..]), params);
+        assert_eq!(unlock(&locked, "pw").unwrap(), b"tuned for a small board");
+
+        // A header asking for absurd work is refused before any of it is done
+        let mut hostile = locked.clone();
+        hostile[LOCKED_MAGIC.len()..LOCKED_MAGIC.len() + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(unlock(&hostile, "pw"), Err(ShrLinkError::Decryption(_))));
+
+        assert!(KdfParams { iterations: 0, ..params }.validate().is_err());
+        assert!(KdfParams::default().validate().is_ok());
+    }
+
+    #[test]
+    fn test_url_key_roundtrip() {
+        let key = SecretKey::generate();
+        let url = url_with_key("https://example.com/files/x.shr", &key);
+        assert!(url.starts_with("https://example.com/files/x.shr#k="));
+
+        let (base, parsed) = split_url_key(&url).unwrap();
+        assert_eq!(base, "https://example.com/files/x.shr");
+        assert_eq!(parsed.unwrap().expose_secret(), key.expose_secret());
+
+        assert!(split_url_key("shr://peer/hash").unwrap().1.is_none());
+        assert_eq!(split_url_key("shr://peer/hash#other=1").unwrap().0, "shr://peer/hash");
+        assert!(split_url_key("shr://peer/hash#k=tooshort").is_err());
+    }
+
+    #[test]
+    fn test_age_encrypt_decrypts_with_age_crate() {
+        let identity = age::x25519::Identity::generate();
+        let plaintext = b"bundle bytes for the age format".repeat(10_000);
+
+        let mut ciphertext = Vec::new();
+        age_encrypt(&mut &plaintext[..], &mut ciphertext, &[identity.to_public()]).unwrap();
+        assert!(is_age_encrypted(&ciphertext));
+        assert!(!age_requires_passphrase(&ciphertext));
+
+        let decrypted = age::decrypt(&identity, &ciphertext).unwrap();
+        assert_eq!(decrypted, plaintext);
+    }
+
+    #[test]
+    fn test_age_stream_seals_as_it_goes() {
+        let identity = age::x25519::Identity::generate();
+        let plaintext = b"frames handed over one at a time".repeat(10_000);
+
+        let mut stream = AgeStream::new(&[identity.to_public()]).unwrap();
+        let mut ciphertext = Vec::new();
+        let mut sent = 0;
+        for piece in plaintext.chunks(5000) {
+            ciphertext.extend(stream.seal(piece).unwrap());
+            sent += piece.len();
+            // Never more than a segment held back
+            assert!(ciphertext.len() + 64 * 1024 >= sent, "{} of {} out", ciphertext.len(), sent);
+        }
+        ciphertext.extend(stream.finish().unwrap());
+
+        let decrypted = age::decrypt(&identity, &ciphertext).unwrap();
+        assert_eq!(decrypted, plaintext);
+    }
+
+    #[test]
+    fn test_age_decrypt_reads_age_crate_output() {
+        let identity = age::x25519::Identity::generate();
+        let plaintext = b"written by the reference implementation".to_vec();
+        let ciphertext = age::encrypt(&identity.to_public(), &plaintext).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let key_path = dir.path().join("key.txt");
+        std::fs::write(&key_path, format!("# test key\n{}\n", age::secrecy::ExposeSecret::expose_secret(&identity.to_string()))).unwrap();
+
+        let mut decrypted = Vec::new();
+        age_decrypt(&ciphertext[..], &mut decrypted, AgeKey::IdentityFile(&key_path)).unwrap();
+        assert_eq!(decrypted, plaintext);
+
+        let other = dir.path().join("other.txt");
+        std::fs::write(&other, age::secrecy::ExposeSecret::expose_secret(&age::x25519::Identity::generate().to_string())).unwrap();
+        assert!(age_decrypt(&ciphertext[..], &mut Vec::new(), AgeKey::IdentityFile(&other)).is_err());
+    }
+
+    #[test]
+    fn test_age_passphrase_payload() {
+        let plaintext = b"passphrase protected".to_vec();
+        let mut recipient = age::scrypt::Recipient::new(SecretString::from("correct horse".to_string()));
+        recipient.set_work_factor(10);
+
+        let encryptor = age::Encryptor::with_recipients(iter::once(&recipient as &dyn age::Recipient)).unwrap();
+        let mut ciphertext = Vec::new();
+        let mut writer = encryptor.wrap_output(&mut ciphertext).unwrap();
+        writer.write_all(&plaintext).unwrap();
+        writer.finish().unwrap();
+        assert!(age_requires_passphrase(&ciphertext));
+
+        let passphrase = Passphrase::new("correct horse".to_string());
+        let mut decrypted = Vec::new();
+        age_decrypt(&ciphertext[..], &mut decrypted, AgeKey::Passphrase(&passphrase)).unwrap();
+        assert_eq!(decrypted, plaintext);
+
+        let wrong = Passphrase::new("battery staple".to_string());
+        assert!(age_decrypt(&ciphertext[..], &mut Vec::new(), AgeKey::Passphrase(&wrong)).is_err());
+
+        // Asked for only once the header says scrypt, then read as a stream
+        let mut reader = age_reader_with(&ciphertext[..], None, || Ok(Passphrase::new("correct horse".to_string()))).unwrap();
+        let mut streamed = Vec::new();
+        reader.read_to_end(&mut streamed).unwrap();
+        assert_eq!(streamed, plaintext);
+    }
+}
@@ -1,3 +1,9 @@
+#[cfg(feature = "p2p")]
+use libp2p::{Multiaddr, PeerId};
+#[cfg(feature = "p2p")]
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "p2p")]
+use std::time::Duration;
 use thiserror::Error;
 
 pub type Result<T> = std::result::Result<T, ShrLinkError>;
@@ -5,7 +11,7 @@ pub type Result<T> = std::result::Result<T, ShrLinkError>;
 #[derive(Error, Debug)]
 pub enum ShrLinkError {
     #[error("IO error: {0}")]
-    Io(#[from] std::io::Error),
+    Io(#[source] std::io::Error),
     
     #[error("Compression error: {0}")]
     Compression(String),
@@ -16,21 +22,125 @@ pub enum ShrLinkError {
     #[error("P2P error: {0}")]
     P2P(String),
     
+    #[cfg(feature = "p2p")]
+    #[error("P2P dial failed: {0}")]
+    Dial(Box<DialError>),
+    
+    // Sender and receiver can't agree on how to carry the chunks; what stands in the way says
+    // what to change
+    #[cfg(feature = "p2p")]
+    #[error("Incompatible peer: {0}")]
+    Incompatible(Box<Incompatibility>),
+    
     #[error("HTTP error: {0}")]
     Http(String),
     
     #[error("Configuration error: {0}")]
     Config(#[from] toml::de::Error),
     
-    #[error("Hash mismatch: expected {expected}, got {actual}")]
-    HashMismatch { expected: String, actual: String },
+    #[error("Encryption error: {0}")]
+    Encryption(String),
+    
+    // Authentication of ciphertext failed: the key is wrong or the data was altered or cut short
+    #[error("Decryption failed: {0}")]
+    Decryption(String),
+    
+    // Checked against the password-protected bundle's header before anything is decrypted
+    #[error("Wrong password")]
+    WrongPassword,
+    
+    // `context` says what was hashed: a chunk, or a whole reassembled file
+    #[error("Hash mismatch for {context}: expected {expected}, got {actual}")]
+    HashMismatch { context: String, expected: String, actual: String },
+    
+    // The fallback server turned the request away (401 or 403) for want of its token
+    #[error("Not authorized: {0}. Set fallback.auth_token (or SHRLINK_FALLBACK_TOKEN) to the token the server was started with, and fallback.auth_downloads = true if it wants it for downloads too")]
+    Unauthorized(String),
+    
+    // 410 Gone, from a one-time link that was already downloaded or one past its expiry
+    #[error("This link was already used or has expired")]
+    LinkGone,
+    
+    // A fallback download whose length or whole-bundle hash wasn't what the server said; the
+    // reason says which
+    #[error("Download corrupted or truncated, try again: {0}")]
+    CorruptDownload(String),
+    
+    // The fallback has no file at the URL given, as `shr delete` reports it
+    #[error("Not found: {0} was already deleted, has expired or was never uploaded")]
+    NotFound(String),
     
     #[error("Timeout: {0}")]
     Timeout(String),
     
+    // Stopped on request, e.g. by Ctrl-C, after cleaning up whatever was left half done
+    #[error("Cancelled")]
+    Cancelled,
+    
     #[error("Invalid input: {0}")]
     InvalidInput(String),
     
     #[error("Other error: {0}")]
     Other(#[from] anyhow::Error),
 }
+
+// Errors that had to pass through `std::io::Read` on their way out come back as themselves
+impl From<std::io::Error> for ShrLinkError {
+    fn from(e: std::io::Error) -> Self {
+        if e.get_ref().is_some_and(|inner| inner.is::<ShrLinkError>()) {
+            *e.into_inner().and_then(|inner| inner.downcast().ok()).expect("checked above")
+        } else {
+            ShrLinkError::Io(e)
+        }
+    }
+}
+
+#[cfg(feature = "p2p")]
+impl From<DialError> for ShrLinkError {
+    fn from(e: DialError) -> Self {
+        ShrLinkError::Dial(Box::new(e))
+    }
+}
+
+#[cfg(feature = "p2p")]
+impl From<Incompatibility> for ShrLinkError {
+    fn from(e: Incompatibility) -> Self {
+        ShrLinkError::Incompatible(Box::new(e))
+    }
+}
+
+#[cfg(feature = "p2p")]
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum DialError {
+    #[error("peer id mismatch: expected {expected}, remote authenticated as {actual}")]
+    PeerIdMismatch { expected: PeerId, actual: PeerId },
+    
+    #[error("connection refused by {0}")]
+    ConnectionRefused(Multiaddr),
+    
+    #[error("timed out after {0:?}")]
+    Timeout(Duration),
+    
+    #[error("address resolves to this peer")]
+    LocalPeer,
+    
+    #[error("transport error: {0}")]
+    Transport(String),
+}
+
+// Always put as the receiver having to take what the sender's chunks are, whichever side found it
+#[cfg(feature = "p2p")]
+#[derive(Error, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Incompatibility {
+    #[error("the sender speaks version {sender} of the chunk protocol and the receiver {receiver}")]
+    Version { sender: String, receiver: String },
+    
+    #[error("the chunks are compressed with {}, which the receiver can't decompress (it supports {})", unsupported.join(", "), supported.join(", "))]
+    Codecs { unsupported: Vec<String>, supported: Vec<String> },
+    
+    #[error("the chunks are hashed with {}, which the receiver can't check (it supports {})", unsupported.join(", "), supported.join(", "))]
+    Checksums { unsupported: Vec<String>, supported: Vec<String> },
+    
+    #[error("a chunk is {largest} bytes, and the receiver takes at most {max}")]
+    ChunkSize { largest: u64, max: u64 },
+}
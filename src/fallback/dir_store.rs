@@ -0,0 +1,285 @@
+use async_trait::async_trait;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_util::sync::CancellationToken;
+use crate::config::FallbackConfig;
+use crate::temp::{self, ScratchKind, TempGuard};
+use crate::verify;
+use crate::{Result, ShrLinkError};
+use super::{original_file_name, remote_file_name, verified_url, Body, BundleResponse, CleanupReport, FallbackStats, FallbackStore, FileInfo, UploadProgress};
+
+// Uploads are copied onto the share in blocks this size, each reported as it's written
+const COPY_BLOCK: usize = 1024 * 1024;
+
+// Writes uploads as files in `fallback.path`, a directory on a share both ends mount, and hands
+// out file:// URLs to them. Receivers read those straight off their own mount, with no server
+// in between; cleanup goes by each file's mtime
+pub struct DirStore {
+    dir: PathBuf,
+    config: FallbackConfig,
+    cancel: CancellationToken,
+    progress: Option<UploadProgress>,
+}
+
+impl DirStore {
+    pub async fn new(config: FallbackConfig) -> Result<Self> {
+        let Some(path) = config.path.clone() else {
+            return Err(ShrLinkError::InvalidInput("fallback.path must be set for the file backend".to_string()));
+        };
+        // Anyone who can see the share can read a file on it as often as they like
+        if config.download_once {
+            return Err(ShrLinkError::InvalidInput("One-time links need the HTTP fallback backend; a file on a share can be read any number of times".to_string()));
+        }
+
+        // Checked up front, rather than on the first upload after minutes of compression. The
+        // URLs handed out are absolute, so receivers find the file whatever their working directory
+        let dir = tokio::fs::canonicalize(&path).await.map_err(|e| fs_error("Opening fallback.path", &path, e))?;
+        if !tokio::fs::metadata(&dir).await.map_err(|e| fs_error("Opening fallback.path", &dir, e))?.is_dir() {
+            return Err(ShrLinkError::InvalidInput(format!("fallback.path {} is not a directory", dir.display())));
+        }
+
+        Ok(Self { dir, config, cancel: CancellationToken::new(), progress: None })
+    }
+
+    // Copies then stop between blocks once `cancel` fires and fail with
+    // `ShrLinkError::Cancelled`, leaving nothing behind on the share
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    pub fn with_upload_progress(mut self, progress: UploadProgress) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    // Only files directly in the directory can be named, so a URL can't reach anywhere else on
+    // the share
+    fn path_for_url(&self, url: &str) -> Result<PathBuf> {
+        let (url, _) = verify::split_url_hash(url)?;
+        let path = file_url_path(url)?;
+        let (Some(name), Some(parent)) = (path.file_name(), path.parent()) else {
+            return Err(ShrLinkError::InvalidInput(format!("Not a file fallback URL: {}", url)));
+        };
+        let parent = std::fs::canonicalize(parent).map_err(|e| fs_error("Opening", parent, e))?;
+        if parent != self.dir {
+            return Err(ShrLinkError::InvalidInput(format!("{} isn't in fallback.path ({})", url, self.dir.display())));
+        }
+        Ok(self.dir.join(name))
+    }
+
+    // The uploads in the directory with their metadata, leaving out anything else on the share.
+    // Copies still being written end in `.shr-tmp`, so they aren't counted either
+    fn uploads(&self) -> Result<Vec<(PathBuf, std::fs::Metadata)>> {
+        let entries = std::fs::read_dir(&self.dir).map_err(|e| fs_error("Listing", &self.dir, e))?;
+        let mut uploads = Vec::new();
+        for entry in entries {
+            let entry = entry.map_err(|e| fs_error("Listing", &self.dir, e))?;
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != "shr") {
+                continue;
+            }
+            // Gone since it was listed, most likely to a cleanup running elsewhere
+            let metadata = match entry.metadata() {
+                Ok(metadata) => metadata,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(fs_error("Reading", &path, e)),
+            };
+            if metadata.is_file() {
+                uploads.push((path, metadata));
+            }
+        }
+        Ok(uploads)
+    }
+
+    // As the S3 backend does, with `verified_uploads` the hash tree goes in front of the bundle.
+    // The copy is written beside its final name and renamed into place, so a receiver never
+    // reads half an upload
+    async fn copy_in(&self, source: &Path, target: &Path) -> Result<Option<blake3::Hash>> {
+        let (root, header) = if self.config.verified_uploads {
+            let source = source.to_path_buf();
+            let outboard = tokio::task::spawn_blocking(move || verify::outboard(std::io::BufReader::new(std::fs::File::open(&source)?)));
+            let (root, header) = outboard.await.map_err(|e| ShrLinkError::Other(e.into()))??;
+            (Some(root), header)
+        } else {
+            (None, Vec::new())
+        };
+
+        let staged = TempGuard::beside(target, ScratchKind::Temp).map_err(|e| match e {
+            ShrLinkError::Io(e) => fs_error("Writing to", &self.dir, e),
+            e => e,
+        })?;
+        let written = |e| fs_error("Writing", staged.path(), e);
+        let mut out = tokio::fs::File::create(staged.path()).await.map_err(written)?;
+        out.write_all(&header).await.map_err(written)?;
+
+        let mut input = tokio::fs::File::open(source).await?;
+        let mut block = vec![0u8; COPY_BLOCK];
+        loop {
+            let read = tokio::select! {
+                read = input.read(&mut block) => read?,
+                _ = self.cancel.cancelled() => return Err(ShrLinkError::Cancelled),
+            };
+            if read == 0 {
+                break;
+            }
+            out.write_all(&block[..read]).await.map_err(written)?;
+            if let Some(progress) = &self.progress {
+                progress(read as u64);
+            }
+        }
+        out.sync_all().await.map_err(written)?;
+
+        // Cleanup deletes what's older than `expiry_secs` by mtime, so an upload with its own
+        // expiry is dated to reach that age when it's due
+        if let Some(secs) = self.config.upload_expiry_secs {
+            let now = SystemTime::now();
+            let dated = match secs.checked_sub(self.config.expiry_secs) {
+                Some(later) => now.checked_add(Duration::from_secs(later)),
+                None => now.checked_sub(Duration::from_secs(self.config.expiry_secs - secs)),
+            };
+            if let Some(dated) = dated {
+                out.into_std().await.set_modified(dated).map_err(written)?;
+            }
+        }
+
+        staged.commit(target).map_err(|e| match e {
+            ShrLinkError::Io(e) => fs_error("Writing", target, e),
+            e => e,
+        })?;
+        Ok(root)
+    }
+}
+
+#[async_trait]
+impl FallbackStore for DirStore {
+    async fn upload_file(&self, path: &Path, original_name: Option<&str>) -> Result<String> {
+        let target = self.dir.join(remote_file_name(original_name));
+        let url = url::Url::from_file_path(&target)
+            .map_err(|_| ShrLinkError::InvalidInput(format!("{} can't be put in a file:// URL", target.display())))?
+            .to_string();
+
+        let root = self.copy_in(path, &target).await?;
+
+        tracing::info!("Uploaded {}", target.display());
+        Ok(verified_url(url, root))
+    }
+
+    async fn download_bundle(&self, url: &str) -> Result<(Vec<u8>, Option<String>)> {
+        let (url, root) = verify::split_url_hash(url)?;
+        open_file(url, root).await?.read().await
+    }
+
+    async fn delete_file(&self, url: &str) -> Result<bool> {
+        let path = self.path_for_url(url)?;
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(fs_error("Deleting", &path, e)),
+        }
+    }
+
+    // Scratch files left by copies that never finished go too, once they're a day old
+    async fn cleanup_old_files(&self, dry_run: bool) -> Result<CleanupReport> {
+        let now = SystemTime::now();
+        let max_age = Duration::from_secs(self.config.expiry_secs);
+
+        let mut deleted = Vec::new();
+        for (path, metadata) in self.uploads()? {
+            let age = metadata.modified().ok().and_then(|m| now.duration_since(m).ok()).unwrap_or_default();
+            if age <= max_age {
+                continue;
+            }
+            let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+            let file = FileInfo { name, size: metadata.len(), age_secs: age.as_secs() };
+            if dry_run {
+                deleted.push(file);
+                continue;
+            }
+            match tokio::fs::remove_file(&path).await {
+                Ok(()) => deleted.push(file),
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(fs_error("Deleting", &path, e)),
+            }
+        }
+        if dry_run {
+            return Ok(CleanupReport::new(deleted));
+        }
+        temp::sweep(&self.dir, temp::DEFAULT_SWEEP_TTL)?;
+
+        tracing::info!("Cleanup deleted {} files from {}", deleted.len(), self.dir.display());
+        Ok(CleanupReport::new(deleted))
+    }
+
+    async fn get_upload_stats(&self) -> Result<FallbackStats> {
+        let uploads = self.uploads()?;
+        Ok(FallbackStats {
+            total_files: uploads.len(),
+            total_bytes: uploads.iter().map(|(_, metadata)| metadata.len()).sum(),
+            files: None,
+        })
+    }
+}
+
+// The local path a file:// URL names. On Windows file://server/share/... is the UNC path
+// \\server\share\...; elsewhere only a local path will do, so the share has to be mounted and
+// named by where it's mounted
+pub fn file_url_path(url: &str) -> Result<PathBuf> {
+    let parsed = url::Url::parse(url).map_err(|e| ShrLinkError::InvalidInput(format!("{} is not a valid file URL: {}", url, e)))?;
+    if parsed.scheme() != "file" {
+        return Err(ShrLinkError::InvalidInput(format!("{} is not a file:// URL", url)));
+    }
+    parsed.to_file_path().map_err(|_| match parsed.host_str() {
+        Some(host) if !host.is_empty() && host != "localhost" => ShrLinkError::InvalidInput(format!(
+            "{} names a share on {}, which can only be opened by host on Windows; mount the share and use the path under the mount",
+            url, host
+        )),
+        _ => ShrLinkError::InvalidInput(format!("{} is not a path this machine can open", url)),
+    })
+}
+
+// The bundle at a file:// URL, to be read and checked against `root` as an HTTP body is
+pub(super) async fn open_file(url: &str, root: Option<blake3::Hash>) -> Result<BundleResponse> {
+    let path = file_url_path(url)?;
+    let file = tokio::fs::File::open(&path).await.map_err(|e| match e.kind() {
+        ErrorKind::NotFound => ShrLinkError::NotFound(url.to_string()),
+        _ => fs_error("Opening", &path, e),
+    })?;
+    let len = file.metadata().await.map_err(|e| fs_error("Opening", &path, e))?.len();
+    let original_name = path.file_name().and_then(|n| n.to_str()).and_then(original_file_name);
+
+    Ok(BundleResponse { body: Body::File(file, len), url: url.to_string(), original_name, root, bundle_hash: None, progress: None, head: Vec::new() })
+}
+
+// Names the path, and for what a share usually gets wrong, what to check
+fn fs_error(action: &str, path: &Path, error: std::io::Error) -> ShrLinkError {
+    let message = match error.kind() {
+        ErrorKind::NotFound => format!("{} {}: it doesn't exist; check the share is mounted", action, path.display()),
+        ErrorKind::PermissionDenied => format!("{} {}: permission denied; check this user's access to it on the share", action, path.display()),
+        _ => format!("{} {}: {}", action, path.display(), error),
+    };
+    ShrLinkError::Io(std::io::Error::new(error.kind(), message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_url_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("0b4e3c1a-8f7e-4d52-9a55-3b7c1f2e9d10_café report.shr");
+        let url = url::Url::from_file_path(&path).unwrap().to_string();
+        assert!(url.starts_with("file://") && url.contains("caf%C3%A9%20report"));
+        assert_eq!(file_url_path(&url).unwrap(), path);
+
+        assert!(file_url_path("https://example.com/a.shr").is_err());
+        #[cfg(unix)]
+        {
+            let unc = file_url_path("file://fileserver/share/shr/a.shr").unwrap_err().to_string();
+            assert!(unc.contains("names a share on fileserver"), "{}", unc);
+        }
+    }
+}
@@ -1,132 +1,1060 @@
-use std::time::Duration;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::future;
+use futures::stream::{self, BoxStream};
+use futures::{SinkExt, Stream, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
-use reqwest::multipart;
 use crate::{Result, ShrLinkError};
-use crate::config::FallbackConfig;
+use crate::config::{FallbackBackend, FallbackConfig};
+use crate::bundle::{Bundle, BundleItem, Decoded, FrameDecoder, Parity, ShrBundleReader};
+use crate::cache::ChunkCache;
 use crate::compression::CompressedChunk;
+use crate::filename;
+use crate::partial::PartialDownload;
+use crate::temp::{ScratchKind, TempGuard};
+use crate::verify::{self, VerifiedReader};
+
+pub mod dir_store;
+pub mod s3;
+#[cfg(feature = "s3")]
+pub mod s3_store;
+#[cfg(feature = "sftp")]
+pub mod sftp_store;
+
+const DEFAULT_ENDPOINT: &str = "http://localhost:8080";
+
+// Carries `upload_expiry_secs` with an upload, for the server to keep it that long
+pub const EXPIRES_HEADER: &str = "x-shr-expires-in";
+// Set to 1 for `download_once`, for the server to serve the upload a single time
+pub const ONCE_HEADER: &str = "x-shr-download-once";
+// The BLAKE3 of the bundle, in hex, sent with an upload for the server to send back with it, so
+// a download that arrives damaged or cut short is caught whole before it's parsed
+pub const BUNDLE_HASH_HEADER: &str = "x-shr-bundle-hash";
+
+// A cached chunk is only skipped when at least this much of it is still to come, since each
+// skip costs a fresh request
+pub const SKIP_MIN_BYTES: u64 = 256 * 1024;
+
+// Parallel downloads are only split so each connection gets at least this much
+const MIN_RANGE_BYTES: u64 = 1024 * 1024;
+// Ranged bodies up to this size are put back together in memory, larger ones on disk
+const STITCH_IN_MEMORY_BYTES: u64 = 64 * 1024 * 1024;
+
+const RETRY_BACKOFF: Duration = Duration::from_millis(250);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(10);
+
+// How long an endpoint that failed is tried after the others
+const ENDPOINT_COOLDOWN: Duration = Duration::from_secs(60);
 
 pub struct HttpFallback {
     client: reqwest::Client,
     config: FallbackConfig,
+    cancel: CancellationToken,
+    cache: Option<ChunkCache>,
+    parity: Option<Parity>,
+    progress: Option<UploadProgress>,
+    // What `upload_stream` encrypts to, if anything
+    recipients: Vec<age::x25519::Recipient>,
+    health: EndpointHealth,
+}
+
+// When each endpoint last failed, so a dead one isn't tried first for every request, and which
+// one took the last request that succeeded
+#[derive(Default)]
+struct EndpointHealth {
+    failed: Mutex<HashMap<String, Instant>>,
+    used: Mutex<Option<String>>,
+}
+
+impl EndpointHealth {
+    fn cooling(&self, endpoint: &str) -> Option<Instant> {
+        self.failed.lock().unwrap().get(endpoint).copied().filter(|at| at.elapsed() < ENDPOINT_COOLDOWN)
+    }
+}
+
+// Called with the bundle bytes each block of an upload adds as it's handed to the connection
+pub type UploadProgress = Arc<dyn Fn(u64) + Send + Sync>;
+
+// Called with the body bytes each block of a download brings in, and with those it didn't need
+// to: what a resume already had, and what a cached chunk skipped
+pub type DownloadProgress = Arc<dyn Fn(u64) + Send + Sync>;
+
+// Where `fallback.backend` sends uploads. Whichever store took one, the URL it returns is plain
+// HTTP(S), a file:// URL on a shared mount or an sftp:// URL, so receivers always download with
+// `HttpFallback`
+#[async_trait]
+pub trait FallbackStore: Send + Sync {
+    // A bundle already staged on disk; with `verified_uploads` the URL carries its hash
+    async fn upload_file(&self, path: &std::path::Path, original_name: Option<&str>) -> Result<String>;
+    
+    async fn download_bundle(&self, url: &str) -> Result<(Vec<u8>, Option<String>)>;
+    
+    // Where the last upload went, for stores with more than one place it could have
+    fn upload_endpoint(&self) -> Option<String> {
+        None
+    }
+    
+    // Whether there was such a file; already gone is no error
+    async fn delete_file(&self, url: &str) -> Result<bool>;
+    
+    // Removes uploads older than `expiry_secs`, returning what went; with `dry_run` only says
+    // what would have
+    async fn cleanup_old_files(&self, dry_run: bool) -> Result<CleanupReport>;
+    
+    async fn get_upload_stats(&self) -> Result<FallbackStats>;
+}
+
+// The store `config.backend` names. Uploads to it stop when `cancel` fires, and report to
+// `progress` as they go
+pub async fn open_store(config: &FallbackConfig, cancel: CancellationToken, progress: Option<UploadProgress>) -> Result<Box<dyn FallbackStore>> {
+    match config.backend {
+        FallbackBackend::Http => {
+            let mut store = HttpFallback::new(config.clone()).await?.with_cancellation(cancel);
+            if let Some(progress) = progress {
+                store = store.with_upload_progress(progress);
+            }
+            Ok(Box::new(store))
+        }
+        #[cfg(feature = "s3")]
+        FallbackBackend::S3 => {
+            let mut store = s3_store::S3Store::new(config.clone()).await?.with_cancellation(cancel);
+            if let Some(progress) = progress {
+                store = store.with_upload_progress(progress);
+            }
+            Ok(Box::new(store))
+        }
+        #[cfg(not(feature = "s3"))]
+        FallbackBackend::S3 => Err(ShrLinkError::InvalidInput(
+            "fallback.backend = \"s3\" needs shrlink built with the s3 feature".to_string(),
+        )),
+        FallbackBackend::File => {
+            let mut store = dir_store::DirStore::new(config.clone()).await?.with_cancellation(cancel);
+            if let Some(progress) = progress {
+                store = store.with_upload_progress(progress);
+            }
+            Ok(Box::new(store))
+        }
+        #[cfg(feature = "sftp")]
+        FallbackBackend::Sftp => {
+            let mut store = sftp_store::SftpStore::new(config.clone())?.with_cancellation(cancel);
+            if let Some(progress) = progress {
+                store = store.with_upload_progress(progress);
+            }
+            Ok(Box::new(store))
+        }
+        #[cfg(not(feature = "sftp"))]
+        FallbackBackend::Sftp => Err(no_sftp()),
+    }
+}
+
+// What a download with a chunk cache ended up with
+pub enum CachedDownload {
+    Bundle {
+        bundle: Bundle,
+        original_name: Option<String>,
+        // Chunks taken from the cache, and how much of the body that saved downloading
+        chunks_reused: usize,
+        bytes_skipped: u64,
+    },
+    // Not a plain bundle, most likely an encrypted one, so nothing in it could be matched
+    Raw(Vec<u8>, Option<String>),
+    // A plain bundle whose items went out as they were decoded, so there's nothing to hand back
+    Streamed {
+        original_name: Option<String>,
+        chunks_reused: usize,
+        bytes_skipped: u64,
+    },
 }
 
 impl HttpFallback {
     pub async fn new(config: FallbackConfig) -> Result<Self> {
-        let client = reqwest::Client::builder()
-            .timeout(Duration::from_secs(30))
-            .build()
-            .map_err(|e| ShrLinkError::Network(format!("Failed to create HTTP client: {}", e)))?;
+        let client = http_client(&config)?;
+        Ok(Self { client, config, cancel: CancellationToken::new(), cache: None, parity: None, progress: None, recipients: Vec::new(), health: EndpointHealth::default() })
+    }
+    
+    // Downloads of plain bundles then skip the chunks `cache` has, where the server can resume
+    // from an offset
+    pub fn with_cache(mut self, cache: ChunkCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+    
+    // Streamed uploads then carry parity chunks, so downloads can rebuild chunks that arrive
+    // damaged
+    pub fn with_parity(mut self, parity: Parity) -> Self {
+        self.parity = Some(parity);
+        self
+    }
+    
+    // Streamed and file uploads then report their progress block by block
+    pub fn with_upload_progress(mut self, progress: UploadProgress) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+    
+    // Streamed uploads are then encrypted to `recipients` as they go, a segment at a time
+    pub fn with_age_recipients(mut self, recipients: Vec<age::x25519::Recipient>) -> Self {
+        self.recipients = recipients;
+        self
+    }
+    
+    // Uploads then drop their request as soon as `cancel` fires, delete whatever the server kept
+    // of it and fail with `ShrLinkError::Cancelled`
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+    
+    // In the configured order, except that those that failed within ENDPOINT_COOLDOWN come after
+    // the rest, the one that failed longest ago first
+    fn endpoints(&self) -> Vec<String> {
+        let mut endpoints: Vec<String> = match self.config.endpoints.is_empty() {
+            true => vec![DEFAULT_ENDPOINT.to_string()],
+            false => self.config.endpoints.iter().map(|e| e.trim_end_matches('/').to_string()).collect(),
+        };
+        endpoints.sort_by_cached_key(|endpoint| self.health.cooling(endpoint));
+        endpoints
+    }
+    
+    // Where `url` can be fetched from, and its path under each: a URL under one of the
+    // endpoints can come from any of them, its own first unless that failed lately. Any other
+    // URL only from itself
+    fn mirrors<'a>(&self, url: &'a str) -> (Vec<String>, &'a str) {
+        let mut endpoints = self.endpoints();
+        let own = endpoints.iter().position(|endpoint| url.strip_prefix(endpoint.as_str()).is_some_and(|path| path.starts_with('/')));
+        match own {
+            Some(own) => {
+                let path = &url[endpoints[own].len()..];
+                if self.health.cooling(&endpoints[own]).is_none() {
+                    let endpoint = endpoints.remove(own);
+                    endpoints.insert(0, endpoint);
+                }
+                (endpoints, path)
+            }
+            None => (vec![url.to_string()], ""),
+        }
+    }
+    
+    // Where the last request that succeeded went, such as the upload just made
+    pub fn last_endpoint(&self) -> Option<String> {
+        self.health.used.lock().unwrap().clone()
+    }
+    
+    // Runs `attempt` against each of `endpoints` in turn for as long as it fails transiently,
+    // marking those it failed on so they're tried last for a while. Fails transiently itself only
+    // when all of them did, so `retrying` around it starts again from the first
+    async fn failing_over<T, F, Fut>(&self, action: &str, endpoints: Vec<String>, mut attempt: F) -> std::result::Result<T, RequestError>
+    where
+        F: FnMut(String) -> Fut,
+        Fut: std::future::Future<Output = std::result::Result<T, RequestError>>,
+    {
+        let mut failed = None;
+        for (i, endpoint) in endpoints.iter().enumerate() {
+            match attempt(endpoint.clone()).await {
+                Ok(value) => {
+                    self.health.failed.lock().unwrap().remove(endpoint);
+                    *self.health.used.lock().unwrap() = Some(endpoint.clone());
+                    return Ok(value);
+                }
+                Err(e) if e.transient && !self.cancel.is_cancelled() => {
+                    self.health.failed.lock().unwrap().insert(endpoint.clone(), Instant::now());
+                    if let Some(next) = endpoints.get(i + 1) {
+                        tracing::warn!("{} failed at {}, trying {}: {}", action, endpoint, next, e.error);
+                    }
+                    failed = Some(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(failed.expect("there is always an endpoint"))
+    }
+    
+    // Downloads only send the token with `auth_downloads`, so receivers need no credentials
+    fn download_token(&self) -> Option<&str> {
+        self.config.auth_token.as_deref().filter(|_| self.config.auth_downloads)
+    }
+    
+    // Runs `attempt` again after a growing, jittered pause while it fails transiently, up to
+    // `max_retries` more times. Cancelling stops the waiting too
+    async fn retrying<T, F, Fut>(&self, action: &str, attempt: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: std::future::Future<Output = std::result::Result<T, RequestError>>,
+    {
+        retry(action, self.config.max_retries, &self.cancel, attempt).await
+    }
+    
+    // The bundle is never held whole: the chunks are encoded once up front only to measure it,
+    // and hash it for `verified_uploads`, then again as it streams. Each retry, and each
+    // endpoint failed over to, streams it again
+    pub async fn upload_chunks(&self, chunks: &[CompressedChunk], original_name: Option<&str>) -> Result<String> {
+        let prelude = &self.measure_bundle(chunks)?;
+        
+        let download_url = self.retrying("Upload", || self.failing_over("Upload", self.endpoints(), |endpoint| async move {
+            // The request body has to own what it sends, so chunks are copied over a couple at a
+            // time as it drains rather than all at once into a finished bundle
+            let (mut tx, rx) = futures::channel::mpsc::channel(2);
+            let feed = async move {
+                for chunk in chunks {
+                    // Closed only when the upload has already failed
+                    if tx.send(Ok(BundleItem::Chunk(chunk.clone()))).await.is_err() {
+                        break;
+                    }
+                }
+            };
+            let (download_url, ()) = futures::join!(self.try_upload_stream(&endpoint, rx, original_name, |_| {}, prelude, &[]), feed);
+            download_url
+        })).await?;
+        
+        tracing::info!("Uploaded {} chunks to HTTP server: {}", chunks.len(), download_url);
+        Ok(download_url)
+    }
+    
+    // The frames `try_upload_stream` will send for `chunks`, counted and hashed without being kept
+    fn measure_bundle(&self, chunks: &[CompressedChunk]) -> Result<Prelude> {
+        let mut encoder = crate::bundle::FrameEncoder::default();
+        if let Some(parity) = self.parity {
+            encoder = encoder.with_parity(parity);
+        }
+        let mut outboard = self.config.verified_uploads.then(verify::OutboardWriter::default);
+        let mut hasher = blake3::Hasher::new();
+        let mut len = 0u64;
+        let mut add = |bytes: &[u8]| {
+            len += bytes.len() as u64;
+            hasher.update(bytes);
+            if let Some(outboard) = &mut outboard {
+                outboard.update(bytes);
+            }
+        };
         
-        Ok(Self { client, config })
+        add(&crate::bundle::header());
+        for chunk in chunks {
+            add(&encoder.encode_chunk(chunk)?);
+        }
+        add(&encoder.flush()?);
+        add(&crate::bundle::trailer());
+        Ok(Prelude { outboard: outboard.map(verify::OutboardWriter::finish), len: Some(len), hash: Some(hasher.finalize()) })
+    }
+    
+    // With `verified_uploads` the URL returned carries the bundle's hash
+    pub async fn upload_bundle(&self, bundle: &[u8], original_name: Option<&str>) -> Result<String> {
+        self.retrying("Upload", || self.failing_over("Upload", self.endpoints(), |endpoint| async move {
+            self.try_upload_bundle(&endpoint, bundle, original_name).await
+        })).await
     }
     
-    pub async fn upload_chunks(&self, chunks: &[CompressedChunk]) -> Result<String> {
-        let bundle = crate::compression::create_shr_bundle(chunks)?;
-        let filename = format!("{}.shr", Uuid::new_v4());
+    async fn try_upload_bundle(&self, endpoint: &str, bundle: &[u8], original_name: Option<&str>) -> std::result::Result<String, RequestError> {
+        let filename = remote_file_name(original_name);
+        let verified = self.config.verified_uploads.then(|| verify::outboard(bundle)).transpose()?;
+        let body = match &verified {
+            Some((_, header)) => [header.as_slice(), bundle].concat(),
+            None => bundle.to_vec(),
+        };
         
         // Create upload endpoint URL
-        let upload_url = if let Some(endpoint) = &self.config.endpoint {
-            format!("{}/upload", endpoint)
-        } else {
-            format!("http://localhost:8080/upload")
-        };
+        let upload_url = format!("{}/upload", endpoint);
         
-        // Create multipart form
-        let form = multipart::Form::new()
-            .part("file", multipart::Part::bytes(bundle)
-                .file_name(filename.clone())
-                .mime_str("application/octet-stream")
-                .map_err(|e| ShrLinkError::Network(format!("Failed to create form part: {}", e)))?);
+        // reqwest only emits a raw `filename="..."`, so the part headers are written by hand
+        // to carry the RFC 5987 `filename*` alongside an ASCII fallback
+        let boundary = format!("shrlink-{}", Uuid::new_v4().simple());
+        let body = multipart_body(&boundary, "file", &filename, &body);
         
-        // Upload file
-        let response = self.client
+        let request = self.client
             .post(&upload_url)
-            .multipart(form)
-            .send()
-            .await
-            .map_err(|e| ShrLinkError::Network(format!("Failed to upload file: {}", e)))?;
+            .header(reqwest::header::CONTENT_TYPE, format!("multipart/form-data; boundary={}", boundary))
+            .body(body);
+        let hash = verified.as_ref().map_or_else(|| blake3::hash(bundle), |(root, _)| *root);
+        let download_url = self.send_upload(endpoint, request, &filename, Some(&hash)).await?;
+        Ok(verified_url(download_url, verified.map(|(root, _)| root)))
+    }
+    
+    // Uploads a bundle already staged on disk without reading it all into memory; with
+    // `verified_uploads` it's read once more first, for its hash tree, and only then does the
+    // server get its hash to keep. One larger than `upload_part_size` goes up in parts, unless
+    // the server doesn't take them
+    pub async fn upload_file(&self, path: &std::path::Path, original_name: Option<&str>) -> Result<String> {
+        let part_size = self.config.upload_part_size;
+        if part_size > 0 && tokio::fs::metadata(path).await?.len() > part_size {
+            if let Some(download_url) = self.upload_file_in_parts(path, original_name).await? {
+                return Ok(download_url);
+            }
+        }
+        self.retrying("Upload", || self.failing_over("Upload", self.endpoints(), |endpoint| async move {
+            self.try_upload_file(&endpoint, path, original_name).await
+        })).await
+    }
+    
+    // `None` when the server doesn't take uploads in parts: a 404 or 405 for /upload/init, or a
+    // reply without the upload's id
+    async fn upload_file_in_parts(&self, path: &std::path::Path, original_name: Option<&str>) -> Result<Option<String>> {
+        let verified = if self.config.verified_uploads {
+            let staged = path.to_path_buf();
+            let outboard = tokio::task::spawn_blocking(move || verify::outboard(std::io::BufReader::new(std::fs::File::open(staged)?)));
+            Some(outboard.await.map_err(|e| ShrLinkError::Other(e.into()))??)
+        } else {
+            None
+        };
+        let verified = &verified;
+        let filename = &remote_file_name(original_name);
         
-        if !response.status().is_success() {
-            return Err(ShrLinkError::Network(format!("Upload failed with status: {}", response.status())));
+        let download_url = self.retrying("Upload", || self.failing_over("Upload", self.endpoints(), |endpoint| async move {
+            self.try_upload_parts(&endpoint, path, filename, verified.as_ref()).await
+        })).await?;
+        match download_url {
+            Some(download_url) => Ok(Some(verified_url(download_url, verified.as_ref().map(|(root, _)| *root)))),
+            None => {
+                tracing::debug!("The server takes no uploads in parts; sending {} as a single request", path.display());
+                Ok(None)
+            }
+        }
+    }
+    
+    // POST /upload/init opens the upload, each part is PUT to /upload/<id>/part/<n> from 1 up
+    // and retried on its own, and POST /upload/<id>/complete files the whole under its name.
+    // Parts that give up for good fail the upload rather than moving it to another endpoint,
+    // which would start it over
+    async fn try_upload_parts(&self, endpoint: &str, path: &std::path::Path, filename: &str, verified: Option<&(blake3::Hash, Vec<u8>)>) -> std::result::Result<Option<String>, RequestError> {
+        let request = self.client.post(format!("{}/upload/init", endpoint)).json(&serde_json::json!({ "name": filename }));
+        let response = self.upload_headers(request, verified.map(|(root, _)| root)).send().await
+            .map_err(|e| RequestError::send("Failed to start the upload", &e))?;
+        match response.status() {
+            reqwest::StatusCode::NOT_FOUND | reqwest::StatusCode::METHOD_NOT_ALLOWED => return Ok(None),
+            status if !status.is_success() => return Err(RequestError::status("Starting the upload failed", status)),
+            _ => {}
+        }
+        let reply = response.json::<serde_json::Value>().await.ok();
+        let Some(id) = reply.as_ref().and_then(|reply| reply.get("id")?.as_str()).filter(|id| !id.is_empty()) else {
+            return Ok(None);
+        };
+        let upload_url = format!("{}/upload/{}", endpoint, filename::encode_path_segment(id));
+        
+        let header = verified.map_or(&[][..], |(_, header)| header.as_slice());
+        let len = header.len() as u64 + tokio::fs::metadata(path).await.map_err(ShrLinkError::from)?.len();
+        let part_size = self.config.upload_part_size;
+        let parts = len.div_ceil(part_size).max(1);
+        for part in 0..parts {
+            let (start, end) = (part * part_size, ((part + 1) * part_size).min(len));
+            let part_url = format!("{}/part/{}", upload_url, part + 1);
+            self.retrying("Upload part", || async {
+                let body = part_body(path, header, start, end, self.progress.clone()).await?;
+                let request = with_token(self.client.put(&part_url), self.config.auth_token.as_deref())
+                    .header(reqwest::header::CONTENT_LENGTH, end - start)
+                    .body(reqwest::Body::wrap_stream(body));
+                let response = tokio::select! {
+                    response = request.send() => response.map_err(|e| RequestError::send("Failed to upload a part", &e))?,
+                    _ = self.cancel.cancelled() => return Err(ShrLinkError::Cancelled.into()),
+                };
+                match response.status() {
+                    status if status.is_success() => Ok(()),
+                    status => Err(RequestError::status("Uploading a part failed", status)),
+                }
+            }).await?;
+            tracing::debug!("Uploaded part {} of {} ({} bytes)", part + 1, parts, end - start);
         }
         
-        // Get the download URL
-        let download_url = if let Some(endpoint) = &self.config.endpoint {
-            format!("{}/files/{}", endpoint, filename)
+        let response = self.retrying("Completing the upload", || async {
+            let response = with_token(self.client.post(format!("{}/complete", upload_url)), self.config.auth_token.as_deref())
+                .json(&serde_json::json!({ "parts": parts }))
+                .send()
+                .await
+                .map_err(|e| RequestError::send("Failed to complete the upload", &e))?;
+            match response.status() {
+                status if status.is_success() => Ok(response),
+                status => Err(RequestError::status("Completing the upload failed", status)),
+            }
+        }).await?;
+        let location = response.headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        let body = response.bytes().await.unwrap_or_default();
+        tracing::info!("Uploaded {} in {} parts", filename, parts);
+        Ok(Some(resolve_download_url(endpoint, filename, location.as_deref(), &body, self.config.url_template.as_deref())))
+    }
+    
+    async fn try_upload_file(&self, endpoint: &str, path: &std::path::Path, original_name: Option<&str>) -> std::result::Result<String, RequestError> {
+        let filename = remote_file_name(original_name);
+        let upload_url = format!("{}/upload", endpoint);
+        let boundary = format!("shrlink-{}", Uuid::new_v4().simple());
+        
+        let verified = if self.config.verified_uploads {
+            let staged = path.to_path_buf();
+            let outboard = tokio::task::spawn_blocking(move || verify::outboard(std::io::BufReader::new(std::fs::File::open(staged)?)));
+            Some(outboard.await.map_err(|e| ShrLinkError::Other(e.into()))??)
         } else {
-            format!("http://localhost:8080/files/{}", filename)
+            None
         };
+        let mut head = multipart_prefix(&boundary, "file", &filename);
+        if let Some((_, header)) = &verified {
+            head.extend_from_slice(header);
+        }
+        let tail = multipart_suffix(&boundary);
+        let file = tokio::fs::File::open(path).await.map_err(ShrLinkError::from)?;
+        let content_length = head.len() as u64 + file.metadata().await.map_err(ShrLinkError::from)?.len() + tail.len() as u64;
         
-        tracing::info!("Uploaded {} chunks to HTTP server: {}", chunks.len(), download_url);
+        let progress = self.progress.clone();
+        let contents = tokio_util::io::ReaderStream::new(file).inspect_ok(move |block| {
+            if let Some(progress) = &progress {
+                progress(block.len() as u64);
+            }
+        });
+        let body = stream::once(async { Ok(Bytes::from(head)) })
+            .chain(contents)
+            .chain(stream::once(async { Ok(Bytes::from(tail)) }));
+        
+        // Sent up front rather than chunked, so servers can turn away what's over their quota
+        let request = self.client
+            .post(&upload_url)
+            .header(reqwest::header::CONTENT_TYPE, format!("multipart/form-data; boundary={}", boundary))
+            .header(reqwest::header::CONTENT_LENGTH, content_length)
+            .body(reqwest::Body::wrap_stream(body));
+        let download_url = self.send_upload(endpoint, request, &filename, verified.as_ref().map(|(root, _)| root)).await?;
+        Ok(verified_url(download_url, verified.map(|(root, _)| root)))
+    }
+    
+    // Streams the bundle to the server as items arrive, so the upload overlaps with compression.
+    // Never verified, whatever `verified_uploads` says: nothing is hashed until it's been sent.
+    // Nor retried, since the items are gone once sent, but an endpoint that fails before the
+    // first of them was taken is failed over like any other
+    pub async fn upload_stream<S, F>(&self, items: S, original_name: Option<&str>, mut on_item: F) -> Result<String>
+    where
+        S: Stream<Item = Result<BundleItem>> + Send + 'static,
+        F: FnMut(&BundleItem) + Send + 'static,
+    {
+        let items = items.inspect(move |item| {
+            if let Ok(item) = item {
+                on_item(item);
+            }
+        });
+        // Handed over to whichever request first starts on its body
+        let pending = Arc::new(Mutex::new(Some(items.boxed())));
+        self.failing_over("Upload", self.endpoints(), |endpoint| {
+            let pending = pending.clone();
+            async move {
+                let items = stream::once({
+                    let pending = pending.clone();
+                    async move { pending.lock().unwrap().take() }
+                })
+                .filter_map(future::ready)
+                .flatten();
+                let uploaded = self.try_upload_stream(&endpoint, items, original_name, |_| {}, &Prelude::default(), &self.recipients).await;
+                // Once the items have gone there's nothing to send anywhere else
+                let sendable = pending.lock().unwrap().is_some();
+                uploaded.map_err(|e| RequestError { transient: e.transient && sendable, ..e })
+            }
+        }).await.map_err(|e| e.error)
+    }
+    
+    // With a measured `prelude` the request has a Content-Length and the bundle's hash, and the
+    // hash tree goes first. With `recipients` the bundle goes out age-encrypted, which only an
+    // unmeasured upload can be
+    async fn try_upload_stream<S, F>(&self, endpoint: &str, items: S, original_name: Option<&str>, mut on_item: F, prelude: &Prelude, recipients: &[age::x25519::Recipient]) -> std::result::Result<String, RequestError>
+    where
+        S: Stream<Item = Result<BundleItem>> + Send + 'static,
+        F: FnMut(&BundleItem) + Send + 'static,
+    {
+        let filename = remote_file_name(original_name);
+        let upload_url = format!("{}/upload", endpoint);
+        let boundary = format!("shrlink-{}", Uuid::new_v4().simple());
+        
+        let mut head = multipart_prefix(&boundary, "file", &filename);
+        if let Some((_, header)) = &prelude.outboard {
+            head.extend_from_slice(header);
+        }
+        let tail = multipart_suffix(&boundary);
+        let content_length = prelude.len.map(|len| head.len() as u64 + len + tail.len() as u64);
+        let mut sealer = match recipients {
+            [] => None,
+            recipients => Some(crate::crypto::AgeStream::new(recipients)?),
+        };
+        let mut bundle_header = crate::bundle::header();
+        if let Some(sealer) = &mut sealer {
+            bundle_header = sealer.seal(&bundle_header)?;
+        }
+        if let Some(progress) = &self.progress {
+            progress(bundle_header.len() as u64);
+        }
+        head.extend_from_slice(&bundle_header);
+        
+        let progress = self.progress.clone();
+        let mut encoder = crate::bundle::FrameEncoder::default();
+        if let Some(parity) = self.parity {
+            encoder = encoder.with_parity(parity);
+        }
+        // The last group's parity is only known once the items run out
+        let frames = items.map(Some).chain(stream::once(async { None })).map(move |item| {
+            let frames = match item {
+                Some(item) => {
+                    let item = item?;
+                    let frame = encoder.encode(&item)?;
+                    on_item(&item);
+                    match &mut sealer {
+                        Some(sealer) => sealer.seal(&frame)?,
+                        None => frame,
+                    }
+                }
+                None => {
+                    let mut frames = encoder.flush()?;
+                    frames.extend_from_slice(&crate::bundle::trailer());
+                    match sealer.take() {
+                        Some(mut sealer) => {
+                            let mut sealed = sealer.seal(&frames)?;
+                            sealed.extend(sealer.finish()?);
+                            sealed
+                        }
+                        None => frames,
+                    }
+                }
+            };
+            if let Some(progress) = &progress {
+                progress(frames.len() as u64);
+            }
+            Ok::<_, ShrLinkError>(Bytes::from(frames))
+        });
+        let body = stream::once(async { Ok(Bytes::from(head)) })
+            .chain(frames)
+            .chain(stream::once(async { Ok(Bytes::from(tail)) }));
+        
+        let mut request = self.client
+            .post(&upload_url)
+            .header(reqwest::header::CONTENT_TYPE, format!("multipart/form-data; boundary={}", boundary));
+        if let Some(content_length) = content_length {
+            request = request.header(reqwest::header::CONTENT_LENGTH, content_length);
+        }
+        let download_url = self.send_upload(endpoint, request.body(reqwest::Body::wrap_stream(body)), &filename, prelude.hash.as_ref()).await?;
+        let download_url = verified_url(download_url, prelude.outboard.as_ref().map(|(root, _)| *root));
+        tracing::info!("Streamed bundle to HTTP server: {}", download_url);
         Ok(download_url)
     }
     
-    pub async fn download_chunks(&self, url: &str) -> Result<Vec<CompressedChunk>> {
-        let response = self.client.get(url).send().await
-            .map_err(|e| ShrLinkError::Network(format!("Failed to download from HTTP server: {}", e)))?;
+    // Returns the download URL the server at `endpoint` files the upload under
+    async fn send_upload(&self, endpoint: &str, request: reqwest::RequestBuilder, filename: &str, hash: Option<&blake3::Hash>) -> std::result::Result<String, RequestError> {
+        // Until the server answers, where a cut-off upload would be is only a guess
+        let guessed_url = format!("{}/files/{}", endpoint, filename::encode_path_segment(filename));
+        let request = self.upload_headers(request, hash);
+        let sent = tokio::select! {
+            response = request.send() => Some(response),
+            _ = self.cancel.cancelled() => None,
+        };
+        // A streamed body also fails on its own once the items feeding it are cancelled, so
+        // that counts as cancelled too
+        let response = match sent {
+            Some(response) if !self.cancel.is_cancelled() => response
+                .map_err(|e| RequestError::send("Failed to upload file", &e))?,
+            _ => {
+                // The body was cut short, but the server may have kept what arrived before that
+                if let Err(e) = self.delete_file(&guessed_url).await {
+                    tracing::warn!("Could not remove the partial upload {}: {}", guessed_url, e);
+                }
+                return Err(ShrLinkError::Cancelled.into());
+            }
+        };
         
         if !response.status().is_success() {
-            return Err(ShrLinkError::Network(format!("HTTP download failed with status: {}", response.status())));
+            return Err(RequestError::status("Upload failed", response.status()));
+        }
+        let location = response.headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        // Only a reply that can't be read falls back to the guess; the upload itself went through
+        let body = response.bytes().await.unwrap_or_default();
+        
+        Ok(resolve_download_url(endpoint, filename, location.as_deref(), &body, self.config.url_template.as_deref()))
+    }
+    
+    // The token, and what's asked of the server for the upload as a whole
+    fn upload_headers(&self, request: reqwest::RequestBuilder, hash: Option<&blake3::Hash>) -> reqwest::RequestBuilder {
+        let mut request = with_token(request, self.config.auth_token.as_deref());
+        if let Some(expiry) = self.config.upload_expiry_secs {
+            request = request.header(EXPIRES_HEADER, expiry);
+        }
+        if self.config.download_once {
+            request = request.header(ONCE_HEADER, "1");
+        }
+        if let Some(hash) = hash {
+            request = request.header(BUNDLE_HASH_HEADER, hash.to_hex().as_str());
         }
+        request
+    }
+    
+    // Whether there was such a file; already gone (a 404) is no error
+    pub async fn delete_file(&self, url: &str) -> Result<bool> {
+        let response = with_token(self.client.delete(url), self.config.auth_token.as_deref())
+            .send()
+            .await
+            .map_err(|e| ShrLinkError::Network(format!("Failed to delete {}: {}", url, error_chain(&e))))?;
         
-        let bundle = response.bytes().await
-            .map_err(|e| ShrLinkError::Network(format!("Failed to read HTTP response: {}", e)))?;
+        match response.status() {
+            status if status.is_success() => Ok(true),
+            reqwest::StatusCode::NOT_FOUND => Ok(false),
+            status => Err(RequestError::status("Delete failed", status).error),
+        }
+    }
+    
+    pub async fn download_chunks(&self, url: &str) -> Result<Vec<CompressedChunk>> {
+        let (chunks, _) = self.download_chunks_named(url).await?;
+        Ok(chunks)
+    }
+    
+    // Parsed as it arrives, so the raw bundle is never held alongside its chunks. A bundle's hash
+    // from the server is checked once the body has all gone past
+    pub async fn download_chunks_named(&self, url: &str) -> Result<(Vec<CompressedChunk>, Option<String>)> {
+        if self.cache.is_some() {
+            let (bundle, original_name) = match self.download_bundle_cached(url).await? {
+                CachedDownload::Bundle { bundle, original_name, .. } => (bundle, original_name),
+                CachedDownload::Raw(bundle, original_name) => (crate::bundle::parse_bundle(&bundle)?, original_name),
+                CachedDownload::Streamed { .. } => unreachable!("nothing was given to stream to"),
+            };
+            let chunks = bundle.into_single_file()?;
+            tracing::info!("Downloaded {} chunks from HTTP server", chunks.len());
+            return Ok((chunks, original_name));
+        }
         
-        let chunks = crate::compression::parse_shr_bundle(&bundle)?;
+        let response = self.open_bundle(url).await?;
+        let original_name = response.original_name().map(str::to_string);
+        let bundle = self.read_checked(response, |response| response.into_reader().read_bundle_to_end()).await?;
+        let chunks = bundle.into_single_file()?;
         
         tracing::info!("Downloaded {} chunks from HTTP server", chunks.len());
-        Ok(chunks)
+        Ok((chunks, original_name))
     }
     
-    pub async fn cleanup_old_files(&self) -> Result<usize> {
-        // For HTTP fallback, we'll call a cleanup endpoint on the server
-        let cleanup_url = if let Some(endpoint) = &self.config.endpoint {
-            format!("{}/cleanup", endpoint)
-        } else {
-            format!("http://localhost:8080/cleanup")
-        };
+    pub async fn download_bundle(&self, url: &str) -> Result<(Vec<u8>, Option<String>)> {
+        self.read_checked(self.open_bundle(url).await?, BundleResponse::read).await
+    }
+    
+    // A body that doesn't add up to what the server said it was is downloaded once more when
+    // `max_retries` allows any retries, rather than given up on. The second download is reported
+    // to the same progress as the first
+    async fn read_checked<T, F, Fut>(&self, response: BundleResponse, read: F) -> Result<T>
+    where
+        F: Fn(BundleResponse) -> Fut,
+        Fut: std::future::Future<Output = Result<T>>,
+    {
+        let url = verified_url(response.url.clone(), response.root);
+        let progress = response.progress.clone();
+        match read(response).await {
+            Err(ShrLinkError::CorruptDownload(reason)) if self.config.max_retries > 0 => {
+                tracing::warn!("{}; downloading it again", reason);
+                let mut response = self.open_bundle(&url).await?;
+                response.progress = progress;
+                read(response).await
+            }
+            result => result,
+        }
+    }
+    
+    // Decodes the bundle frame by frame as it arrives. When the next chunk is one the cache
+    // already has, the rest of its frame is skipped by asking for the body again from just past
+    // it. The cached copy must match the frame's hash, size and checksum, and like everything
+    // else is only trusted once it decompresses to that hash
+    pub async fn download_bundle_cached(&self, url: &str) -> Result<CachedDownload> {
+        self.fetch_bundle(self.open_bundle(url).await?, None, None).await
+    }
+    
+    // As `download_bundle_cached`, keeping the body in `partial` as it arrives. Whatever it
+    // already holds is gone through again rather than downloaded, and the server is only asked
+    // for the rest; if it can't start there, the download starts over
+    pub async fn resume_bundle_download(&self, url: &str, partial: &mut PartialDownload) -> Result<CachedDownload> {
+        self.fetch_bundle(self.open_bundle(url).await?, Some(partial), None).await
+    }
+    
+    // As `resume_bundle_download` for a response already opened, so its length is known first
+    pub async fn resume_opened_download(&self, response: BundleResponse, partial: &mut PartialDownload) -> Result<CachedDownload> {
+        self.fetch_bundle(response, Some(partial), None).await
+    }
+    
+    // As `download_bundle_cached` for a response already opened, or with `partial`
+    // as `resume_bundle_download`, but a plain bundle's items are sent to `items` as they're
+    // decoded rather than collected, so no more of it is held than the frame at hand; it then
+    // comes back as `Streamed`. Anything else still comes back whole as `Raw`, with nothing
+    // sent. Stops if `items` is closed
+    pub async fn stream_bundle_download(&self, response: BundleResponse, partial: Option<&mut PartialDownload>, items: mpsc::Sender<BundleItem>) -> Result<CachedDownload> {
+        self.fetch_bundle(response, partial, Some(&items)).await
+    }
+    
+    // More of the body is asked for from wherever the response came from
+    async fn fetch_bundle(&self, response: BundleResponse, mut partial: Option<&mut PartialDownload>, sink: Option<&mpsc::Sender<BundleItem>>) -> Result<CachedDownload> {
+        let accepts_ranges = response.accepts_ranges();
+        // A body fetched over several connections has all arrived before any of it is read, so
+        // there's nothing left to skip
+        let ranged = self.cache.is_some() && accepts_ranges && matches!(response.body, Body::Single(_));
+        let total = response.content_length();
+        let (url, root, original_name, progress) = (response.url, response.root, response.original_name, response.progress);
+        let (url, progress) = (url.as_str(), progress.as_ref());
+        let mut body = with_head(response.head, response.body.into_stream(progress), progress);
+        
+        let mut buffer = Vec::new();
+        if let Some(partial) = partial.as_deref_mut() {
+            let had = partial.body_len();
+            if had > 0 && accepts_ranges && total.is_some_and(|total| had < total) {
+                tracing::info!("Resuming the download at byte {} of {}", had, total.unwrap_or_default());
+                buffer = partial.body()?;
+                if let Some(progress) = progress {
+                    progress(had);
+                }
+                drop(body);
+                body = self.resume_bundle(url, had, total, progress).await?;
+            } else if had > 0 {
+                tracing::info!("The server can't resume at byte {}, starting over", had);
+                partial.clear()?;
+            }
+        }
+        
+        // Nothing can be skipped without reading past it, to check it
+        if root.is_some() {
+            let bundle = match sink {
+                Some(sink) => stream_rest(buffer, body, root, partial, sink).await?,
+                None => Some(read_rest(buffer, body, root, partial).await?),
+            };
+            return Ok(match bundle {
+                Some(bundle) => CachedDownload::Raw(bundle, original_name),
+                None => CachedDownload::Streamed { original_name, chunks_reused: 0, bytes_skipped: 0 },
+            });
+        }
+        
+        let mut decoder = FrameDecoder::default();
+        // Where `buffer` starts in the body
+        let mut offset = 0u64;
+        let mut items = Vec::new();
+        let (mut chunks_reused, mut bytes_skipped) = (0, 0);
+        loop {
+            if offset == 0 && buffer.len() >= 3 && !buffer.starts_with(b"SHR") {
+                return Ok(CachedDownload::Raw(read_rest(buffer, body, None, partial).await?, original_name));
+            }
+            // Verified without a hash to check it against, which still has to go through its tree
+            if offset == 0 && buffer.starts_with(verify::MAGIC) {
+                let bundle = match sink {
+                    Some(sink) => stream_rest(buffer, body, None, partial, sink).await?,
+                    None => Some(read_rest(buffer, body, None, partial).await?),
+                };
+                return Ok(match bundle {
+                    Some(bundle) => CachedDownload::Raw(bundle, original_name),
+                    None => CachedDownload::Streamed { original_name, chunks_reused, bytes_skipped },
+                });
+            }
+            
+            if let Some(header) = decoder.peek_chunk(&buffer)? {
+                let unread = (header.frame_len - buffer.len().min(header.frame_len)) as u64;
+                let cached = match &self.cache {
+                    Some(cache) if ranged && unread >= SKIP_MIN_BYTES => cache.get(&header.hash).filter(|c| header.matches(c)),
+                    _ => None,
+                };
+                if let Some(chunk) = cached {
+                    take_item(&mut items, sink, decoder.skip_chunk(&header, chunk)?).await?;
+                    offset += header.frame_len as u64;
+                    bytes_skipped += unread;
+                    chunks_reused += 1;
+                    buffer.clear();
+                    // What's kept has to be the body from the start, so it stops here
+                    partial = None;
+                    if let Some(progress) = progress {
+                        progress(unread);
+                    }
+                    // Hung up first, so the rest of the frame stops coming
+                    drop(body);
+                    body = self.resume_bundle(url, offset, total, progress).await?;
+                    continue;
+                }
+            }
+            
+            let (used, decoded) = decoder.decode(&buffer)?;
+            buffer.drain(..used);
+            offset += used as u64;
+            match decoded {
+                Some(Decoded::Item(item)) => take_item(&mut items, sink, item).await?,
+                Some(Decoded::End) => break,
+                None => match next_body_chunk(&mut body).await? {
+                    Some(more) => {
+                        if let Some(partial) = partial.as_deref_mut() {
+                            partial.append(&more)?;
+                        }
+                        buffer.extend_from_slice(&more);
+                    }
+                    None if offset == 0 => return Ok(CachedDownload::Raw(buffer, original_name)),
+                    None => return Err(ShrLinkError::InvalidInput("Bundle ended before its end marker".to_string())),
+                },
+            }
+        }
         
-        let response = self.client
-            .post(&cleanup_url)
-            .json(&serde_json::json!({
-                "max_age_seconds": self.config.expiry_secs
-            }))
+        if chunks_reused > 0 {
+            tracing::info!("Reused {} cached chunks, skipping {} bytes of the download", chunks_reused, bytes_skipped);
+        }
+        if sink.is_some() {
+            return Ok(CachedDownload::Streamed { original_name, chunks_reused, bytes_skipped });
+        }
+        Ok(CachedDownload::Bundle { bundle: Bundle::from_items(items)?, original_name, chunks_reused, bytes_skipped })
+    }
+    
+    // The body from `offset` on. Only a partial response will do; a whole one would mean the
+    // server stopped honouring ranges or the file changed underneath
+    async fn resume_bundle(&self, url: &str, offset: u64, total: Option<u64>, progress: Option<&DownloadProgress>) -> Result<BodyStream> {
+        let response = with_token(self.client.get(url), self.download_token())
+            .header(reqwest::header::RANGE, format!("bytes={}-", offset))
             .send()
             .await
-            .map_err(|e| ShrLinkError::Network(format!("Failed to call cleanup endpoint: {}", e)))?;
+            .map_err(|e| ShrLinkError::Network(format!("Failed to download from HTTP server: {}", e)))?;
         
-        if !response.status().is_success() {
-            return Err(ShrLinkError::Network(format!("Cleanup failed with status: {}", response.status())));
+        let range = response.headers()
+            .get(reqwest::header::CONTENT_RANGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_content_range);
+        match (response.status(), range) {
+            (reqwest::StatusCode::PARTIAL_CONTENT, Some((start, length))) if start == offset && (total.is_none() || length == total) => Ok(Body::Single(response).into_stream(progress)),
+            (status @ (reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN | reqwest::StatusCode::GONE), _) => Err(RequestError::status("HTTP download failed", status).error),
+            (status, _) => Err(ShrLinkError::Network(format!("HTTP server did not resume the download at byte {} (status {})", offset, status))),
+        }
+    }
+    
+    // Resolves once the server has answered with headers, so callers can race it against other
+    // sources. A hash in the URL's fragment is what the body will be checked against. With
+    // `parallel_connections` the body is fetched in ranges when it's first read, if the server
+    // serves them. A URL under one of the endpoints is fetched from another when its own is down.
+    // A file:// URL is read straight off the mount it names, and an sftp:// one over SSH with
+    // the key and host key settings in `sftp`
+    pub async fn open_bundle(&self, url: &str) -> Result<BundleResponse> {
+        let (url, root) = verify::split_url_hash(url)?;
+        if is_file_url(url) {
+            return dir_store::open_file(url, root).await;
+        }
+        if is_sftp_url(url) {
+            #[cfg(feature = "sftp")]
+            return sftp_store::open_file(url, root, &self.config.sftp).await;
+            #[cfg(not(feature = "sftp"))]
+            return Err(no_sftp());
+        }
+        let (mirrors, path) = self.mirrors(url);
+        if self.config.parallel_connections > 1 {
+            if let Some(response) = self.open_ranged(&mirrors, path, root).await? {
+                return Ok(response);
+            }
         }
         
+        let (url, response) = self.retrying("Download", || self.failing_over("Download", mirrors.clone(), |mirror| async move {
+            let url = format!("{}{}", mirror, path);
+            let response = with_token(self.client.get(&url), self.download_token()).send().await
+                .map_err(|e| RequestError::send("Failed to download from HTTP server", &e))?;
+            match response.status() {
+                status if status.is_success() => Ok((url, response)),
+                status => Err(RequestError::status("HTTP download failed", status)),
+            }
+        })).await?;
+        
+        let original_name = advertised_name(response.headers(), &url);
+        let bundle_hash = advertised_hash(response.headers(), &url);
+        Ok(BundleResponse { body: Body::Single(response), url, original_name, root, bundle_hash, progress: None, head: Vec::new() })
+    }
+    
+    // Asks for the headers alone first, to see whether the body can be split. Servers that don't
+    // answer that, don't serve ranges or have too little to split are left to a single stream
+    async fn open_ranged(&self, mirrors: &[String], path: &str, root: Option<blake3::Hash>) -> Result<Option<BundleResponse>> {
+        let (url, head) = self.retrying("Download", || self.failing_over("Download", mirrors.to_vec(), |mirror| async move {
+            let url = format!("{}{}", mirror, path);
+            let head = with_token(self.client.head(&url), self.download_token()).send().await
+                .map_err(|e| RequestError::send("Failed to download from HTTP server", &e))?;
+            match head.status() {
+                status if status.is_server_error() => Err(RequestError::status("HTTP download failed", status)),
+                _ => Ok((url, head)),
+            }
+        })).await?;
+        
+        let headers = head.headers();
+        let accepts_ranges = headers.get(reqwest::header::ACCEPT_RANGES).is_some_and(|v| v.as_bytes() == b"bytes");
+        // Not `content_length()`, which counts the empty body a HEAD gets back
+        let len = headers.get(reqwest::header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok());
+        let connections = len.map_or(0, |len| (self.config.parallel_connections as u64).min(len / MIN_RANGE_BYTES));
+        let len = match len {
+            Some(len) if head.status().is_success() && accepts_ranges && connections > 1 => len,
+            _ => {
+                tracing::debug!("Downloading over a single connection (status {}, ranges {}, length {:?})", head.status(), accepts_ranges, len);
+                return Ok(None);
+            }
+        };
+        
+        let ranges = Ranges {
+            client: self.client.clone(),
+            url: url.clone(),
+            token: self.download_token().map(str::to_string),
+            len,
+            connections,
+            max_retries: self.config.max_retries,
+            cancel: self.cancel.clone(),
+            progress: None,
+        };
+        let original_name = advertised_name(headers, &url);
+        let bundle_hash = advertised_hash(headers, &url);
+        Ok(Some(BundleResponse { body: Body::Ranged(ranges), url, original_name, root, bundle_hash, progress: None, head: Vec::new() }))
+    }
+    
+    // A dry run is `POST /cleanup?dry_run=true`, which the server answers with what it would
+    // have removed. One that doesn't say it took it as a dry run has already cleaned up for real
+    pub async fn cleanup_old_files(&self, dry_run: bool) -> Result<CleanupReport> {
+        let path = if dry_run { "cleanup?dry_run=true" } else { "cleanup" };
+        let response = self.retrying("Cleanup", || self.failing_over("Cleanup", self.endpoints(), |endpoint| async move {
+            let response = with_token(self.client.post(format!("{}/{}", endpoint, path)), self.config.auth_token.as_deref())
+                .json(&serde_json::json!({
+                    "max_age_seconds": self.config.expiry_secs
+                }))
+                .send()
+                .await
+                .map_err(|e| RequestError::send("Failed to call cleanup endpoint", &e))?;
+            match response.status() {
+                status if status.is_success() => Ok(response),
+                status => Err(RequestError::status("Cleanup failed", status)),
+            }
+        })).await?;
+        
         let result: serde_json::Value = response.json().await
             .map_err(|e| ShrLinkError::Network(format!("Failed to parse cleanup response: {}", e)))?;
         
         let deleted_count = result.get("deleted_count")
             .and_then(|v| v.as_u64())
             .unwrap_or(0) as usize;
+        if dry_run && result.get("dry_run").and_then(|v| v.as_bool()) != Some(true) {
+            return Err(ShrLinkError::Network(format!(
+                "The server doesn't support cleanup dry runs, and cleaned up for real: {} files deleted",
+                deleted_count
+            )));
+        }
+        
+        // Servers that only count what they removed leave the names blank
+        let deleted = match result.get("deleted") {
+            Some(deleted) => serde_json::from_value(deleted.clone())
+                .map_err(|e| ShrLinkError::Network(format!("Failed to parse cleanup response: {}", e)))?,
+            None => vec![FileInfo::default(); deleted_count],
+        };
+        let report = CleanupReport::new(deleted);
         
-        tracing::info!("Cleanup deleted {} files", deleted_count);
-        Ok(deleted_count)
+        if dry_run {
+            tracing::info!("Cleanup would delete {} files", report.deleted.len());
+        } else {
+            tracing::info!("Cleanup deleted {} files", report.deleted.len());
+        }
+        Ok(report)
     }
     
     pub async fn get_upload_stats(&self) -> Result<FallbackStats> {
         // For HTTP fallback, we'll call a stats endpoint on the server
-        let stats_url = if let Some(endpoint) = &self.config.endpoint {
-            format!("{}/stats", endpoint)
-        } else {
-            format!("http://localhost:8080/stats")
-        };
-        
-        let response = self.client
-            .get(&stats_url)
-            .send()
-            .await
-            .map_err(|e| ShrLinkError::Network(format!("Failed to call stats endpoint: {}", e)))?;
-        
-        if !response.status().is_success() {
-            return Err(ShrLinkError::Network(format!("Stats request failed with status: {}", response.status())));
-        }
+        let response = self.retrying("Stats request", || self.failing_over("Stats request", self.endpoints(), |endpoint| async move {
+            let response = with_token(self.client.get(format!("{}/stats", endpoint)), self.config.auth_token.as_deref())
+                .send()
+                .await
+                .map_err(|e| RequestError::send("Failed to call stats endpoint", &e))?;
+            match response.status() {
+                status if status.is_success() => Ok(response),
+                status => Err(RequestError::status("Stats request failed", status)),
+            }
+        })).await?;
         
         let result: serde_json::Value = response.json().await
             .map_err(|e| ShrLinkError::Network(format!("Failed to parse stats response: {}", e)))?;
@@ -139,38 +1067,738 @@ impl HttpFallback {
             .and_then(|v| v.as_u64())
             .unwrap_or(0);
         
+        // Only some servers list their files, `shr serve` among them
+        let files = match result.get("files") {
+            Some(files) => Some(serde_json::from_value(files.clone())
+                .map_err(|e| ShrLinkError::Network(format!("Failed to parse stats response: {}", e)))?),
+            None => None,
+        };
+        
         Ok(FallbackStats {
             total_files,
             total_bytes,
+            files,
         })
     }
 }
 
-#[derive(Debug, Default)]
+#[async_trait]
+impl FallbackStore for HttpFallback {
+    async fn upload_file(&self, path: &std::path::Path, original_name: Option<&str>) -> Result<String> {
+        HttpFallback::upload_file(self, path, original_name).await
+    }
+    
+    async fn download_bundle(&self, url: &str) -> Result<(Vec<u8>, Option<String>)> {
+        HttpFallback::download_bundle(self, url).await
+    }
+    
+    fn upload_endpoint(&self) -> Option<String> {
+        self.last_endpoint()
+    }
+    
+    async fn delete_file(&self, url: &str) -> Result<bool> {
+        HttpFallback::delete_file(self, url).await
+    }
+    
+    async fn cleanup_old_files(&self, dry_run: bool) -> Result<CleanupReport> {
+        HttpFallback::cleanup_old_files(self, dry_run).await
+    }
+    
+    async fn get_upload_stats(&self) -> Result<FallbackStats> {
+        HttpFallback::get_upload_stats(self).await
+    }
+}
+
+// What's known of a bundle before it streams: its hash tree, to send ahead of it, its length
+// and its hash, for the server to keep
+#[derive(Default)]
+struct Prelude {
+    outboard: Option<(blake3::Hash, Vec<u8>)>,
+    len: Option<u64>,
+    hash: Option<blake3::Hash>,
+}
+
+// A failed request, and whether the same request could go through if tried again: connection
+// failures, timeouts, 5xx and 429 could, any other status won't
+struct RequestError {
+    error: ShrLinkError,
+    transient: bool,
+}
+
+impl RequestError {
+    fn send(context: &str, e: &reqwest::Error) -> Self {
+        Self {
+            error: ShrLinkError::Network(format!("{}: {}", context, error_chain(e))),
+            transient: e.is_connect() || e.is_timeout(),
+        }
+    }
+    
+    fn status(context: &str, status: reqwest::StatusCode) -> Self {
+        if status == reqwest::StatusCode::UNAUTHORIZED || status == reqwest::StatusCode::FORBIDDEN {
+            return Self { error: ShrLinkError::Unauthorized(format!("{} with status: {}", context, status)), transient: false };
+        }
+        if status == reqwest::StatusCode::GONE {
+            return Self { error: ShrLinkError::LinkGone, transient: false };
+        }
+        Self {
+            error: ShrLinkError::Network(format!("{} with status: {}", context, status)),
+            transient: status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+}
+
+impl From<ShrLinkError> for RequestError {
+    fn from(error: ShrLinkError) -> Self {
+        Self { error, transient: false }
+    }
+}
+
+// As `HttpFallback::retrying`, for requests made away from it
+async fn retry<T, F, Fut>(action: &str, max_retries: u32, cancel: &CancellationToken, mut attempt: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = std::result::Result<T, RequestError>>,
+{
+    let mut retries = 0;
+    loop {
+        match attempt().await {
+            Ok(value) => return Ok(value),
+            Err(e) if e.transient && retries < max_retries => {
+                retries += 1;
+                let delay = retry_backoff(retries);
+                tracing::warn!(
+                    "{} failed (attempt {} of {}), retrying in {}ms: {}",
+                    action, retries, max_retries + 1, delay.as_millis(), e.error
+                );
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = cancel.cancelled() => return Err(ShrLinkError::Cancelled),
+                }
+            }
+            Err(e) => return Err(e.error),
+        }
+    }
+}
+
+// Doubles from RETRY_BACKOFF up to MAX_RETRY_BACKOFF, then a random part of the second half is
+// taken off so clients that failed together don't all come back together
+fn retry_backoff(retry: u32) -> Duration {
+    let ceiling = RETRY_BACKOFF.saturating_mul(1 << retry.saturating_sub(1).min(16)).min(MAX_RETRY_BACKOFF);
+    ceiling / 2 + ceiling.mul_f64(rand::random::<f64>() / 2.0)
+}
+
+pub struct BundleResponse {
+    body: Body,
+    // Where it came from in the end, without any hash, for asking it for more
+    url: String,
+    original_name: Option<String>,
+    root: Option<blake3::Hash>,
+    // What the server says the whole bundle hashes to, checked by `read`
+    bundle_hash: Option<blake3::Hash>,
+    progress: Option<DownloadProgress>,
+    // The start of the body, once `peek` has read it, to be read again ahead of the rest
+    head: Vec<u8>,
+}
+
+impl BundleResponse {
+    // Reports the body to `progress` as it's read, up to `content_length` in all
+    pub fn with_progress(mut self, progress: DownloadProgress) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+    
+    pub fn content_length(&self) -> Option<u64> {
+        match &self.body {
+            // What's left of it, which no longer counts what `peek` took
+            Body::Single(response) => response.content_length().map(|len| len + self.head.len() as u64),
+            Body::Ranged(ranges) => Some(ranges.len),
+            Body::File(_, len) => Some(*len),
+            Body::Stream(_, len) => *len,
+        }
+    }
+    
+    fn accepts_ranges(&self) -> bool {
+        match &self.body {
+            Body::Single(response) => response.headers().get(reqwest::header::ACCEPT_RANGES).is_some_and(|v| v.as_bytes() == b"bytes"),
+            Body::Ranged(_) => true,
+            // Resuming asks the server for the rest; a file is quicker read again from the start
+            Body::File(..) | Body::Stream(..) => false,
+        }
+    }
+    
+    pub fn original_name(&self) -> Option<&str> {
+        self.original_name.as_deref()
+    }
+    
+    // Up to `len` bytes from the start of the body as it was sent, which is still read from its
+    // first byte afterwards. A body fetched in ranges isn't fetched for this and shows nothing
+    pub async fn peek(&mut self, len: usize) -> Result<&[u8]> {
+        while self.head.len() < len {
+            let more = match &mut self.body {
+                Body::Single(response) => response
+                    .chunk()
+                    .await
+                    .map_err(|e| ShrLinkError::Network(format!("Failed to download from HTTP server: {}", error_chain(&e))))?,
+                Body::File(file, _) => {
+                    let mut more = vec![0; len - self.head.len()];
+                    let read = file.read(&mut more).await?;
+                    more.truncate(read);
+                    (read > 0).then(|| Bytes::from(more))
+                }
+                Body::Stream(body, _) => body.next().await.transpose()?,
+                Body::Ranged(_) => None,
+            };
+            match more {
+                Some(more) => self.head.extend_from_slice(&more),
+                None => break,
+            }
+        }
+        Ok(&self.head[..self.head.len().min(len)])
+    }
+    
+    // Items as the body arrives, for callers that write chunks out without keeping them
+    pub fn into_reader(self) -> ShrBundleReader<impl AsyncRead + Unpin> {
+        ShrBundleReader::new(self.into_body())
+    }
+    
+    // The whole bundle, checked as `into_body` checks it
+    pub async fn read(self) -> Result<(Vec<u8>, Option<String>)> {
+        let original_name = self.original_name.clone();
+        let mut bundle = Vec::with_capacity(self.content_length().unwrap_or(0).min(64 * 1024 * 1024) as usize);
+        self.into_body().read_to_end(&mut bundle).await?;
+        Ok((bundle, original_name))
+    }
+    
+    // The bundle itself, checked block by block when it was uploaded verified. Callers that have
+    // to decrypt it before there's a bundle to read start from this. Its end has to come where
+    // the body was said to, and when the server sent the bundle's hash, what came before has to
+    // hash to it; otherwise reading it fails there with `ShrLinkError::CorruptDownload`
+    pub fn into_body(self) -> VerifiedReader<impl AsyncRead + Unpin> {
+        let (total, expected) = (self.content_length(), self.bundle_hash);
+        let body = with_head(self.head, self.body.into_stream(self.progress.as_ref()), self.progress.as_ref());
+        VerifiedReader::new(tokio_util::io::StreamReader::new(checked(body, total, expected)), self.root)
+    }
+}
+
+type BodyStream = BoxStream<'static, std::io::Result<Bytes>>;
+
+// `body` after what `BundleResponse::peek` took from the front of it
+fn with_head(head: Vec<u8>, body: BodyStream, progress: Option<&DownloadProgress>) -> BodyStream {
+    if head.is_empty() {
+        return body;
+    }
+    let head = stream::once(future::ready(Ok(Bytes::from(head))));
+    match progress.cloned() {
+        Some(progress) => head.inspect_ok(move |bytes| progress(bytes.len() as u64)).chain(body).boxed(),
+        None => head.chain(body).boxed(),
+    }
+}
+
+// `body`, hashed as it goes past rather than held, with an error in place of its end when it
+// doesn't add up
+fn checked(body: BodyStream, total: Option<u64>, expected: Option<blake3::Hash>) -> BodyStream {
+    if total.is_none() && expected.is_none() {
+        return body;
+    }
+    let mut received = 0u64;
+    let mut hasher = expected.map(|_| blake3::Hasher::new());
+    body.map(Some).chain(stream::once(future::ready(None))).filter_map(move |item| future::ready(match item {
+        Some(Ok(bytes)) => {
+            received += bytes.len() as u64;
+            if let Some(hasher) = hasher.as_mut() {
+                hasher.update(&bytes);
+            }
+            Some(Ok(bytes))
+        }
+        Some(Err(e)) => Some(Err(e)),
+        None => {
+            let reason = match (total, expected) {
+                (Some(total), _) if total != received => Some(format!("received {} of the {} bytes the server announced", received, total)),
+                (_, Some(expected)) => hasher.as_ref().map(blake3::Hasher::finalize).filter(|actual| *actual != expected)
+                    .map(|actual| format!("the bundle hashes to {}, not the {} the server has for it", actual.to_hex(), expected.to_hex())),
+                _ => None,
+            };
+            reason.map(|reason| Err(std::io::Error::other(ShrLinkError::CorruptDownload(reason))))
+        }
+    })).boxed()
+}
+
+enum Body {
+    Single(reqwest::Response),
+    // Fetched over several connections at once the first time it's read
+    Ranged(Ranges),
+    // On a mount shared with the sender, with its length
+    File(tokio::fs::File, u64),
+    // Read some other way, such as over SFTP, with its length where that's known
+    #[cfg_attr(not(feature = "sftp"), allow(dead_code))]
+    Stream(BodyStream, Option<u64>),
+}
+
+impl Body {
+    // A ranged body reports its ranges as they come in, rather than the stitched body after
+    fn into_stream(self, progress: Option<&DownloadProgress>) -> BodyStream {
+        match self {
+            Body::Single(response) => {
+                let body = response.bytes_stream().map_err(|e| std::io::Error::other(error_chain(&e)));
+                match progress.cloned() {
+                    Some(progress) => body.inspect_ok(move |bytes| progress(bytes.len() as u64)).boxed(),
+                    None => body.boxed(),
+                }
+            }
+            Body::File(file, _) => {
+                let body = tokio_util::io::ReaderStream::new(file);
+                match progress.cloned() {
+                    Some(progress) => body.inspect_ok(move |bytes| progress(bytes.len() as u64)).boxed(),
+                    None => body.boxed(),
+                }
+            }
+            Body::Stream(body, _) => match progress.cloned() {
+                Some(progress) => body.inspect_ok(move |bytes| progress(bytes.len() as u64)).boxed(),
+                None => body,
+            },
+            Body::Ranged(mut ranges) => {
+                ranges.progress = progress.cloned();
+                stream::once(ranges.fetch())
+                    .map_ok(Stitched::into_stream)
+                    .map_err(std::io::Error::other)
+                    .try_flatten()
+                    .boxed()
+            }
+        }
+    }
+}
+
+// A body of `len` bytes the server serves in ranges, split evenly across `connections`
+struct Ranges {
+    client: reqwest::Client,
+    url: String,
+    token: Option<String>,
+    len: u64,
+    connections: u64,
+    max_retries: u32,
+    cancel: CancellationToken,
+    progress: Option<DownloadProgress>,
+}
+
+impl Ranges {
+    // Each connection's share as [start, end), the last also taking what doesn't divide evenly
+    fn spans(&self) -> Vec<(u64, u64)> {
+        let share = self.len / self.connections;
+        (0..self.connections)
+            .map(|i| (i * share, if i + 1 == self.connections { self.len } else { (i + 1) * share }))
+            .collect()
+    }
+    
+    // Every range at once, put back together in memory when the body is small enough and
+    // otherwise in a temp file the size of the body, each range written where it belongs
+    async fn fetch(self) -> Result<Stitched> {
+        tracing::info!("Downloading {} bytes over {} connections", self.len, self.connections);
+        let ranges = &self;
+        if self.len <= STITCH_IN_MEMORY_BYTES {
+            let parts = future::try_join_all(self.spans().into_iter().map(|(start, end)| async move {
+                let mut body = ranges.request(start, end).await?;
+                let (mut part, mut received) = (Vec::new(), 0);
+                while let Some(more) = next_body_chunk(&mut body).await? {
+                    received += more.len() as u64;
+                    part.push(more);
+                }
+                check_range_len(start, end, received)?;
+                Ok::<_, ShrLinkError>(part)
+            })).await?;
+            return Ok(Stitched::Memory(parts.into_iter().flatten().collect()));
+        }
+        
+        let guard = TempGuard::in_dir(&std::env::temp_dir(), "shr-download", ScratchKind::Temp)?;
+        tokio::fs::File::create(guard.path()).await?.set_len(self.len).await?;
+        let path = guard.path();
+        future::try_join_all(self.spans().into_iter().map(|(start, end)| async move {
+            let mut body = ranges.request(start, end).await?;
+            let mut file = tokio::fs::OpenOptions::new().write(true).open(path).await?;
+            file.seek(std::io::SeekFrom::Start(start)).await?;
+            let mut received = 0;
+            while let Some(more) = next_body_chunk(&mut body).await? {
+                received += more.len() as u64;
+                file.write_all(&more).await?;
+            }
+            file.flush().await?;
+            check_range_len(start, end, received)
+        })).await?;
+        Ok(Stitched::File(guard))
+    }
+    
+    // Only the range asked for will do; a whole body would mean the server stopped honouring
+    // ranges, and another length that the file changed underneath
+    async fn request(&self, start: u64, end: u64) -> Result<BodyStream> {
+        let response = retry("Ranged download", self.max_retries, &self.cancel, || async {
+            let response = with_token(self.client.get(&self.url), self.token.as_deref())
+                .header(reqwest::header::RANGE, format!("bytes={}-{}", start, end - 1))
+                .send()
+                .await
+                .map_err(|e| RequestError::send("Failed to download from HTTP server", &e))?;
+            match response.status() {
+                status if status.is_success() => Ok(response),
+                status => Err(RequestError::status("HTTP download failed", status)),
+            }
+        }).await?;
+        
+        let range = response.headers()
+            .get(reqwest::header::CONTENT_RANGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_content_range);
+        match (response.status(), range) {
+            (reqwest::StatusCode::PARTIAL_CONTENT, Some((first, Some(len)))) if first == start && len == self.len => Ok(Body::Single(response).into_stream(self.progress.as_ref())),
+            (status, _) => Err(ShrLinkError::Network(format!("HTTP server did not serve bytes {}-{} of {} (status {})", start, end - 1, self.len, status))),
+        }
+    }
+}
+
+fn check_range_len(start: u64, end: u64, received: u64) -> Result<()> {
+    if received != end - start {
+        return Err(ShrLinkError::Network(format!("HTTP server sent {} bytes for the {} from byte {}", received, end - start, start)));
+    }
+    Ok(())
+}
+
+// A ranged body put back together, read from the start
+enum Stitched {
+    Memory(Vec<Bytes>),
+    File(TempGuard),
+}
+
+impl Stitched {
+    fn into_stream(self) -> BodyStream {
+        match self {
+            Stitched::Memory(parts) => stream::iter(parts.into_iter().map(Ok)).boxed(),
+            Stitched::File(guard) => stream::once(async move {
+                    let file = tokio::fs::File::open(guard.path()).await?;
+                    Ok::<_, std::io::Error>((file, guard))
+                })
+                .map_ok(|(file, guard)| {
+                    // Held until the body has been read, then the file goes with it
+                    tokio_util::io::ReaderStream::new(file).map(move |block| {
+                        let _held = &guard;
+                        block
+                    })
+                })
+                .try_flatten()
+                .boxed(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
 pub struct FallbackStats {
     pub total_files: usize,
     pub total_bytes: u64,
+    // Each upload, where the store says more than the totals
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub files: Option<Vec<FileStats>>,
+}
+
+// One upload as the server that holds it reports it, with times in Unix seconds
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStats {
+    pub name: String,
+    pub size: u64,
+    pub uploaded_at: u64,
+    // When it goes, if it was uploaded with an expiry of its own; otherwise by cleanup's age
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    #[serde(default)]
+    pub downloads: u64,
+}
+
+// An upload cleanup removed, or on a dry run would have, by the name it's stored under
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileInfo {
+    pub name: String,
+    pub size: u64,
+    // Seconds since it was uploaded
+    pub age_secs: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct CleanupReport {
+    pub deleted: Vec<FileInfo>,
+    pub bytes_freed: u64,
+}
+
+impl CleanupReport {
+    pub fn new(deleted: Vec<FileInfo>) -> Self {
+        let bytes_freed = deleted.iter().map(|file| file.size).sum();
+        Self { deleted, bytes_freed }
+    }
+}
+
+// `buffer` and the rest of the body after it, through its tree when `root` is given and
+// otherwise only when it turns out to have one. What arrives is kept in `partial` too
+async fn read_rest(buffer: Vec<u8>, body: BodyStream, root: Option<blake3::Hash>, partial: Option<&mut PartialDownload>) -> Result<Vec<u8>> {
+    let mut bundle = Vec::new();
+    rest_of_body(buffer, body, root, partial).read_to_end(&mut bundle).await?;
+    Ok(bundle)
+}
+
+fn rest_of_body<'a>(buffer: Vec<u8>, body: BodyStream, root: Option<blake3::Hash>, mut partial: Option<&'a mut PartialDownload>) -> VerifiedReader<impl AsyncRead + Unpin + 'a> {
+    let rest = body.map(move |more| -> std::io::Result<Bytes> {
+        let more = more?;
+        if let Some(partial) = partial.as_deref_mut() {
+            partial.append(&more).map_err(std::io::Error::other)?;
+        }
+        Ok(more)
+    });
+    let body = stream::iter([Ok(Bytes::from(buffer))]).chain(rest);
+    VerifiedReader::new(tokio_util::io::StreamReader::new(body), root)
+}
+
+// As `read_rest`, but a plain bundle is decoded as it arrives and its items sent to `sink`, and
+// nothing comes back. Anything else is read whole without sending anything
+async fn stream_rest(buffer: Vec<u8>, body: BodyStream, root: Option<blake3::Hash>, partial: Option<&mut PartialDownload>, sink: &mpsc::Sender<BundleItem>) -> Result<Option<Vec<u8>>> {
+    let mut body = rest_of_body(buffer, body, root, partial);
+    let mut start = Vec::new();
+    (&mut body).take(3).read_to_end(&mut start).await?;
+    let plain = start.starts_with(b"SHR");
+    let mut body = std::io::Cursor::new(start).chain(body);
+    if !plain {
+        let mut bundle = Vec::new();
+        body.read_to_end(&mut bundle).await?;
+        return Ok(Some(bundle));
+    }
+    
+    let mut reader = ShrBundleReader::new(body);
+    while let Some(item) = reader.next_item_async().await? {
+        sink.send(item).await.map_err(|_| ShrLinkError::Cancelled)?;
+    }
+    Ok(None)
+}
+
+// Sent on when there's somewhere to stream them, otherwise kept for the bundle
+async fn take_item(items: &mut Vec<BundleItem>, sink: Option<&mpsc::Sender<BundleItem>>, item: BundleItem) -> Result<()> {
+    match sink {
+        // Only closed once whatever was taking them has given up
+        Some(sink) => sink.send(item).await.map_err(|_| ShrLinkError::Cancelled),
+        None => {
+            items.push(item);
+            Ok(())
+        }
+    }
+}
+
+// Bytes [start, end) of `header` followed by the file at `path`, the body of one part of an
+// upload, reported to `progress` as it's sent
+async fn part_body(path: &std::path::Path, header: &[u8], start: u64, end: u64, progress: Option<UploadProgress>) -> Result<BodyStream> {
+    let header_len = header.len() as u64;
+    let head = Bytes::copy_from_slice(&header[start.min(header_len) as usize..end.min(header_len) as usize]);
+    let mut file = tokio::fs::File::open(path).await?;
+    file.seek(std::io::SeekFrom::Start(start.saturating_sub(header_len))).await?;
+    let rest = end.saturating_sub(header_len.max(start));
+    
+    let contents = stream::once(future::ready(Ok(head)))
+        .chain(tokio_util::io::ReaderStream::new(file.take(rest)))
+        .inspect_ok(move |block| {
+            if let Some(progress) = &progress {
+                progress(block.len() as u64);
+            }
+        });
+    Ok(contents.boxed())
+}
+
+async fn next_body_chunk(body: &mut BodyStream) -> Result<Option<Bytes>> {
+    body.try_next().await
+        .map_err(|e| ShrLinkError::Network(format!("Failed to read HTTP response: {}", e)))
+}
+
+fn with_token(request: reqwest::RequestBuilder, token: Option<&str>) -> reqwest::RequestBuilder {
+    match token {
+        Some(token) => request.bearer_auth(token),
+        None => request,
+    }
+}
+
+// Prefer the name the server advertises, then whatever the URL carries
+fn advertised_name(headers: &reqwest::header::HeaderMap, url: &str) -> Option<String> {
+    let remote_name = headers
+        .get(reqwest::header::CONTENT_DISPOSITION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(filename::parse_content_disposition)
+        .or_else(|| extract_filename_from_url(url));
+    remote_name.as_deref().and_then(original_file_name)
+}
+
+// The bundle's hash as the server kept it from the upload. Servers other than `shr serve`, and
+// uploads that never sent it, go without, and the body is then only checked chunk by chunk
+fn advertised_hash(headers: &reqwest::header::HeaderMap, url: &str) -> Option<blake3::Hash> {
+    let hash = headers.get(BUNDLE_HASH_HEADER).and_then(|v| v.to_str().ok()).and_then(|v| blake3::Hash::from_hex(v.trim()).ok());
+    if hash.is_none() {
+        tracing::debug!("{} came without a usable {} header; not checking the body as a whole", url, BUNDLE_HASH_HEADER);
+    }
+    hash
+}
+
+// Where the server says the upload can be fetched: a `url` in its JSON reply, else an `id` under
+// /files/, else its Location header, with relative URLs taken against the endpoint. A server that
+// says none of these is assumed to file uploads under their own name. `template` overrides all of
+// it, with `{endpoint}`, `{name}` and `{id}` filled in; `{id}` is the upload's name when the
+// server gave none
+fn resolve_download_url(endpoint: &str, filename: &str, location: Option<&str>, body: &[u8], template: Option<&str>) -> String {
+    let reply = serde_json::from_slice::<serde_json::Value>(body).ok();
+    let id = reply.as_ref().and_then(|reply| match reply.get("id")? {
+        serde_json::Value::String(id) if !id.is_empty() => Some(id.clone()),
+        serde_json::Value::Number(id) => Some(id.to_string()),
+        _ => None,
+    });
+    
+    if let Some(template) = template {
+        return template
+            .replace("{endpoint}", endpoint)
+            .replace("{name}", &filename::encode_path_segment(filename))
+            .replace("{id}", &filename::encode_path_segment(id.as_deref().unwrap_or(filename)));
+    }
+    
+    let url = reply.as_ref().and_then(|reply| reply.get("url")?.as_str()).filter(|url| !url.is_empty());
+    let resolved = match (url, id) {
+        (Some(url), _) => join_endpoint(endpoint, url),
+        (None, Some(id)) => Some(format!("{}/files/{}", endpoint, filename::encode_path_segment(&id))),
+        (None, None) => location.and_then(|location| join_endpoint(endpoint, location)),
+    };
+    resolved.unwrap_or_else(|| format!("{}/files/{}", endpoint, filename::encode_path_segment(filename)))
+}
+
+fn join_endpoint(endpoint: &str, url: &str) -> Option<String> {
+    let base = url::Url::parse(&format!("{}/", endpoint.trim_end_matches('/'))).ok()?;
+    base.join(url).ok().map(String::from)
+}
+
+fn verified_url(download_url: String, root: Option<blake3::Hash>) -> String {
+    match root {
+        Some(root) => verify::url_with_hash(&download_url, &root),
+        None => download_url,
+    }
+}
+
+// The first byte and complete length from `bytes <first>-<last>/<length>`, where the length may be `*`
+fn parse_content_range(value: &str) -> Option<(u64, Option<u64>)> {
+    let (range, length) = value.strip_prefix("bytes ")?.split_once('/')?;
+    let start = range.split_once('-')?.0.parse().ok()?;
+    let length = match length {
+        "*" => None,
+        length => Some(length.parse().ok()?),
+    };
+    Some((start, length))
 }
 
 pub fn is_http_url(url: &str) -> bool {
     url.starts_with("http://") || url.starts_with("https://")
 }
 
+// What the file backend hands out, for a receiver that mounts the same share
+pub fn is_file_url(url: &str) -> bool {
+    url.starts_with("file://")
+}
+
+// What the sftp backend hands out, for a receiver that can log in to the same host
+pub fn is_sftp_url(url: &str) -> bool {
+    url.starts_with("sftp://")
+}
+
+#[cfg(not(feature = "sftp"))]
+fn no_sftp() -> ShrLinkError {
+    ShrLinkError::InvalidInput("fallback.backend = \"sftp\" and sftp:// URLs need shrlink built with the sftp feature".to_string())
+}
+
 pub fn extract_filename_from_url(url: &str) -> Option<String> {
-    if let Ok(parsed_url) = url::Url::parse(url) {
-        let path = parsed_url.path();
-        if let Some(filename) = path.split('/').last() {
-            if !filename.is_empty() {
-                Some(filename.to_string())
-            } else {
-                None
-            }
-        } else {
-            None
-        }
-    } else {
+    let parsed_url = url::Url::parse(url).ok()?;
+    let segment = parsed_url.path_segments()?.next_back()?;
+    
+    if segment.is_empty() {
         None
+    } else {
+        filename::decode_path_segment(segment)
+    }
+}
+
+// Uploads are stored as `<uuid>_<original name>.shr` so the name survives the round trip
+// without a server-side index, and the uuid keeps identical names from colliding
+pub fn remote_file_name(original_name: Option<&str>) -> String {
+    match original_name.and_then(filename::sanitize) {
+        Some(name) => format!("{}_{}.shr", Uuid::new_v4(), name),
+        None => format!("{}.shr", Uuid::new_v4()),
+    }
+}
+
+pub fn original_file_name(remote_name: &str) -> Option<String> {
+    let stem = remote_name.strip_suffix(".shr")?;
+    let (id, name) = stem.split_once('_')?;
+    
+    Uuid::parse_str(id).ok()?;
+    filename::sanitize(name)
+}
+
+fn multipart_prefix(boundary: &str, field: &str, file_name: &str) -> Vec<u8> {
+    let disposition = filename::content_disposition(&format!("form-data; name=\"{}\"", field), file_name);
+    
+    format!(
+        "--{}\r\nContent-Disposition: {}\r\nContent-Type: application/octet-stream\r\n\r\n",
+        boundary, disposition
+    )
+    .into_bytes()
+}
+
+fn multipart_suffix(boundary: &str) -> Vec<u8> {
+    format!("\r\n--{}--\r\n", boundary).into_bytes()
+}
+
+fn multipart_body(boundary: &str, field: &str, file_name: &str, data: &[u8]) -> Vec<u8> {
+    let mut body = multipart_prefix(boundary, field, file_name);
+    body.extend_from_slice(data);
+    body.extend_from_slice(&multipart_suffix(boundary));
+    body
+}
+
+// The client every request to the HTTP servers goes out on, S3 downloads included. With no
+// `proxy` set, reqwest picks up HTTPS_PROXY, HTTP_PROXY and NO_PROXY itself
+fn http_client(config: &FallbackConfig) -> Result<reqwest::Client> {
+    let failed = |e: reqwest::Error| ShrLinkError::Network(format!("Failed to create HTTP client: {}", e));
+    let mut builder = reqwest::Client::builder().timeout(Duration::from_secs(30));
+
+    if let Some(proxy) = config.proxy.as_deref().filter(|proxy| !proxy.is_empty()) {
+        // Credentials in the URL go to the proxy as basic auth
+        let proxy = reqwest::Proxy::all(proxy)
+            .map_err(|e| ShrLinkError::InvalidInput(format!("fallback.proxy {} is not a valid proxy URL: {}", proxy, e)))?
+            .no_proxy(reqwest::NoProxy::from_env());
+        builder = builder.proxy(proxy);
+    }
+
+    if let Some(path) = &config.ca_bundle_path {
+        let pem = std::fs::read(path)
+            .map_err(|e| ShrLinkError::Io(std::io::Error::new(e.kind(), format!("Reading fallback.ca_bundle_path {}: {}", path.display(), e))))?;
+        let certificates = reqwest::Certificate::from_pem_bundle(&pem)
+            .map_err(|e| ShrLinkError::InvalidInput(format!("fallback.ca_bundle_path {} is not a PEM bundle: {}", path.display(), e)))?;
+        if certificates.is_empty() {
+            return Err(ShrLinkError::InvalidInput(format!("fallback.ca_bundle_path {} holds no certificates", path.display())));
+        }
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+
+    if config.danger_accept_invalid_certs {
+        tracing::warn!("fallback.danger_accept_invalid_certs is set: server certificates are NOT checked, so anyone on the path can read or change what's sent");
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+
+    builder.build().map_err(failed)
+}
+
+// reqwest hides the cause of a failed body stream behind "error sending request"
+fn error_chain(error: &dyn std::error::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        message.push_str(": ");
+        message.push_str(&cause.to_string());
+        source = cause.source();
     }
+    message
 }
 
 #[cfg(test)]
@@ -183,23 +1811,119 @@ mod tests {
         assert!(is_http_url("http://example.com/file.shr"));
         assert!(!is_http_url("shr://peer123/hash456"));
         assert!(!is_http_url("file:///local/path"));
+        assert!(is_file_url("file:///mnt/shared/shr/0b4e3c1a-8f7e-4d52-9a55-3b7c1f2e9d10.shr"));
+        assert!(!is_file_url("https://example.com/file.shr"));
+        assert!(is_sftp_url("sftp://shr@files.example.com/srv/shr/0b4e3c1a-8f7e-4d52-9a55-3b7c1f2e9d10.shr"));
+        assert!(!is_sftp_url("file:///srv/shr/a.shr"));
     }
     
+    #[tokio::test]
+    async fn test_parts_split_across_the_hash_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bundle.shr");
+        std::fs::write(&path, b"0123456789").unwrap();
+        let header = b"SHRVhead";
+        
+        let mut whole = Vec::new();
+        for (start, end) in [(0, 5), (5, 11), (11, 18)] {
+            let part: Vec<Bytes> = part_body(&path, header, start, end, None).await.unwrap().try_collect().await.unwrap();
+            let part = part.concat();
+            assert_eq!(part.len() as u64, end - start);
+            whole.extend(part);
+        }
+        assert_eq!(whole, b"SHRVhead0123456789");
+    }
+
+    #[tokio::test]
+    async fn test_body_is_checked_as_it_streams() {
+        let pieces = || stream::iter([Ok(Bytes::from_static(b"first ")), Ok(Bytes::from_static(b"second"))]).boxed();
+        let hash = blake3::hash(b"first second");
+
+        let body: Vec<Bytes> = checked(pieces(), Some(12), Some(hash)).try_collect().await.unwrap();
+        assert_eq!(body.concat(), b"first second");
+
+        // Everything is still passed on, with the error where the end would have been
+        let body: Vec<_> = checked(pieces(), None, Some(blake3::hash(b"something else"))).collect().await;
+        assert_eq!(body.len(), 3);
+        let error = ShrLinkError::from(body.into_iter().last().unwrap().unwrap_err());
+        assert!(matches!(error, ShrLinkError::CorruptDownload(_)), "{:?}", error);
+
+        let error = ShrLinkError::from(checked(pieces(), Some(20), None).try_collect::<Vec<_>>().await.unwrap_err());
+        assert!(matches!(error, ShrLinkError::CorruptDownload(_)), "{:?}", error);
+    }
+
     #[test]
     fn test_filename_extraction() {
         let url = "http://localhost:8080/files/abc123.shr";
         let filename = extract_filename_from_url(url);
         assert_eq!(filename, Some("abc123.shr".to_string()));
+        
+        let url = "http://localhost:8080/files/%D0%BE%D1%82%D1%87%D1%91%D1%82%202024.pdf";
+        assert_eq!(extract_filename_from_url(url), Some("отчёт 2024.pdf".to_string()));
+    }
+    
+    #[test]
+    fn test_content_range_parsing() {
+        assert_eq!(parse_content_range("bytes 100-199/200"), Some((100, Some(200))));
+        assert_eq!(parse_content_range("bytes 5-9/*"), Some((5, None)));
+        assert_eq!(parse_content_range("bytes */200"), None);
+        assert_eq!(parse_content_range("items 0-1/2"), None);
+    }
+    
+    #[test]
+    fn test_download_url_from_upload_reply() {
+        let endpoint = "http://localhost:8080";
+        let resolve = |location, body: &str, template| resolve_download_url(endpoint, "a b.shr", location, body.as_bytes(), template);
+        
+        assert_eq!(resolve(None, r#"{"url": "https://cdn.example.com/x/1"}"#, None), "https://cdn.example.com/x/1");
+        assert_eq!(resolve(None, r#"{"url": "/d/1"}"#, None), "http://localhost:8080/d/1");
+        assert_eq!(resolve(None, r#"{"id": "abc 1"}"#, None), "http://localhost:8080/files/abc%201");
+        assert_eq!(resolve(None, r#"{"id": 42}"#, None), "http://localhost:8080/files/42");
+        assert_eq!(resolve(Some("/blobs/7"), "", None), "http://localhost:8080/blobs/7");
+        assert_eq!(resolve(Some("/blobs/7"), r#"{"id": "8"}"#, None), "http://localhost:8080/files/8");
+        assert_eq!(resolve(None, "", None), "http://localhost:8080/files/a%20b.shr");
+        assert_eq!(resolve(None, r#"{"url": ""}"#, None), "http://localhost:8080/files/a%20b.shr");
+        
+        assert_eq!(resolve(None, r#"{"id": "9"}"#, Some("https://cdn.example.com/{id}")), "https://cdn.example.com/9");
+        assert_eq!(resolve(None, "", Some("{endpoint}/get/{name}")), "http://localhost:8080/get/a%20b.shr");
+        assert_eq!(resolve(None, "", Some("{endpoint}/get/{id}")), "http://localhost:8080/get/a%20b.shr");
+    }
+    
+    #[test]
+    fn test_retry_backoff_is_jittered_below_a_doubling_ceiling() {
+        for _ in 0..20 {
+            let first = retry_backoff(1);
+            assert!(first >= RETRY_BACKOFF / 2 && first <= RETRY_BACKOFF, "{:?}", first);
+            let third = retry_backoff(3);
+            assert!(third >= RETRY_BACKOFF * 2 && third <= RETRY_BACKOFF * 4, "{:?}", third);
+            assert!(retry_backoff(40) <= MAX_RETRY_BACKOFF);
+        }
+    }
+    
+    #[test]
+    fn test_remote_name_roundtrip() {
+        for name in ["отчёт 2024.pdf", "会议记录.txt", "🎉 party.mov", "two  spaces.md"] {
+            let remote = remote_file_name(Some(name));
+            assert_eq!(original_file_name(&remote).as_deref(), Some(name));
+        }
+        
+        assert_eq!(original_file_name(&remote_file_name(None)), None);
+        assert_eq!(original_file_name("not-a-uuid_name.txt.shr"), None);
+    }
+    
+    #[test]
+    fn test_multipart_body_carries_both_filename_forms() {
+        let body = multipart_body("xyz", "file", "отчёт 2024.pdf", b"data");
+        let text = String::from_utf8(body).unwrap();
+        
+        assert!(text.starts_with("--xyz\r\n"));
+        assert!(text.contains("form-data; name=\"file\"; filename=\"_____ 2024.pdf\"; filename*=UTF-8''%D0%BE%D1%82%D1%87%D1%91%D1%82%202024.pdf"));
+        assert!(text.ends_with("\r\ndata\r\n--xyz--\r\n"));
     }
     
     #[tokio::test]
     async fn test_fallback_config() {
-        let config = FallbackConfig {
-            region: "".to_string(), // Not used for HTTP fallback
-            bucket: "".to_string(), // Not used for HTTP fallback
-            expiry_secs: 3600,
-            endpoint: Some("http://localhost:8080".to_string()),
-        };
+        let config = FallbackConfig::default();
         
         // Test that the config can be used to create a client
         let result = HttpFallback::new(config).await;
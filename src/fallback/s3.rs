@@ -0,0 +1,246 @@
+use url::Url;
+use crate::config::{FallbackConfig, S3Config, SseMode};
+use crate::filename;
+use crate::{Result, ShrLinkError};
+
+pub const DEFAULT_REGION: &str = "us-east-1";
+
+pub const SSE_HEADER: &str = "x-amz-server-side-encryption";
+pub const SSE_KMS_KEY_HEADER: &str = "x-amz-server-side-encryption-aws-kms-key-id";
+
+// Headers for PutObject, CreateMultipartUpload and UploadPart alike. Presigned GETs carry
+// none of these: S3 decrypts SSE-S3 and SSE-KMS objects transparently for authorized readers
+pub fn sse_headers(config: &S3Config) -> Result<Vec<(&'static str, String)>> {
+    match (config.sse, &config.kms_key_id) {
+        (SseMode::None, None) => Ok(vec![]),
+        (SseMode::S3, None) => Ok(vec![(SSE_HEADER, "AES256".to_string())]),
+        (SseMode::Kms, None) => Ok(vec![(SSE_HEADER, "aws:kms".to_string())]),
+        (SseMode::Kms, Some(key_id)) => Ok(vec![
+            (SSE_HEADER, "aws:kms".to_string()),
+            (SSE_KMS_KEY_HEADER, key_id.clone()),
+        ]),
+        (SseMode::None | SseMode::S3, Some(_)) => Err(ShrLinkError::InvalidInput(
+            "fallback.s3.kms_key_id is set but fallback.s3.sse is not \"kms\"".to_string(),
+        )),
+    }
+}
+
+// The region requests are signed for. S3-compatible services use pseudo-regions that AWS
+// would reject ("auto" on R2, "garage" on Garage), so whatever is configured is passed through
+// as-is; when nothing is configured it is derived from the endpoint where the service encodes it
+pub fn signing_region(config: &FallbackConfig) -> String {
+    let configured = config.region.trim();
+    if !configured.is_empty() {
+        return configured.to_string();
+    }
+
+    let host = config
+        .s3
+        .endpoint_url
+        .as_deref()
+        .and_then(|e| Url::parse(e).ok())
+        .and_then(|u| u.host_str().map(str::to_string));
+
+    match host {
+        // R2 signs everything with "auto"
+        Some(host) if host.ends_with(".r2.cloudflarestorage.com") => "auto".to_string(),
+        // B2 and AWS put the region in the hostname: s3.us-west-004.backblazeb2.com
+        Some(host) if host.ends_with(".backblazeb2.com") || host.ends_with(".amazonaws.com") => host
+            .split('.')
+            .nth(1)
+            .filter(|r| !r.is_empty())
+            .unwrap_or(DEFAULT_REGION)
+            .to_string(),
+        // MinIO and most self-hosted servers accept the AWS default
+        _ => DEFAULT_REGION.to_string(),
+    }
+}
+
+pub fn uses_path_style(config: &FallbackConfig) -> bool {
+    // Dotted bucket names break TLS wildcard matching on virtual-hosted URLs
+    config.s3.force_path_style || (config.s3.endpoint_url.is_none() && config.bucket.contains('.'))
+}
+
+pub fn object_url(config: &FallbackConfig, key: &str) -> Result<Url> {
+    if config.bucket.is_empty() {
+        return Err(ShrLinkError::InvalidInput("fallback.bucket must be set for the S3 backend".to_string()));
+    }
+
+    let endpoint = match &config.s3.endpoint_url {
+        Some(endpoint) => endpoint.trim_end_matches('/').to_string(),
+        None => format!("https://s3.{}.amazonaws.com", signing_region(config)),
+    };
+    let mut url = Url::parse(&endpoint)
+        .map_err(|e| ShrLinkError::InvalidInput(format!("Invalid fallback.s3.endpoint_url {}: {}", endpoint, e)))?;
+
+    let encoded_key: Vec<String> = key.split('/').map(filename::encode_path_segment).collect();
+    let base_path = url.path().trim_end_matches('/').to_string();
+
+    if uses_path_style(config) {
+        url.set_path(&format!("{}/{}/{}", base_path, config.bucket, encoded_key.join("/")));
+    } else {
+        let host = url
+            .host_str()
+            .ok_or_else(|| ShrLinkError::InvalidInput(format!("fallback.s3.endpoint_url has no host: {}", endpoint)))?;
+        let virtual_host = format!("{}.{}", config.bucket, host);
+        url.set_host(Some(&virtual_host))
+            .map_err(|e| ShrLinkError::InvalidInput(format!("Invalid bucket host {}: {}", virtual_host, e)))?;
+        url.set_path(&format!("{}/{}", base_path, encoded_key.join("/")));
+    }
+
+    Ok(url)
+}
+
+// Turns the S3 error codes people actually hit into something that says which setting to change
+pub fn explain_error(status: u16, body: &str, config: &FallbackConfig) -> Option<String> {
+    let code = error_code(body)?;
+    let s3 = &config.s3;
+
+    let hint = match (status, code) {
+        // Bucket policies that demand encryption reject plain PUTs with a bare AccessDenied
+        (403, "AccessDenied") => match s3.sse {
+            SseMode::None => "the bucket policy may require server-side encryption; set fallback.s3.sse = \"kms\" (and kms_key_id) or \"s3\"".to_string(),
+            SseMode::S3 => "the bucket policy may require SSE-KMS rather than SSE-S3; set fallback.s3.sse = \"kms\"".to_string(),
+            SseMode::Kms if s3.kms_key_id.is_none() => "the bucket policy may require a specific KMS key; set fallback.s3.kms_key_id".to_string(),
+            SseMode::Kms => "check that the credentials are allowed to use the configured KMS key (kms:GenerateDataKey)".to_string(),
+        },
+        (403, "SignatureDoesNotMatch") => format!(
+            "requests were signed for region \"{}\"; check fallback.region matches what the service expects (R2 uses \"auto\") and that the secret key is correct",
+            signing_region(config)
+        ),
+        (400, "AuthorizationHeaderMalformed") | (301, "PermanentRedirect") => match tag(body, "Region") {
+            Some(expected) => format!("the bucket lives in region \"{}\"; set fallback.region = \"{}\"", expected, expected),
+            None => "the bucket lives in a different region; check fallback.region".to_string(),
+        },
+        (404, "NoSuchBucket") if s3.endpoint_url.is_some() && !s3.force_path_style => format!(
+            "bucket \"{}\" was not found; most S3-compatible services need fallback.s3.force_path_style = true",
+            config.bucket
+        ),
+        (404, "NoSuchBucket") => format!("bucket \"{}\" does not exist at this endpoint; check fallback.bucket", config.bucket),
+        (400, "InvalidRequest") if !s3.disable_checksum_trailers => {
+            "the service rejected the request body encoding; set fallback.s3.disable_checksum_trailers = true".to_string()
+        }
+        _ => return None,
+    };
+
+    Some(format!("S3 rejected the request ({}): {}", code, hint))
+}
+
+pub fn error_code(body: &str) -> Option<&str> {
+    tag(body, "Code")
+}
+
+fn tag<'a>(body: &'a str, name: &str) -> Option<&'a str> {
+    let open = format!("<{}>", name);
+    let close = format!("</{}>", name);
+    let start = body.find(&open)? + open.len();
+    let end = body[start..].find(&close)? + start;
+    Some(body[start..end].trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::FallbackBackend;
+
+    const ACCESS_DENIED: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<Error><Code>AccessDenied</Code><Message>Access Denied</Message><RequestId>X</RequestId></Error>"#;
+
+    #[test]
+    fn test_sse_headers() {
+        let none = S3Config::default();
+        assert!(sse_headers(&none).unwrap().is_empty());
+
+        let s3 = S3Config { sse: SseMode::S3, ..Default::default() };
+        assert_eq!(sse_headers(&s3).unwrap(), vec![(SSE_HEADER, "AES256".to_string())]);
+
+        let kms = S3Config { sse: SseMode::Kms, kms_key_id: Some("alias/shrlink".to_string()), ..Default::default() };
+        assert_eq!(
+            sse_headers(&kms).unwrap(),
+            vec![(SSE_HEADER, "aws:kms".to_string()), (SSE_KMS_KEY_HEADER, "alias/shrlink".to_string())]
+        );
+
+        let confused = S3Config { sse: SseMode::S3, kms_key_id: Some("alias/shrlink".to_string()), ..Default::default() };
+        assert!(sse_headers(&confused).is_err());
+    }
+
+    fn fallback(bucket: &str, region: &str, s3: S3Config) -> FallbackConfig {
+        FallbackConfig {
+            backend: FallbackBackend::S3,
+            region: region.to_string(),
+            bucket: bucket.to_string(),
+            endpoints: Vec::new(),
+            s3,
+            ..Default::default()
+        }
+    }
+
+    fn endpoint(url: &str, force_path_style: bool) -> S3Config {
+        S3Config { endpoint_url: Some(url.to_string()), force_path_style, ..Default::default() }
+    }
+
+    #[test]
+    fn test_policy_rejection_hint() {
+        let hint = explain_error(403, ACCESS_DENIED, &fallback("b", "", S3Config::default())).unwrap();
+        assert!(hint.contains("fallback.s3.sse"));
+
+        let kms = S3Config { sse: SseMode::Kms, ..Default::default() };
+        assert!(explain_error(403, ACCESS_DENIED, &fallback("b", "", kms)).unwrap().contains("kms_key_id"));
+
+        assert!(explain_error(500, ACCESS_DENIED, &fallback("b", "", S3Config::default())).is_none());
+    }
+
+    #[test]
+    fn test_aws_urls() {
+        let aws = fallback("shr-files", "eu-west-1", S3Config::default());
+        assert_eq!(object_url(&aws, "2024/a b.shr").unwrap().as_str(), "https://shr-files.s3.eu-west-1.amazonaws.com/2024/a%20b.shr");
+
+        // Virtual-hosted addressing with a dotted bucket fails certificate validation
+        let dotted = fallback("files.example.com", "eu-west-1", S3Config::default());
+        assert_eq!(object_url(&dotted, "x.shr").unwrap().as_str(), "https://s3.eu-west-1.amazonaws.com/files.example.com/x.shr");
+    }
+
+    #[test]
+    fn test_minio_needs_path_style() {
+        // MinIO on a bare host:port has no wildcard DNS for bucket subdomains
+        let minio = fallback("shr", "", endpoint("http://127.0.0.1:9000", true));
+        assert_eq!(object_url(&minio, "x.shr").unwrap().as_str(), "http://127.0.0.1:9000/shr/x.shr");
+        assert_eq!(signing_region(&minio), "us-east-1");
+
+        let virtual_hosted = fallback("shr", "", endpoint("http://minio.local:9000", false));
+        assert_eq!(object_url(&virtual_hosted, "x.shr").unwrap().as_str(), "http://shr.minio.local:9000/x.shr");
+        let hint = explain_error(404, "<Error><Code>NoSuchBucket</Code></Error>", &virtual_hosted).unwrap();
+        assert!(hint.contains("force_path_style"));
+    }
+
+    #[test]
+    fn test_r2_pseudo_region() {
+        let r2 = fallback("shr", "", endpoint("https://0123abcd.r2.cloudflarestorage.com", false));
+        assert_eq!(signing_region(&r2), "auto");
+
+        // An explicit pseudo-region is passed through rather than rejected
+        let explicit = fallback("shr", "auto", endpoint("https://0123abcd.r2.cloudflarestorage.com", false));
+        assert_eq!(signing_region(&explicit), "auto");
+        let hint = explain_error(403, "<Error><Code>SignatureDoesNotMatch</Code></Error>", &explicit).unwrap();
+        assert!(hint.contains("\"auto\""));
+    }
+
+    #[test]
+    fn test_b2_region_from_endpoint() {
+        let b2 = fallback("shr", "", endpoint("https://s3.us-west-004.backblazeb2.com", false));
+        assert_eq!(signing_region(&b2), "us-west-004");
+        assert_eq!(object_url(&b2, "x.shr").unwrap().as_str(), "https://shr.s3.us-west-004.backblazeb2.com/x.shr");
+
+        // B2 rejects the aws-chunked trailer encoding newer SDKs default to
+        let hint = explain_error(400, "<Error><Code>InvalidRequest</Code></Error>", &b2).unwrap();
+        assert!(hint.contains("disable_checksum_trailers"));
+    }
+
+    #[test]
+    fn test_wrong_region_hint() {
+        let aws = fallback("shr-files", "us-east-1", S3Config::default());
+        let body = "<Error><Code>AuthorizationHeaderMalformed</Code><Region>eu-central-1</Region></Error>";
+        assert!(explain_error(400, body, &aws).unwrap().contains("fallback.region = \"eu-central-1\""));
+        assert!(explain_error(404, "<Error><Code>NoSuchBucket</Code></Error>", &aws).unwrap().contains("fallback.bucket"));
+    }
+}
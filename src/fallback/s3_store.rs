@@ -0,0 +1,441 @@
+use async_trait::async_trait;
+use aws_config::meta::region::RegionProviderChain;
+use aws_config::BehaviorVersion;
+use aws_sdk_s3::config::http::HttpResponse;
+use aws_sdk_s3::config::{Region, RequestChecksumCalculation};
+use aws_sdk_s3::error::{DisplayErrorContext, ProvideErrorMetadata, SdkError};
+use aws_sdk_s3::presigning::PresigningConfig;
+use aws_sdk_s3::primitives::{ByteStream, Length};
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart, Delete, ObjectIdentifier, ServerSideEncryption};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio_util::sync::CancellationToken;
+use crate::config::{FallbackConfig, SseMode};
+use crate::temp::{ScratchKind, TempGuard};
+use crate::{filename, verify};
+use crate::{Result, ShrLinkError};
+use super::s3::{explain_error, sse_headers, signing_region, uses_path_style, DEFAULT_REGION};
+use super::{extract_filename_from_url, remote_file_name, verified_url, CleanupReport, FallbackStats, FallbackStore, FileInfo, HttpFallback, UploadProgress};
+
+// Everything shrLink uploads sits under this prefix, so cleanup and stats leave the rest of a
+// shared bucket alone
+pub const KEY_PREFIX: &str = "shrlink/";
+
+// DeleteObjects takes at most this many keys per request
+const DELETE_BATCH: usize = 1000;
+
+// Set to the Unix time an upload with `upload_expiry_secs` is due to go, which cleanup honours
+// over `expiry_secs`
+pub const EXPIRES_TAG: &str = "shrlink-expires-at";
+
+// How many objects cleanup reads the tags of at once
+const TAG_READS: usize = 16;
+
+// S3 takes parts of at least 5 MiB, but for the last, and at most 10,000 of them
+const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+const MAX_PARTS: u64 = 10_000;
+
+// Uploads with the AWS SDK and hands out presigned GET URLs that last `expiry_secs`. Those are
+// plain HTTPS, so receivers fetch them with `HttpFallback` and need no credentials of their own
+pub struct S3Store {
+    client: aws_sdk_s3::Client,
+    config: FallbackConfig,
+    cancel: CancellationToken,
+    progress: Option<UploadProgress>,
+    downloads: HttpFallback,
+}
+
+struct ListedObject {
+    key: String,
+    size: u64,
+    modified_at: i64,
+}
+
+impl S3Store {
+    // Credentials come from the standard AWS chain: environment, profile, then instance role
+    pub async fn new(config: FallbackConfig) -> Result<Self> {
+        // Checked up front, rather than on the first upload after minutes of compression
+        sse_headers(&config.s3)?;
+        if config.bucket.is_empty() {
+            return Err(ShrLinkError::InvalidInput("fallback.bucket must be set for the S3 backend".to_string()));
+        }
+        // A presigned URL can be fetched any number of times until it expires
+        if config.download_once {
+            return Err(ShrLinkError::InvalidInput("One-time links need the HTTP fallback backend; S3 can't serve an object only once".to_string()));
+        }
+
+        // A configured region or endpoint decides the signing region; otherwise the chain's, if any
+        let configured = (!config.region.trim().is_empty() || config.s3.endpoint_url.is_some())
+            .then(|| Region::new(signing_region(&config)));
+        let region = RegionProviderChain::first_try(configured)
+            .or_default_provider()
+            .or_else(Region::from_static(DEFAULT_REGION));
+        let mut loader = aws_config::defaults(BehaviorVersion::latest()).region(region);
+        if let Some(endpoint) = &config.s3.endpoint_url {
+            loader = loader.endpoint_url(endpoint);
+        }
+        let shared = loader.load().await;
+
+        let mut s3_config = aws_sdk_s3::config::Builder::from(&shared).force_path_style(uses_path_style(&config));
+        if config.s3.disable_checksum_trailers {
+            s3_config = s3_config.request_checksum_calculation(RequestChecksumCalculation::WhenRequired);
+        }
+        let client = aws_sdk_s3::Client::from_conf(s3_config.build());
+        let downloads = HttpFallback::new(config.clone()).await?;
+
+        Ok(Self { client, config, cancel: CancellationToken::new(), progress: None, downloads })
+    }
+
+    // Uploads then stop as soon as `cancel` fires and fail with `ShrLinkError::Cancelled`. A
+    // PutObject that never completes leaves no object behind, so there is nothing to delete; a
+    // multipart upload is aborted, which lets go of its parts
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    // PutObject sends its body out of sight, so the whole upload is reported once it's in, and a
+    // multipart upload part by part
+    pub fn with_upload_progress(mut self, progress: UploadProgress) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    fn key_for_url(url: &str) -> Result<String> {
+        let (url, _) = verify::split_url_hash(url)?;
+        let name = extract_filename_from_url(url)
+            .ok_or_else(|| ShrLinkError::InvalidInput(format!("Not an S3 fallback URL: {}", url)))?;
+        Ok(format!("{}{}", KEY_PREFIX, name))
+    }
+
+    async fn list_objects(&self) -> Result<Vec<ListedObject>> {
+        let mut pages = self.client
+            .list_objects_v2()
+            .bucket(&self.config.bucket)
+            .prefix(KEY_PREFIX)
+            .into_paginator()
+            .send();
+
+        let mut objects = Vec::new();
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(|e| s3_error("Listing the bucket", e, &self.config))?;
+            for object in page.contents() {
+                let Some(key) = object.key() else {
+                    continue;
+                };
+                objects.push(ListedObject {
+                    key: key.to_string(),
+                    size: object.size().unwrap_or_default().max(0) as u64,
+                    modified_at: object.last_modified().map(|t| t.secs()).unwrap_or_default(),
+                });
+            }
+        }
+        Ok(objects)
+    }
+
+    async fn tagged_expiry(&self, key: &str) -> Result<Option<i64>> {
+        let output = self.client
+            .get_object_tagging()
+            .bucket(&self.config.bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(|e| s3_error("Reading tags", e, &self.config))?;
+        Ok(output.tag_set().iter().find(|tag| tag.key() == EXPIRES_TAG).and_then(|tag| tag.value().parse().ok()))
+    }
+
+    async fn delete_keys(&self, keys: &[String]) -> Result<()> {
+        for batch in keys.chunks(DELETE_BATCH) {
+            let objects = batch
+                .iter()
+                .map(|key| ObjectIdentifier::builder().key(key).build())
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .map_err(|e| ShrLinkError::Other(e.into()))?;
+            let delete = Delete::builder()
+                .set_objects(Some(objects))
+                .quiet(true)
+                .build()
+                .map_err(|e| ShrLinkError::Other(e.into()))?;
+
+            let output = self.client
+                .delete_objects()
+                .bucket(&self.config.bucket)
+                .delete(delete)
+                .send()
+                .await
+                .map_err(|e| s3_error("Deleting old uploads", e, &self.config))?;
+            // Quiet mode only reports the keys that failed
+            if let Some(failed) = output.errors().first() {
+                return Err(ShrLinkError::Network(format!(
+                    "Deleting {} failed with S3 error {}: {}",
+                    failed.key().unwrap_or_default(),
+                    failed.code().unwrap_or("unknown"),
+                    failed.message().unwrap_or_default()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    async fn put_file(&self, path: &Path, key: &str, name: &str) -> Result<()> {
+        let len = tokio::fs::metadata(path).await?.len();
+        let part_size = self.config.upload_part_size;
+        if part_size > 0 && len > part_size {
+            return self.put_file_in_parts(path, key, name, len).await;
+        }
+
+        let body = ByteStream::from_path(path)
+            .await
+            .map_err(|e| ShrLinkError::Other(e.into()))?;
+        let mut request = self.client
+            .put_object()
+            .bucket(&self.config.bucket)
+            .key(key)
+            .body(body)
+            .content_type("application/octet-stream")
+            // Downloads read the original name back from here, as they do from the HTTP server
+            .content_disposition(filename::content_disposition("attachment", name))
+            .set_tagging(self.config.upload_expiry_secs.map(|secs| format!("{}={}", EXPIRES_TAG, unix_now() + secs as i64)));
+        request = match self.config.s3.sse {
+            SseMode::None => request,
+            SseMode::S3 => request.server_side_encryption(ServerSideEncryption::Aes256),
+            SseMode::Kms => request
+                .server_side_encryption(ServerSideEncryption::AwsKms)
+                .set_ssekms_key_id(self.config.s3.kms_key_id.clone()),
+        };
+
+        tokio::select! {
+            sent = request.send() => sent.map_err(|e| s3_error("Upload", e, &self.config))?,
+            _ = self.cancel.cancelled() => return Err(ShrLinkError::Cancelled),
+        };
+        if let Some(progress) = &self.progress {
+            progress(len);
+        }
+        Ok(())
+    }
+
+    // As a multipart upload in parts of `upload_part_size`, raised where S3's limits need it.
+    // The SDK retries each part on its own, as it does any request
+    async fn put_file_in_parts(&self, path: &Path, key: &str, name: &str, len: u64) -> Result<()> {
+        let mut request = self.client
+            .create_multipart_upload()
+            .bucket(&self.config.bucket)
+            .key(key)
+            .content_type("application/octet-stream")
+            .content_disposition(filename::content_disposition("attachment", name))
+            .set_tagging(self.config.upload_expiry_secs.map(|secs| format!("{}={}", EXPIRES_TAG, unix_now() + secs as i64)));
+        request = match self.config.s3.sse {
+            SseMode::None => request,
+            SseMode::S3 => request.server_side_encryption(ServerSideEncryption::Aes256),
+            SseMode::Kms => request
+                .server_side_encryption(ServerSideEncryption::AwsKms)
+                .set_ssekms_key_id(self.config.s3.kms_key_id.clone()),
+        };
+        let created = request.send().await.map_err(|e| s3_error("Starting the upload", e, &self.config))?;
+        let upload_id = created.upload_id()
+            .ok_or_else(|| ShrLinkError::Network("S3 started the upload without giving its id".to_string()))?
+            .to_string();
+
+        let part_size = self.config.upload_part_size.max(MIN_PART_SIZE).max(len.div_ceil(MAX_PARTS));
+        let completed = async {
+            let parts = self.put_parts(path, key, &upload_id, len, part_size).await?;
+            self.client
+                .complete_multipart_upload()
+                .bucket(&self.config.bucket)
+                .key(key)
+                .upload_id(&upload_id)
+                .multipart_upload(CompletedMultipartUpload::builder().set_parts(Some(parts)).build())
+                .send()
+                .await
+                .map_err(|e| s3_error("Completing the upload", e, &self.config))?;
+            Ok(())
+        }.await;
+        if completed.is_err() {
+            let aborted = self.client.abort_multipart_upload().bucket(&self.config.bucket).key(key).upload_id(&upload_id).send().await;
+            if let Err(e) = aborted {
+                tracing::warn!("Could not abort the multipart upload of {}: {}", key, s3_error("Abort", e, &self.config));
+            }
+        }
+        completed
+    }
+
+    async fn put_parts(&self, path: &Path, key: &str, upload_id: &str, len: u64, part_size: u64) -> Result<Vec<CompletedPart>> {
+        let mut parts = Vec::new();
+        for (number, start) in (1..).zip((0..len).step_by(part_size as usize)) {
+            let length = part_size.min(len - start);
+            let body = ByteStream::read_from()
+                .path(path)
+                .offset(start)
+                .length(Length::Exact(length))
+                .build()
+                .await
+                .map_err(|e| ShrLinkError::Other(e.into()))?;
+            let request = self.client
+                .upload_part()
+                .bucket(&self.config.bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .part_number(number)
+                .content_length(length as i64)
+                .body(body);
+            let sent = tokio::select! {
+                sent = request.send() => sent.map_err(|e| s3_error("Uploading a part", e, &self.config))?,
+                _ = self.cancel.cancelled() => return Err(ShrLinkError::Cancelled),
+            };
+            if let Some(progress) = &self.progress {
+                progress(length);
+            }
+            parts.push(CompletedPart::builder().part_number(number).set_e_tag(sent.e_tag().map(str::to_string)).build());
+        }
+        tracing::debug!("Uploaded s3://{}/{} in {} parts", self.config.bucket, key, parts.len());
+        Ok(parts)
+    }
+}
+
+#[async_trait]
+impl FallbackStore for S3Store {
+    // With `verified_uploads` the hash tree has to go in front of the bundle, and PutObject needs
+    // to know the body's length up front, so the two are staged together beside it first
+    async fn upload_file(&self, path: &Path, original_name: Option<&str>) -> Result<String> {
+        let name = remote_file_name(original_name);
+        let key = format!("{}{}", KEY_PREFIX, name);
+
+        let (staged, root) = if self.config.verified_uploads {
+            let source = path.to_path_buf();
+            let staged = TempGuard::beside(path, ScratchKind::Spool)?;
+            let target = staged.path().to_path_buf();
+            let root = tokio::task::spawn_blocking(move || -> Result<blake3::Hash> {
+                let (root, header) = verify::outboard(std::io::BufReader::new(std::fs::File::open(&source)?))?;
+                let mut out = std::io::BufWriter::new(std::fs::File::create(&target)?);
+                out.write_all(&header)?;
+                std::io::copy(&mut std::fs::File::open(&source)?, &mut out)?;
+                out.flush()?;
+                Ok(root)
+            });
+            let root = root.await.map_err(|e| ShrLinkError::Other(e.into()))??;
+            (Some(staged), Some(root))
+        } else {
+            (None, None)
+        };
+
+        let body_path = staged.as_ref().map_or(path, |staged| staged.path());
+        self.put_file(body_path, &key, &name).await?;
+
+        let (expiry, setting) = match self.config.upload_expiry_secs {
+            Some(secs) => (secs, "The upload's expiry"),
+            None => (self.config.expiry_secs, "fallback.expiry_secs"),
+        };
+        let expires_in = PresigningConfig::expires_in(Duration::from_secs(expiry))
+            .map_err(|e| ShrLinkError::InvalidInput(format!("{} can't be used for a presigned URL: {}", setting, e)))?;
+        let presigned = self.client
+            .get_object()
+            .bucket(&self.config.bucket)
+            .key(&key)
+            .presigned(expires_in)
+            .await
+            .map_err(|e| ShrLinkError::Other(e.into()))?;
+
+        tracing::info!("Uploaded s3://{}/{}", self.config.bucket, key);
+        Ok(verified_url(presigned.uri().to_string(), root))
+    }
+
+    async fn download_bundle(&self, url: &str) -> Result<(Vec<u8>, Option<String>)> {
+        self.downloads.download_bundle(url).await
+    }
+
+    // DeleteObject succeeds whether or not there was such an object, so it's looked for first
+    async fn delete_file(&self, url: &str) -> Result<bool> {
+        let key = Self::key_for_url(url)?;
+        let head = self.client.head_object().bucket(&self.config.bucket).key(&key).send().await;
+        match head {
+            Ok(_) => {}
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => return Ok(false),
+            Err(e) => return Err(s3_error("Delete", e, &self.config)),
+        }
+        self.client
+            .delete_object()
+            .bucket(&self.config.bucket)
+            .key(&key)
+            .send()
+            .await
+            .map_err(|e| s3_error("Delete", e, &self.config))?;
+        Ok(true)
+    }
+
+    // The same as a lifecycle rule expiring the prefix after `expiry_secs`, for buckets that
+    // don't have one. Objects tagged with their own expiry go by that instead
+    async fn cleanup_old_files(&self, dry_run: bool) -> Result<CleanupReport> {
+        let now = unix_now();
+        let max_age = self.config.expiry_secs as i64;
+        let expired: Vec<ListedObject> = stream::iter(self.list_objects().await?)
+            .map(|object| async move {
+                let expired = match self.tagged_expiry(&object.key).await? {
+                    Some(expires_at) => now >= expires_at,
+                    None => now.saturating_sub(object.modified_at) > max_age,
+                };
+                Ok::<_, ShrLinkError>(expired.then_some(object))
+            })
+            .buffer_unordered(TAG_READS)
+            .try_filter_map(|object| async move { Ok(object) })
+            .try_collect()
+            .await?;
+
+        if !dry_run {
+            let keys: Vec<String> = expired.iter().map(|object| object.key.clone()).collect();
+            self.delete_keys(&keys).await?;
+            tracing::info!("Cleanup deleted {} objects from s3://{}/{}", keys.len(), self.config.bucket, KEY_PREFIX);
+        }
+        Ok(CleanupReport::new(expired.into_iter().map(|object| FileInfo {
+            name: object.key.strip_prefix(KEY_PREFIX).unwrap_or(&object.key).to_string(),
+            size: object.size,
+            age_secs: now.saturating_sub(object.modified_at).max(0) as u64,
+        }).collect()))
+    }
+
+    async fn get_upload_stats(&self) -> Result<FallbackStats> {
+        let objects = self.list_objects().await?;
+        Ok(FallbackStats {
+            total_files: objects.len(),
+            total_bytes: objects.iter().map(|object| object.size).sum(),
+            files: None,
+        })
+    }
+}
+
+// Carries the S3 error code, with a hint at which setting to change where there is one
+fn s3_error<E: ProvideErrorMetadata + std::error::Error + 'static>(action: &str, error: SdkError<E, HttpResponse>, config: &FallbackConfig) -> ShrLinkError {
+    let explained = error.raw_response().and_then(|response| {
+        let body = std::str::from_utf8(response.body().bytes()?).ok()?;
+        explain_error(response.status().as_u16(), body, config)
+    });
+    let message = match (explained, error.code()) {
+        (Some(hint), _) => hint,
+        (None, Some(code)) => format!("S3 error {}: {}", code, error.message().unwrap_or_default()),
+        (None, None) => DisplayErrorContext(&error).to_string(),
+    };
+    ShrLinkError::Network(format!("{} failed: {}", action, message))
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_for_url() {
+        let presigned = "https://shr-files.s3.eu-west-1.amazonaws.com/shrlink/0b4e3c1a-8f7e-4d52-9a55-3b7c1f2e9d10_report.pdf.shr?X-Amz-Expires=3600&X-Amz-Signature=abc";
+        assert_eq!(
+            S3Store::key_for_url(presigned).unwrap(),
+            "shrlink/0b4e3c1a-8f7e-4d52-9a55-3b7c1f2e9d10_report.pdf.shr"
+        );
+
+        let path_style = "http://localhost:9000/bucket/shrlink/caf%C3%A9.shr?X-Amz-Signature=abc";
+        assert_eq!(S3Store::key_for_url(path_style).unwrap(), "shrlink/café.shr");
+        assert!(S3Store::key_for_url("http://localhost:9000/").is_err());
+    }
+}
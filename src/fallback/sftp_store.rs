@@ -0,0 +1,432 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::{self, StreamExt};
+use percent_encoding::percent_decode_str;
+use ssh2::{CheckResult, ErrorCode, FileStat, KnownHostFileKind, Session, Sftp};
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio_util::sync::CancellationToken;
+use crate::config::{FallbackConfig, SftpConfig};
+use crate::temp::{DEFAULT_SWEEP_TTL, TEMP_SUFFIX};
+use crate::verify;
+use crate::{Result, ShrLinkError};
+use super::{original_file_name, remote_file_name, verified_url, Body, BundleResponse, CleanupReport, FallbackStats, FallbackStore, FileInfo, UploadProgress};
+
+// What's read from or written to the SFTP channel at a time
+const BLOCK: usize = 256 * 1024;
+// Downloaded blocks waiting to be read, so a slow reader holds the channel back rather than
+// filling memory
+const READ_AHEAD: usize = 8;
+// For connecting, and for each request once connected
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+// SFTP status codes libssh2 passes on
+const FX_NO_SUCH_FILE: i32 = 2;
+const FX_PERMISSION_DENIED: i32 = 3;
+
+// Uploads to `sftp.remote_dir` on an SSH host and hands out sftp://user@host/path URLs to them,
+// which receivers download over SFTP with their own key or agent. libssh2 blocks, so each
+// operation gets its own connection on a blocking thread. Cleanup goes by each file's mtime
+pub struct SftpStore {
+    config: FallbackConfig,
+    cancel: CancellationToken,
+    progress: Option<UploadProgress>,
+}
+
+// Where an sftp:// URL points
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SftpLocation {
+    pub user: String,
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl SftpLocation {
+    pub fn parse(url: &str) -> Result<Self> {
+        let invalid = |why: &str| ShrLinkError::InvalidInput(format!("{} is not an sftp:// URL {}", url, why));
+        let parsed = url::Url::parse(url).map_err(|e| invalid(&format!("({})", e)))?;
+        if parsed.scheme() != "sftp" {
+            return Err(invalid("at all"));
+        }
+        let host = parsed.host_str().filter(|host| !host.is_empty()).ok_or_else(|| invalid("with a host"))?;
+        let decode = |s: &str| percent_decode_str(s).decode_utf8().map(|s| s.into_owned()).map_err(|_| invalid("with a UTF-8 path"));
+        let user = decode(parsed.username())?;
+        if user.is_empty() {
+            return Err(invalid("with the user to log in as"));
+        }
+
+        Ok(Self { user, host: host.to_string(), port: parsed.port().unwrap_or(22), path: decode(parsed.path())? })
+    }
+
+    // The port is left out when it's SSH's own
+    pub fn url(&self) -> Result<String> {
+        let invalid = || ShrLinkError::InvalidInput(format!("{}@{}:{} can't be put in an sftp:// URL", self.user, self.host, self.path));
+        let mut url = url::Url::parse(&format!("sftp://{}", self.host)).map_err(|_| invalid())?;
+        url.set_username(&self.user).map_err(|_| invalid())?;
+        url.set_port((self.port != 22).then_some(self.port)).map_err(|_| invalid())?;
+        url.set_path(&self.path);
+        Ok(url.to_string())
+    }
+}
+
+impl SftpStore {
+    pub fn new(config: FallbackConfig) -> Result<Self> {
+        let sftp = &config.sftp;
+        if sftp.host.is_empty() || sftp.user.is_empty() || sftp.remote_dir.is_empty() {
+            return Err(ShrLinkError::InvalidInput("fallback.sftp.host, user and remote_dir must be set for the sftp backend".to_string()));
+        }
+        // Anyone who can log in can read a file as often as they like
+        if config.download_once {
+            return Err(ShrLinkError::InvalidInput("One-time links need the HTTP fallback backend; a file on an SFTP host can be read any number of times".to_string()));
+        }
+
+        Ok(Self { config, cancel: CancellationToken::new(), progress: None })
+    }
+
+    // Uploads then stop between blocks once `cancel` fires and fail with
+    // `ShrLinkError::Cancelled`, removing what they'd written
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    pub fn with_upload_progress(mut self, progress: UploadProgress) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    // Runs `action` on a blocking thread with a fresh connection and `remote_dir` resolved to
+    // an absolute path, so a directory relative to the user's home still gives whole URLs
+    async fn with_sftp<T: Send + 'static>(&self, action: impl FnOnce(&Sftp, &str) -> Result<T> + Send + 'static) -> Result<T> {
+        let config = self.config.sftp.clone();
+        let blocking = tokio::task::spawn_blocking(move || {
+            let sftp = connect(&config, &config.host, config.port, &config.user)?;
+            let dir = sftp.realpath(Path::new(&config.remote_dir))
+                .map_err(|e| sftp_error(&format!("Opening {}", config.remote_dir), e))?;
+            action(&sftp, dir.to_string_lossy().trim_end_matches('/'))
+        });
+        blocking.await.map_err(|e| ShrLinkError::Other(e.into()))?
+    }
+}
+
+#[async_trait]
+impl FallbackStore for SftpStore {
+    // Written beside its final name and renamed into place, so a receiver never reads half an
+    // upload. As the S3 backend does, with `verified_uploads` the hash tree goes in front
+    async fn upload_file(&self, path: &Path, original_name: Option<&str>) -> Result<String> {
+        let name = remote_file_name(original_name);
+        let (source, verified) = (path.to_path_buf(), self.config.verified_uploads);
+        let (cancel, progress) = (self.cancel.clone(), self.progress.clone());
+        // Cleanup deletes what's older than `expiry_secs` by mtime, so an upload with its own
+        // expiry is dated to reach that age when it's due
+        let dated = self.config.upload_expiry_secs.map(|secs| (unix_now() + secs).saturating_sub(self.config.expiry_secs));
+
+        let (remote, root) = self.with_sftp(move |sftp, dir| {
+            let (root, header) = if verified {
+                let (root, header) = verify::outboard(std::io::BufReader::new(std::fs::File::open(&source)?))?;
+                (Some(root), header)
+            } else {
+                (None, Vec::new())
+            };
+
+            let (target, staged) = (format!("{}/{}", dir, name), format!("{}/.{}{}", dir, name, TEMP_SUFFIX));
+            let copied = copy_to(sftp, &source, &staged, &header, &cancel, progress.as_ref()).and_then(|()| {
+                if let Some(mtime) = dated {
+                    let stat = FileStat { size: None, uid: None, gid: None, perm: None, atime: Some(mtime), mtime: Some(mtime) };
+                    sftp.setstat(Path::new(&staged), stat).map_err(|e| sftp_error(&format!("Dating {}", staged), e))?;
+                }
+                sftp.rename(Path::new(&staged), Path::new(&target), None).map_err(|e| sftp_error(&format!("Renaming {}", staged), e))
+            });
+            if let Err(e) = copied {
+                let _ = sftp.unlink(Path::new(&staged));
+                return Err(e);
+            }
+            Ok((target, root))
+        }).await?;
+
+        let sftp = &self.config.sftp;
+        let url = SftpLocation { user: sftp.user.clone(), host: sftp.host.clone(), port: sftp.port, path: remote }.url()?;
+        tracing::info!("Uploaded {}", url);
+        Ok(verified_url(url, root))
+    }
+
+    async fn download_bundle(&self, url: &str) -> Result<(Vec<u8>, Option<String>)> {
+        let (url, root) = verify::split_url_hash(url)?;
+        open_file(url, root, &self.config.sftp).await?.read().await
+    }
+
+    // Only files directly in `remote_dir` on the configured host can be named, so a URL can't
+    // reach anywhere else the user can write
+    async fn delete_file(&self, url: &str) -> Result<bool> {
+        let (url, _) = verify::split_url_hash(url)?;
+        let location = SftpLocation::parse(url)?;
+        if location.host != self.config.sftp.host {
+            return Err(ShrLinkError::InvalidInput(format!("{} isn't on fallback.sftp.host ({})", url, self.config.sftp.host)));
+        }
+        let url = url.to_string();
+
+        self.with_sftp(move |sftp, dir| {
+            let parent = location.path.rsplit_once('/').map_or("", |(parent, _)| parent);
+            let parent = sftp.realpath(Path::new(parent)).map_err(|e| sftp_error(&format!("Opening {}", parent), e))?;
+            if parent.to_string_lossy().trim_end_matches('/') != dir {
+                return Err(ShrLinkError::InvalidInput(format!("{} isn't in fallback.sftp.remote_dir ({})", url, dir)));
+            }
+            match sftp.unlink(Path::new(&location.path)) {
+                Ok(()) => Ok(true),
+                Err(e) if is_not_found(&e) => Ok(false),
+                Err(e) => Err(sftp_error(&format!("Deleting {}", location.path), e)),
+            }
+        }).await
+    }
+
+    // Copies that never finished go too, once they're a day old
+    async fn cleanup_old_files(&self, dry_run: bool) -> Result<CleanupReport> {
+        let max_age = self.config.expiry_secs;
+        let deleted = self.with_sftp(move |sftp, dir| {
+            let now = unix_now();
+            let mut deleted = Vec::new();
+            for (path, stat) in list_uploads(sftp, dir)? {
+                let age = now.saturating_sub(stat.mtime.unwrap_or(now));
+                if age <= max_age {
+                    continue;
+                }
+                let name = path.rsplit('/').next().unwrap_or(&path).to_string();
+                let file = FileInfo { name, size: stat.size.unwrap_or_default(), age_secs: age };
+                if dry_run {
+                    deleted.push(file);
+                    continue;
+                }
+                match sftp.unlink(Path::new(&path)) {
+                    Ok(()) => deleted.push(file),
+                    Err(e) if is_not_found(&e) => {}
+                    Err(e) => return Err(sftp_error(&format!("Deleting {}", path), e)),
+                }
+            }
+            if dry_run {
+                return Ok(deleted);
+            }
+
+            for (path, stat) in sftp.readdir(Path::new(dir)).map_err(|e| sftp_error(&format!("Listing {}", dir), e))? {
+                let abandoned = path.to_string_lossy().ends_with(TEMP_SUFFIX)
+                    && now.saturating_sub(stat.mtime.unwrap_or(now)) > DEFAULT_SWEEP_TTL.as_secs();
+                if abandoned {
+                    let _ = sftp.unlink(&path);
+                }
+            }
+            Ok(deleted)
+        }).await?;
+
+        if !dry_run {
+            tracing::info!("Cleanup deleted {} files from {}:{}", deleted.len(), self.config.sftp.host, self.config.sftp.remote_dir);
+        }
+        Ok(CleanupReport::new(deleted))
+    }
+
+    async fn get_upload_stats(&self) -> Result<FallbackStats> {
+        let uploads = self.with_sftp(|sftp, dir| list_uploads(sftp, dir)).await?;
+        Ok(FallbackStats {
+            total_files: uploads.len(),
+            total_bytes: uploads.iter().filter_map(|(_, stat)| stat.size).sum(),
+            files: None,
+        })
+    }
+}
+
+// The bundle at an sftp:// URL, to be read and checked against `root` as an HTTP body is. It
+// comes over its own connection, read a block at a time on a blocking thread
+pub(super) async fn open_file(url: &str, root: Option<blake3::Hash>, config: &SftpConfig) -> Result<BundleResponse> {
+    let location = SftpLocation::parse(url)?;
+    let (config, remote, shown) = (config.clone(), location.clone(), url.to_string());
+    let (opened_tx, opened) = tokio::sync::oneshot::channel();
+    let (tx, rx) = tokio::sync::mpsc::channel(READ_AHEAD);
+
+    tokio::task::spawn_blocking(move || {
+        let opening = || -> Result<_> {
+            let sftp = connect(&config, &remote.host, remote.port, &remote.user)?;
+            let file = sftp.open(Path::new(&remote.path)).map_err(|e| {
+                if is_not_found(&e) {
+                    ShrLinkError::NotFound(shown)
+                } else {
+                    sftp_error(&format!("Opening {}", remote.path), e)
+                }
+            })?;
+            Ok((sftp, file))
+        };
+        let (_sftp, mut file) = match opening() {
+            Ok((sftp, mut file)) => {
+                let len = file.stat().ok().and_then(|stat| stat.size);
+                if opened_tx.send(Ok(len)).is_err() {
+                    return;
+                }
+                (sftp, file)
+            }
+            Err(e) => {
+                let _ = opened_tx.send(Err(e));
+                return;
+            }
+        };
+
+        let mut block = vec![0u8; BLOCK];
+        loop {
+            let read = match file.read(&mut block) {
+                Ok(0) => return,
+                Ok(read) => Ok(Bytes::copy_from_slice(&block[..read])),
+                Err(e) => Err(e),
+            };
+            let failed = read.is_err();
+            // Stops once the reader has hung up, as well as after an error
+            if tx.blocking_send(read).is_err() || failed {
+                return;
+            }
+        }
+    });
+
+    let len = opened.await.map_err(|e| ShrLinkError::Other(e.into()))??;
+    let body = stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|block| (block, rx)) }).boxed();
+    let original_name = location.path.rsplit('/').next().and_then(original_file_name);
+    Ok(BundleResponse { body: Body::Stream(body, len), url: url.to_string(), original_name, root, bundle_hash: None, progress: None, head: Vec::new() })
+}
+
+// Logs in to `host` as `user` once its host key checks out against known_hosts, with the key
+// in `config` or, without one, whatever the SSH agent offers
+fn connect(config: &SftpConfig, host: &str, port: u16, user: &str) -> Result<Sftp> {
+    let unreachable = |e: std::io::Error| ShrLinkError::Network(format!("Connecting to {}:{} failed: {}", host, port, e));
+    let mut tcp = Err(std::io::Error::new(std::io::ErrorKind::NotFound, "no address found"));
+    for addr in (host, port).to_socket_addrs().map_err(unreachable)? {
+        tcp = TcpStream::connect_timeout(&addr, TIMEOUT);
+        if tcp.is_ok() {
+            break;
+        }
+    }
+
+    let mut session = Session::new().map_err(|e| sftp_error("Starting an SSH session", e))?;
+    session.set_tcp_stream(tcp.map_err(unreachable)?);
+    session.set_timeout(TIMEOUT.as_millis() as u32);
+    session.handshake().map_err(|e| sftp_error(&format!("SSH handshake with {}", host), e))?;
+    if !config.insecure_skip_hostkey {
+        check_host_key(&session, config, host, port)?;
+    }
+
+    let login = match &config.key_path {
+        Some(key) => session.userauth_pubkey_file(user, None, key, None),
+        None => session.userauth_agent(user),
+    };
+    if let Err(e) = login {
+        let hint = match &config.key_path {
+            Some(key) => format!("check the host accepts {} for this user", key.display()),
+            None => "no key the SSH agent offered was accepted; ssh-add one, or set fallback.sftp.key_path".to_string(),
+        };
+        return Err(ShrLinkError::Network(format!("Logging in to {} as {} failed: {}; {}", host, user, e, hint)));
+    }
+
+    session.sftp().map_err(|e| sftp_error(&format!("Starting SFTP on {}", host), e))
+}
+
+// A host that isn't in known_hosts is refused like one whose key changed, rather than trusted
+// on first use, since nobody is there to confirm it
+fn check_host_key(session: &Session, config: &SftpConfig, host: &str, port: u16) -> Result<()> {
+    let path = config.known_hosts.clone()
+        .or_else(|| dirs::home_dir().map(|home| home.join(".ssh").join("known_hosts")))
+        .ok_or_else(|| ShrLinkError::InvalidInput("There's no home directory to find known_hosts in; set fallback.sftp.known_hosts".to_string()))?;
+
+    let mut known = session.known_hosts().map_err(|e| sftp_error("Reading known_hosts", e))?;
+    // A missing file knows no hosts, which is reported below
+    if path.exists() {
+        known.read_file(&path, KnownHostFileKind::OpenSSH).map_err(|e| sftp_error(&format!("Reading {}", path.display()), e))?;
+    }
+    let (key, _) = session.host_key().ok_or_else(|| ShrLinkError::Network(format!("{} sent no host key", host)))?;
+
+    match known.check_port(host, port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::NotFound => Err(ShrLinkError::InvalidInput(format!(
+            "{} isn't in {}; connect once with `ssh -p {} {}` to check its key and add it, or set fallback.sftp.insecure_skip_hostkey",
+            host, path.display(), port, host
+        ))),
+        CheckResult::Mismatch => Err(ShrLinkError::InvalidInput(format!(
+            "The host key {} sent doesn't match the one in {}; the host may have been reinstalled, or someone may be intercepting the connection",
+            host, path.display()
+        ))),
+        CheckResult::Failure => Err(ShrLinkError::Network(format!("Checking {}'s host key against {} failed", host, path.display()))),
+    }
+}
+
+fn copy_to(sftp: &Sftp, source: &Path, remote: &str, header: &[u8], cancel: &CancellationToken, progress: Option<&UploadProgress>) -> Result<()> {
+    let mut out = sftp.create(Path::new(remote)).map_err(|e| sftp_error(&format!("Writing {}", remote), e))?;
+    let failed = |e: std::io::Error| ShrLinkError::Network(format!("Writing {} failed: {}", remote, e));
+    out.write_all(header).map_err(failed)?;
+
+    let mut input = std::fs::File::open(source)?;
+    let mut block = vec![0u8; BLOCK];
+    loop {
+        if cancel.is_cancelled() {
+            return Err(ShrLinkError::Cancelled);
+        }
+        let read = input.read(&mut block)?;
+        if read == 0 {
+            break;
+        }
+        out.write_all(&block[..read]).map_err(failed)?;
+        if let Some(progress) = progress {
+            progress(read as u64);
+        }
+    }
+    out.close().map_err(|e| sftp_error(&format!("Writing {}", remote), e))
+}
+
+// The `.shr` files directly in `dir`, by their full remote paths
+fn list_uploads(sftp: &Sftp, dir: &str) -> Result<Vec<(String, FileStat)>> {
+    let entries = sftp.readdir(Path::new(dir)).map_err(|e| sftp_error(&format!("Listing {}", dir), e))?;
+    Ok(entries
+        .into_iter()
+        .filter(|(_, stat)| stat.is_file())
+        .filter_map(|(path, stat)| {
+            let name = path.file_name()?.to_str()?;
+            (name.ends_with(".shr") && !name.starts_with('.')).then(|| (format!("{}/{}", dir, name), stat))
+        })
+        .collect())
+}
+
+fn is_not_found(error: &ssh2::Error) -> bool {
+    matches!(error.code(), ErrorCode::SFTP(FX_NO_SUCH_FILE))
+}
+
+// With what to check for the failures a shared host usually has
+fn sftp_error(action: &str, error: ssh2::Error) -> ShrLinkError {
+    match error.code() {
+        ErrorCode::SFTP(FX_NO_SUCH_FILE) => ShrLinkError::Network(format!("{} failed: no such file or directory", action)),
+        ErrorCode::SFTP(FX_PERMISSION_DENIED) => ShrLinkError::Network(format!("{} failed: permission denied; check the user's access to it on the host", action)),
+        _ => ShrLinkError::Network(format!("{} failed: {}", action, error)),
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sftp_url_roundtrip() {
+        let location = SftpLocation {
+            user: "shr".to_string(),
+            host: "files.example.com".to_string(),
+            port: 22,
+            path: "/srv/shr/0b4e3c1a-8f7e-4d52-9a55-3b7c1f2e9d10_café report.pdf.shr".to_string(),
+        };
+        let url = location.url().unwrap();
+        assert_eq!(url, "sftp://shr@files.example.com/srv/shr/0b4e3c1a-8f7e-4d52-9a55-3b7c1f2e9d10_caf%C3%A9%20report.pdf.shr");
+        assert_eq!(SftpLocation::parse(&url).unwrap(), location);
+
+        let other_port = SftpLocation { port: 2222, ..location };
+        assert!(other_port.url().unwrap().starts_with("sftp://shr@files.example.com:2222/"));
+        assert_eq!(SftpLocation::parse(&other_port.url().unwrap()).unwrap(), other_port);
+
+        assert!(SftpLocation::parse("sftp://files.example.com/srv/shr/a.shr").is_err());
+        assert!(SftpLocation::parse("https://shr@files.example.com/a.shr").is_err());
+    }
+}
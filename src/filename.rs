@@ -0,0 +1,238 @@
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use std::path::{Component, PathBuf};
+use unicode_normalization::UnicodeNormalization;
+
+// RFC 5987 attr-char: everything except ALPHA / DIGIT / "!#$&+-.^_`|~" is encoded
+const ATTR_CHAR: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'!')
+    .remove(b'#')
+    .remove(b'$')
+    .remove(b'&')
+    .remove(b'+')
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'^')
+    .remove(b'_')
+    .remove(b'`')
+    .remove(b'|')
+    .remove(b'~');
+
+// Unreserved characters from RFC 3986 stay as-is inside a path segment
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+pub fn normalize(name: &str) -> String {
+    name.nfc().collect()
+}
+
+pub fn encode_path_segment(name: &str) -> String {
+    utf8_percent_encode(&normalize(name), PATH_SEGMENT).to_string()
+}
+
+pub fn decode_path_segment(segment: &str) -> Option<String> {
+    percent_decode_str(segment)
+        .decode_utf8()
+        .ok()
+        .map(|name| normalize(&name))
+}
+
+pub fn ascii_fallback(name: &str) -> String {
+    normalize(name)
+        .chars()
+        .map(|c| match c {
+            '"' | '\\' => '_',
+            c if c.is_ascii() && !c.is_ascii_control() => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+pub fn content_disposition(disposition: &str, name: &str) -> String {
+    let name = normalize(name);
+    format!(
+        "{}; filename=\"{}\"; filename*=UTF-8''{}",
+        disposition,
+        ascii_fallback(&name),
+        utf8_percent_encode(&name, ATTR_CHAR)
+    )
+}
+
+pub fn parse_content_disposition(header: &str) -> Option<String> {
+    let mut plain = None;
+    let mut extended = None;
+
+    for param in split_params(header).into_iter().skip(1) {
+        let Some((key, value)) = param.split_once('=') else {
+            continue;
+        };
+
+        match key.trim().to_ascii_lowercase().as_str() {
+            "filename*" => {
+                // charset'language'percent-encoded-value
+                let mut parts = value.trim().splitn(3, '\'');
+                let charset = parts.next().unwrap_or_default();
+                let _language = parts.next();
+                if let (true, Some(encoded)) = (charset.eq_ignore_ascii_case("utf-8"), parts.next()) {
+                    extended = percent_decode_str(encoded).decode_utf8().ok().map(|s| s.into_owned());
+                }
+            }
+            "filename" => {
+                plain = Some(unquote(value.trim()));
+            }
+            _ => {}
+        }
+    }
+
+    extended.or(plain).filter(|name| !name.is_empty()).map(|name| normalize(&name))
+}
+
+pub fn sanitize(name: &str) -> Option<String> {
+    let name = normalize(name);
+    let last = name.rsplit(['/', '\\']).next().unwrap_or_default().trim();
+
+    if last.is_empty() || last == "." || last == ".." || last.chars().any(|c| c.is_control()) {
+        return None;
+    }
+
+    Some(last.to_string())
+}
+
+// Names inside a multi-file bundle may have directories, but must stay relative and never climb
+// out with `..`; both separators count, whatever platform wrote the bundle
+pub fn archive_path(name: &str) -> Option<PathBuf> {
+    let name = normalize(name);
+    if name.starts_with(['/', '\\']) || name.chars().any(|c| c.is_control()) {
+        return None;
+    }
+
+    let mut path = PathBuf::new();
+    for part in name.split(['/', '\\']).filter(|p| !p.is_empty() && *p != ".") {
+        if part == ".." {
+            return None;
+        }
+        path.push(part);
+    }
+
+    // Catches drive prefixes such as `C:` on Windows
+    let plain = path.components().all(|c| matches!(c, Component::Normal(_)));
+    (plain && path.components().next().is_some()).then_some(path)
+}
+
+fn split_params(header: &str) -> Vec<String> {
+    let mut params = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+    let mut escaped = false;
+
+    for c in header.chars() {
+        match c {
+            _ if escaped => {
+                current.push(c);
+                escaped = false;
+            }
+            '\\' if in_quotes => {
+                current.push(c);
+                escaped = true;
+            }
+            '"' => {
+                current.push(c);
+                in_quotes = !in_quotes;
+            }
+            ';' if !in_quotes => params.push(std::mem::take(&mut current)),
+            _ => current.push(c),
+        }
+    }
+    params.push(current);
+
+    params
+}
+
+fn unquote(value: &str) -> String {
+    let Some(inner) = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) else {
+        return value.to_string();
+    };
+
+    let mut result = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if let Some(next) = chars.next() {
+                result.push(next);
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAMES: &[&str] = &[
+        "отчёт 2024.pdf",
+        "会议记录 最终版.docx",
+        "holiday 🏖️ photos.zip",
+        "plain name.txt",
+        "quote\"and;semi.txt",
+    ];
+
+    #[test]
+    fn test_nfc_normalization() {
+        // "e" followed by a combining acute accent
+        let decomposed = "caf\u{0065}\u{0301}.txt";
+        assert_eq!(normalize(decomposed), "caf\u{00e9}.txt");
+    }
+
+    #[test]
+    fn test_path_segment_roundtrip() {
+        for name in NAMES {
+            let encoded = encode_path_segment(name);
+            assert!(encoded.is_ascii());
+            assert!(!encoded.contains(' '));
+            assert!(!encoded.contains('/'));
+            assert_eq!(decode_path_segment(&encoded).as_deref(), Some(normalize(name).as_str()));
+        }
+    }
+
+    #[test]
+    fn test_content_disposition_roundtrip() {
+        for name in NAMES {
+            let header = content_disposition("attachment", name);
+            assert!(header.is_ascii());
+            assert!(header.contains("filename=\""));
+            assert!(header.contains("filename*=UTF-8''"));
+            assert_eq!(parse_content_disposition(&header).as_deref(), Some(normalize(name).as_str()));
+        }
+    }
+
+    #[test]
+    fn test_parse_plain_filename() {
+        let header = "attachment; filename=\"report.pdf\"";
+        assert_eq!(parse_content_disposition(header), Some("report.pdf".to_string()));
+        assert_eq!(parse_content_disposition("inline"), None);
+    }
+
+    #[test]
+    fn test_sanitize() {
+        assert_eq!(sanitize("отчёт 2024.pdf"), Some("отчёт 2024.pdf".to_string()));
+        assert_eq!(sanitize("../../etc/passwd"), Some("passwd".to_string()));
+        assert_eq!(sanitize("..\\evil.exe"), Some("evil.exe".to_string()));
+        assert_eq!(sanitize(".."), None);
+        assert_eq!(sanitize(""), None);
+    }
+
+    #[test]
+    fn test_archive_path() {
+        assert_eq!(archive_path("notes.txt"), Some(PathBuf::from("notes.txt")));
+        assert_eq!(archive_path("docs/./会议记录.docx"), Some(PathBuf::from("docs").join("会议记录.docx")));
+        assert_eq!(archive_path("docs\\a.txt"), Some(PathBuf::from("docs").join("a.txt")));
+        for unsafe_name in ["/etc/passwd", "\\server\\share", "../evil", "docs/../../evil", "..\\evil", "", ".", "a\nb"] {
+            assert_eq!(archive_path(unsafe_name), None, "{:?}", unsafe_name);
+        }
+    }
+}
@@ -1,8 +1,31 @@
+pub mod bundle;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod cache;
 pub mod compression;
+#[cfg(feature = "p2p")]
 pub mod p2p;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod source;
+#[cfg(feature = "cli")]
 pub mod cli;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod fallback;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod config;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod crypto;
 pub mod error;
+pub mod filename;
+#[cfg(feature = "cli")]
+pub mod logging;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod partial;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod temp;
+pub mod verify;
 
+#[cfg(feature = "p2p")]
+pub use error::{DialError, Incompatibility};
 pub use error::{Result, ShrLinkError};
@@ -0,0 +1,98 @@
+use std::io::IsTerminal;
+use tracing::Subscriber;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::EnvFilter;
+use crate::config::LogFormat;
+use crate::{Result, ShrLinkError};
+
+pub fn filter(directives: &str) -> Result<EnvFilter> {
+    EnvFilter::try_new(directives)
+        .map_err(|e| ShrLinkError::InvalidInput(format!("Invalid log filter '{}': {}", directives, e)))
+}
+
+// Logs go to stderr so they never interleave with output meant for pipes. JSON events are
+// flattened, so fields like transfer_id and chunk_index are top-level keys
+pub fn subscriber<W>(format: LogFormat, filter: EnvFilter, ansi: bool, writer: W) -> Box<dyn Subscriber + Send + Sync>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_ansi(ansi)
+        .with_writer(writer);
+
+    match format {
+        LogFormat::Text => Box::new(builder.finish()),
+        LogFormat::Json => Box::new(builder.json().flatten_event(true).finish()),
+    }
+}
+
+// Installs the global subscriber; must run once, after arguments and config are known and
+// before any command logs anything
+pub fn init(format: LogFormat, filter: EnvFilter) {
+    // Colour codes only make sense when a person is looking at the terminal
+    let ansi = format == LogFormat::Text && std::io::stderr().is_terminal();
+    let subscriber = subscriber(format, filter, ansi, std::io::stderr);
+    if tracing::subscriber::set_global_default(subscriber).is_err() {
+        tracing::debug!("Logging was already initialised");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn capture(format: LogFormat) -> String {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = subscriber(format, EnvFilter::new("debug"), false, move || writer.clone());
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::debug!(transfer_id = "4f1c", chunk_index = 3u64, "Chunk written");
+        });
+
+        let bytes = captured.0.lock().unwrap().clone();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn test_json_fields_are_top_level() {
+        let output = capture(LogFormat::Json);
+        let line: serde_json::Value = serde_json::from_str(output.lines().next().unwrap()).unwrap();
+
+        assert_eq!(line["transfer_id"], "4f1c");
+        assert_eq!(line["chunk_index"], 3);
+        assert_eq!(line["message"], "Chunk written");
+        assert_eq!(line["level"], "DEBUG");
+    }
+
+    #[test]
+    fn test_text_is_not_json() {
+        let output = capture(LogFormat::Text);
+        assert!(output.contains("transfer_id=\"4f1c\" chunk_index=3"));
+        assert!(serde_json::from_str::<serde_json::Value>(output.trim()).is_err());
+    }
+
+    #[test]
+    fn test_invalid_filter_is_rejected() {
+        assert!(filter("shrlink::p2p=trace,info").is_ok());
+        assert!(matches!(filter("shrlink=loud"), Err(ShrLinkError::InvalidInput(_))));
+    }
+}
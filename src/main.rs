@@ -1,13 +1,8 @@
 use shrlink::{cli::Cli, Result};
-use tracing_subscriber::{EnvFilter, fmt::format::FmtSpan};
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::from_default_env())
-        .with_span_events(FmtSpan::CLOSE)
-        .init();
-
+    // Logging is set up inside run(), once the flags and config that control it are parsed
     let cli = Cli::new();
     cli.run().await
 }
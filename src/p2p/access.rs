@@ -0,0 +1,82 @@
+use libp2p::PeerId;
+use std::collections::HashSet;
+use crate::config::P2PConfig;
+use crate::{Result, ShrLinkError};
+
+// Who a serve answers: anyone not blocked, or with any peers allowed, only those. A peer that is
+// both is blocked
+#[derive(Debug, Clone, Default)]
+pub struct PeerAccess {
+    allowed: HashSet<PeerId>,
+    blocked: HashSet<PeerId>,
+}
+
+impl PeerAccess {
+    // A peer id that doesn't parse is an error rather than skipped, since an allowlist left
+    // empty by a typo would let everyone in
+    pub fn from_config(config: &P2PConfig) -> Result<Self> {
+        Ok(Self {
+            allowed: parse_peers(&config.allowed_peers, "p2p.allowed_peers")?,
+            blocked: parse_peers(&config.blocked_peers, "p2p.blocked_peers")?,
+        })
+    }
+
+    // With no one else allowed before, that's then the only peer answered
+    pub fn allow(&mut self, peer_id: PeerId) {
+        self.allowed.insert(peer_id);
+    }
+
+    pub fn permits(&self, peer_id: &PeerId) -> bool {
+        !self.blocked.contains(peer_id) && (self.allowed.is_empty() || self.allowed.contains(peer_id))
+    }
+}
+
+fn parse_peers(peers: &[String], key: &str) -> Result<HashSet<PeerId>> {
+    peers
+        .iter()
+        .map(|peer| {
+            peer.trim()
+                .parse()
+                .map_err(|e| ShrLinkError::InvalidInput(format!("Invalid peer id {} in {}: {}", peer, key, e)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn access(allowed: &[PeerId], blocked: &[PeerId]) -> PeerAccess {
+        let mut config = crate::config::Config::default().p2p;
+        config.allowed_peers = allowed.iter().map(PeerId::to_string).collect();
+        config.blocked_peers = blocked.iter().map(PeerId::to_string).collect();
+        PeerAccess::from_config(&config).unwrap()
+    }
+
+    #[test]
+    fn test_empty_lists_let_everyone_in() {
+        assert!(access(&[], &[]).permits(&PeerId::random()));
+    }
+
+    #[test]
+    fn test_allowlist_and_blocklist() {
+        let (friend, stranger, foe) = (PeerId::random(), PeerId::random(), PeerId::random());
+
+        let blocking = access(&[], &[foe]);
+        assert!(blocking.permits(&stranger));
+        assert!(!blocking.permits(&foe));
+
+        let allowing = access(&[friend, foe], &[foe]);
+        assert!(allowing.permits(&friend));
+        assert!(!allowing.permits(&stranger));
+        assert!(!allowing.permits(&foe));
+    }
+
+    #[test]
+    fn test_bad_peer_ids_are_refused() {
+        let mut config = crate::config::Config::default().p2p;
+        config.allowed_peers = vec!["not-a-peer".to_string()];
+        let err = PeerAccess::from_config(&config).unwrap_err();
+        assert!(err.to_string().contains("p2p.allowed_peers"), "{}", err);
+    }
+}
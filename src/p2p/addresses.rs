@@ -0,0 +1,231 @@
+use libp2p::multiaddr::Protocol;
+use libp2p::Multiaddr;
+use super::reachability::is_public;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddressEvent {
+    NewListenAddr(Multiaddr),
+    ExpiredListenAddr(Multiaddr),
+    // Reported by identify: what a remote peer saw us connect from, unverified
+    Observed(Multiaddr),
+    // AutoNAT got a successful dial-back on this address
+    ExternalConfirmed(Multiaddr),
+    ExternalExpired(Multiaddr),
+    RelayReservation(Multiaddr),
+    RelayReservationLost(Multiaddr),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressChange {
+    pub added: Vec<Multiaddr>,
+    pub removed: Vec<Multiaddr>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Dialability {
+    PublicDirect,
+    Relayed,
+    Private,
+}
+
+// Observed addresses are capped because every new connection through a symmetric NAT reports
+// a different port
+const MAX_OBSERVED: usize = 8;
+
+#[derive(Debug, Default)]
+pub struct AddressBook {
+    listen: Vec<Multiaddr>,
+    confirmed: Vec<Multiaddr>,
+    observed: Vec<Multiaddr>,
+    relayed: Vec<Multiaddr>,
+}
+
+impl AddressBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Returns what changed in the advertisable set, if anything
+    pub fn apply(&mut self, event: AddressEvent) -> Option<AddressChange> {
+        let before = self.advertisable_addrs();
+
+        match event {
+            AddressEvent::NewListenAddr(addr) => insert(&mut self.listen, addr),
+            AddressEvent::ExpiredListenAddr(addr) => {
+                // A confirmed address on a socket that's gone can no longer be dialed
+                remove(&mut self.listen, &addr);
+                remove(&mut self.confirmed, &addr);
+            }
+            AddressEvent::Observed(addr) => {
+                if !self.confirmed.contains(&addr) {
+                    insert(&mut self.observed, addr);
+                    if self.observed.len() > MAX_OBSERVED {
+                        self.observed.remove(0);
+                    }
+                }
+            }
+            AddressEvent::ExternalConfirmed(addr) => {
+                remove(&mut self.observed, &addr);
+                insert(&mut self.confirmed, addr);
+            }
+            AddressEvent::ExternalExpired(addr) => remove(&mut self.confirmed, &addr),
+            AddressEvent::RelayReservation(addr) => insert(&mut self.relayed, addr),
+            AddressEvent::RelayReservationLost(addr) => remove(&mut self.relayed, &addr),
+        }
+
+        let after = self.advertisable_addrs();
+        if before == after {
+            return None;
+        }
+
+        Some(AddressChange {
+            added: after.iter().filter(|a| !before.contains(a)).cloned().collect(),
+            removed: before.into_iter().filter(|a| !after.contains(a)).collect(),
+        })
+    }
+
+    // Everything we know we can be reached on, best first; wildcard binds are never included
+    pub fn listeners(&self) -> Vec<Multiaddr> {
+        let mut addrs: Vec<Multiaddr> = Vec::new();
+        for addr in self.confirmed.iter().chain(&self.observed).chain(&self.relayed).chain(&self.listen) {
+            if !is_unspecified(addr) && !addrs.contains(addr) {
+                addrs.push(addr.clone());
+            }
+        }
+
+        // Stable sort keeps confirmed ahead of observed within the same class
+        addrs.sort_by_key(classify);
+        addrs
+    }
+
+    // What goes next to a share URL: unconfirmed observations and loopback are left out
+    pub fn advertisable_addrs(&self) -> Vec<Multiaddr> {
+        self.listeners()
+            .into_iter()
+            .filter(|a| !self.observed.contains(a) && !is_loopback(a))
+            .collect()
+    }
+
+    pub fn confirmed(&self) -> &[Multiaddr] {
+        &self.confirmed
+    }
+
+    pub fn observed(&self) -> &[Multiaddr] {
+        &self.observed
+    }
+}
+
+fn insert(addrs: &mut Vec<Multiaddr>, addr: Multiaddr) {
+    if !addrs.contains(&addr) {
+        addrs.push(addr);
+    }
+}
+
+fn remove(addrs: &mut Vec<Multiaddr>, addr: &Multiaddr) {
+    addrs.retain(|a| a != addr);
+}
+
+fn classify(addr: &Multiaddr) -> Dialability {
+    if addr.iter().any(|p| matches!(p, Protocol::P2pCircuit)) {
+        Dialability::Relayed
+    } else if is_public(addr) {
+        Dialability::PublicDirect
+    } else {
+        Dialability::Private
+    }
+}
+
+fn is_unspecified(addr: &Multiaddr) -> bool {
+    addr.iter().any(|p| match p {
+        Protocol::Ip4(ip) => ip.is_unspecified(),
+        Protocol::Ip6(ip) => ip.is_unspecified(),
+        _ => false,
+    })
+}
+
+fn is_loopback(addr: &Multiaddr) -> bool {
+    addr.iter().any(|p| match p {
+        Protocol::Ip4(ip) => ip.is_loopback(),
+        Protocol::Ip6(ip) => ip.is_loopback(),
+        _ => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> Multiaddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_listeners_ordered_by_dialability() {
+        let mut book = AddressBook::new();
+        let relayed = addr("/ip4/198.51.100.1/tcp/4001/p2p/12D3KooWGCYDpyGwFvjNbFWQXCCK9G4RZekkKfXXc2QnP8HWqDek/p2p-circuit");
+
+        book.apply(AddressEvent::NewListenAddr(addr("/ip4/0.0.0.0/tcp/4001")));
+        book.apply(AddressEvent::NewListenAddr(addr("/ip4/192.168.1.20/tcp/4001")));
+        book.apply(AddressEvent::NewListenAddr(addr("/ip4/127.0.0.1/tcp/4001")));
+        book.apply(AddressEvent::RelayReservation(relayed.clone()));
+        book.apply(AddressEvent::Observed(addr("/ip4/203.0.113.9/tcp/51234")));
+        book.apply(AddressEvent::ExternalConfirmed(addr("/ip4/203.0.113.7/tcp/4001")));
+
+        assert_eq!(
+            book.listeners(),
+            vec![
+                addr("/ip4/203.0.113.7/tcp/4001"),
+                addr("/ip4/203.0.113.9/tcp/51234"),
+                relayed.clone(),
+                addr("/ip4/192.168.1.20/tcp/4001"),
+                addr("/ip4/127.0.0.1/tcp/4001"),
+            ]
+        );
+        assert_eq!(
+            book.advertisable_addrs(),
+            vec![addr("/ip4/203.0.113.7/tcp/4001"), relayed, addr("/ip4/192.168.1.20/tcp/4001")]
+        );
+    }
+
+    #[test]
+    fn test_address_changes_are_reported() {
+        let mut book = AddressBook::new();
+        let lan = addr("/ip4/192.168.1.20/tcp/4001");
+        let renewed = addr("/ip4/192.168.1.57/tcp/4001");
+
+        let change = book.apply(AddressEvent::NewListenAddr(lan.clone())).unwrap();
+        assert_eq!(change.added, vec![lan.clone()]);
+
+        // Observations alone don't change what we advertise
+        assert!(book.apply(AddressEvent::Observed(addr("/ip4/203.0.113.9/tcp/1"))).is_none());
+        assert!(book.apply(AddressEvent::NewListenAddr(lan.clone())).is_none());
+
+        // A DHCP renew shows up as the old interface address expiring and a new one appearing
+        let change = book.apply(AddressEvent::ExpiredListenAddr(lan.clone())).unwrap();
+        assert_eq!(change.removed, vec![lan]);
+        let change = book.apply(AddressEvent::NewListenAddr(renewed.clone())).unwrap();
+        assert_eq!(change.added, vec![renewed]);
+    }
+
+    #[test]
+    fn test_confirmation_lifecycle() {
+        let mut book = AddressBook::new();
+        let external = addr("/ip4/203.0.113.7/tcp/4001");
+
+        book.apply(AddressEvent::Observed(external.clone()));
+        assert_eq!(book.observed(), std::slice::from_ref(&external));
+        assert!(book.advertisable_addrs().is_empty());
+
+        book.apply(AddressEvent::ExternalConfirmed(external.clone()));
+        assert!(book.observed().is_empty());
+        assert_eq!(book.advertisable_addrs(), vec![external.clone()]);
+
+        // Re-observing a confirmed address doesn't demote it
+        book.apply(AddressEvent::Observed(external.clone()));
+        assert!(book.observed().is_empty());
+
+        let change = book.apply(AddressEvent::ExternalExpired(external.clone())).unwrap();
+        assert_eq!(change.removed, vec![external]);
+        assert!(book.listeners().is_empty());
+    }
+}
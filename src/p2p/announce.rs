@@ -0,0 +1,106 @@
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// How long what a peer said it's sharing is believed without it saying so again
+pub const ANNOUNCE_TTL: Duration = Duration::from_secs(30);
+// How often `shr browse` asks the peers it knows again
+pub const BROWSE_REFRESH: Duration = Duration::from_secs(5);
+
+// What a sender tells peers on the local network it's serving, when `p2p.announce` is set
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Announcement {
+    pub file_name: String,
+    // Bytes, before compression
+    pub size: u64,
+    // In hex, as the shr:// URL has it
+    pub file_hash: String,
+    pub peer_id: String,
+    // Ready to hand to `shr recv`, addresses included
+    pub url: String,
+}
+
+// What each peer last said it's sharing, and when
+#[derive(Debug, Default)]
+pub struct Announcements {
+    peers: HashMap<PeerId, (Instant, Vec<Announcement>)>,
+}
+
+impl Announcements {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Replaces whatever `peer_id` said before. A peer only speaks for itself, so anything it
+    // claims another peer is sharing is dropped
+    pub fn record(&mut self, peer_id: PeerId, announcements: Vec<Announcement>, at: Instant) {
+        let own: Vec<_> = announcements.into_iter().filter(|a| a.peer_id == peer_id.to_string()).collect();
+        self.peers.insert(peer_id, (at, own));
+    }
+
+    pub fn forget(&mut self, peer_id: &PeerId) {
+        self.peers.remove(peer_id);
+    }
+
+    // Everything said within `ttl` of `now`, by file name
+    pub fn current(&self, now: Instant, ttl: Duration) -> Vec<Announcement> {
+        let mut current: Vec<_> = self
+            .peers
+            .values()
+            .filter(|(at, _)| now.saturating_duration_since(*at) <= ttl)
+            .flat_map(|(_, announcements)| announcements.iter().cloned())
+            .collect();
+        current.sort_by(|a, b| (&a.file_name, &a.peer_id).cmp(&(&b.file_name, &b.peer_id)));
+        current
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn announcement(peer_id: PeerId, file_name: &str) -> Announcement {
+        Announcement {
+            file_name: file_name.to_string(),
+            size: 1024,
+            file_hash: "ab".repeat(32),
+            peer_id: peer_id.to_string(),
+            url: format!("shr://{}/{}", peer_id, "ab".repeat(32)),
+        }
+    }
+
+    fn names(announcements: &Announcements, now: Instant) -> Vec<String> {
+        announcements.current(now, ANNOUNCE_TTL).into_iter().map(|a| a.file_name).collect()
+    }
+
+    #[test]
+    fn test_stale_and_forgotten_announcements_are_dropped() {
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        let start = Instant::now();
+        let mut announcements = Announcements::new();
+        announcements.record(alice, vec![announcement(alice, "notes.txt")], start);
+        announcements.record(bob, vec![announcement(bob, "backup.tar")], start + Duration::from_secs(20));
+
+        assert_eq!(names(&announcements, start + Duration::from_secs(25)), vec!["backup.tar", "notes.txt"]);
+        assert_eq!(names(&announcements, start + Duration::from_secs(40)), vec!["backup.tar"]);
+
+        // Saying it again keeps it fresh, and saying nothing takes it back
+        announcements.record(alice, vec![announcement(alice, "notes.txt")], start + Duration::from_secs(35));
+        assert_eq!(names(&announcements, start + Duration::from_secs(40)), vec!["backup.tar", "notes.txt"]);
+        announcements.record(alice, Vec::new(), start + Duration::from_secs(41));
+        announcements.forget(&bob);
+        assert!(names(&announcements, start + Duration::from_secs(41)).is_empty());
+    }
+
+    #[test]
+    fn test_peers_only_announce_for_themselves() {
+        let (alice, mallory) = (PeerId::random(), PeerId::random());
+        let now = Instant::now();
+        let mut announcements = Announcements::new();
+        announcements.record(mallory, vec![announcement(alice, "payroll.xlsx"), announcement(mallory, "free.zip")], now);
+        let current = announcements.current(now, ANNOUNCE_TTL);
+        assert_eq!(current.len(), 1);
+        assert_eq!(current[0].file_name, "free.zip");
+    }
+}
@@ -0,0 +1,154 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use crate::bundle::wire;
+use crate::compression::{ChecksumAlgorithm, CompressedChunk, CompressionAlgorithm};
+use crate::error::Incompatibility;
+
+// The chunk protocol's version, as in PROTOCOL_VERSION. Peers with the same major version can
+// always talk, at the lower of their two minor versions
+pub const VERSION: Version = Version { major: 1, minor: 2 };
+
+// What a receiver is taken to have said when the sender predates the handshake
+pub const LEGACY_VERSION: Version = Version { major: 1, minor: 0 };
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Version {
+    pub major: u16,
+    pub minor: u16,
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+// What a receiver can take, or what a sender's chunks need of one. Codecs and checksums go by
+// name, so any a newer peer lists that this build doesn't know are passed over, not refused
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capabilities {
+    pub version: Version,
+    pub codecs: Vec<String>,
+    pub checksums: Vec<String>,
+    // Longest chunk frame, as the chunk protocol carries it after the length prefix
+    pub max_chunk_size: u64,
+}
+
+impl Capabilities {
+    // Everything this build can receive
+    pub fn local() -> Self {
+        let codecs = [
+            CompressionAlgorithm::Lz4,
+            CompressionAlgorithm::Zstd,
+            CompressionAlgorithm::Stored,
+            CompressionAlgorithm::Zero,
+            CompressionAlgorithm::Gzip,
+            CompressionAlgorithm::Snappy,
+        ];
+        Self {
+            version: VERSION,
+            codecs: codecs.iter().map(ToString::to_string).collect(),
+            checksums: [ChecksumAlgorithm::Blake3, ChecksumAlgorithm::Xxh3].iter().map(ToString::to_string).collect(),
+            max_chunk_size: wire::DEFAULT_MAX_FRAME as u64,
+        }
+    }
+
+    // What receiving every one of `chunks` takes
+    pub fn needed_by<'a>(chunks: impl IntoIterator<Item = &'a CompressedChunk>) -> Self {
+        let mut needs = Self { version: VERSION, codecs: Vec::new(), checksums: Vec::new(), max_chunk_size: 0 };
+        for chunk in chunks {
+            let (codec, checksum) = (chunk.algorithm.to_string(), chunk.checksum.to_string());
+            if !needs.codecs.contains(&codec) {
+                needs.codecs.push(codec);
+            }
+            if !needs.checksums.contains(&checksum) {
+                needs.checksums.push(checksum);
+            }
+            needs.max_chunk_size = needs.max_chunk_size.max((wire::encoded_len(chunk) - wire::PREFIX_SIZE) as u64);
+        }
+        needs
+    }
+}
+
+// The version a session goes ahead at, or what stands in its way. `needed` is always the
+// sender's side, so either end can check, and both do
+pub fn negotiate(needed: &Capabilities, receiver: &Capabilities) -> Result<Version, Incompatibility> {
+    if needed.version.major != receiver.version.major {
+        return Err(Incompatibility::Version { sender: needed.version.to_string(), receiver: receiver.version.to_string() });
+    }
+
+    let unsupported = missing(&needed.codecs, &receiver.codecs);
+    if !unsupported.is_empty() {
+        return Err(Incompatibility::Codecs { unsupported, supported: receiver.codecs.clone() });
+    }
+    let unsupported = missing(&needed.checksums, &receiver.checksums);
+    if !unsupported.is_empty() {
+        return Err(Incompatibility::Checksums { unsupported, supported: receiver.checksums.clone() });
+    }
+    if needed.max_chunk_size > receiver.max_chunk_size {
+        return Err(Incompatibility::ChunkSize { largest: needed.max_chunk_size, max: receiver.max_chunk_size });
+    }
+
+    Ok(needed.version.min(receiver.version))
+}
+
+fn missing(needed: &[String], supported: &[String]) -> Vec<String> {
+    needed.iter().filter(|name| !supported.contains(name)).cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::ParallelCompressor;
+
+    fn chunks(algorithm: CompressionAlgorithm) -> Vec<CompressedChunk> {
+        let compressor = ParallelCompressor::default().with_algorithm(algorithm);
+        (1..4).map(|i| compressor.compress_chunk(i, vec![i as u8; 4096]).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_matched_peers_agree() {
+        let needs = Capabilities::needed_by(&chunks(CompressionAlgorithm::Zstd));
+        assert_eq!(needs.codecs, vec!["zstd"]);
+        assert_eq!(needs.checksums, vec!["blake3"]);
+        assert_eq!(negotiate(&needs, &Capabilities::local()), Ok(VERSION));
+        assert!(super::super::PROTOCOL_VERSION.ends_with(&format!("/{}.0", VERSION)));
+
+        // Serving nothing needs nothing of anyone
+        assert_eq!(negotiate(&Capabilities::needed_by(&[]), &Capabilities::local()), Ok(VERSION));
+    }
+
+    #[test]
+    fn test_older_and_newer_peers_downgrade() {
+        let needs = Capabilities::needed_by(&chunks(CompressionAlgorithm::Lz4));
+        let older = Capabilities { version: LEGACY_VERSION, ..Capabilities::local() };
+        assert_eq!(negotiate(&needs, &older), Ok(LEGACY_VERSION));
+
+        // A newer receiver lists codecs this build has never heard of, which don't get in the way
+        let mut newer = Capabilities::local();
+        newer.version.minor += 3;
+        newer.codecs.push("brotli".to_string());
+        assert_eq!(negotiate(&needs, &newer), Ok(VERSION));
+    }
+
+    #[test]
+    fn test_incompatible_peers_say_why() {
+        let needs = Capabilities::needed_by(&chunks(CompressionAlgorithm::Zstd));
+        let lz4_only = Capabilities { codecs: vec!["lz4".to_string()], ..Capabilities::local() };
+        assert_eq!(
+            negotiate(&needs, &lz4_only),
+            Err(Incompatibility::Codecs { unsupported: vec!["zstd".to_string()], supported: vec!["lz4".to_string()] })
+        );
+
+        let blake3_only = Capabilities { checksums: vec!["blake3".to_string()], ..Capabilities::local() };
+        let xxh3 = Capabilities { checksums: vec!["xxh3".to_string()], ..needs.clone() };
+        assert!(matches!(negotiate(&xxh3, &blake3_only), Err(Incompatibility::Checksums { .. })));
+
+        let small = Capabilities { max_chunk_size: 16, ..Capabilities::local() };
+        assert_eq!(negotiate(&needs, &small), Err(Incompatibility::ChunkSize { largest: needs.max_chunk_size, max: 16 }));
+
+        let next = Capabilities { version: Version { major: 2, minor: 0 }, ..Capabilities::local() };
+        let err = negotiate(&needs, &next).unwrap_err();
+        assert_eq!(err.to_string(), "the sender speaks version 1.2 of the chunk protocol and the receiver 2.0");
+    }
+}
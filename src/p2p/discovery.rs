@@ -0,0 +1,83 @@
+use libp2p::{Multiaddr, PeerId};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredPeer {
+    pub peer_id: PeerId,
+    pub addrs: Vec<Multiaddr>,
+}
+
+// Peers found on the local network, as mDNS reports them coming and going. A peer stays for
+// as long as any of its addresses hasn't expired
+#[derive(Debug, Default)]
+pub struct DiscoveredPeers {
+    peers: HashMap<PeerId, Vec<Multiaddr>>,
+}
+
+impl DiscoveredPeers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // True if the peer wasn't known before
+    pub fn discovered(&mut self, peer_id: PeerId, addr: Multiaddr) -> bool {
+        let addrs = self.peers.entry(peer_id).or_default();
+        let is_new = addrs.is_empty();
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+        is_new
+    }
+
+    // True if that was the peer's last address, so it's gone
+    pub fn expired(&mut self, peer_id: &PeerId, addr: &Multiaddr) -> bool {
+        let Some(addrs) = self.peers.get_mut(peer_id) else {
+            return false;
+        };
+        addrs.retain(|a| a != addr);
+        if !addrs.is_empty() {
+            return false;
+        }
+        self.peers.remove(peer_id);
+        true
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    pub fn peer_ids(&self) -> Vec<PeerId> {
+        self.peers.keys().copied().collect()
+    }
+
+    pub fn get(&self, peer_id: &PeerId) -> Option<DiscoveredPeer> {
+        self.peers.get(peer_id).map(|addrs| DiscoveredPeer { peer_id: *peer_id, addrs: addrs.clone() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peers_leave_with_their_last_address() {
+        let mut peers = DiscoveredPeers::new();
+        let peer = PeerId::random();
+        let lan: Multiaddr = "/ip4/192.168.1.20/tcp/4001".parse().unwrap();
+        let wifi: Multiaddr = "/ip4/10.0.0.7/tcp/4001".parse().unwrap();
+
+        assert!(peers.discovered(peer, lan.clone()));
+        assert!(!peers.discovered(peer, lan.clone()));
+        assert!(!peers.discovered(peer, wifi.clone()));
+        assert_eq!(peers.get(&peer).unwrap().addrs, vec![lan.clone(), wifi.clone()]);
+
+        assert!(!peers.expired(&peer, &lan));
+        assert_eq!(peers.peer_ids(), vec![peer]);
+        assert!(peers.expired(&peer, &wifi));
+        assert!(peers.is_empty());
+        assert!(peers.get(&peer).is_none());
+
+        // Expiry of something never seen changes nothing
+        assert!(!peers.expired(&PeerId::random(), &lan));
+    }
+}
@@ -0,0 +1,126 @@
+use libp2p::PeerId;
+use tokio::sync::mpsc;
+use crate::Result;
+use super::stats::{P2PStats, StatsTally};
+
+// What sends and receives report to `P2PClient::transfer_events`, in the order it happened.
+// Each call that transfers chunks ends its events with exactly one Completed or Failed, so a
+// consumer can stop reading there
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransferEvent {
+    PeerConnected(PeerId),
+    // Its last connection closed
+    PeerDisconnected(PeerId),
+    // Acknowledged by the receiver, so counted once however many times it was asked for
+    ChunkSent { peer_id: PeerId, index: usize, bytes: usize },
+    // Checked against the manifest where there is one
+    ChunkReceived { peer_id: PeerId, index: usize, bytes: usize },
+    // Asked for again: by this side after a failed attempt, or of it after the chunk was lost
+    Retry { peer_id: PeerId, index: usize },
+    Completed { chunks: usize, bytes: usize },
+    Failed(String),
+}
+
+impl TransferEvent {
+    pub fn is_terminal(&self) -> bool {
+        matches!(self, TransferEvent::Completed { .. } | TransferEvent::Failed(_))
+    }
+}
+
+// Everyone listening; whoever has dropped their receiver is let go on the next event. Every
+// event is counted too, whether or not anyone listens
+#[derive(Debug, Default)]
+pub struct TransferEvents {
+    subscribers: Vec<mpsc::UnboundedSender<TransferEvent>>,
+    tally: StatsTally,
+}
+
+impl TransferEvents {
+    pub fn subscribe(&mut self) -> mpsc::UnboundedReceiver<TransferEvent> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    pub fn emit(&mut self, event: TransferEvent) {
+        self.tally.observe(&event);
+        self.subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    // The terminal event for a transfer that came to `result`, with `summary` giving the chunks
+    // and bytes it moved
+    pub fn finish<T>(&mut self, result: &Result<T>, summary: impl FnOnce(&T) -> (usize, usize)) {
+        let event = match result {
+            Ok(done) => {
+                let (chunks, bytes) = summary(done);
+                TransferEvent::Completed { chunks, bytes }
+            }
+            Err(e) => TransferEvent::Failed(e.to_string()),
+        };
+        self.emit(event);
+    }
+
+    // What the events since the last call add up to
+    pub fn take_counted(&mut self) -> P2PStats {
+        self.tally.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ShrLinkError;
+
+    #[test]
+    fn test_every_subscriber_sees_events_in_order() {
+        let mut events = TransferEvents::default();
+        let mut first = events.subscribe();
+        let mut second = events.subscribe();
+        let peer_id = PeerId::random();
+
+        events.emit(TransferEvent::PeerConnected(peer_id));
+        events.emit(TransferEvent::ChunkSent { peer_id, index: 0, bytes: 10 });
+        events.finish(&Ok(()), |_| (1, 10));
+        for receiver in [&mut first, &mut second] {
+            assert_eq!(receiver.try_recv().unwrap(), TransferEvent::PeerConnected(peer_id));
+            assert_eq!(receiver.try_recv().unwrap(), TransferEvent::ChunkSent { peer_id, index: 0, bytes: 10 });
+            assert_eq!(receiver.try_recv().unwrap(), TransferEvent::Completed { chunks: 1, bytes: 10 });
+            assert!(receiver.try_recv().is_err());
+        }
+    }
+
+    #[test]
+    fn test_failures_end_with_the_error() {
+        let mut events = TransferEvents::default();
+        let mut receiver = events.subscribe();
+
+        events.finish(&Err::<(), _>(ShrLinkError::Cancelled), |_| unreachable!());
+        let event = receiver.try_recv().unwrap();
+        assert!(event.is_terminal());
+        assert_eq!(event, TransferEvent::Failed(ShrLinkError::Cancelled.to_string()));
+    }
+
+    #[test]
+    fn test_dropped_subscribers_are_let_go() {
+        let mut events = TransferEvents::default();
+        drop(events.subscribe());
+        let mut kept = events.subscribe();
+
+        events.emit(TransferEvent::PeerDisconnected(PeerId::random()));
+        assert_eq!(events.subscribers.len(), 1);
+        assert!(!kept.try_recv().unwrap().is_terminal());
+    }
+
+    #[test]
+    fn test_events_are_counted_without_subscribers() {
+        let mut events = TransferEvents::default();
+        let peer_id = PeerId::random();
+
+        events.emit(TransferEvent::ChunkReceived { peer_id, index: 0, bytes: 10 });
+        events.finish(&Ok(()), |_| (1, 10));
+        let counted = events.take_counted();
+        assert_eq!((counted.chunks_received, counted.bytes_received), (1, 10));
+        assert_eq!(counted.transfers_completed, 1);
+        assert!(events.take_counted().is_empty());
+    }
+}
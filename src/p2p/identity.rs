@@ -0,0 +1,87 @@
+use libp2p::identity::Keypair;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use crate::{Result, ShrLinkError};
+
+pub const IDENTITY_FILE: &str = "identity.key";
+
+// The keypair behind this machine's peer id, made on first use and kept so shr:// URLs handed
+// out earlier still reach it after a restart. A key file that's there but can't be read is an
+// error rather than a reason to make a new one, which would strand every URL already shared
+pub fn load_or_create(path: &Path) -> Result<Keypair> {
+    match fs::read(path) {
+        Ok(bytes) => decode(path, &bytes),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => create(path),
+        Err(e) => Err(ShrLinkError::P2P(format!("Failed to read peer identity {}: {}", path.display(), e))),
+    }
+}
+
+fn decode(path: &Path, bytes: &[u8]) -> Result<Keypair> {
+    Keypair::from_protobuf_encoding(bytes).map_err(|e| {
+        ShrLinkError::P2P(format!(
+            "Peer identity {} is not a valid key ({}); move it aside to start over with a new peer id",
+            path.display(),
+            e
+        ))
+    })
+}
+
+fn create(path: &Path) -> Result<Keypair> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let keypair = Keypair::generate_ed25519();
+    let encoded = keypair.to_protobuf_encoding().map_err(|e| ShrLinkError::P2P(e.to_string()))?;
+
+    // Only ever created whole and private; if another run got there first, its key is the one
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = match options.open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return load_or_create(path),
+        Err(e) => return Err(e.into()),
+    };
+    if let Err(e) = file.write_all(&encoded).and_then(|_| file.sync_all()) {
+        let _ = fs::remove_file(path);
+        return Err(e.into());
+    }
+
+    tracing::info!("Created peer identity {} at {}", keypair.public().to_peer_id(), path.display());
+    Ok(keypair)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identity_is_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nested").join(IDENTITY_FILE);
+
+        let first = load_or_create(&path).unwrap();
+        let again = load_or_create(&path).unwrap();
+        assert_eq!(first.public().to_peer_id(), again.public().to_peer_id());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn test_corrupt_identity_is_not_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(IDENTITY_FILE);
+        fs::write(&path, b"not a key").unwrap();
+
+        let err = load_or_create(&path).unwrap_err();
+        assert!(err.to_string().contains("is not a valid key"), "{}", err);
+        assert_eq!(fs::read(&path).unwrap(), b"not a key");
+    }
+}
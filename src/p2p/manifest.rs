@@ -0,0 +1,238 @@
+use libp2p::identity::{Keypair, PublicKey};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use crate::cache::ChunkCache;
+use crate::compression::{CompressedChunk, CompressionResult};
+use crate::{Result, ShrLinkError};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub index: usize,
+    pub hash: [u8; 32],
+    pub original_size: usize,
+    pub compressed_size: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkManifest {
+    pub file_hash: [u8; 32],
+    pub entries: Vec<ManifestEntry>,
+    // Protobuf-encoded public key of the signer plus one detached signature per entry
+    pub signer: Option<Vec<u8>>,
+    pub signatures: Option<Vec<Vec<u8>>>,
+}
+
+// Who a verification failure should be held against
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Blame {
+    // The data itself is bad no matter who relayed it (forged or unsigned content)
+    Content,
+    // The delivering peer handed over bytes that don't match what the manifest vouches for
+    Peer,
+}
+
+impl Blame {
+    pub fn strikes_peer(&self) -> bool {
+        matches!(self, Blame::Peer)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkRejection {
+    pub index: usize,
+    pub blame: Blame,
+    pub reason: String,
+}
+
+impl ChunkManifest {
+    pub fn from_chunks(file_hash: [u8; 32], chunks: &[CompressedChunk]) -> Self {
+        let entries = chunks
+            .iter()
+            .map(|c| ManifestEntry {
+                index: c.index,
+                hash: c.hash,
+                original_size: c.original_size,
+                compressed_size: c.data.len(),
+            })
+            .collect();
+
+        Self {
+            file_hash,
+            entries,
+            signer: None,
+            signatures: None,
+        }
+    }
+
+    pub fn from_result(result: &CompressionResult) -> Self {
+        Self::from_chunks(result.file_hash, &result.chunks)
+    }
+    
+    // What a receiver answers a manifest with, so the sender can leave those chunks out
+    pub fn cached_in(&self, cache: &ChunkCache) -> HashSet<[u8; 32]> {
+        cache.have(self.entries.iter().map(|e| &e.hash))
+    }
+    
+    // The file's chunks in manifest order: each one from `received` if the sender sent it,
+    // otherwise from the cache under the entry's index
+    pub fn assemble(&self, received: Vec<CompressedChunk>, cache: &ChunkCache) -> Result<Vec<CompressedChunk>> {
+        let mut received: HashMap<usize, CompressedChunk> = received.into_iter().map(|c| (c.index, c)).collect();
+        
+        self.entries
+            .iter()
+            .map(|entry| {
+                let chunk = match received.remove(&entry.index) {
+                    Some(chunk) => chunk,
+                    None => cache
+                        .get(&entry.hash)
+                        .map(|chunk| CompressedChunk { index: entry.index, ..chunk })
+                        .ok_or_else(|| ShrLinkError::P2P(format!("Chunk {} was neither sent nor cached", entry.index)))?,
+                };
+                if chunk.hash != entry.hash || chunk.original_size != entry.original_size {
+                    return Err(ShrLinkError::P2P(format!("Chunk {} does not match its manifest entry", entry.index)));
+                }
+                Ok(chunk)
+            })
+            .collect()
+    }
+    
+    pub fn sign(&mut self, keypair: &Keypair) -> Result<()> {
+        let signatures = self
+            .entries
+            .iter()
+            .map(|e| {
+                keypair
+                    .sign(&signing_payload(&self.file_hash, e.index, &e.hash))
+                    .map_err(|e| ShrLinkError::P2P(format!("Failed to sign chunk: {}", e)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        self.signer = Some(keypair.public().encode_protobuf());
+        self.signatures = Some(signatures);
+        Ok(())
+    }
+
+    pub fn is_signed(&self) -> bool {
+        self.signer.is_some() && self.signatures.is_some()
+    }
+
+    pub fn signer_peer_id(&self) -> Option<PeerId> {
+        let key = PublicKey::try_decode_protobuf(self.signer.as_ref()?).ok()?;
+        Some(key.to_peer_id())
+    }
+
+    pub fn verify_chunk(&self, chunk: &CompressedChunk, expected_signer: Option<PeerId>) -> std::result::Result<(), ChunkRejection> {
+        let reject = |blame, reason: &str| ChunkRejection {
+            index: chunk.index,
+            blame,
+            reason: reason.to_string(),
+        };
+
+        let position = self
+            .entries
+            .iter()
+            .position(|e| e.index == chunk.index)
+            .ok_or_else(|| reject(Blame::Peer, "chunk index not in manifest"))?;
+        let entry = &self.entries[position];
+
+        if let Some(expected) = expected_signer {
+            let (Some(signer), Some(signatures)) = (&self.signer, &self.signatures) else {
+                return Err(reject(Blame::Content, "manifest is missing chunk signatures"));
+            };
+
+            let key = PublicKey::try_decode_protobuf(signer)
+                .map_err(|_| reject(Blame::Content, "manifest signer key is malformed"))?;
+            if key.to_peer_id() != expected {
+                return Err(reject(Blame::Content, "manifest was signed by an unexpected peer"));
+            }
+
+            let signature = signatures
+                .get(position)
+                .ok_or_else(|| reject(Blame::Content, "manifest is missing chunk signatures"))?;
+            if !key.verify(&signing_payload(&self.file_hash, entry.index, &entry.hash), signature) {
+                return Err(reject(Blame::Content, "chunk signature does not verify"));
+            }
+        }
+
+        if chunk.hash != entry.hash || chunk.original_size != entry.original_size {
+            return Err(reject(Blame::Peer, "chunk does not match its manifest entry"));
+        }
+
+        Ok(())
+    }
+}
+
+fn signing_payload(file_hash: &[u8; 32], index: usize, chunk_hash: &[u8; 32]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(32 + 8 + 32);
+    payload.extend_from_slice(file_hash);
+    payload.extend_from_slice(&(index as u64).to_le_bytes());
+    payload.extend_from_slice(chunk_hash);
+    payload
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::ParallelCompressor;
+
+    fn sample_chunks() -> Vec<CompressedChunk> {
+        let compressor = ParallelCompressor::default();
+        (0..3)
+            .map(|i| compressor.compress_chunk(i, vec![i as u8; 1024]).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_signed_chunks_verify() {
+        let keypair = Keypair::generate_ed25519();
+        let chunks = sample_chunks();
+        let mut manifest = ChunkManifest::from_chunks([7u8; 32], &chunks);
+        manifest.sign(&keypair).unwrap();
+
+        assert_eq!(manifest.signer_peer_id(), Some(keypair.public().to_peer_id()));
+        for chunk in &chunks {
+            assert!(manifest.verify_chunk(chunk, Some(keypair.public().to_peer_id())).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_forged_chunk_is_rejected() {
+        let keypair = Keypair::generate_ed25519();
+        let chunks = sample_chunks();
+        let mut manifest = ChunkManifest::from_chunks([7u8; 32], &chunks);
+        manifest.sign(&keypair).unwrap();
+
+        // A relaying peer hands over different bytes than the manifest vouches for
+        let mut tampered = chunks[1].clone();
+        tampered.hash = [0xEE; 32];
+        let rejection = manifest.verify_chunk(&tampered, Some(keypair.public().to_peer_id())).unwrap_err();
+        assert_eq!(rejection.blame, Blame::Peer);
+
+        // Someone rewrote the manifest entry without the originator's key
+        let mut forged = manifest.clone();
+        forged.entries[1].hash = [0xEE; 32];
+        let rejection = forged.verify_chunk(&tampered, Some(keypair.public().to_peer_id())).unwrap_err();
+        assert_eq!(rejection.blame, Blame::Content);
+        assert!(!rejection.blame.strikes_peer());
+
+        // Signed by a key other than the one the URL names
+        let impostor = Keypair::generate_ed25519();
+        let rejection = manifest.verify_chunk(&chunks[0], Some(impostor.public().to_peer_id())).unwrap_err();
+        assert_eq!(rejection.blame, Blame::Content);
+    }
+
+    #[test]
+    fn test_manifest_missing_signatures() {
+        let keypair = Keypair::generate_ed25519();
+        let chunks = sample_chunks();
+        let manifest = ChunkManifest::from_chunks([7u8; 32], &chunks);
+
+        assert!(!manifest.is_signed());
+        assert!(manifest.verify_chunk(&chunks[0], None).is_ok());
+
+        let rejection = manifest.verify_chunk(&chunks[0], Some(keypair.public().to_peer_id())).unwrap_err();
+        assert_eq!(rejection.blame, Blame::Content);
+        assert_eq!(rejection.reason, "manifest is missing chunk signatures");
+    }
+}
@@ -1,184 +1,3659 @@
-use libp2p::{PeerId, Multiaddr};
+use futures::{FutureExt, StreamExt};
+use libp2p::core::transport::ListenerId;
+use libp2p::identity::Keypair;
+use libp2p::request_response::{self, Message, ResponseChannel};
+use libp2p::swarm::SwarmEvent;
+use libp2p::multiaddr::Protocol;
+use libp2p::{autonat, dcutr, identify, mdns, relay, upnp, PeerId, Multiaddr, Swarm};
+use std::collections::hash_map::Entry;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::sync::Arc;
 use std::time::Duration;
+use tokio::sync::watch;
 use tokio::time::sleep;
-use crate::{Result, ShrLinkError};
-use crate::compression::CompressedChunk;
-use crate::config::P2PConfig;
+use tokio_util::sync::CancellationToken;
+use crate::{DialError, Incompatibility, Result, ShrLinkError};
+use crate::compression::{CompressedChunk, ParallelCompressor};
+use crate::bundle::wire;
+use crate::cache::ChunkCache;
+use crate::config::{Config, P2PConfig};
+use swarm::BehaviourEvent;
 
-pub const PROTOCOL_VERSION: &str = "/shr/chunk/1.0.0";
+pub mod access;
+pub mod addresses;
+pub mod announce;
+pub mod capabilities;
+pub mod discovery;
+pub mod events;
+pub mod identity;
+pub mod manifest;
+pub mod pairing;
+pub mod reachability;
+pub mod receipt;
+pub mod rendezvous;
+pub mod reputation;
+pub mod stats;
+pub mod swarm;
+pub mod throttle;
+pub mod transfer;
+
+pub use access::PeerAccess;
+pub use addresses::{AddressBook, AddressChange, AddressEvent};
+pub use announce::{Announcement, Announcements};
+pub use capabilities::{Capabilities, Version};
+pub use discovery::{DiscoveredPeer, DiscoveredPeers};
+pub use events::{TransferEvent, TransferEvents};
+pub use manifest::{Blame, ChunkManifest, ChunkRejection, ManifestEntry};
+pub use pairing::PairingCode;
+use pairing::{SessionKeys, Side, Spake2};
+pub use reachability::{Check, ConnectionPath, NatStatus, PortMapping, ReachabilityReport, Verdict};
+pub use receipt::{DownloadTracker, SignedReceipt, TransferReceipt};
+pub use reputation::{PeerRecord, ReputationStore};
+pub use stats::{P2PStats, PeerStats, StatsStore};
+pub use throttle::{PeerServeStats, RequestGuard, ServeThrottle, TokenBucket};
+pub use rendezvous::{PairingRecord, Rendezvous};
+pub use transfer::{AnnounceCodec, AnnounceRequest, AnnounceResponse, ChunkCodec, ChunkRequest, ChunkResponse, HaveBitmap, HelloCodec, HelloRequest, HelloResponse, ManifestCodec, ManifestRequest, ManifestResponse, PairCodec, PairRequest, PairResponse, ReceiptCodec, ReceiptRequest, ReceiptResponse};
+
+pub const PROTOCOL_VERSION: &str = "/shr/chunk/1.2.0";
+// Still spoken, for peers from before HAVE requests; the codec is the same
+pub const LEGACY_PROTOCOL_VERSION: &str = "/shr/chunk/1.1.0";
+pub const MANIFEST_PROTOCOL: &str = "/shr/manifest/1.0.0";
+pub const HELLO_PROTOCOL: &str = "/shr/hello/1.0.0";
+pub const PAIR_PROTOCOL: &str = "/shr/pair/1.0.0";
+pub const ANNOUNCE_PROTOCOL: &str = "/shr/announce/1.0.0";
+pub const RECEIPT_PROTOCOL: &str = "/shr/receipt/1.0.0";
+pub const RECEIPT_TIMEOUT: Duration = Duration::from_secs(2);
+// How long a finished serve waits for receivers to hang up before returning
+pub const SERVE_LINGER: Duration = Duration::from_secs(5);
+// How long a finished receive waits for its last acknowledgments to reach the sender
+pub const ACK_TIMEOUT: Duration = Duration::from_secs(2);
+// A failed chunk waits this long before it is asked for again, doubling with each attempt
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(5);
+// How long a closing client keeps going so the router hears its port mappings are done with
+const UNMAP_TIMEOUT: Duration = Duration::from_secs(2);
 
 pub struct P2PClient {
+    keypair: Keypair,
     local_peer_id: PeerId,
     config: P2PConfig,
+    serve_throttle: Arc<ServeThrottle>,
+    access: PeerAccess,
+    // Who serves take requests from, and how often
+    requests: RequestGuard,
+    // Shared by everything this client receives, so it's the total that is capped
+    download_limit: Option<TokenBucket>,
+    reputation: ReputationStore,
+    // What this machine's transfers have added up to, saved after each one
+    stats: StatsStore,
+    addresses: AddressBook,
+    discovered: DiscoveredPeers,
+    address_updates: watch::Sender<Vec<Multiaddr>>,
+    swarm: Swarm<swarm::Behaviour>,
+    // Who answered on addresses dialed without a /p2p/ component, so they can be reused too
+    dialed: HashMap<Multiaddr, PeerId>,
+    // Where each connected peer was reached, for telling direct connections from relayed ones
+    connections: HashMap<PeerId, Multiaddr>,
+    // What AutoNAT last concluded, if relays are configured to ask
+    nat_status: NatStatus,
+    port_mapping: PortMapping,
+    listener_ids: Vec<ListenerId>,
+    // Whether this client has asked the relays for reservations, which it does once
+    relaying: bool,
+    // What this client tells senders it can take
+    capabilities: Capabilities,
+    // What a serve tells whoever asks on the local network, if `announce` is set
+    announcing: Vec<Announcement>,
+    // Where a sender's offered manifest is looked up, to tell it which chunks it can leave out
+    cache: Option<ChunkCache>,
+    // Whose manifests this client asked for, the only ones it says anything of its cache about
+    fetched_manifests: HashSet<(PeerId, [u8; 32])>,
+    // Acknowledgments on their way to senders
+    acks: HashSet<request_response::OutboundRequestId>,
+    events: TransferEvents,
+    cancel: CancellationToken,
+    shutdown: CancellationToken,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServeEvent {
+    // A peer asked for its first chunk
+    PeerStarted(PeerId),
+    // A peer asked while `max_concurrent_transfers` others were being served
+    PeerWaiting(PeerId),
+    ChunkServed { peer_id: PeerId, chunks_served: usize, total_chunks: usize },
+    // A peer has had every chunk
+    PeerCompleted(PeerId),
+    // A receiver confirmed, with a receipt it signed, that the whole file checked out
+    ReceiptReceived(TransferReceipt),
+    // A peer said what it can take, and it isn't enough for these chunks
+    PeerIncompatible(PeerId, Incompatibility),
+    // A peer asked too often, or for what no receiver would, and was disconnected and banned
+    PeerCutOff(PeerId, String),
+    Status(ServeStats),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ServeStats {
+    // Each chunk counted once per peer, when the peer acknowledges it, however many times it was asked for
+    pub chunks_served: usize,
+    pub bytes_served: usize,
+    pub peers: usize,
+    // Receivers that had every chunk from this serve or sent a receipt for the file, each counted once
+    pub completed: usize,
+    // Of those, the ones that sent a receipt
+    pub receipts: usize,
+    // Chunks asked for again after being sent, because they were lost or didn't check out
+    pub chunks_retried: usize,
+    // Requests turned away because the peer isn't allowed, or is blocked
+    pub refused: usize,
+    // Peers cut off for asking too often, or for chunks past the end, or in ways that don't parse
+    pub violations: usize,
+}
+
+// What one peer has been sent, and what it has acknowledged
+#[derive(Default)]
+struct Session {
+    written: HashSet<usize>,
+    acked: HashSet<usize>,
+    // Chunks it said it had cached when offered the manifest, and so won't ask for; never
+    // any it has acknowledged
+    cached: HashSet<usize>,
+}
+
+// What a serve hands out, and to whom: anyone unless `only` is set. Every receiver is answered
+// from the same chunks, so a serve takes no more memory for more receivers
+struct Offer {
+    chunks: HashMap<usize, Arc<CompressedChunk>>,
+    // Indexes from here on can't be in the file, whatever a peer has been offered
+    chunk_count: usize,
+    manifest: Option<ChunkManifest>,
+    only: Option<PeerId>,
+    // What receiving the chunks takes, for the handshake
+    needs: Capabilities,
+}
+
+impl Offer {
+    fn new(chunks: Vec<CompressedChunk>, manifest: Option<ChunkManifest>, only: Option<PeerId>) -> Self {
+        let needs = Capabilities::needed_by(&chunks);
+        let past_last = chunks.iter().map(|c| c.index + 1).max().unwrap_or(0);
+        let chunk_count = manifest.as_ref().map_or(past_last, |m| m.entries.len().max(past_last));
+        let chunks = chunks.into_iter().map(|c| (c.index, Arc::new(c))).collect();
+        Self { chunks, chunk_count, manifest, only, needs }
+    }
 }
 
 #[derive(Debug)]
 pub struct TransferProgress {
     pub chunks_sent: usize,
+    // Left out because the receiver already had them
+    pub chunks_skipped: usize,
     pub total_chunks: usize,
     pub bytes_sent: usize,
     pub total_bytes: usize,
+    pub chunks_retried: usize,
 }
 
 impl P2PClient {
     pub async fn new(config: P2PConfig) -> Result<Self> {
-        let local_peer_id = PeerId::random();
+        let keypair = identity::load_or_create(&config.identity_file())?;
+        let local_peer_id = keypair.public().to_peer_id();
         
         tracing::info!("P2P client created with peer ID: {}", local_peer_id);
         
+        let upload_limit = config.max_upload_bps.map(|bps| Arc::new(TokenBucket::new(bps)));
+        let serve_throttle = Arc::new(ServeThrottle::new(config.per_peer_max_bps, upload_limit));
+        let download_limit = config.max_download_bps.map(TokenBucket::new);
+        let access = PeerAccess::from_config(&config)?;
+        let requests = RequestGuard::new(config.max_requests_per_sec, Duration::from_millis(config.request_ban_ms));
+        let swarm = swarm::build_swarm(keypair.clone(), &config)?;
+        let reputation = ReputationStore::load(&Config::reputation_path(), reputation::DEFAULT_CAPACITY);
+        let stats = StatsStore::load(&Config::stats_path());
+        let port_mapping = match config.enable_upnp {
+            true => PortMapping::Pending,
+            false => PortMapping::Disabled,
+        };
+        
         Ok(Self {
+            keypair,
             local_peer_id,
             config,
+            serve_throttle,
+            access,
+            requests,
+            download_limit,
+            reputation,
+            stats,
+            addresses: AddressBook::new(),
+            discovered: DiscoveredPeers::new(),
+            address_updates: watch::channel(Vec::new()).0,
+            swarm,
+            dialed: HashMap::new(),
+            connections: HashMap::new(),
+            nat_status: NatStatus::Unknown,
+            port_mapping,
+            listener_ids: Vec::new(),
+            relaying: false,
+            capabilities: Capabilities::local(),
+            announcing: Vec::new(),
+            cache: None,
+            fetched_manifests: HashSet::new(),
+            acks: HashSet::new(),
+            events: TransferEvents::default(),
+            cancel: CancellationToken::new(),
+            shutdown: CancellationToken::new(),
         })
     }
     
+    // Less than this build can take, as a receiver with older codecs or a smaller frame limit
+    // would say
+    pub fn with_capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+    
+    pub fn with_reputation(mut self, reputation: ReputationStore) -> Self {
+        self.reputation = reputation;
+        self
+    }
+    
+    pub fn with_stats(mut self, stats: StatsStore) -> Self {
+        self.stats = stats;
+        self
+    }
+    
+    // Chunks a sender offers that are in `cache` are reported as had, and left to the caller to
+    // fill in from it
+    pub fn with_cache(mut self, cache: ChunkCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+    
+    // Sends stop between chunks, or while one waits on the throttle, once `cancel` fires
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+    
+    // Once `shutdown` fires, serves take on no one new and give whoever they're serving up to
+    // `shutdown_grace_ms` to finish, then return what they served as usual
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+    
+    // Told to peers that ask while a serve runs, and only if `announce` is set
+    pub fn announce(&mut self, announcement: Announcement) {
+        self.announcing.push(announcement);
+    }
+    
+    // Everything sent and received from here on, as it happens
+    pub fn transfer_events(&mut self) -> tokio::sync::mpsc::UnboundedReceiver<TransferEvent> {
+        self.events.subscribe()
+    }
+    
+    pub fn prepare_manifest(&self, file_hash: [u8; 32], chunks: &[CompressedChunk]) -> Result<ChunkManifest> {
+        let mut manifest = ChunkManifest::from_chunks(file_hash, chunks);
+        
+        // Signatures cost ~64 bytes per chunk, so they are only added when asked for
+        if self.config.sign_chunks {
+            manifest.sign(&self.keypair)?;
+        }
+        
+        Ok(manifest)
+    }
+    
+    // Serves `chunks` to `peer_id` as it asks for them, returning once it has had every one
     pub async fn send_chunks(&mut self, peer_id: PeerId, chunks: Vec<CompressedChunk>) -> Result<TransferProgress> {
+        let sent = self.send_only_to(peer_id, chunks).await;
+        self.finish_transfer(&sent, |progress| (progress.chunks_sent, progress.bytes_sent));
+        sent
+    }
+    
+    async fn send_only_to(&mut self, peer_id: PeerId, chunks: Vec<CompressedChunk>) -> Result<TransferProgress> {
         let total_chunks = chunks.len();
         let total_bytes: usize = chunks.iter().map(|c| c.data.len()).sum();
         
-        let mut progress = TransferProgress {
-            chunks_sent: 0,
+        let offer = Offer::new(chunks, None, Some(peer_id));
+        // There's no manifest, so no file hash for a receipt to name; only serving it counts
+        let mut downloads = DownloadTracker::new([0; 32], Some(1));
+        let mut stats = ServeStats::default();
+        let cancel = self.cancel.clone();
+        let mut ignore = |_| {};
+        tokio::select! {
+            served = self.serve(&offer, &mut downloads, None, &mut stats, &mut ignore) => { served?; }
+            _ = cancel.cancelled() => {
+                tracing::info!("Transfer to {} cancelled after {}/{} chunks", peer_id, stats.chunks_served, total_chunks);
+                return Err(ShrLinkError::Cancelled);
+            }
+        }
+        
+        Ok(TransferProgress {
+            chunks_sent: stats.chunks_served,
+            chunks_skipped: 0,
             total_chunks,
-            bytes_sent: 0,
+            bytes_sent: stats.bytes_served,
             total_bytes,
+            chunks_retried: stats.chunks_retried,
+        })
+    }
+    
+    // Serves `chunks`, and `manifest` to anyone asking for the file, until `copies` peers have
+    // had every chunk or sent a receipt for the file, reporting along the way and every
+    // `status_every` to `on_event`
+    pub async fn serve_chunks(&mut self, manifest: &ChunkManifest, chunks: Vec<CompressedChunk>, copies: usize, status_every: Duration, on_event: impl FnMut(ServeEvent) + Send) -> Result<ServeStats> {
+        let served = self.serve_to_copies(manifest, chunks, copies, status_every, on_event).await;
+        self.stats.record_serve_rates(&self.serve_stats());
+        self.finish_transfer(&served, |stats| (stats.chunks_served, stats.bytes_served));
+        served
+    }
+    
+    async fn serve_to_copies(&mut self, manifest: &ChunkManifest, chunks: Vec<CompressedChunk>, copies: usize, status_every: Duration, mut on_event: impl FnMut(ServeEvent) + Send) -> Result<ServeStats> {
+        let offer = Offer::new(chunks, Some(manifest.clone()), None);
+        let mut downloads = DownloadTracker::new(manifest.file_hash, Some(copies));
+        let mut stats = ServeStats::default();
+        let cancel = self.cancel.clone();
+        let completed = tokio::select! {
+            served = self.serve(&offer, &mut downloads, Some(status_every), &mut stats, &mut on_event) => served?,
+            _ = cancel.cancelled() => {
+                tracing::info!("Serving cancelled after {} chunks to {} peers", stats.chunks_served, stats.peers);
+                return Err(ShrLinkError::Cancelled);
+            }
+        };
+        
+        // The process usually exits next, which would cut off responses still on their way out,
+        // so receivers get a moment to hang up first, and to send their receipts
+        let linger = async {
+            while completed.iter().any(|p| self.swarm.is_connected(p)) {
+                match self.next_event().await {
+                    SwarmEvent::Behaviour(BehaviourEvent::Receipts(swarm::ReceiptEvent::Message { peer, message: Message::Request { request, channel, .. }, .. })) if completed.contains(&peer) => {
+                        self.take_receipt(peer, request, channel, &mut downloads, &mut stats, &mut on_event);
+                    }
+                    event => self.refuse_requests(event),
+                }
+            }
         };
+        let _ = tokio::time::timeout(SERVE_LINGER, linger).await;
+        
+        Ok(stats)
+    }
+    
+    // Returns the peers that had every chunk or sent a receipt, once `downloads` has reached its
+    // limit or a shutdown has seen off those being served. Up to `max_concurrent_transfers` peers
+    // are served at once; requests from any more wait, unanswered, until one of those finishes
+    // or goes away
+    async fn serve(
+        &mut self,
+        offer: &Offer,
+        downloads: &mut DownloadTracker,
+        status_every: Option<Duration>,
+        stats: &mut ServeStats,
+        on_event: &mut (dyn FnMut(ServeEvent) + Send),
+    ) -> Result<Vec<PeerId>> {
+        let offered = &offer.chunks;
+        let only = offer.only;
+        // A chunk only counts as sent once the peer acknowledges it
+        let mut in_flight = HashMap::new();
+        let mut sessions: HashMap<PeerId, Session> = HashMap::new();
+        // Manifests offered to peers as they start, to hear which chunks they won't be asking for
+        let mut offers = HashMap::new();
+        let mut completed = Vec::new();
+        let limit = self.config.max_concurrent_transfers.max(1);
+        let mut active = HashSet::new();
+        // Chunk requests from peers past the limit, answered in the order they came
+        let mut waiting: VecDeque<(PeerId, request_response::InboundRequestId, &Arc<CompressedChunk>, ResponseChannel<ChunkResponse>)> = VecDeque::new();
+        let mut ticker = status_every.map(|every| tokio::time::interval_at(tokio::time::Instant::now() + every, every));
+        let shutdown = self.shutdown.clone();
+        // When a shutdown gives up on whoever is still being served
+        let mut stop_at = None;
         
-        for chunk in chunks {
-            self.send_chunk(peer_id, &chunk).await?;
-            progress.chunks_sent += 1;
-            progress.bytes_sent += chunk.data.len();
+        while !offered.is_empty() && !downloads.limit_reached() {
+            if let Some(stop_at) = stop_at {
+                if (active.is_empty() && in_flight.is_empty()) || tokio::time::Instant::now() >= stop_at {
+                    break;
+                }
+            }
+            // Whoever has been waiting goes first once there's room, and the rest of a peer's
+            // requests follow once it's in
+            let parked = waiting
+                .iter()
+                .position(|(waiter, ..)| active.len() < limit || active.contains(waiter))
+                .and_then(|position| waiting.remove(position));
+            let (peer, request_id, chunk, channel) = match parked {
+                Some(parked) => parked,
+                None => {
+                    // A shutdown is seen to first, however busy the peers keep it
+                    let event = tokio::select! {
+                        biased;
+                        _ = shutdown.cancelled(), if stop_at.is_none() => {
+                            let grace = Duration::from_millis(self.config.shutdown_grace_ms);
+                            tracing::info!("Shutting down: {} peers have up to {:?} to finish", active.len(), grace);
+                            stop_at = Some(tokio::time::Instant::now() + grace);
+                            for (peer, _, chunk, channel) in waiting.drain(..) {
+                                self.refuse_chunk(peer, ChunkRequest::Chunk { index: chunk.index }, channel);
+                            }
+                            continue;
+                        }
+                        _ = tokio::time::sleep_until(stop_at.unwrap_or_else(tokio::time::Instant::now)), if stop_at.is_some() => {
+                            tracing::info!("Stopped serving {} peers that hadn't finished", active.len());
+                            break;
+                        }
+                        _ = async {
+                            match &mut ticker {
+                                Some(ticker) => { ticker.tick().await; }
+                                None => std::future::pending::<()>().await,
+                            }
+                        } => {
+                            on_event(ServeEvent::Status(*stats));
+                            continue;
+                        }
+                        event = self.next_event() => event,
+                    };
+                    
+                    // Before anything is looked up for it: a banned peer is seen off again, and
+                    // one asking too often or for a chunk past the end of the file is cut off
+                    if let Some((peer, index)) = inbound_request(&event) {
+                        if self.requests.is_banned(&peer) {
+                            tracing::debug!("Ignoring a request from {}, which is banned for now", peer);
+                            let _ = self.swarm.disconnect_peer_id(peer);
+                            continue;
+                        }
+                        let violation = match index {
+                            Some(index) if index >= offer.chunk_count => Some(format!("asked for chunk {} of {}", index, offer.chunk_count)),
+                            _ if !self.requests.allow(peer) => Some(format!("made more than {} requests a second", self.requests.rate())),
+                            _ => None,
+                        };
+                        if let Some(violation) = violation {
+                            self.cut_off(peer, violation, stats, on_event);
+                            active.remove(&peer);
+                            waiting.retain(|(waiter, ..)| *waiter != peer);
+                            continue;
+                        }
+                    }
+                    
+                    match event {
+                        SwarmEvent::Behaviour(BehaviourEvent::Chunks(swarm::Event::Message { peer, message: Message::Request { request: ChunkRequest::Ack { index, hash }, channel, .. }, .. })) => {
+                            let _ = self.swarm.behaviour_mut().chunks.send_response(channel, ChunkResponse::NotOffered);
+                            let (Some(session), Some(chunk)) = (sessions.get_mut(&peer), offered.get(&index)) else {
+                                continue;
+                            };
+                            if hash != chunk.hash {
+                                tracing::warn!("Peer {} acknowledged chunk {} as {}, not {}", peer, index, hex::encode(hash), hex::encode(chunk.hash));
+                                continue;
+                            }
+                            if !session.acked.insert(index) {
+                                continue;
+                            }
+                            session.cached.remove(&index);
+                            stats.chunks_served += 1;
+                            stats.bytes_served += chunk.data.len();
+                    
+                            tracing::debug!(
+                                "Sent chunk {}/{} ({} bytes) to {}", 
+                                session.acked.len(), 
+                                offered.len(),
+                                chunk.data.len(),
+                                peer
+                            );
+                            self.events.emit(TransferEvent::ChunkSent { peer_id: peer, index, bytes: chunk.data.len() });
+                            on_event(ServeEvent::ChunkServed { peer_id: peer, chunks_served: session.acked.len(), total_chunks: offered.len() });
+                            if session.acked.len() + session.cached.len() == offered.len() {
+                                self.complete_peer(peer, downloads, stats, &mut completed, on_event);
+                                active.remove(&peer);
+                            }
+                            continue;
+                        }
+                        // What it has cached it won't ask for, and has all the same
+                        SwarmEvent::Behaviour(BehaviourEvent::Manifests(swarm::ManifestEvent::Message { message: Message::Response { request_id, response }, .. })) if offers.contains_key(&request_id) => {
+                            let peer = offers.remove(&request_id).expect("checked just above");
+                            let (Some(session), Some(manifest), ManifestResponse::Cached(cached)) = (sessions.get_mut(&peer), &offer.manifest, response) else {
+                                continue;
+                            };
+                            session.cached = manifest
+                                .entries
+                                .iter()
+                                .enumerate()
+                                .filter(|(position, e)| cached.contains(*position) && offered.contains_key(&e.index) && !session.acked.contains(&e.index))
+                                .map(|(_, e)| e.index)
+                                .collect();
+                            tracing::debug!("Peer {} has {} of the {} chunks cached", peer, session.cached.len(), offered.len());
+                            if !session.cached.is_empty() && session.acked.len() + session.cached.len() == offered.len() {
+                                self.complete_peer(peer, downloads, stats, &mut completed, on_event);
+                                active.remove(&peer);
+                            }
+                            continue;
+                        }
+                        SwarmEvent::Behaviour(BehaviourEvent::Manifests(swarm::ManifestEvent::OutboundFailure { peer, request_id, error, .. })) if offers.contains_key(&request_id) => {
+                            offers.remove(&request_id);
+                            tracing::debug!("Offering the manifest to {} failed: {}", peer, error);
+                            continue;
+                        }
+                        // A receiver that had some chunks from elsewhere is done all the same. Only
+                        // peers this serve has sent chunks to count, so that anyone else who knows
+                        // the file hash can't end it early
+                        SwarmEvent::Behaviour(BehaviourEvent::Receipts(swarm::ReceiptEvent::Message { peer, message: Message::Request { request, channel, .. }, .. })) if offer.manifest.is_some() && sessions.contains_key(&peer) => {
+                            if self.take_receipt(peer, request, channel, downloads, stats, on_event) && !completed.contains(&peer) {
+                                completed.push(peer);
+                                active.remove(&peer);
+                            }
+                            continue;
+                        }
+                        // Anyone not being served is told it has nothing to ask for
+                        SwarmEvent::Behaviour(BehaviourEvent::Chunks(swarm::Event::Message { peer, message: Message::Request { request: ChunkRequest::Have, channel, .. }, .. })) => {
+                            let permitted = self.access.permits(&peer);
+                            if !permitted {
+                                tracing::warn!("Refused the chunk list to {}, which isn't allowed to receive", peer);
+                                stats.refused += 1;
+                            }
+                            let have = if permitted && only.is_none_or(|p| p == peer) && stop_at.is_none() {
+                                HaveBitmap::from_indices(offered.keys().copied())
+                            } else {
+                                HaveBitmap::default()
+                            };
+                            let _ = self.swarm.behaviour_mut().chunks.send_response(channel, ChunkResponse::Have(have));
+                            continue;
+                        }
+                        SwarmEvent::Behaviour(BehaviourEvent::Chunks(swarm::Event::Message { peer, message: Message::Request { request, channel, .. }, .. })) if !self.access.permits(&peer) => {
+                            tracing::warn!("Refused chunk {} to {}, which isn't allowed to receive", request.index().unwrap_or_default(), peer);
+                            stats.refused += 1;
+                            let _ = self.swarm.behaviour_mut().chunks.send_response(channel, ChunkResponse::NotOffered);
+                            continue;
+                        }
+                        SwarmEvent::Behaviour(BehaviourEvent::Manifests(swarm::ManifestEvent::Message { peer, message: Message::Request { request: ManifestRequest::Fetch { file_hash }, channel, .. }, .. })) if !self.access.permits(&peer) => {
+                            tracing::warn!("Refused the manifest of {} to {}, which isn't allowed to receive", hex::encode(file_hash), peer);
+                            stats.refused += 1;
+                            let _ = self.swarm.behaviour_mut().manifests.send_response(channel, ManifestResponse::NotOffered);
+                            continue;
+                        }
+                        SwarmEvent::Behaviour(BehaviourEvent::Chunks(swarm::Event::Message { peer, message: Message::Request { request_id, request, channel }, .. })) => {
+                            let Some(chunk) = request.index().and_then(|index| offered.get(&index)).filter(|_| only.is_none_or(|p| p == peer)) else {
+                                self.refuse_chunk(peer, request, channel);
+                                continue;
+                            };
+                            (peer, request_id, chunk, channel)
+                        }
+                        SwarmEvent::Behaviour(BehaviourEvent::Hello(swarm::HelloEvent::Message { peer, message: Message::Request { request, channel, .. }, .. })) => {
+                            let response = match capabilities::negotiate(&offer.needs, &request) {
+                                Ok(version) => {
+                                    tracing::debug!("Agreed on chunk protocol {} with {}", version, peer);
+                                    HelloResponse::Accepted(offer.needs.clone())
+                                }
+                                Err(reason) => {
+                                    tracing::warn!("Peer {} can't receive these chunks: {}", peer, reason);
+                                    on_event(ServeEvent::PeerIncompatible(peer, reason.clone()));
+                                    HelloResponse::Incompatible(reason)
+                                }
+                            };
+                            let _ = self.swarm.behaviour_mut().hello.send_response(channel, response);
+                            continue;
+                        }
+                        SwarmEvent::Behaviour(BehaviourEvent::Manifests(swarm::ManifestEvent::Message { peer, message: Message::Request { request: ManifestRequest::Fetch { file_hash }, channel, .. }, .. })) => {
+                            let answer = offer.manifest.as_ref().filter(|m| m.file_hash == file_hash && only.is_none_or(|p| p == peer) && stop_at.is_none());
+                            tracing::debug!("Peer {} asked for the manifest of {} ({})", peer, hex::encode(file_hash), if answer.is_some() { "served" } else { "not offered" });
+                            let answer = answer.cloned().map_or(ManifestResponse::NotOffered, ManifestResponse::Manifest);
+                            let _ = self.swarm.behaviour_mut().manifests.send_response(channel, answer);
+                            continue;
+                        }
+                        // A serve for one receiver is nobody else's business
+                        SwarmEvent::Behaviour(BehaviourEvent::Announce(swarm::AnnounceEvent::Message { peer, message: Message::Request { channel, .. }, .. })) => {
+                            let shared = self.config.announce && only.is_none() && stop_at.is_none() && self.access.permits(&peer);
+                            let answer = if shared { self.announcing.clone() } else { Vec::new() };
+                            tracing::debug!("Peer {} asked what's being shared, and was told of {} files", peer, answer.len());
+                            let _ = self.swarm.behaviour_mut().announce.send_response(channel, answer);
+                            continue;
+                        }
+                        SwarmEvent::Behaviour(BehaviourEvent::Chunks(swarm::Event::ResponseSent { request_id, .. })) => {
+                            if let Some((peer, index)) = in_flight.remove(&request_id) {
+                                sessions.entry(peer).or_default().written.insert(index);
+                            }
+                            continue;
+                        }
+                        SwarmEvent::Behaviour(BehaviourEvent::Chunks(swarm::Event::InboundFailure { peer, request_id, error, .. })) => {
+                            if let Some((_, index)) = in_flight.remove(&request_id) {
+                                tracing::debug!("Failed to send chunk {} to {}: {}", index, peer, error);
+                            } else if matches!(&error, request_response::InboundFailure::Io(e) if e.kind() == std::io::ErrorKind::InvalidData) {
+                                self.cut_off(peer, format!("sent a request that doesn't parse ({})", error), stats, on_event);
+                                active.remove(&peer);
+                                waiting.retain(|(waiter, ..)| *waiter != peer);
+                            }
+                            continue;
+                        }
+                        SwarmEvent::ConnectionClosed { peer_id: peer, num_established: 0, .. } if only == Some(peer) => {
+                            return Err(ShrLinkError::Network(format!(
+                                "Peer {} disconnected after {}/{} chunks",
+                                peer, stats.chunks_served, offered.len()
+                            )));
+                        }
+                        // Its place goes to whoever is waiting
+                        SwarmEvent::ConnectionClosed { peer_id: peer, num_established: 0, .. } => {
+                            active.remove(&peer);
+                            waiting.retain(|(waiter, ..)| *waiter != peer);
+                            continue;
+                        }
+                        event => {
+                            self.refuse_requests(event);
+                            continue;
+                        }
+                    }
+                }
+            };
             
-            tracing::debug!(
-                "Sent chunk {}/{} ({} bytes)", 
-                progress.chunks_sent, 
-                progress.total_chunks,
-                chunk.data.len()
-            );
+            if !active.contains(&peer) && !completed.contains(&peer) {
+                if stop_at.is_some() {
+                    self.refuse_chunk(peer, ChunkRequest::Chunk { index: chunk.index }, channel);
+                    continue;
+                }
+                if active.len() >= limit {
+                    if !waiting.iter().any(|(waiter, ..)| *waiter == peer) {
+                        tracing::info!("Peer {} is waiting for one of {} transfers to finish", peer, limit);
+                        on_event(ServeEvent::PeerWaiting(peer));
+                    }
+                    waiting.push_back((peer, request_id, chunk, channel));
+                    continue;
+                }
+                active.insert(peer);
+            }
+            
+            let session = match sessions.entry(peer) {
+                Entry::Occupied(session) => session.into_mut(),
+                Entry::Vacant(session) => {
+                    stats.peers += 1;
+                    on_event(ServeEvent::PeerStarted(peer));
+                    if let Some(manifest) = &offer.manifest {
+                        let request_id = self.swarm.behaviour_mut().manifests.send_request(&peer, ManifestRequest::Offer(manifest.clone()));
+                        offers.insert(request_id, peer);
+                    }
+                    session.insert(Session::default())
+                }
+            };
+            if session.written.contains(&chunk.index) && !session.acked.contains(&chunk.index) {
+                tracing::debug!("Peer {} asked for chunk {} again", peer, chunk.index);
+                stats.chunks_retried += 1;
+                self.events.emit(TransferEvent::Retry { peer_id: peer, index: chunk.index });
+            }
+            let frame_len = wire::encoded_len(chunk);
+            tracing::info!("Sending chunk {} ({} bytes) to peer {}", chunk.index, frame_len, peer);
+            
+            // Rate limits apply where the chunk is written so a throttled peer never holds buffers for others
+            tokio::select! {
+                _ = self.serve_throttle.acquire(peer, frame_len as u64) => {}
+                _ = tokio::time::sleep_until(stop_at.unwrap_or_else(tokio::time::Instant::now)), if stop_at.is_some() => break,
+            }
+            if self.swarm.behaviour_mut().chunks.send_response(channel, ChunkResponse::Chunk(Arc::clone(chunk))).is_ok() {
+                in_flight.insert(request_id, (peer, chunk.index));
+            }
+        }
+        
+        Ok(completed)
+    }
+    
+    // `peer` has every chunk, from this serve or its own cache; it's counted once
+    fn complete_peer(&mut self, peer: PeerId, downloads: &mut DownloadTracker, stats: &mut ServeStats, completed: &mut Vec<PeerId>, on_event: &mut (dyn FnMut(ServeEvent) + Send)) {
+        self.record_served(downloads, peer);
+        stats.completed = downloads.completed_downloads();
+        if !completed.contains(&peer) {
+            completed.push(peer);
+        }
+        on_event(ServeEvent::PeerCompleted(peer));
+    }
+    
+    // Disconnects and bans `peer` for `request_ban_ms`, counting it against the peer in the stats
+    fn cut_off(&mut self, peer: PeerId, violation: String, stats: &mut ServeStats, on_event: &mut (dyn FnMut(ServeEvent) + Send)) {
+        tracing::warn!("Cutting off peer {}, which {}", peer, violation);
+        stats.violations += 1;
+        self.stats.record_violation(&peer);
+        self.requests.ban(peer);
+        let _ = self.swarm.disconnect_peer_id(peer);
+        on_event(ServeEvent::PeerCutOff(peer, violation));
+    }
+    
+    // Counts a receipt for the file being served toward `downloads` and in the stats, answering
+    // the receiver either way; says whether it counted
+    fn take_receipt(
+        &mut self,
+        peer: PeerId,
+        receipt: SignedReceipt,
+        channel: ResponseChannel<ReceiptResponse>,
+        downloads: &mut DownloadTracker,
+        stats: &mut ServeStats,
+        on_event: &mut (dyn FnMut(ServeEvent) + Send),
+    ) -> bool {
+        // Each receiver speaks for itself, not for whoever it passed the file on to
+        if receipt.receipt.receiver_peer_id != peer.to_string() {
+            tracing::warn!("Refused a receipt from {} that names {}", peer, receipt.receipt.receiver_peer_id);
+            let _ = self.swarm.behaviour_mut().receipts.send_response(channel, ReceiptResponse::Refused);
+            return false;
+        }
+        let response = match self.record_receipt(downloads, &receipt) {
+            Ok(receiver) => {
+                tracing::info!("Peer {} confirmed receiving {} bytes in {:?}", receiver, receipt.receipt.bytes, receipt.receipt.duration);
+                self.stats.record_receipt(&receiver);
+                stats.completed = downloads.completed_downloads();
+                stats.receipts = downloads.confirmed_downloads();
+                on_event(ServeEvent::ReceiptReceived(receipt.receipt));
+                ReceiptResponse::Recorded
+            }
+            Err(e) => {
+                tracing::warn!("Refused a receipt from {}: {}", peer, e);
+                ReceiptResponse::Refused
+            }
+        };
+        let _ = self.swarm.behaviour_mut().receipts.send_response(channel, response);
+        response == ReceiptResponse::Recorded
+    }
+    
+    // Whatever isn't being served to the peer asking is answered with nothing rather than left hanging
+    fn refuse_chunk(&mut self, peer: PeerId, request: ChunkRequest, channel: ResponseChannel<ChunkResponse>) {
+        let response = match request {
+            ChunkRequest::Chunk { index } => {
+                tracing::debug!("Peer {} asked for chunk {}, which isn't offered to it", peer, index);
+                ChunkResponse::NotOffered
+            }
+            ChunkRequest::Ack { .. } => ChunkResponse::NotOffered,
+            ChunkRequest::Have => ChunkResponse::Have(HaveBitmap::default()),
+        };
+        let _ = self.swarm.behaviour_mut().chunks.send_response(channel, response);
+    }
+    
+    // Nothing is on offer outside of a serve, so any request in `event` gets a no
+    fn refuse_requests(&mut self, event: SwarmEvent<BehaviourEvent>) {
+        match event {
+            SwarmEvent::Behaviour(BehaviourEvent::Chunks(swarm::Event::Message { peer, message: Message::Request { request, channel, .. }, .. })) => {
+                self.refuse_chunk(peer, request, channel);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Manifests(swarm::ManifestEvent::Message { peer, message: Message::Request { request: ManifestRequest::Offer(manifest), channel, .. }, .. })) => {
+                let cached = self.cached_positions(peer, &manifest);
+                let _ = self.swarm.behaviour_mut().manifests.send_response(channel, ManifestResponse::Cached(cached));
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Manifests(swarm::ManifestEvent::Message { peer, message: Message::Request { request, channel, .. }, .. })) => {
+                tracing::debug!("Peer {} asked for the manifest of {}, which isn't offered", peer, hex::encode(request.file_hash()));
+                let _ = self.swarm.behaviour_mut().manifests.send_response(channel, ManifestResponse::NotOffered);
+            }
+            // Nothing is offered, so nothing is asked of the peer either
+            SwarmEvent::Behaviour(BehaviourEvent::Hello(swarm::HelloEvent::Message { message: Message::Request { channel, .. }, .. })) => {
+                let _ = self.swarm.behaviour_mut().hello.send_response(channel, HelloResponse::Accepted(Capabilities::needed_by([])));
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Pairing(swarm::PairEvent::Message { peer, message: Message::Request { channel, .. }, .. })) => {
+                tracing::debug!("Peer {} asked to pair, but no pairing code is being held", peer);
+                let _ = self.swarm.behaviour_mut().pairing.send_response(channel, PairResponse::Unknown);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Announce(swarm::AnnounceEvent::Message { message: Message::Request { channel, .. }, .. })) => {
+                let _ = self.swarm.behaviour_mut().announce.send_response(channel, Vec::new());
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Receipts(swarm::ReceiptEvent::Message { peer, message: Message::Request { channel, .. }, .. })) => {
+                tracing::debug!("Peer {} sent a receipt, but nothing is being served", peer);
+                let _ = self.swarm.behaviour_mut().receipts.send_response(channel, ReceiptResponse::Refused);
+            }
+            _ => {}
+        }
+    }
+    
+    // The manifest positions of the chunks `manifest` lists that are in the cache, told only to
+    // a peer whose manifest for that file this client asked for
+    fn cached_positions(&self, peer: PeerId, manifest: &ChunkManifest) -> HaveBitmap {
+        let Some(cache) = self.cache.as_ref().filter(|_| self.fetched_manifests.contains(&(peer, manifest.file_hash))) else {
+            tracing::debug!("Peer {} offered the manifest of {}, which wasn't asked for", peer, hex::encode(manifest.file_hash));
+            return HaveBitmap::default();
+        };
+        let cached = manifest.cached_in(cache);
+        tracing::debug!("Peer {} offered {} chunks, {} of them cached", peer, manifest.entries.len(), cached.len());
+        HaveBitmap::from_indices(manifest.entries.iter().enumerate().filter(|(_, e)| cached.contains(&e.hash)).map(|(position, _)| position))
+    }
+    
+    // Asks the receiver which of the manifest's chunks it has cached from earlier transfers. One
+    // that keeps no cache, or predates offers, has none
+    pub async fn offer_manifest(&mut self, peer_id: PeerId, manifest: &ChunkManifest) -> Result<HashSet<[u8; 32]>> {
+        tracing::info!("Offering a manifest of {} chunks to peer {}", manifest.entries.len(), peer_id);
+        
+        let request_id = self.swarm.behaviour_mut().manifests.send_request(&peer_id, ManifestRequest::Offer(manifest.clone()));
+        loop {
+            match self.next_event().await {
+                SwarmEvent::Behaviour(BehaviourEvent::Manifests(swarm::ManifestEvent::Message { message: Message::Response { request_id: id, response }, .. })) if id == request_id => {
+                    let ManifestResponse::Cached(cached) = response else {
+                        return Ok(HashSet::new());
+                    };
+                    return Ok(manifest.entries.iter().enumerate().filter(|(position, _)| cached.contains(*position)).map(|(_, e)| e.hash).collect());
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::Manifests(swarm::ManifestEvent::OutboundFailure { request_id: id, error, .. })) if id == request_id => {
+                    return Err(ShrLinkError::Network(format!("Offering the manifest to {} failed: {}", peer_id, error)));
+                }
+                event => self.refuse_requests(event),
+            }
         }
+    }
+    
+    // Sends only the chunks whose hash isn't in `have`, as answered to `offer_manifest`
+    pub async fn send_missing_chunks(&mut self, peer_id: PeerId, chunks: Vec<CompressedChunk>, have: &HashSet<[u8; 32]>) -> Result<TransferProgress> {
+        let (skipped, missing): (Vec<_>, Vec<_>) = chunks.into_iter().partition(|c| have.contains(&c.hash));
+        tracing::debug!("Peer {} already has {} chunks, sending {}", peer_id, skipped.len(), missing.len());
         
+        let mut progress = self.send_chunks(peer_id, missing).await?;
+        progress.chunks_skipped = skipped.len();
         Ok(progress)
     }
     
-    async fn send_chunk(&mut self, peer_id: PeerId, chunk: &CompressedChunk) -> Result<()> {
-        // This is a simplified implementation
-        // In a real P2P implementation, you would:
-        // 1. Establish a connection to the peer
-        // 2. Open a stream with the SHR protocol
-        // 3. Send the chunk data
-        // 4. Wait for acknowledgment
+    // Asks `peer_id` for chunks 0 to `expected_chunks`, which it must already be connected to
+    pub async fn receive_chunks(&mut self, peer_id: PeerId, expected_chunks: usize) -> Result<Vec<CompressedChunk>> {
+        let indexes: Vec<usize> = (0..expected_chunks).collect();
+        self.request_chunks(peer_id, &indexes).await
+    }
+    
+    // The chunks come back in the order asked for. Their hashes aren't checked here; that's
+    // left to the manifest or to decompression
+    pub async fn request_chunks(&mut self, peer_id: PeerId, indexes: &[usize]) -> Result<Vec<CompressedChunk>> {
+        let config = self.config.clone();
+        let phase = || format!("receiving {} chunks from {}", indexes.len(), peer_id);
+        let received = within_transfer_timeout(&config, phase, self.request_each(peer_id, indexes)).await;
+        self.finish_transfer(&received, |chunks| (chunks.len(), chunks.iter().map(|c| c.data.len()).sum()));
+        received
+    }
+    
+    async fn request_each(&mut self, peer_id: PeerId, indexes: &[usize]) -> Result<Vec<CompressedChunk>> {
+        tracing::info!("Requesting {} chunks from peer {}", indexes.len(), peer_id);
+        
+        let cancel = self.cancel.clone();
+        let mut received = Vec::with_capacity(indexes.len());
+        for &index in indexes {
+            let chunk = tokio::select! {
+                chunk = async {
+                    let chunk = self.request_chunk(peer_id, index).await?;
+                    self.limit_download(&chunk).await;
+                    Ok::<_, ShrLinkError>(chunk)
+                } => chunk?,
+                _ = cancel.cancelled() => {
+                    tracing::info!("Download from {} cancelled after {}/{} chunks", peer_id, received.len(), indexes.len());
+                    return Err(ShrLinkError::Cancelled);
+                }
+            };
+            tracing::debug!("Received chunk {}/{} ({} bytes)", received.len() + 1, indexes.len(), chunk.data.len());
+            self.events.emit(TransferEvent::ChunkReceived { peer_id, index: chunk.index, bytes: chunk.data.len() });
+            self.ack_chunk(peer_id, &chunk);
+            received.push(chunk);
+        }
+        self.flush_acks().await;
         
-        tracing::info!("Sending chunk {} ({} bytes) to peer {}", chunk.index, chunk.data.len(), peer_id);
+        Ok(received)
+    }
+    
+    async fn request_chunk(&mut self, peer_id: PeerId, index: usize) -> Result<CompressedChunk> {
+        let request_id = self.swarm.behaviour_mut().chunks.send_request(&peer_id, ChunkRequest::Chunk { index });
+        
+        loop {
+            match self.next_event().await {
+                SwarmEvent::Behaviour(BehaviourEvent::Chunks(swarm::Event::Message { message: Message::Response { request_id: id, response }, .. })) if id == request_id => {
+                    return chunk_response(peer_id, index, response);
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::Chunks(swarm::Event::OutboundFailure { request_id: id, error, .. })) if id == request_id => {
+                    return Err(chunk_request_failed(peer_id, index, error));
+                }
+                event => self.refuse_requests(event),
+            }
+        }
+    }
+    
+    // Listens where the config says: each of `listen_addrs`, or every interface on `port` (an
+    // ephemeral one if unset). Returns the addresses actually bound
+    pub async fn listen(&mut self) -> Result<Vec<Multiaddr>> {
+        let addrs = listen_addrs(&self.config)?;
+        let mut bound = Vec::with_capacity(addrs.len());
+        for addr in addrs {
+            bound.push(self.listen_on(addr).await?);
+        }
+        // A wildcard bind reports each interface's address in turn, so the rest are taken in
+        // before anyone asks which addresses to advertise
+        while let Some(event) = self.swarm.select_next_some().now_or_never() {
+            if let Some(event) = self.take_discovery(event) {
+                self.refuse_requests(event);
+            }
+        }
+        Ok(bound)
+    }
+    
+    // Starts listening and returns the address actually bound, port 0 resolved
+    pub async fn listen_on(&mut self, addr: Multiaddr) -> Result<Multiaddr> {
+        let listener = self.swarm.listen_on(addr.clone()).map_err(|e| swarm::listen_error(&addr, e))?;
         
-        // Simulate network delay
-        sleep(Duration::from_millis(10)).await;
+        loop {
+            match self.next_event().await {
+                SwarmEvent::NewListenAddr { listener_id, address } if listener_id == listener => {
+                    self.listener_ids.push(listener);
+                    return Ok(address);
+                }
+                SwarmEvent::ListenerClosed { listener_id, reason, .. } if listener_id == listener => {
+                    let reason = reason.err().map(|e| e.to_string()).unwrap_or_else(|| "closed".to_string());
+                    return Err(ShrLinkError::P2P(format!("Failed to listen on {}: {}", addr, reason)));
+                }
+                _ => {}
+            }
+        }
+    }
+    
+    // Makes `peer_id` dialable: at the addresses given for it if there are any, otherwise once
+    // mDNS has seen it on the local network
+    pub async fn reach_peer(&mut self, peer_id: PeerId, addrs: &[Multiaddr]) -> Result<()> {
+        if !addrs.is_empty() {
+            for addr in addrs {
+                self.swarm.add_peer_address(peer_id, addr.clone());
+            }
+            return Ok(());
+        }
+        if !self.config.enable_mdns {
+            return Err(ShrLinkError::P2P(format!(
+                "Peer {} can't be found with mDNS disabled and no address for it in the URL",
+                peer_id
+            )));
+        }
         
+        tracing::info!("Looking for peer {} on the local network", peer_id);
+        while self.discovered.get(&peer_id).is_none() {
+            let event = self.swarm.select_next_some().await;
+            self.take_discovery(event);
+        }
         Ok(())
     }
     
-    pub async fn receive_chunks(&mut self, expected_chunks: usize) -> Result<Vec<CompressedChunk>> {
-        let received_chunks = Vec::new();
+    // As `reach_peer`, but addresses given are dialed there and then, in turn, until one answers
+    // as `peer_id`, so what went wrong is known before anything is asked. One that authenticates
+    // as anyone else is refused rather than tried elsewhere
+    pub async fn connect_to(&mut self, peer_id: PeerId, addrs: &[Multiaddr]) -> Result<()> {
+        let mut failure = None;
+        for addr in addrs {
+            let addr = addr.clone().with_p2p(peer_id).map_err(|addr| {
+                ShrLinkError::InvalidInput(format!("Address {} names a peer other than {}", addr, peer_id))
+            })?;
+            self.swarm.add_peer_address(peer_id, addr.clone());
+            match self.connect_to_peer(addr).await {
+                Ok(_) => return Ok(()),
+                Err(ShrLinkError::Dial(e)) if matches!(*e, DialError::PeerIdMismatch { .. }) => return Err(ShrLinkError::Dial(e)),
+                Err(e) => failure = Some(e),
+            }
+        }
+        match failure {
+            Some(e) => Err(e),
+            None => self.reach_peer(peer_id, addrs).await,
+        }
+    }
+    
+    // Asks `peer_id` for the chunk manifest of the file a shr:// URL names
+    pub async fn request_manifest(&mut self, peer_id: PeerId, file_hash: &str) -> Result<ChunkManifest> {
+        let file_hash: [u8; 32] = hex::decode(file_hash)
+            .ok()
+            .and_then(|hash| hash.try_into().ok())
+            .ok_or_else(|| ShrLinkError::InvalidInput(format!("Invalid file hash: {}", file_hash)))?;
+        self.handshake(peer_id).await?;
+        tracing::info!("Requesting manifest for {} from peer {}", hex::encode(file_hash), peer_id);
+        
+        let request_id = self.swarm.behaviour_mut().manifests.send_request(&peer_id, ManifestRequest::Fetch { file_hash });
+        loop {
+            match self.next_event().await {
+                SwarmEvent::Behaviour(BehaviourEvent::Manifests(swarm::ManifestEvent::Message { message: Message::Response { request_id: id, response }, .. })) if id == request_id => {
+                    return match response {
+                        ManifestResponse::Manifest(manifest) if manifest.file_hash == file_hash => {
+                            self.fetched_manifests.insert((peer_id, file_hash));
+                            Ok(manifest)
+                        }
+                        ManifestResponse::Manifest(_) => Err(ShrLinkError::P2P(format!("Peer {} answered with the manifest of another file", peer_id))),
+                        ManifestResponse::NotOffered | ManifestResponse::Cached(_) => Err(ShrLinkError::P2P(format!("Peer {} has no file {}", peer_id, hex::encode(file_hash)))),
+                    };
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::Manifests(swarm::ManifestEvent::OutboundFailure { request_id: id, error, .. })) if id == request_id => {
+                    return Err(ShrLinkError::Network(format!("Requesting the manifest from {} failed: {}", peer_id, error)));
+                }
+                event => self.refuse_requests(event),
+            }
+        }
+    }
+    
+    // Tells `peer_id` what this client can take and checks what its chunks need, before anything
+    // is asked of it. A sender from before the handshake is taken to speak 1.0
+    pub async fn handshake(&mut self, peer_id: PeerId) -> Result<Version> {
+        let request_id = self.swarm.behaviour_mut().hello.send_request(&peer_id, self.capabilities.clone());
+        loop {
+            match self.next_event().await {
+                SwarmEvent::Behaviour(BehaviourEvent::Hello(swarm::HelloEvent::Message { message: Message::Response { request_id: id, response }, .. })) if id == request_id => {
+                    let version = match response {
+                        HelloResponse::Accepted(needs) => capabilities::negotiate(&needs, &self.capabilities)?,
+                        HelloResponse::Incompatible(reason) => return Err(reason.into()),
+                    };
+                    tracing::debug!("Agreed on chunk protocol {} with {}", version, peer_id);
+                    return Ok(version);
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::Hello(swarm::HelloEvent::OutboundFailure { request_id: id, error: request_response::OutboundFailure::UnsupportedProtocols, .. })) if id == request_id => {
+                    tracing::debug!("Peer {} predates the handshake, so taking it to speak {}", peer_id, capabilities::LEGACY_VERSION);
+                    return Ok(capabilities::LEGACY_VERSION);
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::Hello(swarm::HelloEvent::OutboundFailure { request_id: id, error, .. })) if id == request_id => {
+                    return Err(ShrLinkError::Network(format!("Handshake with {} failed: {}", peer_id, error)));
+                }
+                event => self.refuse_requests(event),
+            }
+        }
+    }
+    
+    // Holds `code` for up to `ttl`, for a receiver to pair with and be handed `offer`, the shr://
+    // URL, once it has shown it has the same code. Whoever that was is then the only peer this
+    // client serves, and is returned. The code is good for one attempt, so a receiver with the
+    // wrong one makes pairing fail on both sides
+    pub async fn await_pairing(&mut self, code: &PairingCode, offer: &str, ttl: Duration) -> Result<PeerId> {
+        let deadline = sleep(ttl);
+        tokio::pin!(deadline);
+        // No one is being served yet, so a shutdown has nothing to wait for either
+        let (cancel, shutdown) = (self.cancel.clone(), self.shutdown.clone());
+        // Who started with this code's number, and the keys they came to
+        let mut started: Option<(PeerId, SessionKeys)> = None;
+        
+        loop {
+            let event = tokio::select! {
+                event = self.next_event() => event,
+                _ = &mut deadline => {
+                    return Err(ShrLinkError::Timeout(format!("No one paired with code {}-… within p2p.pairing_ttl_ms ({:?})", code.nameplate(), ttl)));
+                }
+                _ = cancel.cancelled() => return Err(ShrLinkError::Cancelled),
+                _ = shutdown.cancelled() => return Err(ShrLinkError::Cancelled),
+            };
+            let SwarmEvent::Behaviour(BehaviourEvent::Pairing(swarm::PairEvent::Message { peer, message: Message::Request { request_id, request, channel }, .. })) = event else {
+                self.refuse_requests(event);
+                continue;
+            };
+            
+            match request {
+                _ if !self.access.permits(&peer) => {
+                    tracing::warn!("Refused to pair with {}, which isn't allowed to receive", peer);
+                    let _ = self.swarm.behaviour_mut().pairing.send_response(channel, PairResponse::Unknown);
+                }
+                PairRequest::Start { nameplate, message } if nameplate == code.nameplate() && started.is_none() => {
+                    let spake = Spake2::start(Side::Sender, code);
+                    let ours = spake.message();
+                    match spake.finish(&message, &self.local_peer_id, &peer) {
+                        Ok(keys) => {
+                            let response = PairResponse::Started { message: ours, confirmation: keys.sender_confirmation };
+                            let _ = self.swarm.behaviour_mut().pairing.send_response(channel, response);
+                            started = Some((peer, keys));
+                        }
+                        Err(e) => {
+                            self.respond_to_pairing(request_id, channel, PairResponse::Refused).await;
+                            return Err(ShrLinkError::P2P(format!("Pairing with {} failed, and the code is used up: {}", peer, e)));
+                        }
+                    }
+                }
+                PairRequest::Confirm { confirmation } if started.as_ref().is_some_and(|(p, _)| *p == peer) => {
+                    let (_, keys) = started.take().expect("checked just above");
+                    if !pairing::confirms(&keys.receiver_confirmation, &confirmation) {
+                        self.respond_to_pairing(request_id, channel, PairResponse::Refused).await;
+                        return Err(ShrLinkError::P2P(format!("{} tried a wrong pairing code, and the code is used up", peer)));
+                    }
+                    let sealed = crate::crypto::seal(offer.as_bytes(), &keys.offer)?;
+                    self.respond_to_pairing(request_id, channel, PairResponse::Offer { sealed }).await;
+                    self.access.allow(peer);
+                    tracing::info!("Paired with {}", peer);
+                    return Ok(peer);
+                }
+                _ => {
+                    let _ = self.swarm.behaviour_mut().pairing.send_response(channel, PairResponse::Unknown);
+                }
+            }
+        }
+    }
+    
+    // The last answer of a pairing goes out before the caller moves on, or gives up
+    async fn respond_to_pairing(&mut self, request_id: request_response::InboundRequestId, channel: ResponseChannel<PairResponse>, response: PairResponse) {
+        if self.swarm.behaviour_mut().pairing.send_response(channel, response).is_err() {
+            return;
+        }
+        let _ = tokio::time::timeout(ACK_TIMEOUT, async {
+            loop {
+                match self.next_event().await {
+                    SwarmEvent::Behaviour(BehaviourEvent::Pairing(swarm::PairEvent::ResponseSent { request_id: id, .. } | swarm::PairEvent::InboundFailure { request_id: id, .. })) if id == request_id => return,
+                    event => self.refuse_requests(event),
+                }
+            }
+        })
+        .await;
+    }
+    
+    // Pairs with `peer_id`, if it's holding a code with the same number, for the shr:// URL it
+    // hands over. None if it isn't; an error if it is but the rest of the code didn't match
+    pub async fn pair(&mut self, peer_id: PeerId, code: &PairingCode) -> Result<Option<String>> {
+        let spake = Spake2::start(Side::Receiver, code);
+        let start = PairRequest::Start { nameplate: code.nameplate(), message: spake.message() };
+        let (message, confirmation) = match self.pair_request(peer_id, start).await {
+            Ok(PairResponse::Started { message, confirmation }) => (message, confirmation),
+            Ok(_) => return Ok(None),
+            Err(e) => {
+                tracing::debug!("{} didn't take up pairing: {}", peer_id, e);
+                return Ok(None);
+            }
+        };
+        
+        let wrong_code = || ShrLinkError::P2P(format!("Pairing with {} failed: the code didn't match, and is now used up", peer_id));
+        let keys = spake.finish(&message, &peer_id, &self.local_peer_id)?;
+        let sender_confirmed = pairing::confirms(&keys.sender_confirmation, &confirmation);
+        // Sent either way, so a sender holding some other code finds out too
+        let reply = self.pair_request(peer_id, PairRequest::Confirm { confirmation: keys.receiver_confirmation }).await?;
+        match reply {
+            PairResponse::Offer { sealed } if sender_confirmed => {
+                let offer = crate::crypto::unseal(&sealed, &keys.offer).map_err(|_| wrong_code())?;
+                let url = String::from_utf8(offer).map_err(|_| ShrLinkError::P2P(format!("{} handed over something that isn't a URL", peer_id)))?;
+                Ok(Some(url))
+            }
+            _ => Err(wrong_code()),
+        }
+    }
+    
+    async fn pair_request(&mut self, peer_id: PeerId, request: PairRequest) -> Result<PairResponse> {
+        let request_id = self.swarm.behaviour_mut().pairing.send_request(&peer_id, request);
+        loop {
+            match self.next_event().await {
+                SwarmEvent::Behaviour(BehaviourEvent::Pairing(swarm::PairEvent::Message { message: Message::Response { request_id: id, response }, .. })) if id == request_id => {
+                    return Ok(response);
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::Pairing(swarm::PairEvent::OutboundFailure { request_id: id, error, .. })) if id == request_id => {
+                    return Err(ShrLinkError::Network(format!("Pairing with {} failed: {}", peer_id, error)));
+                }
+                event => self.refuse_requests(event),
+            }
+        }
+    }
+    
+    // Every chunk the manifest lists, in its order, each checked against its entry and its own
+    // hash as it arrives and then acknowledged. Up to `max_inflight_chunks` requests are out at
+    // once and answers may come back in any order. A chunk that fails either way is asked for
+    // again after a backoff, ahead of those not yet asked for, up to `chunk_retries` more times
+    // before the whole download gives up
+    pub async fn fetch_chunks(&mut self, peer_id: PeerId, manifest: &ChunkManifest, mut on_chunk: impl FnMut(&CompressedChunk)) -> Result<Vec<CompressedChunk>> {
+        self.resume_chunks(peer_id, manifest, Vec::new(), |_, chunk| on_chunk(chunk)).await
+    }
+    
+    // As `fetch_chunks`, but only asking for the positions `have` has nothing at, as after an
+    // interrupted receive. What it does have goes through the same checks first, and is asked
+    // for after all if it fails them. `on_chunk` is told each fetched chunk's position
+    pub async fn resume_chunks(&mut self, peer_id: PeerId, manifest: &ChunkManifest, have: Vec<Option<CompressedChunk>>, on_chunk: impl FnMut(usize, &CompressedChunk)) -> Result<Vec<CompressedChunk>> {
+        self.resume_from_peers(&[peer_id], manifest, have, on_chunk).await
+    }
+    
+    // As `resume_chunks`, from every one of `peers` at once. Each is first asked which chunks it
+    // has, and the ones fewest peers have are asked for first, so a peer that drops out leaves as
+    // little as possible behind it. Each peer has its own window of `max_inflight_chunks`. Once
+    // nothing is left to ask for, a peer with room asks again for a chunk still on its way from
+    // another, and whichever copy comes second is dropped. A signed manifest has to have been
+    // signed by the first peer, the one the URL names
+    pub async fn resume_from_peers(&mut self, peers: &[PeerId], manifest: &ChunkManifest, have: Vec<Option<CompressedChunk>>, on_chunk: impl FnMut(usize, &CompressedChunk)) -> Result<Vec<CompressedChunk>> {
+        if peers.is_empty() {
+            return Err(ShrLinkError::InvalidInput("No peers to fetch chunks from".to_string()));
+        }
+        let config = self.config.clone();
+        let names = peers.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ");
+        let phase = || format!("receiving {} from {}", hex::encode(manifest.file_hash), names);
+        let fetched = within_transfer_timeout(&config, phase, self.fetch_missing(peers, manifest, have, on_chunk)).await;
+        self.finish_transfer(&fetched, |chunks| (chunks.len(), chunks.iter().map(|c| c.data.len()).sum()));
+        fetched
+    }
+    
+    async fn fetch_missing(&mut self, peers: &[PeerId], manifest: &ChunkManifest, have: Vec<Option<CompressedChunk>>, mut on_chunk: impl FnMut(usize, &CompressedChunk)) -> Result<Vec<CompressedChunk>> {
+        let total = manifest.entries.len();
+        let window = self.config.max_inflight_chunks.max(1);
+        
+        // A signed manifest has to have been signed by the peer the URL names
+        let signer = manifest.is_signed().then_some(peers[0]);
+        let verifier = ParallelCompressor::default();
+        let mut scratch = Vec::new();
+        let cancel = self.cancel.clone();
         
-        // This is a simplified implementation
-        // In a real P2P implementation, you would:
-        // 1. Listen for incoming connections
-        // 2. Accept streams with the SHR protocol
-        // 3. Receive and validate chunks
-        // 4. Send acknowledgments
+        // Everything is kept by its position in the manifest, whatever order it arrives in
+        let mut received: Vec<Option<CompressedChunk>> = (0..total).map(|_| None).collect();
+        for (position, chunk) in have.into_iter().enumerate().take(total) {
+            let Some(chunk) = chunk else { continue };
+            let checked = manifest.verify_chunk(&chunk, signer)
+                .map_err(|rejection| rejection.reason)
+                .and_then(|()| verifier.decompress_chunk_into(&chunk, &mut scratch).map_err(|e| e.to_string()));
+            match checked {
+                Ok(_) if chunk.index == manifest.entries[position].index => received[position] = Some(chunk),
+                Ok(_) => tracing::warn!("Dropping chunk {} kept at the position of chunk {}", chunk.index, manifest.entries[position].index),
+                Err(reason) => tracing::warn!("Dropping kept chunk {}: {}", chunk.index, reason),
+            }
+        }
+        let mut done = received.iter().flatten().count();
+        
+        // The positions each peer has. A lone peer has to have them all, so it isn't asked
+        let mut sources: Vec<HashSet<usize>> = if peers.len() > 1 {
+            self.ask_what_peers_have(peers, manifest).await?
+        } else {
+            vec![(0..total).collect()]
+        };
+        let mut wanted: Vec<usize> = (0..total).filter(|&position| received[position].is_none()).collect();
+        if let Some(&position) = wanted.iter().find(|position| !sources.iter().any(|s| s.contains(position))) {
+            return Err(ShrLinkError::P2P(format!("None of {} peers has chunk {}", peers.len(), manifest.entries[position].index)));
+        }
+        // Rarest first; a stable sort keeps manifest order among equals
+        wanted.sort_by_key(|position| sources.iter().filter(|s| s.contains(position)).count());
+        let mut wanted = VecDeque::from(wanted);
+        tracing::info!("Fetching {} of {} chunks from {} peers, up to {} at a time from each", wanted.len(), total, peers.len(), window);
         
-        tracing::info!("Waiting to receive {} chunks", expected_chunks);
+        let mut attempts = vec![0u32; total];
+        // Each request's peer, by its place in `peers`, and position
+        let mut in_flight = HashMap::new();
+        let mut loads = vec![0usize; peers.len()];
+        // Failed positions by when they're due to be asked for again, then in the order they fell due
+        let mut retries = BinaryHeap::new();
+        let mut due = VecDeque::new();
+        // Positions asked of a second peer in the endgame
+        let mut doubled = HashSet::new();
+        while done < total {
+            while let Some(Reverse((at, _))) = retries.peek() {
+                if *at > tokio::time::Instant::now() {
+                    break;
+                }
+                if let Some(Reverse((_, position))) = retries.pop() {
+                    due.push_back(position);
+                }
+            }
+            
+            // Requests to a peer that isn't connected yet each dial it, and a dial refused for one
+            // fails them all, so each peer's window only opens once the first request's dial is through
+            let limits: Vec<usize> = peers.iter().map(|peer| if self.swarm.is_connected(peer) { window } else { 1 }).collect();
+            for (p, &peer_id) in peers.iter().enumerate() {
+                while loads[p] < limits[p] {
+                    let has = |position: &usize| sources[p].contains(position);
+                    let position = take_first(&mut due, has).or_else(|| take_first(&mut wanted, has));
+                    let position = match position {
+                        Some(position) => {
+                            attempts[position] += 1;
+                            position
+                        }
+                        None if peers.len() > 1 && wanted.is_empty() && due.is_empty() => {
+                            let Some(position) = in_flight
+                                .values()
+                                .find(|&&(other, position): &&(usize, usize)| other != p && has(&position) && !doubled.contains(&position))
+                                .map(|&(_, position)| position)
+                            else {
+                                break;
+                            };
+                            doubled.insert(position);
+                            position
+                        }
+                        None => break,
+                    };
+                    let index = manifest.entries[position].index;
+                    let request_id = self.swarm.behaviour_mut().chunks.send_request(&peer_id, ChunkRequest::Chunk { index });
+                    in_flight.insert(request_id, (p, position));
+                    loads[p] += 1;
+                }
+            }
+            
+            // Only worth waking for when there's room to send it
+            let room = (0..peers.len()).any(|p| loads[p] < limits[p]);
+            let next_retry = retries.peek().map(|Reverse((at, _))| *at).filter(|_| room);
+            let event = tokio::select! {
+                event = self.next_event() => event,
+                _ = tokio::time::sleep_until(next_retry.unwrap_or_else(tokio::time::Instant::now)), if next_retry.is_some() => continue,
+                _ = cancel.cancelled() => {
+                    tracing::info!("Download from {} cancelled after {}/{} chunks", peers[0], done, total);
+                    return Err(ShrLinkError::Cancelled);
+                }
+            };
+            let ((p, position), outcome) = match event {
+                SwarmEvent::Behaviour(BehaviourEvent::Chunks(swarm::Event::Message { message: Message::Response { request_id, response }, .. })) => {
+                    let Some((p, position)) = in_flight.remove(&request_id) else { continue };
+                    ((p, position), chunk_response(peers[p], manifest.entries[position].index, response))
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::Chunks(swarm::Event::OutboundFailure { request_id, error, .. })) => {
+                    let Some((p, position)) = in_flight.remove(&request_id) else { continue };
+                    ((p, position), Err(chunk_request_failed(peers[p], manifest.entries[position].index, error)))
+                }
+                event => {
+                    self.refuse_requests(event);
+                    continue;
+                }
+            };
+            loads[p] -= 1;
+            let peer_id = peers[p];
+            
+            if let Ok(chunk) = &outcome {
+                tokio::select! {
+                    _ = self.limit_download(chunk) => {}
+                    _ = cancel.cancelled() => return Err(ShrLinkError::Cancelled),
+                }
+            }
+            
+            let entry = &manifest.entries[position];
+            // The other copy of a doubled chunk got here first
+            if let Some(kept) = &received[position] {
+                if let Ok(chunk) = &outcome {
+                    if chunk.hash == kept.hash {
+                        tracing::debug!("Dropping chunk {} from {}, already received", entry.index, peer_id);
+                        self.ack_chunk(peer_id, chunk);
+                    }
+                }
+                continue;
+            }
+            let (failure, timed_out) = match outcome {
+                Ok(chunk) => {
+                    let rejection = match manifest.verify_chunk(&chunk, signer) {
+                        Ok(()) => match verifier.decompress_chunk_into(&chunk, &mut scratch) {
+                            Ok(_) => {
+                                on_chunk(position, &chunk);
+                                self.events.emit(TransferEvent::ChunkReceived { peer_id, index: chunk.index, bytes: chunk.data.len() });
+                                self.ack_chunk(peer_id, &chunk);
+                                received[position] = Some(chunk);
+                                done += 1;
+                                continue;
+                            }
+                            Err(e) => ChunkRejection { index: entry.index, blame: Blame::Peer, reason: e.to_string() },
+                        },
+                        Err(rejection) => rejection,
+                    };
+                    self.record_rejection(peer_id, &rejection);
+                    (rejection.reason, false)
+                }
+                Err(e) => (e.to_string(), matches!(e, ShrLinkError::Timeout(_))),
+            };
+            
+            tracing::debug!("Chunk {} from {} failed on attempt {}: {}", entry.index, peer_id, attempts[position], failure);
+            // Whoever else has it is asked next time
+            if sources.iter().enumerate().any(|(other, s)| other != p && s.contains(&position)) {
+                sources[p].remove(&position);
+            }
+            // The other copy is still on its way
+            if in_flight.values().any(|&(_, other)| other == position) {
+                continue;
+            }
+            if attempts[position] > self.config.chunk_retries {
+                if timed_out {
+                    return Err(ShrLinkError::Timeout(format!(
+                        "chunk {} from {}, unanswered on the last of {} attempts",
+                        entry.index, peer_id, attempts[position]
+                    )));
+                }
+                return Err(ShrLinkError::P2P(format!(
+                    "Chunk {} from {} failed after {} attempts: {}",
+                    entry.index, peer_id, attempts[position], failure
+                )));
+            }
+            doubled.remove(&position);
+            self.events.emit(TransferEvent::Retry { peer_id, index: entry.index });
+            retries.push(Reverse((tokio::time::Instant::now() + retry_backoff(attempts[position]), position)));
+        }
+        self.flush_acks().await;
         
-        // For demo purposes, return empty chunks
-        // In a real implementation, this would receive actual data
+        Ok(received.into_iter().flatten().collect())
+    }
+    
+    // Which positions of the manifest each of `peers` has. A peer that can't say, from before
+    // HAVE requests or out of reach for now, is taken to have them all, and is found out if not
+    async fn ask_what_peers_have(&mut self, peers: &[PeerId], manifest: &ChunkManifest) -> Result<Vec<HashSet<usize>>> {
+        let all: HashSet<usize> = (0..manifest.entries.len()).collect();
+        let mut sources = vec![all.clone(); peers.len()];
+        let mut asked: HashMap<_, _> = peers
+            .iter()
+            .enumerate()
+            .map(|(p, peer_id)| (self.swarm.behaviour_mut().chunks.send_request(peer_id, ChunkRequest::Have), p))
+            .collect();
+        let cancel = self.cancel.clone();
+        while !asked.is_empty() {
+            let event = tokio::select! {
+                event = self.next_event() => event,
+                _ = cancel.cancelled() => return Err(ShrLinkError::Cancelled),
+            };
+            match event {
+                SwarmEvent::Behaviour(BehaviourEvent::Chunks(swarm::Event::Message { message: Message::Response { request_id, response }, .. })) => {
+                    let Some(p) = asked.remove(&request_id) else { continue };
+                    match response {
+                        ChunkResponse::Have(have) => {
+                            sources[p] = (0..manifest.entries.len()).filter(|&position| have.contains(manifest.entries[position].index)).collect();
+                            tracing::debug!("Peer {} has {} of {} chunks", peers[p], sources[p].len(), manifest.entries.len());
+                        }
+                        _ => tracing::debug!("Peer {} didn't say which chunks it has", peers[p]),
+                    }
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::Chunks(swarm::Event::OutboundFailure { request_id, error, .. })) => {
+                    let Some(p) = asked.remove(&request_id) else { continue };
+                    tracing::debug!("Peer {} didn't say which chunks it has: {}", peers[p], error);
+                }
+                event => self.refuse_requests(event),
+            }
+        }
+        Ok(sources)
+    }
+    
+    // Holds up whatever is received next until `chunk` fits within `max_download_bps`
+    async fn limit_download(&self, chunk: &CompressedChunk) {
+        if let Some(limit) = &self.download_limit {
+            limit.acquire(wire::encoded_len(chunk) as u64).await;
+        }
+    }
+    
+    // Tells the sender `chunk` arrived; nothing waits for the answer
+    fn ack_chunk(&mut self, peer_id: PeerId, chunk: &CompressedChunk) {
+        let ack = ChunkRequest::Ack { index: chunk.index, hash: chunk.hash };
+        let request_id = self.swarm.behaviour_mut().chunks.send_request(&peer_id, ack);
+        self.acks.insert(request_id);
+    }
+    
+    // A sender only counts a chunk once it's acknowledged, so the last few are seen out before
+    // a receive returns, as far as `ACK_TIMEOUT` allows
+    async fn flush_acks(&mut self) {
+        let flushed = tokio::time::timeout(ACK_TIMEOUT, async {
+            // Straight from the swarm: the answers themselves are taken in along with discovery
+            while !self.acks.is_empty() {
+                let event = self.swarm.select_next_some().await;
+                if let Some(event) = self.take_discovery(event) {
+                    self.refuse_requests(event);
+                }
+            }
+        })
+        .await;
+        if flushed.is_err() {
+            tracing::debug!("{} acknowledgments still unanswered", self.acks.len());
+            self.acks.clear();
+        }
+    }
+    
+    pub fn issue_receipt(&self, file_hash: [u8; 32], bytes: u64, duration: Duration, receiver_name: Option<String>) -> Result<SignedReceipt> {
+        TransferReceipt {
+            file_hash,
+            bytes,
+            duration,
+            receiver_peer_id: self.local_peer_id.to_string(),
+            receiver_name,
+        }
+        .sign(&self.keypair)
+    }
+    
+    // Receipts are a courtesy: the sender may already be gone, so this never fails the receive
+    pub async fn deliver_receipt(&mut self, peer_id: PeerId, receipt: &SignedReceipt) -> bool {
+        match tokio::time::timeout(RECEIPT_TIMEOUT, self.send_receipt(peer_id, receipt)).await {
+            Ok(Ok(())) => true,
+            Ok(Err(e)) => {
+                tracing::debug!("Failed to deliver receipt to {}: {}", peer_id, e);
+                false
+            }
+            Err(_) => {
+                tracing::debug!("Timed out delivering receipt to {}", peer_id);
+                false
+            }
+        }
+    }
+    
+    async fn send_receipt(&mut self, peer_id: PeerId, receipt: &SignedReceipt) -> Result<()> {
+        tracing::info!("Sending receipt for {} bytes to peer {}", receipt.receipt.bytes, peer_id);
         
-        Ok(received_chunks)
+        let request_id = self.swarm.behaviour_mut().receipts.send_request(&peer_id, receipt.clone());
+        loop {
+            match self.next_event().await {
+                SwarmEvent::Behaviour(BehaviourEvent::Receipts(swarm::ReceiptEvent::Message { message: Message::Response { request_id: id, response }, .. })) if id == request_id => {
+                    return match response {
+                        ReceiptResponse::Recorded => Ok(()),
+                        ReceiptResponse::Refused => Err(ShrLinkError::P2P(format!("Peer {} refused the receipt", peer_id))),
+                    };
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::Receipts(swarm::ReceiptEvent::OutboundFailure { request_id: id, error: request_response::OutboundFailure::UnsupportedProtocols, .. })) if id == request_id => {
+                    return Err(ShrLinkError::P2P(format!("Peer {} predates receipts", peer_id)));
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::Receipts(swarm::ReceiptEvent::OutboundFailure { request_id: id, error, .. })) if id == request_id => {
+                    return Err(ShrLinkError::Network(format!("Sending the receipt to {} failed: {}", peer_id, error)));
+                }
+                event => self.refuse_requests(event),
+            }
+        }
     }
     
     pub fn local_peer_id(&self) -> PeerId {
         self.local_peer_id
     }
     
-    pub fn listeners(&self) -> Vec<Multiaddr> {
-        // Return empty for now - in a real implementation,
-        // this would return the actual listening addresses
-        vec![]
+    pub fn config(&self) -> &P2PConfig {
+        &self.config
+    }
+    
+    pub fn serve_stats(&self) -> Vec<(PeerId, PeerServeStats)> {
+        self.serve_throttle.peer_stats()
+    }
+    
+    // For watching each peer's rate while a serve has the client
+    pub fn serve_throttle(&self) -> Arc<ServeThrottle> {
+        Arc::clone(&self.serve_throttle)
+    }
+    
+    pub fn reputation(&self) -> &ReputationStore {
+        &self.reputation
+    }
+    
+    // Only peer-attributable failures count; forged content is not the relaying peer's fault
+    pub fn record_rejection(&mut self, peer_id: PeerId, rejection: &ChunkRejection) {
+        if rejection.blame.strikes_peer() {
+            tracing::debug!("Strike against {} for chunk {}: {}", peer_id, rejection.index, rejection.reason);
+            self.reputation.record_strike(&peer_id);
+            self.persist_reputation();
+        }
+    }
+    
+    // A receiver that had every chunk and sent a receipt too is one success, not two
+    pub fn record_receipt(&mut self, tracker: &mut DownloadTracker, receipt: &SignedReceipt) -> Result<PeerId> {
+        let counted = tracker.completed_downloads();
+        let peer_id = tracker.record_receipt(receipt)?;
+        if tracker.completed_downloads() > counted {
+            self.record_transfer(peer_id);
+        }
+        Ok(peer_id)
+    }
+    
+    pub fn record_served(&mut self, tracker: &mut DownloadTracker, peer_id: PeerId) {
+        let counted = tracker.completed_downloads();
+        tracker.record_served(peer_id);
+        if tracker.completed_downloads() > counted {
+            self.record_transfer(peer_id);
+        }
+    }
+    
+    pub fn record_transfer(&mut self, peer_id: PeerId) {
+        self.reputation.record_success(&peer_id);
+        self.persist_reputation();
+    }
+    
+    pub fn stats(&self) -> &StatsStore {
+        &self.stats
+    }
+    
+    fn finish_transfer<T>(&mut self, result: &Result<T>, summary: impl FnOnce(&T) -> (usize, usize)) {
+        self.events.finish(result, summary);
+        self.stats.add(&self.events.take_counted());
+        if let Err(e) = self.stats.save() {
+            tracing::warn!("Failed to save transfer statistics: {}", e);
+        }
+    }
+    
+    fn persist_reputation(&self) {
+        if let Err(e) = self.reputation.save() {
+            tracing::warn!("Failed to save peer reputation: {}", e);
+        }
     }
     
-    pub async fn discover_peers(&mut self) -> Result<Vec<PeerId>> {
-        // This is a simplified implementation
-        // In a real P2P implementation, you would:
-        // 1. Use DHT to discover peers
-        // 2. Use mDNS for local discovery
-        // 3. Use bootstrap nodes
+    // Has the relays check each address this client listens on (AutoNAT) and tries for a
+    // reservation on one of them, both within `timeout`. A reservation taken here is given up
+    // again and never advertised, and UPnP is reported as `await_port_mapping` left it
+    pub async fn probe_reachability(&mut self, timeout: Duration) -> ReachabilityReport {
+        let start = std::time::Instant::now();
+        let relays = swarm::relay_nodes(&self.config.relays);
+        let upnp = Check::run(timeout, async { self.port_mapping.check() }).await;
+        let (autonat, relay) = match relays.is_empty() {
+            true => (Check::failed("no relays configured to ask", Duration::ZERO), Check::failed("no relay addresses configured", Duration::ZERO)),
+            false => self.probe_relays(&relays, timeout).await,
+        };
         
-        tracing::info!("Discovering peers...");
+        let report = ReachabilityReport::aggregate(autonat, upnp, relay, self.addresses.observed().to_vec(), start.elapsed());
+        match self.config.enable_holepunching {
+            true => report,
+            false => report.without_holepunching(),
+        }
+    }
+    
+    async fn probe_relays(&mut self, relays: &[(PeerId, Multiaddr)], timeout: Duration) -> (Check<NatStatus>, Check<Multiaddr>) {
+        let start = std::time::Instant::now();
+        let candidates: Vec<Multiaddr> = self.addresses.listeners().into_iter().filter(|a| !is_circuit(a)).collect();
+        let mut probing = false;
+        if let Some(autonat) = self.swarm.behaviour_mut().autonat.as_mut() {
+            for addr in &candidates {
+                autonat.probe_address(addr.clone());
+                probing = true;
+            }
+        }
+        let mut probed = (!probing).then(|| start.elapsed());
+        
+        // A reservation already held says as much; otherwise the relays are tried in turn
+        let mut untried = relays.iter().map(|(_, addr)| addr.clone().with(Protocol::P2pCircuit)).collect::<VecDeque<_>>();
+        let mut reserved = self.addresses.listeners().into_iter().find(is_circuit).map(|addr| (Ok(addr), start.elapsed()));
+        let mut reserving = None;
+        let mut last_error = "no relay gave a reservation".to_string();
+        if reserved.is_none() {
+            reserving = self.reserve_next(&mut untried, &mut last_error);
+            if reserving.is_none() {
+                reserved = Some((Err(last_error.clone()), start.elapsed()));
+            }
+        }
         
-        // Simulate discovery delay
-        sleep(Duration::from_millis(1000)).await;
+        let _ = tokio::time::timeout(timeout, async {
+            while probed.is_none() || reserved.is_none() {
+                // No relay could be reached for a reservation, so none is there to answer either
+                let unreachable = reserved.as_ref().is_some_and(|(outcome, _)| outcome.is_err())
+                    && !relays.iter().any(|(peer_id, _)| self.swarm.is_connected(peer_id));
+                if probed.is_none() && unreachable {
+                    probed = Some(start.elapsed());
+                    continue;
+                }
+                let event = self.swarm.select_next_some().await;
+                match &event {
+                    // A round of probing asks about every candidate at once
+                    SwarmEvent::Behaviour(BehaviourEvent::Autonat(autonat::Event::OutboundProbe(
+                        autonat::OutboundProbeEvent::Response { .. } | autonat::OutboundProbeEvent::Error { .. },
+                    ))) => probed = Some(start.elapsed()),
+                    // Kept from the address book, so it's never shared
+                    SwarmEvent::NewListenAddr { listener_id, address } if reserving.as_ref() == Some(listener_id) => {
+                        self.swarm.remove_listener(*listener_id);
+                        reserving = None;
+                        reserved = Some((Ok(address.clone()), start.elapsed()));
+                        continue;
+                    }
+                    SwarmEvent::ListenerClosed { listener_id, reason, .. } if reserving.as_ref() == Some(listener_id) => {
+                        if let Err(e) = reason {
+                            last_error = e.to_string();
+                        }
+                        reserving = self.reserve_next(&mut untried, &mut last_error);
+                        if reserving.is_none() {
+                            reserved = Some((Err(last_error.clone()), start.elapsed()));
+                        }
+                        continue;
+                    }
+                    _ => {}
+                }
+                if let Some(event) = self.take_discovery(event) {
+                    self.refuse_requests(event);
+                }
+            }
+        })
+        .await;
+        if let Some(listener) = reserving {
+            self.swarm.remove_listener(listener);
+        }
         
-        // For demo purposes, return no peers
-        // In a real implementation, this would return discovered peers
-        Ok(vec![])
+        // What the router maps counts as confirmed too, but it isn't what AutoNAT found
+        let mapped = match &self.port_mapping {
+            PortMapping::Mapped(addrs) => addrs.clone(),
+            _ => Vec::new(),
+        };
+        let confirmed: Vec<Multiaddr> = self.addresses.confirmed().iter().filter(|a| !mapped.contains(a)).cloned().collect();
+        let nat_status = if !confirmed.is_empty() {
+            Ok(NatStatus::Public(confirmed))
+        } else if self.nat_status == NatStatus::Private {
+            Ok(NatStatus::Private)
+        } else if candidates.is_empty() {
+            Err("not listening on any address for the relays to check".to_string())
+        } else {
+            Err("no AutoNAT answer from the relays".to_string())
+        };
+        let autonat = Check { outcome: nat_status, duration: probed.unwrap_or(timeout) };
+        let relay = match reserved {
+            Some((outcome, duration)) => Check { outcome, duration },
+            None => Check::failed(format!("no reservation within {:?}: {}", timeout, last_error), timeout),
+        };
+        (autonat, relay)
     }
     
-    pub async fn connect_to_peer(&mut self, peer_addr: Multiaddr) -> Result<PeerId> {
-        // This is a simplified implementation
-        // In a real P2P implementation, you would:
-        // 1. Parse the multiaddr to extract peer ID
-        // 2. Establish a connection
-        // 3. Perform handshake
-        
-        tracing::info!("Connecting to peer at: {}", peer_addr);
+    // Listens through the next relay in `untried` that can be listened through, which asks it
+    // for a reservation
+    fn reserve_next(&mut self, untried: &mut VecDeque<Multiaddr>, last_error: &mut String) -> Option<ListenerId> {
+        while let Some(circuit) = untried.pop_front() {
+            match self.swarm.listen_on(circuit.clone()) {
+                Ok(listener) => return Some(listener),
+                Err(e) => *last_error = format!("{}: {}", circuit, e),
+            }
+        }
+        None
+    }
+    
+    // How the connection to `peer_id` goes, while there is one
+    pub fn connection_path(&self, peer_id: &PeerId) -> Option<ConnectionPath> {
+        self.connections.get(peer_id).filter(|_| self.swarm.is_connected(peer_id)).map(ConnectionPath::of)
+    }
+    
+    // Fed from swarm events (listen, identify, AutoNAT, relay reservations)
+    pub fn handle_address_event(&mut self, event: AddressEvent) {
+        let Some(change) = self.addresses.apply(event) else {
+            return;
+        };
         
-        // Simulate connection delay
-        sleep(Duration::from_millis(500)).await;
+        if !change.removed.is_empty() {
+            tracing::warn!(
+                "No longer reachable on {} address(es); share URLs handed out earlier may need refreshing",
+                change.removed.len()
+            );
+        }
+        for addr in &change.added {
+            tracing::info!("Now reachable on {}", addr);
+        }
         
-        // For demo purposes, return a random peer ID
-        // In a real implementation, this would return the actual peer ID
-        Ok(PeerId::random())
+        self.address_updates.send_replace(self.addresses.advertisable_addrs());
     }
-}
-
-pub fn create_shr_url(peer_id: PeerId, file_hash: &str) -> String {
-    format!("shr://{}/{}", peer_id, file_hash)
-}
-
-pub fn parse_shr_url(url: &str) -> Result<(PeerId, String)> {
-    if !url.starts_with("shr://") {
-        return Err(ShrLinkError::InvalidInput("Invalid SHR URL format".to_string()));
+    
+    // Long-running serve sessions watch this to tell the user when their URL went stale
+    pub fn watch_addresses(&self) -> watch::Receiver<Vec<Multiaddr>> {
+        self.address_updates.subscribe()
     }
     
-    let parts: Vec<&str> = url[6..].split('/').collect();
-    if parts.len() != 2 {
-        return Err(ShrLinkError::InvalidInput("Invalid SHR URL format".to_string()));
+    pub fn listeners(&self) -> Vec<Multiaddr> {
+        self.addresses.listeners()
     }
     
-    let peer_id = parts[0].parse::<PeerId>()
-        .map_err(|e| ShrLinkError::InvalidInput(format!("Invalid peer ID: {}", e)))?;
+    pub fn advertisable_addrs(&self) -> Vec<Multiaddr> {
+        self.addresses.advertisable_addrs()
+    }
     
-    let file_hash = parts[1].to_string();
+    // The advertisable addresses a browser can dial: WebRTC ones, with the certificate's hash
+    pub fn browser_addrs(&self) -> Vec<Multiaddr> {
+        self.advertisable_addrs()
+            .into_iter()
+            .filter(|addr| addr.iter().any(|p| matches!(p, Protocol::Certhash(_))))
+            .collect()
+    }
     
-    Ok((peer_id, file_hash))
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
+    pub fn port_mapping(&self) -> &PortMapping {
+        &self.port_mapping
+    }
     
-    #[test]
-    fn test_shr_url_parsing() {
-        let peer_id = PeerId::random();
-        let file_hash = "abc123";
-        
-        let url = create_shr_url(peer_id, file_hash);
-        let (parsed_peer_id, parsed_hash) = parse_shr_url(&url).unwrap();
-        
-        assert_eq!(peer_id, parsed_peer_id);
-        assert_eq!(file_hash, parsed_hash);
+    // Drives the swarm until the router has answered about the ports being listened on, or
+    // `timeout` is up
+    pub async fn await_port_mapping(&mut self, timeout: Duration) -> &PortMapping {
+        let _ = tokio::time::timeout(timeout, async {
+            while self.port_mapping == PortMapping::Pending {
+                self.next_event().await;
+            }
+        })
+        .await;
+        &self.port_mapping
     }
     
-    #[test]
-    fn test_invalid_shr_url() {
-        assert!(parse_shr_url("http://example.com").is_err());
-        assert!(parse_shr_url("shr://invalid").is_err());
+    // Stops listening, which has UPnP ask the router to drop what it mapped. Leases run out
+    // within the hour regardless, so the router's answer is only waited on for UNMAP_TIMEOUT
+    pub async fn close(&mut self) {
+        let mut open: HashSet<ListenerId> = self.listener_ids.drain(..).filter(|&l| self.swarm.remove_listener(l)).collect();
+        let mapped = matches!(self.port_mapping, PortMapping::Mapped(_));
+        if mapped {
+            tracing::debug!("Asking the router to drop its port mappings");
+        }
+        let _ = tokio::time::timeout(UNMAP_TIMEOUT, async {
+            while !open.is_empty() || mapped {
+                if let SwarmEvent::ListenerClosed { listener_id, .. } = self.next_event().await {
+                    open.remove(&listener_id);
+                }
+            }
+        })
+        .await;
+    }
+    
+    // Returns as soon as any peer is known, so callers bound the wait with their own timeout.
+    // With mDNS off there is nothing to wait for
+    pub async fn discover_peers(&mut self) -> Result<Vec<DiscoveredPeer>> {
+        tracing::info!("Discovering peers...");
+        
+        if !self.config.enable_mdns {
+            tracing::debug!("mDNS is disabled, so no peers can be discovered");
+            return Ok(Vec::new());
+        }
+        
+        // Whatever mDNS has already reported, expiries included, is taken in before deciding
+        // that the peers known are enough
+        while let Some(event) = self.swarm.select_next_some().now_or_never() {
+            self.take_discovery(event);
+        }
+        while self.discovered.is_empty() {
+            let event = self.swarm.select_next_some().await;
+            self.take_discovery(event);
+        }
+        
+        let ranked = self.reputation.rank_sources(self.discovered.peer_ids());
+        Ok(ranked.iter().filter_map(|p| self.discovered.get(p)).collect())
+    }
+    
+    // Asks every peer mDNS finds on the local network what it's sharing, again every
+    // `BROWSE_REFRESH`, until `duration` is up. Whatever a peer said more than `ANNOUNCE_TTL`
+    // ago, or said before it left the network, is left out
+    pub async fn browse(&mut self, duration: Duration) -> Result<Vec<Announcement>> {
+        if !self.config.enable_mdns {
+            return Err(ShrLinkError::InvalidInput("Browsing needs p2p.enable_mdns to find peers on the local network".to_string()));
+        }
+        
+        let deadline = sleep(duration);
+        tokio::pin!(deadline);
+        let mut refresh = tokio::time::interval(announce::BROWSE_REFRESH);
+        let cancel = self.cancel.clone();
+        let mut seen = Announcements::new();
+        let mut asked: HashMap<request_response::OutboundRequestId, PeerId> = HashMap::new();
+        let mut last_asked: HashMap<PeerId, tokio::time::Instant> = HashMap::new();
+        loop {
+            for peer_id in self.discovered.peer_ids() {
+                let due = last_asked.get(&peer_id).is_none_or(|at| at.elapsed() >= announce::BROWSE_REFRESH);
+                if due && !asked.values().any(|p| *p == peer_id) {
+                    let request_id = self.swarm.behaviour_mut().announce.send_request(&peer_id, AnnounceRequest);
+                    asked.insert(request_id, peer_id);
+                    last_asked.insert(peer_id, tokio::time::Instant::now());
+                }
+            }
+            
+            let event = tokio::select! {
+                _ = &mut deadline => break,
+                _ = refresh.tick() => continue,
+                _ = cancel.cancelled() => return Err(ShrLinkError::Cancelled),
+                event = self.next_event() => event,
+            };
+            match event {
+                SwarmEvent::Behaviour(BehaviourEvent::Announce(swarm::AnnounceEvent::Message { peer, message: Message::Response { request_id, response }, .. })) => {
+                    if asked.remove(&request_id).is_some() {
+                        tracing::debug!("Peer {} is sharing {} files", peer, response.len());
+                        seen.record(peer, response, std::time::Instant::now());
+                    }
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::Announce(swarm::AnnounceEvent::OutboundFailure { peer, request_id, error, .. })) => {
+                    if asked.remove(&request_id).is_some() {
+                        tracing::debug!("Peer {} didn't say what it's sharing: {}", peer, error);
+                    }
+                }
+                event => self.refuse_requests(event),
+            }
+        }
+        
+        for peer_id in last_asked.keys().filter(|p| self.discovered.get(p).is_none()) {
+            seen.forget(peer_id);
+        }
+        Ok(seen.current(std::time::Instant::now(), announce::ANNOUNCE_TTL))
+    }
+    
+    // The swarm's next event, with discovery taken care of on the way
+    async fn next_event(&mut self) -> SwarmEvent<BehaviourEvent> {
+        loop {
+            let event = self.swarm.select_next_some().await;
+            if let Some(event) = self.take_discovery(event) {
+                return event;
+            }
+        }
+    }
+    
+    // Hands back anything that isn't mDNS reporting peers coming or going, identify and
+    // AutoNAT reporting addresses, relays and hole punching, or UPnP mapping ports, keeping track of listen addresses and connections on the way
+    fn take_discovery(&mut self, event: SwarmEvent<BehaviourEvent>) -> Option<SwarmEvent<BehaviourEvent>> {
+        match event {
+            SwarmEvent::Behaviour(BehaviourEvent::Mdns(mdns::Event::Discovered(found))) => {
+                for (peer_id, addr) in found {
+                    if self.discovered.discovered(peer_id, addr.clone()) {
+                        tracing::info!("Discovered peer {} on the local network", peer_id);
+                    }
+                    self.swarm.add_peer_address(peer_id, addr);
+                }
+                None
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Mdns(mdns::Event::Expired(gone))) => {
+                for (peer_id, addr) in gone {
+                    if self.discovered.expired(&peer_id, &addr) {
+                        tracing::debug!("Peer {} is no longer seen on the local network", peer_id);
+                    }
+                }
+                None
+            }
+            SwarmEvent::NewListenAddr { ref address, .. } => {
+                self.handle_address_event(listen_address_event(address, true));
+                Some(event)
+            }
+            SwarmEvent::ExpiredListenAddr { ref address, .. } => {
+                self.handle_address_event(listen_address_event(address, false));
+                Some(event)
+            }
+            // A listener that's removed takes its addresses with it, without expiring them one by one
+            SwarmEvent::ListenerClosed { ref addresses, .. } => {
+                for address in addresses {
+                    self.handle_address_event(listen_address_event(address, false));
+                }
+                Some(event)
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Chunks(swarm::Event::Message { message: Message::Response { request_id, .. }, .. })) if self.acks.contains(&request_id) => {
+                self.acks.remove(&request_id);
+                None
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Chunks(swarm::Event::OutboundFailure { peer, request_id, error, .. })) if self.acks.contains(&request_id) => {
+                tracing::debug!("Failed to acknowledge a chunk to {}: {}", peer, error);
+                self.acks.remove(&request_id);
+                None
+            }
+            SwarmEvent::ConnectionEstablished { peer_id, ref endpoint, num_established, .. } => {
+                self.connections.insert(peer_id, endpoint.get_remote_address().clone());
+                if num_established.get() == 1 {
+                    self.events.emit(TransferEvent::PeerConnected(peer_id));
+                }
+                Some(event)
+            }
+            SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => {
+                self.events.emit(TransferEvent::PeerDisconnected(peer_id));
+                Some(event)
+            }
+            SwarmEvent::ExternalAddrConfirmed { address } => {
+                self.handle_address_event(AddressEvent::ExternalConfirmed(address));
+                None
+            }
+            SwarmEvent::ExternalAddrExpired { address } => {
+                self.handle_address_event(AddressEvent::ExternalExpired(address));
+                None
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Received { info, .. })) => {
+                self.handle_address_event(AddressEvent::Observed(info.observed_addr));
+                None
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Autonat(autonat::Event::StatusChanged { new, .. })) => {
+                self.nat_status = match new {
+                    autonat::NatStatus::Public(addr) => {
+                        tracing::info!("AutoNAT: publicly reachable on {}", addr);
+                        NatStatus::Public(vec![addr])
+                    }
+                    autonat::NatStatus::Private => {
+                        tracing::info!("AutoNAT: not publicly reachable");
+                        self.listen_through_relays();
+                        NatStatus::Private
+                    }
+                    autonat::NatStatus::Unknown => NatStatus::Unknown,
+                };
+                None
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::RelayClient(relay::client::Event::ReservationReqAccepted { relay_peer_id, renewal, .. })) => {
+                if !renewal {
+                    tracing::info!("Relay {} accepted a reservation", relay_peer_id);
+                }
+                None
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Dcutr(dcutr::Event { remote_peer_id, result })) => {
+                match result {
+                    Ok(_) => tracing::info!("Hole punched: now connected to {} directly", remote_peer_id),
+                    Err(e) => tracing::debug!("Hole punching to {} failed, staying on the relay: {}", remote_peer_id, e),
+                }
+                None
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Upnp(upnp::Event::NewExternalAddr(addr))) => {
+                tracing::info!("UPnP: the router forwards {} to us", addr);
+                match &mut self.port_mapping {
+                    PortMapping::Mapped(addrs) => addrs.push(addr),
+                    mapping => *mapping = PortMapping::Mapped(vec![addr]),
+                }
+                None
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Upnp(upnp::Event::ExpiredExternalAddr(addr))) => {
+                if let PortMapping::Mapped(addrs) = &mut self.port_mapping {
+                    addrs.retain(|a| *a != addr);
+                    if addrs.is_empty() {
+                        self.port_mapping_failed(format!("the router stopped forwarding {}", addr));
+                    }
+                }
+                None
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Upnp(upnp::Event::GatewayNotFound)) => {
+                self.port_mapping_failed("no UPnP router answered on the local network".to_string());
+                None
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Upnp(upnp::Event::NonRoutableGateway)) => {
+                self.port_mapping_failed("the router isn't on the public internet, so is likely behind another NAT".to_string());
+                None
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Identify(_) | BehaviourEvent::Autonat(_) | BehaviourEvent::RelayClient(_)) => None,
+            event => Some(event),
+        }
+    }
+    
+    // Behind NAT, peers can still come through a relay: each configured one is asked for a
+    // reservation, and the circuit address it gives is advertised alongside the rest
+    fn listen_through_relays(&mut self) {
+        if std::mem::replace(&mut self.relaying, true) {
+            return;
+        }
+        for (peer_id, addr) in swarm::relay_nodes(&self.config.relays) {
+            let circuit = addr.with(Protocol::P2pCircuit);
+            match self.swarm.listen_on(circuit.clone()) {
+                Ok(listener) => {
+                    tracing::info!("Asking relay {} for a reservation", peer_id);
+                    self.listener_ids.push(listener);
+                }
+                Err(e) => tracing::warn!("Failed to listen through relay {}: {}", circuit, e),
+            }
+        }
+    }
+    
+    // A failed mapping is no reason to stop: peers can still come through relays or the LAN
+    fn port_mapping_failed(&mut self, reason: String) {
+        tracing::info!("UPnP: no port mapped, {}", reason);
+        self.port_mapping = PortMapping::Failed(reason);
+    }
+    
+    pub async fn connect_to_peer(&mut self, peer_addr: Multiaddr) -> Result<PeerId> {
+        let known = swarm::peer_id_in(&peer_addr).or_else(|| self.dialed.get(&peer_addr).copied());
+        if let Some(peer_id) = known.filter(|p| self.swarm.is_connected(p)) {
+            tracing::debug!("Reusing existing connection to {}", peer_id);
+            return Ok(peer_id);
+        }
+        
+        tracing::info!("Connecting to peer at: {}", peer_addr);
+        
+        let timeout = Duration::from_millis(self.config.dial_timeout_ms);
+        let (peer_id, first) = swarm::dial(&mut self.swarm, peer_addr.clone(), timeout).await?;
+        self.connections.insert(peer_id, peer_addr.clone());
+        self.dialed.insert(peer_addr, peer_id);
+        if first {
+            self.events.emit(TransferEvent::PeerConnected(peer_id));
+        }
+        
+        Ok(peer_id)
+    }
+}
+
+fn is_circuit(addr: &Multiaddr) -> bool {
+    addr.iter().any(|p| matches!(p, Protocol::P2pCircuit))
+}
+
+// A circuit address is a relay reservation rather than a socket of this machine's own
+fn listen_address_event(addr: &Multiaddr, new: bool) -> AddressEvent {
+    match (is_circuit(addr), new) {
+        (true, true) => AddressEvent::RelayReservation(addr.clone()),
+        (true, false) => AddressEvent::RelayReservationLost(addr.clone()),
+        (false, true) => AddressEvent::NewListenAddr(addr.clone()),
+        (false, false) => AddressEvent::ExpiredListenAddr(addr.clone()),
+    }
+}
+
+// Who is asking something of this client in `event`, and the chunk it's about if there is one
+fn inbound_request(event: &SwarmEvent<BehaviourEvent>) -> Option<(PeerId, Option<usize>)> {
+    match event {
+        SwarmEvent::Behaviour(BehaviourEvent::Chunks(swarm::Event::Message { peer, message: Message::Request { request, .. }, .. })) => Some((*peer, request.index())),
+        SwarmEvent::Behaviour(BehaviourEvent::Manifests(swarm::ManifestEvent::Message { peer, message: Message::Request { .. }, .. }))
+        | SwarmEvent::Behaviour(BehaviourEvent::Hello(swarm::HelloEvent::Message { peer, message: Message::Request { .. }, .. }))
+        | SwarmEvent::Behaviour(BehaviourEvent::Pairing(swarm::PairEvent::Message { peer, message: Message::Request { .. }, .. }))
+        | SwarmEvent::Behaviour(BehaviourEvent::Announce(swarm::AnnounceEvent::Message { peer, message: Message::Request { .. }, .. }))
+        | SwarmEvent::Behaviour(BehaviourEvent::Receipts(swarm::ReceiptEvent::Message { peer, message: Message::Request { .. }, .. })) => Some((*peer, None)),
+        _ => None,
+    }
+}
+
+fn chunk_response(peer_id: PeerId, index: usize, response: ChunkResponse) -> Result<CompressedChunk> {
+    match response {
+        ChunkResponse::Chunk(chunk) if chunk.index == index => Ok(Arc::unwrap_or_clone(chunk)),
+        ChunkResponse::Chunk(chunk) => Err(ShrLinkError::P2P(format!(
+            "Peer {} answered a request for chunk {} with chunk {}",
+            peer_id, index, chunk.index
+        ))),
+        ChunkResponse::NotOffered => Err(ShrLinkError::P2P(format!("Peer {} doesn't offer chunk {}", peer_id, index))),
+        ChunkResponse::Have(_) => Err(ShrLinkError::P2P(format!("Peer {} answered a request for chunk {} with a chunk list", peer_id, index))),
+    }
+}
+
+fn chunk_request_failed(peer_id: PeerId, index: usize, error: request_response::OutboundFailure) -> ShrLinkError {
+    match error {
+        request_response::OutboundFailure::Timeout => {
+            ShrLinkError::Timeout(format!("chunk {} from {} wasn't answered within p2p.chunk_timeout_ms", index, peer_id))
+        }
+        error => ShrLinkError::Network(format!("Requesting chunk {} from {} failed: {}", index, peer_id, error)),
+    }
+}
+
+// Gives up on `transfer` once `transfer_timeout_ms` has passed, naming what it was doing
+async fn within_transfer_timeout<T>(config: &P2PConfig, phase: impl FnOnce() -> String, transfer: impl std::future::Future<Output = Result<T>>) -> Result<T> {
+    let Some(limit) = config.transfer_timeout_ms.map(Duration::from_millis) else {
+        return transfer.await;
+    };
+    tokio::time::timeout(limit, transfer)
+        .await
+        .unwrap_or_else(|_| Err(ShrLinkError::Timeout(format!("{} took longer than p2p.transfer_timeout_ms ({:?})", phase(), limit))))
+}
+
+// `file_hash` is in hex, of the file's contents as `ChunkManifest::file_hash` has it
+pub fn create_shr_url(peer_id: PeerId, file_hash: &str) -> String {
+    format!("shr://{}/{}", peer_id, file_hash)
+}
+
+pub fn parse_shr_url(url: &str) -> Result<(PeerId, String)> {
+    let (peer_id, file_hash, _) = parse_hybrid_url(url)?;
+    Ok((peer_id, file_hash))
+}
+
+// `url` with `addrs` given for its peer, as `shr_url_addrs` reads them back
+pub fn add_url_addrs(url: &str, addrs: &[Multiaddr]) -> String {
+    let mut url = url.to_string();
+    for addr in addrs {
+        url.push(if url.contains('?') { '&' } else { '?' });
+        url.push_str("addr=");
+        url.extend(percent_encoding::utf8_percent_encode(&addr.to_string(), percent_encoding::NON_ALPHANUMERIC));
+    }
+    url
+}
+
+// Addresses given for the URL's peer as `addr=` parameters, so it can be dialed without
+// discovery. Each one either leaves out the peer id or names the URL's own
+pub fn shr_url_addrs(url: &str) -> Result<Vec<Multiaddr>> {
+    let (peer_id, _, _) = parse_hybrid_url(url)?;
+    
+    url.split_once('?')
+        .into_iter()
+        .flat_map(|(_, query)| query.split('&'))
+        .filter_map(|pair| pair.strip_prefix("addr="))
+        .map(|value| {
+            let value = percent_encoding::percent_decode_str(value).decode_utf8_lossy();
+            let addr: Multiaddr = value
+                .parse()
+                .map_err(|e| ShrLinkError::InvalidInput(format!("Invalid peer address {} in SHR URL: {}", value, e)))?;
+            match swarm::peer_id_in(&addr) {
+                Some(other) if other != peer_id => Err(ShrLinkError::InvalidInput(format!(
+                    "Address {} in SHR URL is for peer {}, not {}",
+                    addr, other, peer_id
+                ))),
+                _ => Ok(addr),
+            }
+        })
+        .collect()
+}
+
+// A shr:// URL that also names an HTTP copy of the bundle, so receivers can race the two
+pub fn create_hybrid_url(peer_id: PeerId, file_hash: &str, fallback_url: &str) -> String {
+    let encoded = percent_encoding::utf8_percent_encode(fallback_url, percent_encoding::NON_ALPHANUMERIC);
+    format!("{}?fallback={}", create_shr_url(peer_id, file_hash), encoded)
+}
+
+pub fn parse_hybrid_url(url: &str) -> Result<(PeerId, String, Option<String>)> {
+    let Some(rest) = url.strip_prefix("shr://") else {
+        return Err(ShrLinkError::InvalidInput("Invalid SHR URL format".to_string()));
+    };
+    
+    let (path, query) = match rest.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (rest, None),
+    };
+    
+    let parts: Vec<&str> = path.split('/').collect();
+    if parts.len() != 2 {
+        return Err(ShrLinkError::InvalidInput("Invalid SHR URL format".to_string()));
+    }
+    
+    let peer_id = parts[0].parse::<PeerId>()
+        .map_err(|e| ShrLinkError::InvalidInput(format!("Invalid peer ID: {}", e)))?;
+    
+    // The BLAKE3 hash of the file's contents, which is what the download is checked against
+    let file_hash = parts[1].to_string();
+    if file_hash.len() != 64 || !file_hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(ShrLinkError::InvalidInput(format!("Invalid file hash in SHR URL: {} isn't 64 hex digits", file_hash)));
+    }
+    
+    let fallback = query
+        .into_iter()
+        .flat_map(|q| q.split('&'))
+        .find_map(|pair| pair.strip_prefix("fallback="))
+        .map(|v| percent_encoding::percent_decode_str(v).decode_utf8_lossy().into_owned())
+        .filter(|v| crate::fallback::is_http_url(v));
+    
+    Ok((peer_id, file_hash, fallback))
+}
+
+// Removes and returns the first of `queue` that `wanted` accepts
+fn take_first(queue: &mut VecDeque<usize>, wanted: impl Fn(&usize) -> bool) -> Option<usize> {
+    let found = queue.iter().position(wanted)?;
+    queue.remove(found)
+}
+
+fn retry_backoff(attempt: u32) -> Duration {
+    RETRY_BACKOFF.saturating_mul(1 << attempt.saturating_sub(1).min(16)).min(MAX_RETRY_BACKOFF)
+}
+
+fn listen_addrs(config: &P2PConfig) -> Result<Vec<Multiaddr>> {
+    let port = config.port.unwrap_or(0);
+    let all_interfaces = [
+        format!("/ip4/0.0.0.0/tcp/{}", port),
+        // For browsers; the same number, but a UDP port
+        #[cfg(feature = "webrtc")]
+        format!("/ip4/0.0.0.0/udp/{}/webrtc-direct", port),
+    ];
+    let addrs = match config.listen_addrs.is_empty() {
+        true => &all_interfaces[..],
+        false => &config.listen_addrs[..],
+    };
+    addrs
+        .iter()
+        .map(|addr| addr.parse().map_err(|e| ShrLinkError::P2P(format!("Invalid listen address {}: {}", addr, e))))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    // A client with an identity of its own, as if on a machine of its own
+    async fn new_client(mut config: P2PConfig) -> P2PClient {
+        let dir = tempfile::tempdir().unwrap();
+        config.identity_path = Some(dir.path().join(identity::IDENTITY_FILE));
+        P2PClient::new(config)
+            .await
+            .unwrap()
+            .with_stats(StatsStore::in_memory())
+            .with_reputation(ReputationStore::in_memory(reputation::DEFAULT_CAPACITY))
+    }
+    
+    #[tokio::test]
+    async fn test_peer_id_survives_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = crate::config::Config::default().p2p;
+        config.identity_path = Some(dir.path().join(identity::IDENTITY_FILE));
+        
+        let first = P2PClient::new(config.clone()).await.unwrap().local_peer_id();
+        assert_eq!(P2PClient::new(config.clone()).await.unwrap().local_peer_id(), first);
+        
+        std::fs::write(dir.path().join(identity::IDENTITY_FILE), b"truncated").unwrap();
+        let err = P2PClient::new(config).await.err().unwrap();
+        assert!(err.to_string().contains("move it aside"), "{}", err);
+    }
+    
+    #[test]
+    fn test_shr_url_parsing() {
+        let peer_id = PeerId::random();
+        let file_hash = hex::encode([7u8; 32]);
+        
+        let url = create_shr_url(peer_id, &file_hash);
+        let (parsed_peer_id, parsed_hash) = parse_shr_url(&url).unwrap();
+        
+        assert_eq!(peer_id, parsed_peer_id);
+        assert_eq!(file_hash, parsed_hash);
+    }
+    
+    #[test]
+    fn test_shr_url_addrs() {
+        let peer_id = PeerId::random();
+        let file_hash = hex::encode([7u8; 32]);
+        let url = create_shr_url(peer_id, &file_hash);
+        assert!(shr_url_addrs(&url).unwrap().is_empty());
+        
+        let with_addrs = format!("{}?addr=/ip4/192.168.1.5/tcp/4001&addr=%2Fip4%2F10.0.0.2%2Ftcp%2F4001%2Fp2p%2F{}", url, peer_id);
+        let addrs = shr_url_addrs(&with_addrs).unwrap();
+        assert_eq!(addrs[0], "/ip4/192.168.1.5/tcp/4001".parse().unwrap());
+        assert_eq!(addrs[1], format!("/ip4/10.0.0.2/tcp/4001/p2p/{}", peer_id).parse().unwrap());
+        assert_eq!(parse_shr_url(&with_addrs).unwrap(), (peer_id, file_hash));
+        
+        assert!(shr_url_addrs(&format!("{}?addr=not-an-address", url)).is_err());
+        let elsewhere = format!("{}?addr=/ip4/10.0.0.2/tcp/4001/p2p/{}", url, PeerId::random());
+        assert!(shr_url_addrs(&elsewhere).unwrap_err().to_string().contains("is for peer"));
+    }
+    
+    #[test]
+    fn test_added_url_addrs_read_back() {
+        let peer_id = PeerId::random();
+        let file_hash = hex::encode([7u8; 32]);
+        let addrs: Vec<Multiaddr> = vec!["/ip4/203.0.113.7/tcp/4001".parse().unwrap(), "/ip4/192.168.1.5/tcp/4001".parse().unwrap()];
+        
+        let url = add_url_addrs(&create_shr_url(peer_id, &file_hash), &addrs);
+        assert_eq!(shr_url_addrs(&url).unwrap(), addrs);
+        assert_eq!(add_url_addrs(&url, &[]), url);
+        
+        let hybrid = add_url_addrs(&create_hybrid_url(peer_id, &file_hash, "https://example.com/f.shr"), &addrs);
+        assert_eq!(shr_url_addrs(&hybrid).unwrap(), addrs);
+        assert_eq!(parse_hybrid_url(&hybrid).unwrap().2.as_deref(), Some("https://example.com/f.shr"));
+    }
+    
+    // A browser can only dial addresses that carry the certificate's hash, and those survive the
+    // URL untouched, whichever transport the sender listens on
+    #[test]
+    fn test_certhash_addrs_survive_the_url() {
+        let peer_id = PeerId::random();
+        let webrtc: Multiaddr = "/ip4/192.168.1.5/udp/4001/webrtc-direct/certhash/uEiDDq4_xNyDorZBH3TlGazyJdOWSwvo4PUo5YHFMrvDE8g".parse().unwrap();
+        assert!(webrtc.iter().any(|p| matches!(p, libp2p::multiaddr::Protocol::Certhash(_))));
+        
+        let url = add_url_addrs(&create_shr_url(peer_id, &hex::encode([7u8; 32])), std::slice::from_ref(&webrtc));
+        assert!(!url[url.find('?').unwrap()..].contains('/'));
+        assert_eq!(shr_url_addrs(&url).unwrap(), [webrtc]);
+    }
+    
+    #[tokio::test]
+    async fn test_port_mapping_is_reported_and_closed() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = crate::config::Config::default().p2p;
+        config.enable_mdns = false;
+        config.identity_path = Some(dir.path().join("peer.key"));
+        
+        let mut client = P2PClient::new(config.clone()).await.unwrap();
+        assert_eq!(client.port_mapping(), &PortMapping::Disabled);
+        let report = client.probe_reachability(Duration::from_secs(1)).await;
+        assert!(report.upnp.outcome.unwrap_err().contains("p2p.enable_upnp"));
+        
+        // No router answers in a test, which isn't an error: the client listens as usual
+        config.enable_upnp = true;
+        let mut client = P2PClient::new(config).await.unwrap();
+        client.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).await.unwrap();
+        let mapping = client.await_port_mapping(Duration::from_millis(200)).await.clone();
+        assert!(!matches!(mapping, PortMapping::Disabled | PortMapping::Mapped(_)), "{:?}", mapping);
+        
+        let start = std::time::Instant::now();
+        client.close().await;
+        assert!(start.elapsed() < UNMAP_TIMEOUT);
+        assert!(client.listeners().is_empty());
+    }
+    
+    #[tokio::test]
+    async fn test_relays_are_asked_about_reachability() {
+        let relay = PeerId::random();
+        let mut config = crate::config::Config::default().p2p;
+        config.relays = vec![
+            format!("/ip4/127.0.0.1/tcp/9/p2p/{}", relay),
+            "/ip4/127.0.0.1/tcp/9".to_string(),
+            "not-an-address".to_string(),
+        ];
+        let nodes = swarm::relay_nodes(&config.relays);
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].0, relay);
+        
+        // Nothing to dial back before anything is listened on
+        let mut client = new_client(config).await;
+        let report = client.probe_reachability(Duration::from_millis(500)).await;
+        assert!(report.lines().iter().any(|l| l.contains("not listening")), "{:?}", report.lines());
+        
+        // The relay isn't there, so neither a dial-back nor a reservation comes of asking it,
+        // and the probe says so without waiting out its timeout
+        client.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).await.unwrap();
+        let report = client.probe_reachability(Duration::from_secs(10)).await;
+        assert!(report.lines().iter().any(|l| l.contains("no AutoNAT answer")), "{:?}", report.lines());
+        assert!(report.relay.outcome.is_err());
+        assert!(report.duration < Duration::from_secs(10));
+        assert_eq!(report.verdict, Verdict::Isolated);
+        assert!(client.advertisable_addrs().iter().all(|a| !is_circuit(a)));
+        
+        let mut client = new_client(crate::config::Config::default().p2p).await;
+        let report = client.probe_reachability(Duration::from_millis(500)).await;
+        assert!(report.lines().iter().any(|l| l.contains("no relays configured")), "{:?}", report.lines());
+    }
+    
+    #[tokio::test]
+    async fn test_behind_nat_listens_through_relays() {
+        let relay = PeerId::random();
+        let mut config = crate::config::Config::default().p2p;
+        config.relays = vec![format!("/ip4/127.0.0.1/tcp/9/p2p/{}", relay)];
+        let mut client = new_client(config.clone()).await;
+        assert!(client.swarm.behaviour().dcutr.is_enabled());
+        
+        // Asked once, however often AutoNAT says so
+        client.listen_through_relays();
+        client.listen_through_relays();
+        assert_eq!(client.listener_ids.len(), 1);
+        
+        // The reservation's circuit address is shared, and dropped with it
+        let circuit: Multiaddr = format!("/ip4/127.0.0.1/tcp/9/p2p/{}/p2p-circuit", relay).parse().unwrap();
+        let public: Multiaddr = format!("/ip4/203.0.113.9/tcp/4001/p2p/{}/p2p-circuit", relay).parse().unwrap();
+        client.handle_address_event(listen_address_event(&public, true));
+        assert_eq!(client.advertisable_addrs(), std::slice::from_ref(&public));
+        client.handle_address_event(listen_address_event(&public, false));
+        assert!(client.advertisable_addrs().is_empty());
+        assert_eq!(listen_address_event(&circuit, true), AddressEvent::RelayReservation(circuit));
+        
+        config.enable_holepunching = false;
+        assert!(!new_client(config).await.swarm.behaviour().dcutr.is_enabled());
+        assert!(!new_client(crate::config::Config::default().p2p).await.swarm.behaviour().dcutr.is_enabled());
+    }
+    
+    #[tokio::test]
+    async fn test_prepare_manifest_honors_sign_chunks() {
+        let compressor = crate::compression::ParallelCompressor::default();
+        let chunks = vec![compressor.compress_chunk(0, b"signed".to_vec()).unwrap()];
+        
+        let mut config = crate::config::Config::default().p2p;
+        let client = new_client(config.clone()).await;
+        assert!(!client.prepare_manifest([1u8; 32], &chunks).unwrap().is_signed());
+        
+        config.sign_chunks = true;
+        let client = new_client(config).await;
+        let manifest = client.prepare_manifest([1u8; 32], &chunks).unwrap();
+        assert!(manifest.is_signed());
+        assert!(manifest.verify_chunk(&chunks[0], Some(client.local_peer_id())).is_ok());
+    }
+    
+    #[tokio::test]
+    async fn test_issued_receipt_verifies() {
+        let mut client = new_client(crate::config::Config::default().p2p).await;
+        let receipt = client.issue_receipt([9u8; 32], 1024, Duration::from_secs(1), None).unwrap();
+        
+        assert_eq!(receipt.verify(&[9u8; 32]).unwrap(), client.local_peer_id());
+        
+        // A sender that's gone doesn't hold up the receive
+        let started = std::time::Instant::now();
+        assert!(!client.deliver_receipt(PeerId::random(), &receipt).await);
+        assert!(started.elapsed() <= RECEIPT_TIMEOUT + Duration::from_millis(500));
+    }
+    
+    #[tokio::test]
+    async fn test_address_events_reach_watchers() {
+        let mut client = new_client(crate::config::Config::default().p2p).await;
+        let mut updates = client.watch_addresses();
+        let lan: Multiaddr = "/ip4/192.168.1.20/tcp/4001".parse().unwrap();
+        
+        client.handle_address_event(AddressEvent::NewListenAddr("/ip4/0.0.0.0/tcp/4001".parse().unwrap()));
+        assert!(!updates.has_changed().unwrap());
+        
+        client.handle_address_event(AddressEvent::NewListenAddr(lan.clone()));
+        assert!(updates.has_changed().unwrap());
+        assert_eq!(*updates.borrow_and_update(), vec![lan.clone()]);
+        assert_eq!(client.listeners(), vec![lan]);
+    }
+    
+    #[tokio::test]
+    async fn test_listens_where_configured() {
+        let mut config = crate::config::Config::default().p2p;
+        config.listen_addrs = vec!["/ip4/127.0.0.1/tcp/0".to_string()];
+        let mut client = new_client(config.clone()).await;
+        let bound = client.listen().await.unwrap();
+        assert_eq!(bound.len(), 1);
+        assert!(!bound[0].to_string().ends_with("/tcp/0"), "{}", bound[0]);
+        assert_eq!(client.listeners(), bound);
+        
+        // The same port again, this time through `port`
+        let port = bound[0].iter().find_map(|p| match p {
+            libp2p::multiaddr::Protocol::Tcp(port) => Some(port),
+            _ => None,
+        }).unwrap();
+        config.listen_addrs.clear();
+        config.port = Some(port);
+        let err = new_client(config.clone()).await.listen().await.unwrap_err();
+        assert!(err.to_string().contains("already in use"), "{}", err);
+        
+        config.listen_addrs = vec!["not-an-address".to_string()];
+        assert!(matches!(new_client(config).await.listen().await, Err(ShrLinkError::P2P(_))));
+    }
+    
+    // Two native clients stand in for a sender and a browser, with nothing but WebRTC between
+    // them and the address taken from the URL
+    #[cfg(feature = "webrtc")]
+    #[tokio::test]
+    async fn test_chunks_move_over_webrtc() {
+        let mut config = crate::config::Config::default().p2p;
+        config.listen_addrs = vec!["/ip4/127.0.0.1/udp/0/webrtc-direct".to_string()];
+        let mut sender = new_client(config.clone()).await;
+        let bound = sender.listen().await.unwrap();
+        assert!(bound[0].iter().any(|p| matches!(p, Protocol::Certhash(_))), "{}", bound[0]);
+        
+        let url = add_url_addrs(&create_shr_url(sender.local_peer_id(), &hex::encode([7u8; 32])), &bound);
+        let addr = shr_url_addrs(&url).unwrap().remove(0);
+        // libp2p's WebRTC only dials out from a socket it's listening on
+        let mut receiver = new_client(config).await;
+        receiver.listen().await.unwrap();
+        let chunk = crate::compression::ParallelCompressor::default().compress_chunk(0, vec![1; 1024]).unwrap();
+        
+        let (progress, received) = tokio::join!(sender.send_chunks(receiver.local_peer_id(), vec![chunk.clone()]), async {
+            let peer_id = receiver.connect_to_peer(addr).await?;
+            receiver.receive_chunks(peer_id, 1).await
+        });
+        assert_eq!(progress.unwrap().chunks_sent, 1);
+        let received = received.unwrap();
+        assert_eq!((received.len(), received[0].hash, &received[0].data), (1, chunk.hash, &chunk.data));
+    }
+    
+    // A bare swarm listening on loopback, driven in the background for the life of the test
+    async fn spawn_listener() -> (PeerId, Multiaddr) {
+        use futures::StreamExt;
+        use libp2p::swarm::SwarmEvent;
+        
+        let mut listener = swarm::build_swarm(Keypair::generate_ed25519(), &crate::config::Config::default().p2p).unwrap();
+        let peer_id = *listener.local_peer_id();
+        listener.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        
+        let addr = loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = listener.select_next_some().await {
+                break address;
+            }
+        };
+        tokio::spawn(async move {
+            loop {
+                listener.select_next_some().await;
+            }
+        });
+        
+        (peer_id, addr)
+    }
+    
+    #[tokio::test]
+    async fn test_connect_to_peer_authenticates_identity() {
+        let (peer_id, addr) = spawn_listener().await;
+        let mut client = new_client(crate::config::Config::default().p2p).await;
+        
+        // Without a /p2p/ component the handshake decides who we're talking to
+        assert_eq!(client.connect_to_peer(addr.clone()).await.unwrap(), peer_id);
+        
+        let with_id = addr.with(libp2p::multiaddr::Protocol::P2p(peer_id));
+        assert_eq!(client.connect_to_peer(with_id.clone()).await.unwrap(), peer_id);
+        assert_eq!(client.swarm.connected_peers().count(), 1);
+    }
+    
+    #[tokio::test]
+    async fn test_connect_to_peer_rejects_mismatched_id() {
+        let (_, addr) = spawn_listener().await;
+        let mut client = new_client(crate::config::Config::default().p2p).await;
+        
+        let impostor = PeerId::random();
+        let err = client.connect_to_peer(addr.with(libp2p::multiaddr::Protocol::P2p(impostor))).await.unwrap_err();
+        assert!(matches!(&err, ShrLinkError::Dial(e) if matches!(**e, crate::DialError::PeerIdMismatch { expected, .. } if expected == impostor)));
+        assert!(err.to_string().contains("peer id mismatch"));
+    }
+    
+    #[tokio::test]
+    async fn test_connect_to_peer_connection_refused() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let addr: Multiaddr = format!("/ip4/127.0.0.1/tcp/{}", port).parse().unwrap();
+        let mut client = new_client(crate::config::Config::default().p2p).await;
+        
+        let err = client.connect_to_peer(addr.clone()).await.unwrap_err();
+        assert!(matches!(&err, ShrLinkError::Dial(e) if **e == crate::DialError::ConnectionRefused(addr.clone())), "{}", err);
+    }
+    
+    #[tokio::test]
+    async fn test_connect_to_dials_the_addresses_given() {
+        let (peer_id, addr) = spawn_listener().await;
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let refused: Multiaddr = format!("/ip4/127.0.0.1/tcp/{}", port).parse().unwrap();
+        let mut config = crate::config::Config::default().p2p;
+        config.enable_mdns = false;
+        
+        // An address that refuses is passed over for the next one
+        let mut client = new_client(config.clone()).await;
+        let mut events = client.transfer_events();
+        client.connect_to(peer_id, &[refused.clone(), addr.clone()]).await.unwrap();
+        assert!(client.swarm.is_connected(&peer_id));
+        assert_eq!(events.try_recv().unwrap(), TransferEvent::PeerConnected(peer_id));
+        
+        let mut client = new_client(config.clone()).await;
+        let err = client.connect_to(peer_id, &[refused]).await.unwrap_err();
+        assert!(matches!(&err, ShrLinkError::Dial(e) if matches!(**e, DialError::ConnectionRefused(_))), "{}", err);
+        
+        // Whoever answers has to be the peer asked for, whatever the other addresses
+        let impostor = PeerId::random();
+        let err = client.connect_to(impostor, &[addr.clone(), addr]).await.unwrap_err();
+        assert!(matches!(&err, ShrLinkError::Dial(e) if matches!(**e, DialError::PeerIdMismatch { expected, actual } if expected == impostor && actual == peer_id)), "{}", err);
+    }
+    
+    // A sender listening on loopback, and the address a receiver can reach it on
+    async fn listening_sender(cancel: CancellationToken) -> (P2PClient, Multiaddr) {
+        let mut sender = new_client(crate::config::Config::default().p2p).await.with_cancellation(cancel);
+        let addr = sender.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).await.unwrap();
+        (sender, addr)
+    }
+    
+    #[tokio::test]
+    async fn test_send_chunks_stops_when_cancelled() {
+        let cancel = CancellationToken::new();
+        let (mut sender, addr) = listening_sender(cancel.clone()).await;
+        let mut receiver = new_client(crate::config::Config::default().p2p).await;
+        let chunk = crate::compression::ParallelCompressor::default().compress_chunk(0, vec![1; 1024]).unwrap();
+        
+        let (progress, received) = tokio::join!(sender.send_chunks(receiver.local_peer_id(), vec![chunk.clone()]), async {
+            let peer_id = receiver.connect_to_peer(addr).await?;
+            receiver.receive_chunks(peer_id, 1).await
+        });
+        assert_eq!(progress.unwrap().chunks_sent, 1);
+        let received = received.unwrap();
+        assert_eq!((received.len(), received[0].hash, &received[0].data), (1, chunk.hash, &chunk.data));
+        
+        // Nobody asks for these, so only cancelling ends the send
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(25)).await;
+            cancel.cancel();
+        });
+        let started = std::time::Instant::now();
+        let chunks = (0..1000).map(|index| CompressedChunk { index, ..chunk.clone() }).collect();
+        let result = sender.send_chunks(receiver.local_peer_id(), chunks).await;
+        assert!(matches!(result, Err(ShrLinkError::Cancelled)));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+    
+    #[tokio::test]
+    async fn test_chunks_are_only_served_to_their_receiver() {
+        let (mut sender, addr) = listening_sender(CancellationToken::new()).await;
+        let mut receiver = new_client(crate::config::Config::default().p2p).await;
+        let chunk = crate::compression::ParallelCompressor::default().compress_chunk(0, vec![2; 1024]).unwrap();
+        
+        // Offered to someone else, so the request is answered with nothing
+        let cancel = sender.cancel.clone();
+        let (sent, received) = tokio::join!(sender.send_chunks(PeerId::random(), vec![chunk]), async {
+            let peer_id = receiver.connect_to_peer(addr).await.unwrap();
+            let received = receiver.receive_chunks(peer_id, 1).await;
+            cancel.cancel();
+            received
+        });
+        assert!(matches!(sent, Err(ShrLinkError::Cancelled)));
+        let err = received.unwrap_err();
+        assert!(err.to_string().contains("doesn't offer chunk 0"), "{}", err);
+        
+        // And a peer that was never connected can't be asked at all
+        assert!(matches!(receiver.receive_chunks(PeerId::random(), 1).await, Err(ShrLinkError::Network(_))));
+    }
+    
+    #[tokio::test]
+    async fn test_serve_chunks_until_enough_copies() {
+        let (mut sender, addr) = listening_sender(CancellationToken::new()).await;
+        let compressor = crate::compression::ParallelCompressor::default();
+        let chunks: Vec<_> = (0..3).map(|i| compressor.compress_chunk(i, vec![i as u8; 4096]).unwrap()).collect();
+        let manifest = sender.prepare_manifest([4; 32], &chunks).unwrap();
+        let sender_id = sender.local_peer_id();
+        
+        // By the file hash alone, as a shr:// URL names it
+        let fetch = |addr: Multiaddr| async move {
+            let mut receiver = new_client(crate::config::Config::default().p2p).await;
+            receiver.reach_peer(sender_id, &[addr]).await.unwrap();
+            let manifest = receiver.request_manifest(sender_id, &hex::encode([4; 32])).await.unwrap();
+            assert!(matches!(receiver.connection_path(&sender_id), Some(ConnectionPath::Direct(_))));
+            let received = receiver.fetch_chunks(sender_id, &manifest, |_| {}).await.unwrap();
+            (receiver.local_peer_id(), received)
+        };
+        let mut events = Vec::new();
+        let (served, first, second) = tokio::join!(
+            sender.serve_chunks(&manifest, chunks, 2, Duration::from_secs(60), |event| events.push(event)),
+            fetch(addr.clone()),
+            fetch(addr.clone())
+        );
+        
+        let stats = served.unwrap();
+        assert_eq!(stats, ServeStats { chunks_served: 6, bytes_served: stats.bytes_served, peers: 2, completed: 2, receipts: 0, chunks_retried: 0, refused: 0, violations: 0 });
+        for (peer_id, received) in [first, second] {
+            assert_eq!(received.len(), 3);
+            assert!(events.contains(&ServeEvent::PeerStarted(peer_id)));
+            assert!(events.contains(&ServeEvent::PeerCompleted(peer_id)));
+            // Without a receipt, having every chunk is what counts in the peer's favour
+            assert_eq!(sender.reputation().get(&peer_id).unwrap().successes, 1);
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_receipts_reach_the_sender() {
+        let (mut sender, addr) = listening_sender(CancellationToken::new()).await;
+        let compressor = crate::compression::ParallelCompressor::default();
+        let chunks: Vec<_> = (0..2).map(|i| compressor.compress_chunk(i, vec![i as u8; 4096]).unwrap()).collect();
+        let manifest = sender.prepare_manifest([6; 32], &chunks).unwrap();
+        let sender_id = sender.local_peer_id();
+        let mut receiver = new_client(crate::config::Config::default().p2p).await;
+        let receiver_id = receiver.local_peer_id();
+        
+        // The receiver goes away once it's done, as it would on exiting, so the serve can too
+        let mut events = Vec::new();
+        let (served, delivered) = tokio::join!(sender.serve_chunks(&manifest, chunks, 1, Duration::from_secs(60), |event| events.push(event)), async move {
+            receiver.reach_peer(sender_id, &[addr]).await.unwrap();
+            let manifest = receiver.request_manifest(sender_id, &hex::encode([6; 32])).await.unwrap();
+            receiver.fetch_chunks(sender_id, &manifest, |_| {}).await.unwrap();
+            let receipt = receiver.issue_receipt(manifest.file_hash, 8192, Duration::from_millis(10), Some("alice-laptop".to_string())).unwrap();
+            receiver.deliver_receipt(sender_id, &receipt).await
+        });
+        
+        assert!(delivered);
+        let stats = served.unwrap();
+        assert_eq!((stats.completed, stats.receipts), (1, 1));
+        assert!(events.iter().any(|e| matches!(e, ServeEvent::ReceiptReceived(r) if r.receiver_peer_id == receiver_id.to_string() && r.receiver_name.as_deref() == Some("alice-laptop"))));
+        assert_eq!(sender.stats().stats().receipts, 1);
+        assert_eq!(sender.stats().stats().peers[&receiver_id.to_string()].receipts, 1);
+        // Served in full and confirmed, which is still the one transfer
+        assert_eq!(sender.reputation().get(&receiver_id).unwrap().successes, 1);
+    }
+    
+    #[tokio::test]
+    async fn test_receipts_count_toward_copies() {
+        let (mut sender, addr) = listening_sender(CancellationToken::new()).await;
+        let compressor = crate::compression::ParallelCompressor::default();
+        let chunks: Vec<_> = (0..3).map(|i| compressor.compress_chunk(i, vec![i as u8; 4096]).unwrap()).collect();
+        let manifest = sender.prepare_manifest([7; 32], &chunks).unwrap();
+        let sender_id = sender.local_peer_id();
+        let mut receiver = new_client(crate::config::Config::default().p2p).await;
+        
+        // The receiver already has the first chunk, so this serve never sees it have them all;
+        // only its receipt says it's done
+        let have = vec![Some(chunks[0].clone()), None, None];
+        let (served, delivered) = tokio::join!(sender.serve_chunks(&manifest, chunks.clone(), 1, Duration::from_secs(60), |_| {}), async move {
+            receiver.reach_peer(sender_id, &[addr]).await.unwrap();
+            let manifest = receiver.request_manifest(sender_id, &hex::encode([7; 32])).await.unwrap();
+            let received = receiver.resume_chunks(sender_id, &manifest, have, |_, _| {}).await.unwrap();
+            assert_eq!(received.len(), 3);
+            let receipt = receiver.issue_receipt(manifest.file_hash, 3 * 4096, Duration::from_millis(10), None).unwrap();
+            receiver.deliver_receipt(sender_id, &receipt).await
+        });
+        
+        assert!(delivered);
+        let stats = served.unwrap();
+        assert_eq!((stats.chunks_served, stats.completed, stats.receipts), (2, 1, 1));
+    }
+    
+    #[tokio::test]
+    async fn test_chunks_come_from_every_peer_that_has_them() {
+        let (mut even, even_addr) = listening_sender(CancellationToken::new()).await;
+        let (mut odd, odd_addr) = listening_sender(CancellationToken::new()).await;
+        let compressor = crate::compression::ParallelCompressor::default();
+        let chunks: Vec<_> = (0..6).map(|i| compressor.compress_chunk(i, vec![i as u8 + 1; 4096]).unwrap()).collect();
+        let manifest = even.prepare_manifest([5; 32], &chunks).unwrap();
+        let (even_id, odd_id) = (even.local_peer_id(), odd.local_peer_id());
+        let (even_half, odd_half): (Vec<_>, Vec<_>) = chunks.iter().cloned().partition(|c| c.index % 2 == 0);
+        
+        let mut receiver = new_client(crate::config::Config::default().p2p).await;
+        receiver.reach_peer(even_id, &[even_addr]).await.unwrap();
+        receiver.reach_peer(odd_id, &[odd_addr]).await.unwrap();
+        let mut arrived = Vec::new();
+        let peers = [even_id, odd_id];
+        let (from_even, from_odd, received) = tokio::join!(
+            even.serve_chunks(&manifest, even_half, 1, Duration::from_secs(60), |_| {}),
+            odd.serve_chunks(&manifest, odd_half, 1, Duration::from_secs(60), |_| {}),
+            receiver.resume_from_peers(&peers, &manifest, Vec::new(), |position, _| arrived.push(position))
+        );
+        
+        let received = received.unwrap();
+        assert!(received.iter().map(|c| c.index).eq(0..6));
+        assert!(received.iter().zip(&chunks).all(|(r, c)| r.hash == c.hash));
+        arrived.sort();
+        assert_eq!(arrived, (0..6).collect::<Vec<_>>());
+        assert_eq!(from_even.unwrap().chunks_served, 3);
+        assert_eq!(from_odd.unwrap().chunks_served, 3);
+    }
+    
+    #[tokio::test]
+    async fn test_chunks_no_peer_has_are_an_error() {
+        let (mut first, first_addr) = listening_sender(CancellationToken::new()).await;
+        let (mut second, second_addr) = listening_sender(CancellationToken::new()).await;
+        let compressor = crate::compression::ParallelCompressor::default();
+        let chunks: Vec<_> = (0..3).map(|i| compressor.compress_chunk(i, vec![i as u8 + 1; 4096]).unwrap()).collect();
+        let manifest = first.prepare_manifest([6; 32], &chunks).unwrap();
+        let (first_id, second_id) = (first.local_peer_id(), second.local_peer_id());
+        
+        let mut receiver = new_client(crate::config::Config::default().p2p).await;
+        receiver.reach_peer(first_id, &[first_addr]).await.unwrap();
+        receiver.reach_peer(second_id, &[second_addr]).await.unwrap();
+        let peers = [first_id, second_id];
+        let fetched = tokio::select! {
+            _ = first.serve_chunks(&manifest, chunks[..1].to_vec(), 1, Duration::from_secs(60), |_| {}) => unreachable!(),
+            _ = second.serve_chunks(&manifest, chunks[..1].to_vec(), 1, Duration::from_secs(60), |_| {}) => unreachable!(),
+            fetched = receiver.resume_from_peers(&peers, &manifest, Vec::new(), |_, _| {}) => fetched,
+        };
+        let err = fetched.unwrap_err();
+        assert!(err.to_string().contains("None of 2 peers has chunk 1"), "{}", err);
+    }
+    
+    #[tokio::test]
+    async fn test_receivers_past_the_limit_wait_their_turn() {
+        let mut config = crate::config::Config::default().p2p;
+        config.max_concurrent_transfers = 1;
+        let mut sender = new_client(config).await;
+        let addr = sender.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).await.unwrap();
+        let compressor = crate::compression::ParallelCompressor::default();
+        let chunks: Vec<_> = (0..4).map(|i| compressor.compress_chunk(i, vec![i as u8; 4096]).unwrap()).collect();
+        let manifest = sender.prepare_manifest([6; 32], &chunks).unwrap();
+        let sender_id = sender.local_peer_id();
+        
+        let fetch = |addr: Multiaddr| async move {
+            let mut receiver = new_client(crate::config::Config::default().p2p).await;
+            receiver.reach_peer(sender_id, &[addr]).await.unwrap();
+            let manifest = receiver.request_manifest(sender_id, &hex::encode([6; 32])).await.unwrap();
+            receiver.fetch_chunks(sender_id, &manifest, |_| {}).await.unwrap().len()
+        };
+        let mut events = Vec::new();
+        let (served, first, second) = tokio::join!(
+            sender.serve_chunks(&manifest, chunks, 2, Duration::from_secs(60), |event| events.push(event)),
+            fetch(addr.clone()),
+            fetch(addr.clone())
+        );
+        
+        assert_eq!(served.unwrap().completed, 2);
+        assert_eq!((first, second), (4, 4));
+        // Whoever came second only started once the first had everything
+        let kinds: Vec<_> = events.iter().filter_map(|e| match e {
+            ServeEvent::PeerStarted(_) => Some("started"),
+            ServeEvent::PeerWaiting(_) => Some("waiting"),
+            ServeEvent::PeerCompleted(_) => Some("completed"),
+            _ => None,
+        }).collect();
+        assert_eq!(kinds, ["started", "waiting", "completed", "started", "completed"]);
+    }
+    
+    // Chunks of bytes that don't compress, `count` of them at 256 KiB
+    fn incompressible_chunks(count: usize) -> Vec<CompressedChunk> {
+        let compressor = crate::compression::ParallelCompressor::default();
+        let mut state = 0x9E37_79B9_7F4A_7C15u64;
+        (0..count).map(|i| {
+            let data = (0..256 * 1024).map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            }).collect();
+            compressor.compress_chunk(i, data).unwrap()
+        }).collect()
+    }
+    
+    #[tokio::test]
+    async fn test_upload_cap_is_shared_by_receivers() {
+        let mut config = crate::config::Config::default().p2p;
+        config.max_upload_bps = Some(1024 * 1024);
+        let mut sender = new_client(config).await;
+        let addr = sender.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).await.unwrap();
+        // Two receivers of 2.5 MiB each come to 5 MiB, a second of it in the first burst
+        let chunks = incompressible_chunks(10);
+        let manifest = sender.prepare_manifest([9; 32], &chunks).unwrap();
+        let sender_id = sender.local_peer_id();
+        
+        let fetch = |addr: Multiaddr| async move {
+            let mut receiver = new_client(crate::config::Config::default().p2p).await;
+            receiver.reach_peer(sender_id, &[addr]).await.unwrap();
+            let manifest = receiver.request_manifest(sender_id, &hex::encode([9; 32])).await.unwrap();
+            receiver.fetch_chunks(sender_id, &manifest, |_| {}).await.unwrap().len()
+        };
+        let start = std::time::Instant::now();
+        let (served, first, second) = tokio::join!(
+            sender.serve_chunks(&manifest, chunks, 2, Duration::from_secs(60), |_| {}),
+            fetch(addr.clone()),
+            fetch(addr.clone())
+        );
+        let elapsed = start.elapsed();
+        
+        assert_eq!(served.unwrap().completed, 2);
+        assert_eq!((first, second), (10, 10));
+        assert!(elapsed >= Duration::from_millis(3500) && elapsed < Duration::from_secs(7), "{:?}", elapsed);
+        
+        // Each peer's rate is kept for `shr peer list`, and together they're held to the cap
+        let rates: Vec<u64> = sender.stats().stats().peers.values().map(|p| p.serve_bps).collect();
+        assert_eq!(rates.len(), 2);
+        assert!(rates.iter().all(|bps| *bps > 0) && rates.iter().sum::<u64>() < 2 * 1024 * 1024, "{:?}", rates);
+    }
+    
+    #[tokio::test]
+    async fn test_shutdown_lets_receivers_finish_but_takes_no_more() {
+        let shutdown = CancellationToken::new();
+        let mut config = crate::config::Config::default().p2p;
+        config.per_peer_max_bps = Some(512 * 1024);
+        let mut sender = new_client(config).await.with_shutdown(shutdown.clone());
+        let addr = sender.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).await.unwrap();
+        let chunks = incompressible_chunks(4);
+        let manifest = sender.prepare_manifest([16; 32], &chunks).unwrap();
+        let sender_id = sender.local_peer_id();
+        let mut first = new_client(crate::config::Config::default().p2p).await;
+        let mut late = new_client(crate::config::Config::default().p2p).await;
+        
+        let stop = shutdown.clone();
+        let on_event = move |event| {
+            if let ServeEvent::ChunkServed { .. } = event {
+                stop.cancel();
+            }
+        };
+        let (served, received, refused) = tokio::join!(
+            sender.serve_chunks(&manifest, chunks, 2, Duration::from_secs(60), on_event),
+            async {
+                first.reach_peer(sender_id, std::slice::from_ref(&addr)).await.unwrap();
+                let manifest = first.request_manifest(sender_id, &hex::encode([16; 32])).await.unwrap();
+                first.fetch_chunks(sender_id, &manifest, |_| {}).await
+            },
+            async {
+                shutdown.cancelled().await;
+                late.reach_peer(sender_id, std::slice::from_ref(&addr)).await.unwrap();
+                late.request_manifest(sender_id, &hex::encode([16; 32])).await
+            }
+        );
+        
+        assert_eq!(received.unwrap().len(), 4);
+        assert!(refused.is_err());
+        let stats = served.unwrap();
+        assert_eq!((stats.completed, stats.peers), (1, 1));
+    }
+    
+    #[tokio::test]
+    async fn test_shutdown_gives_up_after_the_grace_period() {
+        let shutdown = CancellationToken::new();
+        let mut config = crate::config::Config::default().p2p;
+        config.per_peer_max_bps = Some(256 * 1024);
+        config.shutdown_grace_ms = 300;
+        let mut sender = new_client(config).await.with_shutdown(shutdown.clone());
+        let addr = sender.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).await.unwrap();
+        // 2 MiB at 256 KiB/s, far longer than the grace period
+        let chunks = incompressible_chunks(8);
+        let manifest = sender.prepare_manifest([17; 32], &chunks).unwrap();
+        let sender_id = sender.local_peer_id();
+        let mut config = crate::config::Config::default().p2p;
+        config.chunk_timeout_ms = 1000;
+        config.chunk_retries = 0;
+        let mut receiver = new_client(config).await;
+        
+        let started = std::time::Instant::now();
+        let on_event = move |event| {
+            if let ServeEvent::PeerStarted(_) = event {
+                shutdown.cancel();
+            }
+        };
+        let (served, received) = tokio::join!(sender.serve_chunks(&manifest, chunks, 1, Duration::from_secs(60), on_event), async {
+            receiver.reach_peer(sender_id, &[addr]).await.unwrap();
+            let manifest = receiver.request_manifest(sender_id, &hex::encode([17; 32])).await.unwrap();
+            receiver.fetch_chunks(sender_id, &manifest, |_| {}).await
+        });
+        
+        assert!(received.is_err());
+        assert_eq!(served.unwrap().completed, 0);
+        assert!(started.elapsed() < Duration::from_secs(5), "{:?}", started.elapsed());
+    }
+    
+    #[tokio::test]
+    async fn test_download_cap_holds_back_requests() {
+        let (mut sender, addr) = listening_sender(CancellationToken::new()).await;
+        let chunks = incompressible_chunks(8);
+        let manifest = sender.prepare_manifest([10; 32], &chunks).unwrap();
+        let sender_id = sender.local_peer_id();
+        
+        let mut config = crate::config::Config::default().p2p;
+        config.max_download_bps = Some(1024 * 1024);
+        let mut receiver = new_client(config).await;
+        let start = std::time::Instant::now();
+        let (served, received) = tokio::join!(sender.serve_chunks(&manifest, chunks, 1, Duration::from_secs(60), |_| {}), async {
+            receiver.reach_peer(sender_id, &[addr]).await.unwrap();
+            let manifest = receiver.request_manifest(sender_id, &hex::encode([10; 32])).await.unwrap();
+            receiver.fetch_chunks(sender_id, &manifest, |_| {}).await.unwrap()
+        });
+        
+        // 2 MiB, the first of them in the burst
+        assert_eq!((served.unwrap().completed, received.len()), (1, 8));
+        assert!(start.elapsed() >= Duration::from_millis(900), "{:?}", start.elapsed());
+    }
+    
+    #[tokio::test]
+    async fn test_fetch_retries_then_gives_up_on_bad_chunks() {
+        let (mut sender, addr) = listening_sender(CancellationToken::new()).await;
+        let sender_id = sender.local_peer_id();
+        let compressor = crate::compression::ParallelCompressor::default();
+        let chunks: Vec<_> = (0..2).map(|i| compressor.compress_chunk(i, vec![7; 4096]).unwrap()).collect();
+        let manifest = sender.prepare_manifest([5; 32], &chunks).unwrap();
+        
+        // Chunk 1 claims the right hash, but its bytes don't decompress to it
+        let mut damaged = chunks.clone();
+        damaged[1] = compressor.compress_chunk(1, vec![8; 4096]).unwrap();
+        damaged[1].hash = chunks[1].hash;
+        
+        let mut config = crate::config::Config::default().p2p;
+        config.chunk_retries = 2;
+        let mut receiver = new_client(config).await.with_reputation(ReputationStore::in_memory(8));
+        // The bad chunk is never acknowledged, so the sender keeps serving until it's stopped
+        let cancel = sender.cancel.clone();
+        let (served, fetched) = tokio::join!(sender.serve_chunks(&manifest, damaged, 1, Duration::from_secs(60), |_| {}), async {
+            receiver.reach_peer(sender_id, &[addr]).await.unwrap();
+            let err = receiver.request_manifest(sender_id, &hex::encode([6; 32])).await.unwrap_err();
+            assert!(err.to_string().contains("has no file"), "{}", err);
+            
+            let manifest = receiver.request_manifest(sender_id, &hex::encode([5; 32])).await.unwrap();
+            let mut arrived = Vec::new();
+            let fetched = receiver.fetch_chunks(sender_id, &manifest, |c| arrived.push(c.index)).await;
+            cancel.cancel();
+            (fetched, arrived)
+        });
+        
+        assert!(matches!(served, Err(ShrLinkError::Cancelled)));
+        let (fetched, arrived) = fetched;
+        let err = fetched.unwrap_err();
+        assert!(err.to_string().contains("Chunk 1") && err.to_string().contains("after 3 attempts"), "{}", err);
+        assert_eq!(arrived, vec![0]);
+        assert!(receiver.reputation().get(&sender_id).is_some_and(|r| r.strikes > 2.0));
+    }
+    
+    fn drain(events: &mut tokio::sync::mpsc::UnboundedReceiver<TransferEvent>) -> Vec<TransferEvent> {
+        std::iter::from_fn(|| events.try_recv().ok()).collect()
+    }
+    
+    #[tokio::test]
+    async fn test_only_allowed_peers_are_served() {
+        let mut allowed = new_client(crate::config::Config::default().p2p).await;
+        let mut blocked = new_client(crate::config::Config::default().p2p).await;
+        let mut config = crate::config::Config::default().p2p;
+        config.allowed_peers = vec![allowed.local_peer_id().to_string()];
+        config.blocked_peers = vec![blocked.local_peer_id().to_string()];
+        let mut sender = new_client(config).await;
+        let addr = sender.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).await.unwrap();
+        let compressor = crate::compression::ParallelCompressor::default();
+        let chunks: Vec<_> = (0..3).map(|i| compressor.compress_chunk(i, vec![i as u8; 4096]).unwrap()).collect();
+        let manifest = sender.prepare_manifest([13; 32], &chunks).unwrap();
+        let sender_id = sender.local_peer_id();
+        
+        let (served, received) = tokio::join!(sender.serve_chunks(&manifest, chunks, 1, Duration::from_secs(60), |_| {}), async {
+            blocked.reach_peer(sender_id, std::slice::from_ref(&addr)).await.unwrap();
+            assert!(blocked.request_manifest(sender_id, &hex::encode([13; 32])).await.is_err());
+            let err = blocked.request_chunks(sender_id, &[0]).await.unwrap_err();
+            assert!(err.to_string().contains("doesn't offer chunk 0"), "{}", err);
+            
+            allowed.reach_peer(sender_id, &[addr]).await.unwrap();
+            let manifest = allowed.request_manifest(sender_id, &hex::encode([13; 32])).await.unwrap();
+            allowed.fetch_chunks(sender_id, &manifest, |_| {}).await.unwrap()
+        });
+        
+        let stats = served.unwrap();
+        assert_eq!(received.len(), 3);
+        assert_eq!((stats.completed, stats.peers, stats.refused), (1, 1, 2));
+    }
+    
+    #[tokio::test]
+    async fn test_receivers_that_cant_take_the_chunks_are_told_why() {
+        let lz4_only = Capabilities { codecs: vec!["lz4".to_string()], ..Capabilities::local() };
+        let mut limited = new_client(crate::config::Config::default().p2p).await.with_capabilities(lz4_only);
+        let mut capable = new_client(crate::config::Config::default().p2p).await;
+        let mut sender = new_client(crate::config::Config::default().p2p).await;
+        let addr = sender.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).await.unwrap();
+        let compressor = crate::compression::ParallelCompressor::default().with_algorithm(crate::compression::CompressionAlgorithm::Zstd);
+        let chunks: Vec<_> = (1..4).map(|i| compressor.compress_chunk(i, vec![i as u8; 4096]).unwrap()).collect();
+        let manifest = sender.prepare_manifest([17; 32], &chunks).unwrap();
+        let (sender_id, limited_id) = (sender.local_peer_id(), limited.local_peer_id());
+        let mut events = Vec::new();
+        
+        let (served, received) = tokio::join!(sender.serve_chunks(&manifest, chunks, 1, Duration::from_secs(60), |e| events.push(e)), async {
+            limited.reach_peer(sender_id, std::slice::from_ref(&addr)).await.unwrap();
+            let err = limited.request_manifest(sender_id, &hex::encode([17; 32])).await.unwrap_err();
+            let ShrLinkError::Incompatible(reason) = err else { panic!("{}", err) };
+            assert_eq!(*reason, Incompatibility::Codecs { unsupported: vec!["zstd".to_string()], supported: vec!["lz4".to_string()] });
+            
+            capable.reach_peer(sender_id, &[addr]).await.unwrap();
+            assert_eq!(capable.handshake(sender_id).await.unwrap(), capabilities::VERSION);
+            let manifest = capable.request_manifest(sender_id, &hex::encode([17; 32])).await.unwrap();
+            capable.fetch_chunks(sender_id, &manifest, |_| {}).await.unwrap()
+        });
+        
+        assert_eq!(served.unwrap().completed, 1);
+        assert_eq!(received.len(), 3);
+        assert!(events.iter().any(|e| matches!(e, ServeEvent::PeerIncompatible(peer, Incompatibility::Codecs { .. }) if *peer == limited_id)));
+    }
+    
+    #[tokio::test]
+    async fn test_pairing_hands_the_offer_to_the_right_code() {
+        let mut sender = new_client(crate::config::Config::default().p2p).await;
+        let mut receiver = new_client(crate::config::Config::default().p2p).await;
+        let outsider = PeerId::random();
+        let addr = sender.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).await.unwrap();
+        let code: PairingCode = "7-guitar-orbit-maple".parse().unwrap();
+        let elsewhere: PairingCode = "8-guitar-orbit-maple".parse().unwrap();
+        let (sender_id, receiver_id) = (sender.local_peer_id(), receiver.local_peer_id());
+        
+        let (paired, offer) = tokio::join!(sender.await_pairing(&code, "shr://offer", Duration::from_secs(30)), async {
+            receiver.reach_peer(sender_id, &[addr]).await.unwrap();
+            // A code with another number is someone else's, and doesn't use this one up
+            assert_eq!(receiver.pair(sender_id, &elsewhere).await.unwrap(), None);
+            receiver.pair(sender_id, &code).await.unwrap()
+        });
+        
+        assert_eq!(paired.unwrap(), receiver_id);
+        assert_eq!(offer.as_deref(), Some("shr://offer"));
+        assert!(sender.access.permits(&receiver_id));
+        assert!(!sender.access.permits(&outsider));
+    }
+    
+    #[tokio::test]
+    async fn test_pairing_with_the_wrong_code_fails_on_both_sides() {
+        let mut sender = new_client(crate::config::Config::default().p2p).await;
+        let mut receiver = new_client(crate::config::Config::default().p2p).await;
+        let addr = sender.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).await.unwrap();
+        let code: PairingCode = "7-guitar-orbit-maple".parse().unwrap();
+        let guess: PairingCode = "7-guitar-orbit-amber".parse().unwrap();
+        let sender_id = sender.local_peer_id();
+        
+        let (paired, offer) = tokio::join!(sender.await_pairing(&code, "shr://offer", Duration::from_secs(30)), async {
+            receiver.reach_peer(sender_id, &[addr]).await.unwrap();
+            receiver.pair(sender_id, &guess).await
+        });
+        
+        let err = paired.unwrap_err();
+        assert!(err.to_string().contains("wrong pairing code"), "{}", err);
+        let err = offer.unwrap_err();
+        assert!(err.to_string().contains("didn't match"), "{}", err);
+    }
+    
+    #[tokio::test]
+    async fn test_pairing_times_out() {
+        let mut sender = new_client(crate::config::Config::default().p2p).await;
+        let code = PairingCode::generate();
+        let err = sender.await_pairing(&code, "shr://offer", Duration::from_millis(100)).await.unwrap_err();
+        assert!(matches!(err, ShrLinkError::Timeout(_)), "{}", err);
+    }
+    
+    #[tokio::test]
+    async fn test_bad_peer_ids_in_config_are_an_error() {
+        let mut config = crate::config::Config::default().p2p;
+        config.blocked_peers = vec!["not-a-peer".to_string()];
+        let dir = tempfile::tempdir().unwrap();
+        config.identity_path = Some(dir.path().join(identity::IDENTITY_FILE));
+        assert!(matches!(P2PClient::new(config).await, Err(ShrLinkError::InvalidInput(_))));
+    }
+    
+    #[tokio::test]
+    async fn test_transfer_events_end_with_completed() {
+        let (mut sender, addr) = listening_sender(CancellationToken::new()).await;
+        let compressor = crate::compression::ParallelCompressor::default();
+        let chunks: Vec<_> = (0..4).map(|i| compressor.compress_chunk(i, vec![i as u8; 4096]).unwrap()).collect();
+        let bytes: usize = chunks.iter().map(|c| c.data.len()).sum();
+        let manifest = sender.prepare_manifest([11; 32], &chunks).unwrap();
+        let sender_id = sender.local_peer_id();
+        let mut receiver = new_client(crate::config::Config::default().p2p).await;
+        let receiver_id = receiver.local_peer_id();
+        let mut sent = sender.transfer_events();
+        let mut received = receiver.transfer_events();
+        
+        let (served, fetched) = tokio::join!(sender.serve_chunks(&manifest, chunks, 1, Duration::from_secs(60), |_| {}), async {
+            receiver.reach_peer(sender_id, &[addr]).await.unwrap();
+            let manifest = receiver.request_manifest(sender_id, &hex::encode([11; 32])).await.unwrap();
+            receiver.fetch_chunks(sender_id, &manifest, |_| {}).await
+        });
+        served.unwrap();
+        fetched.unwrap();
+        
+        let received = drain(&mut received);
+        assert_eq!(received.first(), Some(&TransferEvent::PeerConnected(sender_id)));
+        let indexes: Vec<_> = received.iter().filter_map(|e| match e {
+            TransferEvent::ChunkReceived { peer_id, index, .. } if *peer_id == sender_id => Some(*index),
+            _ => None,
+        }).collect();
+        assert_eq!(indexes, [0, 1, 2, 3]);
+        assert_eq!(received.last(), Some(&TransferEvent::Completed { chunks: 4, bytes }));
+        assert_eq!(received.iter().filter(|e| e.is_terminal()).count(), 1);
+        
+        let sent = drain(&mut sent);
+        let connected = sent.iter().position(|e| *e == TransferEvent::PeerConnected(receiver_id)).unwrap();
+        let first_chunk = sent.iter().position(|e| matches!(e, TransferEvent::ChunkSent { .. })).unwrap();
+        assert!(connected < first_chunk);
+        assert_eq!(sent.iter().filter(|e| matches!(e, TransferEvent::ChunkSent { peer_id, .. } if *peer_id == receiver_id)).count(), 4);
+        assert_eq!(sent.last(), Some(&TransferEvent::Completed { chunks: 4, bytes }));
+        assert_eq!(sent.iter().filter(|e| e.is_terminal()).count(), 1);
+    }
+    
+    #[tokio::test]
+    async fn test_transfer_events_end_with_failed() {
+        let (mut sender, addr) = listening_sender(CancellationToken::new()).await;
+        let sender_id = sender.local_peer_id();
+        let compressor = crate::compression::ParallelCompressor::default();
+        let chunks: Vec<_> = (0..2).map(|i| compressor.compress_chunk(i, vec![7; 4096]).unwrap()).collect();
+        let manifest = sender.prepare_manifest([12; 32], &chunks).unwrap();
+        let mut damaged = chunks.clone();
+        damaged[1] = compressor.compress_chunk(1, vec![8; 4096]).unwrap();
+        damaged[1].hash = chunks[1].hash;
+        
+        let mut config = crate::config::Config::default().p2p;
+        config.chunk_retries = 1;
+        let mut receiver = new_client(config).await.with_reputation(ReputationStore::in_memory(8));
+        let mut sent = sender.transfer_events();
+        let mut received = receiver.transfer_events();
+        let cancel = sender.cancel.clone();
+        let (served, fetched) = tokio::join!(sender.serve_chunks(&manifest, damaged, 1, Duration::from_secs(60), |_| {}), async {
+            receiver.reach_peer(sender_id, &[addr]).await.unwrap();
+            let manifest = receiver.request_manifest(sender_id, &hex::encode([12; 32])).await.unwrap();
+            let fetched = receiver.fetch_chunks(sender_id, &manifest, |_| {}).await;
+            cancel.cancel();
+            fetched
+        });
+        let err = fetched.unwrap_err();
+        
+        let received = drain(&mut received);
+        assert!(received.contains(&TransferEvent::Retry { peer_id: sender_id, index: 1 }));
+        assert_eq!(received.last(), Some(&TransferEvent::Failed(err.to_string())));
+        assert_eq!(received.iter().filter(|e| e.is_terminal()).count(), 1);
+        
+        // Cancelled while the receiver was still being answered
+        assert!(matches!(served, Err(ShrLinkError::Cancelled)));
+        let sent = drain(&mut sent);
+        assert_eq!(sent.last(), Some(&TransferEvent::Failed(ShrLinkError::Cancelled.to_string())));
+        assert_eq!(sent.iter().filter(|e| e.is_terminal()).count(), 1);
+    }
+    
+    #[tokio::test]
+    async fn test_transfer_stats_are_saved_after_each_transfer() {
+        let dir = tempfile::tempdir().unwrap();
+        let (sender_path, receiver_path) = (dir.path().join("sender.json"), dir.path().join("receiver.json"));
+        let (sender, addr) = listening_sender(CancellationToken::new()).await;
+        let mut sender = sender.with_stats(StatsStore::load(&sender_path));
+        let sender_id = sender.local_peer_id();
+        let mut receiver = new_client(crate::config::Config::default().p2p).await.with_stats(StatsStore::load(&receiver_path));
+        let receiver_id = receiver.local_peer_id();
+        let compressor = crate::compression::ParallelCompressor::default();
+        let chunks: Vec<_> = (0..3).map(|i| compressor.compress_chunk(i, vec![i as u8; 4096]).unwrap()).collect();
+        let bytes: u64 = chunks.iter().map(|c| c.data.len() as u64).sum();
+        let manifest = sender.prepare_manifest([13; 32], &chunks).unwrap();
+        
+        let (served, fetched) = tokio::join!(sender.serve_chunks(&manifest, chunks, 1, Duration::from_secs(60), |_| {}), async {
+            receiver.reach_peer(sender_id, &[addr]).await.unwrap();
+            let manifest = receiver.request_manifest(sender_id, &hex::encode([13; 32])).await.unwrap();
+            receiver.fetch_chunks(sender_id, &manifest, |_| {}).await
+        });
+        served.unwrap();
+        fetched.unwrap();
+        
+        // Read back from disk, as the next run would
+        let sent = StatsStore::load(&sender_path).stats().clone();
+        assert_eq!(sent.version, stats::STATS_VERSION);
+        assert_eq!((sent.chunks_sent, sent.bytes_sent, sent.transfers_completed), (3, bytes, 1));
+        assert_eq!(sent.peers[&receiver_id.to_string()].chunks_sent, 3);
+        let received = StatsStore::load(&receiver_path).stats().clone();
+        assert_eq!((received.chunks_received, received.bytes_received, received.transfers_completed), (3, bytes, 1));
+        assert_eq!(received.peers.len(), 1);
+        assert_eq!(received.peers[&sender_id.to_string()].bytes_received, bytes);
+        assert_eq!(received, *receiver.stats().stats());
+    }
+    
+    // A bare receiver with `keypair` that fires `requests` at the sender at `addr` as soon as it's
+    // connected, and returns once the sender hangs up on it
+    fn spawn_hostile_receiver(keypair: Keypair, addr: Multiaddr, mut requests: Vec<ChunkRequest>) -> tokio::task::JoinHandle<()> {
+        let mut config = crate::config::Config::default().p2p;
+        config.enable_mdns = false;
+        let mut receiver = swarm::build_swarm(keypair, &config).unwrap();
+        tokio::spawn(async move {
+            receiver.dial(addr).unwrap();
+            loop {
+                match receiver.select_next_some().await {
+                    SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                        for request in requests.drain(..) {
+                            receiver.behaviour_mut().chunks.send_request(&peer_id, request);
+                        }
+                    }
+                    SwarmEvent::ConnectionClosed { num_established: 0, .. } => return,
+                    _ => {}
+                }
+            }
+        })
+    }
+    
+    #[tokio::test]
+    async fn test_chunks_past_the_end_get_the_peer_cut_off() {
+        let cancel = CancellationToken::new();
+        let (mut sender, addr) = listening_sender(cancel.clone()).await;
+        let compressor = crate::compression::ParallelCompressor::default();
+        let chunks: Vec<_> = (0..3).map(|i| compressor.compress_chunk(i, vec![i as u8; 4096]).unwrap()).collect();
+        let manifest = sender.prepare_manifest([14; 32], &chunks).unwrap();
+        let keypair = Keypair::generate_ed25519();
+        let hostile = keypair.public().to_peer_id();
+        let mut events = Vec::new();
+        
+        // Once for the index no file of three chunks has, and again for coming back while banned
+        let (served, ()) = tokio::join!(sender.serve_chunks(&manifest, chunks.clone(), 1, Duration::from_secs(60), |event| events.push(event)), async {
+            let requests = vec![ChunkRequest::Chunk { index: usize::MAX }, ChunkRequest::Chunk { index: 3 }];
+            tokio::time::timeout(Duration::from_secs(10), spawn_hostile_receiver(keypair.clone(), addr.clone(), requests)).await.unwrap().unwrap();
+            let requests = vec![ChunkRequest::Chunk { index: 0 }];
+            tokio::time::timeout(Duration::from_secs(10), spawn_hostile_receiver(keypair.clone(), addr.clone(), requests)).await.unwrap().unwrap();
+            cancel.cancel();
+        });
+        assert!(matches!(served, Err(ShrLinkError::Cancelled)));
+        
+        let cut_off: Vec<_> = events.iter().filter_map(|e| match e {
+            ServeEvent::PeerCutOff(peer, reason) if *peer == hostile => Some(reason.as_str()),
+            _ => None,
+        }).collect();
+        assert_eq!(cut_off, [format!("asked for chunk {} of 3", usize::MAX)]);
+        assert!(!events.iter().any(|e| matches!(e, ServeEvent::PeerStarted(_))));
+        assert!(sender.requests.is_banned(&hostile));
+        assert_eq!(sender.stats().stats().violations, 1);
+        assert_eq!(sender.stats().stats().peers[&hostile.to_string()].violations, 1);
+    }
+    
+    #[tokio::test]
+    async fn test_peers_asking_too_often_are_cut_off() {
+        let mut config = crate::config::Config::default().p2p;
+        config.max_requests_per_sec = 5;
+        let mut sender = new_client(config).await;
+        let addr = sender.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).await.unwrap();
+        let compressor = crate::compression::ParallelCompressor::default();
+        let chunks: Vec<_> = (0..2).map(|i| compressor.compress_chunk(i, vec![i as u8; 4096]).unwrap()).collect();
+        let keypair = Keypair::generate_ed25519();
+        let hostile = keypair.public().to_peer_id();
+        
+        // Only to this peer, so the serve ends when it is disconnected
+        let requests = vec![ChunkRequest::Chunk { index: 0 }; 50];
+        let (sent, hung_up) = tokio::join!(sender.send_chunks(hostile, chunks), async {
+            tokio::time::timeout(Duration::from_secs(10), spawn_hostile_receiver(keypair, addr, requests)).await
+        });
+        hung_up.unwrap().unwrap();
+        assert!(matches!(sent, Err(ShrLinkError::Network(ref e)) if e.contains("disconnected")), "{:?}", sent);
+        assert!(sender.requests.is_banned(&hostile));
+        assert_eq!(sender.stats().stats().violations, 1);
+    }
+    
+    // A bare peer that answers every chunk request once its delay is up, however many are
+    // waiting, as a far-away sender would. Odd chunks come back in half the time, and the first
+    // request for `refused` gets nothing. With `drop_every`, that many requests in, counting
+    // acknowledgments, one goes unanswered as if lost on the way
+    async fn spawn_slow_sender(chunks: Vec<CompressedChunk>, latency: Duration, refused: Option<usize>, drop_every: Option<usize>) -> (PeerId, Multiaddr) {
+        use futures::stream::FuturesUnordered;
+        
+        let mut config = crate::config::Config::default().p2p;
+        config.enable_mdns = false;
+        let mut sender = swarm::build_swarm(Keypair::generate_ed25519(), &config).unwrap();
+        let peer_id = *sender.local_peer_id();
+        sender.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        let addr = loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = sender.select_next_some().await {
+                break address;
+            }
+        };
+        
+        tokio::spawn(async move {
+            let mut pending = FuturesUnordered::new();
+            let mut refused = refused;
+            let mut requests = 0;
+            loop {
+                tokio::select! {
+                    event = sender.select_next_some() => {
+                        if let SwarmEvent::Behaviour(BehaviourEvent::Chunks(swarm::Event::Message { message: Message::Request { request, channel, .. }, .. })) = event {
+                            requests += 1;
+                            if drop_every.is_some_and(|every| requests % every == 0) {
+                                drop(channel);
+                                continue;
+                            }
+                            let ChunkRequest::Chunk { index } = request else {
+                                let _ = sender.behaviour_mut().chunks.send_response(channel, ChunkResponse::NotOffered);
+                                continue;
+                            };
+                            let chunk = match refused {
+                                Some(refused_index) if refused_index == index => refused.take().and(None),
+                                _ => chunks.get(index).cloned().map(Arc::new),
+                            };
+                            let delay = if index % 2 == 1 { latency / 2 } else { latency };
+                            pending.push(async move {
+                                sleep(delay).await;
+                                (channel, chunk.map_or(ChunkResponse::NotOffered, ChunkResponse::Chunk))
+                            });
+                        }
+                    }
+                    Some((channel, chunk)) = pending.next(), if !pending.is_empty() => {
+                        let _ = sender.behaviour_mut().chunks.send_response(channel, chunk);
+                    }
+                }
+            }
+        });
+        
+        (peer_id, addr)
+    }
+    
+    #[tokio::test]
+    async fn test_fetch_keeps_a_window_of_requests_in_flight() {
+        let latency = Duration::from_millis(100);
+        let compressor = crate::compression::ParallelCompressor::default();
+        let chunks: Vec<_> = (0..16).map(|i| compressor.compress_chunk(i, vec![i as u8; 8192]).unwrap()).collect();
+        let manifest = ChunkManifest::from_chunks([3; 32], &chunks);
+        let (sender_id, addr) = spawn_slow_sender(chunks, latency, Some(3), None).await;
+        
+        let timed = |window: usize| {
+            let (addr, manifest) = (addr.clone(), manifest.clone());
+            async move {
+                let mut config = crate::config::Config::default().p2p;
+                config.max_inflight_chunks = window;
+                let mut receiver = new_client(config).await;
+                receiver.reach_peer(sender_id, &[addr]).await.unwrap();
+                
+                let start = std::time::Instant::now();
+                let mut arrived = Vec::new();
+                let received = receiver.fetch_chunks(sender_id, &manifest, |c| arrived.push(c.index)).await.unwrap();
+                (start.elapsed(), arrived, received)
+            }
+        };
+        
+        // The refused chunk is only refused once, to whichever receiver asks first
+        let (windowed, arrived, received) = timed(8).await;
+        let (serial, _, _) = timed(1).await;
+        
+        // Sixteen round trips one after another, against two windows' worth plus a retry
+        assert!(serial >= latency * 12, "{:?}", serial);
+        assert!(windowed * 3 < serial, "{:?} with a window against {:?} without", windowed, serial);
+        
+        // Answers came back out of order, the refused chunk after ones asked for alongside it,
+        // but the chunks are still in manifest order
+        assert_ne!(arrived, (0..16).collect::<Vec<_>>());
+        assert!(arrived.iter().position(|&i| i == 3) > arrived.iter().position(|&i| i == 7), "{:?}", arrived);
+        assert_eq!(received.iter().map(|c| c.index).collect::<Vec<_>>(), (0..16).collect::<Vec<_>>());
+    }
+    
+    #[tokio::test]
+    async fn test_fetch_survives_a_lossy_link() {
+        let compressor = crate::compression::ParallelCompressor::default();
+        let chunks: Vec<_> = (0..12).map(|i| compressor.compress_chunk(i, vec![i as u8; 4096]).unwrap()).collect();
+        let manifest = ChunkManifest::from_chunks([8; 32], &chunks);
+        let (sender_id, addr) = spawn_slow_sender(chunks, Duration::from_millis(5), None, Some(3)).await;
+        
+        // A chunk asked for again on its own can keep landing on a lost request, so it gets more goes
+        let mut config = crate::config::Config::default().p2p;
+        config.max_inflight_chunks = 4;
+        config.chunk_retries = 8;
+        let mut receiver = new_client(config).await;
+        receiver.reach_peer(sender_id, &[addr]).await.unwrap();
+        let received = receiver.fetch_chunks(sender_id, &manifest, |_| {}).await.unwrap();
+        assert_eq!(received.iter().map(|c| c.index).collect::<Vec<_>>(), (0..12).collect::<Vec<_>>());
+        assert!(receiver.acks.is_empty());
+    }
+    
+    #[tokio::test]
+    async fn test_stalled_chunks_time_out_and_are_retried() {
+        let compressor = crate::compression::ParallelCompressor::default();
+        let chunks: Vec<_> = (0..2).map(|i| compressor.compress_chunk(i, vec![i as u8; 4096]).unwrap()).collect();
+        let manifest = ChunkManifest::from_chunks([14; 32], &chunks);
+        // Chunk 1 is answered in 150ms, chunk 0 only after 300ms, past the timeout every time
+        let (sender_id, addr) = spawn_slow_sender(chunks, Duration::from_millis(300), None, None).await;
+        
+        let mut config = crate::config::Config::default().p2p;
+        config.chunk_timeout_ms = 200;
+        config.chunk_retries = 2;
+        let mut receiver = new_client(config).await;
+        let mut events = receiver.transfer_events();
+        receiver.reach_peer(sender_id, &[addr]).await.unwrap();
+        let err = receiver.fetch_chunks(sender_id, &manifest, |_| {}).await.unwrap_err();
+        
+        match &err {
+            ShrLinkError::Timeout(context) => assert!(context.contains("chunk 0") && context.contains("3 attempts"), "{}", context),
+            other => panic!("expected a timeout, got {}", other),
+        }
+        let events = drain(&mut events);
+        assert_eq!(events.iter().filter(|e| **e == TransferEvent::Retry { peer_id: sender_id, index: 0 }).count(), 2);
+        assert!(events.iter().any(|e| matches!(e, TransferEvent::ChunkReceived { index: 1, .. })));
+    }
+    
+    #[tokio::test]
+    async fn test_transfer_timeout_caps_the_whole_receive() {
+        let compressor = crate::compression::ParallelCompressor::default();
+        let chunks: Vec<_> = (0..8).map(|i| compressor.compress_chunk(i, vec![i as u8; 4096]).unwrap()).collect();
+        let manifest = ChunkManifest::from_chunks([15; 32], &chunks);
+        let (sender_id, addr) = spawn_slow_sender(chunks, Duration::from_millis(200), None, None).await;
+        
+        // Each chunk comes back in time, but one at a time they take over a second in all
+        let mut config = crate::config::Config::default().p2p;
+        config.max_inflight_chunks = 1;
+        config.transfer_timeout_ms = Some(500);
+        let mut receiver = new_client(config).await;
+        receiver.reach_peer(sender_id, &[addr]).await.unwrap();
+        let started = std::time::Instant::now();
+        let err = receiver.fetch_chunks(sender_id, &manifest, |_| {}).await.unwrap_err();
+        
+        assert!(started.elapsed() < Duration::from_secs(1), "{:?}", started.elapsed());
+        match &err {
+            ShrLinkError::Timeout(context) => assert!(context.contains("receiving") && context.contains("transfer_timeout_ms"), "{}", context),
+            other => panic!("expected a timeout, got {}", other),
+        }
+    }
+    
+    #[test]
+    fn test_retry_backoff_doubles_up_to_a_limit() {
+        assert_eq!(retry_backoff(1), RETRY_BACKOFF);
+        assert_eq!(retry_backoff(3), RETRY_BACKOFF * 4);
+        assert_eq!(retry_backoff(40), MAX_RETRY_BACKOFF);
+    }
+    
+    #[tokio::test]
+    async fn test_sent_chunks_count_once_acknowledged() {
+        let (mut sender, addr) = listening_sender(CancellationToken::new()).await;
+        let mut receiver = new_client(crate::config::Config::default().p2p).await;
+        let compressor = crate::compression::ParallelCompressor::default();
+        let chunks: Vec<_> = (0..3).map(|i| compressor.compress_chunk(i, vec![i as u8; 1024]).unwrap()).collect();
+        
+        let (progress, received) = tokio::join!(sender.send_chunks(receiver.local_peer_id(), chunks), async {
+            let peer_id = receiver.connect_to_peer(addr).await.unwrap();
+            // Chunk 1 arrives but is never acknowledged, as if it had been lost, and is asked for again
+            let lost = receiver.swarm.behaviour_mut().chunks.send_request(&peer_id, ChunkRequest::Chunk { index: 1 });
+            loop {
+                if let SwarmEvent::Behaviour(BehaviourEvent::Chunks(swarm::Event::Message { message: Message::Response { request_id, .. }, .. })) = receiver.next_event().await {
+                    if request_id == lost {
+                        break;
+                    }
+                }
+            }
+            // A wrong hash doesn't count either
+            let forged = ChunkRequest::Ack { index: 0, hash: [0; 32] };
+            let request_id = receiver.swarm.behaviour_mut().chunks.send_request(&peer_id, forged);
+            receiver.acks.insert(request_id);
+            receiver.request_chunks(peer_id, &[0, 1, 2]).await
+        });
+        
+        let progress = progress.unwrap();
+        assert_eq!(received.unwrap().len(), 3);
+        assert_eq!((progress.chunks_sent, progress.chunks_retried), (3, 1));
+    }
+    
+    #[tokio::test]
+    async fn test_resend_only_sends_changed_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = crate::cache::ChunkCache::open(dir.path()).unwrap();
+        let compressor = crate::compression::ParallelCompressor::new(1024, 1).unwrap();
+        let v1: Vec<u8> = (0..8 * 1024u32).map(|i| (i * 7 % 253) as u8).collect();
+        let mut v2 = v1.clone();
+        v2[3 * 1024 + 5] ^= 0xFF;
+        
+        // The receiver kept v1's chunks from the first transfer
+        for chunk in compressor.compress_bytes(&v1).unwrap().chunks {
+            cache.put(&chunk).unwrap();
+        }
+        
+        let result = compressor.compress_bytes(&v2).unwrap();
+        let (mut sender, addr) = listening_sender(CancellationToken::new()).await;
+        let sender_id = sender.local_peer_id();
+        let manifest = sender.prepare_manifest(result.file_hash, &result.chunks).unwrap();
+        let mut receiver = new_client(crate::config::Config::default().p2p).await.with_cache(cache.clone());
+        
+        // The serve offers the receiver the manifest, hears it has all but one chunk cached, and
+        // counts it done once that one is acknowledged
+        let mut received = None;
+        let served = tokio::select! {
+            served = sender.serve_chunks(&manifest, result.chunks.clone(), 1, Duration::from_secs(60), |_| {}) => served.unwrap(),
+            _ = async {
+                receiver.reach_peer(sender_id, std::slice::from_ref(&addr)).await.unwrap();
+                let manifest = receiver.request_manifest(sender_id, &hex::encode(result.file_hash)).await.unwrap();
+                let have = manifest.entries.iter().map(|e| cache.get(&e.hash).map(|chunk| CompressedChunk { index: e.index, ..chunk })).collect();
+                received = Some(receiver.resume_chunks(sender_id, &manifest, have, |_, _| {}).await.unwrap());
+                loop {
+                    let event = receiver.next_event().await;
+                    receiver.refuse_requests(event);
+                }
+            } => unreachable!(),
+        };
+        assert_eq!((served.chunks_served, served.completed, served.receipts), (1, 1, 0));
+        
+        let chunks = received.unwrap();
+        assert_eq!(compressor.decompress_chunks_parallel(&chunks).unwrap().concat(), v2);
+        
+        // Offered directly, the same manifest comes back with the one changed chunk missing
+        let mut receiver = new_client(crate::config::Config::default().p2p).await.with_cache(cache);
+        let receiver_id = receiver.local_peer_id();
+        let have = tokio::select! {
+            _ = async {
+                receiver.fetched_manifests.insert((sender_id, result.file_hash));
+                receiver.connect_to(sender_id, &[addr]).await.unwrap();
+                loop {
+                    let event = receiver.next_event().await;
+                    receiver.refuse_requests(event);
+                }
+            } => unreachable!(),
+            have = async {
+                while !sender.swarm.is_connected(&receiver_id) {
+                    let event = sender.next_event().await;
+                    sender.refuse_requests(event);
+                }
+                sender.offer_manifest(receiver_id, &manifest).await.unwrap()
+            } => have,
+        };
+        assert_eq!(have.len(), 7);
+        assert!(!have.contains(&result.chunks[3].hash));
+    }
+    
+    #[tokio::test]
+    async fn test_discover_peers_on_local_network() {
+        let config = crate::config::Config::default().p2p;
+        let mut client = new_client(config.clone()).await;
+        let mut announcer = new_client(config.clone()).await;
+        let announcer_id = announcer.local_peer_id();
+        let addr = announcer.listen_on("/ip4/0.0.0.0/tcp/0".parse().unwrap()).await.unwrap();
+        let chunk = crate::compression::ParallelCompressor::default().compress_chunk(0, vec![3; 1024]).unwrap();
+        let client_id = client.local_peer_id();
+        // Handed back so its connections outlive the send until the client has its answer
+        let serving = tokio::spawn(async move {
+            let progress = announcer.send_chunks(client_id, vec![chunk]).await;
+            (progress, announcer)
+        });
+        
+        // Other tests' clients may answer too, so only this one is looked for
+        let timeout = Duration::from_millis(config.timeout_ms);
+        let found = tokio::time::timeout(timeout, async {
+            loop {
+                let peers = client.discover_peers().await.unwrap();
+                if let Some(peer) = peers.into_iter().find(|p| p.peer_id == announcer_id) {
+                    return peer;
+                }
+            }
+        })
+        .await
+        .expect("announcer not discovered in time");
+        let port = |a: &Multiaddr| a.iter().find_map(|p| match p {
+            libp2p::multiaddr::Protocol::Tcp(port) => Some(port),
+            _ => None,
+        });
+        assert!(found.addrs.iter().any(|a| port(a) == port(&addr)), "{:?}", found.addrs);
+        
+        // Discovered addresses are dialable by peer id alone
+        assert_eq!(client.receive_chunks(announcer_id, 1).await.unwrap().len(), 1);
+        assert_eq!(serving.await.unwrap().0.unwrap().chunks_sent, 1);
+        
+        let mut config = config;
+        config.enable_mdns = false;
+        let mut client = new_client(config).await;
+        assert!(client.discover_peers().await.unwrap().is_empty());
+    }
+    
+    #[tokio::test]
+    async fn test_browse_finds_only_what_is_announced() {
+        let mut config = crate::config::Config::default().p2p;
+        config.announce = true;
+        let cancel = CancellationToken::new();
+        let mut sharer = new_client(config).await.with_cancellation(cancel.clone());
+        let mut quiet = new_client(crate::config::Config::default().p2p).await.with_cancellation(cancel.clone());
+        let chunks = vec![crate::compression::ParallelCompressor::default().compress_chunk(0, vec![3; 1024]).unwrap()];
+        let manifest = sharer.prepare_manifest([7; 32], &chunks).unwrap();
+        let mut ids = Vec::new();
+        for client in [&mut sharer, &mut quiet] {
+            client.listen_on("/ip4/0.0.0.0/tcp/0".parse().unwrap()).await.unwrap();
+            let peer_id = client.local_peer_id();
+            client.announce(Announcement {
+                file_name: "notes.txt".to_string(),
+                size: 1024,
+                file_hash: hex::encode([7; 32]),
+                peer_id: peer_id.to_string(),
+                url: create_shr_url(peer_id, &hex::encode([7; 32])),
+            });
+            ids.push(peer_id.to_string());
+        }
+        let mut browser = new_client(crate::config::Config::default().p2p).await;
+        
+        // Other tests' clients may be on the network too, so only these two are looked at
+        let (_, _, found) = tokio::join!(
+            sharer.serve_chunks(&manifest, chunks.clone(), 1, Duration::from_secs(60), |_| {}),
+            quiet.serve_chunks(&manifest, chunks.clone(), 1, Duration::from_secs(60), |_| {}),
+            async {
+                let found = browser.browse(Duration::from_secs(8)).await;
+                cancel.cancel();
+                found
+            }
+        );
+        let found = found.unwrap();
+        assert!(found.iter().any(|a| a.peer_id == ids[0] && a.file_name == "notes.txt"), "{:?}", found);
+        assert!(!found.iter().any(|a| a.peer_id == ids[1]), "{:?}", found);
+        
+        let mut config = crate::config::Config::default().p2p;
+        config.enable_mdns = false;
+        assert!(matches!(new_client(config).await.browse(Duration::from_secs(1)).await, Err(ShrLinkError::InvalidInput(_))));
+    }
+    
+    #[test]
+    fn test_hybrid_url_roundtrip() {
+        let peer_id = PeerId::random();
+        let fallback = "http://localhost:8080/download/a%20b.shr?x=1&y=2";
+        
+        let file_hash = hex::encode([7u8; 32]);
+        
+        let url = create_hybrid_url(peer_id, &file_hash, fallback);
+        assert_eq!(parse_hybrid_url(&url).unwrap(), (peer_id, file_hash.clone(), Some(fallback.to_string())));
+        assert_eq!(parse_shr_url(&url).unwrap(), (peer_id, file_hash.clone()));
+        
+        let plain = create_shr_url(peer_id, &file_hash);
+        assert_eq!(parse_hybrid_url(&plain).unwrap().2, None);
+    }
+    
+    #[test]
+    fn test_invalid_shr_url() {
+        assert!(parse_shr_url("http://example.com").is_err());
+        assert!(parse_shr_url("shr://invalid").is_err());
+        
+        // The hash has to be a whole BLAKE3 hash in hex
+        let peer_id = PeerId::random();
+        let full = hex::encode([7u8; 32]);
+        assert!(parse_shr_url(&create_shr_url(peer_id, &full)).is_ok());
+        for hash in ["abc123", &full[..63], &format!("{}0", full), &format!("{}g", &full[..63])] {
+            let err = parse_shr_url(&create_shr_url(peer_id, hash)).unwrap_err();
+            assert!(err.to_string().contains("64 hex digits"), "{}", err);
+        }
     }
 }
@@ -0,0 +1,280 @@
+use curve25519_dalek::constants::RISTRETTO_BASEPOINT_POINT;
+use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
+use curve25519_dalek::scalar::Scalar;
+use hkdf::Hkdf;
+use libp2p::PeerId;
+use rand::{Rng, RngCore};
+use sha2::{Digest, Sha256, Sha512};
+use std::fmt;
+use std::str::FromStr;
+use subtle::ConstantTimeEq;
+use zeroize::Zeroize;
+use crate::crypto::SecretKey;
+use crate::{Result, ShrLinkError};
+
+// Words after the number in a code, each one of 256. A code is used up by the first attempt at
+// it, so a guess gets one go at 24 bits
+pub const CODE_WORDS: usize = 3;
+// The number is only what a code is looked up by, and isn't secret
+const MAX_NAMEPLATE: u16 = 999;
+
+const WORDS: [&str; 256] = [
+    "acid", "acorn", "actor", "agent", "alarm", "album", "alpha", "amber", "anchor", "angle", "apple", "apron", "arena", "arrow", "aspen", "atlas",
+    "badge", "baker", "bamboo", "banjo", "barn", "basil", "beach", "beaver", "berry", "bison", "blade", "blanket", "bloom", "bonus", "bottle", "brave",
+    "bread", "brick", "bridge", "bronze", "brush", "bucket", "bugle", "butter", "cabin", "cactus", "camel", "candle", "canoe", "canyon", "carbon", "carpet",
+    "castle", "cedar", "cello", "chalk", "cherry", "chess", "chief", "cider", "circle", "citrus", "clay", "cliff", "clover", "cobalt", "cocoa", "comet",
+    "copper", "coral", "cotton", "cougar", "crane", "crater", "cricket", "crystal", "cube", "cypress", "dagger", "daisy", "dancer", "delta", "denim", "desert",
+    "diesel", "dingo", "disco", "dolphin", "donkey", "dragon", "drum", "eagle", "echo", "ember", "emerald", "engine", "falcon", "fern", "ferry", "fiddle",
+    "flame", "flint", "forest", "fossil", "fox", "galaxy", "garden", "garlic", "gecko", "ginger", "glacier", "globe", "goblin", "granite", "grape", "gravel",
+    "guitar", "hammer", "harbor", "hazel", "helmet", "hermit", "hickory", "honey", "hornet", "hungry", "husky", "igloo", "indigo", "iris", "island", "ivory",
+    "jacket", "jaguar", "jasmine", "jelly", "jewel", "jigsaw", "jungle", "kayak", "kettle", "kiwi", "koala", "ladder", "lagoon", "lantern", "laser", "lemon",
+    "lilac", "lily", "linen", "lizard", "llama", "lobster", "locket", "lotus", "lunar", "magnet", "mango", "maple", "marble", "meadow", "melon", "mercury",
+    "meteor", "mint", "mirror", "mocha", "monkey", "mosaic", "moss", "muffin", "nectar", "needle", "nickel", "noodle", "nutmeg", "oasis", "ocean", "olive",
+    "onyx", "opal", "orbit", "orchid", "otter", "oyster", "paddle", "panda", "papaya", "parrot", "pepper", "pebble", "pelican", "penguin", "piano", "pickle",
+    "pilot", "pine", "pirate", "pixel", "planet", "plum", "polar", "pony", "poppy", "potato", "prism", "pumpkin", "puzzle", "quartz", "quill", "rabbit",
+    "radar", "radish", "raven", "reef", "ribbon", "river", "robin", "rocket", "ruby", "saddle", "saffron", "salmon", "sandal", "satin", "scarlet", "shadow",
+    "silver", "sketch", "sloth", "spider", "sponge", "spruce", "squid", "statue", "stone", "sugar", "summit", "sunset", "swan", "tango", "teapot", "thunder",
+    "tiger", "timber", "toast", "tomato", "topaz", "torch", "tulip", "tundra", "turtle", "velvet", "violet", "walrus", "willow", "yacht", "zebra", "zephyr",
+];
+
+// What a receiver types instead of a shr:// URL, like 7-hungry-walrus-copper
+#[derive(Clone, PartialEq, Eq)]
+pub struct PairingCode {
+    nameplate: u16,
+    words: Vec<&'static str>,
+}
+
+impl PairingCode {
+    pub fn generate() -> Self {
+        let mut rng = rand::rngs::OsRng;
+        Self {
+            nameplate: rng.gen_range(1..=MAX_NAMEPLATE),
+            words: (0..CODE_WORDS).map(|_| WORDS[rng.gen::<u8>() as usize]).collect(),
+        }
+    }
+
+    pub fn nameplate(&self) -> u16 {
+        self.nameplate
+    }
+}
+
+impl fmt::Display for PairingCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.nameplate, self.words.join("-"))
+    }
+}
+
+// The words stay out of logs
+impl fmt::Debug for PairingCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PairingCode({}-<redacted>)", self.nameplate)
+    }
+}
+
+impl FromStr for PairingCode {
+    type Err = ShrLinkError;
+
+    fn from_str(code: &str) -> Result<Self> {
+        let invalid = || ShrLinkError::InvalidInput(format!("A pairing code is a number and {} words, like 7-hungry-walrus-copper", CODE_WORDS));
+        let code = code.trim().to_ascii_lowercase();
+        let mut parts = code.split('-');
+        let nameplate = parts
+            .next()
+            .and_then(|n| n.parse().ok())
+            .filter(|n| (1..=MAX_NAMEPLATE).contains(n))
+            .ok_or_else(invalid)?;
+        let words = parts
+            .map(|word| {
+                WORDS
+                    .iter()
+                    .find(|&&w| w == word)
+                    .copied()
+                    .ok_or_else(|| ShrLinkError::InvalidInput(format!("{} isn't a word pairing codes are made of", word)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if words.len() != CODE_WORDS {
+            return Err(invalid());
+        }
+        Ok(Self { nameplate, words })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    // Starts the exchange, with the code it was given
+    Receiver,
+    Sender,
+}
+
+// One side of SPAKE2 over Ristretto255, keyed by the whole code. Only sides with the same code
+// end up with the same keys, and an eavesdropper or a side with the wrong code learns nothing
+// about the right one beyond that it wasn't theirs
+pub struct Spake2 {
+    side: Side,
+    secret: Scalar,
+    password: Scalar,
+    message: [u8; 32],
+}
+
+// What a finished exchange agrees on: each side proves it has the same keys with its own
+// confirmation, and the shr:// URL goes over sealed with `offer`
+pub struct SessionKeys {
+    pub sender_confirmation: [u8; 32],
+    pub receiver_confirmation: [u8; 32],
+    pub offer: SecretKey,
+}
+
+impl Spake2 {
+    pub fn start(side: Side, code: &PairingCode) -> Self {
+        let mut wide = [0u8; 64];
+        rand::rngs::OsRng.fill_bytes(&mut wide);
+        let secret = Scalar::from_bytes_mod_order_wide(&wide);
+        wide.zeroize();
+
+        let password = code_scalar(code);
+        let blind = match side {
+            Side::Receiver => blinding_point(b"M"),
+            Side::Sender => blinding_point(b"N"),
+        };
+        let message = (RISTRETTO_BASEPOINT_POINT * secret + blind * password).compress().to_bytes();
+        Self { side, secret, password, message }
+    }
+
+    pub fn message(&self) -> [u8; 32] {
+        self.message
+    }
+
+    // Both sides name the same two peers, as the connection between them proved who they are
+    pub fn finish(self, theirs: &[u8; 32], sender: &PeerId, receiver: &PeerId) -> Result<SessionKeys> {
+        let malformed = || ShrLinkError::InvalidInput("Malformed pairing message".to_string());
+        let their_point = CompressedRistretto(*theirs).decompress().ok_or_else(malformed)?;
+        let their_blind = match self.side {
+            Side::Receiver => blinding_point(b"N"),
+            Side::Sender => blinding_point(b"M"),
+        };
+        let shared = (their_point - their_blind * self.password) * self.secret;
+        if their_point == RistrettoPoint::default() || shared == RistrettoPoint::default() {
+            return Err(malformed());
+        }
+
+        let (receiver_message, sender_message) = match self.side {
+            Side::Receiver => (self.message, *theirs),
+            Side::Sender => (*theirs, self.message),
+        };
+        let mut transcript = Sha256::new();
+        for part in [
+            b"shrlink pairing 1".as_slice(),
+            &sender.to_bytes(),
+            &receiver.to_bytes(),
+            &receiver_message,
+            &sender_message,
+            shared.compress().as_bytes(),
+            self.password.as_bytes(),
+        ] {
+            transcript.update((part.len() as u64).to_le_bytes());
+            transcript.update(part);
+        }
+
+        let hkdf = Hkdf::<Sha256>::new(None, &transcript.finalize());
+        let expand = |label: &[u8]| {
+            let mut key = [0u8; 32];
+            hkdf.expand(label, &mut key).expect("32 bytes is a valid HKDF-SHA256 length");
+            key
+        };
+        Ok(SessionKeys {
+            sender_confirmation: expand(b"sender confirmation"),
+            receiver_confirmation: expand(b"receiver confirmation"),
+            offer: SecretKey::from_bytes(expand(b"offer")),
+        })
+    }
+}
+
+impl Drop for Spake2 {
+    fn drop(&mut self) {
+        self.secret.zeroize();
+        self.password.zeroize();
+    }
+}
+
+// Without giving away through timing how much of a confirmation matched
+pub fn confirms(expected: &[u8; 32], received: &[u8; 32]) -> bool {
+    expected.ct_eq(received).into()
+}
+
+// M and N, from hashes, so no one knows their discrete logs
+fn blinding_point(name: &[u8]) -> RistrettoPoint {
+    let mut wide = [0u8; 64];
+    wide.copy_from_slice(&Sha512::new().chain_update(b"shrlink pairing SPAKE2 ").chain_update(name).finalize());
+    RistrettoPoint::from_uniform_bytes(&wide)
+}
+
+fn code_scalar(code: &PairingCode) -> Scalar {
+    let mut wide = [0u8; 64];
+    wide.copy_from_slice(&Sha512::new().chain_update(b"shrlink pairing code ").chain_update(code.to_string()).finalize());
+    let scalar = Scalar::from_bytes_mod_order_wide(&wide);
+    wide.zeroize();
+    scalar
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exchange(sender_code: &PairingCode, receiver_code: &PairingCode) -> (SessionKeys, SessionKeys) {
+        let (sender, receiver) = (PeerId::random(), PeerId::random());
+        let sending = Spake2::start(Side::Sender, sender_code);
+        let receiving = Spake2::start(Side::Receiver, receiver_code);
+        let (to_receiver, to_sender) = (sending.message(), receiving.message());
+        (
+            sending.finish(&to_sender, &sender, &receiver).unwrap(),
+            receiving.finish(&to_receiver, &sender, &receiver).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_codes_parse_back() {
+        for _ in 0..100 {
+            let code = PairingCode::generate();
+            assert_eq!(code.to_string().parse::<PairingCode>().unwrap(), code);
+        }
+        let code: PairingCode = " 7-Hungry-walrus-copper\n".parse().unwrap();
+        assert_eq!((code.nameplate(), code.to_string().as_str()), (7, "7-hungry-walrus-copper"));
+        assert!(!format!("{:?}", code).contains("walrus"));
+
+        for bad in ["", "7", "7-hungry-walrus", "7-hungry-walrus-copper-toast", "0-hungry-walrus-copper", "hungry-walrus-copper-7", "7-hungry-walrus-platypus"] {
+            assert!(matches!(bad.parse::<PairingCode>(), Err(ShrLinkError::InvalidInput(_))), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_same_code_agrees_on_keys() {
+        let code = PairingCode::generate();
+        let (sender, receiver) = exchange(&code, &code);
+        assert!(confirms(&sender.sender_confirmation, &receiver.sender_confirmation));
+        assert!(confirms(&sender.receiver_confirmation, &receiver.receiver_confirmation));
+        assert_eq!(sender.offer.expose_secret(), receiver.offer.expose_secret());
+        assert_ne!(sender.sender_confirmation, sender.receiver_confirmation);
+    }
+
+    #[test]
+    fn test_wrong_code_agrees_on_nothing() {
+        let code: PairingCode = "7-hungry-walrus-copper".parse().unwrap();
+        // One word off is no closer than all of them
+        for guess in ["7-hungry-walrus-cotton", "8-hungry-walrus-copper", "7-acid-acid-acid"] {
+            let (sender, receiver) = exchange(&code, &guess.parse().unwrap());
+            assert!(!confirms(&sender.sender_confirmation, &receiver.sender_confirmation), "{}", guess);
+            assert!(!confirms(&sender.receiver_confirmation, &receiver.receiver_confirmation), "{}", guess);
+            assert_ne!(sender.offer.expose_secret(), receiver.offer.expose_secret());
+        }
+    }
+
+    #[test]
+    fn test_malformed_messages_are_refused() {
+        let code = PairingCode::generate();
+        let (sender, receiver) = (PeerId::random(), PeerId::random());
+        assert!(Spake2::start(Side::Sender, &code).finish(&[0xff; 32], &sender, &receiver).is_err());
+        // The identity point isn't a message anyone honest could have sent
+        let identity = RistrettoPoint::default().compress().to_bytes();
+        assert!(Spake2::start(Side::Sender, &code).finish(&identity, &sender, &receiver).is_err());
+    }
+}
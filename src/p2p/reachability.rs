@@ -0,0 +1,337 @@
+use libp2p::multiaddr::Protocol;
+use libp2p::Multiaddr;
+use std::fmt;
+use std::future::Future;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NatStatus {
+    // Peers dialed us back on these addresses
+    Public(Vec<Multiaddr>),
+    Private,
+    Unknown,
+}
+
+// What the router made of the request to forward the listening port
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PortMapping {
+    // `enable_upnp` is off
+    Disabled,
+    // No answer from the router yet, or nothing listening to map
+    Pending,
+    // The external addresses it forwards to us
+    Mapped(Vec<Multiaddr>),
+    Failed(String),
+}
+
+impl PortMapping {
+    // As `shr doctor` reports it
+    pub fn check(&self) -> std::result::Result<Multiaddr, String> {
+        match self {
+            PortMapping::Disabled => Err("port mapping is not enabled (p2p.enable_upnp)".to_string()),
+            PortMapping::Pending => Err("no answer from the router yet".to_string()),
+            PortMapping::Mapped(addrs) => addrs.first().cloned().ok_or_else(|| "the mapping lapsed".to_string()),
+            PortMapping::Failed(e) => Err(e.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Direct,
+    HolePunchable,
+    RelayOnly,
+    Isolated,
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            Verdict::Direct => "direct",
+            Verdict::HolePunchable => "hole-punchable",
+            Verdict::RelayOnly => "relay-only",
+            Verdict::Isolated => "isolated",
+        };
+        f.write_str(text)
+    }
+}
+
+// How a connection to a peer goes, so a slow transfer can be put down to a relay
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionPath {
+    Direct(Multiaddr),
+    Relayed(Multiaddr),
+}
+
+impl ConnectionPath {
+    pub fn of(remote: &Multiaddr) -> Self {
+        if remote.iter().any(|p| matches!(p, Protocol::P2pCircuit)) {
+            ConnectionPath::Relayed(remote.clone())
+        } else {
+            ConnectionPath::Direct(remote.clone())
+        }
+    }
+}
+
+impl fmt::Display for ConnectionPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionPath::Direct(addr) => write!(f, "directly at {}", addr),
+            ConnectionPath::Relayed(addr) => write!(f, "through a relay at {}", addr),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check<T> {
+    pub outcome: std::result::Result<T, String>,
+    pub duration: Duration,
+}
+
+impl<T> Check<T> {
+    pub fn ok(value: T, duration: Duration) -> Self {
+        Self { outcome: Ok(value), duration }
+    }
+
+    pub fn failed(error: impl Into<String>, duration: Duration) -> Self {
+        Self { outcome: Err(error.into()), duration }
+    }
+
+    pub fn value(&self) -> Option<&T> {
+        self.outcome.as_ref().ok()
+    }
+
+    // Runs one check under the probe's deadline so a hung dial can't stall the whole report
+    pub async fn run<F>(timeout: Duration, check: F) -> Self
+    where
+        F: Future<Output = std::result::Result<T, String>>,
+    {
+        let start = Instant::now();
+        let outcome = match tokio::time::timeout(timeout, check).await {
+            Ok(outcome) => outcome,
+            Err(_) => Err(format!("timed out after {:?}", timeout)),
+        };
+        Self { outcome, duration: start.elapsed() }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReachabilityReport {
+    pub verdict: Verdict,
+    pub autonat: Check<NatStatus>,
+    pub upnp: Check<Multiaddr>,
+    pub relay: Check<Multiaddr>,
+    // Addresses peers told us they see us on, confirmed or not
+    pub observed_addrs: Vec<Multiaddr>,
+    pub duration: Duration,
+}
+
+impl ReachabilityReport {
+    pub fn aggregate(
+        autonat: Check<NatStatus>,
+        upnp: Check<Multiaddr>,
+        relay: Check<Multiaddr>,
+        observed_addrs: Vec<Multiaddr>,
+        duration: Duration,
+    ) -> Self {
+        let publicly_dialable = matches!(autonat.value(), Some(NatStatus::Public(addrs)) if !addrs.is_empty());
+        let relay_reachable = relay.value().is_some();
+
+        // A working port mapping only counts once something outside confirms it, otherwise a
+        // mapping on a double-NATed router would be reported as direct
+        let verdict = if publicly_dialable {
+            Verdict::Direct
+        } else if relay_reachable && (upnp.value().is_some() || observed_addrs.iter().any(is_public)) {
+            Verdict::HolePunchable
+        } else if relay_reachable {
+            Verdict::RelayOnly
+        } else {
+            Verdict::Isolated
+        };
+
+        Self {
+            verdict,
+            autonat,
+            upnp,
+            relay,
+            observed_addrs,
+            duration,
+        }
+    }
+
+    // With hole punching turned off, a relay is as good as it gets
+    pub fn without_holepunching(mut self) -> Self {
+        if self.verdict == Verdict::HolePunchable {
+            self.verdict = Verdict::RelayOnly;
+        }
+        self
+    }
+
+    pub fn confirmed_addrs(&self) -> &[Multiaddr] {
+        match self.autonat.value() {
+            Some(NatStatus::Public(addrs)) => addrs,
+            _ => &[],
+        }
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec![format!("Verdict: {}", self.verdict)];
+
+        lines.push(describe_check("AutoNAT", &self.autonat, |status| match status {
+            NatStatus::Public(addrs) => format!("public on {} address(es)", addrs.len()),
+            NatStatus::Private => "behind NAT".to_string(),
+            NatStatus::Unknown => "no answer".to_string(),
+        }));
+        lines.push(describe_check("UPnP", &self.upnp, |addr| format!("mapped {}", addr)));
+        lines.push(describe_check("Relay", &self.relay, |addr| format!("reservation at {}", addr)));
+
+        for addr in self.confirmed_addrs() {
+            lines.push(format!("  confirmed {}", addr));
+        }
+        for addr in &self.observed_addrs {
+            lines.push(format!("  observed {}", addr));
+        }
+
+        lines
+    }
+}
+
+fn describe_check<T>(name: &str, check: &Check<T>, describe: impl Fn(&T) -> String) -> String {
+    match &check.outcome {
+        Ok(value) => format!("{:<8} ok     {:>6}ms  {}", name, check.duration.as_millis(), describe(value)),
+        Err(e) => format!("{:<8} failed {:>6}ms  {}", name, check.duration.as_millis(), e),
+    }
+}
+
+pub fn is_public(addr: &Multiaddr) -> bool {
+    addr.iter().any(|p| match p {
+        Protocol::Ip4(ip) => is_public_ip(IpAddr::V4(ip)),
+        Protocol::Ip6(ip) => is_public_ip(IpAddr::V6(ip)),
+        Protocol::Dns(_) | Protocol::Dns4(_) | Protocol::Dns6(_) => true,
+        _ => false,
+    })
+}
+
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            // 100.64.0.0/10 is carrier-grade NAT space
+            let cgnat = ip.octets()[0] == 100 && (ip.octets()[1] & 0xc0) == 64;
+            !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified() || cgnat)
+        }
+        IpAddr::V6(ip) => !(ip.is_loopback() || ip.is_unspecified() || (ip.segments()[0] & 0xfe00) == 0xfc00 || (ip.segments()[0] & 0xffc0) == 0xfe80),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> Multiaddr {
+        s.parse().unwrap()
+    }
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn test_verdicts_from_mocked_checks() {
+        let public = addr("/ip4/203.0.113.7/tcp/4001");
+        let relay = addr("/dns4/relay.example.com/tcp/443");
+
+        let report = ReachabilityReport::aggregate(
+            Check::ok(NatStatus::Public(vec![public.clone()]), ms(40)),
+            Check::failed("no gateway found", ms(3)),
+            Check::ok(relay.clone(), ms(12)),
+            vec![public.clone()],
+            ms(45),
+        );
+        assert_eq!(report.verdict, Verdict::Direct);
+        assert_eq!(report.confirmed_addrs(), std::slice::from_ref(&public));
+
+        let report = ReachabilityReport::aggregate(
+            Check::ok(NatStatus::Private, ms(40)),
+            Check::failed("no gateway found", ms(3)),
+            Check::ok(relay.clone(), ms(12)),
+            vec![public.clone()],
+            ms(45),
+        );
+        assert_eq!(report.verdict, Verdict::HolePunchable);
+        assert!(report.confirmed_addrs().is_empty());
+
+        let report = ReachabilityReport::aggregate(
+            Check::ok(NatStatus::Private, ms(40)),
+            Check::failed("no gateway found", ms(3)),
+            Check::ok(relay, ms(12)),
+            vec![addr("/ip4/192.168.1.20/tcp/4001")],
+            ms(45),
+        );
+        assert_eq!(report.verdict, Verdict::RelayOnly);
+
+        let report = ReachabilityReport::aggregate(
+            Check::failed("timed out after 5s", ms(5000)),
+            Check::ok(public, ms(80)),
+            Check::failed("connection refused", ms(1)),
+            vec![],
+            ms(5000),
+        );
+        assert_eq!(report.verdict, Verdict::Isolated);
+        assert!(report.lines().iter().any(|l| l.contains("timed out")));
+    }
+
+    #[test]
+    fn test_holepunching_off_leaves_the_relay() {
+        let report = ReachabilityReport::aggregate(
+            Check::ok(NatStatus::Private, ms(40)),
+            Check::failed("no gateway found", ms(3)),
+            Check::ok(addr("/dns4/relay.example.com/tcp/443"), ms(12)),
+            vec![addr("/ip4/203.0.113.7/tcp/4001")],
+            ms(45),
+        );
+        assert_eq!(report.verdict, Verdict::HolePunchable);
+        assert_eq!(report.without_holepunching().verdict, Verdict::RelayOnly);
+    }
+
+    #[test]
+    fn test_connection_path_spots_relay_circuits() {
+        let direct = addr("/ip4/203.0.113.7/tcp/4001");
+        assert_eq!(ConnectionPath::of(&direct), ConnectionPath::Direct(direct.clone()));
+        let relayed = addr("/ip4/203.0.113.9/tcp/4001/p2p/12D3KooWDpJ7As7BWAwRMfu1VU2WCqNjvq387JEYKDBj4kx6nXTN/p2p-circuit");
+        assert_eq!(ConnectionPath::of(&relayed), ConnectionPath::Relayed(relayed.clone()));
+        assert!(ConnectionPath::of(&relayed).to_string().contains("through a relay"));
+    }
+
+    #[test]
+    fn test_public_address_classification() {
+        assert!(is_public(&addr("/ip4/203.0.113.7/tcp/4001")));
+        assert!(is_public(&addr("/dns4/example.com/tcp/443")));
+        assert!(!is_public(&addr("/ip4/10.0.0.2/tcp/4001")));
+        assert!(!is_public(&addr("/ip4/100.64.1.1/tcp/4001")));
+        assert!(!is_public(&addr("/ip6/fe80::1/tcp/4001")));
+        assert!(!is_public(&addr("/ip4/0.0.0.0/tcp/4001")));
+    }
+
+    #[tokio::test]
+    async fn test_check_timeout_is_reported() {
+        let check: Check<()> = Check::run(ms(20), async {
+            tokio::time::sleep(ms(500)).await;
+            Ok(())
+        })
+        .await;
+
+        assert!(check.outcome.unwrap_err().contains("timed out"));
+        assert!(check.duration < ms(500));
+    }
+
+    #[test]
+    fn test_port_mapping_checks() {
+        let mapped = addr("/ip4/203.0.113.7/tcp/4001");
+        assert_eq!(PortMapping::Mapped(vec![mapped.clone()]).check(), Ok(mapped));
+        assert!(PortMapping::Disabled.check().unwrap_err().contains("p2p.enable_upnp"));
+        assert_eq!(PortMapping::Failed("no router".to_string()).check(), Err("no router".to_string()));
+        assert!(PortMapping::Pending.check().is_err());
+        assert!(PortMapping::Mapped(Vec::new()).check().is_err());
+    }
+}
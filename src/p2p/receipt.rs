@@ -0,0 +1,211 @@
+use libp2p::identity::{Keypair, PublicKey};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+use crate::{Result, ShrLinkError};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransferReceipt {
+    pub file_hash: [u8; 32],
+    pub bytes: u64,
+    pub duration: Duration,
+    pub receiver_peer_id: String,
+    pub receiver_name: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedReceipt {
+    pub receipt: TransferReceipt,
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl TransferReceipt {
+    pub fn sign(self, keypair: &Keypair) -> Result<SignedReceipt> {
+        let signature = keypair
+            .sign(&self.signing_payload()?)
+            .map_err(|e| ShrLinkError::P2P(format!("Failed to sign receipt: {}", e)))?;
+
+        Ok(SignedReceipt {
+            receipt: self,
+            public_key: keypair.public().encode_protobuf(),
+            signature,
+        })
+    }
+
+    fn signing_payload(&self) -> Result<Vec<u8>> {
+        serde_json::to_vec(self).map_err(|e| ShrLinkError::P2P(format!("Failed to encode receipt: {}", e)))
+    }
+}
+
+impl SignedReceipt {
+    pub fn verify(&self, file_hash: &[u8; 32]) -> Result<PeerId> {
+        let key = PublicKey::try_decode_protobuf(&self.public_key)
+            .map_err(|e| ShrLinkError::P2P(format!("Receipt has a malformed key: {}", e)))?;
+        let peer_id = key.to_peer_id();
+
+        if peer_id.to_string() != self.receipt.receiver_peer_id {
+            return Err(ShrLinkError::P2P("Receipt was signed by a different peer than it names".to_string()));
+        }
+
+        if !key.verify(&self.receipt.signing_payload()?, &self.signature) {
+            return Err(ShrLinkError::P2P("Receipt signature does not verify".to_string()));
+        }
+
+        if &self.receipt.file_hash != file_hash {
+            return Err(ShrLinkError::P2P("Receipt is for a different file".to_string()));
+        }
+
+        Ok(peer_id)
+    }
+}
+
+#[derive(Debug, Default)]
+struct DownloadState {
+    served_complete: bool,
+    receipt: Option<TransferReceipt>,
+}
+
+// Counts completed downloads per receiver; a receipt and a fully served session from the
+// same peer are the same download, and old receivers that never send receipts still count
+#[derive(Debug)]
+pub struct DownloadTracker {
+    file_hash: [u8; 32],
+    max_downloads: Option<usize>,
+    peers: HashMap<PeerId, DownloadState>,
+}
+
+impl DownloadTracker {
+    pub fn new(file_hash: [u8; 32], max_downloads: Option<usize>) -> Self {
+        Self {
+            file_hash,
+            max_downloads,
+            peers: HashMap::new(),
+        }
+    }
+
+    pub fn record_served(&mut self, peer_id: PeerId) {
+        self.peers.entry(peer_id).or_default().served_complete = true;
+    }
+
+    pub fn record_receipt(&mut self, receipt: &SignedReceipt) -> Result<PeerId> {
+        let peer_id = receipt.verify(&self.file_hash)?;
+        self.peers.entry(peer_id).or_default().receipt = Some(receipt.receipt.clone());
+        Ok(peer_id)
+    }
+
+    pub fn completed_downloads(&self) -> usize {
+        self.peers
+            .values()
+            .filter(|s| s.served_complete || s.receipt.is_some())
+            .count()
+    }
+
+    pub fn confirmed_downloads(&self) -> usize {
+        self.peers.values().filter(|s| s.receipt.is_some()).count()
+    }
+
+    pub fn limit_reached(&self) -> bool {
+        self.max_downloads
+            .is_some_and(|max| self.completed_downloads() >= max)
+    }
+
+    pub fn receipts(&self) -> impl Iterator<Item = &TransferReceipt> {
+        self.peers.values().filter_map(|s| s.receipt.as_ref())
+    }
+}
+
+pub fn short_peer_id(peer_id: &str) -> String {
+    let chars: Vec<char> = peer_id.chars().collect();
+    if chars.len() <= 10 {
+        return peer_id.to_string();
+    }
+
+    let head: String = chars[..4].iter().collect();
+    let tail: String = chars[chars.len() - 3..].iter().collect();
+    format!("{}…{}", head, tail)
+}
+
+pub fn describe_receipt(receipt: &TransferReceipt) -> String {
+    match &receipt.receiver_name {
+        Some(name) => format!("received by {} ({})", short_peer_id(&receipt.receiver_peer_id), name),
+        None => format!("received by {}", short_peer_id(&receipt.receiver_peer_id)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn receipt_for(keypair: &Keypair, file_hash: [u8; 32]) -> SignedReceipt {
+        TransferReceipt {
+            file_hash,
+            bytes: 4096,
+            duration: Duration::from_millis(1500),
+            receiver_peer_id: keypair.public().to_peer_id().to_string(),
+            receiver_name: Some("alice-laptop".to_string()),
+        }
+        .sign(keypair)
+        .unwrap()
+    }
+
+    #[test]
+    fn test_receipt_roundtrip() {
+        let keypair = Keypair::generate_ed25519();
+        let signed = receipt_for(&keypair, [3u8; 32]);
+
+        let encoded = serde_json::to_vec(&signed).unwrap();
+        let decoded: SignedReceipt = serde_json::from_slice(&encoded).unwrap();
+
+        assert_eq!(decoded.verify(&[3u8; 32]).unwrap(), keypair.public().to_peer_id());
+        assert!(decoded.verify(&[4u8; 32]).is_err());
+        assert!(describe_receipt(&decoded.receipt).ends_with("(alice-laptop)"));
+    }
+
+    #[test]
+    fn test_tampered_receipt_rejected() {
+        let keypair = Keypair::generate_ed25519();
+        let mut signed = receipt_for(&keypair, [3u8; 32]);
+        signed.receipt.bytes = 1;
+
+        assert!(signed.verify(&[3u8; 32]).is_err());
+    }
+
+    #[test]
+    fn test_max_downloads_with_receipts() {
+        let alice = Keypair::generate_ed25519();
+        let bob = Keypair::generate_ed25519();
+        let mut tracker = DownloadTracker::new([3u8; 32], Some(2));
+
+        // Served and receipted by the same peer counts once
+        tracker.record_served(alice.public().to_peer_id());
+        tracker.record_receipt(&receipt_for(&alice, [3u8; 32])).unwrap();
+        assert_eq!(tracker.completed_downloads(), 1);
+        assert!(!tracker.limit_reached());
+
+        tracker.record_receipt(&receipt_for(&bob, [3u8; 32])).unwrap();
+        assert_eq!(tracker.confirmed_downloads(), 2);
+        assert!(tracker.limit_reached());
+    }
+
+    #[test]
+    fn test_max_downloads_without_receipts() {
+        let mut tracker = DownloadTracker::new([3u8; 32], Some(2));
+
+        tracker.record_served(PeerId::random());
+        assert!(!tracker.limit_reached());
+        tracker.record_served(PeerId::random());
+        assert!(tracker.limit_reached());
+        assert_eq!(tracker.confirmed_downloads(), 0);
+
+        let unlimited = DownloadTracker::new([3u8; 32], None);
+        assert!(!unlimited.limit_reached());
+    }
+
+    #[test]
+    fn test_short_peer_id() {
+        assert_eq!(short_peer_id("12D3KooWabcdefabc"), "12D3…abc");
+        assert_eq!(short_peer_id("short"), "short");
+    }
+}
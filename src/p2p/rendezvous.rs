@@ -0,0 +1,129 @@
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use crate::{Result, ShrLinkError};
+use super::reputation::unix_now;
+
+// Where the sender holding a pairing code can be reached, kept under the code's number. It
+// holds nothing secret and names no file: what's sent is only handed over to a receiver that
+// proves it has the words too
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PairingRecord {
+    pub peer_id: String,
+    pub addrs: Vec<String>,
+    // Unix seconds after which the code is no good
+    pub expires_at: u64,
+}
+
+impl PairingRecord {
+    pub fn new(peer_id: PeerId, addrs: &[Multiaddr], ttl: Duration) -> Self {
+        Self {
+            peer_id: peer_id.to_string(),
+            addrs: addrs.iter().map(Multiaddr::to_string).collect(),
+            expires_at: unix_now() + ttl.as_secs(),
+        }
+    }
+
+    // Addresses that don't parse are skipped, as long as one does or there were none
+    pub fn peer(&self) -> Result<(PeerId, Vec<Multiaddr>)> {
+        let peer_id = self
+            .peer_id
+            .parse()
+            .map_err(|e| ShrLinkError::InvalidInput(format!("Invalid peer id {} in pairing record: {}", self.peer_id, e)))?;
+        let addrs = self.addrs.iter().filter_map(|a| a.parse().ok()).collect();
+        Ok((peer_id, addrs))
+    }
+}
+
+// Anything answering PUT, GET and DELETE on `<url>/<number>` with a record as JSON. A PUT
+// carries `If-None-Match: *`, which the server turns away with 412 when the number is taken
+pub struct Rendezvous {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl Rendezvous {
+    pub fn new(url: &str) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()
+            .map_err(|e| ShrLinkError::Network(format!("Failed to create HTTP client: {}", e)))?;
+        Ok(Self { client, url: url.trim_end_matches('/').to_string() })
+    }
+
+    fn record_url(&self, nameplate: u16) -> String {
+        format!("{}/{}", self.url, nameplate)
+    }
+
+    // False if another sender already has the number
+    pub async fn publish(&self, nameplate: u16, record: &PairingRecord) -> Result<bool> {
+        let response = self.client
+            .put(self.record_url(nameplate))
+            .header(reqwest::header::IF_NONE_MATCH, "*")
+            .json(record)
+            .send()
+            .await
+            .map_err(|e| ShrLinkError::Network(format!("Failed to reach the rendezvous server: {}", e)))?;
+
+        match response.status() {
+            status if status.is_success() => Ok(true),
+            reqwest::StatusCode::PRECONDITION_FAILED => Ok(false),
+            status => Err(ShrLinkError::Network(format!("Rendezvous server refused the pairing record: {}", status))),
+        }
+    }
+
+    // None if there's no record for the number, or it has expired
+    pub async fn lookup(&self, nameplate: u16) -> Result<Option<PairingRecord>> {
+        let response = self.client
+            .get(self.record_url(nameplate))
+            .send()
+            .await
+            .map_err(|e| ShrLinkError::Network(format!("Failed to reach the rendezvous server: {}", e)))?;
+
+        match response.status() {
+            reqwest::StatusCode::NOT_FOUND => Ok(None),
+            status if !status.is_success() => Err(ShrLinkError::Network(format!("Rendezvous server lookup failed: {}", status))),
+            _ => {
+                let record: PairingRecord = response
+                    .json()
+                    .await
+                    .map_err(|e| ShrLinkError::Network(format!("Malformed pairing record: {}", e)))?;
+                Ok(Some(record).filter(|r| r.expires_at > unix_now()))
+            }
+        }
+    }
+
+    // Already gone counts as withdrawn
+    pub async fn withdraw(&self, nameplate: u16) -> Result<()> {
+        let response = self.client
+            .delete(self.record_url(nameplate))
+            .send()
+            .await
+            .map_err(|e| ShrLinkError::Network(format!("Failed to reach the rendezvous server: {}", e)))?;
+
+        if !response.status().is_success() && response.status() != reqwest::StatusCode::NOT_FOUND {
+            return Err(ShrLinkError::Network(format!("Rendezvous server kept the pairing record: {}", response.status())));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_names_the_peer() {
+        let peer_id = PeerId::random();
+        let addr: Multiaddr = "/ip4/203.0.113.7/tcp/4001".parse().unwrap();
+        let record = PairingRecord::new(peer_id, std::slice::from_ref(&addr), Duration::from_secs(600));
+        assert!(record.expires_at > unix_now());
+        assert_eq!(record.peer().unwrap(), (peer_id, vec![addr]));
+
+        let json = serde_json::to_string(&record).unwrap();
+        assert_eq!(serde_json::from_str::<PairingRecord>(&json).unwrap(), record);
+
+        let forged = PairingRecord { peer_id: "not-a-peer".to_string(), ..record };
+        assert!(forged.peer().is_err());
+    }
+}
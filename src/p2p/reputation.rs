@@ -0,0 +1,276 @@
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::{Result, ShrLinkError};
+
+pub const REPUTATION_FILE: &str = "peers.json";
+pub const DEFAULT_CAPACITY: usize = 1024;
+// Effective strikes at or above this keep a peer out of the source list
+pub const BLOCK_THRESHOLD: f64 = 5.0;
+// Strikes halve every day, so a peer blocked for a burst of bad chunks is usable again within days
+pub const STRIKE_HALF_LIFE_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PeerRecord {
+    // Strike count as of `last_seen`; decay is applied lazily when the record is read or updated
+    pub strikes: f64,
+    pub successes: u64,
+    pub last_seen: u64,
+}
+
+impl PeerRecord {
+    pub fn effective_strikes(&self, now: u64) -> f64 {
+        decay(self.strikes, now.saturating_sub(self.last_seen))
+    }
+
+    pub fn is_blocked(&self, now: u64) -> bool {
+        self.effective_strikes(now) >= BLOCK_THRESHOLD
+    }
+
+    // Higher is better; successes count for less than strikes so one bad chunk outweighs a good transfer
+    pub fn score(&self, now: u64) -> f64 {
+        (self.successes as f64).ln_1p() - self.effective_strikes(now)
+    }
+
+    fn touch(&mut self, now: u64) {
+        self.strikes = self.effective_strikes(now);
+        self.last_seen = self.last_seen.max(now);
+    }
+}
+
+pub fn decay(strikes: f64, elapsed_secs: u64) -> f64 {
+    strikes * 0.5f64.powf(elapsed_secs as f64 / STRIKE_HALF_LIFE_SECS as f64)
+}
+
+#[derive(Debug)]
+pub struct ReputationStore {
+    path: Option<PathBuf>,
+    capacity: usize,
+    peers: HashMap<String, PeerRecord>,
+}
+
+impl ReputationStore {
+    pub fn in_memory(capacity: usize) -> Self {
+        Self {
+            path: None,
+            capacity: capacity.max(1),
+            peers: HashMap::new(),
+        }
+    }
+
+    // A missing or unreadable store starts empty rather than failing the transfer
+    pub fn load(path: &Path, capacity: usize) -> Self {
+        let peers = match fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+                tracing::warn!("Ignoring corrupt peer reputation store {}: {}", path.display(), e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+
+        let mut store = Self {
+            path: Some(path.to_path_buf()),
+            capacity: capacity.max(1),
+            peers,
+        };
+        store.evict();
+        store
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let content = serde_json::to_vec_pretty(&self.peers)
+            .map_err(|e| ShrLinkError::Other(e.into()))?;
+        fs::write(path, content)?;
+        Ok(())
+    }
+
+    pub fn record_strike(&mut self, peer_id: &PeerId) {
+        self.record_strike_at(peer_id, unix_now());
+    }
+
+    pub fn record_success(&mut self, peer_id: &PeerId) {
+        self.record_success_at(peer_id, unix_now());
+    }
+
+    pub fn record_strike_at(&mut self, peer_id: &PeerId, now: u64) {
+        let record = self.entry(peer_id, now);
+        record.strikes += 1.0;
+        self.evict();
+    }
+
+    pub fn record_success_at(&mut self, peer_id: &PeerId, now: u64) {
+        let record = self.entry(peer_id, now);
+        record.successes += 1;
+        self.evict();
+    }
+
+    pub fn get(&self, peer_id: &PeerId) -> Option<&PeerRecord> {
+        self.peers.get(&peer_id.to_string())
+    }
+
+    pub fn is_blocked(&self, peer_id: &PeerId) -> bool {
+        self.is_blocked_at(peer_id, unix_now())
+    }
+
+    pub fn is_blocked_at(&self, peer_id: &PeerId, now: u64) -> bool {
+        self.get(peer_id).is_some_and(|r| r.is_blocked(now))
+    }
+
+    pub fn forgive(&mut self, peer_id: &PeerId) -> bool {
+        self.peers.remove(&peer_id.to_string()).is_some()
+    }
+
+    // Drops blocked peers and orders the rest best-first; peers with no history sit at zero
+    pub fn rank_sources(&self, peers: Vec<PeerId>) -> Vec<PeerId> {
+        self.rank_sources_at(peers, unix_now())
+    }
+
+    pub fn rank_sources_at(&self, peers: Vec<PeerId>, now: u64) -> Vec<PeerId> {
+        let mut ranked: Vec<(PeerId, f64)> = peers
+            .into_iter()
+            .filter(|p| !self.is_blocked_at(p, now))
+            .map(|p| {
+                let score = self.get(&p).map_or(0.0, |r| r.score(now));
+                (p, score)
+            })
+            .collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+        ranked.into_iter().map(|(p, _)| p).collect()
+    }
+
+    pub fn entries(&self) -> Vec<(&str, &PeerRecord)> {
+        let mut entries: Vec<_> = self.peers.iter().map(|(id, r)| (id.as_str(), r)).collect();
+        entries.sort_by_key(|(_, r)| std::cmp::Reverse(r.last_seen));
+        entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    fn entry(&mut self, peer_id: &PeerId, now: u64) -> &mut PeerRecord {
+        let record = self.peers.entry(peer_id.to_string()).or_insert_with(|| PeerRecord {
+            last_seen: now,
+            ..Default::default()
+        });
+        record.touch(now);
+        record
+    }
+
+    // Least recently seen peers go first once the store is over capacity
+    fn evict(&mut self) {
+        if self.peers.len() <= self.capacity {
+            return;
+        }
+
+        let mut by_age: Vec<(String, u64)> = self.peers.iter().map(|(id, r)| (id.clone(), r.last_seen)).collect();
+        by_age.sort_by_key(|(_, last_seen)| *last_seen);
+
+        let excess = self.peers.len() - self.capacity;
+        for (id, _) in by_age.into_iter().take(excess) {
+            self.peers.remove(&id);
+        }
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strikes_accumulate() {
+        let mut store = ReputationStore::in_memory(16);
+        let peer = PeerId::random();
+
+        for _ in 0..3 {
+            store.record_strike_at(&peer, 1000);
+        }
+        store.record_success_at(&peer, 1000);
+
+        let record = store.get(&peer).unwrap();
+        assert_eq!(record.strikes, 3.0);
+        assert_eq!(record.successes, 1);
+        assert_eq!(record.last_seen, 1000);
+    }
+
+    #[test]
+    fn test_strike_decay() {
+        assert_eq!(decay(4.0, 0), 4.0);
+        assert!((decay(4.0, STRIKE_HALF_LIFE_SECS) - 2.0).abs() < 1e-9);
+        assert!((decay(4.0, 3 * STRIKE_HALF_LIFE_SECS) - 0.5).abs() < 1e-9);
+
+        // Decay is folded in before a new strike lands
+        let mut store = ReputationStore::in_memory(16);
+        let peer = PeerId::random();
+        store.record_strike_at(&peer, 0);
+        store.record_strike_at(&peer, 0);
+        store.record_strike_at(&peer, STRIKE_HALF_LIFE_SECS);
+        assert!((store.get(&peer).unwrap().strikes - 2.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_auto_blocklist_threshold() {
+        let mut store = ReputationStore::in_memory(16);
+        let bad = PeerId::random();
+        let good = PeerId::random();
+        let unknown = PeerId::random();
+
+        for _ in 0..(BLOCK_THRESHOLD as usize - 1) {
+            store.record_strike_at(&bad, 0);
+        }
+        assert!(!store.is_blocked_at(&bad, 0));
+        store.record_strike_at(&bad, 0);
+        assert!(store.is_blocked_at(&bad, 0));
+
+        // Blocked peers are dropped from the source list, known-good ones lead it
+        store.record_success_at(&good, 0);
+        assert_eq!(store.rank_sources_at(vec![unknown, bad, good], 0), vec![good, unknown]);
+
+        // ...and come back once enough time has passed
+        assert!(!store.is_blocked_at(&bad, STRIKE_HALF_LIFE_SECS));
+
+        assert!(store.forgive(&bad));
+        assert!(store.get(&bad).is_none());
+    }
+
+    #[test]
+    fn test_lru_eviction_and_persistence() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(REPUTATION_FILE);
+        let peers: Vec<PeerId> = (0..4).map(|_| PeerId::random()).collect();
+
+        let mut store = ReputationStore::load(&path, 3);
+        for (i, peer) in peers.iter().enumerate() {
+            store.record_success_at(peer, i as u64);
+        }
+        assert_eq!(store.len(), 3);
+        assert!(store.get(&peers[0]).is_none());
+        store.save().unwrap();
+
+        let reloaded = ReputationStore::load(&path, 3);
+        assert_eq!(reloaded.len(), 3);
+        assert_eq!(reloaded.get(&peers[3]).unwrap().successes, 1);
+    }
+}
@@ -0,0 +1,346 @@
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use crate::{Result, ShrLinkError};
+use super::events::TransferEvent;
+use super::reputation::unix_now;
+use super::throttle::PeerServeStats;
+
+pub const STATS_FILE: &str = "stats.json";
+// Bumped only when a field changes meaning; fields added later read as zero from older files
+pub const STATS_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PeerStats {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub chunks_sent: u64,
+    pub chunks_received: u64,
+    pub retries: u64,
+    // Times it was cut off by a serve for asking too often or for what no receiver would
+    pub violations: u64,
+    // Receipts it sent for files it had whole
+    pub receipts: u64,
+    // How fast the last serve to it went on average, throttling included
+    pub serve_bps: u64,
+    pub last_seen: u64,
+}
+
+impl PeerStats {
+    fn add(&mut self, other: &PeerStats) {
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_received += other.bytes_received;
+        self.chunks_sent += other.chunks_sent;
+        self.chunks_received += other.chunks_received;
+        self.retries += other.retries;
+        self.violations += other.violations;
+        self.receipts += other.receipts;
+        if other.serve_bps > 0 {
+            self.serve_bps = other.serve_bps;
+        }
+        self.last_seen = self.last_seen.max(other.last_seen);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct P2PStats {
+    pub version: u32,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub chunks_sent: u64,
+    pub chunks_received: u64,
+    pub retries: u64,
+    pub transfers_completed: u64,
+    pub transfers_failed: u64,
+    pub violations: u64,
+    // Receivers that confirmed with a signed receipt that a file served from here arrived whole
+    pub receipts: u64,
+    // Each transfer timed from its first event to its Completed or Failed
+    pub transfer_ms: u64,
+    // Keyed by peer ID; only peers that chunks went to or came from
+    pub peers: BTreeMap<String, PeerStats>,
+}
+
+impl Default for P2PStats {
+    fn default() -> Self {
+        Self {
+            version: STATS_VERSION,
+            bytes_sent: 0,
+            bytes_received: 0,
+            chunks_sent: 0,
+            chunks_received: 0,
+            retries: 0,
+            transfers_completed: 0,
+            transfers_failed: 0,
+            violations: 0,
+            receipts: 0,
+            transfer_ms: 0,
+            peers: BTreeMap::new(),
+        }
+    }
+}
+
+impl P2PStats {
+    pub fn transfers(&self) -> u64 {
+        self.transfers_completed + self.transfers_failed
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transfers() == 0 && self.peers.is_empty()
+    }
+
+    pub fn add(&mut self, other: &P2PStats) {
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_received += other.bytes_received;
+        self.chunks_sent += other.chunks_sent;
+        self.chunks_received += other.chunks_received;
+        self.retries += other.retries;
+        self.transfers_completed += other.transfers_completed;
+        self.transfers_failed += other.transfers_failed;
+        self.violations += other.violations;
+        self.receipts += other.receipts;
+        self.transfer_ms += other.transfer_ms;
+        for (peer, stats) in &other.peers {
+            self.peers.entry(peer.clone()).or_default().add(stats);
+        }
+    }
+
+    fn peer(&mut self, peer_id: &PeerId, now: u64) -> &mut PeerStats {
+        let stats = self.peers.entry(peer_id.to_string()).or_default();
+        stats.last_seen = stats.last_seen.max(now);
+        stats
+    }
+}
+
+// What a client's transfer events add up to since the counts were last taken
+#[derive(Debug, Default)]
+pub struct StatsTally {
+    counted: P2PStats,
+    started: Option<Instant>,
+}
+
+impl StatsTally {
+    pub fn observe(&mut self, event: &TransferEvent) {
+        self.observe_at(event, Instant::now(), unix_now());
+    }
+
+    pub fn observe_at(&mut self, event: &TransferEvent, at: Instant, now: u64) {
+        let started = *self.started.get_or_insert(at);
+        let counted = &mut self.counted;
+        match event {
+            TransferEvent::ChunkSent { peer_id, bytes, .. } => {
+                counted.bytes_sent += *bytes as u64;
+                counted.chunks_sent += 1;
+                let peer = counted.peer(peer_id, now);
+                peer.bytes_sent += *bytes as u64;
+                peer.chunks_sent += 1;
+            }
+            TransferEvent::ChunkReceived { peer_id, bytes, .. } => {
+                counted.bytes_received += *bytes as u64;
+                counted.chunks_received += 1;
+                let peer = counted.peer(peer_id, now);
+                peer.bytes_received += *bytes as u64;
+                peer.chunks_received += 1;
+            }
+            TransferEvent::Retry { peer_id, .. } => {
+                counted.retries += 1;
+                counted.peer(peer_id, now).retries += 1;
+            }
+            TransferEvent::Completed { .. } => {
+                counted.transfers_completed += 1;
+                self.end(started, at);
+            }
+            TransferEvent::Failed(_) => {
+                counted.transfers_failed += 1;
+                self.end(started, at);
+            }
+            TransferEvent::PeerConnected(_) | TransferEvent::PeerDisconnected(_) => {}
+        }
+    }
+
+    fn end(&mut self, started: Instant, at: Instant) {
+        self.counted.transfer_ms += at.saturating_duration_since(started).as_millis() as u64;
+        self.started = None;
+    }
+
+    pub fn take(&mut self) -> P2PStats {
+        std::mem::take(&mut self.counted)
+    }
+}
+
+#[derive(Debug)]
+pub struct StatsStore {
+    path: Option<PathBuf>,
+    stats: P2PStats,
+    // Written by a newer shr, whose fields this one would drop on saving
+    read_only: bool,
+}
+
+impl StatsStore {
+    pub fn in_memory() -> Self {
+        Self {
+            path: None,
+            stats: P2PStats::default(),
+            read_only: false,
+        }
+    }
+
+    // A missing or unreadable file starts from zero rather than failing the transfer
+    pub fn load(path: &Path) -> Self {
+        let stats = match fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+                tracing::warn!("Ignoring corrupt transfer statistics {}: {}", path.display(), e);
+                P2PStats::default()
+            }),
+            Err(_) => P2PStats::default(),
+        };
+
+        Self {
+            path: Some(path.to_path_buf()),
+            read_only: stats.version > STATS_VERSION,
+            stats,
+        }
+    }
+
+    pub fn stats(&self) -> &P2PStats {
+        &self.stats
+    }
+
+    pub fn add(&mut self, stats: &P2PStats) {
+        self.stats.add(stats);
+    }
+
+    pub fn record_violation(&mut self, peer_id: &PeerId) {
+        self.stats.violations += 1;
+        self.stats.peer(peer_id, unix_now()).violations += 1;
+    }
+
+    pub fn record_receipt(&mut self, peer_id: &PeerId) {
+        self.stats.receipts += 1;
+        self.stats.peer(peer_id, unix_now()).receipts += 1;
+    }
+
+    // What the serve throttle measured for each peer it served
+    pub fn record_serve_rates(&mut self, served: &[(PeerId, PeerServeStats)]) {
+        let now = unix_now();
+        for (peer_id, stats) in served.iter().filter(|(_, s)| s.bytes_served > 0) {
+            self.stats.peer(peer_id, now).serve_bps = stats.average_bps;
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.stats = P2PStats::default();
+        self.read_only = false;
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        if self.read_only {
+            return Err(ShrLinkError::InvalidInput(format!(
+                "{} was written by a newer shr (statistics version {}); leaving it as it is",
+                path.display(),
+                self.stats.version
+            )));
+        }
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let content = serde_json::to_vec_pretty(&self.stats)
+            .map_err(|e| ShrLinkError::Other(e.into()))?;
+        fs::write(path, content)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_tally_counts_per_peer_and_times_transfers() {
+        let mut tally = StatsTally::default();
+        let start = Instant::now();
+        let (a, b) = (PeerId::random(), PeerId::random());
+
+        tally.observe_at(&TransferEvent::PeerConnected(a), start, 100);
+        tally.observe_at(&TransferEvent::ChunkSent { peer_id: a, index: 0, bytes: 10 }, start, 100);
+        tally.observe_at(&TransferEvent::Retry { peer_id: a, index: 0 }, start, 100);
+        tally.observe_at(&TransferEvent::ChunkReceived { peer_id: b, index: 1, bytes: 7 }, start, 200);
+        tally.observe_at(&TransferEvent::Completed { chunks: 1, bytes: 10 }, start + Duration::from_millis(250), 200);
+        tally.observe_at(&TransferEvent::Failed("gone".into()), start + Duration::from_secs(5), 300);
+
+        let counted = tally.take();
+        assert_eq!((counted.bytes_sent, counted.chunks_sent), (10, 1));
+        assert_eq!((counted.bytes_received, counted.chunks_received), (7, 1));
+        assert_eq!(counted.retries, 1);
+        assert_eq!((counted.transfers_completed, counted.transfers_failed), (1, 1));
+        // The failed transfer had only its terminal event, so it took no time
+        assert_eq!(counted.transfer_ms, 250);
+        assert_eq!(counted.peers.len(), 2);
+        assert_eq!(counted.peers[&a.to_string()].retries, 1);
+        assert_eq!(counted.peers[&b.to_string()].last_seen, 200);
+        assert!(tally.take().is_empty());
+    }
+
+    #[test]
+    fn test_store_accumulates_across_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(STATS_FILE);
+        let peer = PeerId::random();
+        let mut run = P2PStats { bytes_sent: 10, transfers_completed: 1, ..Default::default() };
+        run.peer(&peer, 5).bytes_sent = 10;
+
+        for _ in 0..2 {
+            let mut store = StatsStore::load(&path);
+            store.add(&run);
+            store.save().unwrap();
+        }
+
+        let store = StatsStore::load(&path);
+        assert_eq!(store.stats().version, STATS_VERSION);
+        assert_eq!(store.stats().bytes_sent, 20);
+        assert_eq!(store.stats().transfers(), 2);
+        assert_eq!(store.stats().peers[&peer.to_string()].bytes_sent, 20);
+
+        let mut store = store;
+        store.reset();
+        store.save().unwrap();
+        assert!(StatsStore::load(&path).stats().is_empty());
+    }
+
+    #[test]
+    fn test_unknown_and_missing_fields() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(STATS_FILE);
+
+        // Fields this version doesn't know are dropped, ones the file lacks read as zero
+        fs::write(&path, r#"{"version": 1, "bytes_sent": 3, "later_field": true}"#).unwrap();
+        let store = StatsStore::load(&path);
+        assert_eq!(store.stats().bytes_sent, 3);
+        assert_eq!(store.stats().retries, 0);
+        store.save().unwrap();
+
+        // A newer version's file is read but never overwritten
+        let newer = format!(r#"{{"version": {}, "bytes_sent": 3}}"#, STATS_VERSION + 1);
+        fs::write(&path, &newer).unwrap();
+        let mut store = StatsStore::load(&path);
+        assert_eq!(store.stats().bytes_sent, 3);
+        store.add(&P2PStats { bytes_sent: 1, ..Default::default() });
+        assert!(store.save().is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), newer);
+
+        fs::write(&path, "not json").unwrap();
+        assert!(StatsStore::load(&path).stats().is_empty());
+    }
+}
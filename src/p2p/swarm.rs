@@ -0,0 +1,261 @@
+use futures::StreamExt;
+use libp2p::core::muxing::StreamMuxerBox;
+use libp2p::core::transport::{Boxed, TransportError};
+use libp2p::identity::Keypair;
+use libp2p::multiaddr::Protocol;
+use libp2p::swarm::dial_opts::DialOpts;
+use libp2p::request_response::{self, ProtocolSupport};
+use libp2p::swarm::behaviour::toggle::Toggle;
+use libp2p::swarm::{NetworkBehaviour, SwarmEvent};
+use libp2p::{autonat, dcutr, identify, mdns, noise, relay, tcp, upnp, yamux, Multiaddr, PeerId, StreamProtocol, Swarm};
+use std::io;
+use std::time::Duration;
+use crate::config::P2PConfig;
+// Result stays qualified here: the NetworkBehaviour derive expands to code that means std's
+use crate::{DialError, ShrLinkError};
+use super::transfer::{AnnounceCodec, AnnounceRequest, AnnounceResponse, ChunkCodec, ChunkRequest, ChunkResponse, HelloCodec, HelloRequest, HelloResponse, ManifestCodec, ManifestRequest, ManifestResponse, PairCodec, PairRequest, PairResponse, ReceiptCodec, ReceiptRequest, ReceiptResponse};
+use super::{ANNOUNCE_PROTOCOL, HELLO_PROTOCOL, LEGACY_PROTOCOL_VERSION, MANIFEST_PROTOCOL, PAIR_PROTOCOL, PROTOCOL_VERSION, RECEIPT_PROTOCOL};
+
+#[derive(NetworkBehaviour)]
+pub struct Behaviour {
+    pub chunks: request_response::Behaviour<ChunkCodec>,
+    pub manifests: request_response::Behaviour<ManifestCodec>,
+    pub hello: request_response::Behaviour<HelloCodec>,
+    pub pairing: request_response::Behaviour<PairCodec>,
+    pub announce: request_response::Behaviour<AnnounceCodec>,
+    pub receipts: request_response::Behaviour<ReceiptCodec>,
+    // Only there when `enable_mdns` is set
+    pub mdns: Toggle<mdns::tokio::Behaviour>,
+    // Only there when relays are configured: identify tells peers which address they see us
+    // on, and AutoNAT has the relays dial it back to find out if it's reachable
+    pub identify: Toggle<identify::Behaviour>,
+    pub autonat: Toggle<autonat::Behaviour>,
+    // Listens through the relays once AutoNAT finds this machine behind NAT, and dials peers
+    // that did the same; inert until then
+    pub relay_client: relay::client::Behaviour,
+    // Only there with relays configured and `enable_holepunching` set: turns a relayed
+    // connection into a direct one where both NATs allow it
+    pub dcutr: Toggle<dcutr::Behaviour>,
+    // Only there when `enable_upnp` is set: maps each listening port on the router
+    pub upnp: Toggle<upnp::tokio::Behaviour>,
+}
+
+pub type Event = request_response::Event<ChunkRequest, ChunkResponse>;
+pub type ManifestEvent = request_response::Event<ManifestRequest, ManifestResponse>;
+pub type HelloEvent = request_response::Event<HelloRequest, HelloResponse>;
+pub type PairEvent = request_response::Event<PairRequest, PairResponse>;
+pub type AnnounceEvent = request_response::Event<AnnounceRequest, AnnounceResponse>;
+pub type ReceiptEvent = request_response::Event<ReceiptRequest, ReceiptResponse>;
+
+// Connections outlive a single request so later dials to the same peer can reuse them
+const IDLE_CONNECTION_TIMEOUT: Duration = Duration::from_secs(60);
+// How long a manifest, handshake, pairing, announce or receipt request waits for its answer; chunk requests wait `chunk_timeout_ms`,
+// throttling on the sender's side included
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+const IDENTIFY_PROTOCOL: &str = "/shr/id/1.0.0";
+// Long enough for the first listeners to be up, so they're among what the first probe asks about
+const AUTONAT_BOOT_DELAY: Duration = Duration::from_secs(1);
+
+pub fn build_swarm(keypair: Keypair, config: &P2PConfig) -> crate::Result<Swarm<Behaviour>> {
+    let mdns = config
+        .enable_mdns
+        .then(|| mdns::tokio::Behaviour::new(mdns::Config::default(), keypair.public().to_peer_id()))
+        .transpose()
+        .map_err(|e| ShrLinkError::P2P(format!("Failed to set up mDNS: {}", e)))?;
+
+    let relays = relay_nodes(&config.relays);
+    let swarm = libp2p::SwarmBuilder::with_existing_identity(keypair)
+        .with_tokio()
+        .with_tcp(tcp::Config::default().nodelay(true), noise::Config::new, yamux::Config::default)
+        .map_err(|e| ShrLinkError::P2P(format!("Failed to set up transport: {}", e)))?
+        .with_other_transport(webrtc_transport)
+        .map_err(|e| ShrLinkError::P2P(format!("Failed to set up the WebRTC transport: {}", e)))?
+        .with_relay_client(noise::Config::new, yamux::Config::default)
+        .map_err(|e| ShrLinkError::P2P(format!("Failed to set up the relay transport: {}", e)))?
+        .with_behaviour(|key, relay_client| Behaviour {
+            chunks: request_response::Behaviour::with_codec(
+                ChunkCodec::default(),
+                [
+                    (StreamProtocol::new(PROTOCOL_VERSION), ProtocolSupport::Full),
+                    (StreamProtocol::new(LEGACY_PROTOCOL_VERSION), ProtocolSupport::Full),
+                ],
+                request_response::Config::default().with_request_timeout(Duration::from_millis(config.chunk_timeout_ms)),
+            ),
+            manifests: request_response::Behaviour::with_codec(
+                ManifestCodec,
+                [(StreamProtocol::new(MANIFEST_PROTOCOL), ProtocolSupport::Full)],
+                request_response::Config::default().with_request_timeout(REQUEST_TIMEOUT),
+            ),
+            hello: request_response::Behaviour::with_codec(
+                HelloCodec,
+                [(StreamProtocol::new(HELLO_PROTOCOL), ProtocolSupport::Full)],
+                request_response::Config::default().with_request_timeout(REQUEST_TIMEOUT),
+            ),
+            pairing: request_response::Behaviour::with_codec(
+                PairCodec,
+                [(StreamProtocol::new(PAIR_PROTOCOL), ProtocolSupport::Full)],
+                request_response::Config::default().with_request_timeout(REQUEST_TIMEOUT),
+            ),
+            announce: request_response::Behaviour::with_codec(
+                AnnounceCodec,
+                [(StreamProtocol::new(ANNOUNCE_PROTOCOL), ProtocolSupport::Full)],
+                request_response::Config::default().with_request_timeout(REQUEST_TIMEOUT),
+            ),
+            receipts: request_response::Behaviour::with_codec(
+                ReceiptCodec,
+                [(StreamProtocol::new(RECEIPT_PROTOCOL), ProtocolSupport::Full)],
+                request_response::Config::default().with_request_timeout(REQUEST_TIMEOUT),
+            ),
+            mdns: Toggle::from(mdns),
+            identify: Toggle::from((!relays.is_empty()).then(|| {
+                identify::Behaviour::new(identify::Config::new(IDENTIFY_PROTOCOL.to_string(), key.public()))
+            })),
+            // Only the relays are asked, not whichever peers happen to be connected
+            autonat: Toggle::from((!relays.is_empty()).then(|| {
+                let mut autonat = autonat::Behaviour::new(key.public().to_peer_id(), autonat::Config {
+                    use_connected: false,
+                    boot_delay: AUTONAT_BOOT_DELAY,
+                    ..Default::default()
+                });
+                for (peer_id, addr) in &relays {
+                    autonat.add_server(*peer_id, Some(addr.clone()));
+                }
+                autonat
+            })),
+            relay_client,
+            dcutr: Toggle::from((!relays.is_empty() && config.enable_holepunching).then(|| dcutr::Behaviour::new(key.public().to_peer_id()))),
+            upnp: Toggle::from(config.enable_upnp.then(upnp::tokio::Behaviour::default)),
+        })
+        .map_err(|e| ShrLinkError::P2P(format!("Failed to set up behaviour: {}", e)))?
+        .with_swarm_config(|c| c.with_idle_connection_timeout(IDLE_CONNECTION_TIMEOUT))
+        .build();
+
+    Ok(swarm)
+}
+
+// WebRTC, which browsers can dial: each run has a certificate of its own, whose hash the
+// listen addresses carry. The chunk, manifest and hello protocols only need an ordered byte
+// stream per request, which a data channel is
+#[cfg(feature = "webrtc")]
+fn webrtc_transport(key: &Keypair) -> std::result::Result<Boxed<(PeerId, StreamMuxerBox)>, Box<dyn std::error::Error + Send + Sync>> {
+    use libp2p::core::Transport;
+    use libp2p_webrtc::tokio::{Certificate, Transport as WebRtcTransport};
+
+    let certificate = Certificate::generate(&mut rand::thread_rng())?;
+    Ok(WebRtcTransport::new(key.clone(), certificate)
+        .map(|(peer_id, connection), _| (peer_id, StreamMuxerBox::new(connection)))
+        .boxed())
+}
+
+// Without the `webrtc` feature nothing is listened on or dialed this way
+#[cfg(not(feature = "webrtc"))]
+fn webrtc_transport(_key: &Keypair) -> std::result::Result<Boxed<(PeerId, StreamMuxerBox)>, Box<dyn std::error::Error + Send + Sync>> {
+    use libp2p::core::Transport;
+
+    Ok(libp2p::core::transport::dummy::DummyTransport::new().boxed())
+}
+
+// The configured relays that can be used, each of which has to name its peer id
+pub fn relay_nodes(relays: &[String]) -> Vec<(PeerId, Multiaddr)> {
+    relays
+        .iter()
+        .filter_map(|relay| {
+            let addr: Multiaddr = relay.parse().map_err(|e| tracing::warn!("Ignoring relay {}: {}", relay, e)).ok()?;
+            let Some(peer_id) = peer_id_in(&addr) else {
+                tracing::warn!("Ignoring relay {}: it has no /p2p/<peer id>", relay);
+                return None;
+            };
+            Some((peer_id, addr))
+        })
+        .collect()
+}
+
+pub fn peer_id_in(addr: &Multiaddr) -> Option<PeerId> {
+    addr.iter().find_map(|p| match p {
+        Protocol::P2p(peer_id) => Some(peer_id),
+        _ => None,
+    })
+}
+
+// Drives the swarm until this particular dial resolves; the handshake is noise, so the
+// returned id is the one the remote proved it holds the key for. Also says whether this is the
+// only connection to it, as the event saying so is used up here
+pub async fn dial(swarm: &mut Swarm<Behaviour>, addr: Multiaddr, timeout: Duration) -> crate::Result<(PeerId, bool)> {
+    let expected = peer_id_in(&addr);
+    let opts = DialOpts::unknown_peer_id().address(addr.clone()).build();
+    let connection_id = opts.connection_id();
+
+    swarm.dial(opts).map_err(|e| map_dial_error(&addr, expected, e))?;
+
+    let outcome = tokio::time::timeout(timeout, async {
+        loop {
+            match swarm.select_next_some().await {
+                SwarmEvent::ConnectionEstablished { peer_id, connection_id: id, num_established, .. } if id == connection_id => {
+                    return Ok((peer_id, num_established.get() == 1));
+                }
+                SwarmEvent::OutgoingConnectionError { error, connection_id: id, .. } if id == connection_id => {
+                    return Err(map_dial_error(&addr, expected, error));
+                }
+                _ => {}
+            }
+        }
+    })
+    .await
+    .map_err(|_| ShrLinkError::from(DialError::Timeout(timeout)))??;
+
+    match expected {
+        Some(expected) if expected != outcome.0 => {
+            swarm.close_connection(connection_id);
+            Err(DialError::PeerIdMismatch { expected, actual: outcome.0 }.into())
+        }
+        _ => Ok(outcome),
+    }
+}
+
+fn map_dial_error(addr: &Multiaddr, expected: Option<PeerId>, error: libp2p::swarm::DialError) -> ShrLinkError {
+    use libp2p::swarm::DialError as SwarmDialError;
+
+    let failure = match error {
+        SwarmDialError::WrongPeerId { obtained, .. } => match expected {
+            Some(expected) => DialError::PeerIdMismatch { expected, actual: obtained },
+            None => DialError::Transport(format!("unexpected peer id {}", obtained)),
+        },
+        SwarmDialError::LocalPeerId { .. } => DialError::LocalPeer,
+        SwarmDialError::Transport(errors) => {
+            let refused = errors.iter().any(|(_, e)| match e {
+                TransportError::Other(e) => is_refused(e),
+                TransportError::MultiaddrNotSupported(_) => false,
+            });
+
+            if refused {
+                DialError::ConnectionRefused(addr.clone())
+            } else {
+                let reasons: Vec<String> = errors.iter().map(|(a, e)| format!("{}: {:?}", a, e)).collect();
+                DialError::Transport(reasons.join("; "))
+            }
+        }
+        other => DialError::Transport(other.to_string()),
+    };
+
+    failure.into()
+}
+
+// Says why a port couldn't be bound in terms of what to do about it
+pub fn listen_error(addr: &Multiaddr, error: TransportError<io::Error>) -> ShrLinkError {
+    let reason = match error {
+        TransportError::MultiaddrNotSupported(_) => "the address isn't one shr can listen on".to_string(),
+        TransportError::Other(e) => match e.kind() {
+            io::ErrorKind::AddrInUse => "the port is already in use; pick another with p2p.port or p2p.listen_addrs".to_string(),
+            io::ErrorKind::PermissionDenied => "permission denied; ports below 1024 need elevated privileges".to_string(),
+            io::ErrorKind::AddrNotAvailable => "no interface on this machine has that address".to_string(),
+            _ => e.to_string(),
+        },
+    };
+    ShrLinkError::P2P(format!("Failed to listen on {}: {}", addr, reason))
+}
+
+// The OS error ends up wrapped in the transport's timeout and Either layers, none of which
+// expose it through source(), so the kind is recovered from the debug representation
+fn is_refused(error: &io::Error) -> bool {
+    error.kind() == io::ErrorKind::ConnectionRefused || format!("{:?}", error).contains("ConnectionRefused")
+}
@@ -0,0 +1,338 @@
+use libp2p::PeerId;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+const RATE_WINDOW: Duration = Duration::from_secs(2);
+
+struct BucketState {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+// Callers reserve bytes up front and sleep off any debt, so concurrent users queue fairly
+// behind each other and a request larger than the burst size still goes through eventually
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    state: Mutex<BucketState>,
+}
+
+impl TokenBucket {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let rate = bytes_per_sec.max(1) as f64;
+        Self {
+            rate,
+            capacity: rate,
+            state: Mutex::new(BucketState {
+                tokens: rate,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate as u64
+    }
+
+    pub async fn acquire(&self, bytes: u64) {
+        let wait = self.reserve(bytes);
+        if !wait.is_zero() {
+            sleep(wait).await;
+        }
+    }
+
+    fn reserve(&self, bytes: u64) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.rate).min(self.capacity);
+        state.last_refill = now;
+        state.tokens -= bytes as f64;
+
+        if state.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.tokens / self.rate)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PeerServeStats {
+    pub bytes_served: u64,
+    // Over the last couple of seconds
+    pub current_bps: u64,
+    // From its first request to its last chunk, waiting on the throttle included
+    pub average_bps: u64,
+}
+
+struct PeerEntry {
+    bucket: Option<Arc<TokenBucket>>,
+    bytes_served: u64,
+    recent: VecDeque<(Instant, u64)>,
+    started: Instant,
+    last: Instant,
+}
+
+impl PeerEntry {
+    fn record(&mut self, bytes: u64) {
+        let now = Instant::now();
+        self.bytes_served += bytes;
+        self.last = now;
+        self.recent.push_back((now, bytes));
+        while self.recent.front().is_some_and(|(at, _)| now.duration_since(*at) > RATE_WINDOW) {
+            self.recent.pop_front();
+        }
+    }
+
+    fn stats(&self) -> PeerServeStats {
+        let recent: u64 = self.recent.iter().map(|(_, b)| b).sum();
+        let serving = self.last.duration_since(self.started).max(Duration::from_millis(1));
+        PeerServeStats {
+            bytes_served: self.bytes_served,
+            current_bps: (recent as f64 / RATE_WINDOW.as_secs_f64()) as u64,
+            average_bps: (self.bytes_served as f64 / serving.as_secs_f64()) as u64,
+        }
+    }
+}
+
+// Each receiver gets its own bucket so one greedy peer can't starve the rest, and every
+// write additionally draws from the shared global bucket when one is configured
+pub struct ServeThrottle {
+    per_peer_bps: Option<u64>,
+    global: Option<Arc<TokenBucket>>,
+    peers: Mutex<HashMap<PeerId, PeerEntry>>,
+}
+
+impl ServeThrottle {
+    pub fn new(per_peer_bps: Option<u64>, global: Option<Arc<TokenBucket>>) -> Self {
+        Self {
+            per_peer_bps,
+            global,
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(None, None)
+    }
+
+    pub fn per_peer_bps(&self) -> Option<u64> {
+        self.per_peer_bps
+    }
+
+    pub async fn acquire(&self, peer_id: PeerId, bytes: u64) {
+        let bucket = {
+            let mut peers = self.peers.lock().unwrap();
+            let entry = peers.entry(peer_id).or_insert_with(|| PeerEntry {
+                bucket: self.per_peer_bps.map(|bps| Arc::new(TokenBucket::new(bps))),
+                bytes_served: 0,
+                recent: VecDeque::new(),
+                started: Instant::now(),
+                last: Instant::now(),
+            });
+            entry.bucket.clone()
+        };
+
+        if let Some(bucket) = bucket {
+            bucket.acquire(bytes).await;
+        }
+        if let Some(global) = &self.global {
+            global.acquire(bytes).await;
+        }
+
+        if let Some(entry) = self.peers.lock().unwrap().get_mut(&peer_id) {
+            entry.record(bytes);
+        }
+    }
+
+    pub fn peer_stats(&self) -> Vec<(PeerId, PeerServeStats)> {
+        let peers = self.peers.lock().unwrap();
+        let mut stats: Vec<_> = peers.iter().map(|(id, e)| (*id, e.stats())).collect();
+        stats.sort_by_key(|(_, s)| std::cmp::Reverse(s.bytes_served));
+        stats
+    }
+
+    pub fn forget_peer(&self, peer_id: &PeerId) {
+        self.peers.lock().unwrap().remove(peer_id);
+    }
+}
+
+// Requests each peer may make of a serve, counted whatever they ask for, and who is shut out for
+// a while for having made too many or ones no honest receiver would
+pub struct RequestGuard {
+    // Per second, with a second's worth as the burst; none when zero
+    rate: u64,
+    cooldown: Duration,
+    allowances: HashMap<PeerId, (f64, Instant)>,
+    banned: HashMap<PeerId, Instant>,
+}
+
+impl RequestGuard {
+    pub fn new(requests_per_sec: u64, cooldown: Duration) -> Self {
+        Self {
+            rate: requests_per_sec,
+            cooldown,
+            allowances: HashMap::new(),
+            banned: HashMap::new(),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    pub fn is_banned(&mut self, peer_id: &PeerId) -> bool {
+        self.is_banned_at(peer_id, Instant::now())
+    }
+
+    pub fn is_banned_at(&mut self, peer_id: &PeerId, now: Instant) -> bool {
+        match self.banned.get(peer_id) {
+            Some(until) if now < *until => true,
+            Some(_) => {
+                self.banned.remove(peer_id);
+                false
+            }
+            None => false,
+        }
+    }
+
+    // Takes one request from the peer's allowance, saying whether there was one
+    pub fn allow(&mut self, peer_id: PeerId) -> bool {
+        self.allow_at(peer_id, Instant::now())
+    }
+
+    pub fn allow_at(&mut self, peer_id: PeerId, now: Instant) -> bool {
+        if self.rate == 0 {
+            return true;
+        }
+        let rate = self.rate as f64;
+        let (tokens, last) = self.allowances.entry(peer_id).or_insert((rate, now));
+        *tokens = (*tokens + now.saturating_duration_since(*last).as_secs_f64() * rate).min(rate);
+        *last = now;
+        if *tokens < 1.0 {
+            return false;
+        }
+        *tokens -= 1.0;
+        true
+    }
+
+    pub fn ban(&mut self, peer_id: PeerId) {
+        self.ban_at(peer_id, Instant::now());
+    }
+
+    // The allowance starts afresh once the ban is over
+    pub fn ban_at(&mut self, peer_id: PeerId, now: Instant) {
+        self.allowances.remove(&peer_id);
+        self.banned.insert(peer_id, now + self.cooldown);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_token_bucket_limits_rate() {
+        let bucket = TokenBucket::new(100_000);
+        let start = Instant::now();
+
+        // The first 100 KB is the burst allowance, the next 50 KB has to wait
+        for _ in 0..15 {
+            bucket.acquire(10_000).await;
+        }
+
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(450), "finished too fast: {:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(900), "finished too slow: {:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn test_per_peer_caps_are_independent_and_fair() {
+        const CAP: u64 = 200_000;
+        const CHUNK: u64 = 20_000;
+
+        let throttle = Arc::new(ServeThrottle::new(Some(CAP), None));
+        let alice = PeerId::random();
+        let bob = PeerId::random();
+        let start = Instant::now();
+        let deadline = Duration::from_millis(1500);
+
+        let receivers = [alice, bob].map(|peer| {
+            let throttle = throttle.clone();
+            tokio::spawn(async move {
+                let mut served = 0u64;
+                while start.elapsed() < deadline {
+                    throttle.acquire(peer, CHUNK).await;
+                    served += CHUNK;
+                }
+                served
+            })
+        });
+
+        let mut totals = Vec::new();
+        for receiver in receivers {
+            totals.push(receiver.await.unwrap());
+        }
+        let elapsed = start.elapsed().as_secs_f64();
+
+        // Burst of one second's worth plus the sustained cap, with one chunk of slack
+        let ceiling = CAP + (CAP as f64 * elapsed) as u64 + CHUNK;
+        for total in &totals {
+            assert!(*total <= ceiling, "peer exceeded its cap: {} > {}", total, ceiling);
+        }
+        let (low, high) = (totals[0].min(totals[1]), totals[0].max(totals[1]));
+        assert!(high - low <= 2 * CHUNK, "peers progressed unevenly: {:?}", totals);
+
+        let stats = throttle.peer_stats();
+        assert_eq!(stats.len(), 2);
+        assert!(stats.iter().all(|(_, s)| s.bytes_served > 0 && s.current_bps > 0));
+        // The first second's burst is all that takes either over its cap on average
+        assert!(stats.iter().all(|(_, s)| s.average_bps > 0 && s.average_bps < 2 * CAP), "{:?}", stats);
+    }
+
+    #[tokio::test]
+    async fn test_global_bucket_caps_aggregate() {
+        let global = Arc::new(TokenBucket::new(100_000));
+        let throttle = ServeThrottle::new(None, Some(global));
+        let start = Instant::now();
+
+        for _ in 0..8 {
+            throttle.acquire(PeerId::random(), 25_000).await;
+        }
+
+        assert!(start.elapsed() >= Duration::from_millis(900));
+    }
+
+    #[test]
+    fn test_request_guard_bans_for_the_cooldown() {
+        let mut guard = RequestGuard::new(10, Duration::from_secs(60));
+        let (greedy, polite) = (PeerId::random(), PeerId::random());
+        let start = Instant::now();
+
+        // A second's worth at once, then one more every tenth of a second
+        assert!((0..10).all(|_| guard.allow_at(greedy, start)));
+        assert!(!guard.allow_at(greedy, start));
+        assert!(guard.allow_at(greedy, start + Duration::from_millis(100)));
+        assert!(guard.allow_at(polite, start));
+
+        guard.ban_at(greedy, start);
+        assert!(guard.is_banned_at(&greedy, start + Duration::from_secs(59)));
+        assert!(!guard.is_banned_at(&polite, start));
+        assert!(!guard.is_banned_at(&greedy, start + Duration::from_secs(60)));
+        assert!(guard.allow_at(greedy, start + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_request_guard_without_a_rate_allows_everything() {
+        let mut guard = RequestGuard::new(0, Duration::from_secs(1));
+        let peer = PeerId::random();
+        let now = Instant::now();
+
+        assert!((0..10_000).all(|_| guard.allow_at(peer, now)));
+        guard.ban_at(peer, now);
+        assert!(guard.is_banned_at(&peer, now));
+    }
+}
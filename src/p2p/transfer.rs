@@ -0,0 +1,789 @@
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use libp2p::StreamProtocol;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::io;
+use std::sync::Arc;
+use crate::bundle::wire;
+use crate::compression::CompressedChunk;
+use crate::error::Incompatibility;
+use super::announce::Announcement;
+use super::capabilities::Capabilities;
+use super::manifest::ChunkManifest;
+use super::receipt::SignedReceipt;
+
+// The chunk protocol: a receiver asks for one chunk by index on a fresh stream, and the sender
+// answers on the same stream with the chunk in its wire encoding, or a single zero byte if it
+// has nothing to give for that index. Once a chunk has checked out, the receiver acknowledges it
+// the same way, with the chunk's hash after its index, and gets the zero byte back. A receiver
+// with more than one sender to ask first finds out which chunks each has, with a single HAVE
+// byte where the hash would be, and gets back a bitmap of chunk indices
+const REQUEST_SIZE: usize = 8;
+const ACK_HASH_SIZE: usize = 32;
+const HAVE_REQUEST: u8 = 1;
+const NOT_OFFERED: u8 = 0;
+const CHUNK: u8 = 1;
+const HAVE: u8 = 2;
+// Enough for over eight million chunks
+pub const MAX_HAVE_SIZE: usize = 1024 * 1024;
+
+// The manifest protocol works the same way: a receiver asks by file hash, and the sender answers
+// with the manifest as length-prefixed JSON, or a single zero byte if it isn't serving that file.
+// A sender offers a manifest the other way round, with the manifest after the hash, and the
+// receiver answers with a bitmap of the manifest positions it has cached. A peer from before
+// offers reads the hash alone and says it isn't serving that file, which offers nothing either
+const FILE_HASH_SIZE: usize = 32;
+const MANIFEST: u8 = 1;
+const CACHED: u8 = 2;
+pub const MAX_MANIFEST_SIZE: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkRequest {
+    Chunk { index: usize },
+    // The chunk with this index arrived and hashes to `hash`
+    Ack { index: usize, hash: [u8; 32] },
+    // Which chunks the sender has to offer whoever asked
+    Have,
+}
+
+impl ChunkRequest {
+    pub fn index(&self) -> Option<usize> {
+        match *self {
+            ChunkRequest::Chunk { index } | ChunkRequest::Ack { index, .. } => Some(index),
+            ChunkRequest::Have => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ChunkResponse {
+    // The sender isn't offering that chunk to whoever asked, or the request was an acknowledgment
+    NotOffered,
+    // Shared, so a sender answering many receivers doesn't copy the chunk for each
+    Chunk(Arc<CompressedChunk>),
+    Have(HaveBitmap),
+}
+
+// One bit per chunk index, lowest first, set for each chunk a sender has to offer
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HaveBitmap(Vec<u8>);
+
+impl HaveBitmap {
+    pub fn from_indices(indices: impl IntoIterator<Item = usize>) -> Self {
+        let mut bits = Vec::new();
+        for index in indices {
+            if bits.len() <= index / 8 {
+                bits.resize(index / 8 + 1, 0);
+            }
+            bits[index / 8] |= 1 << (index % 8);
+        }
+        Self(bits)
+    }
+
+    pub fn contains(&self, index: usize) -> bool {
+        self.0.get(index / 8).is_some_and(|byte| byte & (1 << (index % 8)) != 0)
+    }
+
+    pub fn remove(&mut self, index: usize) {
+        if let Some(byte) = self.0.get_mut(index / 8) {
+            *byte &= !(1 << (index % 8));
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChunkCodec {
+    max_frame: usize,
+}
+
+impl Default for ChunkCodec {
+    fn default() -> Self {
+        Self { max_frame: wire::DEFAULT_MAX_FRAME }
+    }
+}
+
+impl ChunkCodec {
+    // Responses with a longer frame are refused before any of it is buffered
+    pub fn with_max_frame(mut self, max_frame: usize) -> Self {
+        self.max_frame = max_frame;
+        self
+    }
+}
+
+#[async_trait]
+impl libp2p::request_response::Codec for ChunkCodec {
+    type Protocol = StreamProtocol;
+    type Request = ChunkRequest;
+    type Response = ChunkResponse;
+
+    async fn read_request<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<ChunkRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut index = [0u8; REQUEST_SIZE];
+        io.read_exact(&mut index).await?;
+        let index = usize::try_from(u64::from_le_bytes(index))
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "chunk index out of range"))?;
+
+        let mut hash = Vec::with_capacity(ACK_HASH_SIZE);
+        io.take(ACK_HASH_SIZE as u64 + 1).read_to_end(&mut hash).await?;
+        match hash.len() {
+            0 => Ok(ChunkRequest::Chunk { index }),
+            1 if hash[0] == HAVE_REQUEST => Ok(ChunkRequest::Have),
+            ACK_HASH_SIZE => Ok(ChunkRequest::Ack { index, hash: hash.try_into().unwrap() }),
+            len => Err(invalid_data(format!("chunk request with {} bytes after the index", len))),
+        }
+    }
+
+    async fn read_response<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<ChunkResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut status = [0u8; 1];
+        io.read_exact(&mut status).await?;
+        match status[0] {
+            NOT_OFFERED => return Ok(ChunkResponse::NotOffered),
+            CHUNK => {}
+            HAVE => {
+                let mut prefix = [0u8; 4];
+                io.read_exact(&mut prefix).await?;
+                let len = u32::from_le_bytes(prefix) as usize;
+                if len > MAX_HAVE_SIZE {
+                    return Err(invalid_data(format!("have bitmap of {} bytes is over the {} byte limit", len, MAX_HAVE_SIZE)));
+                }
+                let mut bits = vec![0u8; len];
+                io.read_exact(&mut bits).await?;
+                return Ok(ChunkResponse::Have(HaveBitmap(bits)));
+            }
+            other => return Err(invalid_data(format!("unknown response status {}", other))),
+        }
+
+        let mut prefix = [0u8; wire::PREFIX_SIZE];
+        io.read_exact(&mut prefix).await?;
+        let len = u32::from_le_bytes(prefix) as usize;
+        if len > self.max_frame {
+            return Err(invalid_data(format!("chunk of {} bytes is over the {} byte limit", len, self.max_frame)));
+        }
+
+        let mut frame = prefix.to_vec();
+        io.take(len as u64).read_to_end(&mut frame).await?;
+        match wire::decode_chunk_limited(&frame, self.max_frame) {
+            Ok(Some((chunk, _))) => Ok(ChunkResponse::Chunk(Arc::new(chunk))),
+            Ok(None) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "stream ended partway through a chunk")),
+            Err(e) => Err(invalid_data(e.to_string())),
+        }
+    }
+
+    async fn write_request<T>(&mut self, _: &StreamProtocol, io: &mut T, request: ChunkRequest) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        io.write_all(&(request.index().unwrap_or(0) as u64).to_le_bytes()).await?;
+        match request {
+            ChunkRequest::Chunk { .. } => {}
+            ChunkRequest::Ack { hash, .. } => io.write_all(&hash).await?,
+            ChunkRequest::Have => io.write_all(&[HAVE_REQUEST]).await?,
+        }
+        io.close().await
+    }
+
+    async fn write_response<T>(&mut self, _: &StreamProtocol, io: &mut T, response: ChunkResponse) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        match response {
+            ChunkResponse::NotOffered => io.write_all(&[NOT_OFFERED]).await?,
+            ChunkResponse::Chunk(chunk) => {
+                let encoded = wire::encode_chunk(&chunk).map_err(|e| invalid_data(e.to_string()))?;
+                io.write_all(&[CHUNK]).await?;
+                io.write_all(&encoded).await?;
+            }
+            ChunkResponse::Have(bitmap) => {
+                if bitmap.0.len() > MAX_HAVE_SIZE {
+                    return Err(invalid_data(format!("have bitmap of {} bytes is over the {} byte limit", bitmap.0.len(), MAX_HAVE_SIZE)));
+                }
+                io.write_all(&[HAVE]).await?;
+                io.write_all(&(bitmap.0.len() as u32).to_le_bytes()).await?;
+                io.write_all(&bitmap.0).await?;
+            }
+        }
+        io.close().await
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ManifestRequest {
+    Fetch { file_hash: [u8; 32] },
+    // Which of this manifest's chunks the receiver has already
+    Offer(ChunkManifest),
+}
+
+impl ManifestRequest {
+    pub fn file_hash(&self) -> [u8; 32] {
+        match self {
+            ManifestRequest::Fetch { file_hash } => *file_hash,
+            ManifestRequest::Offer(manifest) => manifest.file_hash,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ManifestResponse {
+    NotOffered,
+    Manifest(ChunkManifest),
+    // One bit per position in an offered manifest, set for each chunk the receiver has cached
+    Cached(HaveBitmap),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ManifestCodec;
+
+#[async_trait]
+impl libp2p::request_response::Codec for ManifestCodec {
+    type Protocol = StreamProtocol;
+    type Request = ManifestRequest;
+    type Response = ManifestResponse;
+
+    async fn read_request<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<ManifestRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut file_hash = [0u8; FILE_HASH_SIZE];
+        io.read_exact(&mut file_hash).await?;
+
+        let mut prefix = Vec::with_capacity(4);
+        io.take(4).read_to_end(&mut prefix).await?;
+        match prefix.len() {
+            0 => return Ok(ManifestRequest::Fetch { file_hash }),
+            4 => {}
+            len => return Err(invalid_data(format!("manifest request with {} bytes after the hash", len))),
+        }
+        let manifest: ChunkManifest = read_manifest(io, u32::from_le_bytes(prefix.try_into().unwrap()) as usize).await?;
+        if manifest.file_hash != file_hash {
+            return Err(invalid_data("offered manifest is of another file".to_string()));
+        }
+        Ok(ManifestRequest::Offer(manifest))
+    }
+
+    async fn read_response<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<ManifestResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut status = [0u8; 1];
+        io.read_exact(&mut status).await?;
+        let mut prefix = [0u8; 4];
+        match status[0] {
+            NOT_OFFERED => Ok(ManifestResponse::NotOffered),
+            MANIFEST => {
+                io.read_exact(&mut prefix).await?;
+                read_manifest(io, u32::from_le_bytes(prefix) as usize).await.map(ManifestResponse::Manifest)
+            }
+            CACHED => {
+                io.read_exact(&mut prefix).await?;
+                let len = u32::from_le_bytes(prefix) as usize;
+                if len > MAX_HAVE_SIZE {
+                    return Err(invalid_data(format!("cached bitmap of {} bytes is over the {} byte limit", len, MAX_HAVE_SIZE)));
+                }
+                let mut bits = vec![0u8; len];
+                io.read_exact(&mut bits).await?;
+                Ok(ManifestResponse::Cached(HaveBitmap(bits)))
+            }
+            other => Err(invalid_data(format!("unknown response status {}", other))),
+        }
+    }
+
+    async fn write_request<T>(&mut self, _: &StreamProtocol, io: &mut T, request: ManifestRequest) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        io.write_all(&request.file_hash()).await?;
+        if let ManifestRequest::Offer(manifest) = &request {
+            write_manifest(io, manifest).await?;
+        }
+        io.close().await
+    }
+
+    async fn write_response<T>(&mut self, _: &StreamProtocol, io: &mut T, response: ManifestResponse) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        match response {
+            ManifestResponse::NotOffered => io.write_all(&[NOT_OFFERED]).await?,
+            ManifestResponse::Manifest(manifest) => {
+                io.write_all(&[MANIFEST]).await?;
+                write_manifest(io, &manifest).await?;
+            }
+            ManifestResponse::Cached(bitmap) => {
+                if bitmap.0.len() > MAX_HAVE_SIZE {
+                    return Err(invalid_data(format!("cached bitmap of {} bytes is over the {} byte limit", bitmap.0.len(), MAX_HAVE_SIZE)));
+                }
+                io.write_all(&[CACHED]).await?;
+                io.write_all(&(bitmap.0.len() as u32).to_le_bytes()).await?;
+                io.write_all(&bitmap.0).await?;
+            }
+        }
+        io.close().await
+    }
+}
+
+// The handshake, pairing, announce and receipt protocols carry length-prefixed JSON, none of it ever large
+pub const MAX_JSON_MESSAGE_SIZE: usize = 64 * 1024;
+
+// The handshake: before asking for a manifest, a receiver says what it can take, and the sender
+// answers with what its chunks need, or why the receiver can't have them
+pub type HelloRequest = Capabilities;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HelloResponse {
+    Accepted(Capabilities),
+    Incompatible(Incompatibility),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HelloCodec;
+
+#[async_trait]
+impl libp2p::request_response::Codec for HelloCodec {
+    type Protocol = StreamProtocol;
+    type Request = HelloRequest;
+    type Response = HelloResponse;
+
+    async fn read_request<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<HelloRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_json(io).await
+    }
+
+    async fn read_response<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<HelloResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_json(io).await
+    }
+
+    async fn write_request<T>(&mut self, _: &StreamProtocol, io: &mut T, request: HelloRequest) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_json(io, &request).await
+    }
+
+    async fn write_response<T>(&mut self, _: &StreamProtocol, io: &mut T, response: HelloResponse) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_json(io, &response).await
+    }
+}
+
+// The pairing protocol: a receiver with a code starts SPAKE2 with a sender that may be holding
+// it, then shows it came to the same keys
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PairRequest {
+    Start { nameplate: u16, message: [u8; 32] },
+    Confirm { confirmation: [u8; 32] },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PairResponse {
+    // Not holding a code with that number, or not any more
+    Unknown,
+    Started { message: [u8; 32], confirmation: [u8; 32] },
+    // The shr:// URL, sealed with the keys both sides came to
+    Offer { sealed: Vec<u8> },
+    Refused,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PairCodec;
+
+#[async_trait]
+impl libp2p::request_response::Codec for PairCodec {
+    type Protocol = StreamProtocol;
+    type Request = PairRequest;
+    type Response = PairResponse;
+
+    async fn read_request<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<PairRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_json(io).await
+    }
+
+    async fn read_response<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<PairResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_json(io).await
+    }
+
+    async fn write_request<T>(&mut self, _: &StreamProtocol, io: &mut T, request: PairRequest) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_json(io, &request).await
+    }
+
+    async fn write_response<T>(&mut self, _: &StreamProtocol, io: &mut T, response: PairResponse) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_json(io, &response).await
+    }
+}
+
+// The announce protocol: anyone on the local network can ask a sender what it's serving, and
+// gets an empty list unless `p2p.announce` is set and a serve is running
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AnnounceRequest;
+
+pub type AnnounceResponse = Vec<Announcement>;
+
+#[derive(Debug, Clone, Default)]
+pub struct AnnounceCodec;
+
+#[async_trait]
+impl libp2p::request_response::Codec for AnnounceCodec {
+    type Protocol = StreamProtocol;
+    type Request = AnnounceRequest;
+    type Response = AnnounceResponse;
+
+    async fn read_request<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<AnnounceRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_json(io).await
+    }
+
+    async fn read_response<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<AnnounceResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_json(io).await
+    }
+
+    async fn write_request<T>(&mut self, _: &StreamProtocol, io: &mut T, request: AnnounceRequest) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_json(io, &request).await
+    }
+
+    async fn write_response<T>(&mut self, _: &StreamProtocol, io: &mut T, response: AnnounceResponse) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_json(io, &response).await
+    }
+}
+
+// The receipt protocol: once the whole file has checked out, a receiver tells the sender so with
+// a receipt it signed, and hears whether it was taken. Senders from before receipts don't speak it
+pub type ReceiptRequest = SignedReceipt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReceiptResponse {
+    Recorded,
+    // Not for what's being served, not signed by who it names, or nothing is being served
+    Refused,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ReceiptCodec;
+
+#[async_trait]
+impl libp2p::request_response::Codec for ReceiptCodec {
+    type Protocol = StreamProtocol;
+    type Request = ReceiptRequest;
+    type Response = ReceiptResponse;
+
+    async fn read_request<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<ReceiptRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_json(io).await
+    }
+
+    async fn read_response<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<ReceiptResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_json(io).await
+    }
+
+    async fn write_request<T>(&mut self, _: &StreamProtocol, io: &mut T, request: ReceiptRequest) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_json(io, &request).await
+    }
+
+    async fn write_response<T>(&mut self, _: &StreamProtocol, io: &mut T, response: ReceiptResponse) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_json(io, &response).await
+    }
+}
+
+async fn read_json<T, M>(io: &mut T) -> io::Result<M>
+where
+    T: AsyncRead + Unpin + Send,
+    M: DeserializeOwned,
+{
+    let mut prefix = [0u8; 4];
+    io.read_exact(&mut prefix).await?;
+    let len = u32::from_le_bytes(prefix) as usize;
+    if len > MAX_JSON_MESSAGE_SIZE {
+        return Err(invalid_data(format!("message of {} bytes is over the {} byte limit", len, MAX_JSON_MESSAGE_SIZE)));
+    }
+
+    let mut json = vec![0u8; len];
+    io.read_exact(&mut json).await?;
+    serde_json::from_slice(&json).map_err(|e| invalid_data(format!("malformed message: {}", e)))
+}
+
+async fn write_json<T, M>(io: &mut T, message: &M) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin + Send,
+    M: Serialize,
+{
+    let json = serde_json::to_vec(message).map_err(|e| invalid_data(e.to_string()))?;
+    io.write_all(&(json.len() as u32).to_le_bytes()).await?;
+    io.write_all(&json).await?;
+    io.close().await
+}
+
+// `len` is the manifest's length prefix, already read
+async fn read_manifest<T>(io: &mut T, len: usize) -> io::Result<ChunkManifest>
+where
+    T: AsyncRead + Unpin + Send,
+{
+    if len > MAX_MANIFEST_SIZE {
+        return Err(invalid_data(format!("manifest of {} bytes is over the {} byte limit", len, MAX_MANIFEST_SIZE)));
+    }
+    let mut json = vec![0u8; len];
+    io.read_exact(&mut json).await?;
+    serde_json::from_slice(&json).map_err(|e| invalid_data(format!("malformed manifest: {}", e)))
+}
+
+async fn write_manifest<T>(io: &mut T, manifest: &ChunkManifest) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin + Send,
+{
+    let json = serde_json::to_vec(manifest).map_err(|e| invalid_data(e.to_string()))?;
+    if json.len() > MAX_MANIFEST_SIZE {
+        return Err(invalid_data(format!("manifest of {} bytes is over the {} byte limit", json.len(), MAX_MANIFEST_SIZE)));
+    }
+    io.write_all(&(json.len() as u32).to_le_bytes()).await?;
+    io.write_all(&json).await
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::io::Cursor;
+    use libp2p::request_response::Codec;
+    use crate::p2p::receipt::TransferReceipt;
+
+    fn protocol() -> StreamProtocol {
+        StreamProtocol::new(super::super::PROTOCOL_VERSION)
+    }
+
+    async fn roundtrip(codec: &mut ChunkCodec, response: ChunkResponse) -> io::Result<ChunkResponse> {
+        let mut buf = Cursor::new(Vec::new());
+        codec.write_response(&protocol(), &mut buf, response).await?;
+        codec.read_response(&protocol(), &mut Cursor::new(buf.into_inner())).await
+    }
+
+    #[tokio::test]
+    async fn test_codec_roundtrip() {
+        let mut codec = ChunkCodec::default();
+        let mut buf = Cursor::new(Vec::new());
+        codec.write_request(&protocol(), &mut buf, ChunkRequest::Chunk { index: 70_000 }).await.unwrap();
+        let request = codec.read_request(&protocol(), &mut Cursor::new(buf.into_inner())).await.unwrap();
+        assert_eq!(request, ChunkRequest::Chunk { index: 70_000 });
+
+        let ack = ChunkRequest::Ack { index: 70_000, hash: [4; 32] };
+        let mut buf = Cursor::new(Vec::new());
+        codec.write_request(&protocol(), &mut buf, ack).await.unwrap();
+        assert_eq!(codec.read_request(&protocol(), &mut Cursor::new(buf.into_inner())).await.unwrap(), ack);
+
+        let mut buf = Cursor::new(Vec::new());
+        codec.write_request(&protocol(), &mut buf, ChunkRequest::Have).await.unwrap();
+        assert_eq!(codec.read_request(&protocol(), &mut Cursor::new(buf.into_inner())).await.unwrap(), ChunkRequest::Have);
+
+        let chunk = crate::compression::ParallelCompressor::default().compress_chunk(3, b"over the wire ".repeat(100)).unwrap();
+        let ChunkResponse::Chunk(received) = roundtrip(&mut codec, ChunkResponse::Chunk(Arc::new(chunk.clone()))).await.unwrap() else { panic!() };
+        assert_eq!((received.index, received.hash, &received.data), (3, chunk.hash, &chunk.data));
+        assert!(matches!(roundtrip(&mut codec, ChunkResponse::NotOffered).await.unwrap(), ChunkResponse::NotOffered));
+
+        let have = HaveBitmap::from_indices([0, 9, 70_000]);
+        let ChunkResponse::Have(received) = roundtrip(&mut codec, ChunkResponse::Have(have.clone())).await.unwrap() else { panic!() };
+        assert_eq!(received, have);
+
+        // A frame over the limit is refused from its prefix alone
+        assert!(roundtrip(&mut ChunkCodec::default().with_max_frame(16), ChunkResponse::Chunk(Arc::new(chunk))).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_damaged_responses_are_refused() {
+        let mut codec = ChunkCodec::default();
+        let chunk = crate::compression::ParallelCompressor::default().compress_chunk(0, vec![5; 2048]).unwrap();
+        let mut buf = Cursor::new(Vec::new());
+        codec.write_response(&protocol(), &mut buf, ChunkResponse::Chunk(Arc::new(chunk))).await.unwrap();
+        let encoded = buf.into_inner();
+
+        for len in 0..encoded.len() {
+            assert!(codec.read_response(&protocol(), &mut Cursor::new(&encoded[..len])).await.is_err(), "{} bytes", len);
+        }
+        assert!(codec.read_response(&protocol(), &mut Cursor::new([7u8])).await.is_err());
+        assert!(codec.read_request(&protocol(), &mut Cursor::new([1u8, 2, 3])).await.is_err());
+        // An acknowledgment cut short of its hash
+        assert!(codec.read_request(&protocol(), &mut Cursor::new([1u8; 20])).await.is_err());
+        let mut oversized = vec![HAVE];
+        oversized.extend_from_slice(&(MAX_HAVE_SIZE as u32 + 1).to_le_bytes());
+        assert!(codec.read_response(&protocol(), &mut Cursor::new(oversized)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_manifest_codec_roundtrip() {
+        let mut codec = ManifestCodec;
+        let mut buf = Cursor::new(Vec::new());
+        codec.write_request(&protocol(), &mut buf, ManifestRequest::Fetch { file_hash: [9; 32] }).await.unwrap();
+        let request = codec.read_request(&protocol(), &mut Cursor::new(buf.into_inner())).await.unwrap();
+        assert!(matches!(request, ManifestRequest::Fetch { file_hash: [9, ..] }));
+
+        let chunk = crate::compression::ParallelCompressor::default().compress_chunk(0, vec![1; 4096]).unwrap();
+        let manifest = ChunkManifest::from_chunks([9; 32], &[chunk]);
+        let mut buf = Cursor::new(Vec::new());
+        codec.write_response(&protocol(), &mut buf, ManifestResponse::Manifest(manifest.clone())).await.unwrap();
+        let encoded = buf.into_inner();
+        let ManifestResponse::Manifest(received) = codec.read_response(&protocol(), &mut Cursor::new(&encoded)).await.unwrap() else { panic!() };
+        assert_eq!((received.file_hash, received.entries), (manifest.file_hash, manifest.entries.clone()));
+
+        for len in 0..encoded.len() {
+            assert!(codec.read_response(&protocol(), &mut Cursor::new(&encoded[..len])).await.is_err(), "{} bytes", len);
+        }
+        let mut oversized = vec![MANIFEST];
+        oversized.extend_from_slice(&(MAX_MANIFEST_SIZE as u32 + 1).to_le_bytes());
+        assert!(codec.read_response(&protocol(), &mut Cursor::new(oversized)).await.is_err());
+
+        // An offer carries the manifest after the hash, and is answered with what's cached
+        let mut buf = Cursor::new(Vec::new());
+        codec.write_request(&protocol(), &mut buf, ManifestRequest::Offer(manifest.clone())).await.unwrap();
+        let encoded = buf.into_inner();
+        let ManifestRequest::Offer(offered) = codec.read_request(&protocol(), &mut Cursor::new(&encoded)).await.unwrap() else { panic!() };
+        assert_eq!(offered.entries, manifest.entries);
+        for len in FILE_HASH_SIZE + 1..encoded.len() {
+            assert!(codec.read_request(&protocol(), &mut Cursor::new(&encoded[..len])).await.is_err(), "{} bytes", len);
+        }
+        let mut elsewhere = encoded.clone();
+        elsewhere[0] ^= 1;
+        assert!(codec.read_request(&protocol(), &mut Cursor::new(elsewhere)).await.is_err());
+
+        let cached = HaveBitmap::from_indices([0]);
+        let mut buf = Cursor::new(Vec::new());
+        codec.write_response(&protocol(), &mut buf, ManifestResponse::Cached(cached.clone())).await.unwrap();
+        let ManifestResponse::Cached(received) = codec.read_response(&protocol(), &mut Cursor::new(buf.into_inner())).await.unwrap() else { panic!() };
+        assert_eq!(received, cached);
+    }
+
+    #[tokio::test]
+    async fn test_pair_codec_roundtrip() {
+        let mut codec = PairCodec;
+        let request = PairRequest::Start { nameplate: 7, message: [3; 32] };
+        let mut buf = Cursor::new(Vec::new());
+        codec.write_request(&protocol(), &mut buf, request.clone()).await.unwrap();
+        assert_eq!(codec.read_request(&protocol(), &mut Cursor::new(buf.into_inner())).await.unwrap(), request);
+
+        let response = PairResponse::Offer { sealed: vec![1, 2, 3] };
+        let mut buf = Cursor::new(Vec::new());
+        codec.write_response(&protocol(), &mut buf, response.clone()).await.unwrap();
+        let encoded = buf.into_inner();
+        assert_eq!(codec.read_response(&protocol(), &mut Cursor::new(&encoded)).await.unwrap(), response);
+
+        for len in 0..encoded.len() {
+            assert!(codec.read_response(&protocol(), &mut Cursor::new(&encoded[..len])).await.is_err(), "{} bytes", len);
+        }
+        let oversized = (MAX_JSON_MESSAGE_SIZE as u32 + 1).to_le_bytes();
+        assert!(codec.read_request(&protocol(), &mut Cursor::new(oversized)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_hello_codec_roundtrip() {
+        let mut codec = HelloCodec;
+        let mut buf = Cursor::new(Vec::new());
+        codec.write_request(&protocol(), &mut buf, Capabilities::local()).await.unwrap();
+        assert_eq!(codec.read_request(&protocol(), &mut Cursor::new(buf.into_inner())).await.unwrap(), Capabilities::local());
+
+        let response = HelloResponse::Incompatible(Incompatibility::ChunkSize { largest: 100, max: 10 });
+        let mut buf = Cursor::new(Vec::new());
+        codec.write_response(&protocol(), &mut buf, response.clone()).await.unwrap();
+        assert_eq!(codec.read_response(&protocol(), &mut Cursor::new(buf.into_inner())).await.unwrap(), response);
+    }
+
+    #[tokio::test]
+    async fn test_announce_codec_roundtrip() {
+        let mut codec = AnnounceCodec;
+        let mut buf = Cursor::new(Vec::new());
+        codec.write_request(&protocol(), &mut buf, AnnounceRequest).await.unwrap();
+        assert_eq!(codec.read_request(&protocol(), &mut Cursor::new(buf.into_inner())).await.unwrap(), AnnounceRequest);
+
+        let response = vec![Announcement {
+            file_name: "notes.txt".to_string(),
+            size: 42,
+            file_hash: "ab".repeat(32),
+            peer_id: "12D3KooW".to_string(),
+            url: "shr://12D3KooW/ab".to_string(),
+        }];
+        let mut buf = Cursor::new(Vec::new());
+        codec.write_response(&protocol(), &mut buf, response.clone()).await.unwrap();
+        assert_eq!(codec.read_response(&protocol(), &mut Cursor::new(buf.into_inner())).await.unwrap(), response);
+    }
+
+    #[tokio::test]
+    async fn test_receipt_codec_roundtrip() {
+        let mut codec = ReceiptCodec;
+        let keypair = libp2p::identity::Keypair::generate_ed25519();
+        let receipt = TransferReceipt {
+            file_hash: [5; 32],
+            bytes: 4096,
+            duration: std::time::Duration::from_millis(250),
+            receiver_peer_id: keypair.public().to_peer_id().to_string(),
+            receiver_name: Some("alice-laptop".to_string()),
+        }
+        .sign(&keypair)
+        .unwrap();
+        let mut buf = Cursor::new(Vec::new());
+        codec.write_request(&protocol(), &mut buf, receipt.clone()).await.unwrap();
+        let decoded = codec.read_request(&protocol(), &mut Cursor::new(buf.into_inner())).await.unwrap();
+        assert_eq!(decoded.receipt, receipt.receipt);
+        assert_eq!(decoded.verify(&[5; 32]).unwrap(), keypair.public().to_peer_id());
+
+        let mut buf = Cursor::new(Vec::new());
+        codec.write_response(&protocol(), &mut buf, ReceiptResponse::Refused).await.unwrap();
+        assert_eq!(codec.read_response(&protocol(), &mut Cursor::new(buf.into_inner())).await.unwrap(), ReceiptResponse::Refused);
+    }
+
+    #[test]
+    fn test_have_bitmap() {
+        let mut have = HaveBitmap::from_indices([1, 8, 17]);
+        assert_eq!(have.as_bytes(), &[0b10, 0b1, 0b10]);
+        assert!((0..30).filter(|&i| have.contains(i)).eq([1, 8, 17]));
+        have.remove(8);
+        have.remove(1000);
+        assert!(!have.contains(8) && have.contains(17));
+        assert!(!HaveBitmap::default().contains(0));
+    }
+}
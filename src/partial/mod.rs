@@ -0,0 +1,301 @@
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use crate::bundle::wire;
+use crate::compression::CompressedChunk;
+use crate::{Result, ShrLinkError};
+
+pub const PARTIAL_SUFFIX: &str = ".shrpart";
+
+const MAGIC: &[u8; 8] = b"SHRPART1";
+const HEADER_SIZE: usize = MAGIC.len() + 32 + 8;
+const POSITION_SIZE: usize = 8;
+
+// What an interrupted receive got, kept beside its output so `shr recv --resume` can carry on
+// from there. A header names the file and how many chunks it has, then one bit per chunk is set
+// once that chunk is safely in the data after it. The data is each chunk received from a peer
+// after its position in the manifest, or for a download over HTTP (no chunks known up front)
+// the body as it arrived
+pub struct PartialDownload {
+    path: PathBuf,
+    file: File,
+    file_hash: [u8; 32],
+    bitmap: Vec<u8>,
+    data_len: u64,
+    chunks: Vec<Option<CompressedChunk>>,
+}
+
+impl PartialDownload {
+    pub fn path_for(output: &Path) -> PathBuf {
+        let mut path = output.as_os_str().to_owned();
+        path.push(PARTIAL_SUFFIX);
+        PathBuf::from(path)
+    }
+
+    // Starts afresh at `path`, replacing whatever was there
+    pub fn create(path: impl Into<PathBuf>, file_hash: [u8; 32], chunk_count: usize) -> Result<Self> {
+        let path = path.into();
+        let file = File::options().read(true).write(true).create(true).truncate(true).open(&path)?;
+        let mut partial = Self {
+            path,
+            file,
+            file_hash,
+            bitmap: vec![0; chunk_count.div_ceil(8)],
+            data_len: 0,
+            chunks: (0..chunk_count).map(|_| None).collect(),
+        };
+        partial.clear()?;
+        Ok(partial)
+    }
+
+    // The receive left at `path`, or None if there isn't one. It has to be of the same file, cut
+    // into as many chunks; whatever was being written when it stopped is dropped
+    pub fn open(path: impl Into<PathBuf>, file_hash: [u8; 32], chunk_count: usize) -> Result<Option<Self>> {
+        let path = path.into();
+        let mut file = match File::options().read(true).write(true).open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+
+        let not_partial = || ShrLinkError::InvalidInput(format!("{} isn't a partial download", path.display()));
+        let header = contents.get(..HEADER_SIZE).filter(|h| h.starts_with(MAGIC)).ok_or_else(not_partial)?;
+        let found_hash: [u8; 32] = header[MAGIC.len()..MAGIC.len() + 32].try_into().unwrap();
+        if found_hash != file_hash {
+            return Err(ShrLinkError::InvalidInput(format!(
+                "{} is a partial download of {}, not {}",
+                path.display(), hex::encode(found_hash), hex::encode(file_hash)
+            )));
+        }
+        let found_count = u64::from_le_bytes(header[MAGIC.len() + 32..].try_into().unwrap());
+        if found_count != chunk_count as u64 {
+            return Err(ShrLinkError::InvalidInput(format!(
+                "{} is a partial download of {} chunks, not {}",
+                path.display(), found_count, chunk_count
+            )));
+        }
+        let bitmap = contents.get(HEADER_SIZE..HEADER_SIZE + chunk_count.div_ceil(8)).ok_or_else(not_partial)?.to_vec();
+        let data = &contents[HEADER_SIZE + bitmap.len()..];
+
+        let mut partial = Self {
+            path,
+            file,
+            file_hash,
+            bitmap,
+            data_len: data.len() as u64,
+            chunks: (0..chunk_count).map(|_| None).collect(),
+        };
+        if chunk_count > 0 {
+            // Chunks are only counted once their bit is set, which happens after they're written
+            let mut used = 0;
+            while let Some((position, chunk, len)) = decode_record(&data[used..]) {
+                if partial.is_set(position) {
+                    partial.chunks[position] = Some(chunk);
+                }
+                used += len;
+            }
+            if used < data.len() {
+                tracing::debug!("Dropping {} bytes of an unfinished chunk from {}", data.len() - used, partial.path.display());
+                partial.data_len = used as u64;
+                partial.file.set_len(partial.data_start() + partial.data_len)?;
+            }
+        }
+        Ok(Some(partial))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // How many chunks it has, whether or not they've been taken
+    pub fn received(&self) -> usize {
+        (0..self.chunks.len()).filter(|&position| self.is_set(position)).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data_len == 0
+    }
+
+    // Chunks by their position in the manifest, as `P2PClient::resume_chunks` takes them
+    pub fn take_chunks(&mut self) -> Vec<Option<CompressedChunk>> {
+        self.chunks.iter_mut().map(Option::take).collect()
+    }
+
+    pub fn record(&mut self, position: usize, chunk: &CompressedChunk) -> Result<()> {
+        if position >= self.chunks.len() {
+            return Err(ShrLinkError::InvalidInput(format!(
+                "Chunk position {} is past the {} chunks of {}",
+                position, self.chunks.len(), self.path.display()
+            )));
+        }
+        let mut record = (position as u64).to_le_bytes().to_vec();
+        record.extend_from_slice(&wire::encode_chunk(chunk)?);
+        self.append(&record)?;
+
+        let byte = position / 8;
+        self.bitmap[byte] |= 1 << (position % 8);
+        self.file.seek(SeekFrom::Start((HEADER_SIZE + byte) as u64))?;
+        self.file.write_all(&self.bitmap[byte..byte + 1])?;
+        Ok(())
+    }
+
+    // How much of an HTTP body it has
+    pub fn body_len(&self) -> u64 {
+        self.data_len
+    }
+
+    pub fn body(&mut self) -> Result<Vec<u8>> {
+        let mut body = Vec::with_capacity(self.data_len as usize);
+        self.file.seek(SeekFrom::Start(self.data_start()))?;
+        (&mut self.file).take(self.data_len).read_to_end(&mut body)?;
+        Ok(body)
+    }
+
+    pub fn append(&mut self, data: &[u8]) -> Result<()> {
+        self.file.seek(SeekFrom::Start(self.data_start() + self.data_len))?;
+        self.file.write_all(data)?;
+        self.data_len += data.len() as u64;
+        Ok(())
+    }
+
+    // Back to nothing received, for when what it has can't be picked up from after all
+    pub fn clear(&mut self) -> Result<()> {
+        self.bitmap.fill(0);
+        self.chunks.iter_mut().for_each(|c| *c = None);
+        self.data_len = 0;
+
+        let mut header = Vec::with_capacity(HEADER_SIZE + self.bitmap.len());
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&self.file_hash);
+        header.extend_from_slice(&(self.chunks.len() as u64).to_le_bytes());
+        header.extend_from_slice(&self.bitmap);
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&header)?;
+        Ok(())
+    }
+
+    // Once the receive it was for has finished
+    pub fn remove(self) -> Result<()> {
+        drop(self.file);
+        match fs::remove_file(&self.path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn data_start(&self) -> u64 {
+        (HEADER_SIZE + self.bitmap.len()) as u64
+    }
+
+    fn is_set(&self, position: usize) -> bool {
+        position < self.chunks.len() && self.bitmap[position / 8] & (1 << (position % 8)) != 0
+    }
+}
+
+// A recorded chunk, its position and how many bytes it took, or None if it was cut short
+fn decode_record(input: &[u8]) -> Option<(usize, CompressedChunk, usize)> {
+    let position = u64::from_le_bytes(input.get(..POSITION_SIZE)?.try_into().unwrap());
+    let (chunk, len) = wire::decode_chunk(&input[POSITION_SIZE..]).ok()??;
+    Some((usize::try_from(position).ok()?, chunk, POSITION_SIZE + len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::ParallelCompressor;
+
+    fn chunks(count: usize) -> Vec<CompressedChunk> {
+        let compressor = ParallelCompressor::default();
+        (0..count).map(|i| compressor.compress_chunk(i, vec![i as u8; 4096]).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_recorded_chunks_survive_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = PartialDownload::path_for(&dir.path().join("out.bin"));
+        assert_eq!(path, dir.path().join("out.bin.shrpart"));
+        let chunks = chunks(10);
+
+        let mut partial = PartialDownload::create(&path, [1; 32], 10).unwrap();
+        for position in [0, 3, 9] {
+            partial.record(position, &chunks[position]).unwrap();
+        }
+        assert!(partial.record(10, &chunks[0]).is_err());
+        drop(partial);
+
+        let mut partial = PartialDownload::open(&path, [1; 32], 10).unwrap().unwrap();
+        assert_eq!(partial.received(), 3);
+        let kept = partial.take_chunks();
+        for (position, chunk) in kept.iter().enumerate() {
+            match [0, 3, 9].contains(&position) {
+                true => assert_eq!(chunk.as_ref().map(|c| &c.data), Some(&chunks[position].data)),
+                false => assert!(chunk.is_none()),
+            }
+        }
+
+        partial.remove().unwrap();
+        assert!(!path.exists());
+        assert!(PartialDownload::open(&path, [1; 32], 10).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_unfinished_chunk_is_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.bin.shrpart");
+        let chunks = chunks(4);
+
+        let mut partial = PartialDownload::create(&path, [1; 32], 4).unwrap();
+        partial.record(0, &chunks[0]).unwrap();
+        // Killed part way through writing chunk 1, before its bit was set
+        let mut record = 1u64.to_le_bytes().to_vec();
+        record.extend_from_slice(&wire::encode_chunk(&chunks[1]).unwrap());
+        partial.append(&record[..record.len() / 2]).unwrap();
+        drop(partial);
+
+        let mut partial = PartialDownload::open(&path, [1; 32], 4).unwrap().unwrap();
+        assert_eq!(partial.received(), 1);
+        partial.record(2, &chunks[2]).unwrap();
+        drop(partial);
+
+        let mut partial = PartialDownload::open(&path, [1; 32], 4).unwrap().unwrap();
+        let kept: Vec<_> = partial.take_chunks().iter().map(Option::is_some).collect();
+        assert_eq!(kept, [true, false, true, false]);
+    }
+
+    #[test]
+    fn test_another_files_partial_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.bin.shrpart");
+        PartialDownload::create(&path, [1; 32], 4).unwrap();
+
+        let err = PartialDownload::open(&path, [2; 32], 4).err().unwrap();
+        assert!(err.to_string().contains(&hex::encode([1; 32])), "{}", err);
+        assert!(PartialDownload::open(&path, [1; 32], 5).is_err());
+
+        fs::write(&path, b"not a partial download").unwrap();
+        assert!(matches!(PartialDownload::open(&path, [1; 32], 4), Err(ShrLinkError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn test_body_appends_and_clears() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.bin.shrpart");
+
+        let mut partial = PartialDownload::create(&path, [1; 32], 0).unwrap();
+        partial.append(b"SHR").unwrap();
+        partial.append(b"body").unwrap();
+        drop(partial);
+
+        let mut partial = PartialDownload::open(&path, [1; 32], 0).unwrap().unwrap();
+        assert_eq!(partial.body_len(), 7);
+        assert_eq!(partial.body().unwrap(), b"SHRbody");
+        partial.clear().unwrap();
+        assert_eq!(partial.body().unwrap(), b"");
+        partial.append(b"again").unwrap();
+        assert_eq!(partial.body().unwrap(), b"again");
+    }
+}
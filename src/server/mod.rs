@@ -0,0 +1,1003 @@
+use axum::body::{boxed, Bytes, StreamBody};
+use axum::extract::multipart::MultipartError;
+use axum::extract::{BodyStream, DefaultBodyLimit, FromRef, FromRequestParts, Multipart, Path as AxumPath, Query, State};
+use axum::http::header::{HeaderMap, ACCEPT_RANGES, AUTHORIZATION, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, FORWARDED, HOST, RANGE, WWW_AUTHENTICATE};
+use axum::http::request::Parts;
+use axum::http::{Method, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post, put};
+use axum::{Json, Router};
+use futures::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+use crate::{Result, ShrLinkError};
+use crate::fallback::{CleanupReport, FileInfo, FileStats, BUNDLE_HASH_HEADER, EXPIRES_HEADER, ONCE_HEADER};
+use crate::filename;
+use crate::temp::{ScratchKind, TempGuard};
+
+pub const INDEX_FILE: &str = "index.json";
+// Uploads live in a directory of their own, so no upload can be named over the index
+const FILES_DIR: &str = "files";
+// Parts of uploads sent in parts, a directory for each until it's complete
+const PARTS_DIR: &str = "parts";
+// The most parts one upload can be sent in, as S3 allows
+const MAX_PARTS: u32 = 10_000;
+
+// How long a one-time file's bytes outlive the download that used it up
+const CONSUMED_DISCARD_DELAY: Duration = Duration::from_secs(5);
+// How often download counts are written to the index, rather than on every download
+const COUNTS_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredFile {
+    pub size: u64,
+    pub uploaded_at: u64,
+    // When the uploader asked for it to go, which cleanup honours over the age it's given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    // Only to be downloaded once; `consumed` once it has been, after which the entry stays
+    // without its file, so later requests hear it's gone rather than that it never was
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub once: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub consumed: bool,
+    // The bundle's BLAKE3 in hex, as the uploader sent it, for downloads to check the body by
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bundle_hash: Option<String>,
+    // Downloads that got as far as the last byte, for `shr stats --detailed`
+    #[serde(default)]
+    pub downloads: u64,
+}
+
+impl StoredFile {
+    fn expired(&self, max_age_secs: u64, now: u64) -> bool {
+        match self.expires_at {
+            Some(expires_at) => now >= expires_at,
+            None => now.saturating_sub(self.uploaded_at) > max_age_secs,
+        }
+    }
+}
+
+// An upload being sent in parts, to be filed as `name` once complete
+#[derive(Debug, Clone)]
+struct PendingUpload {
+    name: String,
+    options: UploadOptions,
+    started_at: u64,
+}
+
+// The uploads a server holds, by the sanitized name each was filed under. Only what's in the
+// index is served, and its upload times are what cleanup expires by
+#[derive(Debug)]
+pub struct FileStore {
+    dir: PathBuf,
+    index: Mutex<BTreeMap<String, StoredFile>>,
+    // Uploads under way in parts, by the id each was given. Not kept across restarts, so their
+    // parts aren't either
+    pending: Mutex<HashMap<String, PendingUpload>>,
+    // Download counts in `index` that aren't in the saved index yet
+    counts_unsaved: AtomicBool,
+}
+
+impl FileStore {
+    // Entries whose file has since gone missing are dropped, except consumed ones, whose file
+    // is removed if it's still there
+    pub fn open(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir.join(FILES_DIR))?;
+        let index: BTreeMap<String, StoredFile> = match fs::read(dir.join(INDEX_FILE)) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| ShrLinkError::InvalidInput(format!("Corrupt index {}: {}", dir.join(INDEX_FILE).display(), e)))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let files = dir.join(FILES_DIR);
+        for (name, _) in index.iter().filter(|(_, file)| file.consumed) {
+            remove_if_present(&files.join(name))?;
+        }
+        let index = index.into_iter().filter(|(name, file)| file.consumed || files.join(name).is_file()).collect();
+        remove_dir_if_present(&dir.join(PARTS_DIR))?;
+
+        Ok(Self { dir: dir.to_path_buf(), index: Mutex::new(index), pending: Mutex::new(HashMap::new()), counts_unsaved: AtomicBool::new(false) })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(FILES_DIR).join(name)
+    }
+
+    // Only ever built for ids `begin_parts` handed out, so nothing in one can reach elsewhere
+    fn part_path(&self, id: &str, part: u32) -> PathBuf {
+        self.dir.join(PARTS_DIR).join(id).join(part.to_string())
+    }
+
+    // Opens an upload to be sent in parts, returning the id its parts go under
+    fn begin_parts(&self, name: String, options: UploadOptions, now: u64) -> Result<String> {
+        let id = Uuid::new_v4().simple().to_string();
+        fs::create_dir_all(self.dir.join(PARTS_DIR).join(&id))?;
+        self.pending.lock().unwrap().insert(id.clone(), PendingUpload { name, options, started_at: now });
+        Ok(id)
+    }
+
+    fn pending_upload(&self, id: &str) -> Option<PendingUpload> {
+        self.pending.lock().unwrap().get(id).cloned()
+    }
+
+    // The numbers of the parts of `id` that have arrived, in order
+    fn uploaded_parts(&self, id: &str) -> Result<Vec<u32>> {
+        let mut parts = Vec::new();
+        for entry in fs::read_dir(self.dir.join(PARTS_DIR).join(id))? {
+            // Scratch files of parts still arriving have names of their own
+            if let Some(part) = entry?.file_name().to_str().and_then(|name| name.parse::<u32>().ok()) {
+                parts.push(part);
+            }
+        }
+        parts.sort_unstable();
+        Ok(parts)
+    }
+
+    // Forgets an upload in parts along with whatever parts of it arrived
+    fn end_parts(&self, id: &str) -> Result<()> {
+        self.pending.lock().unwrap().remove(id);
+        remove_dir_if_present(&self.dir.join(PARTS_DIR).join(id))
+    }
+
+    pub fn get(&self, name: &str) -> Option<StoredFile> {
+        self.index.lock().unwrap().get(name).cloned()
+    }
+
+    // (files, bytes), not counting consumed ones
+    pub fn totals(&self) -> (usize, u64) {
+        let index = self.index.lock().unwrap();
+        let held = index.values().filter(|f| !f.consumed);
+        (held.clone().count(), held.map(|f| f.size).sum())
+    }
+
+    // The files `totals` counts, one by one
+    pub fn files(&self) -> Vec<FileStats> {
+        let index = self.index.lock().unwrap();
+        index
+            .iter()
+            .filter(|(_, file)| !file.consumed)
+            .map(|(name, file)| FileStats {
+                name: name.clone(),
+                size: file.size,
+                uploaded_at: file.uploaded_at,
+                expires_at: file.expires_at,
+                downloads: file.downloads,
+            })
+            .collect()
+    }
+
+    // Kept in the index, so the counts outlive the server, but only saved with whatever's saved
+    // next or by `save_counts`
+    pub fn count_download(&self, name: &str) {
+        let mut index = self.index.lock().unwrap();
+        if let Some(file) = index.get_mut(name) {
+            file.downloads += 1;
+            self.counts_unsaved.store(true, Ordering::Relaxed);
+        }
+    }
+
+    // Saves the index if downloads were counted since it last was
+    pub fn save_counts(&self) -> Result<()> {
+        let index = self.index.lock().unwrap();
+        if self.counts_unsaved.load(Ordering::Relaxed) {
+            self.save(&index)?;
+        }
+        Ok(())
+    }
+
+    // Claims a one-time file for the download about to finish with it. Only the first claim
+    // succeeds; the file itself is left for `discard`
+    pub fn consume(&self, name: &str) -> Result<bool> {
+        let mut index = self.index.lock().unwrap();
+        match index.get_mut(name) {
+            Some(file) if !file.consumed => {
+                file.consumed = true;
+                self.save(&index)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    // Removes a consumed entry's file; the entry itself stays until cleanup expires it
+    fn discard(&self, name: &str) -> Result<()> {
+        remove_if_present(&self.path(name))
+    }
+
+    // Takes the upload staged in `staged` as `name`, replacing any earlier file of that name
+    fn insert(&self, name: &str, staged: TempGuard, file: StoredFile) -> Result<()> {
+        let mut index = self.index.lock().unwrap();
+        staged.commit(&self.path(name))?;
+        index.insert(name.to_string(), file);
+        self.save(&index)
+    }
+
+    // Whether there was such a file
+    pub fn remove(&self, name: &str) -> Result<bool> {
+        let mut index = self.index.lock().unwrap();
+        if index.remove(name).is_none() {
+            return Ok(false);
+        }
+        remove_if_present(&self.path(name))?;
+        self.save(&index)?;
+        Ok(true)
+    }
+
+    // Removes everything uploaded more than `max_age_secs` before `now`, or past the expiry it
+    // was uploaded with, returning what went; with `dry_run` nothing is removed, only listed.
+    // Consumed files are listed without a size, their bytes having gone already. Uploads in parts
+    // started that long ago and never completed go too, without being listed
+    pub fn expire(&self, max_age_secs: u64, now: u64, dry_run: bool) -> Result<Vec<FileInfo>> {
+        if !dry_run {
+            let abandoned: Vec<String> = self.pending.lock().unwrap()
+                .iter()
+                .filter(|(_, upload)| now.saturating_sub(upload.started_at) > max_age_secs)
+                .map(|(id, _)| id.clone())
+                .collect();
+            for id in &abandoned {
+                self.end_parts(id)?;
+            }
+        }
+
+        let mut index = self.index.lock().unwrap();
+        let expired: Vec<FileInfo> = index
+            .iter()
+            .filter(|(_, file)| file.expired(max_age_secs, now))
+            .map(|(name, file)| FileInfo {
+                name: name.clone(),
+                size: if file.consumed { 0 } else { file.size },
+                age_secs: now.saturating_sub(file.uploaded_at),
+            })
+            .collect();
+        if dry_run || expired.is_empty() {
+            return Ok(expired);
+        }
+        for file in &expired {
+            remove_if_present(&self.path(&file.name))?;
+            index.remove(&file.name);
+        }
+        self.save(&index)?;
+        Ok(expired)
+    }
+
+    // Called with `index` locked, so the counts saved are all there are
+    fn save(&self, index: &BTreeMap<String, StoredFile>) -> Result<()> {
+        let path = self.dir.join(INDEX_FILE);
+        let staged = TempGuard::beside(&path, ScratchKind::Temp)?;
+        let content = serde_json::to_vec_pretty(index).map_err(|e| ShrLinkError::Other(e.into()))?;
+        fs::write(staged.path(), content)?;
+        staged.commit(&path)?;
+        self.counts_unsaved.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+fn remove_if_present(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+fn remove_dir_if_present(path: &Path) -> Result<()> {
+    match fs::remove_dir_all(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+// The other end of `HttpFallback`: multipart uploads to /upload, or in parts through
+// /upload/init, downloads from /files/<name> with byte ranges, DELETE of the same, POST /cleanup
+// and GET /stats
+pub struct FallbackServer {
+    store: Arc<FileStore>,
+    auth: Arc<Auth>,
+    public_url: PublicUrl,
+}
+
+impl FallbackServer {
+    pub fn new(dir: &Path) -> Result<Self> {
+        Ok(Self { store: Arc::new(FileStore::open(dir)?), auth: Arc::new(Auth::default()), public_url: PublicUrl(None) })
+    }
+
+    // Uploads, deletes, cleanup and stats then need `Authorization: Bearer <token>`, as do
+    // downloads with `downloads`; anything without it gets a 401
+    pub fn with_token(mut self, token: String, downloads: bool) -> Self {
+        self.auth = Arc::new(Auth { token: Some(token), downloads });
+        self
+    }
+
+    // Share URLs are then built on `url`, such as https://files.example.com behind a proxy,
+    // whatever the request came in on
+    pub fn with_public_url(mut self, url: &str) -> Self {
+        self.public_url = PublicUrl(Some(url.trim_end_matches('/').into()));
+        self
+    }
+
+    pub fn store(&self) -> &FileStore {
+        &self.store
+    }
+
+    pub fn router(&self) -> Router {
+        Router::new()
+            .route("/upload", post(upload))
+            .route("/upload/init", post(begin_parts))
+            .route("/upload/:id/part/:part", put(upload_part))
+            .route("/upload/:id/complete", post(complete_parts))
+            .route("/files/:name", get(download).delete(delete))
+            .route("/cleanup", post(cleanup))
+            .route("/stats", get(stats))
+            // Bundles are as big as the files they carry
+            .layer(DefaultBodyLimit::disable())
+            .with_state(ServerState { store: self.store.clone(), auth: self.auth.clone(), public_url: self.public_url.clone() })
+    }
+
+    // Answers on `listener` until `shutdown` fires, then lets requests under way finish. Download
+    // counts are saved every so often meanwhile, and once more at the end
+    pub async fn serve(self, listener: std::net::TcpListener, shutdown: CancellationToken) -> Result<()> {
+        listener.set_nonblocking(true)?;
+        let store = self.store.clone();
+        let saving = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(COUNTS_SAVE_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = store.save_counts() {
+                    tracing::warn!("Could not save download counts: {}", e);
+                }
+            }
+        });
+        let served = axum::Server::from_tcp(listener)
+            .map_err(|e| ShrLinkError::Network(format!("Failed to start HTTP server: {}", e)))?
+            .serve(self.router().into_make_service())
+            .with_graceful_shutdown(shutdown.cancelled_owned())
+            .await
+            .map_err(|e| ShrLinkError::Network(format!("HTTP server failed: {}", e)));
+        saving.abort();
+        self.store.save_counts()?;
+        served
+    }
+}
+
+#[derive(Clone)]
+struct ServerState {
+    store: Arc<FileStore>,
+    auth: Arc<Auth>,
+    public_url: PublicUrl,
+}
+
+// Where the server is reached from outside, when it was told
+#[derive(Debug, Clone)]
+struct PublicUrl(Option<Arc<str>>);
+
+impl FromRef<ServerState> for PublicUrl {
+    fn from_ref(state: &ServerState) -> Self {
+        state.public_url.clone()
+    }
+}
+
+impl FromRef<ServerState> for Arc<FileStore> {
+    fn from_ref(state: &ServerState) -> Self {
+        state.store.clone()
+    }
+}
+
+// The token requests have to carry, if any
+#[derive(Debug, Default)]
+struct Auth {
+    token: Option<String>,
+    downloads: bool,
+}
+
+impl Auth {
+    fn check(&self, headers: &HeaderMap) -> std::result::Result<(), Unauthorized> {
+        let Some(token) = &self.token else {
+            return Ok(());
+        };
+        let given = headers
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        // Compared by hash, which blake3 does in constant time, so how long a refusal takes says
+        // nothing about the token
+        if given.is_some_and(|given| blake3::hash(given.as_bytes()) == blake3::hash(token.as_bytes())) {
+            return Ok(());
+        }
+        tracing::warn!("Refused a request without the server's token");
+        Err(Unauthorized)
+    }
+}
+
+// What a request without the token gets
+struct Unauthorized;
+
+impl IntoResponse for Unauthorized {
+    fn into_response(self) -> Response {
+        let error = Json(serde_json::json!({ "error": "This server needs its bearer token" }));
+        (StatusCode::UNAUTHORIZED, [(WWW_AUTHENTICATE, "Bearer")], error).into_response()
+    }
+}
+
+// Taken by the handlers that need the token, so they're never reached without it
+struct Authorized;
+
+#[axum::async_trait]
+impl FromRequestParts<ServerState> for Authorized {
+    type Rejection = Unauthorized;
+
+    async fn from_request_parts(parts: &mut Parts, state: &ServerState) -> std::result::Result<Self, Unauthorized> {
+        state.auth.check(&parts.headers).map(|()| Authorized)
+    }
+}
+
+// As `Authorized`, for downloads, which only need the token when the server was told so
+struct AuthorizedDownload;
+
+#[axum::async_trait]
+impl FromRequestParts<ServerState> for AuthorizedDownload {
+    type Rejection = Unauthorized;
+
+    async fn from_request_parts(parts: &mut Parts, state: &ServerState) -> std::result::Result<Self, Unauthorized> {
+        if !state.auth.downloads {
+            return Ok(AuthorizedDownload);
+        }
+        state.auth.check(&parts.headers).map(|()| AuthorizedDownload)
+    }
+}
+
+impl IntoResponse for ShrLinkError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            ShrLinkError::InvalidInput(_) => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        tracing::warn!("Request failed: {}", self);
+        (status, Json(serde_json::json!({ "error": self.to_string() }))).into_response()
+    }
+}
+
+// Written to a scratch file as the body arrives, and only filed under its name once the part is
+// complete, so a cut-off upload leaves nothing behind
+async fn upload(_: Authorized, State(store): State<Arc<FileStore>>, State(public_url): State<PublicUrl>, headers: HeaderMap, mut multipart: Multipart) -> Result<Response> {
+    // The first part is the file; any others are ignored
+    let mut field = multipart
+        .next_field()
+        .await
+        .map_err(multipart_error)?
+        .ok_or_else(|| ShrLinkError::InvalidInput("Upload has no file part".to_string()))?;
+    // The part's own header, not `file_name()`, so the UTF-8 `filename*` wins over the ASCII fallback
+    let name = field
+        .headers()
+        .get(CONTENT_DISPOSITION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(filename::parse_content_disposition)
+        .and_then(|name| filename::sanitize(&name))
+        .ok_or_else(|| ShrLinkError::InvalidInput("The upload's part has no usable file name".to_string()))?;
+    let options = UploadOptions::from_headers(&headers)?;
+
+    let staged = TempGuard::beside(&store.path(&name), ScratchKind::Temp)?;
+    let mut file = tokio::fs::File::create(staged.path()).await?;
+    let mut size = 0u64;
+    while let Some(chunk) = field.chunk().await.map_err(multipart_error)? {
+        file.write_all(&chunk).await?;
+        size += chunk.len() as u64;
+    }
+    file.flush().await?;
+    drop(file);
+
+    let stored = options.stored(size, unix_now());
+    store.insert(&name, staged, stored.clone())?;
+    tracing::info!("Stored {} ({} bytes)", name, size);
+    Ok(upload_reply(download_url(&public_url, &headers, &name), &name, &stored))
+}
+
+// What an upload asks of the server in its headers, whether it's sent whole or in parts
+#[derive(Debug, Clone)]
+struct UploadOptions {
+    once: bool,
+    expires_in: Option<u64>,
+    bundle_hash: Option<String>,
+}
+
+impl UploadOptions {
+    fn from_headers(headers: &HeaderMap) -> Result<Self> {
+        let once = headers.get(ONCE_HEADER).is_some_and(|v| v.as_bytes() == b"1");
+        let expires_in = match headers.get(EXPIRES_HEADER) {
+            Some(value) => Some(value.to_str().ok().and_then(|v| v.trim().parse::<u64>().ok()).ok_or_else(|| {
+                ShrLinkError::InvalidInput(format!("{} has to be a number of seconds", EXPIRES_HEADER))
+            })?),
+            None => None,
+        };
+        // Only kept in the canonical form, so it goes back out exactly as a client will compare it
+        let bundle_hash = match headers.get(BUNDLE_HASH_HEADER) {
+            Some(value) => Some(value.to_str().ok().and_then(|v| blake3::Hash::from_hex(v.trim()).ok()).ok_or_else(|| {
+                ShrLinkError::InvalidInput(format!("{} has to be a BLAKE3 hash in hex", BUNDLE_HASH_HEADER))
+            })?.to_hex().to_string()),
+            None => None,
+        };
+        Ok(Self { once, expires_in, bundle_hash })
+    }
+
+    fn stored(&self, size: u64, uploaded_at: u64) -> StoredFile {
+        StoredFile {
+            size,
+            uploaded_at,
+            expires_at: self.expires_in.map(|secs| uploaded_at.saturating_add(secs)),
+            once: self.once,
+            consumed: false,
+            bundle_hash: self.bundle_hash.clone(),
+            downloads: 0,
+        }
+    }
+}
+
+// Where the upload can be downloaded, which `HttpFallback` reads back as the share URL
+fn upload_reply(url: String, name: &str, stored: &StoredFile) -> Response {
+    let mut reply = serde_json::json!({ "id": name, "url": url, "size": stored.size });
+    if let Some(expires_at) = stored.expires_at {
+        reply["expires_at"] = expires_at.into();
+    }
+    if stored.once {
+        reply["once"] = true.into();
+    }
+    Json(reply).into_response()
+}
+
+// On the public URL if there is one, or on what a proxy in front says the request came in as.
+// Otherwise the server can't know its scheme, so the path is left for `HttpFallback` to put
+// on the endpoint it uploaded to
+fn download_url(public_url: &PublicUrl, headers: &HeaderMap, name: &str) -> String {
+    let path = format!("/files/{}", filename::encode_path_segment(name));
+    match public_url.0.as_deref().map(str::to_string).or_else(|| forwarded_base(headers)) {
+        Some(base) => format!("{}{}", base, path),
+        None => path,
+    }
+}
+
+// `proto://host` from the first hop of `Forwarded`, or from `X-Forwarded-Proto` with
+// `X-Forwarded-Host` or `Host`
+fn forwarded_base(headers: &HeaderMap) -> Option<String> {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+    let first = |value: &str| value.split(',').next().unwrap_or_default().trim().to_string();
+    let forwarded: HashMap<String, String> = header(FORWARDED.as_str())
+        .map(|value| {
+            first(value)
+                .split(';')
+                .filter_map(|pair| pair.split_once('='))
+                .map(|(key, value)| (key.trim().to_ascii_lowercase(), value.trim().trim_matches('"').to_string()))
+                .collect()
+        })
+        .unwrap_or_default();
+    let proto = forwarded.get("proto").cloned().or_else(|| header("x-forwarded-proto").map(first))?.to_ascii_lowercase();
+    let host = forwarded.get("host").cloned()
+        .or_else(|| header("x-forwarded-host").map(first))
+        .or_else(|| header(HOST.as_str()).map(str::to_string))?;
+    // Only what a URL could be built from, so a header can't smuggle anything else in
+    let host_ok = !host.is_empty() && host.chars().all(|c| c.is_ascii_alphanumeric() || ".-:[]".contains(c));
+    (matches!(proto.as_str(), "http" | "https") && host_ok).then(|| format!("{}://{}", proto, host))
+}
+
+#[derive(Deserialize)]
+struct BeginPartsRequest {
+    name: String,
+}
+
+// Takes the headers a whole upload would carry, and the name it's to be filed under in a JSON
+// body, since there's no multipart part to carry it
+async fn begin_parts(_: Authorized, State(store): State<Arc<FileStore>>, headers: HeaderMap, body: Bytes) -> Result<Json<serde_json::Value>> {
+    let begin: BeginPartsRequest = serde_json::from_slice(&body)
+        .map_err(|e| ShrLinkError::InvalidInput(format!("Starting an upload in parts needs {{\"name\": \"...\"}}: {}", e)))?;
+    let name = filename::sanitize(&begin.name)
+        .ok_or_else(|| ShrLinkError::InvalidInput("The upload has no usable file name".to_string()))?;
+    let options = UploadOptions::from_headers(&headers)?;
+
+    let id = store.begin_parts(name.clone(), options, unix_now())?;
+    tracing::debug!("Receiving {} in parts as {}", name, id);
+    Ok(Json(serde_json::json!({ "id": id })))
+}
+
+// A part sent again, as a retry does, replaces what arrived of it before
+async fn upload_part(_: Authorized, State(store): State<Arc<FileStore>>, AxumPath((id, part)): AxumPath<(String, u32)>, mut body: BodyStream) -> Result<Response> {
+    if store.pending_upload(&id).is_none() {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+    if part == 0 || part > MAX_PARTS {
+        return Err(ShrLinkError::InvalidInput(format!("Parts are numbered from 1 to {}", MAX_PARTS)));
+    }
+
+    let path = store.part_path(&id, part);
+    let staged = TempGuard::beside(&path, ScratchKind::Temp)?;
+    let mut file = tokio::fs::File::create(staged.path()).await?;
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|e| ShrLinkError::InvalidInput(format!("Bad part {} of upload {}: {}", part, id, e)))?;
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+    drop(file);
+    staged.commit(&path)?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+#[derive(Deserialize)]
+struct CompletePartsRequest {
+    parts: u32,
+}
+
+// Puts parts 1 to `parts` together in order and files them as a whole upload would be. Those
+// have to be exactly the parts that arrived, so a client that lost count finds out
+async fn complete_parts(_: Authorized, State(store): State<Arc<FileStore>>, State(public_url): State<PublicUrl>, AxumPath(id): AxumPath<String>, headers: HeaderMap, body: Bytes) -> Result<Response> {
+    let complete: CompletePartsRequest = serde_json::from_slice(&body)
+        .map_err(|e| ShrLinkError::InvalidInput(format!("Completing an upload needs {{\"parts\": N}}: {}", e)))?;
+    let Some(pending) = store.pending_upload(&id) else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    if complete.parts == 0 || complete.parts > MAX_PARTS {
+        return Err(ShrLinkError::InvalidInput(format!("An upload is sent in 1 to {} parts, not {}", MAX_PARTS, complete.parts)));
+    }
+    let uploaded = store.uploaded_parts(&id)?;
+    if let Some(part) = (1..=complete.parts).find(|part| uploaded.binary_search(part).is_err()) {
+        return Err(ShrLinkError::InvalidInput(format!("Part {} of upload {} never arrived", part, id)));
+    }
+    if uploaded.len() != complete.parts as usize {
+        return Err(ShrLinkError::InvalidInput(format!("Upload {} was sent in {} parts, not {}", id, uploaded.len(), complete.parts)));
+    }
+
+    let staged = TempGuard::beside(&store.path(&pending.name), ScratchKind::Temp)?;
+    let mut file = tokio::fs::File::create(staged.path()).await?;
+    let mut size = 0u64;
+    for part in uploaded {
+        let mut part_file = tokio::fs::File::open(store.part_path(&id, part)).await?;
+        size += tokio::io::copy(&mut part_file, &mut file).await?;
+    }
+    file.flush().await?;
+    drop(file);
+
+    let stored = pending.options.stored(size, unix_now());
+    store.insert(&pending.name, staged, stored.clone())?;
+    store.end_parts(&id)?;
+    tracing::info!("Stored {} ({} bytes in {} parts)", pending.name, size, complete.parts);
+    Ok(upload_reply(download_url(&public_url, &headers, &pending.name), &pending.name, &stored))
+}
+
+fn multipart_error(e: MultipartError) -> ShrLinkError {
+    ShrLinkError::InvalidInput(format!("Bad multipart upload: {}", e))
+}
+
+async fn download(_: AuthorizedDownload, State(store): State<Arc<FileStore>>, AxumPath(name): AxumPath<String>, method: Method, headers: HeaderMap) -> Result<Response> {
+    let Some(stored) = stored_name(&name).and_then(|name| store.get(&name)) else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    if stored.consumed || stored.expires_at.is_some_and(|expires_at| unix_now() >= expires_at) {
+        return Ok(gone());
+    }
+    let mut file = match tokio::fs::File::open(store.path(&name)).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(StatusCode::NOT_FOUND.into_response()),
+        Err(e) => return Err(e.into()),
+    };
+    let total = stored.size;
+
+    let range = headers.get(RANGE).and_then(|v| v.to_str().ok()).and_then(|v| parse_range(v, total));
+    let mut response = Response::builder()
+        .header(CONTENT_TYPE, "application/octet-stream")
+        .header(CONTENT_DISPOSITION, filename::content_disposition("attachment", &name));
+    // Still served in ranges when asked, so a cut-off download can resume, but a one-time file
+    // isn't offered for splitting, as a range finishing early would use it up for the others
+    if !stored.once {
+        response = response.header(ACCEPT_RANGES, "bytes");
+    }
+    if let Some(hash) = &stored.bundle_hash {
+        response = response.header(BUNDLE_HASH_HEADER, hash.as_str());
+    }
+    let (start, end) = match range {
+        None => (0, total),
+        Some(Some((start, end))) => {
+            response = response
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(CONTENT_RANGE, format!("bytes {}-{}/{}", start, end - 1, total));
+            (start, end)
+        }
+        Some(None) => {
+            return Ok((StatusCode::RANGE_NOT_SATISFIABLE, [(CONTENT_RANGE, format!("bytes */{}", total))]).into_response());
+        }
+    };
+    // Counted as the last byte goes out, so a download split across connections or resumed
+    // counts once, one cut off not at all, and a HEAD never
+    let counts = end == total && method != Method::HEAD;
+
+    // A one-time file is used up by whichever download reaches its last byte first, which is
+    // held back until the file has been claimed for it. Any other gets cut off just short of
+    // the end, and one that's interrupted earlier leaves the link working
+    let body = if stored.once && end == total && total > 0 {
+        let mut last = [0u8; 1];
+        file.seek(std::io::SeekFrom::Start(end - 1)).await?;
+        file.read_exact(&mut last).await?;
+        file.seek(std::io::SeekFrom::Start(start)).await?;
+        let tail = stream::once(async move {
+            match claim(&store, &name) {
+                Ok(true) => {
+                    if counts {
+                        store.count_download(&name);
+                    }
+                    Ok(Bytes::copy_from_slice(&last))
+                }
+                Ok(false) => Err(std::io::Error::other("Another download used the one-time link first")),
+                Err(e) => Err(std::io::Error::other(e.to_string())),
+            }
+        });
+        boxed(StreamBody::new(ReaderStream::new(file.take(end - start - 1)).chain(tail)))
+    } else {
+        // With no last byte to hold back, an empty one is used up as soon as it's asked for
+        if stored.once && total == 0 && !claim(&store, &name)? {
+            return Ok(gone());
+        }
+        if counts && start == end {
+            store.count_download(&name);
+        }
+        file.seek(std::io::SeekFrom::Start(start)).await?;
+        let mut left = end - start;
+        let body = ReaderStream::new(file.take(left)).inspect(move |chunk| {
+            if let Ok(chunk) = chunk {
+                left -= chunk.len() as u64;
+                if counts && left == 0 {
+                    store.count_download(&name);
+                }
+            }
+        });
+        boxed(StreamBody::new(body))
+    };
+    Ok(response
+        .header(CONTENT_LENGTH, end - start)
+        .body(body)
+        .expect("static response parts"))
+}
+
+// Consumes a one-time file, and removes it once downloads that lost the race for it have had
+// a moment to let go of it
+fn claim(store: &Arc<FileStore>, name: &str) -> Result<bool> {
+    if !store.consume(name)? {
+        return Ok(false);
+    }
+    tracing::info!("{} was downloaded once and is now gone", name);
+    let (store, name) = (store.clone(), name.to_string());
+    tokio::spawn(async move {
+        tokio::time::sleep(CONSUMED_DISCARD_DELAY).await;
+        if let Err(e) = store.discard(&name) {
+            tracing::warn!("Could not remove the used-up {}: {}", name, e);
+        }
+    });
+    Ok(true)
+}
+
+fn gone() -> Response {
+    let error = Json(serde_json::json!({ "error": "This link was already used or has expired" }));
+    (StatusCode::GONE, error).into_response()
+}
+
+// Already gone is a 404, which `HttpFallback` counts as deleted all the same
+async fn delete(_: Authorized, State(store): State<Arc<FileStore>>, AxumPath(name): AxumPath<String>) -> Result<StatusCode> {
+    match stored_name(&name) {
+        Some(name) if store.remove(&name)? => Ok(StatusCode::NO_CONTENT),
+        _ => Ok(StatusCode::NOT_FOUND),
+    }
+}
+
+#[derive(Deserialize)]
+struct CleanupRequest {
+    max_age_seconds: u64,
+}
+
+#[derive(Deserialize)]
+struct CleanupQuery {
+    #[serde(default)]
+    dry_run: bool,
+}
+
+// With `?dry_run=true` it only answers with what would go, saying so with `"dry_run": true`
+async fn cleanup(_: Authorized, State(store): State<Arc<FileStore>>, Query(query): Query<CleanupQuery>, body: Bytes) -> Result<Json<serde_json::Value>> {
+    let cleanup: CleanupRequest = serde_json::from_slice(&body)
+        .map_err(|e| ShrLinkError::InvalidInput(format!("Cleanup needs {{\"max_age_seconds\": N}}: {}", e)))?;
+
+    let report = CleanupReport::new(store.expire(cleanup.max_age_seconds, unix_now(), query.dry_run)?);
+    if !query.dry_run && !report.deleted.is_empty() {
+        tracing::info!("Cleanup removed {} files older than {}s", report.deleted.len(), cleanup.max_age_seconds);
+    }
+    Ok(Json(serde_json::json!({
+        "deleted_count": report.deleted.len(),
+        "deleted": report.deleted,
+        "bytes_freed": report.bytes_freed,
+        "dry_run": query.dry_run,
+    })))
+}
+
+async fn stats(_: Authorized, State(store): State<Arc<FileStore>>) -> Json<serde_json::Value> {
+    let (files, bytes) = store.totals();
+    Json(serde_json::json!({ "total_files": files, "total_bytes": bytes, "files": store.files() }))
+}
+
+// Only names that come out of sanitizing unchanged could have been stored, so nothing in a path
+// can reach outside the files directory. The router has already percent-decoded `name`
+fn stored_name(name: &str) -> Option<String> {
+    (filename::sanitize(name)? == name).then(|| name.to_string())
+}
+
+// A single `bytes=start-[end]` range as [start, end); `Some(None)` when it starts past the end.
+// Anything else, suffix and multiple ranges included, is answered with the whole file
+fn parse_range(value: &str, total: u64) -> Option<Option<(u64, u64)>> {
+    let (start, end) = value.strip_prefix("bytes=")?.split_once('-')?;
+    let start: u64 = start.trim().parse().ok()?;
+    let end = match end.trim() {
+        "" => total,
+        end => end.parse::<u64>().ok()?.saturating_add(1).min(total),
+    };
+    if start >= total || start >= end {
+        return Some(None);
+    }
+    Some(Some((start, end)))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_store_expires_by_upload_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileStore::open(dir.path()).unwrap();
+        for (name, uploaded_at) in [("old.shr", 100), ("new.shr", 900)] {
+            let staged = TempGuard::beside(&store.path(name), ScratchKind::Temp).unwrap();
+            fs::write(staged.path(), name).unwrap();
+            store.insert(name, staged, StoredFile { size: name.len() as u64, uploaded_at, expires_at: None, once: false, consumed: false, bundle_hash: None, downloads: 0 }).unwrap();
+        }
+        assert_eq!(store.totals(), (2, 14));
+
+        // A dry run lists what would go and leaves it
+        let listed = store.expire(500, 1000, true).unwrap();
+        assert_eq!(listed, [FileInfo { name: "old.shr".to_string(), size: 7, age_secs: 900 }]);
+        assert!(store.path("old.shr").exists());
+        assert_eq!(store.totals(), (2, 14));
+
+        assert_eq!(store.expire(500, 1000, false).unwrap().len(), 1);
+        assert!(!store.path("old.shr").exists());
+        assert!(store.get("new.shr").is_some());
+
+        // The index outlives the server, and forgets files removed behind its back
+        let reopened = FileStore::open(dir.path()).unwrap();
+        assert_eq!(reopened.totals(), (1, 7));
+        fs::remove_file(reopened.path("new.shr")).unwrap();
+        assert_eq!(FileStore::open(dir.path()).unwrap().totals(), (0, 0));
+    }
+
+    #[test]
+    fn test_store_expires_by_requested_expiry_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileStore::open(dir.path()).unwrap();
+        // Kept past the max age, gone before it, and going by the max age
+        for (name, expires_at) in [("week.shr", Some(100 + 7 * 86_400)), ("hour.shr", Some(100 + 3600)), ("day.shr", None)] {
+            let staged = TempGuard::beside(&store.path(name), ScratchKind::Temp).unwrap();
+            fs::write(staged.path(), name).unwrap();
+            store.insert(name, staged, StoredFile { size: 8, uploaded_at: 100, expires_at, once: false, consumed: false, bundle_hash: None, downloads: 0 }).unwrap();
+        }
+
+        assert_eq!(store.expire(86_400, 100 + 3599, false).unwrap().len(), 0);
+        assert_eq!(store.expire(86_400, 100 + 3600, false).unwrap().len(), 1);
+        assert!(store.get("hour.shr").is_none());
+        assert_eq!(store.expire(86_400, 100 + 86_401, false).unwrap().len(), 1);
+        assert!(store.get("day.shr").is_none());
+
+        // The expiry is kept in the index along with the rest
+        let reopened = FileStore::open(dir.path()).unwrap();
+        assert_eq!(reopened.get("week.shr").unwrap().expires_at, Some(100 + 7 * 86_400));
+        assert_eq!(reopened.expire(86_400, 100 + 7 * 86_400, false).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_store_lists_files_with_their_downloads() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileStore::open(dir.path()).unwrap();
+        let staged = TempGuard::beside(&store.path("report.shr"), ScratchKind::Temp).unwrap();
+        fs::write(staged.path(), "report").unwrap();
+        store.insert("report.shr", staged, StoredFile { size: 6, uploaded_at: 100, expires_at: Some(200), once: false, consumed: false, bundle_hash: None, downloads: 0 }).unwrap();
+
+        store.count_download("report.shr");
+        store.count_download("report.shr");
+        store.count_download("other.shr");
+        let listed = FileStats { name: "report.shr".to_string(), size: 6, uploaded_at: 100, expires_at: Some(200), downloads: 2 };
+        assert_eq!(store.files(), std::slice::from_ref(&listed));
+
+        // Only saved when asked, or with the next change that's saved anyway
+        assert_eq!(FileStore::open(dir.path()).unwrap().files()[0].downloads, 0);
+        store.save_counts().unwrap();
+        assert_eq!(FileStore::open(dir.path()).unwrap().files(), [listed]);
+    }
+
+    #[test]
+    fn test_one_time_file_is_consumed_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileStore::open(dir.path()).unwrap();
+        let staged = TempGuard::beside(&store.path("secret.shr"), ScratchKind::Temp).unwrap();
+        fs::write(staged.path(), "secret").unwrap();
+        store.insert("secret.shr", staged, StoredFile { size: 6, uploaded_at: 100, expires_at: None, once: true, consumed: false, bundle_hash: None, downloads: 0 }).unwrap();
+
+        assert!(store.consume("secret.shr").unwrap());
+        assert!(!store.consume("secret.shr").unwrap());
+        assert!(!store.consume("other.shr").unwrap());
+        assert_eq!(store.totals(), (0, 0));
+
+        // Still known as consumed after a restart, without the file
+        let reopened = FileStore::open(dir.path()).unwrap();
+        assert!(reopened.get("secret.shr").unwrap().consumed);
+        assert!(!reopened.path("secret.shr").exists());
+        assert_eq!(reopened.expire(3600, 100 + 3601, false).unwrap().len(), 1);
+        assert!(reopened.get("secret.shr").is_none());
+    }
+
+    #[test]
+    fn test_download_url_is_only_absolute_when_the_scheme_is_known() {
+        let headers = |pairs: &[(&'static str, &str)]| -> HeaderMap {
+            pairs.iter().map(|(name, value)| (axum::http::HeaderName::from_static(name), value.parse().unwrap())).collect()
+        };
+        let none = PublicUrl(None);
+
+        assert_eq!(download_url(&none, &headers(&[("host", "files.example.com")]), "a b.shr"), "/files/a%20b.shr");
+        let proxied = headers(&[("host", "10.0.0.5:8080"), ("x-forwarded-proto", "https"), ("x-forwarded-host", "files.example.com")]);
+        assert_eq!(download_url(&none, &proxied, "a.shr"), "https://files.example.com/files/a.shr");
+        let forwarded = headers(&[("host", "10.0.0.5:8080"), ("forwarded", "for=192.0.2.1;proto=https;host=\"files.example.com\", for=10.0.0.1")]);
+        assert_eq!(download_url(&none, &forwarded, "a.shr"), "https://files.example.com/files/a.shr");
+        // Nothing but a scheme and a host is taken from a header
+        let smuggled = headers(&[("host", "files.example.com"), ("x-forwarded-proto", "javascript")]);
+        assert_eq!(download_url(&none, &smuggled, "a.shr"), "/files/a.shr");
+        let smuggled = headers(&[("x-forwarded-proto", "https"), ("x-forwarded-host", "evil.example/phish?")]);
+        assert_eq!(download_url(&none, &smuggled, "a.shr"), "/files/a.shr");
+
+        let public = PublicUrl(Some("https://example.com/shr".into()));
+        assert_eq!(download_url(&public, &proxied, "a.shr"), "https://example.com/shr/files/a.shr");
+    }
+
+    #[test]
+    fn test_stored_names_cannot_leave_the_files_directory() {
+        assert_eq!(stored_name("report.pdf.shr").as_deref(), Some("report.pdf.shr"));
+        assert_eq!(stored_name("café.shr").as_deref(), Some("café.shr"));
+        assert_eq!(stored_name(".."), None);
+        assert_eq!(stored_name("../index.json"), None);
+        assert_eq!(stored_name("a\\b"), None);
+    }
+
+    #[test]
+    fn test_auth_wants_the_exact_bearer_token() {
+        let headers = |value: &str| HeaderMap::from_iter([(AUTHORIZATION, value.parse().unwrap())]);
+        assert!(Auth::default().check(&HeaderMap::new()).is_ok());
+
+        let auth = Auth { token: Some("s3cret".to_string()), downloads: false };
+        assert!(auth.check(&headers("Bearer s3cret")).is_ok());
+        for refused in [HeaderMap::new(), headers("Bearer s3cre"), headers("Basic s3cret"), headers("Bearer s3cret2")] {
+            assert_eq!(auth.check(&refused).unwrap_err().into_response().status(), StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=10-", 100), Some(Some((10, 100))));
+        assert_eq!(parse_range("bytes=10-19", 100), Some(Some((10, 20))));
+        assert_eq!(parse_range("bytes=10-500", 100), Some(Some((10, 100))));
+        assert_eq!(parse_range("bytes=100-", 100), Some(None));
+        assert_eq!(parse_range("bytes=-10", 100), None);
+    }
+}
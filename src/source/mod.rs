@@ -0,0 +1,324 @@
+use std::fmt;
+use std::future::Future;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use crate::{Result, ShrLinkError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    P2P,
+    Http,
+    // Read off a share mounted on both ends, from the file fallback backend
+    File,
+    // From the sftp fallback backend's host
+    Sftp,
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::P2P => f.write_str("P2P"),
+            Transport::Http => f.write_str("HTTP"),
+            Transport::File => f.write_str("shared directory"),
+            Transport::Sftp => f.write_str("SFTP"),
+        }
+    }
+}
+
+// A source that answered its preflight (manifest for P2P, response headers for HTTP)
+#[derive(Debug)]
+pub enum Ready<P, H> {
+    P2P(P),
+    Http(H),
+}
+
+impl<P, H> Ready<P, H> {
+    pub fn transport(&self) -> Transport {
+        match self {
+            Ready::P2P(_) => Transport::P2P,
+            Ready::Http(_) => Transport::Http,
+        }
+    }
+}
+
+type Preflight<P, H> = JoinHandle<Option<Result<Ready<P, H>>>>;
+
+// The losing side's preflight, left running so it can take over if the winner fails mid-transfer
+pub struct Failover<P, H> {
+    task: Preflight<P, H>,
+    token: CancellationToken,
+}
+
+impl<P, H> Failover<P, H> {
+    pub async fn ready(mut self) -> Option<Ready<P, H>> {
+        match (&mut self.task).await {
+            Ok(Some(Ok(ready))) => Some(ready),
+            _ => None,
+        }
+    }
+}
+
+impl<P, H> Drop for Failover<P, H> {
+    fn drop(&mut self) {
+        self.token.cancel();
+    }
+}
+
+pub struct RaceOutcome<P, H> {
+    pub winner: Ready<P, H>,
+    pub failover: Option<Failover<P, H>>,
+}
+
+impl<P, H> RaceOutcome<P, H> {
+    pub fn transport(&self) -> Transport {
+        self.winner.transport()
+    }
+}
+
+fn spawn_preflight<T, F>(token: CancellationToken, preflight: F) -> JoinHandle<Option<Result<T>>>
+where
+    T: Send + 'static,
+    F: Future<Output = Result<T>> + Send + 'static,
+{
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = token.cancelled() => None,
+            result = preflight => Some(result),
+        }
+    })
+}
+
+// Runs both preflights at once and commits to whichever is ready first. The loser is
+// cancelled unless `keep_failover` is set, in which case it keeps going in the background
+pub async fn race_sources<P, H, FP, FH>(
+    p2p: FP,
+    http: FH,
+    keep_failover: bool,
+    cancel: &CancellationToken,
+) -> Result<RaceOutcome<P, H>>
+where
+    P: Send + 'static,
+    H: Send + 'static,
+    FP: Future<Output = Result<P>> + Send + 'static,
+    FH: Future<Output = Result<H>> + Send + 'static,
+{
+    let p2p_token = cancel.child_token();
+    let http_token = cancel.child_token();
+
+    let mut p2p_task = spawn_preflight(p2p_token.clone(), async move { p2p.await.map(Ready::P2P) });
+    let mut http_task = spawn_preflight(http_token.clone(), async move { http.await.map(Ready::Http) });
+
+    let (first, loser_task, loser_token) = tokio::select! {
+        r = &mut p2p_task => (joined(r), http_task, http_token),
+        r = &mut http_task => (joined(r), p2p_task, p2p_token),
+    };
+
+    match first {
+        Ok(winner) => {
+            let failover = Failover { task: loser_task, token: loser_token };
+            Ok(RaceOutcome {
+                winner,
+                // Dropping the failover cancels the loser
+                failover: keep_failover.then_some(failover),
+            })
+        }
+        Err(first_error) => {
+            // The first answer was a failure, so whatever the other side says decides it
+            match joined(loser_task.await) {
+                Ok(winner) => Ok(RaceOutcome { winner, failover: None }),
+                Err(second_error) => Err(ShrLinkError::Network(format!(
+                    "No source is reachable ({}; {})",
+                    first_error, second_error
+                ))),
+            }
+        }
+    }
+}
+
+fn joined<T>(result: std::result::Result<Option<Result<T>>, tokio::task::JoinError>) -> Result<T> {
+    result
+        .map_err(|e| ShrLinkError::Other(e.into()))?
+        .unwrap_or_else(|| Err(ShrLinkError::Network("Source preflight was cancelled".to_string())))
+}
+
+// Downloads from the winner, and from the kept loser if the winner fails part way through
+pub async fn fetch_with_failover<P, H, T, FP, FH, DP, DH>(
+    outcome: RaceOutcome<P, H>,
+    fetch_p2p: DP,
+    fetch_http: DH,
+) -> Result<(Transport, T)>
+where
+    DP: Fn(P) -> FP,
+    DH: Fn(H) -> FH,
+    FP: Future<Output = Result<T>>,
+    FH: Future<Output = Result<T>>,
+{
+    let transport = outcome.transport();
+
+    let error = match fetch(outcome.winner, &fetch_p2p, &fetch_http).await {
+        Ok(value) => return Ok((transport, value)),
+        Err(e) => e,
+    };
+
+    let Some(failover) = outcome.failover else {
+        return Err(error);
+    };
+
+    tracing::warn!("{} transfer failed ({}), failing over", transport, error);
+    match failover.ready().await {
+        Some(backup) => {
+            let backup_transport = backup.transport();
+            fetch(backup, &fetch_p2p, &fetch_http).await.map(|v| (backup_transport, v))
+        }
+        None => Err(error),
+    }
+}
+
+async fn fetch<P, H, T, FP, FH, DP, DH>(ready: Ready<P, H>, fetch_p2p: &DP, fetch_http: &DH) -> Result<T>
+where
+    DP: Fn(P) -> FP,
+    DH: Fn(H) -> FH,
+    FP: Future<Output = Result<T>>,
+    FH: Future<Output = Result<T>>,
+{
+    match ready {
+        Ready::P2P(p) => fetch_p2p(p).await,
+        Ready::Http(h) => fetch_http(h).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    struct Counters {
+        p2p: AtomicUsize,
+        http: AtomicUsize,
+    }
+
+    async fn download(counters: Arc<Counters>, outcome: RaceOutcome<&'static str, &'static str>) -> Result<(Transport, &'static str)> {
+        let (p2p, http) = (counters.clone(), counters);
+        fetch_with_failover(
+            outcome,
+            move |payload| {
+                p2p.p2p.fetch_add(1, Ordering::SeqCst);
+                async move { Ok(payload) }
+            },
+            move |payload| {
+                http.http.fetch_add(1, Ordering::SeqCst);
+                async move { Ok(payload) }
+            },
+        )
+        .await
+    }
+
+    fn counters() -> Arc<Counters> {
+        Arc::new(Counters { p2p: AtomicUsize::new(0), http: AtomicUsize::new(0) })
+    }
+
+    #[tokio::test]
+    async fn test_dead_peer_healthy_server() {
+        let cancelled = Arc::new(AtomicUsize::new(0));
+        let guard = DropFlag(cancelled.clone());
+
+        // The peer never answers; its preflight has to be cancelled rather than waited out
+        let outcome = race_sources(
+            async move {
+                let _guard = guard;
+                std::future::pending::<()>().await;
+                Ok("p2p")
+            },
+            async {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                Ok("http")
+            },
+            false,
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(outcome.transport(), Transport::Http);
+
+        let counters = counters();
+        assert_eq!(download(counters.clone(), outcome).await.unwrap(), (Transport::Http, "http"));
+        assert_eq!(counters.http.load(Ordering::SeqCst), 1);
+        assert_eq!(counters.p2p.load(Ordering::SeqCst), 0);
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(cancelled.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_healthy_peer_dead_server() {
+        let outcome = race_sources(
+            async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                Ok("p2p")
+            },
+            async { Err::<&str, _>(ShrLinkError::Network("connection refused".to_string())) },
+            true,
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+        // The server failing first doesn't end the race, and leaves nothing to fail over to
+        assert_eq!(outcome.transport(), Transport::P2P);
+        assert!(outcome.failover.is_none());
+
+        let counters = counters();
+        assert_eq!(download(counters.clone(), outcome).await.unwrap(), (Transport::P2P, "p2p"));
+        assert_eq!(counters.http.load(Ordering::SeqCst), 0);
+        assert_eq!(counters.p2p.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_failover_to_loser_mid_transfer() {
+        let outcome = race_sources(
+            async { Ok("p2p") },
+            async {
+                tokio::time::sleep(Duration::from_millis(30)).await;
+                Ok("http")
+            },
+            true,
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(outcome.transport(), Transport::P2P);
+
+        let (transport, value) = fetch_with_failover(
+            outcome,
+            |_| async { Err::<&str, _>(ShrLinkError::Network("peer went away".to_string())) },
+            |payload| async move { Ok(payload) },
+        )
+        .await
+        .unwrap();
+        assert_eq!((transport, value), (Transport::Http, "http"));
+    }
+
+    #[tokio::test]
+    async fn test_both_sources_dead() {
+        let result = race_sources(
+            async { Err::<(), _>(ShrLinkError::Network("no route to peer".to_string())) },
+            async { Err::<(), _>(ShrLinkError::Network("404".to_string())) },
+            false,
+            &CancellationToken::new(),
+        )
+        .await;
+
+        let error = result.err().unwrap().to_string();
+        assert!(error.contains("no route to peer") && error.contains("404"));
+    }
+
+    struct DropFlag(Arc<AtomicUsize>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
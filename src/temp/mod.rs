@@ -0,0 +1,250 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+use crate::Result;
+
+// Everything we write as scratch ends in one of these, which is what the sweep keys on
+pub const TEMP_SUFFIX: &str = ".shr-tmp";
+pub const SPOOL_SUFFIX: &str = ".shr-spool";
+pub const PART_SUFFIX: &str = ".shr-part";
+
+pub const DEFAULT_SWEEP_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScratchKind {
+    // Output being written before it is renamed into place
+    Temp,
+    // Bundles staged on disk before upload
+    Spool,
+    // Partial downloads that may be kept for resume
+    Part,
+}
+
+impl ScratchKind {
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            ScratchKind::Temp => TEMP_SUFFIX,
+            ScratchKind::Spool => SPOOL_SUFFIX,
+            ScratchKind::Part => PART_SUFFIX,
+        }
+    }
+}
+
+pub fn is_scratch_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .is_some_and(|n| [TEMP_SUFFIX, SPOOL_SUFFIX, PART_SUFFIX].iter().any(|s| n.ends_with(s)))
+}
+
+// Removes the file on drop, including on early returns, panics and dropped futures, unless it
+// was committed to its final name or persisted for resume
+#[derive(Debug)]
+pub struct TempGuard {
+    path: PathBuf,
+    armed: bool,
+}
+
+impl TempGuard {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path, armed: true }
+    }
+
+    // A unique scratch path in `dir`, derived from `stem` so leftovers are recognisable
+    pub fn in_dir(dir: &Path, stem: &str, kind: ScratchKind) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        let name = format!(".{}.{}{}", stem, Uuid::new_v4().simple(), kind.suffix());
+        Ok(Self::new(dir.join(name)))
+    }
+
+    // Scratch file next to `target`, so committing is a same-filesystem rename
+    pub fn beside(target: &Path, kind: ScratchKind) -> Result<Self> {
+        let dir = match target.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let stem = target.file_name().and_then(|n| n.to_str()).unwrap_or("shr");
+        Self::in_dir(&dir, stem, kind)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn commit(mut self, target: &Path) -> Result<()> {
+        fs::rename(&self.path, target)?;
+        self.armed = false;
+        Ok(())
+    }
+
+    pub fn persist(mut self) -> PathBuf {
+        self.armed = false;
+        std::mem::take(&mut self.path)
+    }
+}
+
+impl Drop for TempGuard {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+
+        match fs::remove_file(&self.path) {
+            Ok(()) => tracing::debug!("Removed scratch file {}", self.path.display()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => tracing::warn!("Failed to remove scratch file {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SweepReport {
+    pub removed: Vec<PathBuf>,
+    pub bytes: u64,
+}
+
+// Removes scratch files older than `ttl`; younger ones may belong to a transfer still running
+pub fn sweep(dir: &Path, ttl: Duration) -> Result<SweepReport> {
+    let mut report = SweepReport::default();
+
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(report),
+        Err(e) => return Err(e.into()),
+    };
+
+    let now = SystemTime::now();
+    for entry in entries {
+        let entry = entry?;
+        let path = entry.path();
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() || !is_scratch_file(&path) {
+            continue;
+        }
+
+        let age = metadata
+            .modified()
+            .ok()
+            .and_then(|m| now.duration_since(m).ok())
+            .unwrap_or_default();
+        if age < ttl {
+            continue;
+        }
+
+        if fs::remove_file(&path).is_ok() {
+            report.bytes += metadata.len();
+            report.removed.push(path);
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn files_in(dir: &Path) -> Vec<PathBuf> {
+        fs::read_dir(dir).unwrap().map(|e| e.unwrap().path()).collect()
+    }
+
+    fn write_through(guard: &TempGuard, fail: bool) -> Result<()> {
+        fs::write(guard.path(), b"partial")?;
+        if fail {
+            return Err(crate::ShrLinkError::Network("connection reset".to_string()));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_guard_removes_on_error_and_commits_on_success() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("out.bin");
+
+        let failed = || -> Result<()> {
+            let guard = TempGuard::beside(&target, ScratchKind::Temp)?;
+            write_through(&guard, true)?;
+            guard.commit(&target)
+        };
+        assert!(failed().is_err());
+        assert!(files_in(dir.path()).is_empty());
+
+        let guard = TempGuard::beside(&target, ScratchKind::Temp).unwrap();
+        write_through(&guard, false).unwrap();
+        guard.commit(&target).unwrap();
+        assert_eq!(files_in(dir.path()), vec![target]);
+    }
+
+    #[test]
+    fn test_guard_removes_on_panic() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_path_buf();
+
+        let result = std::panic::catch_unwind(move || {
+            let guard = TempGuard::in_dir(&path, "bundle", ScratchKind::Spool).unwrap();
+            fs::write(guard.path(), b"spooled").unwrap();
+            panic!("compressor blew up");
+        });
+
+        assert!(result.is_err());
+        assert!(files_in(dir.path()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_guard_removes_on_cancellation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_path_buf();
+        let (written_tx, written_rx) = tokio::sync::oneshot::channel();
+
+        let task = tokio::spawn(async move {
+            let guard = TempGuard::in_dir(&path, "download", ScratchKind::Part).unwrap();
+            fs::write(guard.path(), b"first bytes").unwrap();
+            written_tx.send(()).unwrap();
+            std::future::pending::<()>().await;
+            drop(guard);
+        });
+
+        written_rx.await.unwrap();
+        assert_eq!(files_in(dir.path()).len(), 1);
+        task.abort();
+        assert!(task.await.unwrap_err().is_cancelled());
+        assert!(files_in(dir.path()).is_empty());
+    }
+
+    #[test]
+    fn test_persisted_part_survives_for_resume() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let guard = TempGuard::in_dir(dir.path(), "download", ScratchKind::Part).unwrap();
+        fs::write(guard.path(), b"resume me").unwrap();
+        let kept = guard.persist();
+
+        assert_eq!(files_in(dir.path()), vec![kept.clone()]);
+        assert!(is_scratch_file(&kept));
+    }
+
+    #[test]
+    fn test_sweep_removes_only_stale_scratch_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let stale = dir.path().join(".old.abc.shr-part");
+        let fresh = dir.path().join(".new.def.shr-tmp");
+        let unrelated = dir.path().join("report.pdf");
+        for path in [&stale, &fresh, &unrelated] {
+            fs::write(path, b"data").unwrap();
+        }
+
+        let two_days_ago = SystemTime::now() - Duration::from_secs(2 * 24 * 60 * 60);
+        fs::File::options().write(true).open(&stale).unwrap().set_modified(two_days_ago).unwrap();
+        fs::File::options().write(true).open(&unrelated).unwrap().set_modified(two_days_ago).unwrap();
+
+        let report = sweep(dir.path(), DEFAULT_SWEEP_TTL).unwrap();
+        assert_eq!(report.removed, vec![stale]);
+        assert_eq!(report.bytes, 4);
+
+        let mut left = files_in(dir.path());
+        left.sort();
+        assert_eq!(left, vec![fresh, unrelated]);
+
+        assert!(sweep(&dir.path().join("missing"), DEFAULT_SWEEP_TTL).unwrap().removed.is_empty());
+    }
+}
@@ -0,0 +1,500 @@
+use std::io::Read;
+#[cfg(not(target_arch = "wasm32"))]
+use std::pin::Pin;
+#[cfg(not(target_arch = "wasm32"))]
+use std::task::{ready, Context, Poll};
+use blake3::hazmat::{left_subtree_len, merge_subtrees_non_root, merge_subtrees_root, ChainingValue, HasherExt, Mode};
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::io::{AsyncRead, ReadBuf};
+use crate::{Result, ShrLinkError};
+
+// Bytes checked at a time: 16 BLAKE3 chunks, a subtree of the tree over the whole body
+pub const BLOCK_SIZE: usize = 16 * 1024;
+
+// A verified body starts with this, its length as a u64, and the BLAKE3 chaining value of each
+// block, which together hash up to the BLAKE3 of the body. A body of one block has none; it's
+// checked against the hash directly
+pub const MAGIC: &[u8; 4] = b"SHRV";
+
+const URL_HASH_PARAM: &str = "h=";
+
+// The hash of `body`, and the header that lets a receiver check it block by block against
+// that hash
+pub fn outboard<R: Read>(mut body: R) -> Result<(blake3::Hash, Vec<u8>)> {
+    let mut writer = OutboardWriter::default();
+    let mut block = vec![0u8; BLOCK_SIZE];
+    loop {
+        let filled = read_block(&mut body, &mut block)?;
+        if filled == 0 {
+            break;
+        }
+        writer.update(&block[..filled]);
+    }
+    Ok(writer.finish())
+}
+
+// `outboard` for a body written out piece by piece, so it never has to be held or read back
+#[derive(Default)]
+pub struct OutboardWriter {
+    leaves: Vec<ChainingValue>,
+    whole: blake3::Hasher,
+    // The block still filling up
+    pending: Vec<u8>,
+    len: u64,
+}
+
+impl OutboardWriter {
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let take = (BLOCK_SIZE - self.pending.len()).min(data.len());
+            self.pending.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.pending.len() == BLOCK_SIZE {
+                self.end_block();
+            }
+        }
+    }
+
+    fn end_block(&mut self) {
+        self.leaves.push(leaf(self.len, &self.pending));
+        self.whole.update(&self.pending);
+        self.len += self.pending.len() as u64;
+        self.pending.clear();
+    }
+
+    // Bytes written so far
+    pub fn len(&self) -> u64 {
+        self.len + self.pending.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn finish(mut self) -> (blake3::Hash, Vec<u8>) {
+        if !self.pending.is_empty() {
+            self.end_block();
+        }
+        if self.len <= BLOCK_SIZE as u64 {
+            self.leaves.clear();
+        }
+        let mut header = Vec::with_capacity(MAGIC.len() + 8 + self.leaves.len() * 32);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&self.len.to_le_bytes());
+        for cv in &self.leaves {
+            header.extend_from_slice(cv);
+        }
+        (self.whole.finalize(), header)
+    }
+}
+
+impl std::io::Write for OutboardWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.update(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Fills `block` unless the body ends first
+fn read_block<R: Read>(body: &mut R, block: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < block.len() {
+        match body.read(&mut block[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(filled)
+}
+
+fn leaf(offset: u64, block: &[u8]) -> ChainingValue {
+    blake3::Hasher::new().set_input_offset(offset).update(block).finalize_non_root()
+}
+
+fn block_count(len: u64) -> Option<usize> {
+    match len {
+        len if len <= BLOCK_SIZE as u64 => Some(0),
+        len => usize::try_from(len.div_ceil(BLOCK_SIZE as u64)).ok(),
+    }
+}
+
+// The hash of a body longer than one block, from its blocks' chaining values. Every split
+// BLAKE3 makes in a body that long falls on a block boundary
+fn root_of(len: u64, leaves: &[ChainingValue]) -> blake3::Hash {
+    fn subtree(len: u64, leaves: &[ChainingValue]) -> ChainingValue {
+        if len <= BLOCK_SIZE as u64 {
+            return leaves[0];
+        }
+        let left = left_subtree_len(len);
+        let (l, r) = leaves.split_at((left / BLOCK_SIZE as u64) as usize);
+        merge_subtrees_non_root(&subtree(left, l), &subtree(len - left, r), Mode::Hash)
+    }
+
+    let left = left_subtree_len(len);
+    let (l, r) = leaves.split_at((left / BLOCK_SIZE as u64) as usize);
+    merge_subtrees_root(&subtree(left, l), &subtree(len - left, r), Mode::Hash)
+}
+
+pub fn url_with_hash(url: &str, root: &blake3::Hash) -> String {
+    let separator = if url.contains('#') { '&' } else { '#' };
+    format!("{}{}{}{}", url, separator, URL_HASH_PARAM, root.to_hex())
+}
+
+// Splits the fragment off a share URL, and the `h=<hash>` it may carry
+pub fn split_url_hash(url: &str) -> Result<(&str, Option<blake3::Hash>)> {
+    let Some((base, fragment)) = url.split_once('#') else {
+        return Ok((url, None));
+    };
+    let Some(encoded) = fragment.split('&').find_map(|p| p.strip_prefix(URL_HASH_PARAM)) else {
+        return Ok((base, None));
+    };
+
+    let root = blake3::Hash::from_hex(encoded)
+        .map_err(|_| ShrLinkError::InvalidInput("The hash in the share URL is malformed".to_string()))?;
+    Ok((base, Some(root)))
+}
+
+enum State {
+    Magic,
+    Header,
+    Blocks { len: u64, leaves: Vec<ChainingValue>, offset: u64 },
+    // Not a verified body, and nothing to check it against
+    Passthrough,
+    Done,
+}
+
+// Hands out a verified body's bytes a block at a time, each only once it hashes to what the
+// header says, and the header only once it hashes up to `root`. So a download stops at the
+// first damaged block instead of after a whole chunk. Without a root anything else is passed
+// through as it is, and a verified body is only checked against its own header, which still
+// catches damage in transit. Errors come out of `Read` as `InvalidData` and turn back into
+// themselves on the way into `ShrLinkError`
+pub struct VerifiedReader<R> {
+    inner: R,
+    root: Option<blake3::Hash>,
+    state: State,
+    // Read but not yet checked; never much more than a block, or the header
+    input: Vec<u8>,
+    // Checked and not yet handed out
+    output: Vec<u8>,
+    handed_out: usize,
+}
+
+impl<R> VerifiedReader<R> {
+    pub fn new(inner: R, root: Option<blake3::Hash>) -> Self {
+        Self {
+            inner,
+            root,
+            state: State::Magic,
+            input: Vec::new(),
+            output: Vec::new(),
+            handed_out: 0,
+        }
+    }
+
+    fn take_output(&mut self, buf: &mut [u8]) -> usize {
+        let n = buf.len().min(self.output.len() - self.handed_out);
+        buf[..n].copy_from_slice(&self.output[self.handed_out..self.handed_out + n]);
+        self.handed_out += n;
+        n
+    }
+
+    // Whether anything moved along; false means more input is needed
+    fn step(&mut self) -> Result<bool> {
+        if self.handed_out < self.output.len() {
+            return Ok(true);
+        }
+        self.output.clear();
+        self.handed_out = 0;
+
+        match &mut self.state {
+            State::Magic => {
+                let Some(magic) = self.input.get(..MAGIC.len()) else {
+                    return Ok(false);
+                };
+                if magic == MAGIC {
+                    self.input.drain(..MAGIC.len());
+                    self.state = State::Header;
+                } else if self.root.is_some() {
+                    return Err(ShrLinkError::InvalidInput("The download has no hash tree to check against the share URL's hash".to_string()));
+                } else {
+                    self.state = State::Passthrough;
+                }
+                Ok(true)
+            }
+            State::Header => {
+                let Some(len) = self.input.get(..8).map(|len| u64::from_le_bytes(len.try_into().unwrap())) else {
+                    return Ok(false);
+                };
+                let end = block_count(len)
+                    .and_then(|count| count.checked_mul(32))
+                    .and_then(|size| size.checked_add(8))
+                    .ok_or_else(|| ShrLinkError::InvalidInput("Verified download is too large".to_string()))?;
+                if self.input.len() < end {
+                    return Ok(false);
+                }
+
+                let leaves: Vec<ChainingValue> = self.input[8..end].chunks_exact(32).map(|cv| cv.try_into().unwrap()).collect();
+                if let (Some(root), false) = (self.root, leaves.is_empty()) {
+                    let actual = root_of(len, &leaves);
+                    if actual != root {
+                        return Err(mismatch("the download's hash tree", &root, &actual));
+                    }
+                }
+                self.input.drain(..end);
+                self.state = State::Blocks { len, leaves, offset: 0 };
+                Ok(true)
+            }
+            State::Blocks { len, leaves, offset } => {
+                if *offset == *len {
+                    if !self.input.is_empty() {
+                        return Err(ShrLinkError::InvalidInput("Verified download goes on past its length".to_string()));
+                    }
+                    self.state = State::Done;
+                    return Ok(true);
+                }
+                let size = (*len - *offset).min(BLOCK_SIZE as u64) as usize;
+                if self.input.len() < size {
+                    return Ok(false);
+                }
+
+                let block = &self.input[..size];
+                let context = || format!("bytes {}..{} of the download", offset, *offset + size as u64);
+                if leaves.is_empty() {
+                    let actual = blake3::hash(block);
+                    if let Some(root) = self.root.filter(|root| *root != actual) {
+                        return Err(mismatch(&context(), &root, &actual));
+                    }
+                } else {
+                    let expected = leaves[(*offset / BLOCK_SIZE as u64) as usize];
+                    let actual = leaf(*offset, block);
+                    if actual != expected {
+                        return Err(mismatch(&context(), &blake3::Hash::from(expected), &blake3::Hash::from(actual)));
+                    }
+                }
+                *offset += size as u64;
+                self.output.extend(self.input.drain(..size));
+                Ok(true)
+            }
+            State::Passthrough => {
+                if self.input.is_empty() {
+                    return Ok(false);
+                }
+                std::mem::swap(&mut self.output, &mut self.input);
+                Ok(true)
+            }
+            State::Done => Ok(false),
+        }
+    }
+
+    // The inner reader has run out
+    fn finish(&mut self) -> Result<()> {
+        match self.state {
+            State::Magic if self.root.is_none() => {
+                std::mem::swap(&mut self.output, &mut self.input);
+                self.state = State::Done;
+                Ok(())
+            }
+            State::Passthrough | State::Done => {
+                self.state = State::Done;
+                Ok(())
+            }
+            _ => Err(ShrLinkError::InvalidInput("Verified download ended early".to_string())),
+        }
+    }
+}
+
+fn mismatch(context: &str, expected: &blake3::Hash, actual: &blake3::Hash) -> ShrLinkError {
+    ShrLinkError::HashMismatch {
+        context: context.to_string(),
+        expected: expected.to_hex().to_string(),
+        actual: actual.to_hex().to_string(),
+    }
+}
+
+fn io_error(e: ShrLinkError) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+}
+
+impl<R: Read> Read for VerifiedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut scratch = [0u8; BLOCK_SIZE];
+        loop {
+            if self.step().map_err(io_error)? {
+                if self.handed_out < self.output.len() {
+                    return Ok(self.take_output(buf));
+                }
+                continue;
+            }
+            if matches!(self.state, State::Done) {
+                return Ok(0);
+            }
+            match self.inner.read(&mut scratch)? {
+                0 => self.finish().map_err(io_error)?,
+                n => self.input.extend_from_slice(&scratch[..n]),
+            }
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl<R: AsyncRead + Unpin> AsyncRead for VerifiedReader<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let mut scratch = [0u8; BLOCK_SIZE];
+        loop {
+            if this.step().map_err(io_error)? {
+                if this.handed_out < this.output.len() {
+                    let n = this.take_output(buf.initialize_unfilled());
+                    buf.advance(n);
+                    return Poll::Ready(Ok(()));
+                }
+                continue;
+            }
+            if matches!(this.state, State::Done) {
+                return Poll::Ready(Ok(()));
+            }
+            let mut read = ReadBuf::new(&mut scratch);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+            match read.filled() {
+                [] => this.finish().map_err(io_error)?,
+                filled => this.input.extend_from_slice(filled),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    fn verified(body: &[u8]) -> (blake3::Hash, Vec<u8>) {
+        let (root, mut wrapped) = outboard(body).unwrap();
+        wrapped.extend_from_slice(body);
+        (root, wrapped)
+    }
+
+    // Counts how much of the stream has been taken, a little at a time
+    struct Counting<'a> {
+        data: &'a [u8],
+        taken: usize,
+    }
+
+    impl Read for Counting<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(1000).min(self.data.len() - self.taken);
+            buf[..n].copy_from_slice(&self.data[self.taken..self.taken + n]);
+            self.taken += n;
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_root_is_the_blake3_of_the_body() {
+        for len in [0, 1, BLOCK_SIZE - 1, BLOCK_SIZE, BLOCK_SIZE + 1, 3 * BLOCK_SIZE, 5 * BLOCK_SIZE + 7, 200_000] {
+            let data = body(len);
+            let (root, wrapped) = verified(&data);
+            assert_eq!(root, blake3::hash(&data), "{} bytes", len);
+
+            let mut out = Vec::new();
+            VerifiedReader::new(wrapped.as_slice(), Some(root)).read_to_end(&mut out).unwrap();
+            assert_eq!(out, data, "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn test_outboard_writer_matches_outboard() {
+        for len in [0, 1, BLOCK_SIZE, BLOCK_SIZE + 1, 200_000] {
+            let data = body(len);
+            let mut writer = OutboardWriter::default();
+            for piece in data.chunks(7_777) {
+                std::io::Write::write_all(&mut writer, piece).unwrap();
+            }
+            assert_eq!(writer.len(), len as u64);
+            assert_eq!(writer.finish(), outboard(data.as_slice()).unwrap(), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn test_damage_stops_the_stream_early() {
+        let data = body(4 * 1024 * 1024);
+        let (root, wrapped) = verified(&data);
+        let header = wrapped.len() - data.len();
+
+        let corrupt_at = header + 100_000;
+        let mut damaged = wrapped.clone();
+        damaged[corrupt_at] ^= 1;
+        let mut source = Counting { data: &damaged, taken: 0 };
+        let mut out = Vec::new();
+        let err = VerifiedReader::new(&mut source, Some(root)).read_to_end(&mut out).unwrap_err();
+        assert!(matches!(ShrLinkError::from(err), ShrLinkError::HashMismatch { .. }));
+        // Nothing past the damaged block was read or handed out
+        assert!(source.taken < corrupt_at + 2 * BLOCK_SIZE, "read {} of {}", source.taken, damaged.len());
+        assert_eq!(out, data[..out.len()]);
+        assert!(out.len() <= corrupt_at - header);
+
+        // Neither a header that doesn't add up to the URL's hash nor the wrong hash gets
+        // anything out
+        let mut forged = wrapped.clone();
+        forged[20] ^= 1;
+        let mut out = Vec::new();
+        assert!(VerifiedReader::new(forged.as_slice(), Some(root)).read_to_end(&mut out).is_err());
+        assert!(out.is_empty());
+        assert!(VerifiedReader::new(wrapped.as_slice(), Some(blake3::hash(b"other"))).read_to_end(&mut Vec::new()).is_err());
+
+        // Without a hash the header still catches damage, and cut short is an error
+        assert!(VerifiedReader::new(damaged.as_slice(), None).read_to_end(&mut Vec::new()).is_err());
+        assert!(VerifiedReader::new(&wrapped[..wrapped.len() - 1], None).read_to_end(&mut Vec::new()).is_err());
+        let small = verified(b"short");
+        let mut tampered = small.1.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(VerifiedReader::new(tampered.as_slice(), Some(small.0)).read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_unverified_bodies_pass_through() {
+        for data in [b"SHR\x06 and the rest".to_vec(), b"ab".to_vec(), Vec::new()] {
+            let mut out = Vec::new();
+            VerifiedReader::new(data.as_slice(), None).read_to_end(&mut out).unwrap();
+            assert_eq!(out, data);
+        }
+        // A hash in the URL means the body must be verifiable
+        assert!(VerifiedReader::new(&b"SHR\x06 and the rest"[..], Some(blake3::hash(b"x"))).read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_async_reads_match() {
+        use tokio::io::AsyncReadExt;
+        let data = body(100_000);
+        let (root, wrapped) = verified(&data);
+        let mut out = Vec::new();
+        VerifiedReader::new(tokio::io::BufReader::new(wrapped.as_slice()), Some(root)).read_to_end(&mut out).await.unwrap();
+        assert_eq!(out, data);
+
+        let mut damaged = wrapped;
+        let last = damaged.len() - 1;
+        damaged[last] ^= 1;
+        let err = VerifiedReader::new(tokio::io::BufReader::new(damaged.as_slice()), Some(root)).read_to_end(&mut Vec::new()).await.unwrap_err();
+        assert!(matches!(ShrLinkError::from(err), ShrLinkError::HashMismatch { .. }));
+    }
+
+    #[test]
+    fn test_url_hash_roundtrip() {
+        let root = blake3::hash(b"bundle");
+        let url = url_with_hash("https://example.com/files/x.shr", &root);
+        assert_eq!(split_url_hash(&url).unwrap(), ("https://example.com/files/x.shr", Some(root)));
+        assert_eq!(split_url_hash(&url_with_hash("https://e.com/x#k=abc", &root)).unwrap().1, Some(root));
+        assert_eq!(split_url_hash("https://e.com/x#k=abc").unwrap(), ("https://e.com/x", None));
+        assert!(split_url_hash("https://e.com/x#h=zz").is_err());
+    }
+}
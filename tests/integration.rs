@@ -1,8 +1,11 @@
-use shrlink::compression::ParallelCompressor;
+use shrlink::compression::{BlockSize, ParallelCompressor};
 use shrlink::config::Config;
+#[cfg(feature = "fs")]
 use tempfile::NamedTempFile;
+#[cfg(feature = "fs")]
 use std::io::Write;
 
+#[cfg(feature = "fs")]
 #[tokio::test]
 async fn test_end_to_end_compression() {
     let test_data = b"Hello, world! This is a test file for compression.".repeat(1000);
@@ -46,9 +49,9 @@ fn test_config_loading() {
     let config = Config::default();
     
     assert_eq!(config.compression.algorithm, "lz4");
-    assert_eq!(config.compression.block_size, 4 * 1024 * 1024);
+    assert_eq!(config.compression.block_size, BlockSize::Fixed(4 * 1024 * 1024));
     assert_eq!(config.p2p.timeout_ms, 5000);
-    assert_eq!(config.fallback.endpoint, Some("http://localhost:8080".to_string()));
+    assert_eq!(config.fallback.endpoints, ["http://localhost:8080"]);
 }
 
 #[test]
@@ -74,21 +77,520 @@ fn test_parallel_compression_performance() {
     assert!(decompression_time < std::time::Duration::from_secs(1));
 }
 
+#[cfg(feature = "p2p")]
 #[test]
 fn test_url_parsing() {
     use shrlink::p2p::{create_shr_url, parse_shr_url};
     use libp2p::PeerId;
     
     let peer_id = PeerId::random();
-    let file_hash = "abc123def456";
+    let file_hash = hex::encode([9u8; 32]);
     
-    let url = create_shr_url(peer_id, file_hash);
+    let url = create_shr_url(peer_id, &file_hash);
     let (parsed_peer_id, parsed_hash) = parse_shr_url(&url).unwrap();
     
     assert_eq!(peer_id, parsed_peer_id);
     assert_eq!(file_hash, parsed_hash);
 }
 
+// In-process clients keep their transfer stats and peer reputation in memory, so tests leave
+// the real data directory alone
+#[cfg(feature = "p2p")]
+async fn p2p_client(config: shrlink::config::P2PConfig) -> shrlink::p2p::P2PClient {
+    use shrlink::p2p::{reputation, P2PClient, ReputationStore, StatsStore};
+
+    P2PClient::new(config)
+        .await
+        .unwrap()
+        .with_stats(StatsStore::in_memory())
+        .with_reputation(ReputationStore::in_memory(reputation::DEFAULT_CAPACITY))
+}
+
+#[cfg(feature = "p2p")]
+#[tokio::test]
+async fn test_p2p_chunk_transfer_over_localhost() {
+    let data: Vec<u8> = (0..300_000u32).map(|i| (i.wrapping_mul(2654435761) >> 11) as u8).collect();
+    let compressor = ParallelCompressor::new(64 * 1024, 2).unwrap();
+    let result = compressor.compress_bytes(&data).unwrap();
+    assert!(result.chunks.len() > 1);
+
+    // Each with an identity of its own, as on two machines
+    let dir = tempfile::tempdir().unwrap();
+    let client = |name: &str| {
+        let mut config = Config::default().p2p;
+        config.identity_path = Some(dir.path().join(name));
+        p2p_client(config)
+    };
+    let mut sender = client("sender.key").await;
+    let mut receiver = client("receiver.key").await;
+    let addr = sender.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).await.unwrap();
+
+    let expected_chunks = result.chunks.len();
+    let (progress, received) = tokio::join!(sender.send_chunks(receiver.local_peer_id(), result.chunks.clone()), async {
+        let peer_id = receiver.connect_to_peer(addr).await?;
+        receiver.receive_chunks(peer_id, expected_chunks).await
+    });
+
+    let progress = progress.unwrap();
+    assert_eq!((progress.chunks_sent, progress.bytes_sent), (expected_chunks, progress.total_bytes));
+    let reconstructed = compressor.decompress_chunks_parallel(&received.unwrap()).unwrap().concat();
+    assert_eq!(reconstructed, data);
+}
+
+// `recv` of a shr:// URL fetches the manifest and then every chunk straight from the peer
+#[cfg(all(feature = "cli", feature = "p2p"))]
+#[tokio::test]
+async fn test_recv_from_peer_over_localhost() {
+    use shrlink::p2p::create_shr_url;
+    use std::time::Duration;
+
+    let dir = tempfile::tempdir().unwrap();
+    let data: Vec<u8> = (0..300_000u32).map(|i| (i.wrapping_mul(2654435761) >> 9) as u8).collect();
+    let compressor = ParallelCompressor::new(64 * 1024, 1).unwrap();
+    let result = compressor.compress_bytes(&data).unwrap();
+    assert!(result.chunks.len() > 1);
+
+    let mut sender_config = Config::default().p2p;
+    sender_config.identity_path = Some(dir.path().join("sender.key"));
+    let mut sender = p2p_client(sender_config).await;
+    let addr = sender.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).await.unwrap();
+    let manifest = sender.prepare_manifest(result.file_hash, &result.chunks).unwrap();
+
+    // mDNS off on the receiving side, so only the address in the URL can lead to the sender
+    let mut config = Config::default();
+    config.p2p.enable_mdns = false;
+    let url = format!("{}?addr={}", create_shr_url(sender.local_peer_id(), &hex::encode(result.file_hash)), addr);
+    let output_path = dir.path().join("received.bin");
+    let args: [&std::ffi::OsStr; 4] = ["recv".as_ref(), url.as_ref(), "-o".as_ref(), output_path.as_os_str()];
+
+    let (served, stdout) = tokio::join!(
+        sender.serve_chunks(&manifest, result.chunks.clone(), 1, Duration::from_secs(60), |_| {}),
+        run_shr(dir.path(), &config, &args)
+    );
+    assert_eq!(served.unwrap().completed, 1);
+    assert!(stdout.contains("via P2P"), "{}", stdout);
+    assert_eq!(std::fs::read(&output_path).unwrap(), data);
+
+    // A file the peer isn't serving fails rather than waiting on it
+    let mut idle_config = Config::default().p2p;
+    idle_config.identity_path = Some(dir.path().join("idle.key"));
+    let mut idle = p2p_client(idle_config).await;
+    let idle_addr = idle.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).await.unwrap();
+    let url = format!("{}?addr={}", create_shr_url(idle.local_peer_id(), &hex::encode([1u8; 32])), idle_addr);
+    let other_manifest = idle.prepare_manifest([2u8; 32], &result.chunks).unwrap();
+    let args: [&std::ffi::OsStr; 2] = ["recv".as_ref(), url.as_ref()];
+    let cancel = tokio_util::sync::CancellationToken::new();
+    let mut idle = idle.with_cancellation(cancel.clone());
+    let (_, output) = tokio::join!(idle.serve_chunks(&other_manifest, result.chunks.clone(), 1, Duration::from_secs(60), |_| {}), async {
+        let output = shr_output(dir.path(), &config, &args).await;
+        cancel.cancel();
+        output
+    });
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("has no file"), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+// With mDNS off and only a hash to go by, `recv --peer` dials the sender at the address given
+// and says which of the ways it can go wrong it was
+#[cfg(all(feature = "cli", feature = "p2p"))]
+#[tokio::test]
+async fn test_recv_by_hash_from_a_peer_address() {
+    use std::time::Duration;
+
+    let dir = tempfile::tempdir().unwrap();
+    let data: Vec<u8> = (0..200_000u32).map(|i| (i.wrapping_mul(2654435761) >> 11) as u8).collect();
+    let compressor = ParallelCompressor::new(64 * 1024, 1).unwrap();
+    let result = compressor.compress_bytes(&data).unwrap();
+    let hash = hex::encode(result.file_hash);
+
+    let mut sender_config = Config::default().p2p;
+    sender_config.identity_path = Some(dir.path().join("sender.key"));
+    sender_config.enable_mdns = false;
+    let mut sender = p2p_client(sender_config).await;
+    let addr = sender.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).await.unwrap();
+    let manifest = sender.prepare_manifest(result.file_hash, &result.chunks).unwrap();
+
+    let mut config = Config::default();
+    config.p2p.enable_mdns = false;
+    let peer = format!("{}/p2p/{}", addr, sender.local_peer_id());
+    let output_path = dir.path().join("received.bin");
+    let args: [&std::ffi::OsStr; 6] = ["recv".as_ref(), "--peer".as_ref(), peer.as_ref(), hash.as_ref(), "-o".as_ref(), output_path.as_os_str()];
+    let (served, stdout) = tokio::join!(
+        sender.serve_chunks(&manifest, result.chunks.clone(), 1, Duration::from_secs(60), |_| {}),
+        run_shr(dir.path(), &config, &args)
+    );
+    assert_eq!(served.unwrap().completed, 1);
+    assert!(stdout.contains("via P2P"), "{}", stdout);
+    assert_eq!(std::fs::read(&output_path).unwrap(), data);
+
+    let failure = |args: Vec<String>| {
+        let config = config.clone();
+        let dir = dir.path().to_path_buf();
+        async move {
+            let args: Vec<&std::ffi::OsStr> = args.iter().map(|a| a.as_ref()).collect();
+            let output = shr_output(&dir, &config, &args).await;
+            assert!(!output.status.success());
+            String::from_utf8(output.stderr).unwrap()
+        }
+    };
+
+    // Nothing listening there
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let refused = format!("/ip4/127.0.0.1/tcp/{}/p2p/{}", port, sender.local_peer_id());
+    let stderr = failure(vec!["recv".into(), "--peer".into(), refused, hash.clone()]).await;
+    assert!(stderr.contains("ConnectionRefused"), "{}", stderr);
+
+    // The sender answers, but isn't the peer the address names
+    let impostor = format!("{}/p2p/{}", addr, libp2p::PeerId::random());
+    let args = vec!["recv".into(), "--peer".into(), impostor, hash.clone()];
+    let (stderr, _) = tokio::join!(failure(args), tokio::time::timeout(Duration::from_secs(5), sender.serve_chunks(&manifest, result.chunks.clone(), 1, Duration::from_secs(60), |_| {})));
+    assert!(stderr.contains("PeerIdMismatch"), "{}", stderr);
+
+    // The right peer, serving something else
+    let args = vec!["recv".into(), "--peer".into(), peer, hex::encode([3u8; 32])];
+    let (stderr, _) = tokio::join!(failure(args), tokio::time::timeout(Duration::from_secs(5), sender.serve_chunks(&manifest, result.chunks.clone(), 1, Duration::from_secs(60), |_| {})));
+    assert!(stderr.contains("has no file"), "{}", stderr);
+}
+
+// `recv --peer` fetches from every peer named, so two seeders that each have half the file are
+// enough for the whole of it
+#[cfg(all(feature = "cli", feature = "p2p"))]
+#[tokio::test]
+async fn test_recv_from_two_half_seeders() {
+    use shrlink::p2p::create_shr_url;
+    use std::time::Duration;
+
+    let dir = tempfile::tempdir().unwrap();
+    let data: Vec<u8> = (0..300_000u32).map(|i| (i.wrapping_mul(2654435761) >> 10) as u8).collect();
+    let compressor = ParallelCompressor::new(32 * 1024, 1).unwrap();
+    let result = compressor.compress_bytes(&data).unwrap();
+    let (even, odd): (Vec<_>, Vec<_>) = result.chunks.iter().cloned().partition(|c| c.index % 2 == 0);
+
+    let mut seeders = Vec::new();
+    for name in ["a.key", "b.key"] {
+        let mut seeder_config = Config::default().p2p;
+        seeder_config.identity_path = Some(dir.path().join(name));
+        let mut seeder = p2p_client(seeder_config).await;
+        let addr = seeder.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).await.unwrap();
+        seeders.push((seeder, addr));
+    }
+    let (mut b, b_addr) = seeders.pop().unwrap();
+    let (mut a, a_addr) = seeders.pop().unwrap();
+    let manifest = a.prepare_manifest(result.file_hash, &result.chunks).unwrap();
+
+    let mut config = Config::default();
+    config.p2p.enable_mdns = false;
+    let url = format!("{}?addr={}", create_shr_url(a.local_peer_id(), &hex::encode(result.file_hash)), a_addr);
+    let peer = format!("{}/p2p/{}", b_addr, b.local_peer_id());
+    let output_path = dir.path().join("received.bin");
+    let args: [&std::ffi::OsStr; 6] = ["recv".as_ref(), url.as_ref(), "--peer".as_ref(), peer.as_ref(), "-o".as_ref(), output_path.as_os_str()];
+
+    let (from_a, from_b, stdout) = tokio::join!(
+        a.serve_chunks(&manifest, even.clone(), 1, Duration::from_secs(60), |_| {}),
+        b.serve_chunks(&manifest, odd.clone(), 1, Duration::from_secs(60), |_| {}),
+        run_shr(dir.path(), &config, &args)
+    );
+    assert!(stdout.contains("Fetching from 2 peers"), "{}", stdout);
+    assert_eq!(from_a.unwrap().chunks_served, even.len());
+    assert_eq!(from_b.unwrap().chunks_served, odd.len());
+    assert_eq!(std::fs::read(&output_path).unwrap(), data);
+}
+
+// A peer can hand out chunks that each check out against its own manifest, but they still have
+// to add up to the file the URL names
+#[cfg(all(feature = "cli", feature = "p2p"))]
+#[tokio::test]
+async fn test_recv_rejects_chunks_of_another_file() {
+    use shrlink::p2p::create_shr_url;
+    use std::time::Duration;
+
+    let dir = tempfile::tempdir().unwrap();
+    let compressor = ParallelCompressor::new(64 * 1024, 1).unwrap();
+    let wanted = compressor.compress_bytes(&vec![1u8; 200_000]).unwrap();
+    let served = compressor.compress_bytes(&vec![2u8; 200_000]).unwrap();
+
+    let mut sender_config = Config::default().p2p;
+    sender_config.identity_path = Some(dir.path().join("sender.key"));
+    let mut sender = p2p_client(sender_config).await;
+    let addr = sender.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).await.unwrap();
+    let manifest = sender.prepare_manifest(wanted.file_hash, &served.chunks).unwrap();
+
+    let mut config = Config::default();
+    config.p2p.enable_mdns = false;
+    let url = format!("{}?addr={}", create_shr_url(sender.local_peer_id(), &hex::encode(wanted.file_hash)), addr);
+    let output_path = dir.path().join("received.bin");
+    let args: [&std::ffi::OsStr; 4] = ["recv".as_ref(), url.as_ref(), "-o".as_ref(), output_path.as_os_str()];
+
+    let (_, output) = tokio::join!(
+        sender.serve_chunks(&manifest, served.chunks.clone(), 1, Duration::from_secs(60), |_| {}),
+        shr_output(dir.path(), &config, &args)
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains(&hex::encode(wanted.file_hash)) && stderr.contains("reassembled"), "{}", stderr);
+    assert!(!output_path.exists());
+    assert!(!dir.path().join("received.bin.shrpart").exists());
+}
+
+// Everyone a URL is shared with can receive at once, past the limit too once a place is free
+#[cfg(all(feature = "cli", feature = "p2p"))]
+#[tokio::test]
+async fn test_send_serves_several_receivers_at_once() {
+    use shrlink::p2p::create_shr_url;
+    use std::time::Duration;
+
+    let dir = tempfile::tempdir().unwrap();
+    let data: Vec<u8> = (0..300_000u32).map(|i| (i.wrapping_mul(2654435761) >> 7) as u8).collect();
+    let compressor = ParallelCompressor::new(32 * 1024, 1).unwrap();
+    let result = compressor.compress_bytes(&data).unwrap();
+
+    let mut sender_config = Config::default().p2p;
+    sender_config.identity_path = Some(dir.path().join("sender.key"));
+    sender_config.max_concurrent_transfers = 2;
+    let mut sender = p2p_client(sender_config).await;
+    let addr = sender.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).await.unwrap();
+    let manifest = sender.prepare_manifest(result.file_hash, &result.chunks).unwrap();
+
+    let mut config = Config::default();
+    config.p2p.enable_mdns = false;
+    let url = format!("{}?addr={}", create_shr_url(sender.local_peer_id(), &hex::encode(result.file_hash)), addr);
+    // Each in a home of its own, so each has an identity of its own
+    let receive = |name: &str| {
+        let home = dir.path().join(name);
+        std::fs::create_dir(&home).unwrap();
+        let (url, config) = (url.clone(), config.clone());
+        async move {
+            let output_path = home.join("received.bin");
+            let args: [&std::ffi::OsStr; 4] = ["recv".as_ref(), url.as_ref(), "-o".as_ref(), output_path.as_os_str()];
+            run_shr(&home, &config, &args).await;
+            std::fs::read(&output_path).unwrap()
+        }
+    };
+
+    let (served, first, second, third) = tokio::join!(
+        sender.serve_chunks(&manifest, result.chunks.clone(), 3, Duration::from_secs(60), |_| {}),
+        receive("first"),
+        receive("second"),
+        receive("third")
+    );
+    let served = served.unwrap();
+    assert_eq!((served.peers, served.completed), (3, 3));
+    assert_eq!(served.chunks_served, 3 * result.chunks.len());
+    for received in [first, second, third] {
+        assert_eq!(received, data);
+    }
+}
+
+// Picking up a receive that was cut off part way asks the peer only for what's missing
+#[cfg(all(feature = "cli", feature = "p2p"))]
+#[tokio::test]
+async fn test_recv_resumes_from_peer() {
+    use shrlink::p2p::{create_shr_url, ServeEvent};
+    use shrlink::partial::PartialDownload;
+    use std::time::Duration;
+
+    let dir = tempfile::tempdir().unwrap();
+    let data: Vec<u8> = (0..300_000u32).map(|i| (i.wrapping_mul(2654435761) >> 11) as u8).collect();
+    let compressor = ParallelCompressor::new(32 * 1024, 1).unwrap();
+    let result = compressor.compress_bytes(&data).unwrap();
+    let total = result.chunks.len();
+
+    let mut sender_config = Config::default().p2p;
+    sender_config.identity_path = Some(dir.path().join("sender.key"));
+    let cancel = tokio_util::sync::CancellationToken::new();
+    let mut sender = p2p_client(sender_config).await.with_cancellation(cancel.clone());
+    let addr = sender.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).await.unwrap();
+    let manifest = sender.prepare_manifest(result.file_hash, &result.chunks).unwrap();
+
+    // The first receive got every other chunk before it was killed
+    let output_path = dir.path().join("received.bin");
+    let partial_path = PartialDownload::path_for(&output_path);
+    let mut partial = PartialDownload::create(&partial_path, result.file_hash, total).unwrap();
+    for position in (0..total).step_by(2) {
+        partial.record(position, &result.chunks[position]).unwrap();
+    }
+    drop(partial);
+
+    let mut config = Config::default();
+    config.p2p.enable_mdns = false;
+    let url = format!("{}?addr={}", create_shr_url(sender.local_peer_id(), &hex::encode(result.file_hash)), addr);
+    let args: [&std::ffi::OsStr; 5] = ["recv".as_ref(), url.as_ref(), "--resume".as_ref(), "-o".as_ref(), output_path.as_os_str()];
+
+    // The sender never sees the whole file go out, so it's stopped once the receiver is done
+    let mut served = 0;
+    let (_, stdout) = tokio::join!(
+        sender.serve_chunks(&manifest, result.chunks.clone(), 1, Duration::from_secs(60), |event| {
+            if let ServeEvent::ChunkServed { chunks_served, .. } = event {
+                served = chunks_served;
+            }
+        }),
+        async {
+            let stdout = run_shr(dir.path(), &config, &args).await;
+            cancel.cancel();
+            stdout
+        }
+    );
+    assert!(stdout.contains(&format!("Resuming with {} of {} chunks", total.div_ceil(2), total)), "{}", stdout);
+    assert_eq!(served, total / 2);
+    assert_eq!(std::fs::read(&output_path).unwrap(), data);
+    assert!(!partial_path.exists());
+}
+
+// `send` keeps serving after it prints its URL, and exits once the receiver has the whole file
+#[cfg(all(feature = "cli", feature = "p2p"))]
+#[tokio::test]
+async fn test_send_serves_until_the_receiver_finishes() {
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    let dir = tempfile::tempdir().unwrap();
+    let data: Vec<u8> = (0..200_000u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+    let payload = dir.path().join("payload.bin");
+    std::fs::write(&payload, &data).unwrap();
+
+    let mut config = Config::default();
+    config.compression.block_size = BlockSize::Fixed(64 * 1024);
+    let config_path = dir.path().join("config.toml");
+    std::fs::write(&config_path, toml::to_string_pretty(&config).unwrap()).unwrap();
+
+    // Listening and answering mDNS, so the sender has someone to serve
+    let mut receiver_config = config.p2p.clone();
+    receiver_config.identity_path = Some(dir.path().join("receiver.key"));
+    let mut receiver = p2p_client(receiver_config).await;
+    receiver.listen_on("/ip4/0.0.0.0/tcp/0".parse().unwrap()).await.unwrap();
+
+    let mut sender = tokio::process::Command::new(env!("CARGO_BIN_EXE_shr"))
+        .arg("--config").arg(&config_path)
+        .args(["--progress", "none", "send", "--timeout", "30"])
+        .arg(&payload)
+        .current_dir(dir.path())
+        .env("HOME", dir.path())
+        .env("XDG_CACHE_HOME", dir.path().join("cache"))
+        .env("XDG_DATA_HOME", dir.path().join("data"))
+        .stdout(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+    let mut stdout = BufReader::new(sender.stdout.take().unwrap()).lines();
+
+    // The receiver's swarm has to keep running for the sender to discover it
+    let url = tokio::time::timeout(Duration::from_secs(60), async {
+        loop {
+            tokio::select! {
+                line = stdout.next_line() => {
+                    let line = line.unwrap().expect("send exited without printing a URL");
+                    if let Some(url) = line.split_whitespace().find(|w| w.starts_with("shr://")) {
+                        return url.to_string();
+                    }
+                }
+                // Returns at once when anyone's already been found, so it's paced
+                _ = async {
+                    let _ = receiver.discover_peers().await;
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                } => {}
+            }
+        }
+    })
+    .await
+    .expect("no shr:// URL printed in time");
+    let (sender_id, _) = shrlink::p2p::parse_shr_url(&url).unwrap();
+
+    // Other tests' clients may answer too, so only the sender is looked for
+    tokio::time::timeout(Duration::from_secs(60), async {
+        while !receiver.discover_peers().await.unwrap().iter().any(|p| p.peer_id == sender_id) {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .expect("sender not discovered in time");
+    let received = receiver.receive_chunks(sender_id, data.len().div_ceil(64 * 1024)).await.unwrap();
+    drop(receiver);
+
+    let status = tokio::time::timeout(Duration::from_secs(30), sender.wait())
+        .await
+        .expect("send kept serving after the transfer finished")
+        .unwrap();
+    assert!(status.success());
+    let mut rest = String::new();
+    while let Some(line) = stdout.next_line().await.unwrap() {
+        rest.push_str(&line);
+        rest.push('\n');
+    }
+    assert!(rest.contains("1 of them complete"), "{}", rest);
+
+    let compressor = ParallelCompressor::new(64 * 1024, 1).unwrap();
+    assert_eq!(compressor.decompress_chunks_parallel(&received).unwrap().concat(), data);
+}
+
+// Ctrl-C while serving is a clean stop: the summary says how far it got, and the exit code is 0
+#[cfg(all(feature = "cli", feature = "p2p", unix))]
+#[tokio::test]
+async fn test_send_stops_cleanly_on_ctrl_c() {
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    let dir = tempfile::tempdir().unwrap();
+    let payload = dir.path().join("payload.bin");
+    std::fs::write(&payload, vec![5u8; 100_000]).unwrap();
+
+    let config = Config::default();
+    let config_path = dir.path().join("config.toml");
+    std::fs::write(&config_path, toml::to_string_pretty(&config).unwrap()).unwrap();
+
+    let mut receiver_config = config.p2p.clone();
+    receiver_config.identity_path = Some(dir.path().join("receiver.key"));
+    let mut receiver = p2p_client(receiver_config).await;
+    receiver.listen_on("/ip4/0.0.0.0/tcp/0".parse().unwrap()).await.unwrap();
+
+    let mut sender = tokio::process::Command::new(env!("CARGO_BIN_EXE_shr"))
+        .arg("--config").arg(&config_path)
+        .args(["--progress", "none", "send", "--timeout", "30000"])
+        .arg(&payload)
+        .current_dir(dir.path())
+        .env("HOME", dir.path())
+        .env("XDG_CACHE_HOME", dir.path().join("cache"))
+        .env("XDG_DATA_HOME", dir.path().join("data"))
+        .stdout(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+    let mut stdout = BufReader::new(sender.stdout.take().unwrap()).lines();
+
+    tokio::time::timeout(Duration::from_secs(60), async {
+        loop {
+            tokio::select! {
+                line = stdout.next_line() => {
+                    let line = line.unwrap().expect("send exited before waiting for receivers");
+                    if line.contains("Waiting for a receiver") {
+                        return;
+                    }
+                }
+                _ = async {
+                    let _ = receiver.discover_peers().await;
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                } => {}
+            }
+        }
+    })
+    .await
+    .expect("send never started serving");
+    drop(receiver);
+
+    let pid = sender.id().unwrap().to_string();
+    assert!(std::process::Command::new("kill").args(["-INT", &pid]).status().unwrap().success());
+    let status = tokio::time::timeout(Duration::from_secs(30), sender.wait())
+        .await
+        .expect("send kept serving after Ctrl-C")
+        .unwrap();
+    assert!(status.success(), "{:?}", status);
+    let mut rest = String::new();
+    while let Some(line) = stdout.next_line().await.unwrap() {
+        rest.push_str(&line);
+        rest.push('\n');
+    }
+    assert!(rest.contains("Stopped with 0 of 1 receivers complete"), "{}", rest);
+}
+
 #[test]
 fn test_fallback_url_detection() {
     use shrlink::fallback::is_http_url;
@@ -99,6 +601,7 @@ fn test_fallback_url_detection() {
     assert!(!is_http_url("file:///local/path"));
 }
 
+#[cfg(feature = "fs")]
 #[tokio::test]
 async fn test_large_file_chunking() {
     let large_data = vec![1u8; 20 * 1024 * 1024]; // 20 MB
@@ -106,7 +609,7 @@ async fn test_large_file_chunking() {
     let mut temp_file = NamedTempFile::new().unwrap();
     temp_file.write_all(&large_data).unwrap();
     
-    let compressor = ParallelCompressor::new(4 * 1024 * 1024, 1); // 4 MiB chunks
+    let compressor = ParallelCompressor::new(4 * 1024 * 1024, 1).unwrap(); // 4 MiB chunks
     let result = compressor.compress_file(temp_file.path()).unwrap();
     
     assert_eq!(result.chunks.len(), 5); // 20 MB / 4 MiB = 5 chunks
@@ -122,7 +625,6 @@ async fn test_large_file_chunking() {
 
 #[tokio::test]
 async fn test_hash_verification() {
-
     use shrlink::ShrLinkError;
     
     let test_data = b"Test data for hash verification";
@@ -142,3 +644,2612 @@ async fn test_hash_verification() {
         _ => panic!("Expected hash mismatch error"),
     }
 }
+
+fn decode_chunked_body(raw: &[u8]) -> Option<Vec<u8>> {
+    let mut body = Vec::new();
+    let mut offset = 0;
+    
+    loop {
+        let line_end = offset + raw[offset..].windows(2).position(|w| w == b"\r\n")?;
+        let size = usize::from_str_radix(std::str::from_utf8(&raw[offset..line_end]).ok()?.trim(), 16).ok()?;
+        offset = line_end + 2;
+        
+        if size == 0 {
+            return Some(body);
+        }
+        if raw.len() < offset + size + 2 {
+            return None;
+        }
+        
+        body.extend_from_slice(&raw[offset..offset + size]);
+        offset += size + 2;
+    }
+}
+
+// A sender holding a pairing code is found through the rendezvous server and hands the file to
+// the receiver with the same code, which needs no URL at all
+#[cfg(all(feature = "cli", feature = "p2p"))]
+#[tokio::test]
+async fn test_send_and_recv_with_a_pairing_code() {
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    let dir = tempfile::tempdir().unwrap();
+    let receiver_dir = tempfile::tempdir().unwrap();
+    let data: Vec<u8> = (0..150_000u32).map(|i| (i.wrapping_mul(2654435761) >> 11) as u8).collect();
+    let payload = dir.path().join("payload.bin");
+    std::fs::write(&payload, &data).unwrap();
+
+    let (rendezvous_url, records) = spawn_mock_rendezvous_server().await;
+    let mut config = Config::default();
+    config.p2p.enable_mdns = false;
+    config.p2p.rendezvous_url = Some(rendezvous_url);
+    let config_path = dir.path().join("config.toml");
+    std::fs::write(&config_path, toml::to_string_pretty(&config).unwrap()).unwrap();
+
+    let mut sender = tokio::process::Command::new(env!("CARGO_BIN_EXE_shr"))
+        .arg("--config").arg(&config_path)
+        .args(["--progress", "none", "send", "--code"])
+        .arg(&payload)
+        .current_dir(dir.path())
+        .env("HOME", dir.path())
+        .env("XDG_CACHE_HOME", dir.path().join("cache"))
+        .env("XDG_DATA_HOME", dir.path().join("data"))
+        .stdout(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+    let mut stdout = BufReader::new(sender.stdout.take().unwrap()).lines();
+
+    let code = tokio::time::timeout(Duration::from_secs(60), async {
+        loop {
+            let line = stdout.next_line().await.unwrap().expect("send exited without printing a code");
+            if let Some(code) = line.trim().strip_prefix("shr recv --code ") {
+                return code.to_string();
+            }
+        }
+    })
+    .await
+    .expect("no pairing code printed in time");
+    assert_eq!(records.lock().unwrap().len(), 1);
+
+    // A code with another number finds no sender, and leaves this one for its receiver
+    let other = format!("{}-guitar-orbit-maple", (code.split('-').next().unwrap().parse::<u16>().unwrap() % 999) + 1);
+    let output = shr_output(receiver_dir.path(), &config, &["recv".as_ref(), "--code".as_ref(), other.as_ref()]).await;
+    assert!(!output.status.success());
+
+    let out = receiver_dir.path().join("received.bin");
+    let stdout_text = run_shr(receiver_dir.path(), &config, &["recv".as_ref(), "--code".as_ref(), code.as_ref(), "-o".as_ref(), out.as_os_str()]).await;
+    assert!(stdout_text.contains("Paired with"), "{}", stdout_text);
+    assert_eq!(std::fs::read(&out).unwrap(), data);
+
+    let status = tokio::time::timeout(Duration::from_secs(30), sender.wait())
+        .await
+        .expect("send kept serving after the transfer finished")
+        .unwrap();
+    assert!(status.success());
+    assert!(records.lock().unwrap().is_empty(), "the pairing record was left behind");
+}
+
+async fn read_http_request(stream: &mut tokio::net::TcpStream) -> (String, Vec<u8>) {
+    use tokio::io::AsyncReadExt;
+    
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let header_end = loop {
+        let n = stream.read(&mut chunk).await.unwrap();
+        buffer.extend_from_slice(&chunk[..n]);
+        if let Some(pos) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+    };
+    
+    let head = String::from_utf8(buffer[..header_end].to_vec()).unwrap();
+    let lower = head.to_ascii_lowercase();
+    let mut body = buffer[header_end..].to_vec();
+    
+    if lower.contains("transfer-encoding: chunked") {
+        loop {
+            if let Some(decoded) = decode_chunked_body(&body) {
+                return (head, decoded);
+            }
+            let n = stream.read(&mut chunk).await.unwrap();
+            body.extend_from_slice(&chunk[..n]);
+        }
+    }
+    
+    let content_length = lower
+        .lines()
+        .find_map(|line| line.strip_prefix("content-length:").map(|v| v.trim().parse::<usize>().unwrap()))
+        .unwrap_or(0);
+    
+    while body.len() < content_length {
+        let n = stream.read(&mut chunk).await.unwrap();
+        body.extend_from_slice(&chunk[..n]);
+    }
+    
+    (head, body)
+}
+
+// Just enough of the fallback server protocol to exercise the client's filename handling
+async fn spawn_mock_fallback_server() -> String {
+    use std::collections::HashMap;
+    use tokio::io::AsyncWriteExt;
+    
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    
+    tokio::spawn(async move {
+        let mut files: HashMap<String, Vec<u8>> = HashMap::new();
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (head, body) = read_http_request(&mut stream).await;
+            let path = head.split_whitespace().nth(1).unwrap().to_string();
+            
+            // Only whole uploads; /upload/init is a 404, as on servers that take no parts
+            if head.starts_with("POST /upload ") {
+                let header_end = body.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+                let part_head = String::from_utf8(body[..header_end].to_vec()).unwrap();
+                let disposition = part_head.lines().find(|l| l.starts_with("Content-Disposition:")).unwrap();
+                let name = shrlink::filename::parse_content_disposition(disposition).unwrap();
+                
+                let data_start = header_end + 4;
+                let closing = body.windows(4).rposition(|w| w == b"\r\n--").unwrap();
+                files.insert(name, body[data_start..closing].to_vec());
+                
+                stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await.unwrap();
+            } else if let Some(segment) = path.strip_prefix("/files/") {
+                let name = shrlink::filename::decode_path_segment(segment).unwrap();
+                let data = files.get(&name).cloned().unwrap_or_default();
+                let start = head
+                    .to_ascii_lowercase()
+                    .lines()
+                    .find_map(|line| line.strip_prefix("range: bytes=")?.strip_suffix('-')?.parse::<usize>().ok());
+                let (status, range) = match start {
+                    Some(start) => ("206 Partial Content", format!("Content-Range: bytes {}-{}/{}\r\n", start, data.len() - 1, data.len())),
+                    None => ("200 OK", String::new()),
+                };
+                let body = &data[start.unwrap_or(0)..];
+                let head = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nAccept-Ranges: bytes\r\n{}Content-Disposition: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    body.len(),
+                    range,
+                    shrlink::filename::content_disposition("attachment", &name)
+                );
+                // A receiver skipping a cached chunk hangs up partway through
+                let _ = stream.write_all(head.as_bytes()).await;
+                let _ = stream.write_all(body).await;
+            } else {
+                stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await.unwrap();
+            }
+        }
+    });
+    
+    format!("http://{}", addr)
+}
+
+// PUT, GET and DELETE of pairing records by number, as the rendezvous client expects
+#[cfg(all(feature = "cli", feature = "p2p"))]
+async fn spawn_mock_rendezvous_server() -> (String, std::sync::Arc<std::sync::Mutex<std::collections::HashMap<String, Vec<u8>>>>) {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::AsyncWriteExt;
+    
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let records: Arc<Mutex<HashMap<String, Vec<u8>>>> = Arc::default();
+    
+    tokio::spawn({
+        let records = records.clone();
+        async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let (head, body) = read_http_request(&mut stream).await;
+                let mut words = head.split_whitespace();
+                let (method, path) = (words.next().unwrap().to_string(), words.next().unwrap().to_string());
+                let response = {
+                    let mut records = records.lock().unwrap();
+                    match method.as_str() {
+                        "PUT" if records.contains_key(&path) => "HTTP/1.1 412 Precondition Failed\r\nContent-Length: 0\r\n".to_string(),
+                        "PUT" => {
+                            records.insert(path, body);
+                            "HTTP/1.1 201 Created\r\nContent-Length: 0\r\n".to_string()
+                        }
+                        "GET" => match records.get(&path) {
+                            Some(record) => format!(
+                                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                                record.len(),
+                                String::from_utf8_lossy(record)
+                            ),
+                            None => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n".to_string(),
+                        },
+                        "DELETE" if records.remove(&path).is_some() => "HTTP/1.1 204 No Content\r\n".to_string(),
+                        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n".to_string(),
+                    }
+                };
+                let response = match response.contains("\r\n\r\n") {
+                    true => response.replacen("\r\n\r\n", "\r\nConnection: close\r\n\r\n", 1),
+                    false => format!("{}Connection: close\r\n\r\n", response),
+                };
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        }
+    });
+    
+    (format!("http://{}/pair", addr), records)
+}
+
+#[tokio::test]
+async fn test_unicode_filename_http_roundtrip() {
+    use shrlink::fallback::HttpFallback;
+    
+    let endpoint = spawn_mock_fallback_server().await;
+    let client = HttpFallback::new(parallel_config(endpoint, 1)).await.unwrap();
+    
+    let compressor = ParallelCompressor::default();
+    let test_data = b"unicode filename payload".repeat(64);
+    
+    for name in ["отчёт 2024.pdf", "会议记录 最终版.docx", "🎉 party 🎉.mov", "with spaces.txt"] {
+        let chunk = compressor.compress_chunk(0, test_data.clone()).unwrap();
+        let url = client.upload_chunks(&[chunk], Some(name)).await.unwrap();
+        assert!(url.is_ascii(), "download URL must be percent-encoded: {}", url);
+        
+        let (chunks, received_name) = client.download_chunks_named(&url).await.unwrap();
+        assert_eq!(received_name.as_deref(), Some(name));
+        assert_eq!(compressor.decompress_chunk(&chunks[0]).unwrap(), test_data);
+    }
+}
+
+#[tokio::test]
+async fn test_verified_upload_is_checked_against_url_hash() {
+    use shrlink::config::FallbackConfig;
+    use shrlink::fallback::HttpFallback;
+    use shrlink::verify;
+    
+    let endpoint = spawn_mock_fallback_server().await;
+    let client = HttpFallback::new(FallbackConfig { verified_uploads: true, ..parallel_config(endpoint, 1) }).await.unwrap();
+    
+    let compressor = ParallelCompressor::default();
+    let test_data: Vec<u8> = (0..300_000u32).map(|i| (i * 7 % 253) as u8).collect();
+    let result = compressor.compress_bytes(&test_data).unwrap();
+    let url = client.upload_chunks(&result.chunks, Some("verified.bin")).await.unwrap();
+    let (base, root) = verify::split_url_hash(&url).unwrap();
+    assert!(root.is_some(), "{}", url);
+    
+    let chunks = client.download_chunks(&url).await.unwrap();
+    assert_eq!(compressor.decompress_chunks_parallel(&chunks).unwrap().concat(), test_data);
+    
+    // Without the hash the body still checks against its own tree
+    let (bundle, _) = client.download_bundle(base).await.unwrap();
+    assert_eq!(shrlink::bundle::parse_shr_bundle(&bundle).unwrap().len(), chunks.len());
+    
+    let wrong = verify::url_with_hash(base, &blake3::hash(b"something else"));
+    assert!(matches!(client.download_chunks(&wrong).await, Err(shrlink::ShrLinkError::HashMismatch { .. })));
+}
+
+// Answers uploads the way `reply` says, filing each under both a server-chosen id and the
+// uploaded name, and serves GETs of any path by its last segment
+async fn spawn_mock_reply_server(reply: &'static str) -> String {
+    use std::collections::HashMap;
+    use tokio::io::AsyncWriteExt;
+    
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    
+    tokio::spawn(async move {
+        let mut files: HashMap<String, Vec<u8>> = HashMap::new();
+        for n in 0.. {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (head, body) = read_http_request(&mut stream).await;
+            let path = head.split_whitespace().nth(1).unwrap().to_string();
+            
+            if head.starts_with("POST /upload") {
+                let header_end = body.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+                let part_head = String::from_utf8(body[..header_end].to_vec()).unwrap();
+                let disposition = part_head.lines().find(|l| l.starts_with("Content-Disposition:")).unwrap();
+                let name = shrlink::filename::parse_content_disposition(disposition).unwrap();
+                let closing = body.windows(4).rposition(|w| w == b"\r\n--").unwrap();
+                let id = format!("blob-{}", n);
+                files.insert(name, body[header_end + 4..closing].to_vec());
+                files.insert(id.clone(), body[header_end + 4..closing].to_vec());
+                
+                let (headers, body) = match reply {
+                    "json-url" => (String::new(), format!("{{\"url\": \"http://{}/cdn/{}\"}}", addr, id)),
+                    "json-id" => (String::new(), format!("{{\"id\": \"{}\"}}", id)),
+                    "location" => (format!("Location: /blobs/{}\r\n", id), String::new()),
+                    _ => (String::new(), String::new()),
+                };
+                let response = format!("HTTP/1.1 201 Created\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}", headers, body.len(), body);
+                stream.write_all(response.as_bytes()).await.unwrap();
+            } else {
+                let segment = path.rsplit('/').next().unwrap();
+                let response = match files.get(&shrlink::filename::decode_path_segment(segment).unwrap()) {
+                    Some(data) => [format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", data.len()).into_bytes(), data.clone()].concat(),
+                    None => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec(),
+                };
+                let _ = stream.write_all(&response).await;
+            }
+        }
+    });
+    
+    format!("http://{}", addr)
+}
+
+#[tokio::test]
+async fn test_download_url_comes_from_upload_reply() {
+    use shrlink::config::FallbackConfig;
+    use shrlink::fallback::HttpFallback;
+    
+    let compressor = ParallelCompressor::default();
+    let test_data = b"where did it go".repeat(100);
+    let chunk = compressor.compress_chunk(0, test_data.clone()).unwrap();
+    
+    for (reply, path) in [("json-url", "/cdn/blob-0"), ("json-id", "/files/blob-0"), ("location", "/blobs/blob-0"), ("empty", "/files/")] {
+        let endpoint = spawn_mock_reply_server(reply).await;
+        let client = HttpFallback::new(parallel_config(endpoint.clone(), 1)).await.unwrap();
+        
+        let url = client.upload_chunks(std::slice::from_ref(&chunk), Some("reply.bin")).await.unwrap();
+        assert!(url.starts_with(&format!("{}{}", endpoint, path)), "{}: {}", reply, url);
+        let chunks = client.download_chunks(&url).await.unwrap();
+        assert_eq!(compressor.decompress_chunk(&chunks[0]).unwrap(), test_data, "{}", reply);
+    }
+    
+    // A fixed pattern wins over where the server says, with its id filled in
+    let endpoint = spawn_mock_reply_server("json-id").await;
+    let client = HttpFallback::new(FallbackConfig { url_template: Some("{endpoint}/mirror/{id}".to_string()), ..parallel_config(endpoint.clone(), 1) }).await.unwrap();
+    let url = client.upload_chunks(&[chunk], None).await.unwrap();
+    assert_eq!(url, format!("{}/mirror/blob-0", endpoint));
+    assert!(client.download_chunks(&url).await.is_ok());
+}
+
+// Answers with each of `failures` in turn, then with empty stats; counts every request
+async fn spawn_mock_flaky_server(failures: &'static [&'static str]) -> (String, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+    use std::sync::atomic::Ordering;
+    use tokio::io::AsyncWriteExt;
+    
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let requests = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    
+    let counted = requests.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            read_http_request(&mut stream).await;
+            let n = counted.fetch_add(1, Ordering::SeqCst);
+            let response = match failures.get(n) {
+                Some(status) => format!("HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status),
+                None => {
+                    let body = r#"{"total_files": 2, "total_bytes": 10}"#;
+                    format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body)
+                }
+            };
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+    });
+    
+    (format!("http://{}", addr), requests)
+}
+
+#[tokio::test]
+async fn test_transient_http_failures_are_retried() {
+    use shrlink::config::FallbackConfig;
+    use shrlink::fallback::HttpFallback;
+    use std::sync::atomic::Ordering;
+    
+    let config = |endpoint: String| parallel_config(endpoint, 1);
+    
+    let (endpoint, requests) = spawn_mock_flaky_server(&["503 Service Unavailable", "503 Service Unavailable"]).await;
+    let stats = HttpFallback::new(config(endpoint)).await.unwrap().get_upload_stats().await.unwrap();
+    assert_eq!((stats.total_files, stats.total_bytes), (2, 10));
+    assert_eq!(requests.load(Ordering::SeqCst), 3);
+    
+    // 429 is worth waiting out too, but only so many times
+    let (endpoint, requests) = spawn_mock_flaky_server(&["429 Too Many Requests"; 5]).await;
+    let client = HttpFallback::new(FallbackConfig { max_retries: 1, ..config(endpoint) }).await.unwrap();
+    assert!(client.get_upload_stats().await.is_err());
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+    
+    // Any other 4xx won't change by asking again
+    let (endpoint, requests) = spawn_mock_flaky_server(&["404 Not Found"]).await;
+    assert!(HttpFallback::new(config(endpoint)).await.unwrap().get_upload_stats().await.is_err());
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+}
+
+// Reads each upload without keeping it, counting body bytes as they arrive, and keeps the
+// request heads
+async fn spawn_mock_draining_server() -> (String, std::sync::Arc<std::sync::atomic::AtomicU64>, std::sync::Arc<std::sync::Mutex<Vec<String>>>) {
+    use std::sync::atomic::Ordering;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+    
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let received = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
+    let heads = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    
+    async fn drain<R: tokio::io::AsyncRead + Unpin>(reader: &mut R, len: u64, counted: &std::sync::atomic::AtomicU64) {
+        let mut part = reader.take(len);
+        let mut buffer = vec![0u8; 64 * 1024];
+        loop {
+            let n = part.read(&mut buffer).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            counted.fetch_add(n as u64, Ordering::SeqCst);
+        }
+    }
+    
+    let (counted, kept) = (received.clone(), heads.clone());
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = tokio::io::BufReader::new(stream);
+            let mut head = String::new();
+            while !head.ends_with("\r\n\r\n") {
+                stream.read_line(&mut head).await.unwrap();
+            }
+            let lower = head.to_ascii_lowercase();
+            let content_length = lower.lines().find_map(|line| line.strip_prefix("content-length:")?.trim().parse::<u64>().ok());
+            kept.lock().unwrap().push(head);
+            
+            match content_length {
+                Some(len) => drain(&mut stream, len, &counted).await,
+                None => loop {
+                    let mut size = String::new();
+                    stream.read_line(&mut size).await.unwrap();
+                    let size = u64::from_str_radix(size.trim(), 16).unwrap();
+                    if size == 0 {
+                        stream.read_line(&mut String::new()).await.unwrap();
+                        break;
+                    }
+                    drain(&mut stream, size, &counted).await;
+                    stream.read_line(&mut String::new()).await.unwrap();
+                },
+            }
+            
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await.unwrap();
+        }
+    });
+    
+    (format!("http://{}", addr), received, heads)
+}
+
+#[tokio::test]
+async fn test_streamed_upload_keeps_little_in_flight() {
+    use shrlink::bundle::BundleItem;
+    use shrlink::compression::{ChecksumAlgorithm, CompressedChunk, CompressionAlgorithm};
+    use shrlink::config::FallbackConfig;
+    use shrlink::fallback::HttpFallback;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    
+    const CHUNKS: usize = 256;
+    const CHUNK_SIZE: usize = 1024 * 1024;
+    let chunk = |index: usize| CompressedChunk {
+        index,
+        data: vec![index as u8; CHUNK_SIZE],
+        hash: *blake3::hash(&index.to_le_bytes()).as_bytes(),
+        original_size: CHUNK_SIZE,
+        algorithm: CompressionAlgorithm::Stored,
+        checksum: ChecksumAlgorithm::Blake3,
+    };
+    let config = |endpoint: String, verified_uploads| FallbackConfig { verified_uploads, ..parallel_config(endpoint, 1) };
+    
+    // How far what's been handed to the connection ever got ahead of what the server has read
+    let (endpoint, received, _) = spawn_mock_draining_server().await;
+    let (sent, peak) = (Arc::new(AtomicU64::new(0)), Arc::new(AtomicU64::new(0)));
+    let client = HttpFallback::new(config(endpoint, false)).await.unwrap().with_upload_progress(Arc::new({
+        let (sent, peak, received) = (sent.clone(), peak.clone(), received.clone());
+        move |n: u64| {
+            let ahead = (sent.fetch_add(n, Ordering::SeqCst) + n).saturating_sub(received.load(Ordering::SeqCst));
+            peak.fetch_max(ahead, Ordering::SeqCst);
+        }
+    }));
+    
+    // Made one at a time as the body asks for them, so none of the 256 MiB is held up front
+    let items = futures::stream::iter((0..CHUNKS).map(move |i| Ok(BundleItem::Chunk(chunk(i)))));
+    client.upload_stream(items, Some("big.bin"), |_| {}).await.unwrap();
+    
+    assert!(sent.load(Ordering::SeqCst) > (CHUNKS * CHUNK_SIZE) as u64);
+    assert!(received.load(Ordering::SeqCst) > sent.load(Ordering::SeqCst));
+    let peak = peak.load(Ordering::SeqCst);
+    assert!(peak < 32 * 1024 * 1024, "{} bytes were in flight at once", peak);
+    
+    // Chunks at hand give a body of known length, verified or not
+    for verified in [false, true] {
+        let (endpoint, received, heads) = spawn_mock_draining_server().await;
+        let client = HttpFallback::new(config(endpoint, verified)).await.unwrap();
+        let chunks: Vec<_> = (0..4).map(chunk).collect();
+        let url = client.upload_chunks(&chunks, None).await.unwrap();
+        assert_eq!(shrlink::verify::split_url_hash(&url).unwrap().1.is_some(), verified);
+        
+        let head = heads.lock().unwrap()[0].to_ascii_lowercase();
+        assert!(!head.contains("transfer-encoding: chunked"), "{}", head);
+        assert!(head.contains(&format!("content-length: {}", received.load(Ordering::SeqCst))), "{}", head);
+    }
+}
+
+// Serves one bundle of `chunks` stored chunks of `chunk_size` bytes, made as it's sent, and
+// counts the bytes handed to the connection
+async fn spawn_mock_bundle_server(chunks: usize, chunk_size: usize) -> (String, std::sync::Arc<std::sync::atomic::AtomicU64>) {
+    use shrlink::bundle::{self, EntryKind, FileMeta};
+    use shrlink::compression::{ChecksumAlgorithm, CompressedChunk, CompressionAlgorithm};
+    use std::sync::atomic::Ordering;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+    
+    async fn send_frame<W: tokio::io::AsyncWrite + Unpin>(stream: &mut W, frame: &[u8], counted: &std::sync::atomic::AtomicU64) {
+        stream.write_all(format!("{:x}\r\n", frame.len()).as_bytes()).await.unwrap();
+        stream.write_all(frame).await.unwrap();
+        stream.write_all(b"\r\n").await.unwrap();
+        counted.fetch_add(frame.len() as u64, Ordering::SeqCst);
+    }
+    
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let sent = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
+    
+    let counted = sent.clone();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = tokio::io::BufReader::new(stream);
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            stream.read_line(&mut head).await.unwrap();
+        }
+        stream.write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n").await.unwrap();
+        
+        let meta = FileMeta {
+            name: Some("big.bin".to_string()),
+            size: (chunks * chunk_size) as u64,
+            kind: EntryKind::File,
+            block_size: Some(chunk_size as u64),
+            ..Default::default()
+        };
+        send_frame(&mut stream, &bundle::header(), &counted).await;
+        send_frame(&mut stream, &bundle::encode_meta_frame(&meta).unwrap(), &counted).await;
+        let mut hasher = blake3::Hasher::new();
+        for index in 0..chunks {
+            let data = vec![(index % 250 + 1) as u8; chunk_size];
+            hasher.update(&data);
+            let chunk = CompressedChunk {
+                index,
+                hash: *blake3::hash(&data).as_bytes(),
+                data,
+                original_size: chunk_size,
+                algorithm: CompressionAlgorithm::Stored,
+                checksum: ChecksumAlgorithm::Blake3,
+            };
+            send_frame(&mut stream, &bundle::encode_frame(&chunk).unwrap(), &counted).await;
+        }
+        send_frame(&mut stream, &bundle::encode_file_hash_frame(hasher.finalize().as_bytes()), &counted).await;
+        send_frame(&mut stream, &bundle::trailer(), &counted).await;
+        stream.write_all(b"0\r\n\r\n").await.unwrap();
+    });
+    
+    (format!("http://{}", addr), sent)
+}
+
+#[tokio::test]
+async fn test_streamed_download_writes_as_it_arrives() {
+    use shrlink::bundle::BundleItem;
+    use shrlink::fallback::{CachedDownload, HttpFallback};
+    use std::sync::atomic::Ordering;
+    
+    const CHUNKS: usize = 256;
+    const CHUNK_SIZE: usize = 1024 * 1024;
+    let (endpoint, sent) = spawn_mock_bundle_server(CHUNKS, CHUNK_SIZE).await;
+    let url = format!("{}/files/big.bin", endpoint);
+    let client = HttpFallback::new(parallel_config(endpoint, 1)).await.unwrap();
+    let compressor = ParallelCompressor::default().with_memory_budget(8 * 1024 * 1024);
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("big.bin");
+    
+    // The whole-file hash comes after the chunks, so it's only there once they've all gone
+    let (tx, rx) = tokio::sync::mpsc::channel(16);
+    let file_hash = std::cell::Cell::new(None);
+    let kept = &file_hash;
+    let chunks = futures::stream::unfold(rx, move |mut rx| async move {
+        loop {
+            match rx.recv().await? {
+                BundleItem::Chunk(chunk) => return Some((Ok(chunk), rx)),
+                BundleItem::FileHash(hash) => kept.set(Some(hash)),
+                _ => {}
+            }
+        }
+    });
+    
+    // How far what the server has sent ever got ahead of what's on disk
+    let (mut written, mut peak) = (0u64, 0u64);
+    let response = client.open_bundle(&url).await.unwrap();
+    let (downloaded, streamed) = tokio::join!(
+        client.stream_bundle_download(response, None, tx),
+        compressor.write_stream_to_file(Box::pin(chunks), &output, |chunk| {
+            written += chunk.original_size as u64;
+            peak = peak.max(sent.load(Ordering::SeqCst).saturating_sub(written));
+        }),
+    );
+    assert!(matches!(downloaded.unwrap(), CachedDownload::Streamed { chunks_reused: 0, .. }));
+    
+    let mut expected = blake3::Hasher::new();
+    for index in 0..CHUNKS {
+        expected.update(&vec![(index % 250 + 1) as u8; CHUNK_SIZE]);
+    }
+    assert_eq!(file_hash.get(), Some(*expected.finalize().as_bytes()));
+    streamed.unwrap().commit(&output, file_hash.get().as_ref()).unwrap();
+    assert_eq!(std::fs::metadata(&output).unwrap().len(), (CHUNKS * CHUNK_SIZE) as u64);
+    assert_eq!(written, (CHUNKS * CHUNK_SIZE) as u64);
+    assert!(peak < 64 * 1024 * 1024, "{} bytes were held at once", peak);
+}
+
+// Serves `body` to every GET, in the range asked for when `ranges` is set and advertised by
+// HEAD, each connection sending no more than `bytes_per_sec`. Counts the GETs it answers
+async fn spawn_mock_range_server(body: Vec<u8>, ranges: bool, bytes_per_sec: Option<u64>) -> (String, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+    use std::sync::atomic::Ordering;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+    
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let body = std::sync::Arc::new(body);
+    let gets = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    
+    let counted = gets.clone();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (body, counted) = (body.clone(), counted.clone());
+            tokio::spawn(async move {
+                let mut stream = tokio::io::BufReader::new(stream);
+                let mut head = String::new();
+                while !head.ends_with("\r\n\r\n") {
+                    if stream.read_line(&mut head).await.unwrap() == 0 {
+                        return;
+                    }
+                }
+                let range = head.lines()
+                    .find_map(|line| line.to_ascii_lowercase().strip_prefix("range: bytes=").map(str::to_string))
+                    .filter(|_| ranges)
+                    .and_then(|range| {
+                        let (start, end) = range.split_once('-')?;
+                        Some((start.parse::<usize>().ok()?, end.parse::<usize>().ok()? + 1))
+                    });
+                
+                let mut reply = String::new();
+                let span = match range {
+                    Some((start, end)) => {
+                        reply += "HTTP/1.1 206 Partial Content\r\n";
+                        reply += &format!("Content-Range: bytes {}-{}/{}\r\n", start, end - 1, body.len());
+                        start..end
+                    }
+                    None => {
+                        reply += "HTTP/1.1 200 OK\r\n";
+                        0..body.len()
+                    }
+                };
+                if ranges {
+                    reply += "Accept-Ranges: bytes\r\n";
+                }
+                reply += &format!("Content-Length: {}\r\nConnection: close\r\n\r\n", span.len());
+                stream.write_all(reply.as_bytes()).await.unwrap();
+                if head.starts_with("HEAD ") {
+                    return;
+                }
+                
+                counted.fetch_add(1, Ordering::SeqCst);
+                for block in body[span].chunks(16 * 1024) {
+                    if stream.write_all(block).await.is_err() {
+                        return;
+                    }
+                    if let Some(rate) = bytes_per_sec {
+                        tokio::time::sleep(std::time::Duration::from_secs_f64(block.len() as f64 / rate as f64)).await;
+                    }
+                }
+            });
+        }
+    });
+    
+    (format!("http://{}", addr), gets)
+}
+
+// An HTTP fallback to `endpoint` split across `parallel_connections`, the rest as shipped
+fn parallel_config(endpoint: String, parallel_connections: u32) -> shrlink::config::FallbackConfig {
+    shrlink::config::FallbackConfig { endpoints: vec![endpoint], parallel_connections, ..Default::default() }
+}
+
+#[tokio::test]
+async fn test_parallel_download_splits_into_ranges() {
+    use shrlink::fallback::HttpFallback;
+    use std::sync::atomic::Ordering;
+    
+    let mut body = vec![0u8; 4 * 1024 * 1024 + 12345];
+    blake3::Hasher::new().update(b"ranges").finalize_xof().fill(&mut body);
+    
+    // Each connection is held to 8 MiB/s, so four at once should take about a quarter as long
+    let (endpoint, gets) = spawn_mock_range_server(body.clone(), true, Some(8 * 1024 * 1024)).await;
+    let url = format!("{}/files/data.bin", endpoint);
+    
+    let single = HttpFallback::new(parallel_config(endpoint.clone(), 1)).await.unwrap();
+    let started = std::time::Instant::now();
+    let (downloaded, _) = single.download_bundle(&url).await.unwrap();
+    let single_time = started.elapsed();
+    assert!(downloaded == body);
+    assert_eq!(gets.swap(0, Ordering::SeqCst), 1);
+    
+    let parallel = HttpFallback::new(parallel_config(endpoint, 4)).await.unwrap();
+    let started = std::time::Instant::now();
+    let (downloaded, _) = parallel.download_bundle(&url).await.unwrap();
+    let parallel_time = started.elapsed();
+    assert!(downloaded == body);
+    assert_eq!(gets.load(Ordering::SeqCst), 4);
+    assert!(parallel_time * 2 < single_time, "{:?} over four connections against {:?} over one", parallel_time, single_time);
+    
+    // A server without ranges gets asked once, for all of it
+    let (endpoint, gets) = spawn_mock_range_server(body.clone(), false, None).await;
+    let client = HttpFallback::new(parallel_config(endpoint.clone(), 4)).await.unwrap();
+    let (downloaded, _) = client.download_bundle(&format!("{}/files/data.bin", endpoint)).await.unwrap();
+    assert!(downloaded == body);
+    assert_eq!(gets.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_parallel_download_of_large_body_stitches_on_disk() {
+    use shrlink::fallback::HttpFallback;
+    use std::sync::atomic::Ordering;
+    
+    // Past what's put back together in memory, with a remainder for the last range
+    let mut body = vec![0u8; 72 * 1024 * 1024 + 7];
+    blake3::Hasher::new().update(b"stitched").finalize_xof().fill(&mut body);
+    let (endpoint, gets) = spawn_mock_range_server(body.clone(), true, None).await;
+    
+    let client = HttpFallback::new(parallel_config(endpoint.clone(), 3)).await.unwrap();
+    let (downloaded, _) = client.download_bundle(&format!("{}/files/data.bin", endpoint)).await.unwrap();
+    assert!(downloaded == body);
+    assert_eq!(gets.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn test_download_progress_adds_up_to_the_body() {
+    use shrlink::fallback::HttpFallback;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    
+    let mut body = vec![0u8; 3 * 1024 * 1024 + 99];
+    blake3::Hasher::new().update(b"progress").finalize_xof().fill(&mut body);
+    let (endpoint, _) = spawn_mock_range_server(body.clone(), true, None).await;
+    let url = format!("{}/files/data.bin", endpoint);
+    
+    // Over one connection and over several ranges alike
+    for connections in [1, 4] {
+        let client = HttpFallback::new(parallel_config(endpoint.clone(), connections)).await.unwrap();
+        let response = client.open_bundle(&url).await.unwrap();
+        assert_eq!(response.content_length(), Some(body.len() as u64));
+        
+        let counted = Arc::new(AtomicU64::new(0));
+        let response = response.with_progress(Arc::new({
+            let counted = counted.clone();
+            move |bytes| {
+                counted.fetch_add(bytes, Ordering::SeqCst);
+            }
+        }));
+        let (downloaded, _) = response.read().await.unwrap();
+        assert!(downloaded == body);
+        assert_eq!(counted.load(Ordering::SeqCst), body.len() as u64, "over {} connections", connections);
+    }
+}
+
+#[tokio::test]
+async fn test_file_backend_roundtrip() {
+    use shrlink::config::{FallbackBackend, FallbackConfig};
+    use shrlink::fallback::dir_store::file_url_path;
+    use shrlink::fallback::{open_store, HttpFallback};
+    use tokio_util::sync::CancellationToken;
+    
+    let share = tempfile::tempdir().unwrap();
+    let staging = tempfile::tempdir().unwrap();
+    let staged = staging.path().join("bundle");
+    let mut bundle = vec![0u8; 3 * 1024 * 1024 + 5];
+    blake3::Hasher::new().update(b"shared").finalize_xof().fill(&mut bundle);
+    std::fs::write(&staged, &bundle).unwrap();
+    
+    let config = FallbackConfig {
+        backend: FallbackBackend::File,
+        path: Some(share.path().to_path_buf()),
+        ..parallel_config("http://localhost:8080".to_string(), 1)
+    };
+    let store = open_store(&config, CancellationToken::new(), None).await.unwrap();
+    let url = store.upload_file(&staged, Some("report.pdf")).await.unwrap();
+    assert!(url.starts_with("file://") && url.ends_with("_report.pdf.shr"), "{}", url);
+    let other = store.upload_file(&staged, None).await.unwrap();
+    
+    // Receivers read it with the client they'd download over HTTP with
+    let client = HttpFallback::new(parallel_config("http://localhost:8080".to_string(), 1)).await.unwrap();
+    let (downloaded, name) = client.download_bundle(&url).await.unwrap();
+    assert!(downloaded == bundle);
+    assert_eq!(name.as_deref(), Some("report.pdf"));
+    
+    // Checked against the hash in the URL as an HTTP body is
+    let verified = open_store(&FallbackConfig { verified_uploads: true, ..config.clone() }, CancellationToken::new(), None).await.unwrap();
+    let checked = verified.upload_file(&staged, None).await.unwrap();
+    assert!(client.download_bundle(&checked).await.unwrap().0 == bundle);
+    let on_share = file_url_path(shrlink::verify::split_url_hash(&checked).unwrap().0).unwrap();
+    let mut tampered = std::fs::read(&on_share).unwrap();
+    *tampered.last_mut().unwrap() ^= 1;
+    std::fs::write(&on_share, tampered).unwrap();
+    assert!(client.download_bundle(&checked).await.is_err());
+    
+    let stats = store.get_upload_stats().await.unwrap();
+    assert_eq!(stats.total_files, 3);
+    assert!(store.delete_file(&checked).await.unwrap());
+    assert!(!store.delete_file(&checked).await.unwrap());
+    assert!(matches!(client.download_bundle(&checked).await, Err(shrlink::ShrLinkError::NotFound(_))));
+    // Nothing outside the directory can be named
+    let outside = url::Url::from_file_path(staged.canonicalize().unwrap()).unwrap().to_string();
+    assert!(store.delete_file(&outside).await.is_err());
+    assert!(staged.exists());
+    
+    // Cleanup goes by mtime, which an upload with its own expiry is dated by
+    let brief = open_store(&FallbackConfig { upload_expiry_secs: Some(1), ..config.clone() }, CancellationToken::new(), None).await.unwrap();
+    let expiring = brief.upload_file(&staged, None).await.unwrap();
+    assert_eq!(store.cleanup_old_files(false).await.unwrap().deleted.len(), 0);
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+    assert_eq!(store.cleanup_old_files(false).await.unwrap().deleted.len(), 1);
+    assert!(client.download_bundle(&expiring).await.is_err());
+    assert!(client.download_bundle(&other).await.is_ok());
+    let stats = store.get_upload_stats().await.unwrap();
+    assert_eq!((stats.total_files, stats.total_bytes), (2, 2 * bundle.len() as u64));
+    
+    let missing = FallbackConfig { path: Some(share.path().join("not-mounted")), ..config };
+    let error = open_store(&missing, CancellationToken::new(), None).await.err().unwrap().to_string();
+    assert!(error.contains("not-mounted") && error.contains("mounted"), "{}", error);
+}
+
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_builtin_fallback_server_roundtrip() {
+    use shrlink::config::FallbackConfig;
+    use shrlink::fallback::HttpFallback;
+    use shrlink::server::FallbackServer;
+    use tokio_util::sync::CancellationToken;
+    
+    let dir = tempfile::tempdir().unwrap();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    let shutdown = CancellationToken::new();
+    let server = tokio::spawn(FallbackServer::new(dir.path()).unwrap().serve(listener, shutdown.clone()));
+    
+    let config = parallel_config(endpoint.clone(), 1);
+    let client = HttpFallback::new(config.clone()).await.unwrap();
+    
+    let compressor = ParallelCompressor::default();
+    let test_data: Vec<u8> = (0..300_000u32).map(|i| (i * 7 % 253) as u8).collect();
+    let result = compressor.compress_bytes(&test_data).unwrap();
+    let url = client.upload_chunks(&result.chunks, Some("../../отчёт.bin")).await.unwrap();
+    let other = client.upload_chunks(&result.chunks[..1], None).await.unwrap();
+    
+    let (chunks, name) = client.download_chunks_named(&url).await.unwrap();
+    assert_eq!(name.as_deref(), Some("отчёт.bin"));
+    assert_eq!(compressor.decompress_chunks_parallel(&chunks).unwrap().concat(), test_data);
+    
+    // Resumed downloads ask for the rest of the body from an offset
+    let (bundle, _) = client.download_bundle(&url).await.unwrap();
+    let response = reqwest::Client::new().get(&url).header("Range", "bytes=100-").send().await.unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::PARTIAL_CONTENT);
+    assert_eq!(
+        response.headers()["content-range"].to_str().unwrap(),
+        format!("bytes 100-{}/{}", bundle.len() - 1, bundle.len())
+    );
+    assert_eq!(response.bytes().await.unwrap(), bundle[100..]);
+    
+    let stats = client.get_upload_stats().await.unwrap();
+    assert_eq!(stats.total_files, 2);
+    // Every download that got to the last byte counts, the one resumed from an offset included
+    let files = stats.files.unwrap();
+    let mut downloads: Vec<u64> = files.iter().map(|file| file.downloads).collect();
+    downloads.sort();
+    assert_eq!(downloads, [0, 3]);
+    assert_eq!(files.iter().map(|file| file.size).sum::<u64>(), stats.total_bytes);
+    
+    assert!(client.delete_file(&other).await.unwrap());
+    assert!(client.download_chunks(&other).await.is_err());
+    assert!(!client.delete_file(&other).await.unwrap());
+    assert_eq!(client.get_upload_stats().await.unwrap().total_bytes, bundle.len() as u64);
+    
+    // Nothing is old enough yet; a second on, everything is
+    assert_eq!(client.cleanup_old_files(false).await.unwrap().deleted.len(), 0);
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+    let expiring = HttpFallback::new(FallbackConfig { expiry_secs: 0, ..config }).await.unwrap();
+    assert_eq!(expiring.cleanup_old_files(false).await.unwrap().deleted.len(), 1);
+    assert_eq!(client.get_upload_stats().await.unwrap().total_files, 0);
+    assert!(client.download_chunks(&url).await.is_err());
+    
+    shutdown.cancel();
+    server.await.unwrap().unwrap();
+}
+
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_builtin_fallback_server_catches_a_damaged_body_whole() {
+    use shrlink::fallback::{HttpFallback, BUNDLE_HASH_HEADER};
+    use shrlink::server::FallbackServer;
+    use shrlink::ShrLinkError;
+    use tokio_util::sync::CancellationToken;
+    
+    let dir = tempfile::tempdir().unwrap();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    let shutdown = CancellationToken::new();
+    let server = tokio::spawn(FallbackServer::new(dir.path()).unwrap().serve(listener, shutdown.clone()));
+    
+    let compressor = ParallelCompressor::default();
+    let test_data: Vec<u8> = (0..200_000u32).map(|i| (i * 13 % 251) as u8).collect();
+    let result = compressor.compress_bytes(&test_data).unwrap();
+    let client = HttpFallback::new(parallel_config(endpoint.clone(), 1)).await.unwrap();
+    let url = client.upload_chunks(&result.chunks, None).await.unwrap();
+    
+    // The hash sent with the upload comes back with the download, and is what the body hashes to
+    let response = reqwest::Client::new().get(&url).send().await.unwrap();
+    let advertised = response.headers()[BUNDLE_HASH_HEADER].to_str().unwrap().to_string();
+    assert_eq!(advertised, blake3::hash(&response.bytes().await.unwrap()).to_hex().as_str());
+    assert!(client.download_chunks(&url).await.is_ok());
+    
+    // Damaged on the server without changing its length, it fails as one clear error rather
+    // than in whichever chunk the damage landed, whether or not it's downloaded again first
+    let stored = dir.path().join("files").join(url.rsplit('/').next().unwrap());
+    let mut body = std::fs::read(&stored).unwrap();
+    let middle = body.len() / 2;
+    body[middle] ^= 0xff;
+    std::fs::write(&stored, body).unwrap();
+    for max_retries in [0, 1] {
+        let client = HttpFallback::new(shrlink::config::FallbackConfig { max_retries, ..parallel_config(endpoint.clone(), 1) }).await.unwrap();
+        match client.download_chunks(&url).await {
+            Err(e @ ShrLinkError::CorruptDownload(_)) => assert!(e.to_string().contains("corrupted or truncated, try again"), "{}", e),
+            other => panic!("expected a corrupt download, got {:?}", other.map(|chunks| chunks.len())),
+        }
+    }
+    
+    shutdown.cancel();
+    server.await.unwrap().unwrap();
+}
+
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_builtin_fallback_server_takes_uploads_in_parts() {
+    use shrlink::config::FallbackConfig;
+    use shrlink::fallback::HttpFallback;
+    use shrlink::server::FallbackServer;
+    use tokio_util::sync::CancellationToken;
+    
+    let dir = tempfile::tempdir().unwrap();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    let shutdown = CancellationToken::new();
+    let server = tokio::spawn(FallbackServer::new(&dir.path().join("served")).unwrap().serve(listener, shutdown.clone()));
+    
+    let staged = dir.path().join("bundle.shr");
+    let body: Vec<u8> = (0..60_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8).collect();
+    std::fs::write(&staged, &body).unwrap();
+    
+    for (upload_part_size, verified_uploads) in [(25_000, false), (25_000, true), (0, false)] {
+        let config = FallbackConfig { upload_part_size, verified_uploads, ..parallel_config(endpoint.clone(), 1) };
+        let client = HttpFallback::new(config).await.unwrap();
+        let url = client.upload_file(&staged, Some("parts.bin")).await.unwrap();
+        assert_eq!(url.contains("#h="), verified_uploads);
+        let (downloaded, name) = client.download_bundle(&url).await.unwrap();
+        assert_eq!(downloaded, body);
+        assert_eq!(name.as_deref(), Some("parts.bin"));
+    }
+    // Nothing is left of the parts once they're put together
+    assert_eq!(std::fs::read_dir(dir.path().join("served").join("parts")).unwrap().count(), 0);
+    
+    // Completed with other than the parts that arrived, or with a part out of range, it's refused
+    let http = reqwest::Client::new();
+    let init: serde_json::Value = http.post(format!("{}/upload/init", endpoint)).body(r#"{"name": "miscounted.bin"}"#).send().await.unwrap().json().await.unwrap();
+    let upload = format!("{}/upload/{}", endpoint, init["id"].as_str().unwrap());
+    for part in [1, 2, 3] {
+        assert!(http.put(format!("{}/part/{}", upload, part)).body("part").send().await.unwrap().status().is_success());
+    }
+    assert_eq!(http.put(format!("{}/part/10001", upload)).body("part").send().await.unwrap().status(), reqwest::StatusCode::BAD_REQUEST);
+    for parts in [0, 2, 4] {
+        let response = http.post(format!("{}/complete", upload)).body(format!(r#"{{"parts": {}}}"#, parts)).send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST, "{} parts", parts);
+    }
+    let response = http.post(format!("{}/complete", upload)).body(r#"{"parts": 3}"#).send().await.unwrap();
+    let reply: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(reply["size"], 12);
+    // Where to download it from is left for the client to put on the endpoint it used
+    assert_eq!(reply["url"], "/files/miscounted.bin");
+    
+    // Servers that don't take parts get the whole upload in one request instead
+    let whole = spawn_mock_fallback_server().await;
+    let client = HttpFallback::new(FallbackConfig { upload_part_size: 25_000, ..parallel_config(whole, 1) }).await.unwrap();
+    let url = client.upload_file(&staged, None).await.unwrap();
+    assert_eq!(client.download_bundle(&url).await.unwrap().0, body);
+    
+    shutdown.cancel();
+    server.await.unwrap().unwrap();
+}
+
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_builtin_fallback_server_honours_upload_expiry() {
+    use shrlink::fallback::HttpFallback;
+    use shrlink::server::FallbackServer;
+    use tokio_util::sync::CancellationToken;
+    
+    let dir = tempfile::tempdir().unwrap();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    let shutdown = CancellationToken::new();
+    let server = tokio::spawn(FallbackServer::new(dir.path()).unwrap().serve(listener, shutdown.clone()));
+    
+    let compressor = ParallelCompressor::default();
+    let result = compressor.compress_bytes(&[7u8; 50_000]).unwrap();
+    let upload = |upload_expiry_secs| {
+        let config = shrlink::config::FallbackConfig { upload_expiry_secs, ..parallel_config(endpoint.clone(), 1) };
+        let chunks = &result.chunks;
+        async move { HttpFallback::new(config).await.unwrap().upload_chunks(chunks, None).await.unwrap() }
+    };
+    let brief = upload(Some(1)).await;
+    let week = upload(Some(7 * 86_400)).await;
+    let unasked = upload(None).await;
+    
+    // Cleanup's own max age is an hour, which only the upload that asked for none goes by
+    tokio::time::sleep(std::time::Duration::from_millis(2100)).await;
+    let client = HttpFallback::new(parallel_config(endpoint.clone(), 1)).await.unwrap();
+    assert_eq!(client.cleanup_old_files(false).await.unwrap().deleted.len(), 1);
+    assert!(client.download_chunks(&brief).await.is_err());
+    assert!(client.download_chunks(&week).await.is_ok());
+    assert!(client.download_chunks(&unasked).await.is_ok());
+    
+    let expiring = HttpFallback::new(shrlink::config::FallbackConfig { expiry_secs: 0, ..parallel_config(endpoint, 1) }).await.unwrap();
+    assert_eq!(expiring.cleanup_old_files(false).await.unwrap().deleted.len(), 1);
+    assert!(client.download_chunks(&week).await.is_ok());
+    
+    shutdown.cancel();
+    server.await.unwrap().unwrap();
+}
+
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_builtin_fallback_server_wants_its_token() {
+    use shrlink::fallback::HttpFallback;
+    use shrlink::server::FallbackServer;
+    use shrlink::ShrLinkError;
+    use tokio_util::sync::CancellationToken;
+    
+    let compressor = ParallelCompressor::default();
+    let result = compressor.compress_bytes(&[42u8; 100_000]).unwrap();
+    for auth_downloads in [false, true] {
+        let dir = tempfile::tempdir().unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let shutdown = CancellationToken::new();
+        let server = FallbackServer::new(dir.path()).unwrap().with_token("s3cret".to_string(), auth_downloads);
+        let server = tokio::spawn(server.serve(listener, shutdown.clone()));
+        
+        let config = |auth_token: Option<&str>| shrlink::config::FallbackConfig {
+            auth_token: auth_token.map(str::to_string),
+            auth_downloads,
+            ..parallel_config(endpoint.clone(), 1)
+        };
+        let anonymous = HttpFallback::new(config(None)).await.unwrap();
+        let wrong = HttpFallback::new(config(Some("guess"))).await.unwrap();
+        let sender = HttpFallback::new(config(Some("s3cret"))).await.unwrap();
+        
+        for refused in [&anonymous, &wrong] {
+            let error = refused.upload_chunks(&result.chunks, None).await.unwrap_err();
+            assert!(matches!(error, ShrLinkError::Unauthorized(_)), "{}", error);
+            assert!(error.to_string().contains("fallback.auth_token"), "{}", error);
+            assert!(matches!(refused.get_upload_stats().await, Err(ShrLinkError::Unauthorized(_))));
+            assert!(matches!(refused.cleanup_old_files(false).await, Err(ShrLinkError::Unauthorized(_))));
+        }
+        let url = sender.upload_chunks(&result.chunks, None).await.unwrap();
+        assert_eq!(sender.get_upload_stats().await.unwrap().total_files, 1);
+        
+        // Receivers only need the token when the server guards downloads, and then only send it
+        // with auth_downloads
+        let received = HttpFallback::new(config(None)).await.unwrap().download_chunks(&url).await;
+        if auth_downloads {
+            assert!(matches!(received, Err(ShrLinkError::Unauthorized(_))));
+            assert_eq!(sender.download_chunks(&url).await.unwrap().len(), result.chunks.len());
+        } else {
+            assert_eq!(received.unwrap().len(), result.chunks.len());
+        }
+        
+        assert!(matches!(anonymous.delete_file(&url).await, Err(ShrLinkError::Unauthorized(_))));
+        assert!(sender.delete_file(&url).await.unwrap());
+        
+        shutdown.cancel();
+        server.await.unwrap().unwrap();
+    }
+}
+
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_fallback_fails_over_between_endpoints() {
+    use shrlink::config::FallbackConfig;
+    use shrlink::fallback::HttpFallback;
+    use shrlink::server::FallbackServer;
+    use std::sync::atomic::Ordering;
+    use tokio_util::sync::CancellationToken;
+    
+    let serve = |dir: &std::path::Path| {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let shutdown = CancellationToken::new();
+        let server = tokio::spawn(FallbackServer::new(dir).unwrap().serve(listener, shutdown.clone()));
+        (endpoint, shutdown, server)
+    };
+    let (primary, requests) = spawn_mock_flaky_server(&["503 Service Unavailable"; 16]).await;
+    let dir = tempfile::tempdir().unwrap();
+    let (secondary, secondary_shutdown, secondary_server) = serve(dir.path());
+    
+    let result = ParallelCompressor::default().compress_bytes(&[3u8; 60_000]).unwrap();
+    let sender = HttpFallback::new(FallbackConfig {
+        endpoints: vec![primary.clone(), secondary.clone()],
+        ..parallel_config(primary.clone(), 1)
+    }).await.unwrap();
+    let url = sender.upload_chunks(&result.chunks, None).await.unwrap();
+    assert!(url.starts_with(&secondary), "{}", url);
+    assert_eq!(sender.last_endpoint(), Some(secondary.clone()));
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+    
+    // The primary that failed sits out a while rather than being asked first for every upload
+    sender.upload_chunks(&result.chunks, Some("again.bin")).await.unwrap();
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+    
+    // A mirror serving the same files takes downloads of the secondary's URLs once it's down
+    let (mirror, mirror_shutdown, mirror_server) = serve(dir.path());
+    secondary_shutdown.cancel();
+    secondary_server.await.unwrap().unwrap();
+    let unmirrored = HttpFallback::new(FallbackConfig { max_retries: 0, ..parallel_config(secondary.clone(), 1) }).await.unwrap();
+    assert!(unmirrored.download_chunks(&url).await.is_err());
+    let mirrored = HttpFallback::new(FallbackConfig {
+        endpoints: vec![secondary, mirror.clone()],
+        ..parallel_config(primary, 1)
+    }).await.unwrap();
+    assert_eq!(mirrored.download_chunks(&url).await.unwrap().len(), result.chunks.len());
+    assert_eq!(mirrored.last_endpoint(), Some(mirror));
+    
+    mirror_shutdown.cancel();
+    mirror_server.await.unwrap().unwrap();
+}
+
+// An HTTPS server with a certificate for 127.0.0.1 from a CA of its own, answering every request
+// with the stats of a store of seven files. Returns its endpoint and the CA's certificate
+async fn spawn_mock_tls_server() -> (String, String) {
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa, SanType};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_native_tls::native_tls;
+    
+    let mut ca_params = CertificateParams::new(Vec::new());
+    ca_params.distinguished_name.push(DnType::CommonName, "shrlink test CA");
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = Certificate::from_params(ca_params).unwrap();
+    let mut params = CertificateParams::new(Vec::new());
+    params.distinguished_name.push(DnType::CommonName, "shrlink test server");
+    params.subject_alt_names = vec![SanType::IpAddress(std::net::Ipv4Addr::LOCALHOST.into())];
+    let cert = Certificate::from_params(params).unwrap();
+    let identity = native_tls::Identity::from_pkcs8(
+        cert.serialize_pem_with_signer(&ca).unwrap().as_bytes(),
+        cert.serialize_private_key_pem().as_bytes(),
+    ).unwrap();
+    let acceptor = tokio_native_tls::TlsAcceptor::from(native_tls::TlsAcceptor::new(identity).unwrap());
+    
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                // Clients that don't trust the certificate hang up during the handshake
+                let Ok(mut stream) = acceptor.accept(stream).await else { return };
+                let mut head = Vec::new();
+                let mut byte = [0u8; 1];
+                while !head.ends_with(b"\r\n\r\n") {
+                    if stream.read(&mut byte).await.unwrap_or(0) == 0 {
+                        return;
+                    }
+                    head.push(byte[0]);
+                }
+                let body = r#"{"total_files":7,"total_bytes":700}"#;
+                let reply = format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+                let _ = stream.write_all(reply.as_bytes()).await;
+                let _ = stream.shutdown().await;
+            });
+        }
+    });
+    
+    (format!("https://{}", addr), ca.serialize_pem().unwrap())
+}
+
+#[tokio::test]
+async fn test_fallback_trusts_the_configured_ca_bundle() {
+    use shrlink::config::FallbackConfig;
+    use shrlink::fallback::HttpFallback;
+    
+    let (endpoint, ca) = spawn_mock_tls_server().await;
+    let dir = tempfile::tempdir().unwrap();
+    let bundle = dir.path().join("ca.pem");
+    std::fs::write(&bundle, ca).unwrap();
+    let config = FallbackConfig { max_retries: 0, ..parallel_config(endpoint, 1) };
+    
+    // The system's roots alone don't vouch for the server; with its CA added they do
+    let untrusted = HttpFallback::new(config.clone()).await.unwrap();
+    assert!(untrusted.get_upload_stats().await.is_err());
+    let trusted = HttpFallback::new(FallbackConfig { ca_bundle_path: Some(bundle.clone()), ..config.clone() }).await.unwrap();
+    let stats = trusted.get_upload_stats().await.unwrap();
+    // Only the two totals, as servers without file listings send
+    assert_eq!((stats.total_files, stats.files), (7, None));
+    
+    // Without checking certificates at all, any server will do
+    let unchecked = HttpFallback::new(FallbackConfig { danger_accept_invalid_certs: true, ..config.clone() }).await.unwrap();
+    assert_eq!(unchecked.get_upload_stats().await.unwrap().total_bytes, 700);
+    
+    // A bundle without a certificate in it is refused before anything is sent
+    std::fs::write(&bundle, "not a certificate").unwrap();
+    assert!(HttpFallback::new(FallbackConfig { ca_bundle_path: Some(bundle), ..config.clone() }).await.is_err());
+    assert!(HttpFallback::new(FallbackConfig { ca_bundle_path: Some(dir.path().join("missing.pem")), ..config }).await.is_err());
+}
+
+#[tokio::test]
+async fn test_fallback_goes_through_the_configured_proxy() {
+    use shrlink::config::FallbackConfig;
+    use shrlink::fallback::HttpFallback;
+    use tokio::io::AsyncWriteExt;
+    
+    // A forward proxy that answers for every server itself, keeping what it was asked
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy = format!("http://shr:s3cret@{}", listener.local_addr().unwrap());
+    let heads = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let kept = heads.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (head, _) = read_http_request(&mut stream).await;
+            kept.lock().unwrap().push(head);
+            let body = r#"{"total_files":3,"total_bytes":300}"#;
+            let reply = format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+            stream.write_all(reply.as_bytes()).await.unwrap();
+        }
+    });
+    
+    // The server's name is never looked up here; only the proxy is connected to
+    let config = FallbackConfig { proxy: Some(proxy), max_retries: 0, ..parallel_config("http://fallback.example.invalid:8080".to_string(), 1) };
+    let client = HttpFallback::new(config.clone()).await.unwrap();
+    assert_eq!(client.get_upload_stats().await.unwrap().total_files, 3);
+    
+    let head = heads.lock().unwrap()[0].clone();
+    assert!(head.starts_with("GET http://fallback.example.invalid:8080/stats HTTP/1.1\r\n"), "{}", head);
+    assert!(head.to_ascii_lowercase().contains("proxy-authorization: basic c2hyonmzy3jlda=="), "{}", head);
+    
+    assert!(HttpFallback::new(FallbackConfig { proxy: Some("not a url".to_string()), ..config }).await.is_err());
+}
+
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_builtin_fallback_server_serves_once_links_once() {
+    use shrlink::fallback::HttpFallback;
+    use shrlink::server::FallbackServer;
+    use shrlink::ShrLinkError;
+    use tokio_util::sync::CancellationToken;
+    
+    let dir = tempfile::tempdir().unwrap();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    let shutdown = CancellationToken::new();
+    let server = tokio::spawn(FallbackServer::new(dir.path()).unwrap().serve(listener, shutdown.clone()));
+    
+    // More than the sockets between the two ends can hold, so the server can't have sent the
+    // last byte before an interrupted download is dropped
+    let mut data = vec![0u8; 32 * 1024 * 1024];
+    blake3::Hasher::new().update(b"once").finalize_xof().fill(&mut data);
+    let result = ParallelCompressor::default().compress_bytes(&data).unwrap();
+    let sender = HttpFallback::new(shrlink::config::FallbackConfig { download_once: true, ..parallel_config(endpoint.clone(), 1) }).await.unwrap();
+    let url = sender.upload_chunks(&result.chunks, None).await.unwrap();
+    
+    // A download cut off partway leaves the link working
+    let http = reqwest::Client::new();
+    let mut interrupted = http.get(&url).send().await.unwrap();
+    assert!(interrupted.headers().get(reqwest::header::ACCEPT_RANGES).is_none());
+    let total = interrupted.content_length().unwrap();
+    assert!(interrupted.chunk().await.unwrap().is_some());
+    drop(interrupted);
+    
+    // Of two racing downloads, only one gets the whole file; the other is cut off or turned away
+    let fetch = || async {
+        let response = http.get(&url).send().await.unwrap();
+        if response.status() != reqwest::StatusCode::OK {
+            assert_eq!(response.status(), reqwest::StatusCode::GONE);
+            return None;
+        }
+        response.bytes().await.ok()
+    };
+    let (first, second) = tokio::join!(fetch(), fetch());
+    let whole: Vec<_> = [first, second].into_iter().flatten().collect();
+    assert_eq!(whole.len(), 1, "both racing downloads finished");
+    assert_eq!(whole[0].len() as u64, total);
+    
+    // After which it's gone, to the client and to the server's totals
+    let receiver = HttpFallback::new(parallel_config(endpoint.clone(), 4)).await.unwrap();
+    let error = receiver.download_bundle(&url).await.unwrap_err();
+    assert!(matches!(error, ShrLinkError::LinkGone), "{}", error);
+    assert!(error.to_string().contains("already used"), "{}", error);
+    assert_eq!(receiver.get_upload_stats().await.unwrap().total_files, 0);
+    
+    shutdown.cancel();
+    server.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_pipelined_upload_overlaps_compression() {
+    use shrlink::fallback::HttpFallback;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    
+    const CHUNKS: usize = 6;
+    const CHUNK_SIZE: usize = 256 * 1024;
+    
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    let first_frame_seen: Arc<Mutex<Option<Instant>>> = Arc::new(Mutex::new(None));
+    
+    let server = {
+        let first_frame_seen = first_frame_seen.clone();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut raw = Vec::new();
+            let mut buf = [0u8; 16 * 1024];
+            
+            loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                raw.extend_from_slice(&buf[..n]);
+                
+                // More than one chunk's worth of body means frames are arriving
+                if raw.len() > CHUNK_SIZE && first_frame_seen.lock().unwrap().is_none() {
+                    *first_frame_seen.lock().unwrap() = Some(Instant::now());
+                }
+                
+                let header_end = raw.windows(4).position(|w| w == b"\r\n\r\n").map(|p| p + 4);
+                if let Some(body) = header_end.and_then(|end| decode_chunked_body(&raw[end..])) {
+                    stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await.unwrap();
+                    return body;
+                }
+            }
+        })
+    };
+    
+    let client = HttpFallback::new(parallel_config(endpoint, 1)).await.unwrap();
+    
+    // An artificially slow compressor: each chunk becomes ready 100 ms after the previous one
+    let last_chunk_ready: Arc<Mutex<Option<Instant>>> = Arc::new(Mutex::new(None));
+    let chunks = {
+        let last_chunk_ready = last_chunk_ready.clone();
+        futures::stream::unfold(0usize, move |index| {
+            let last_chunk_ready = last_chunk_ready.clone();
+            async move {
+                if index == CHUNKS {
+                    return None;
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                // Incompressible, so each frame is roughly CHUNK_SIZE on the wire
+                let mut data = vec![0u8; CHUNK_SIZE];
+                blake3::Hasher::new().update(&[index as u8]).finalize_xof().fill(&mut data);
+                let chunk = ParallelCompressor::default().compress_chunk(index, data).map(shrlink::bundle::BundleItem::Chunk);
+                *last_chunk_ready.lock().unwrap() = Some(Instant::now());
+                Some((chunk, index + 1))
+            }
+        })
+    };
+    
+    let uploaded = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let counter = uploaded.clone();
+    client.upload_stream(chunks, Some("pipelined.bin"), move |_| {
+        counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    }).await.unwrap();
+    
+    let body = server.await.unwrap();
+    let first_frame = first_frame_seen.lock().unwrap().unwrap();
+    let last_ready = last_chunk_ready.lock().unwrap().unwrap();
+    assert!(first_frame < last_ready, "upload only started after compression finished");
+    assert_eq!(uploaded.load(std::sync::atomic::Ordering::SeqCst), CHUNKS);
+    
+    // The streamed multipart body carries a complete, parseable bundle
+    let data_start = body.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+    let data_end = body.windows(4).rposition(|w| w == b"\r\n--").unwrap();
+    let parsed = shrlink::compression::parse_shr_bundle(&body[data_start..data_end]).unwrap();
+    assert_eq!(parsed.len(), CHUNKS);
+}
+
+#[tokio::test]
+async fn test_cancelled_upload_removes_partial_file() {
+    use shrlink::fallback::HttpFallback;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use futures::StreamExt;
+    use tokio_util::sync::CancellationToken;
+    
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    let cancel = CancellationToken::new();
+    
+    // Cancels once the upload is under way, then answers the clean-up that should follow
+    let server = {
+        let cancel = cancel.clone();
+        tokio::spawn(async move {
+            let (mut upload, _) = listener.accept().await.unwrap();
+            let mut raw = Vec::new();
+            let mut buf = [0u8; 16 * 1024];
+            while !raw.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = upload.read(&mut buf).await.unwrap();
+                raw.extend_from_slice(&buf[..n]);
+            }
+            cancel.cancel();
+            
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (head, _) = read_http_request(&mut stream).await;
+            stream.write_all(b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n").await.unwrap();
+            head.lines().next().unwrap().to_string()
+        })
+    };
+    
+    let client = HttpFallback::new(parallel_config(endpoint.clone(), 1)).await.unwrap().with_cancellation(cancel);
+    
+    // One chunk, then nothing ever again
+    let chunk = ParallelCompressor::default().compress_chunk(0, b"partial".repeat(100)).map(shrlink::bundle::BundleItem::Chunk);
+    let items = futures::stream::iter([chunk]).chain(futures::stream::pending());
+    
+    let result = tokio::time::timeout(Duration::from_secs(10), client.upload_stream(items, Some("partial.bin"), |_| {})).await.unwrap();
+    assert!(matches!(result, Err(shrlink::ShrLinkError::Cancelled)), "{:?}", result);
+    
+    let request_line = server.await.unwrap();
+    assert!(request_line.starts_with("DELETE /files/"), "{}", request_line);
+    assert!(request_line.contains("partial.bin"), "{}", request_line);
+}
+
+#[cfg(feature = "cli")]
+#[tokio::test]
+async fn test_json_log_format_flag() {
+    let endpoint = spawn_mock_fallback_server().await;
+    let dir = tempfile::tempdir().unwrap();
+    
+    let mut config = Config::default();
+    config.fallback.endpoints = vec![endpoint];
+    let config_path = dir.path().join("config.toml");
+    std::fs::write(&config_path, toml::to_string_pretty(&config).unwrap()).unwrap();
+    
+    let payload = dir.path().join("payload.bin");
+    std::fs::write(&payload, b"structured logging payload".repeat(256)).unwrap();
+    
+    let output = tokio::process::Command::new(env!("CARGO_BIN_EXE_shr"))
+        .arg("--config").arg(&config_path)
+        .args(["--log-format", "json", "-vv", "--progress", "none", "send"])
+        .env_remove("RUST_LOG")
+        .arg(&payload)
+        .arg("--force-fallback")
+        .env("HOME", dir.path())
+        .env("XDG_CACHE_HOME", dir.path().join("cache"))
+        .env("XDG_DATA_HOME", dir.path().join("data"))
+        .output()
+        .await
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let uploaded: serde_json::Value = stderr
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .find(|line| line["message"] == "Chunk uploaded")
+        .expect("no chunk upload was logged");
+    
+    assert_eq!(uploaded["level"], "DEBUG");
+    assert_eq!(uploaded["chunk_index"], 0);
+    assert_eq!(uploaded["transfer_id"].as_str().unwrap().len(), 32);
+}
+
+#[cfg(feature = "cli")]
+#[tokio::test]
+async fn test_stats_shows_and_resets_local_p2p_counts() {
+    use shrlink::p2p::{P2PStats, PeerStats, StatsStore};
+
+    let endpoint = spawn_mock_fallback_server().await;
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = Config::default();
+    config.fallback.endpoints = vec![endpoint];
+
+    let stats_path = dir.path().join("data").join("shrlink").join("stats.json");
+    let peer_id = libp2p::PeerId::random().to_string();
+    let mut stats = P2PStats { bytes_received: 4096, chunks_received: 2, transfers_completed: 2, retries: 1, ..Default::default() };
+    stats.peers.insert(peer_id.clone(), PeerStats { bytes_received: 4096, chunks_received: 2, retries: 1, ..Default::default() });
+    std::fs::create_dir_all(stats_path.parent().unwrap()).unwrap();
+    std::fs::write(&stats_path, serde_json::to_vec(&stats).unwrap()).unwrap();
+
+    // The mock server has no /stats, which leaves the local counts to stand on their own
+    let shown = run_shr(dir.path(), &config, &["stats".as_ref()]).await;
+    assert!(shown.contains("Transfers: 2 (0 failed)"), "{}", shown);
+    assert!(shown.contains("Retries: 1"), "{}", shown);
+    assert!(shown.contains(&peer_id), "{}", shown);
+    assert!(shown.contains("HTTP fallback statistics unavailable"), "{}", shown);
+
+    run_shr(dir.path(), &config, &["stats".as_ref(), "--reset".as_ref()]).await;
+    assert!(StatsStore::load(&stats_path).stats().is_empty());
+    let shown = run_shr(dir.path(), &config, &["stats".as_ref()]).await;
+    assert!(shown.contains("No P2P transfers on record"), "{}", shown);
+}
+
+#[cfg(all(feature = "cli", feature = "server"))]
+#[tokio::test]
+async fn test_delete_removes_an_upload() {
+    use shrlink::fallback::HttpFallback;
+    use shrlink::server::FallbackServer;
+    use tokio_util::sync::CancellationToken;
+    
+    let dir = tempfile::tempdir().unwrap();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    let shutdown = CancellationToken::new();
+    let server = FallbackServer::new(&dir.path().join("served")).unwrap().with_token("s3cret".to_string(), false);
+    let server = tokio::spawn(server.serve(listener, shutdown.clone()));
+    
+    let sender = HttpFallback::new(shrlink::config::FallbackConfig {
+        auth_token: Some("s3cret".to_string()),
+        ..parallel_config(endpoint.clone(), 1)
+    }).await.unwrap();
+    let result = ParallelCompressor::default().compress_bytes(b"delete me").unwrap();
+    let url = sender.upload_chunks(&result.chunks, None).await.unwrap();
+    // Whatever follows the # is no part of where the upload is
+    let shared = format!("{}#h=0123", url);
+    
+    let mut config = Config::default();
+    config.fallback.endpoints = vec![endpoint];
+    let refused = shr_output(dir.path(), &config, &["delete".as_ref(), shared.as_ref()]).await;
+    assert!(!refused.status.success());
+    assert!(String::from_utf8_lossy(&refused.stderr).contains("Unauthorized"), "{}", String::from_utf8_lossy(&refused.stderr));
+    assert_eq!(sender.get_upload_stats().await.unwrap().total_files, 1);
+    
+    let deleted = shr_output_with_env(dir.path(), &config, &["delete".as_ref(), shared.as_ref()], &[("SHRLINK_FALLBACK_TOKEN", "s3cret")]).await;
+    assert!(deleted.status.success(), "{}", String::from_utf8_lossy(&deleted.stderr));
+    assert!(String::from_utf8_lossy(&deleted.stdout).contains("Deleted"));
+    assert_eq!(sender.get_upload_stats().await.unwrap().total_files, 0);
+    assert!(sender.download_chunks(&url).await.is_err());
+    
+    // A second time there's nothing there, which isn't the same failure as being refused
+    let again = shr_output_with_env(dir.path(), &config, &["delete".as_ref(), shared.as_ref()], &[("SHRLINK_FALLBACK_TOKEN", "s3cret")]).await;
+    assert!(!again.status.success());
+    assert!(String::from_utf8_lossy(&again.stderr).contains("NotFound"), "{}", String::from_utf8_lossy(&again.stderr));
+    
+    shutdown.cancel();
+    server.await.unwrap().unwrap();
+}
+
+#[cfg(all(feature = "cli", feature = "server"))]
+#[tokio::test]
+async fn test_stats_lists_the_server_files() {
+    use shrlink::fallback::HttpFallback;
+    use shrlink::server::FallbackServer;
+    use tokio_util::sync::CancellationToken;
+    
+    let dir = tempfile::tempdir().unwrap();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    let shutdown = CancellationToken::new();
+    let server = tokio::spawn(FallbackServer::new(&dir.path().join("served")).unwrap().serve(listener, shutdown.clone()));
+    
+    let uploader = HttpFallback::new(parallel_config(endpoint.clone(), 1)).await.unwrap();
+    let result = ParallelCompressor::default().compress_bytes(b"counted").unwrap();
+    let url = uploader.upload_chunks(&result.chunks, None).await.unwrap();
+    let name = url.rsplit('/').next().unwrap().to_string();
+    uploader.download_chunks(&url).await.unwrap();
+    
+    let mut config = Config::default();
+    config.fallback.endpoints = vec![endpoint];
+    let output = run_shr(dir.path(), &config, &["stats".as_ref(), "--json".as_ref()]).await;
+    let stats: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(stats["fallback"]["backend"], "http");
+    assert_eq!(stats["fallback"]["total_files"], 1);
+    assert_eq!(stats["fallback"]["files"][0]["name"], name.as_str());
+    assert_eq!(stats["fallback"]["files"][0]["downloads"], 1);
+    assert!(stats["p2p"].is_object(), "{}", output);
+    
+    let table = run_shr(dir.path(), &config, &["stats".as_ref(), "--detailed".as_ref()]).await;
+    assert!(table.contains("DOWNLOADS") && table.contains(&name), "{}", table);
+    
+    shutdown.cancel();
+    server.await.unwrap().unwrap();
+}
+
+#[cfg(all(feature = "cli", feature = "server"))]
+#[tokio::test]
+async fn test_cleanup_dry_run_and_age_filter() {
+    use shrlink::config::FallbackConfig;
+    use shrlink::fallback::HttpFallback;
+    use shrlink::server::FallbackServer;
+    use tokio_util::sync::CancellationToken;
+    
+    let dir = tempfile::tempdir().unwrap();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    let shutdown = CancellationToken::new();
+    let server = tokio::spawn(FallbackServer::new(&dir.path().join("served")).unwrap().serve(listener, shutdown.clone()));
+    
+    let uploader = HttpFallback::new(parallel_config(endpoint.clone(), 1)).await.unwrap();
+    let result = ParallelCompressor::default().compress_bytes(b"old enough to go").unwrap();
+    let url = uploader.upload_chunks(&result.chunks, None).await.unwrap();
+    let name = url.rsplit('/').next().unwrap().to_string();
+    tokio::time::sleep(std::time::Duration::from_millis(2100)).await;
+    
+    // A dry run names what would go, and leaves it
+    let expiring = HttpFallback::new(FallbackConfig { expiry_secs: 0, ..parallel_config(endpoint.clone(), 1) }).await.unwrap();
+    let report = expiring.cleanup_old_files(true).await.unwrap();
+    assert_eq!(report.deleted.len(), 1);
+    assert_eq!(report.deleted[0].name, name);
+    assert!(report.deleted[0].age_secs >= 2);
+    assert!(report.bytes_freed > 0 && report.bytes_freed == report.deleted[0].size);
+    assert!(uploader.download_chunks(&url).await.is_ok());
+    
+    let mut config = Config::default();
+    config.fallback.endpoints = vec![endpoint];
+    config.fallback.expiry_secs = 0;
+    let listed = run_shr(dir.path(), &config, &["cleanup".as_ref(), "--dry-run".as_ref()]).await;
+    assert!(listed.contains(&name) && listed.contains("Would delete 1 old files"), "{}", listed);
+    assert_eq!(uploader.get_upload_stats().await.unwrap().total_files, 1);
+    
+    // --older-than stands in for the configured expiry, this once: an hour keeps the upload,
+    // a second doesn't
+    let kept = run_shr(dir.path(), &config, &["cleanup".as_ref(), "--older-than".as_ref(), "1h".as_ref()]).await;
+    assert!(kept.contains("Deleted 0 old files"), "{}", kept);
+    assert!(uploader.download_chunks(&url).await.is_ok());
+    config.fallback.expiry_secs = 86_400;
+    let deleted = run_shr(dir.path(), &config, &["cleanup".as_ref(), "--older-than".as_ref(), "1s".as_ref()]).await;
+    assert!(deleted.contains(&name) && deleted.contains("Deleted 1 old files"), "{}", deleted);
+    assert!(uploader.download_chunks(&url).await.is_err());
+    
+    shutdown.cancel();
+    server.await.unwrap().unwrap();
+}
+
+#[cfg(feature = "cli")]
+async fn shr_output(dir: &std::path::Path, config: &Config, args: &[&std::ffi::OsStr]) -> std::process::Output {
+    shr_output_with_env(dir, config, args, &[]).await
+}
+
+#[cfg(feature = "cli")]
+async fn shr_output_with_env(dir: &std::path::Path, config: &Config, args: &[&std::ffi::OsStr], env: &[(&str, &str)]) -> std::process::Output {
+    let config_path = dir.join(format!("config-{}.toml", config.compression.algorithm));
+    std::fs::write(&config_path, toml::to_string_pretty(config).unwrap()).unwrap();
+    
+    tokio::process::Command::new(env!("CARGO_BIN_EXE_shr"))
+        .arg("--config").arg(&config_path)
+        .args(["--progress", "none"])
+        .args(args)
+        .current_dir(dir)
+        .env("HOME", dir)
+        .env("XDG_CACHE_HOME", dir.join("cache"))
+        .env("XDG_DATA_HOME", dir.join("data"))
+        .envs(env.iter().copied())
+        .output()
+        .await
+        .unwrap()
+}
+
+#[cfg(feature = "cli")]
+async fn run_shr(dir: &std::path::Path, config: &Config, args: &[&std::ffi::OsStr]) -> String {
+    let output = shr_output(dir, config, args).await;
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+// The receiver's own algorithm setting must not matter: chunks say how they were compressed
+#[cfg(feature = "cli")]
+#[tokio::test]
+async fn test_zstd_send_lz4_recv() {
+    let endpoint = spawn_mock_fallback_server().await;
+    let dir = tempfile::tempdir().unwrap();
+    
+    let mut sender = Config::default();
+    sender.fallback.endpoints = vec![endpoint];
+    sender.compression.algorithm = "zstd".to_string();
+    let mut receiver = sender.clone();
+    receiver.compression.algorithm = "lz4".to_string();
+    
+    let payload = dir.path().join("payload.bin");
+    let data = b"zstd across the wire ".repeat(4096);
+    std::fs::write(&payload, &data).unwrap();
+    
+    let stdout = run_shr(dir.path(), &sender, &["send".as_ref(), payload.as_os_str(), "--force-fallback".as_ref()]).await;
+    let url = stdout.lines().map(str::trim).find(|l| l.starts_with("http://")).expect("no share URL printed");
+    
+    let client = shrlink::fallback::HttpFallback::new(receiver.fallback.clone()).await.unwrap();
+    let chunks = client.download_chunks(url).await.unwrap();
+    assert!(chunks.iter().all(|c| c.algorithm == shrlink::compression::CompressionAlgorithm::Zstd));
+    
+    let received = dir.path().join("received.bin");
+    run_shr(dir.path(), &receiver, &["recv".as_ref(), url.as_ref(), "--output".as_ref(), received.as_os_str()]).await;
+    assert_eq!(std::fs::read(&received).unwrap(), data);
+}
+
+// A second version of a file only downloads what changed since the first
+#[cfg(feature = "cli")]
+#[tokio::test]
+async fn test_recv_reuses_cached_chunks() {
+    use shrlink::cache::ChunkCache;
+    use shrlink::fallback::{CachedDownload, HttpFallback};
+    
+    const BLOCK: usize = 1024 * 1024;
+    let endpoint = spawn_mock_fallback_server().await;
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = Config::default();
+    config.fallback.endpoints = vec![endpoint];
+    config.compression.block_size = BlockSize::Fixed(BLOCK);
+    let cache_dir = dir.path().join("chunk-cache");
+    
+    let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+    let v1: Vec<u8> = (0..8 * BLOCK)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect();
+    let mut v2 = v1.clone();
+    v2[3 * BLOCK + 100..3 * BLOCK + 200].fill(0xAB);
+    
+    let mut urls = Vec::new();
+    for (version, data) in [&v1, &v2].into_iter().enumerate() {
+        let payload = dir.path().join(format!("v{}.bin", version + 1));
+        std::fs::write(&payload, data).unwrap();
+        let stdout = run_shr(dir.path(), &config, &["send".as_ref(), payload.as_os_str(), "--force-fallback".as_ref()]).await;
+        urls.push(stdout.lines().map(str::trim).find(|l| l.starts_with("http://")).expect("no share URL printed").to_string());
+    }
+    
+    let recv = |url: &str, output: &std::path::Path| {
+        let args = ["recv".into(), url.into(), "--output".into(), output.as_os_str().to_owned(), "--cache-dir".into(), cache_dir.as_os_str().to_owned()];
+        let (dir, config) = (dir.path().to_path_buf(), config.clone());
+        async move {
+            let args: Vec<&std::ffi::OsStr> = args.iter().map(|a: &std::ffi::OsString| a.as_os_str()).collect();
+            run_shr(&dir, &config, &args).await
+        }
+    };
+    
+    let first = dir.path().join("first.bin");
+    let stdout = recv(&urls[0], &first).await;
+    assert!(!stdout.contains("Reused"), "{}", stdout);
+    assert_eq!(std::fs::read(&first).unwrap(), v1);
+    
+    // Seven of the eight chunks are in the cache now
+    let second = dir.path().join("second.bin");
+    let stdout = recv(&urls[1], &second).await;
+    assert!(stdout.contains("Reused 7 cached chunks"), "{}", stdout);
+    assert_eq!(std::fs::read(&second).unwrap(), v2);
+    
+    let client = HttpFallback::new(config.fallback.clone()).await.unwrap().with_cache(ChunkCache::open(&cache_dir).unwrap());
+    let CachedDownload::Bundle { bundle, chunks_reused, bytes_skipped, .. } = client.download_bundle_cached(&urls[1]).await.unwrap() else {
+        panic!("a plain bundle came back raw");
+    };
+    assert_eq!(chunks_reused, 8);
+    assert!(bytes_skipped > 7 * BLOCK as u64, "only {} bytes were skipped", bytes_skipped);
+    let compressor = ParallelCompressor::default();
+    assert_eq!(compressor.decompress_chunks_parallel(&bundle.into_single_file().unwrap()).unwrap().concat(), v2);
+    
+    let (chunks, _) = client.download_chunks_named(&urls[0]).await.unwrap();
+    assert_eq!(compressor.decompress_chunks_parallel(&chunks).unwrap().concat(), v1);
+}
+
+// A receive over HTTP that was cut off carries on from the byte it got to, and won't pick up
+// what was kept for another file
+#[cfg(feature = "cli")]
+#[tokio::test]
+async fn test_recv_resumes_over_http() {
+    use shrlink::partial::PartialDownload;
+    
+    let endpoint = spawn_mock_fallback_server().await;
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = Config::default();
+    config.fallback.endpoints = vec![endpoint];
+    config.compression.block_size = BlockSize::Fixed(64 * 1024);
+    
+    let data: Vec<u8> = (0..1_000_000u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+    let payload = dir.path().join("payload.bin");
+    std::fs::write(&payload, &data).unwrap();
+    let stdout = run_shr(dir.path(), &config, &["send".as_ref(), payload.as_os_str(), "--force-fallback".as_ref()]).await;
+    let url = stdout.lines().map(str::trim).find(|l| l.starts_with("http://")).expect("no share URL printed").to_string();
+    
+    // What the first receive got of the body before it was killed
+    let (base, root) = shrlink::verify::split_url_hash(&url).unwrap();
+    let file_hash = match root {
+        Some(root) => *root.as_bytes(),
+        None => *blake3::hash(base.as_bytes()).as_bytes(),
+    };
+    let body = reqwest::get(base).await.unwrap().bytes().await.unwrap();
+    let output = dir.path().join("received.bin");
+    let partial_path = PartialDownload::path_for(&output);
+    let mut partial = PartialDownload::create(&partial_path, file_hash, 0).unwrap();
+    partial.append(&body[..body.len() * 3 / 5]).unwrap();
+    drop(partial);
+    
+    let args: [&std::ffi::OsStr; 5] = ["recv".as_ref(), url.as_ref(), "--resume".as_ref(), "-o".as_ref(), output.as_os_str()];
+    let stdout = run_shr(dir.path(), &config, &args).await;
+    assert!(stdout.contains("Resuming with"), "{}", stdout);
+    assert_eq!(std::fs::read(&output).unwrap(), data);
+    assert!(!partial_path.exists());
+    
+    // Left by a receive of something else
+    PartialDownload::create(&partial_path, [7; 32], 0).unwrap().append(b"SHR").unwrap();
+    let output = shr_output(dir.path(), &config, &args).await;
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("is a partial download of"), "{}", stderr);
+}
+
+// A mostly empty disk image goes over the wire as little more than its size, and comes back
+// with its holes where the filesystem supports them
+#[cfg(all(feature = "cli", unix))]
+#[tokio::test]
+async fn test_sparse_file_send_recv() {
+    use std::io::Read;
+    use std::os::unix::fs::{FileExt, MetadataExt};
+
+    let endpoint = spawn_mock_fallback_server().await;
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = Config::default();
+    config.fallback.endpoints = vec![endpoint];
+    let client = shrlink::fallback::HttpFallback::new(config.fallback.clone()).await.unwrap();
+
+    let size: u64 = 1 << 30;
+    let payload = dir.path().join("disk.img");
+    let file = std::fs::File::create(&payload).unwrap();
+    file.set_len(size).unwrap();
+    file.write_all_at(b"boot sector", 0).unwrap();
+    file.write_all_at(b"superblock", 600 << 20).unwrap();
+    drop(file);
+
+    let stdout = run_shr(dir.path(), &config, &["send".as_ref(), payload.as_os_str(), "--force-fallback".as_ref()]).await;
+    let url = stdout.lines().map(str::trim).find(|l| l.starts_with("http://")).expect("no share URL printed");
+    let (stored, _) = client.download_bundle(url).await.unwrap();
+    assert!(stored.len() < 64 * 1024, "bundle is {} bytes", stored.len());
+
+    let received = dir.path().join("received.img");
+    run_shr(dir.path(), &config, &["recv".as_ref(), url.as_ref(), "--output".as_ref(), received.as_os_str()]).await;
+    assert_eq!(std::fs::metadata(&received).unwrap().len(), size);
+
+    let (mut a, mut b) = (std::fs::File::open(&payload).unwrap(), std::fs::File::open(&received).unwrap());
+    let (mut block_a, mut block_b) = (vec![0u8; 4 << 20], vec![0u8; 4 << 20]);
+    for _ in 0..size / block_a.len() as u64 {
+        a.read_exact(&mut block_a).unwrap();
+        b.read_exact(&mut block_b).unwrap();
+        assert!(block_a == block_b);
+    }
+
+    let allocated = |path: &std::path::Path| std::fs::metadata(path).unwrap().blocks() * 512;
+    if allocated(&payload) < 16 << 20 {
+        assert!(allocated(&received) < 16 << 20, "{} bytes allocated", allocated(&received));
+    }
+}
+
+// The encrypted bundle is built from the compression stream; a multi-chunk file checks the
+// frames and age segments line up across chunk boundaries
+#[cfg(feature = "cli")]
+#[tokio::test]
+async fn test_age_send_recv_roundtrip() {
+    let endpoint = spawn_mock_fallback_server().await;
+    let dir = tempfile::tempdir().unwrap();
+    
+    let mut config = Config::default();
+    config.fallback.endpoints = vec![endpoint];
+    config.compression.block_size = BlockSize::Fixed(64 * 1024);
+    
+    let identity = age::x25519::Identity::generate();
+    let key_path = dir.path().join("key.txt");
+    std::fs::write(&key_path, age::secrecy::ExposeSecret::expose_secret(&identity.to_string())).unwrap();
+    let recipient = identity.to_public().to_string();
+    
+    let payload = dir.path().join("secret.bin");
+    let data: Vec<u8> = (0..300_000u32).map(|i| (i % 253) as u8).collect();
+    std::fs::write(&payload, &data).unwrap();
+    
+    let stdout = run_shr(dir.path(), &config, &["send".as_ref(), payload.as_os_str(), "--encrypt-to".as_ref(), recipient.as_ref()]).await;
+    assert!(stdout.contains("Compressed to 5 chunks"), "{}", stdout);
+    let url = stdout.lines().map(str::trim).find(|l| l.starts_with("http://")).expect("no share URL printed");
+    
+    let received = dir.path().join("received.bin");
+    run_shr(dir.path(), &config, &["recv".as_ref(), url.as_ref(), "--identity".as_ref(), key_path.as_os_str(), "--output".as_ref(), received.as_os_str()]).await;
+    assert_eq!(std::fs::read(&received).unwrap(), data);
+}
+
+// Without --output the receiver names the file after the bundle's metadata, not the URL, and
+// puts back the sender's permissions and modification time
+#[cfg(all(feature = "cli", unix))]
+#[tokio::test]
+async fn test_recv_restores_embedded_file_meta() {
+    use std::os::unix::fs::PermissionsExt;
+    
+    let endpoint = spawn_mock_fallback_server().await;
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = Config::default();
+    config.fallback.endpoints = vec![endpoint];
+    
+    let outbox = dir.path().join("outbox");
+    std::fs::create_dir(&outbox).unwrap();
+    let payload = outbox.join("résumé ✓.sh");
+    let data = b"#!/bin/sh\necho bonjour\n".repeat(100);
+    std::fs::write(&payload, &data).unwrap();
+    std::fs::set_permissions(&payload, std::fs::Permissions::from_mode(0o741)).unwrap();
+    let last_week = std::time::SystemTime::now() - std::time::Duration::from_secs(7 * 24 * 60 * 60);
+    std::fs::File::options().write(true).open(&payload).unwrap().set_modified(last_week).unwrap();
+    
+    let stdout = run_shr(dir.path(), &config, &["send".as_ref(), payload.as_os_str(), "--force-fallback".as_ref()]).await;
+    let url = stdout.lines().map(str::trim).find(|l| l.starts_with("http://")).expect("no share URL printed");
+    run_shr(dir.path(), &config, &["recv".as_ref(), url.as_ref()]).await;
+    
+    let received = dir.path().join("résumé ✓.sh");
+    assert_eq!(std::fs::read(&received).unwrap(), data);
+    let metadata = std::fs::metadata(&received).unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o741);
+    let modified = |m: &std::fs::Metadata| m.modified().unwrap().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+    assert_eq!(modified(&metadata), modified(&std::fs::metadata(&payload).unwrap()));
+}
+
+#[cfg(feature = "cli")]
+#[tokio::test]
+async fn test_multi_file_send_recv() {
+    let endpoint = spawn_mock_fallback_server().await;
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = Config::default();
+    config.fallback.endpoints = vec![endpoint];
+    config.compression.block_size = BlockSize::Fixed(64 * 1024);
+    
+    let outbox = dir.path().join("outbox");
+    std::fs::create_dir(&outbox).unwrap();
+    let files: Vec<(std::path::PathBuf, Vec<u8>)> = vec![
+        (outbox.join("notes.txt"), b"meeting notes ".repeat(2000)),
+        (outbox.join("diagram.png"), (0..200_000u32).map(|i| (i % 251) as u8).collect()),
+        (outbox.join("empty.pdf"), Vec::new()),
+    ];
+    for (path, data) in &files {
+        std::fs::write(path, data).unwrap();
+    }
+    
+    let mut args: Vec<&std::ffi::OsStr> = vec!["send".as_ref(), "--force-fallback".as_ref()];
+    args.extend(files.iter().map(|(path, _)| path.as_os_str()));
+    let stdout = run_shr(dir.path(), &config, &args).await;
+    assert!(stdout.contains("Compressing 3 files"), "{}", stdout);
+    assert!(stdout.contains("notes.txt: 28000 → "), "{}", stdout);
+    assert!(stdout.contains("empty.pdf: 0 → 0 bytes"), "{}", stdout);
+    let url = stdout.lines().map(str::trim).find(|l| l.starts_with("http://")).expect("no share URL printed");
+    
+    let inbox = dir.path().join("inbox");
+    run_shr(dir.path(), &config, &["recv".as_ref(), url.as_ref(), "--output".as_ref(), inbox.as_os_str()]).await;
+    for (path, data) in &files {
+        assert_eq!(&std::fs::read(inbox.join(path.file_name().unwrap())).unwrap(), data);
+    }
+    
+    // Same names from different directories would collide on the receiving side
+    let other = dir.path().join("notes.txt");
+    std::fs::write(&other, b"other notes").unwrap();
+    let output = shr_output(dir.path(), &config, &["send".as_ref(), "--force-fallback".as_ref(), files[0].0.as_os_str(), other.as_os_str()]).await;
+    assert!(!output.status.success());
+}
+
+#[cfg(feature = "cli")]
+#[tokio::test]
+async fn test_recv_refuses_escaping_archive_entries() {
+    use shrlink::bundle::{BundleEntry, FileMeta};
+    
+    let endpoint = spawn_mock_fallback_server().await;
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = Config::default();
+    config.fallback.endpoints = vec![endpoint];
+    let client = shrlink::fallback::HttpFallback::new(config.fallback.clone()).await.unwrap();
+    
+    let chunk = ParallelCompressor::default().compress_chunk(0, b"payload".to_vec()).unwrap();
+    let inbox = dir.path().join("inbox");
+    for name in ["../escaped.txt", "/tmp/absolute.txt"] {
+        let entries = vec![
+            BundleEntry { meta: Some(FileMeta { name: Some("fine.txt".to_string()), size: 7, ..Default::default() }), chunks: vec![chunk.clone()], file_hash: None },
+            BundleEntry { meta: Some(FileMeta { name: Some(name.to_string()), size: 7, ..Default::default() }), chunks: vec![chunk.clone()], file_hash: None },
+        ];
+        let url = client.upload_bundle(&shrlink::bundle::create_shr_archive(&entries).unwrap(), None).await.unwrap();
+        
+        let output = shr_output(dir.path(), &config, &["recv".as_ref(), url.as_ref(), "--output".as_ref(), inbox.as_os_str()]).await;
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains("unsafe path"), "{}", String::from_utf8_lossy(&output.stderr));
+        // Nothing is written, not even the entries that were fine
+        assert!(!inbox.exists());
+        assert!(!dir.path().join("escaped.txt").exists());
+    }
+    
+    // Nor through a symlink already sitting in the output directory
+    #[cfg(unix)]
+    {
+        std::fs::create_dir(&inbox).unwrap();
+        std::os::unix::fs::symlink(dir.path(), inbox.join("link")).unwrap();
+        let entries = vec![
+            BundleEntry { meta: Some(FileMeta { name: Some("link/escaped.txt".to_string()), size: 7, ..Default::default() }), chunks: vec![chunk.clone()], file_hash: None },
+            BundleEntry { meta: Some(FileMeta { name: Some("fine.txt".to_string()), size: 7, ..Default::default() }), chunks: vec![chunk], file_hash: None },
+        ];
+        let url = client.upload_bundle(&shrlink::bundle::create_shr_archive(&entries).unwrap(), None).await.unwrap();
+        
+        let output = shr_output(dir.path(), &config, &["recv".as_ref(), url.as_ref(), "--output".as_ref(), inbox.as_os_str()]).await;
+        assert!(!output.status.success());
+        assert!(!dir.path().join("escaped.txt").exists());
+    }
+}
+
+// Each chunk can verify on its own while the file they add up to is still not the one sent
+#[cfg(feature = "cli")]
+#[tokio::test]
+async fn test_recv_checks_whole_file_hash() {
+    use shrlink::bundle::{BundleEntry, FileMeta};
+    
+    let endpoint = spawn_mock_fallback_server().await;
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = Config::default();
+    config.fallback.endpoints = vec![endpoint];
+    config.compression.block_size = BlockSize::Fixed(4096);
+    let client = shrlink::fallback::HttpFallback::new(config.fallback.clone()).await.unwrap();
+    
+    let payload = dir.path().join("ledger.csv");
+    let data = b"date,amount\n2026-01-01,100\n".repeat(1000);
+    std::fs::write(&payload, &data).unwrap();
+    let stdout = run_shr(dir.path(), &config, &["send".as_ref(), payload.as_os_str(), "--force-fallback".as_ref()]).await;
+    let url = stdout.lines().map(str::trim).find(|l| l.starts_with("http://")).expect("no share URL printed");
+    
+    let (stored, _) = client.download_bundle(url).await.unwrap();
+    let mut sent = shrlink::bundle::parse_bundle(&stored).unwrap();
+    assert_eq!(sent.entries[0].file_hash, Some(*blake3::hash(&data).as_bytes()));
+    
+    // The same chunks, claimed to make up a file with a different hash
+    let entry = sent.entries.pop().unwrap();
+    let forged = BundleEntry { file_hash: Some(*blake3::hash(b"something else").as_bytes()), ..entry };
+    let meta = FileMeta { name: Some("forged.csv".to_string()), ..forged.meta.clone().unwrap() };
+    let forged_url = client.upload_bundle(&shrlink::bundle::create_shr_archive(&[BundleEntry { meta: Some(meta), ..forged }]).unwrap(), None).await.unwrap();
+    
+    let output = shr_output(dir.path(), &config, &["recv".as_ref(), forged_url.as_ref()]).await;
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("HashMismatch") && stderr.contains("forged.csv"), "{}", stderr);
+    assert!(!dir.path().join("forged.csv").exists());
+}
+
+#[cfg(feature = "cli")]
+#[tokio::test]
+async fn test_auto_block_size_is_recorded_and_checked() {
+    use shrlink::bundle::{BundleEntry, FileMeta};
+    use shrlink::compression::AUTO_MIN_BLOCK_SIZE;
+    
+    let endpoint = spawn_mock_fallback_server().await;
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = Config::default();
+    config.fallback.endpoints = vec![endpoint];
+    config.compression.block_size = BlockSize::Auto;
+    let client = shrlink::fallback::HttpFallback::new(config.fallback.clone()).await.unwrap();
+    
+    let payload = dir.path().join("small.bin");
+    let data: Vec<u8> = (0..3 * AUTO_MIN_BLOCK_SIZE + 5).map(|i| (i * 7 % 253) as u8).collect();
+    std::fs::write(&payload, &data).unwrap();
+    let stdout = run_shr(dir.path(), &config, &["send".as_ref(), payload.as_os_str(), "--force-fallback".as_ref()]).await;
+    let url = stdout.lines().map(str::trim).find(|l| l.starts_with("http://")).expect("no share URL printed");
+    
+    // Small files get the smallest automatic size
+    let (stored, _) = client.download_bundle(url).await.unwrap();
+    let sent = shrlink::bundle::parse_bundle(&stored).unwrap();
+    let meta = sent.entries[0].meta.clone().unwrap();
+    assert_eq!(meta.block_size, Some(AUTO_MIN_BLOCK_SIZE as u64));
+    assert_eq!(sent.entries[0].chunks.len(), 4);
+    
+    // The same bytes cut smaller than the metadata says
+    let result = ParallelCompressor::new(AUTO_MIN_BLOCK_SIZE / 2, 1).unwrap().compress_bytes(&data).unwrap();
+    let meta = FileMeta { name: Some("recut.bin".to_string()), ..meta };
+    let entry = BundleEntry { meta: Some(meta), chunks: result.chunks, file_hash: Some(result.file_hash) };
+    let recut_url = client.upload_bundle(&shrlink::bundle::create_shr_archive(&[entry]).unwrap(), None).await.unwrap();
+    
+    let output = shr_output(dir.path(), &config, &["recv".as_ref(), recut_url.as_ref()]).await;
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("chunks are 1048576 bytes"));
+    assert!(!dir.path().join("recut.bin").exists());
+}
+
+#[cfg(all(feature = "cli", unix))]
+#[tokio::test]
+async fn test_directory_send_recv() {
+    use std::os::unix::fs::PermissionsExt;
+    
+    let endpoint = spawn_mock_fallback_server().await;
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = Config::default();
+    config.fallback.endpoints = vec![endpoint];
+    
+    let project = dir.path().join("outbox/my-project");
+    std::fs::create_dir_all(project.join("bin")).unwrap();
+    std::fs::create_dir_all(project.join("empty")).unwrap();
+    std::fs::create_dir_all(project.join("logs")).unwrap();
+    std::fs::write(project.join("README.md"), b"# my project\n").unwrap();
+    std::fs::write(project.join("bin/run.sh"), b"#!/bin/sh\n").unwrap();
+    std::fs::set_permissions(project.join("bin/run.sh"), std::fs::Permissions::from_mode(0o755)).unwrap();
+    std::fs::set_permissions(project.join("empty"), std::fs::Permissions::from_mode(0o700)).unwrap();
+    std::fs::write(project.join("logs/app.log"), b"noise").unwrap();
+    std::os::unix::fs::symlink("README.md", project.join("docs.md")).unwrap();
+    let _socket = std::os::unix::net::UnixListener::bind(project.join("agent.sock")).unwrap();
+    
+    for follow in [false, true] {
+        let mut args: Vec<&std::ffi::OsStr> = vec!["send".as_ref(), "--force-fallback".as_ref(), "--exclude".as_ref(), "*.log".as_ref()];
+        if follow {
+            args.push("--follow-symlinks".as_ref());
+        }
+        args.push(project.as_os_str());
+        let stdout = run_shr(dir.path(), &config, &args).await;
+        let url = stdout.lines().map(str::trim).find(|l| l.starts_with("http://")).expect("no share URL printed");
+        
+        let inbox = dir.path().join(format!("inbox-{}", follow));
+        run_shr(dir.path(), &config, &["recv".as_ref(), url.as_ref(), "--output".as_ref(), inbox.as_os_str()]).await;
+        let received = inbox.join("my-project");
+        
+        assert_eq!(std::fs::read(received.join("README.md")).unwrap(), b"# my project\n");
+        let mode = |path: &std::path::Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&received.join("bin/run.sh")), 0o755);
+        assert_eq!(mode(&received.join("empty")), 0o700);
+        assert!(std::fs::read_dir(received.join("empty")).unwrap().next().is_none());
+        assert!(received.join("logs").is_dir());
+        assert!(!received.join("logs/app.log").exists());
+        assert!(!received.join("agent.sock").exists());
+        
+        let docs = std::fs::symlink_metadata(received.join("docs.md")).unwrap();
+        if follow {
+            assert!(docs.is_file());
+        } else {
+            assert_eq!(std::fs::read_link(received.join("docs.md")).unwrap(), std::path::Path::new("README.md"));
+        }
+        assert_eq!(std::fs::read(received.join("docs.md")).unwrap(), b"# my project\n");
+    }
+}
+
+// Each small file is a chunk of its own, which without a dictionary starts from nothing
+#[cfg(feature = "cli")]
+#[tokio::test]
+async fn test_dictionary_send_recv() {
+    let endpoint = spawn_mock_fallback_server().await;
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = Config::default();
+    config.fallback.endpoints = vec![endpoint];
+    config.compression.algorithm = "zstd".to_string();
+    let client = shrlink::fallback::HttpFallback::new(config.fallback.clone()).await.unwrap();
+    
+    let exports = dir.path().join("exports");
+    std::fs::create_dir_all(&exports).unwrap();
+    let record = |i: usize| {
+        format!(
+            "{{\n  \"id\": {},\n  \"customer\": \"customer-{:04}\",\n  \"email\": \"c{}@example.com\",\n  \"status\": \"{}\",\n  \"currency\": \"EUR\",\n  \"items\": [{{\"sku\": \"SKU-{}\", \"quantity\": {}, \"unit_price\": {}.99}}],\n  \"shipping\": {{\"method\": \"standard\", \"country\": \"DE\"}}\n}}\n",
+            i,
+            i * 37 % 10_000,
+            i,
+            ["pending", "shipped", "delivered"][i % 3],
+            i * 13 % 500,
+            1 + i % 5,
+            i % 90
+        )
+    };
+    for i in 0..500 {
+        std::fs::write(exports.join(format!("order-{:03}.json", i)), record(i)).unwrap();
+    }
+    
+    let mut sizes = Vec::new();
+    for dict in [false, true] {
+        let mut args: Vec<&std::ffi::OsStr> = vec!["send".as_ref(), "--force-fallback".as_ref()];
+        if dict {
+            args.push("--dict".as_ref());
+        }
+        args.push(exports.as_os_str());
+        let stdout = run_shr(dir.path(), &config, &args).await;
+        let url = stdout.lines().map(str::trim).find(|l| l.starts_with("http://")).expect("no share URL printed").to_string();
+        
+        let (stored, _) = client.download_bundle(&url).await.unwrap();
+        let bundle = shrlink::bundle::parse_bundle(&stored).unwrap();
+        assert_eq!(bundle.dictionary.is_some(), dict);
+        let dictionary = bundle.dictionary.as_ref().map_or(0, |d| d.as_bytes().len());
+        sizes.push(dictionary + bundle.entries.iter().flat_map(|e| &e.chunks).map(|c| c.data.len()).sum::<usize>());
+        
+        let inbox = dir.path().join(format!("inbox-{}", dict));
+        run_shr(dir.path(), &config, &["recv".as_ref(), url.as_ref(), "--output".as_ref(), inbox.as_os_str()]).await;
+        for i in [0, 123, 499] {
+            let name = format!("exports/order-{:03}.json", i);
+            assert_eq!(std::fs::read_to_string(inbox.join(name)).unwrap(), record(i));
+        }
+    }
+    
+    // Dictionary included, well under half
+    let (plain, trained) = (sizes[0], sizes[1]);
+    assert!(trained * 2 < plain, "{} bytes with a dictionary, {} without", trained, plain);
+}
+
+#[cfg(feature = "cli")]
+#[tokio::test]
+async fn test_gzip_and_snappy_send_recv() {
+    use shrlink::compression::CompressionAlgorithm;
+    
+    let endpoint = spawn_mock_fallback_server().await;
+    let dir = tempfile::tempdir().unwrap();
+    let mut receiver = Config::default();
+    receiver.fallback.endpoints = vec![endpoint];
+    let client = shrlink::fallback::HttpFallback::new(receiver.fallback.clone()).await.unwrap();
+    
+    let input = dir.path().join("access.log");
+    let log: String = (0..20_000).map(|i| format!("10.0.{}.{} - - \"GET /assets/app-{}.js HTTP/1.1\" 200 {}\n", i % 7, i % 250, i % 40, 1000 + i % 900)).collect();
+    std::fs::write(&input, &log).unwrap();
+    
+    // One picked on the command line, one from the sender's config; the receiver has neither
+    let mut snappy = receiver.clone();
+    snappy.compression.algorithm = "snappy".to_string();
+    let sends: [(&Config, &[&str], CompressionAlgorithm); 2] = [
+        (&receiver, &["--algorithm", "gzip"], CompressionAlgorithm::Gzip),
+        (&snappy, &[], CompressionAlgorithm::Snappy),
+    ];
+    for (sender, flags, algorithm) in sends {
+        let mut args: Vec<&std::ffi::OsStr> = vec!["send".as_ref(), "--force-fallback".as_ref()];
+        args.extend(flags.iter().map(std::ffi::OsStr::new));
+        args.push(input.as_os_str());
+        let stdout = run_shr(dir.path(), sender, &args).await;
+        let url = stdout.lines().map(str::trim).find(|l| l.starts_with("http://")).expect("no share URL printed").to_string();
+        
+        let (stored, _) = client.download_bundle(&url).await.unwrap();
+        let bundle = shrlink::bundle::parse_bundle(&stored).unwrap();
+        let chunks = &bundle.entries[0].chunks;
+        assert!(chunks.iter().all(|c| c.algorithm == algorithm), "{}", algorithm);
+        if algorithm == CompressionAlgorithm::Gzip {
+            // Each chunk is a gzip file of its own
+            let mut out = Vec::new();
+            shrlink::compression::gzip::decompress(&chunks[0].data, chunks[0].original_size, &mut out).unwrap();
+            assert_eq!(out, log.as_bytes()[..chunks[0].original_size]);
+        }
+        
+        let output = dir.path().join(format!("received-{}.log", algorithm));
+        run_shr(dir.path(), &receiver, &["recv".as_ref(), url.as_ref(), "--output".as_ref(), output.as_os_str()]).await;
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), log);
+    }
+}
+
+#[cfg(feature = "cli")]
+#[tokio::test]
+async fn test_empty_tiny_and_block_sized_files_send_recv() {
+    let endpoint = spawn_mock_fallback_server().await;
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = Config::default();
+    config.fallback.endpoints = vec![endpoint];
+    config.compression.block_size = BlockSize::Fixed(64 * 1024);
+    let client = shrlink::fallback::HttpFallback::new(config.fallback.clone()).await.unwrap();
+    
+    for (size, chunks) in [(0, 0), (1, 1), (64 * 1024, 1), (64 * 1024 + 1, 2)] {
+        let name = format!("input-{}.bin", size);
+        let input = dir.path().join(&name);
+        let data: Vec<u8> = (0..size).map(|i| (i * 31 % 251) as u8).collect();
+        std::fs::write(&input, &data).unwrap();
+        
+        let stdout = run_shr(dir.path(), &config, &["send".as_ref(), "--force-fallback".as_ref(), input.as_os_str()]).await;
+        let url = stdout.lines().map(str::trim).find(|l| l.starts_with("http://")).expect("no share URL printed").to_string();
+        
+        let (stored, _) = client.download_bundle(&url).await.unwrap();
+        let bundle = shrlink::bundle::parse_bundle(&stored).unwrap();
+        let entry = &bundle.entries[0];
+        assert_eq!(entry.chunks.len(), chunks, "{} bytes", size);
+        assert_eq!(entry.meta.as_ref().unwrap().size, size as u64);
+        assert_eq!(entry.file_hash, Some(*blake3::hash(&data).as_bytes()));
+        
+        let output = dir.path().join(format!("received-{}", name));
+        let stdout = run_shr(dir.path(), &config, &["recv".as_ref(), url.as_ref(), "--output".as_ref(), output.as_os_str()]).await;
+        assert!(stdout.contains(&format!("Downloaded {} chunks", chunks)), "{}", stdout);
+        assert_eq!(std::fs::read(&output).unwrap(), data, "{} bytes", size);
+    }
+}
+
+#[cfg(feature = "cli")]
+#[tokio::test]
+async fn test_estimate_command() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("table.csv");
+    let rows: String = (0..400_000).map(|i| format!("{},item-{},{}.00\n", i, i % 300, i % 50)).collect();
+    std::fs::write(&input, &rows).unwrap();
+    let mut config = Config::default();
+    config.compression.block_size = BlockSize::Fixed(1024 * 1024);
+    
+    let stdout = run_shr(dir.path(), &config, &["estimate".as_ref(), "--bandwidth".as_ref(), "8".as_ref(), "--sample-size".as_ref(), "2097152".as_ref(), input.as_os_str()]).await;
+    assert!(stdout.contains(&format!("Original size:   {} bytes", rows.len())), "{}", stdout);
+    assert!(stdout.contains("from 2097152 bytes sampled"), "{}", stdout);
+    assert!(stdout.contains(&format!("Chunks:          {} of 1048576 bytes", rows.len().div_ceil(1024 * 1024))), "{}", stdout);
+    assert!(stdout.contains("Upload time:     ~") && stdout.contains("at 8 Mbit/s"), "{}", stdout);
+    
+    let estimated: u64 = stdout.lines().find_map(|l| l.trim().strip_prefix("Compressed size: ~")).and_then(|l| l.split(' ').next()).unwrap().parse().unwrap();
+    let actual = ParallelCompressor::new(1024 * 1024, 1).unwrap().compress_file(&input).unwrap().total_compressed_size as u64;
+    assert!(estimated.abs_diff(actual) * 10 < actual, "estimated {}, actually {}", estimated, actual);
+    
+    let output = shr_output(dir.path(), &config, &["estimate".as_ref(), "--bandwidth".as_ref(), "0".as_ref(), input.as_os_str()]).await;
+    assert!(!output.status.success());
+}
+
+// `browse` lists what senders with `p2p.announce` set are serving on the local network
+#[cfg(all(feature = "cli", feature = "p2p"))]
+#[tokio::test]
+async fn test_browse_lists_announced_files() {
+    use shrlink::p2p::{create_shr_url, Announcement};
+    use std::time::Duration;
+
+    let dir = tempfile::tempdir().unwrap();
+    let compressor = ParallelCompressor::new(64 * 1024, 1).unwrap();
+    let result = compressor.compress_bytes(&[9u8; 1000]).unwrap();
+
+    let mut sender_config = Config::default().p2p;
+    sender_config.identity_path = Some(dir.path().join("sender.key"));
+    sender_config.announce = true;
+    let cancel = tokio_util::sync::CancellationToken::new();
+    let mut sender = p2p_client(sender_config).await.with_cancellation(cancel.clone());
+    sender.listen_on("/ip4/0.0.0.0/tcp/0".parse().unwrap()).await.unwrap();
+    let manifest = sender.prepare_manifest(result.file_hash, &result.chunks).unwrap();
+    let url = create_shr_url(sender.local_peer_id(), &hex::encode(result.file_hash));
+    sender.announce(Announcement {
+        file_name: "team-notes.txt".to_string(),
+        size: 1000,
+        file_hash: hex::encode(result.file_hash),
+        peer_id: sender.local_peer_id().to_string(),
+        url: url.clone(),
+    });
+
+    let config = Config::default();
+    let args: [&std::ffi::OsStr; 3] = ["browse".as_ref(), "--timeout".as_ref(), "8".as_ref()];
+    let (_, stdout) = tokio::join!(sender.serve_chunks(&manifest, result.chunks.clone(), 1, Duration::from_secs(60), |_| {}), async {
+        let stdout = run_shr(dir.path(), &config, &args).await;
+        cancel.cancel();
+        stdout
+    });
+    let line = stdout.lines().find(|line| line.contains("team-notes.txt")).unwrap_or_else(|| panic!("{}", stdout));
+    assert!(line.contains(&url) && line.contains("1000 B"), "{}", line);
+
+    let mut config = Config::default();
+    config.p2p.enable_mdns = false;
+    let output = shr_output(dir.path(), &config, &args[..1]).await;
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("p2p.enable_mdns"));
+}
+
+#[cfg(feature = "cli")]
+#[tokio::test]
+async fn test_id_command() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config::default();
+
+    // Made on first use in the data directory, then the same on every run
+    let first = run_shr(dir.path(), &config, &["id".as_ref()]).await;
+    let peer_id: libp2p::PeerId = first.trim().parse().unwrap();
+    assert!(dir.path().join("data").join("shrlink").join("identity.key").exists());
+    assert_eq!(run_shr(dir.path(), &config, &["id".as_ref()]).await, first);
+
+    let mut moved = Config::default();
+    moved.p2p.identity_path = Some(dir.path().join("elsewhere.key"));
+    let other: libp2p::PeerId = run_shr(dir.path(), &moved, &["id".as_ref()]).await.trim().parse().unwrap();
+    assert_ne!(other, peer_id);
+
+    std::fs::write(dir.path().join("elsewhere.key"), b"garbage").unwrap();
+    let output = shr_output(dir.path(), &moved, &["id".as_ref()]).await;
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is not a valid key"));
+}
+
+#[cfg(feature = "cli")]
+#[tokio::test]
+async fn test_url_key_encrypted_send_recv() {
+    let endpoint = spawn_mock_fallback_server().await;
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = Config::default();
+    config.fallback.endpoints = vec![endpoint];
+    config.compression.block_size = BlockSize::Fixed(64 * 1024);
+    
+    let payload = dir.path().join("tax-return.pdf");
+    let data: Vec<u8> = (0..200_000u32).map(|i| (i % 241) as u8).collect();
+    std::fs::write(&payload, &data).unwrap();
+    
+    let stdout = run_shr(dir.path(), &config, &["send".as_ref(), payload.as_os_str(), "--encrypt".as_ref(), "--force-fallback".as_ref()]).await;
+    let url = stdout.lines().map(str::trim).find(|l| l.starts_with("http://")).expect("no share URL printed");
+    let (base, key) = url.split_once("#k=").expect("no key in the share URL");
+    // Neither the key nor the file name reaches the server
+    assert!(!base.contains("tax-return"));
+    
+    let client = shrlink::fallback::HttpFallback::new(config.fallback.clone()).await.unwrap();
+    let (stored, _) = client.download_bundle(base).await.unwrap();
+    assert!(shrlink::crypto::is_sealed(&stored));
+    
+    let received = dir.path().join("received.pdf");
+    run_shr(dir.path(), &config, &["recv".as_ref(), url.as_ref(), "--output".as_ref(), received.as_os_str()]).await;
+    assert_eq!(std::fs::read(&received).unwrap(), data);
+    
+    let recv_fails = |url: String| {
+        let dir = dir.path().to_path_buf();
+        let config = config.clone();
+        async move {
+            let output = shr_output(&dir, &config, &["recv".as_ref(), url.as_ref(), "--output".as_ref(), dir.join("never.pdf").as_os_str()]).await;
+            assert!(!output.status.success());
+            assert!(!dir.join("never.pdf").exists());
+            String::from_utf8_lossy(&output.stderr).into_owned()
+        }
+    };
+    
+    let wrong_key = shrlink::crypto::url_with_key(base, &shrlink::crypto::SecretKey::generate());
+    assert!(recv_fails(wrong_key).await.contains("Decryption(\"wrong key"));
+    assert!(recv_fails(base.to_string()).await.contains("no #k= key"));
+    
+    // Cut short on the server, and swapped for a plaintext bundle by the server
+    let truncated = client.upload_bundle(&stored[..stored.len() - 100], None).await.unwrap();
+    assert!(recv_fails(format!("{}#k={}", truncated, key)).await.contains("Decryption(\"wrong key"));
+    let plain = client.upload_chunks(&[ParallelCompressor::default().compress_chunk(0, data.clone()).unwrap()], None).await.unwrap();
+    assert!(recv_fails(format!("{}#k={}", plain, key)).await.contains("not encrypted"));
+}
+
+// The sender's KDF settings travel in the bundle, so a receiver on defaults still opens it
+#[cfg(feature = "cli")]
+#[tokio::test]
+async fn test_password_send_recv() {
+    let endpoint = spawn_mock_fallback_server().await;
+    let dir = tempfile::tempdir().unwrap();
+    let mut receiver = Config::default();
+    receiver.fallback.endpoints = vec![endpoint];
+    let mut sender = receiver.clone();
+    sender.kdf.memory_kib = 1024;
+    sender.kdf.iterations = 1;
+    
+    let payload = dir.path().join("contract.pdf");
+    let data = b"signed and sealed ".repeat(10_000);
+    std::fs::write(&payload, &data).unwrap();
+    
+    let password = [("SHR_PASSWORD", "correct horse")];
+    let output = shr_output_with_env(dir.path(), &sender, &["send".as_ref(), payload.as_os_str(), "--password".as_ref(), "--force-fallback".as_ref()], &password).await;
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let url = stdout.lines().map(str::trim).find(|l| l.starts_with("http://")).expect("no share URL printed");
+    assert!(!url.contains("contract") && !url.contains('#'));
+    
+    let client = shrlink::fallback::HttpFallback::new(receiver.fallback.clone()).await.unwrap();
+    assert!(shrlink::crypto::is_locked(&client.download_bundle(url).await.unwrap().0));
+    
+    let received = dir.path().join("received.pdf");
+    let recv = |env: &'static [(&'static str, &'static str)]| {
+        let dir = dir.path().to_path_buf();
+        let receiver = receiver.clone();
+        let received = received.clone();
+        let url = url.to_string();
+        async move {
+            shr_output_with_env(&dir, &receiver, &["recv".as_ref(), url.as_ref(), "--output".as_ref(), received.as_os_str()], env).await
+        }
+    };
+    
+    let wrong = recv(&[("SHR_PASSWORD", "battery staple")]).await;
+    assert!(!wrong.status.success());
+    assert!(String::from_utf8_lossy(&wrong.stderr).contains("WrongPassword"));
+    assert!(!received.exists());
+    
+    let right = recv(&[("SHR_PASSWORD", "correct horse")]).await;
+    assert!(right.status.success(), "{}", String::from_utf8_lossy(&right.stderr));
+    assert_eq!(std::fs::read(&received).unwrap(), data);
+}
+
+// Embedders build with default-features = false and must not pull in the CLI or libp2p stacks
+#[test]
+fn test_core_build_has_no_cli_dependencies() {
+    let output = std::process::Command::new(env!("CARGO"))
+        .args(["tree", "--offline", "--no-default-features", "--edges", "normal", "--prefix", "none", "--format", "{p}"])
+        .arg("--manifest-path")
+        .arg(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml"))
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    
+    let tree = String::from_utf8(output.stdout).unwrap();
+    let crates: Vec<&str> = tree.lines().filter_map(|l| l.split_whitespace().next()).collect();
+    assert!(crates.contains(&"lz4_flex") && crates.contains(&"reqwest"));
+    for heavy in ["clap", "clap_mangen", "indicatif", "console", "rpassword", "tracing-subscriber", "globset", "libp2p"] {
+        assert!(!crates.contains(&heavy), "{} is in the no-default-features dependency tree", heavy);
+    }
+}
+
+// The browser companion decodes bundles with the same code; this catches anything native
+// creeping into the bundle or compression modules
+#[test]
+fn test_wasm_build_compiles() {
+    let sysroot = std::process::Command::new("rustc").args(["--print", "sysroot"]).output().unwrap();
+    let sysroot = String::from_utf8(sysroot.stdout).unwrap();
+    if !std::path::Path::new(sysroot.trim()).join("lib/rustlib/wasm32-unknown-unknown").exists() {
+        eprintln!("skipping: wasm32-unknown-unknown is not installed (rustup target add wasm32-unknown-unknown)");
+        return;
+    }
+    
+    let output = std::process::Command::new(env!("CARGO"))
+        .args(["check", "--offline", "--lib", "--target", "wasm32-unknown-unknown", "--no-default-features", "--features", "wasm"])
+        .arg("--manifest-path")
+        .arg(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml"))
+        // A separate target dir so this doesn't wait on the build lock held by the outer cargo
+        .arg("--target-dir")
+        .arg(concat!(env!("CARGO_TARGET_TMPDIR"), "/wasm-check"))
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+// Inputs that once crashed or hung a fuzz target, or sit right on a parser boundary. They are
+// replayed here the same way the targets in fuzz/ drive them, so the fixes stay fixed without nightly
+fn fuzz_regressions(target: &str) -> Vec<(String, Vec<u8>)> {
+    let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/regressions").join(target);
+    let mut inputs: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .map(|p| (p.file_name().unwrap().to_string_lossy().into_owned(), std::fs::read(&p).unwrap()))
+        .collect();
+    inputs.sort();
+    assert!(!inputs.is_empty(), "no regression inputs in {}", dir.display());
+    inputs
+}
+
+#[test]
+fn test_fuzz_regressions_bundle_parser() {
+    let compressor = ParallelCompressor::default();
+    for (name, input) in fuzz_regressions("bundle_parser") {
+        let parsed = shrlink::compression::parse_shr_bundle(&input);
+        if name.ends_with("-valid.bin") {
+            assert!(parsed.is_ok(), "{} should parse", name);
+        }
+        
+        let results: Vec<_> = parsed.iter().flatten().map(|chunk| compressor.decompress_chunk(chunk)).collect();
+        if name == "oversized-claim.bin" || name == "short-prefix.bin" {
+            assert!(!results.is_empty() && results.iter().all(|r| r.is_err()), "{} should fail to decompress", name);
+        }
+    }
+}
+
+#[test]
+fn test_fuzz_regressions_wire_chunk() {
+    use shrlink::bundle::wire::{decode_chunk, encode_chunk, read_chunk, DEFAULT_MAX_FRAME};
+
+    for (name, input) in fuzz_regressions("wire_chunk") {
+        let decoded = decode_chunk(&input);
+        if let Ok(Some((chunk, used))) = &decoded {
+            assert_eq!(encode_chunk(chunk).unwrap(), input[..*used], "{} does not round trip", name);
+        }
+        if name.ends_with("-valid.bin") {
+            assert!(matches!(decoded, Ok(Some(_))), "{} should decode", name);
+        }
+
+        let mut stream = input.as_slice();
+        while let Ok(Some(_)) = read_chunk(&mut stream, DEFAULT_MAX_FRAME) {}
+    }
+}
+
+#[cfg(feature = "p2p")]
+#[test]
+fn test_fuzz_regressions_shr_url() {
+    use shrlink::p2p::{create_hybrid_url, parse_hybrid_url};
+    
+    for (name, input) in fuzz_regressions("shr_url") {
+        let Ok(url) = std::str::from_utf8(&input) else {
+            continue;
+        };
+        if let Ok((peer_id, file_hash, Some(fallback))) = parse_hybrid_url(url.trim()) {
+            let again = parse_hybrid_url(&create_hybrid_url(peer_id, &file_hash, &fallback));
+            assert_eq!(again.ok(), Some((peer_id, file_hash, Some(fallback))), "{} does not round trip", name);
+        }
+    }
+}
+
+#[cfg(feature = "p2p")]
+#[test]
+fn test_fuzz_regressions_manifest() {
+    use shrlink::compression::CompressedChunk;
+    use shrlink::p2p::ChunkManifest;
+    
+    for (_, input) in fuzz_regressions("manifest") {
+        let Ok(manifest) = serde_json::from_slice::<ChunkManifest>(&input) else {
+            continue;
+        };
+        let signer = manifest.signer_peer_id();
+        for entry in &manifest.entries {
+            let chunk = CompressedChunk { index: entry.index, data: Vec::new(), hash: entry.hash, original_size: entry.original_size, algorithm: Default::default(), checksum: Default::default() };
+            let _ = manifest.verify_chunk(&chunk, signer);
+        }
+    }
+}